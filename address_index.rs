@@ -0,0 +1,197 @@
+use crate::blockchain::Block;
+use crate::storage::{IndexOp, CF_ADDRESS_INDEX, CF_ADDRESS_UTXOS};
+use crate::transaction::{Coin, OutPoint, TxHash, TxOutput};
+use std::collections::{HashMap, HashSet};
+
+// Optional explorer index from locking scripts to the outputs that paid them and the inputs that
+// spent those outputs. Scripts are keyed by their blake3 hash so every key has the same 32-byte
+// prefix whatever the script length.
+//   address_index: script hash, height, txid, kind, output or input index -> amount [, spent outpoint]
+//   address_utxos: script hash, txid, output index -> amount, height
+const KIND_FUNDING: u8 = 0;
+const KIND_SPENDING: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressEventKind {
+    Funding,
+    Spending,
+}
+
+// One output paying the script, or one input spending such an output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressEvent {
+    pub height: u64,
+    pub txid: TxHash,
+    pub kind: AddressEventKind,
+    // Output index for funding events, input index for spending ones
+    pub index: u32,
+    pub amount: u64,
+    pub spent_outpoint: Option<OutPoint>,
+}
+
+impl AddressEvent {
+    pub fn decode(key: &[u8], value: &[u8]) -> Option<Self> {
+        if key.len() != 32 + 8 + 32 + 1 + 4 || value.len() < 8 {
+            return None;
+        }
+        let kind = match key[72] {
+            KIND_FUNDING => AddressEventKind::Funding,
+            KIND_SPENDING => AddressEventKind::Spending,
+            _ => return None,
+        };
+        let spent_outpoint = match value.len() {
+            44 => Some(OutPoint { txid: value[8..40].try_into().ok()?, index: u32::from_be_bytes(value[40..].try_into().ok()?) }),
+            _ => None,
+        };
+        Some(AddressEvent {
+            height: u64::from_be_bytes(key[32..40].try_into().ok()?),
+            txid: key[40..72].try_into().ok()?,
+            kind,
+            index: u32::from_be_bytes(key[73..].try_into().ok()?),
+            amount: u64::from_be_bytes(value[..8].try_into().ok()?),
+            spent_outpoint,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressUtxo {
+    pub outpoint: OutPoint,
+    pub amount: u64,
+    pub height: u64,
+}
+
+impl AddressUtxo {
+    pub fn decode(key: &[u8], value: &[u8]) -> Option<Self> {
+        if key.len() != 32 + 32 + 4 || value.len() != 16 {
+            return None;
+        }
+        Some(AddressUtxo {
+            outpoint: OutPoint { txid: key[32..64].try_into().ok()?, index: u32::from_be_bytes(key[64..].try_into().ok()?) },
+            amount: u64::from_be_bytes(value[..8].try_into().ok()?),
+            height: u64::from_be_bytes(value[8..].try_into().ok()?),
+        })
+    }
+}
+
+pub fn script_hash(script: &[u8]) -> [u8; 32] {
+    blake3::hash(script).into()
+}
+
+fn event_key(script: &[u8; 32], height: u64, txid: &TxHash, kind: u8, index: u32) -> Vec<u8> {
+    let mut key = Vec::with_capacity(32 + 8 + 32 + 1 + 4);
+    key.extend_from_slice(script);
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(txid);
+    key.push(kind);
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+fn utxo_key(script: &[u8; 32], outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = Vec::with_capacity(32 + 32 + 4);
+    key.extend_from_slice(script);
+    key.extend_from_slice(&outpoint.txid);
+    key.extend_from_slice(&outpoint.index.to_be_bytes());
+    key
+}
+
+fn utxo_value(amount: u64, height: u64) -> Vec<u8> {
+    [amount.to_be_bytes(), height.to_be_bytes()].concat()
+}
+
+// Index writes for connecting (`connect`) or disconnecting the block at `height`. `spent_coins` are
+// the coins it spent from the existing set, as recorded in its undo data.
+pub fn block_changes(block: &Block, height: u64, spent_coins: &[(OutPoint, Coin)], connect: bool) -> Vec<IndexOp> {
+    let mut outputs: HashMap<OutPoint, (&TxOutput, u64)> = spent_coins.iter()
+        .map(|(outpoint, coin)| (*outpoint, (&coin.output, coin.height)))
+        .collect();
+    let mut created = HashSet::new();
+    let mut ops = Vec::new();
+    // Entries the block adds are written on connect and deleted on disconnect
+    let added = |value: Vec<u8>| connect.then_some(value);
+
+    for tx in &block.transactions {
+        let txid = tx.hash();
+        for (input_index, input) in tx.inputs.iter().enumerate() {
+            let outpoint = input.previous_output;
+            let Some(&(output, coin_height)) = outputs.get(&outpoint) else { continue };
+            let script = script_hash(&output.script_pubkey);
+            let value = [&output.value.to_be_bytes()[..], &outpoint.txid, &outpoint.index.to_be_bytes()].concat();
+            ops.push((CF_ADDRESS_INDEX, event_key(&script, height, &txid, KIND_SPENDING, input_index as u32), added(value)));
+            // Leaves the unspent set on connect and returns on disconnect, unless this block created
+            // it: then it is gone either way, after the put its funding output wrote on connect
+            let restored = (!connect && !created.contains(&outpoint)).then(|| utxo_value(output.value, coin_height));
+            ops.push((CF_ADDRESS_UTXOS, utxo_key(&script, &outpoint), restored));
+        }
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint { txid, index: index as u32 };
+            let script = script_hash(&output.script_pubkey);
+            ops.push((CF_ADDRESS_INDEX, event_key(&script, height, &txid, KIND_FUNDING, index as u32), added(output.value.to_be_bytes().to_vec())));
+            ops.push((CF_ADDRESS_UTXOS, utxo_key(&script, &outpoint), added(utxo_value(output.value, height))));
+            outputs.insert(outpoint, (output, height));
+            created.insert(outpoint);
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{BlockHeader, BlockType};
+    use crate::transaction::{Transaction, TxInput};
+    use std::collections::BTreeMap;
+
+    fn apply(index: &mut BTreeMap<(&'static str, Vec<u8>), Vec<u8>>, ops: Vec<IndexOp>) {
+        for (cf, key, value) in ops {
+            match value {
+                Some(value) => index.insert((cf, key), value),
+                None => index.remove(&(cf, key)),
+            };
+        }
+    }
+
+    #[test]
+    fn test_connect_then_disconnect_restores_index() {
+        let script = vec![4u8; 32];
+        let funding = Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value: 50, script_pubkey: script.clone() }], lock_time: 0 };
+        let old_coin = (OutPoint { txid: [8; 32], index: 0 }, Coin { output: TxOutput { value: 70, script_pubkey: script.clone() }, height: 2, median_time_past: 0, is_coinbase: false });
+        let input = |previous_output| TxInput { previous_output, public_key: [4; 32], signature: Vec::new(), sequence: 0 };
+        // Spends the old coin and the output created just before it in the same block
+        let spend = Transaction {
+            inputs: vec![input(old_coin.0), input(OutPoint { txid: funding.hash(), index: 0 })],
+            outputs: vec![TxOutput { value: 110, script_pubkey: vec![5; 32] }],
+            lock_time: 0,
+        };
+        let block = Block {
+            header: BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [0; 32], fruits_root: [0; 32], timestamp: 0, bits: 0, nonce: 0 },
+            block_type: BlockType::Block,
+            fruit_header: None,
+            fruits: Vec::new(),
+            transactions: vec![funding, spend.clone()],
+        };
+
+        let mut index = BTreeMap::new();
+        index.insert((CF_ADDRESS_UTXOS, utxo_key(&script_hash(&script), &old_coin.0)), utxo_value(70, 2));
+        let before = index.clone();
+
+        apply(&mut index, block_changes(&block, 3, std::slice::from_ref(&old_coin), true));
+        let prefix = script_hash(&script);
+        let events = index.iter()
+            .filter(|((cf, key), _)| *cf == CF_ADDRESS_INDEX && key.starts_with(&prefix))
+            .filter_map(|((_, key), value)| AddressEvent::decode(key, value))
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().any(|event| event.kind == AddressEventKind::Spending && event.spent_outpoint == Some(old_coin.0) && event.txid == spend.hash()));
+        // Both of the script's coins are spent; only the payment to the other script is unspent
+        let utxos = index.iter()
+            .filter(|((cf, _), _)| *cf == CF_ADDRESS_UTXOS)
+            .filter_map(|((_, key), value)| AddressUtxo::decode(key, value))
+            .collect::<Vec<_>>();
+        assert_eq!(utxos, vec![AddressUtxo { outpoint: OutPoint { txid: spend.hash(), index: 0 }, amount: 110, height: 3 }]);
+
+        apply(&mut index, block_changes(&block, 3, std::slice::from_ref(&old_coin), false));
+        assert_eq!(index, before);
+    }
+}
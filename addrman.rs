@@ -0,0 +1,421 @@
+use crate::codec::{self, CodecError};
+use crate::protocol::{NetAddress, MAX_ADDR_PER_MESSAGE};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use thiserror::Error;
+
+pub const PEERS_FILE_NAME: &str = "peers.dat";
+
+// Addresses are spread over buckets keyed by their network group (/16 for IPv4, /32 for IPv6) so a
+// single source or subnet can only fill a small part of either table
+const NEW_BUCKET_COUNT: usize = 256;
+const TRIED_BUCKET_COUNT: usize = 64;
+const BUCKET_SIZE: usize = 64;
+// A single source group may only place addresses into this many new buckets
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 16;
+// Addresses not heard about for this long are treated as stale and evicted first
+const STALE_AFTER_SECS: u64 = 30 * 24 * 3600;
+const MAX_FAILED_ATTEMPTS: u32 = 10;
+// getaddr replies cover at most this share of known addresses
+const GETADDR_PERCENT: usize = 23;
+
+#[derive(Error, Debug)]
+pub enum AddrManError {
+    #[error("Undecodable address table: {0}")]
+    Codec(#[from] CodecError),
+    #[error("Address table has {new} new and {tried} tried buckets")]
+    Layout { new: usize, tried: usize },
+    #[error("Address table index is inconsistent at {0}")]
+    Index(SocketAddr),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddrInfo {
+    pub address: NetAddress,
+    // Peer that told us about the address
+    pub source: IpAddr,
+    pub attempts: u32,
+    pub last_try: u64,
+    pub last_success: u64,
+}
+
+impl AddrInfo {
+    // Entries that should be evicted before anything else
+    fn is_terrible(&self, now: u64) -> bool {
+        (self.last_success == 0 && self.attempts >= MAX_FAILED_ATTEMPTS)
+            || now.saturating_sub(self.address.last_seen) > STALE_AFTER_SECS
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddrManager {
+    // Secret salt for bucket placement so outsiders can't aim addresses at chosen buckets
+    key: u64,
+    entries: HashMap<SocketAddr, (AddrInfo, bool)>,
+    new_buckets: Vec<Vec<SocketAddr>>,
+    tried_buckets: Vec<Vec<SocketAddr>>,
+}
+
+impl AddrManager {
+    pub fn new() -> Self {
+        AddrManager {
+            key: rand::thread_rng().gen(),
+            entries: HashMap::new(),
+            new_buckets: vec![Vec::new(); NEW_BUCKET_COUNT],
+            tried_buckets: vec![Vec::new(); TRIED_BUCKET_COUNT],
+        }
+    }
+
+    // Loads peers.dat, starting empty if it is missing or unreadable
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read(path) {
+            Ok(bytes) => Self::decode(&bytes).unwrap_or_else(|e| {
+                log::warn!("Ignoring {}, starting with an empty address table: {}", path.display(), e);
+                Self::new()
+            }),
+            Err(_) => Self::new(),
+        }
+    }
+
+    // Decodes a saved table, checking that the buckets and entries agree: every bucketed address
+    // has an entry in the matching table, sits in the bucket it hashes to and is listed only once
+    pub fn decode(bytes: &[u8]) -> Result<Self, AddrManError> {
+        let manager = codec::decode::<AddrManager>(bytes)?;
+        if manager.new_buckets.len() != NEW_BUCKET_COUNT || manager.tried_buckets.len() != TRIED_BUCKET_COUNT {
+            return Err(AddrManError::Layout { new: manager.new_buckets.len(), tried: manager.tried_buckets.len() });
+        }
+        let mut listed = HashSet::with_capacity(manager.entries.len());
+        for (tried, buckets) in [(false, &manager.new_buckets), (true, &manager.tried_buckets)] {
+            for (bucket, addrs) in buckets.iter().enumerate() {
+                for addr in addrs {
+                    let placed = match manager.entries.get(addr) {
+                        Some((info, is_tried)) if *is_tried == tried && addrs.len() <= BUCKET_SIZE => {
+                            let expected = if tried { manager.tried_bucket(addr) } else { manager.new_bucket(addr, &info.source) };
+                            expected == bucket
+                        }
+                        _ => false,
+                    };
+                    if !placed || !listed.insert(*addr) {
+                        return Err(AddrManError::Index(*addr));
+                    }
+                }
+            }
+        }
+        if let Some(addr) = manager.entries.keys().find(|addr| !listed.contains(*addr)) {
+            return Err(AddrManError::Index(*addr));
+        }
+        Ok(manager)
+    }
+
+    // Written to a temporary file first so a crash never leaves a truncated peers.dat
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let bytes = codec::encode(self).map_err(std::io::Error::other)?;
+        let tmp = path.with_extension("dat.tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn tried_count(&self) -> usize {
+        self.entries.values().filter(|(_, tried)| *tried).count()
+    }
+
+    // Adds gossiped or seeded addresses to the new table. Returns how many were new to us.
+    pub fn add(&mut self, addresses: &[NetAddress], source: IpAddr, now: u64) -> usize {
+        let mut added = 0;
+        for address in addresses.iter().take(MAX_ADDR_PER_MESSAGE) {
+            if !is_routable(&address.addr.ip()) {
+                continue;
+            }
+            if let Some((info, _)) = self.entries.get_mut(&address.addr) {
+                info.address.last_seen = info.address.last_seen.max(address.last_seen.min(now));
+                info.address.services |= address.services;
+                continue;
+            }
+
+            let bucket = self.new_bucket(&address.addr, &source);
+            if self.new_buckets[bucket].len() >= BUCKET_SIZE && !self.evict_from_new(bucket, now) {
+                continue;
+            }
+            let mut address = *address;
+            // Don't trust timestamps from the future
+            address.last_seen = address.last_seen.min(now);
+            self.new_buckets[bucket].push(address.addr);
+            self.entries.insert(address.addr, (AddrInfo { address, source, attempts: 0, last_try: 0, last_success: 0 }, false));
+            added += 1;
+        }
+        added
+    }
+
+    pub fn attempt(&mut self, addr: &SocketAddr, now: u64) {
+        if let Some((info, _)) = self.entries.get_mut(addr) {
+            info.attempts += 1;
+            info.last_try = now;
+        }
+    }
+
+    // A connection to `addr` succeeded: move it to the tried table, pushing out the oldest tried
+    // entry in its bucket back into the new table if needed
+    pub fn good(&mut self, addr: &SocketAddr, now: u64) {
+        let source = match self.entries.get_mut(addr) {
+            Some((info, tried)) => {
+                info.attempts = 0;
+                info.last_success = now;
+                info.last_try = now;
+                info.address.last_seen = now;
+                if *tried {
+                    return;
+                }
+                info.source
+            }
+            None => return,
+        };
+
+        let new_bucket = self.new_bucket(addr, &source);
+        self.new_buckets[new_bucket].retain(|a| a != addr);
+
+        let bucket = self.tried_bucket(addr);
+        if self.tried_buckets[bucket].len() >= BUCKET_SIZE {
+            let oldest = self.tried_buckets[bucket].iter()
+                .min_by_key(|a| self.entries[*a].0.last_success)
+                .copied();
+            if let Some(oldest) = oldest {
+                self.tried_buckets[bucket].retain(|a| *a != oldest);
+                let source = self.entries[&oldest].0.source;
+                let demoted_bucket = self.new_bucket(&oldest, &source);
+                if self.new_buckets[demoted_bucket].len() >= BUCKET_SIZE && !self.evict_from_new(demoted_bucket, now) {
+                    self.entries.remove(&oldest);
+                } else {
+                    self.new_buckets[demoted_bucket].push(oldest);
+                    if let Some(entry) = self.entries.get_mut(&oldest) {
+                        entry.1 = false;
+                    }
+                }
+            }
+        }
+        self.tried_buckets[bucket].push(*addr);
+        if let Some(entry) = self.entries.get_mut(addr) {
+            entry.1 = true;
+        }
+    }
+
+    // Picks an address to connect to, alternating between the tables and skipping ones tried recently
+    pub fn select(&self, now: u64, retry_after_secs: u64) -> Option<NetAddress> {
+        let mut rng = rand::thread_rng();
+        let candidates = |tried: bool| self.entries.values()
+            .filter(move |(info, is_tried)| *is_tried == tried && now.saturating_sub(info.last_try) >= retry_after_secs)
+            .map(|(info, _)| info)
+            .collect::<Vec<_>>();
+
+        let (first, second) = if rng.gen_bool(0.5) { (true, false) } else { (false, true) };
+        for tried in [first, second] {
+            let candidates = candidates(tried);
+            if !candidates.is_empty() {
+                return Some(candidates[rng.gen_range(0..candidates.len())].address);
+            }
+        }
+        None
+    }
+
+    // Random sample of non-terrible addresses for answering getaddr
+    pub fn get_addr(&self, now: u64) -> Vec<NetAddress> {
+        let mut addresses = self.entries.values()
+            .filter(|(info, _)| !info.is_terrible(now))
+            .map(|(info, _)| info.address)
+            .collect::<Vec<_>>();
+        let count = (addresses.len() * GETADDR_PERCENT / 100).max(addresses.len().min(1)).min(MAX_ADDR_PER_MESSAGE);
+        let mut rng = rand::thread_rng();
+        for i in 0..count {
+            let j = rng.gen_range(i..addresses.len());
+            addresses.swap(i, j);
+        }
+        addresses.truncate(count);
+        addresses
+    }
+
+    // Frees a slot in a full new bucket by dropping a terrible entry, or failing that the oldest one
+    fn evict_from_new(&mut self, bucket: usize, now: u64) -> bool {
+        let victim = self.new_buckets[bucket].iter()
+            .find(|a| self.entries[*a].0.is_terrible(now))
+            .or_else(|| self.new_buckets[bucket].iter().min_by_key(|a| self.entries[*a].0.address.last_seen))
+            .copied();
+        match victim {
+            Some(victim) => {
+                self.new_buckets[bucket].retain(|a| *a != victim);
+                self.entries.remove(&victim);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn new_bucket(&self, addr: &SocketAddr, source: &IpAddr) -> usize {
+        let source_group = network_group(source);
+        let slot = self.hash(&[&source_group, &network_group(&addr.ip())]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        (self.hash(&[&source_group, &slot.to_le_bytes()]) % NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    fn tried_bucket(&self, addr: &SocketAddr) -> usize {
+        (self.hash(&[addr.to_string().as_bytes(), &network_group(&addr.ip())]) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> u64 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.key.to_le_bytes());
+        for part in parts {
+            hasher.update(part);
+        }
+        u64::from_le_bytes(hasher.finalize().as_bytes()[..8].try_into().unwrap())
+    }
+}
+
+impl Default for AddrManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// /16 for IPv4, /32 for IPv6: blocks of addresses one operator can easily get many of
+pub fn network_group(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(v4) => v4.octets()[..2].to_vec(),
+        IpAddr::V6(v6) => v6.octets()[..4].to_vec(),
+    }
+}
+
+// Only public addresses are worth keeping: loopback, private and link-local ones point at
+// different machines depending on who connects
+fn is_routable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => !(v4.is_unspecified()
+            || v4.is_broadcast()
+            || v4.is_documentation()
+            || v4.is_loopback()
+            || v4.is_private()
+            || v4.is_link_local()),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_routable(&IpAddr::V4(v4)),
+            None => !(v6.is_unspecified() || v6.is_loopback() || v6.is_unique_local() || v6.is_unicast_link_local()),
+        },
+    }
+}
+
+// Resolves DNS seeds into addresses on the chain's default port; failures are logged and skipped
+pub async fn resolve_dns_seeds(seeds: &[String], default_port: u16) -> Vec<NetAddress> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut addresses = Vec::new();
+    for seed in seeds {
+        match tokio::net::lookup_host((seed.as_str(), default_port)).await {
+            Ok(resolved) => addresses.extend(resolved.map(|addr| NetAddress { addr, services: 0, last_seen: now })),
+            Err(e) => log::warn!("DNS seed {} failed to resolve: {}", seed, e),
+        }
+    }
+    addresses
+}
+
+// Resolves "host:port" seed nodes; failures are logged and skipped
+pub async fn resolve_seed_nodes(nodes: &[String]) -> Vec<NetAddress> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut addresses = Vec::new();
+    for node in nodes {
+        match tokio::net::lookup_host(node.as_str()).await {
+            Ok(resolved) => addresses.extend(resolved.map(|addr| NetAddress { addr, services: 0, last_seen: now })),
+            Err(e) => log::warn!("Seed node {} failed to resolve: {}", node, e),
+        }
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn address(a: u8, b: u8, c: u8, last_seen: u64) -> NetAddress {
+        NetAddress { addr: SocketAddr::from(([a, b, c, 1], 9333)), services: 1, last_seen }
+    }
+
+    #[test]
+    fn test_addresses_move_to_tried_and_persist() {
+        let now = 1_000_000;
+        let source: IpAddr = [10, 0, 0, 1].into();
+        let mut manager = AddrManager::new();
+        let addresses = (0..50).map(|i| address(1, i, 1, now - 10)).collect::<Vec<_>>();
+        assert_eq!(manager.add(&addresses, source, now), 50);
+        assert_eq!(manager.add(&addresses, source, now), 0);
+
+        manager.good(&addresses[3].addr, now);
+        assert_eq!(manager.tried_count(), 1);
+        assert!(manager.select(now, 600).is_some());
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(PEERS_FILE_NAME);
+        manager.save(&path).unwrap();
+        let loaded = AddrManager::load(&path);
+        assert_eq!(loaded.len(), 50);
+        assert_eq!(loaded.tried_count(), 1);
+    }
+
+    #[test]
+    fn test_one_source_cannot_flood_the_new_table() {
+        let now = 1_000_000;
+        let source: IpAddr = [10, 0, 0, 1].into();
+        let mut manager = AddrManager::new();
+        for a in 1..=100u8 {
+            let addresses = (0..=255u8).map(|b| address(a, b, 7, now)).collect::<Vec<_>>();
+            manager.add(&addresses, source, now);
+        }
+        // One source group reaches at most NEW_BUCKETS_PER_SOURCE_GROUP buckets
+        assert!(manager.len() <= NEW_BUCKETS_PER_SOURCE_GROUP as usize * BUCKET_SIZE);
+    }
+
+    #[test]
+    fn test_local_addresses_are_not_added() {
+        let now = 1_000_000;
+        let source: IpAddr = [1, 2, 3, 4].into();
+        let mut manager = AddrManager::new();
+        let local = [[127, 0, 0, 1], [10, 1, 2, 3], [172, 16, 0, 9], [192, 168, 1, 1], [169, 254, 0, 1], [0, 0, 0, 0]]
+            .map(|ip| NetAddress { addr: SocketAddr::from((ip, 9333)), services: 1, last_seen: now });
+        assert_eq!(manager.add(&local, source, now), 0);
+        let v6 = ["::1", "fd00::1", "fe80::1", "::ffff:192.168.0.1"]
+            .map(|ip| NetAddress { addr: SocketAddr::new(ip.parse().unwrap(), 9333), services: 1, last_seen: now });
+        assert_eq!(manager.add(&v6, source, now), 0);
+        assert_eq!(manager.add(&[address(8, 8, 8, now)], source, now), 1);
+    }
+
+    #[test]
+    fn test_inconsistent_index_is_an_error() {
+        let now = 1_000_000;
+        let mut manager = AddrManager::new();
+        let addresses = (0..4).map(|i| address(1, i, 1, now)).collect::<Vec<_>>();
+        manager.add(&addresses, [1, 2, 3, 4].into(), now);
+        assert!(AddrManager::decode(&codec::encode(&manager).unwrap()).is_ok());
+
+        // A bucket naming an address the table doesn't know
+        let stray = address(2, 2, 2, now).addr;
+        manager.new_buckets[0].push(stray);
+        assert!(matches!(AddrManager::decode(&codec::encode(&manager).unwrap()), Err(AddrManError::Index(addr)) if addr == stray));
+        manager.new_buckets[0].pop();
+
+        // An entry in no bucket at all
+        let bucket = manager.new_buckets.iter().position(|bucket| !bucket.is_empty()).unwrap();
+        let orphan = manager.new_buckets[bucket].pop().unwrap();
+        assert!(matches!(AddrManager::decode(&codec::encode(&manager).unwrap()), Err(AddrManError::Index(addr)) if addr == orphan));
+
+        // Loading the damaged file starts over instead of panicking later
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(PEERS_FILE_NAME);
+        manager.save(&path).unwrap();
+        assert!(AddrManager::load(&path).is_empty());
+    }
+}
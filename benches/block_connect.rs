@@ -0,0 +1,111 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ed25519_dalek::{Signer, SigningKey};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use xcore::blockchain::{Block, Blockchain};
+use xcore::chain_params::Network;
+use xcore::miner::BlockTemplateBuilder;
+use xcore::node_config::BlockchainConfig;
+use xcore::reward;
+use xcore::transaction::{OutPoint, Transaction, TxInput, TxOutput, SEQUENCE_FINAL};
+use xcore::validation;
+
+const BLOCKS: usize = 20;
+const TRANSACTIONS_PER_BLOCK: usize = 200;
+const START_TIME: u64 = 1_700_000_000;
+
+async fn regtest_chain() -> (TempDir, Blockchain) {
+    let datadir = TempDir::new().unwrap();
+    let mut config = BlockchainConfig::load(datadir.path()).unwrap();
+    config.chain = Network::Regtest;
+    let blockchain = Blockchain::new(config).await.unwrap();
+    (datadir, blockchain)
+}
+
+fn signed(key: &SigningKey, previous_output: OutPoint, outputs: Vec<TxOutput>) -> Transaction {
+    let mut tx = Transaction {
+        inputs: vec![TxInput { previous_output, public_key: key.verifying_key().to_bytes(), signature: Vec::new(), sequence: SEQUENCE_FINAL }],
+        outputs,
+        lock_time: 0,
+    };
+    let message = tx.signature_hash().unwrap();
+    tx.inputs[0].signature = key.sign(&message).to_bytes().to_vec();
+    tx
+}
+
+async fn next_block(chain: &Blockchain, transactions: Vec<Transaction>, script: &[u8]) -> Block {
+    let height = chain.get_chain_height().map_or(0, |h| h + 1);
+    let timestamp = START_TIME + height * chain.params().target_block_spacing_secs;
+    let coinbase = Transaction {
+        inputs: Vec::new(),
+        outputs: reward::coinbase_outputs(reward::block_subsidy(chain.params(), height), Vec::new(), &reward::MinerPayout::single(script.to_vec())),
+        lock_time: height as u32,
+    };
+    let bits = chain.next_block_bits(timestamp).await.unwrap();
+    let mut block = BlockTemplateBuilder::new(chain.params().clone())
+        .build(chain.next_block_version(), chain.get_chain_tip(), height, chain.median_time_past(), timestamp, bits, coinbase, Vec::new(), transactions)
+        .unwrap();
+    while validation::check_header(&block.header).is_err() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+// A genesis block, a block fanning its coinbase out, then BLOCKS blocks of signed transactions
+// each spending one output of the block before
+async fn build_blocks() -> Vec<Block> {
+    let (_datadir, chain) = regtest_chain().await;
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let script = reward::fruit_payout_script(&key.verifying_key().to_bytes());
+
+    let mut blocks = Vec::new();
+    let genesis = next_block(&chain, Vec::new(), &script).await;
+    let coinbase = OutPoint { txid: genesis.transactions[0].hash(), index: 0 };
+    chain.add_block(genesis.clone()).await.unwrap();
+    blocks.push(genesis);
+
+    let value = reward::block_subsidy(chain.params(), 0) / TRANSACTIONS_PER_BLOCK as u64;
+    let fan_out = signed(&key, coinbase, (0..TRANSACTIONS_PER_BLOCK).map(|_| TxOutput { value, script_pubkey: script.clone() }).collect());
+    let mut spendable = (0..TRANSACTIONS_PER_BLOCK as u32).map(|index| OutPoint { txid: fan_out.hash(), index }).collect::<Vec<_>>();
+    let mut transactions = vec![fan_out];
+    for _ in 0..=BLOCKS {
+        let block = next_block(&chain, transactions, &script).await;
+        chain.add_block(block.clone()).await.unwrap();
+        blocks.push(block);
+        transactions = spendable.iter()
+            .map(|outpoint| signed(&key, *outpoint, vec![TxOutput { value: value - 1, script_pubkey: script.clone() }]))
+            .collect();
+        spendable = transactions.iter().map(|tx| OutPoint { txid: tx.hash(), index: 0 }).collect();
+    }
+    blocks
+}
+
+fn connect(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let blocks = runtime.block_on(build_blocks());
+    let mut group = c.benchmark_group("blockchain");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((BLOCKS * TRANSACTIONS_PER_BLOCK) as u64));
+    // Validation, UTXO updates, block and undo writes and index updates for every block
+    group.bench_function("connect_blocks", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    // Opening a fresh chainstate isn't part of the measurement
+                    let (_datadir, chain) = regtest_chain().await;
+                    let start = Instant::now();
+                    for block in blocks.iter().cloned() {
+                        chain.add_block(block).await.unwrap();
+                    }
+                    total += start.elapsed();
+                }
+                total
+            })
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, connect);
+criterion_main!(benches);
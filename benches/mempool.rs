@@ -0,0 +1,77 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use xcore::blockchain::calculate_merkle_root;
+use xcore::mempool::Mempool;
+use xcore::merkle::merkle_root;
+use xcore::difficulty::MIN_DIFFICULTY_BITS;
+use xcore::policy::RelayPolicy;
+use xcore::transaction::{OutPoint, Transaction, TxInput, TxOutput, SEQUENCE_FINAL};
+
+const TRANSACTIONS: usize = 100_000;
+// Large enough that the size limit never turns transactions away
+const SIZE_LIMIT_MB: usize = 1024;
+
+// Distinct one-input, two-output transactions of typical size; the mempool doesn't check signatures
+fn transactions(count: usize) -> Vec<Transaction> {
+    (0..count)
+        .map(|i| Transaction {
+            inputs: vec![TxInput {
+                previous_output: OutPoint { txid: blake3::hash(&i.to_le_bytes()).into(), index: 0 },
+                public_key: [1; 32],
+                signature: vec![2; 64],
+                sequence: SEQUENCE_FINAL,
+            }],
+            outputs: vec![
+                TxOutput { value: 1_000, script_pubkey: vec![3; 32] },
+                TxOutput { value: 2_000, script_pubkey: vec![4; 32] },
+            ],
+            lock_time: 0,
+        })
+        .collect()
+}
+
+fn mempool() -> Mempool {
+    Mempool::new(SIZE_LIMIT_MB, 600, 3600, 60, RelayPolicy { min_relay_fee_rate: 0.0, dust_limit: 0, accept_non_standard: false }, 16, MIN_DIFFICULTY_BITS)
+}
+
+fn filled(txs: &[Transaction]) -> Mempool {
+    let mut mempool = mempool();
+    for tx in txs {
+        mempool.add_transaction(tx.clone(), 1_000, 1, 0).unwrap();
+    }
+    mempool
+}
+
+fn add_and_remove(c: &mut Criterion) {
+    let txs = transactions(TRANSACTIONS);
+    let mut group = c.benchmark_group("mempool");
+    group.sample_size(10);
+    group.bench_function("add_100k", |b| {
+        b.iter_batched(|| (mempool(), txs.clone()), |(mut mempool, txs)| {
+            for tx in txs {
+                mempool.add_transaction(tx, 1_000, 1, 0).unwrap();
+            }
+            mempool
+        }, BatchSize::PerIteration);
+    });
+    // As after a block confirms most of the pool
+    group.bench_function("remove_100k", |b| {
+        b.iter_batched(|| filled(&txs), |mut mempool| {
+            mempool.remove_transactions(&txs);
+            mempool
+        }, BatchSize::PerIteration);
+    });
+    group.finish();
+}
+
+fn merkle_rebuild(c: &mut Criterion) {
+    let txs = transactions(TRANSACTIONS);
+    let leaves = txs.iter().map(Transaction::hash).collect::<Vec<_>>();
+    let mut group = c.benchmark_group("merkle");
+    group.bench_function("root_100k_leaves", |b| b.iter(|| merkle_root(&leaves)));
+    // Hashing the transactions too, as block validation does
+    group.bench_function("block_root_5k_transactions", |b| b.iter(|| calculate_merkle_root(&txs[..5_000])));
+    group.finish();
+}
+
+criterion_group!(benches, add_and_remove, merkle_rebuild);
+criterion_main!(benches);
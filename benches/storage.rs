@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+use xcore::block_storage::BlockStorage;
+use xcore::node_config::BlockchainConfig;
+use xcore::storage::BlockLocation;
+
+const BLOCK_SIZES: [usize; 4] = [1024, 64 * 1024, 512 * 1024, 4 * 1024 * 1024];
+// Blocks written per iteration, so file rollover and fsync policy show up in the numbers
+const BLOCKS_PER_ITER: usize = 16;
+
+fn block_storage() -> (TempDir, BlockStorage) {
+    let datadir = TempDir::new().unwrap();
+    let config = BlockchainConfig::load(datadir.path()).unwrap();
+    let storage = BlockStorage::new(config).unwrap();
+    (datadir, storage)
+}
+
+fn block_data(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn append(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_storage/append");
+    group.sample_size(20);
+    for size in BLOCK_SIZES {
+        let data = block_data(size);
+        group.throughput(Throughput::Bytes((size * BLOCKS_PER_ITER) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter_batched(
+                block_storage,
+                |(_datadir, storage)| {
+                    for _ in 0..BLOCKS_PER_ITER {
+                        storage.append_block_to_file(data, 0).unwrap();
+                    }
+                    storage.sync().unwrap();
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_storage/read");
+    for size in BLOCK_SIZES {
+        let (_datadir, storage) = block_storage();
+        let data = block_data(size);
+        let locations = (0..BLOCKS_PER_ITER)
+            .map(|_| {
+                let (file_name, byte_offset) = storage.append_block_to_file(&data, 0).unwrap();
+                BlockLocation { file_name, byte_offset }
+            })
+            .collect::<Vec<_>>();
+        storage.sync().unwrap();
+
+        group.throughput(Throughput::Bytes((size * BLOCKS_PER_ITER) as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                for location in &locations {
+                    criterion::black_box(storage.read_block_from_file(location).unwrap());
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, append, read);
+criterion_main!(benches);
@@ -0,0 +1,98 @@
+use clap::Parser;
+use serde_json::Value;
+use std::path::PathBuf;
+use xcore::node_config::{self, BlockchainConfig};
+use xcore::rpc::{RpcRequest, RpcResponse};
+use xcore::rpc_auth::{self, COOKIE_FILE_NAME};
+
+#[derive(Parser)]
+#[command(name = "xcore-cli", version, about = "Command line client for the xCore RPC server")]
+struct Cli {
+    /// Data directory whose configuration supplies the RPC address
+    #[arg(long)]
+    datadir: Option<PathBuf>,
+
+    /// RPC host to connect to, overriding the configured bind address
+    #[arg(long)]
+    rpcconnect: Option<String>,
+
+    /// RPC port to connect to, overriding the configured port
+    #[arg(long)]
+    rpcport: Option<u16>,
+
+    /// RPC user, overriding the configured one; without either the node's cookie file is used
+    #[arg(long, requires = "rpcpassword")]
+    rpcuser: Option<String>,
+
+    #[arg(long, requires = "rpcuser")]
+    rpcpassword: Option<String>,
+
+    /// Cookie file to authenticate with, e.g. the .readonly.cookie of a read-only node
+    #[arg(long)]
+    rpccookiefile: Option<PathBuf>,
+
+    /// RPC method, e.g. getblock
+    method: String,
+
+    /// Method parameters; values that parse as JSON are sent as JSON, anything else as a string
+    params: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let datadir = cli.datadir.unwrap_or_else(node_config::default_datadir);
+    let rpc = BlockchainConfig::load(&datadir).map(|c| c.rpc).unwrap_or_default();
+    let host = cli.rpcconnect.unwrap_or(rpc.bind);
+    let port = cli.rpcport.unwrap_or(rpc.port);
+    let (user, password) = match (cli.rpcuser.or(rpc.user), cli.rpcpassword.or(rpc.password)) {
+        (Some(user), Some(password)) => (user, password),
+        _ => {
+            let cookie_path = cli.rpccookiefile.unwrap_or_else(|| datadir.join(COOKIE_FILE_NAME));
+            rpc_auth::read_cookie(&cookie_path)
+                .map_err(|e| format!("no RPC credentials configured and cannot read {}: {}", cookie_path.display(), e))?
+        }
+    };
+    // A node serving TLS usually has a self-signed certificate, so trust the configured one
+    let mut client = reqwest::Client::builder();
+    let scheme = match &rpc.tls_cert {
+        Some(cert) => {
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&std::fs::read(cert)?)?);
+            "https"
+        }
+        None => "http",
+    };
+
+    let params = cli.params.iter()
+        .map(|p| serde_json::from_str(p).unwrap_or_else(|_| Value::String(p.clone())))
+        .collect();
+    let request = RpcRequest { id: Value::from(1), method: cli.method, params };
+
+    let response = client.build()?
+        .post(format!("{}://{}:{}/", scheme, host, port))
+        .basic_auth(user, Some(password))
+        .json(&request)
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Err("the node rejected the RPC credentials".into());
+    }
+    let response: RpcResponse = response.json().await?;
+
+    if let Some(error) = response.error {
+        return Err(format!("{} (code {})", error.message, error.code).into());
+    }
+    match response.result {
+        Some(Value::String(s)) => println!("{}", s),
+        Some(result) => println!("{}", serde_json::to_string_pretty(&result)?),
+        None => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,896 @@
+use clap::{Parser, Subcommand};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use xcore::addrman::{self, AddrManager, PEERS_FILE_NAME};
+use xcore::blockchain::{Block, Blockchain, BlockHeader, ChainEvent};
+use xcore::chain_export::{self, ExportFormat};
+use xcore::chain_params::Network;
+use xcore::datadir::{self, DataDirLock};
+use xcore::disk_monitor::DiskState;
+use xcore::grpc::GrpcService;
+use xcore::hd_keys;
+use xcore::jobs::{JobManager, JobProgress};
+use xcore::light_client::{HeaderError, LightClient};
+use xcore::merkle::MerkleBranch;
+use xcore::mempool::{read_saved_fruits, Mempool, FRUITS_FILE_NAME};
+use xcore::net::PeerManager;
+use xcore::node_config::{self, BlockchainConfig, ConfigHandle, NodeMode};
+use xcore::outbound::{self, ANCHORS_FILE_NAME};
+use xcore::notifications::{self, NotificationPublisher};
+use xcore::policy::RelayPolicy;
+use xcore::protocol;
+use xcore::rpc::{RpcServer, MAX_HEADERS_PER_REQUEST};
+use xcore::rpc_auth::{self, RpcAuth};
+use xcore::scheduler::Scheduler;
+use xcore::snapshot::SNAPSHOTS_DIR_NAME;
+use xcore::storage::Storage;
+use xcore::transport::{parse_trusted_keys, NodeKey};
+use xcore::wallet::{Wallet, Wallets};
+
+const MEMPOOL_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+const PEERS_SAVE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Fruits carry mining work, so they're saved more often than peers and a crash loses little of it
+const FRUITS_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const WALLET_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Compaction debt in one column family past which the node compacts it rather than wait for RocksDB
+const COMPACTION_HINT_BYTES: u64 = 256 * 1024 * 1024;
+const METRICS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How often a read-only node looks for blocks the primary has written
+const READ_ONLY_CATCH_UP_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Parser)]
+#[command(name = "xcored", version, about = "xCore full node daemon")]
+struct Cli {
+    /// Data directory holding the configuration, block files and chainstate
+    #[arg(long, global = true)]
+    datadir: Option<PathBuf>,
+
+    /// Follow the data directory of a running node without writing to it, e.g. for explorers
+    #[arg(long, global = true)]
+    readonly: bool,
+
+    /// main, test, regtest, or the path of a TOML file defining a private network, overriding
+    /// the configured chain
+    #[arg(long, global = true)]
+    chain: Option<String>,
+
+    /// Before starting, validate and connect the blocks in another node's blocks directory or a
+    /// block archive; may be given more than once
+    #[arg(long)]
+    loadblock: Vec<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the node and its RPC server until stopped
+    Start,
+    /// Create the data directory and a default configuration file
+    Init,
+    /// Rebuild the UTXO set and height index from the stored blocks
+    Reindex,
+    /// Delete block files that only hold blocks far below the tip
+    Prune {
+        /// Number of most recent blocks that must stay on disk
+        #[arg(long, default_value_t = 288)]
+        keep_blocks: u64,
+    },
+    /// Write a range of the active chain to a portable block archive
+    ExportBlocks {
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// Last height to export, defaulting to the tip
+        #[arg(long)]
+        to: Option<u64>,
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Write a range of the active chain as blocks, transactions and outputs tables for analytics
+    Export {
+        /// parquet or csv
+        #[arg(long, default_value = "parquet")]
+        format: ExportFormat,
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// Last height to export, defaulting to the tip
+        #[arg(long)]
+        to: Option<u64>,
+        /// Directory to write the tables and their manifest into
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Validate and connect the blocks of an archive written by export-blocks
+    ImportBlocks {
+        #[arg(long = "in")]
+        input: PathBuf,
+    },
+    /// Re-read and re-check the most recent blocks and the UTXO set, reporting any corruption
+    #[command(alias = "verifychain")]
+    VerifyChain {
+        /// Number of blocks below and including the tip to check
+        #[arg(long, default_value_t = 6)]
+        depth: u64,
+        /// 0: block data, 1: structure and proof of work, 2: signatures, 3: UTXO set and undo data
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(0..=3))]
+        level: u8,
+    },
+    /// Create an HD wallet from a freshly generated mnemonic, printing the words to back up
+    CreateWallet {
+        name: String,
+        /// Mnemonic length, 12 or 24 words
+        #[arg(long, default_value_t = 12)]
+        words: usize,
+        /// BIP44 account to derive from, overriding wallet.account
+        #[arg(long)]
+        account: Option<u32>,
+        /// Unused addresses to look past when scanning, overriding wallet.gap_limit
+        #[arg(long)]
+        gap_limit: Option<u32>,
+    },
+    /// Create a wallet that watches public keys without holding their private keys
+    CreateWatchOnlyWallet {
+        name: String,
+        /// Hex public key to watch; may be given more than once
+        #[arg(long = "key", required = true)]
+        keys: Vec<String>,
+    },
+    /// Print the public key a multisig wallet from a mnemonic, read from stdin, would sign with,
+    /// to hand to the other cosigners
+    MultisigKey {
+        #[arg(long)]
+        account: Option<u32>,
+    },
+    /// Create an m-of-n multisig wallet from a mnemonic, read from stdin, and the other cosigners' keys
+    CreateMultisigWallet {
+        name: String,
+        /// Signatures needed to spend, counting this wallet's
+        #[arg(long)]
+        required: usize,
+        /// Hex public key of another cosigner, from their `multisig-key`; may be given more than once
+        #[arg(long = "cosigner", required = true)]
+        cosigners: Vec<String>,
+        #[arg(long)]
+        account: Option<u32>,
+    },
+    /// Recreate a wallet from its mnemonic, read from stdin, and scan the chain for its addresses
+    RestoreWallet {
+        name: String,
+        #[arg(long)]
+        account: Option<u32>,
+        #[arg(long)]
+        gap_limit: Option<u32>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let datadir = cli.datadir.unwrap_or_else(node_config::default_datadir);
+
+    if let Command::Init = cli.command {
+        return init(&datadir);
+    }
+
+    let mut config = BlockchainConfig::load(&datadir)?;
+    config.read_only |= cli.readonly;
+    match cli.chain.as_deref() {
+        None => {}
+        Some("main") => config.chain = Network::Main,
+        Some("test") => config.chain = Network::Test,
+        Some("regtest") => config.chain = Network::Regtest,
+        Some(path) => {
+            config.chain = Network::Custom;
+            config.chain_file = Some(std::path::absolute(path)?);
+        }
+    }
+    // The effective level is controlled through log::set_max_level so it can change on reload
+    env_logger::Builder::new().filter_level(log::LevelFilter::Trace).init();
+    if let Some(chain_file) = config.chain_file.as_ref().filter(|_| config.chain == Network::Custom) {
+        log::info!("Custom chain from {}, network magic {}", chain_file.display(), hex::encode(config.chain_params()?.magic));
+    }
+    // Held until exit; read-only instances exist precisely to share a directory with its owner
+    let _lock = if config.read_only {
+        None
+    } else {
+        let lock = DataDirLock::acquire(&datadir)?;
+        datadir::create_layout(&config)?;
+        Some(lock)
+    };
+    let config_handle = ConfigHandle::new(datadir, config.clone());
+
+    if config.mode == NodeMode::Light {
+        return match cli.command {
+            Command::Start => start_light(config).await,
+            _ => Err("this command needs a full node; this datadir is configured for light mode".into()),
+        };
+    }
+    if config.read_only && (!cli.loadblock.is_empty() || !matches!(cli.command, Command::Start | Command::ExportBlocks { .. } | Command::Export { .. } | Command::VerifyChain { .. } | Command::MultisigKey { .. })) {
+        return Err("this command modifies the data directory and can't run read-only".into());
+    }
+    // Creating a wallet doesn't touch the chain, so don't pay for opening it
+    if let Command::CreateWallet { name, words, account, gap_limit } = &cli.command {
+        return create_wallet(&config, name, *words, *account, *gap_limit);
+    }
+    if let Command::CreateWatchOnlyWallet { name, keys } = &cli.command {
+        Wallet::create_watch_only(&config.wallets_dir, name, parse_keys(keys)?)?;
+        println!("Created watch-only wallet '{}'; it scans the chain for its keys when the node starts", name);
+        return Ok(());
+    }
+    if let Command::MultisigKey { account } = &cli.command {
+        let (mnemonic, passphrase) = (hd_keys::parse_mnemonic(&prompt_line("Mnemonic: ")?)?, prompt_line("Passphrase (empty for none): ")?);
+        let key = Wallet::multisig_key(&mnemonic, &passphrase, config.chain_params()?.hd_coin_type, account.unwrap_or(config.wallet.account))?;
+        println!("{}", hex::encode(key));
+        return Ok(());
+    }
+    if let Command::CreateMultisigWallet { name, required, cosigners, account } = &cli.command {
+        let (mnemonic, passphrase) = (hd_keys::parse_mnemonic(&prompt_line("Mnemonic: ")?)?, prompt_line("Passphrase (empty for none): ")?);
+        let wallet = Wallet::create_multisig(
+            &config.wallets_dir, name, &mnemonic, &passphrase, config.chain_params()?.hd_coin_type,
+            account.unwrap_or(config.wallet.account), *required, parse_keys(cosigners)?,
+        )?;
+        let script = wallet.multisig_script().expect("created as a multisig wallet");
+        println!("Created {}-of-{} multisig wallet '{}' with address {}", script.required(), script.keys().len(), name, script.address());
+        return Ok(());
+    }
+
+    let blockchain = Arc::new(Blockchain::new(config.clone()).await?);
+
+    match cli.command {
+        Command::Start => {
+            for source in &cli.loadblock {
+                let result = blockchain.load_blocks(source).await;
+                blockchain.sync_block_files()?;
+                blockchain.flush_utxo_cache().await?;
+                let summary = result?;
+                log::info!(
+                    "Loaded {}: {} blocks connected, {} already known, {} invalid, {} without a parent",
+                    source.display(), summary.connected, summary.skipped, summary.invalid, summary.unconnected,
+                );
+            }
+            start(blockchain, config_handle).await
+        }
+        Command::Reindex => {
+            let progress = JobProgress::new();
+            // Ctrl-C stops between blocks; running reindex again carries on from there
+            tokio::spawn({
+                let progress = progress.clone();
+                async move {
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        progress.cancel();
+                    }
+                }
+            });
+            let blocks = blockchain.reindex(&progress).await?;
+            if progress.is_cancelled() {
+                log::info!("Reindex interrupted after {} blocks; run reindex again to finish", blocks);
+            } else {
+                log::info!("Reindexed {} blocks", blocks);
+            }
+            Ok(())
+        }
+        Command::Prune { keep_blocks } => {
+            let pruned = blockchain.prune(keep_blocks).await?;
+            log::info!("Pruned {} block files", pruned.len());
+            Ok(())
+        }
+        Command::ExportBlocks { from, to, out } => {
+            let to = match to.or(blockchain.get_chain_height()) {
+                Some(to) => to,
+                None => return Err("the chain is empty, nothing to export".into()),
+            };
+            let file = std::io::BufWriter::new(std::fs::File::create(&out)?);
+            let blocks = blockchain.export_blocks(from, to, file).await?;
+            log::info!("Exported {} blocks to {}", blocks, out.display());
+            Ok(())
+        }
+        Command::Export { format, from, to, out } => {
+            let to = match to.or(blockchain.get_chain_height()) {
+                Some(to) => to,
+                None => return Err("the chain is empty, nothing to export".into()),
+            };
+            let summary = chain_export::export_chain(&blockchain, from..=to, format, &out).await?;
+            log::info!(
+                "Exported {} blocks, {} transactions and {} outputs to {}",
+                summary.blocks, summary.transactions, summary.outputs, out.display(),
+            );
+            Ok(())
+        }
+        Command::ImportBlocks { input } => {
+            let file = std::io::BufReader::new(std::fs::File::open(&input)?);
+            let result = blockchain.import_blocks(file).await;
+            // Keep whatever connected before a failure
+            blockchain.sync_block_files()?;
+            blockchain.flush_utxo_cache().await?;
+            let summary = result?;
+            log::info!("Imported {} blocks, {} already known", summary.connected, summary.skipped);
+            Ok(())
+        }
+        Command::VerifyChain { depth, level } => {
+            let report = blockchain.verify_chain(depth, level, &JobProgress::new()).await?;
+            for problem in &report.problems {
+                log::error!("Height {}: {}", problem.height, problem.message);
+            }
+            if let Some(height) = report.pruned_at {
+                log::warn!("Stopped at height {}, which has been pruned", height);
+            }
+            if !report.is_ok() {
+                return Err(format!("found {} problems in the last {} blocks", report.problems.len(), report.checked).into());
+            }
+            log::info!("Verified {} blocks at level {}, no problems found", report.checked, level);
+            Ok(())
+        }
+        Command::RestoreWallet { name, account, gap_limit } => {
+            let phrase = prompt_line("Mnemonic: ")?;
+            let mnemonic = hd_keys::parse_mnemonic(&phrase)?;
+            let passphrase = prompt_line("Passphrase (empty for none): ")?;
+            let coin_type = blockchain.params().hd_coin_type;
+            let mut wallet = Wallet::create(
+                &config.wallets_dir, &name, &mnemonic, &passphrase, coin_type,
+                account.unwrap_or(config.wallet.account), gap_limit.unwrap_or(config.wallet.gap_limit),
+            )?;
+            let summary = wallet.sync(&blockchain).await?;
+            if summary.blocks_missing > 0 {
+                log::warn!("{} pruned blocks could not be scanned; outputs in them were missed", summary.blocks_missing);
+            }
+            log::info!(
+                "Restored wallet '{}': {} outputs found in {} blocks, next receive index {}, next change index {}",
+                name, summary.outputs_found, summary.blocks_scanned, summary.next_receive_index, summary.next_change_index,
+            );
+            Ok(())
+        }
+        Command::Init | Command::CreateWallet { .. } | Command::CreateWatchOnlyWallet { .. } | Command::MultisigKey { .. } | Command::CreateMultisigWallet { .. } => {
+            unreachable!("handled before the node is opened")
+        }
+    }
+}
+
+fn init(datadir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(datadir)?;
+    let config_path = datadir.join(node_config::CONFIG_FILE_NAME);
+    if config_path.exists() {
+        println!("{} already exists, leaving it untouched", config_path.display());
+    } else {
+        std::fs::write(&config_path, node_config::DEFAULT_CONFIG_TOML)?;
+        println!("Wrote default configuration to {}", config_path.display());
+    }
+    Ok(())
+}
+
+fn create_wallet(config: &BlockchainConfig, name: &str, words: usize, account: Option<u32>, gap_limit: Option<u32>) -> Result<(), Box<dyn std::error::Error>> {
+    let mnemonic = hd_keys::generate_mnemonic(words)?;
+    let passphrase = prompt_line("Passphrase (empty for none): ")?;
+    let coin_type = config.chain_params()?.hd_coin_type;
+    Wallet::create(
+        &config.wallets_dir, name, &mnemonic, &passphrase, coin_type,
+        account.unwrap_or(config.wallet.account), gap_limit.unwrap_or(config.wallet.gap_limit),
+    )?;
+    println!("Created wallet '{}'. Write down these words; with the passphrase they are the only way to restore it:", name);
+    println!("{}", mnemonic);
+    Ok(())
+}
+
+fn parse_keys(keys: &[String]) -> Result<Vec<[u8; 32]>, String> {
+    keys.iter()
+        .map(|key| hex::decode(key).ok().and_then(|bytes| <[u8; 32]>::try_from(bytes).ok()).ok_or_else(|| format!("'{}' is not a 32-byte hex public key", key)))
+        .collect()
+}
+
+// Catches every wallet up with the chain, then with the mempool
+async fn sync_wallets(blockchain: &Blockchain, mempool: &Mutex<Mempool>, wallets: &tokio::sync::Mutex<Wallets>) -> Result<(), String> {
+    let mut wallets = wallets.lock().await;
+    for wallet in wallets.iter_mut() {
+        let summary = wallet.sync(blockchain).await.map_err(|e| format!("wallet '{}': {}", wallet.name(), e))?;
+        if summary.outputs_found > 0 {
+            log::info!("Wallet '{}' received {} outputs in {} new blocks", wallet.name(), summary.outputs_found, summary.blocks_scanned);
+        }
+    }
+    let pool = mempool.lock().transactions().cloned().collect::<Vec<_>>();
+    for wallet in wallets.iter_mut() {
+        wallet.sync_mempool(&pool).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Offers a disconnected block's transactions back to the mempool, parents before children as the
+// block ordered them. Those the new branch already spent or confirmed fail on their inputs.
+async fn resubmit_disconnected(blockchain: &Blockchain, mempool: &Mutex<Mempool>, block: &Block, next_height: u64) {
+    let mut returned = 0;
+    for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+        let fee = blockchain
+            .package_fees(std::slice::from_ref(tx), |outpoint| {
+                let mempool = mempool.lock();
+                mempool.get_transaction(&outpoint.txid)?.outputs.get(outpoint.index as usize).cloned()
+            })
+            .await
+            .map_err(|e| e.to_string());
+        let outcome = fee.and_then(|fees| {
+            mempool.lock().add_transaction(tx.clone(), fees[0], next_height, blockchain.median_time_past()).map_err(|e| e.to_string())
+        });
+        match outcome {
+            Ok(()) => returned += 1,
+            Err(e) => log::debug!("Disconnected transaction {} stays out of the mempool: {}", hex::encode(tx.hash()), e),
+        }
+    }
+    if returned > 0 {
+        log::info!("Returned {} transactions of disconnected block {} to the mempool", returned, hex::encode(block.hash()));
+    }
+}
+
+// Reads one line from stdin, so secrets can be piped in rather than passed as arguments
+fn prompt_line(prompt: &str) -> std::io::Result<String> {
+    use std::io::Write;
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn start(blockchain: Arc<Blockchain>, config_handle: ConfigHandle) -> Result<(), Box<dyn std::error::Error>> {
+    let config = config_handle.get();
+    // Stays cancelled, so a stop requested before a task starts waiting still reaches it
+    let shutdown = CancellationToken::new();
+    let encryption = if config.network.encryption && !config.read_only {
+        // Operators share this key with peers that list us in network.trusted_keys
+        let node_key = NodeKey::load_or_generate(config_handle.datadir())?;
+        log::info!("Encrypted transport enabled, node key {}", node_key.public_key_hex());
+        Some((node_key, parse_trusted_keys(&config.network.trusted_keys)?))
+    } else {
+        None
+    };
+    let mempool = Arc::new(Mutex::new(Mempool::new(
+        config.mempool.size_limit_mb,
+        config.mempool.min_age_secs,
+        config.mempool.max_age_secs,
+        config.mempool.fruit_timeout_secs,
+        RelayPolicy::from_config(&config.mempool),
+        blockchain.params().fruit_freshness_window,
+        blockchain.params().fruit_bits,
+    )));
+
+    // Saved fruits whose block is no longer on the active chain, or that went stale while the node
+    // was down, are dropped as they're restored
+    let fruits_path = config_handle.datadir().join(FRUITS_FILE_NAME);
+    let persist_fruits = config.mempool.persist_fruits && !config.read_only;
+    if persist_fruits {
+        let saved = read_saved_fruits(&fruits_path);
+        if !saved.is_empty() {
+            let mut anchored = Vec::with_capacity(saved.len());
+            for fruit in saved {
+                let hang_from = fruit.fruit.block.fruit_header.as_ref().map(|header| header.hang_from);
+                let anchor_height = match hang_from {
+                    Some(hash) => blockchain.get_block_height(&hash).await?,
+                    None => None,
+                };
+                anchored.push((fruit, anchor_height));
+            }
+            let total = anchored.len();
+            let tip_height = blockchain.get_chain_height().unwrap_or(0);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            let restored = mempool.lock().restore_fruits(anchored, tip_height, now);
+            log::info!("Restored {} of {} saved fruits", restored, total);
+        }
+    }
+
+    if let Some(publisher) = NotificationPublisher::bind(&config.notifications)? {
+        tokio::spawn(notifications::run(publisher, blockchain.subscribe(), mempool.lock().subscribe()));
+    }
+
+    // Confirmed and double-spent transactions leave the pool, held ones enter it as they become final,
+    // and rejections are retried once the tip changes
+    tokio::spawn({
+        let blockchain = Arc::clone(&blockchain);
+        let mempool = Arc::clone(&mempool);
+        let mut events = blockchain.subscribe();
+        async move {
+            loop {
+                match events.recv().await {
+                    Ok(ChainEvent::BlockConnected { block, height }) => {
+                        let mut mempool = mempool.lock();
+                        mempool.transactions_confirmed(&block.transactions);
+                        mempool.update_tip(height + 1, blockchain.median_time_past());
+                    }
+                    // The disconnected block's height is that of the next block on the new tip
+                    Ok(ChainEvent::BlockDisconnected { block, height }) => {
+                        mempool.lock().block_disconnected(height, blockchain.median_time_past());
+                        resubmit_disconnected(&blockchain, &mempool, &block, height).await;
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        log::warn!("Mempool fell behind the chain and skipped {} events", missed);
+                        mempool.lock().tip_changed();
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+    });
+
+    // Mempool limits are among the settings a reload may change
+    tokio::spawn({
+        let mempool = Arc::clone(&mempool);
+        let mut updates = config_handle.subscribe();
+        async move {
+            while updates.changed().await.is_ok() {
+                let limits = updates.borrow().mempool.clone();
+                let mut mempool = mempool.lock();
+                mempool.set_size_limit_mb(limits.size_limit_mb);
+                mempool.set_age_limits(limits.min_age_secs, limits.max_age_secs);
+                mempool.set_policy(RelayPolicy::from_config(&limits));
+            }
+        }
+    });
+
+    // SIGHUP re-reads the configuration and applies the settings that don't need a restart
+    tokio::spawn({
+        let config_handle = config_handle.clone();
+        async move {
+            let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    log::warn!("SIGHUP config reload unavailable: {}", e);
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                match config_handle.reload() {
+                    Ok(report) => {
+                        log::info!("Configuration reloaded, applied: {:?}", report.applied);
+                        if !report.requires_restart.is_empty() {
+                            log::warn!("Changes to {:?} take effect after a restart", report.requires_restart);
+                        }
+                    }
+                    Err(e) => log::error!("Configuration reload failed, keeping current settings: {}", e),
+                }
+            }
+        }
+    });
+
+    let peers_path = config_handle.datadir().join(PEERS_FILE_NAME);
+    let addrman = Arc::new(Mutex::new(AddrManager::load(&peers_path)));
+    // An empty table is filled from the configured seeds; seeded addresses share one source so
+    // together they stay within a source group's share of the new table
+    if !config.read_only && addrman.lock().is_empty() {
+        let addrman = Arc::clone(&addrman);
+        let network = config.network.clone();
+        tokio::spawn(async move {
+            let mut seeds = addrman::resolve_dns_seeds(&network.dns_seeds, network.listen_port).await;
+            seeds.extend(addrman::resolve_seed_nodes(&network.seed_nodes).await);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            let added = addrman.lock().add(&seeds, std::net::Ipv4Addr::UNSPECIFIED.into(), now);
+            if added > 0 {
+                log::info!("Added {} peer addresses from seeds", added);
+            }
+        });
+    }
+
+    // A read-only node follows the primary's datadir rather than the network
+    let anchors_path = config_handle.datadir().join(ANCHORS_FILE_NAME);
+    let peers = if config.read_only {
+        None
+    } else {
+        // An empty chainstate, or one part way through a snapshot load, starts from peers' snapshots
+        let snapshot_sync = config.network.snapshot_sync && blockchain.get_chain_height().is_none();
+        let services = protocol::local_services(&config, snapshot_sync || blockchain.snapshot_base().await?.is_some());
+        let mut peers = PeerManager::new(Arc::clone(&blockchain), Arc::clone(&mempool), Arc::clone(&addrman), config.network.clone(), services, shutdown.clone())
+            .with_anchors(outbound::take_anchors(&anchors_path));
+        if config.network.serve_snapshots {
+            peers = peers.with_snapshots(config_handle.datadir().join(SNAPSHOTS_DIR_NAME));
+        }
+        if snapshot_sync {
+            peers = peers.with_snapshot_sync();
+        }
+        if let Some((node_key, trusted_keys)) = encryption {
+            peers = peers.with_encryption(node_key, trusted_keys);
+        }
+        let listener = tokio::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], config.network.listen_port))).await?;
+        log::info!("Listening for peers on {}", listener.local_addr()?);
+        let peers = Arc::new(peers);
+        Some((Arc::clone(&peers), tokio::spawn(peers.run(listener))))
+    };
+
+    let mut scheduler = Scheduler::new();
+    if config.read_only {
+        log::info!("Following {} read-only", config.db_path);
+        scheduler.schedule("catch_up", READ_ONLY_CATCH_UP_INTERVAL, {
+            let blockchain = Arc::clone(&blockchain);
+            move || {
+                let blockchain = Arc::clone(&blockchain);
+                async move { blockchain.refresh().await.map(|_| ()) }
+            }
+        });
+    } else {
+        // Persist dirty UTXO entries even when the memory budget isn't reached
+        scheduler.schedule("utxo_flush", Duration::from_secs(config.utxo_flush_interval_secs), {
+            let blockchain = Arc::clone(&blockchain);
+            move || {
+                let blockchain = Arc::clone(&blockchain);
+                async move {
+                    blockchain.flush_utxo_cache().await?;
+                    blockchain.sync_block_files()?;
+                    Ok::<_, Box<dyn std::error::Error>>(())
+                }
+            }
+        });
+        scheduler.schedule("disk_check", DISK_CHECK_INTERVAL, {
+            let blockchain = Arc::clone(&blockchain);
+            move || std::future::ready(blockchain.disk_monitor().check().map(|_| ()))
+        });
+        // Stop while there's still room to flush the UTXO cache and sync the block files
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            let mut disk = blockchain.disk_monitor().subscribe();
+            async move {
+                if disk.wait_for(|state| *state == DiskState::Critical).await.is_ok() {
+                    shutdown.cancel();
+                }
+            }
+        });
+        scheduler.schedule("peers_save", PEERS_SAVE_INTERVAL, {
+            let addrman = Arc::clone(&addrman);
+            let peers_path = peers_path.clone();
+            move || std::future::ready(addrman.lock().save(&peers_path))
+        });
+        scheduler.schedule("compaction", COMPACTION_CHECK_INTERVAL, {
+            let blockchain = Arc::clone(&blockchain);
+            move || {
+                let blockchain = Arc::clone(&blockchain);
+                async move {
+                    let stats = blockchain.database_stats().await?;
+                    for cf in stats.column_families.iter().filter(|cf| cf.pending_compaction_bytes > COMPACTION_HINT_BYTES) {
+                        log::info!("Compacting {} with {} bytes of compaction pending", cf.name, cf.pending_compaction_bytes);
+                        blockchain.compact_database(Some(cf.name)).await?;
+                    }
+                    Ok::<_, Box<dyn std::error::Error>>(())
+                }
+            }
+        });
+    }
+    scheduler.schedule("mempool_cleanup", MEMPOOL_CLEANUP_INTERVAL, {
+        let mempool = Arc::clone(&mempool);
+        move || {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            let removed = mempool.lock().cleanup_expired(now);
+            if removed > 0 {
+                log::debug!("Expired {} mempool entries", removed);
+            }
+            std::future::ready(Ok::<_, std::convert::Infallible>(()))
+        }
+    });
+    if persist_fruits {
+        scheduler.schedule("fruits_save", FRUITS_SAVE_INTERVAL, {
+            let mempool = Arc::clone(&mempool);
+            let fruits_path = fruits_path.clone();
+            move || std::future::ready(mempool.lock().save_fruits(&fruits_path))
+        });
+    }
+    scheduler.schedule("metrics", METRICS_INTERVAL, {
+        let blockchain = Arc::clone(&blockchain);
+        let mempool = Arc::clone(&mempool);
+        move || {
+            let blockchain = Arc::clone(&blockchain);
+            let mempool = Arc::clone(&mempool);
+            async move {
+                let utxos = blockchain.utxo_cache_stats().await;
+                let pool = mempool.lock().info();
+                log::info!(
+                    "Height {:?}, mempool {} transactions and {} fruits in {} bytes, UTXO cache hit rate {:.3} over {} flushes",
+                    blockchain.get_chain_height(), pool.size, pool.fruits, pool.usage, utxos.hit_rate(), utxos.flushes,
+                );
+                Ok::<_, std::convert::Infallible>(())
+            }
+        }
+    });
+
+    // Wallet databases belong to the primary node, so a read-only instance serves no wallets
+    let wallets = Arc::new(tokio::sync::Mutex::new(if config.read_only { Wallets::default() } else { Wallets::load(&config.wallets_dir)? }));
+    if !config.read_only {
+        scheduler.schedule("wallet_sync", WALLET_SYNC_INTERVAL, {
+            let blockchain = Arc::clone(&blockchain);
+            let mempool = Arc::clone(&mempool);
+            let wallets = Arc::clone(&wallets);
+            move || {
+                let blockchain = Arc::clone(&blockchain);
+                let mempool = Arc::clone(&mempool);
+                let wallets = Arc::clone(&wallets);
+                async move { sync_wallets(&blockchain, &mempool, &wallets).await }
+            }
+        });
+
+        // Between syncs wallets follow the chain block by block, so a reorg puts their transactions
+        // in the blocks it takes away back to pending and flags any it double-spends as it happens
+        tokio::spawn({
+            let blockchain = Arc::clone(&blockchain);
+            let wallets = Arc::clone(&wallets);
+            let mut events = blockchain.subscribe();
+            async move {
+                loop {
+                    let event = match events.recv().await {
+                        Ok(event) => event,
+                        // The next sync finds any blocks the missed events took away
+                        Err(RecvError::Lagged(missed)) => {
+                            log::warn!("Wallets fell behind the chain and skipped {} events", missed);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    let mut wallets = wallets.lock().await;
+                    for wallet in wallets.iter_mut() {
+                        if let Err(e) = wallet.chain_event(&blockchain, &event).await {
+                            log::error!("Wallet '{}' failed to follow the chain: {}", wallet.name(), e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    let cookie_name = if config.read_only { rpc_auth::READ_ONLY_COOKIE_FILE_NAME } else { rpc_auth::COOKIE_FILE_NAME };
+    let rpc_auth = Arc::new(RpcAuth::new(&config.rpc, &config_handle.datadir().join(cookie_name))?);
+
+    let grpc_handle = if config.grpc.enabled {
+        let grpc_addr: SocketAddr = format!("{}:{}", config.grpc.bind, config.grpc.port).parse()?;
+        let grpc_service = GrpcService::new(Arc::clone(&blockchain), Arc::clone(&mempool), Arc::clone(&rpc_auth));
+        let tls = match (&config.rpc.tls_cert, &config.rpc.tls_key) {
+            (Some(cert), Some(key)) => Some(tonic::transport::Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?)),
+            _ => None,
+        };
+        Some(tokio::spawn(grpc_service.serve(grpc_addr, tls, shutdown.clone())))
+    } else {
+        None
+    };
+
+    let rpc_addr: SocketAddr = format!("{}:{}", config.rpc.bind, config.rpc.port).parse()?;
+    let jobs = Arc::new(JobManager::new());
+    let rpc_server = RpcServer::new(Arc::clone(&blockchain), Arc::clone(&mempool), wallets, Arc::clone(&addrman), peers.as_ref().map(|(peers, _)| Arc::clone(peers)), Arc::clone(&jobs), config_handle, rpc_auth, shutdown.clone());
+    let rpc_handle = tokio::spawn(rpc_server.serve(rpc_addr));
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => shutdown.cancel(),
+        _ = shutdown.cancelled() => {}
+    }
+    log::info!("Shutting down");
+
+    // Stop the maintenance jobs and write out whatever is still dirty
+    scheduler.shutdown().await;
+    rpc_handle.await??;
+    if let Some((peers, peers_handle)) = peers {
+        peers_handle.await?;
+        outbound::save_anchors(&anchors_path, &peers.anchors())?;
+    }
+    if let Some(grpc_handle) = grpc_handle {
+        grpc_handle.await??;
+    }
+    // A running reindex or chain check stops at its next block
+    jobs.shutdown().await;
+    if !config.read_only {
+        addrman.lock().save(&peers_path)?;
+    }
+    if persist_fruits {
+        mempool.lock().save_fruits(&fruits_path)?;
+    }
+    blockchain.sync_block_files()?;
+    blockchain.flush_utxo_cache().await?;
+    let stats = blockchain.utxo_cache_stats().await;
+    log::info!("UTXO cache hit rate: {:.1}% over {} flushes", stats.hit_rate() * 100.0, stats.flushes);
+    let block_cache = blockchain.block_cache_stats();
+    log::info!("Block cache hit rate: {:.1}% over {} lookups", block_cache.hit_rate() * 100.0, block_cache.hits + block_cache.misses);
+
+    Ok(())
+}
+
+// Headers-only mode: follows the best header chain of a trusted full node over its RPC interface
+async fn start_light(config: BlockchainConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let source = config.light.source.clone().expect("validated when the configuration was loaded");
+    let storage = Storage::new(&config.db_path, &config.database).await?;
+    let mut light_client = LightClient::open(storage, config.chain_params()?).await?;
+    log::info!("Light client loaded {} headers", light_client.chain().height().map_or(0, |h| h + 1));
+
+    let mut watched = Vec::with_capacity(config.light.watch.len());
+    for watch in &config.light.watch {
+        let txid: [u8; 32] = hex::decode(&watch.txid)?.try_into().map_err(|_| "validated when the configuration was loaded")?;
+        let block_hash: [u8; 32] = hex::decode(&watch.block)?.try_into().map_err(|_| "validated when the configuration was loaded")?;
+        watched.push((txid, block_hash));
+    }
+    // Height each watched transaction was last confirmed at, to log only changes
+    let mut confirmed = HashMap::new();
+
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.light.poll_interval_secs));
+    // How far to step back when the source's chain no longer connects to ours
+    let mut rewind = 0;
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = interval.tick() => {}
+        }
+
+        let start = light_client.chain().height().map_or(0, |h| (h + 1).saturating_sub(rewind));
+        let headers = match fetch_headers(&client, &source, start).await {
+            Ok(headers) => headers,
+            Err(e) => {
+                log::warn!("Failed to fetch headers from {}: {}", source, e);
+                continue;
+            }
+        };
+
+        let mut disconnected = false;
+        match light_client.add_headers(&headers).await {
+            Ok(_) => {}
+            Err(e) if matches!(e.downcast_ref::<HeaderError>(), Some(HeaderError::UnknownParent(_))) => disconnected = true,
+            Err(e) => log::error!("Rejected headers from {}: {}", source, e),
+        }
+        rewind = if disconnected { (rewind * 2).max(1) } else { 0 };
+        if let Some(tip) = light_client.chain().tip() {
+            log::debug!("Header chain at height {} ({})", tip.height, hex::encode(tip.header.hash()));
+        }
+
+        for (txid, block_hash) in &watched {
+            let status = match fetch_proof(&client, &source, txid, block_hash).await {
+                Ok(branch) => light_client.verify_transaction(txid, block_hash, &branch).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match status {
+                Ok(height) if confirmed.insert(*txid, height) != Some(height) => {
+                    log::info!("Transaction {} confirmed in block {} at height {}", hex::encode(txid), hex::encode(block_hash), height);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    if confirmed.remove(txid).is_some() {
+                        log::warn!("Transaction {} is no longer confirmed: {}", hex::encode(txid), e);
+                    } else {
+                        log::debug!("Transaction {} not confirmed yet: {}", hex::encode(txid), e);
+                    }
+                }
+            }
+        }
+    }
+
+    log::info!("Shutting down");
+    Ok(())
+}
+
+// The source's merkle branch for `txid` in `block_hash`, to be checked against our own headers
+async fn fetch_proof(client: &reqwest::Client, source: &str, txid: &[u8; 32], block_hash: &[u8; 32]) -> Result<MerkleBranch, Box<dyn std::error::Error>> {
+    let request = json!({ "jsonrpc": "2.0", "id": 0, "method": "gettxoutproof", "params": [hex::encode(txid), hex::encode(block_hash)] });
+    let response: Value = client.post(source).json(&request).send().await?.json().await?;
+    if let Some(error) = response.get("error") {
+        return Err(format!("gettxoutproof failed: {}", error).into());
+    }
+    let result = &response["result"];
+    let index = result["index"].as_u64().ok_or("gettxoutproof returned no index")? as usize;
+    let leaf_count = result["leafcount"].as_u64().ok_or("gettxoutproof returned no leaf count")? as usize;
+    let path = result["path"].as_array().ok_or("gettxoutproof returned no path")?
+        .iter()
+        .map(|value| {
+            let bytes = hex::decode(value.as_str().ok_or("path entry is not a hex string")?)?;
+            Ok(<[u8; 32]>::try_from(bytes).map_err(|_| "path entry is not a 32-byte hash")?)
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+    Ok(MerkleBranch { index, leaf_count, path })
+}
+
+async fn fetch_headers(client: &reqwest::Client, source: &str, start: u64) -> Result<Vec<BlockHeader>, Box<dyn std::error::Error>> {
+    let request = json!({ "jsonrpc": "2.0", "id": 0, "method": "getheaders", "params": [start, MAX_HEADERS_PER_REQUEST] });
+    let response: Value = client.post(source).json(&request).send().await?.json().await?;
+    if let Some(error) = response.get("error") {
+        return Err(format!("getheaders failed: {}", error).into());
+    }
+    let encoded = response["result"].as_array().ok_or("getheaders returned no result")?;
+    encoded.iter()
+        .map(|value| {
+            let bytes = hex::decode(value.as_str().ok_or("header is not a hex string")?)?;
+            Ok(BlockHeader::decode(&bytes)?)
+        })
+        .collect()
+}
@@ -0,0 +1,213 @@
+use crate::blockchain::Block;
+use crate::chain_params::Network;
+use crate::codec::{self, CodecError};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+// Archive layout: magic, format version, network, height of the first block, then one record per
+// block of [payload length: u32 LE][codec-encoded block][first 8 bytes of its blake3 hash]. Records
+// carry the same encoding as the block files, so archives outlive any particular database.
+const ARCHIVE_MAGIC: [u8; 4] = *b"XCBA";
+const ARCHIVE_VERSION: u8 = 1;
+const CHECKSUM_LEN: usize = 8;
+// Bound on a single record so a corrupt length can't trigger a huge allocation
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Codec error: {0}")]
+    Codec(#[from] CodecError),
+    #[error("Not a block archive")]
+    BadMagic,
+    #[error("Unsupported archive version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown network id {0}")]
+    UnknownNetwork(u8),
+    #[error("Checksum mismatch in the record for height {0}")]
+    ChecksumMismatch(u64),
+    #[error("Record for height {height} claims {len} bytes, more than the {max} byte limit")]
+    RecordTooLarge { height: u64, len: usize, max: usize },
+    #[error("Archive ends partway through the record for height {0}")]
+    Truncated(u64),
+}
+
+fn network_id(network: Network) -> u8 {
+    match network {
+        Network::Main => 0,
+        Network::Test => 1,
+        Network::Regtest => 2,
+        // Archives don't say which custom chain; importing checks each block against this node's rules
+        Network::Custom => 3,
+    }
+}
+
+fn network_from_id(id: u8) -> Result<Network, ArchiveError> {
+    match id {
+        0 => Ok(Network::Main),
+        1 => Ok(Network::Test),
+        2 => Ok(Network::Regtest),
+        3 => Ok(Network::Custom),
+        _ => Err(ArchiveError::UnknownNetwork(id)),
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    blake3::hash(payload).as_bytes()[..CHECKSUM_LEN].try_into().expect("blake3 hashes are 32 bytes")
+}
+
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+    start_height: u64,
+    written: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut inner: W, network: Network, start_height: u64) -> Result<Self, ArchiveError> {
+        inner.write_all(&ARCHIVE_MAGIC)?;
+        inner.write_all(&[ARCHIVE_VERSION, network_id(network)])?;
+        inner.write_all(&start_height.to_le_bytes())?;
+        Ok(ArchiveWriter { inner, start_height, written: 0 })
+    }
+
+    pub fn write_block(&mut self, block: &Block) -> Result<(), ArchiveError> {
+        let payload = codec::encode(block)?;
+        if payload.len() > MAX_RECORD_LEN {
+            return Err(ArchiveError::RecordTooLarge { height: self.start_height + self.written, len: payload.len(), max: MAX_RECORD_LEN });
+        }
+        self.inner.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&payload)?;
+        self.inner.write_all(&checksum(&payload))?;
+        self.written += 1;
+        Ok(())
+    }
+
+    // Flushes and returns the underlying writer along with how many blocks were written
+    pub fn finish(mut self) -> Result<(W, u64), ArchiveError> {
+        self.inner.flush()?;
+        Ok((self.inner, self.written))
+    }
+}
+
+// Yields the archived blocks in order, stopping at the first damaged record
+pub struct ArchiveReader<R: Read> {
+    inner: R,
+    network: Network,
+    start_height: u64,
+    next_height: u64,
+    failed: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut inner: R) -> Result<Self, ArchiveError> {
+        let mut header = [0u8; 14];
+        inner.read_exact(&mut header).map_err(|_| ArchiveError::BadMagic)?;
+        if header[..4] != ARCHIVE_MAGIC {
+            return Err(ArchiveError::BadMagic);
+        }
+        if header[4] != ARCHIVE_VERSION {
+            return Err(ArchiveError::UnsupportedVersion(header[4]));
+        }
+        let network = network_from_id(header[5])?;
+        let start_height = u64::from_le_bytes(header[6..].try_into().expect("slice is 8 bytes"));
+        Ok(ArchiveReader { inner, network, start_height, next_height: start_height, failed: false })
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn start_height(&self) -> u64 {
+        self.start_height
+    }
+
+    fn read_record(&mut self) -> Result<Option<Block>, ArchiveError> {
+        let height = self.next_height;
+        let mut len = [0u8; 4];
+        // A clean end of file falls between records
+        match self.inner.read(&mut len[..1])? {
+            0 => return Ok(None),
+            _ => self.read_exact(&mut len[1..], height)?,
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(ArchiveError::RecordTooLarge { height, len, max: MAX_RECORD_LEN });
+        }
+
+        let mut payload = vec![0u8; len];
+        self.read_exact(&mut payload, height)?;
+        let mut expected = [0u8; CHECKSUM_LEN];
+        self.read_exact(&mut expected, height)?;
+        if checksum(&payload) != expected {
+            return Err(ArchiveError::ChecksumMismatch(height));
+        }
+        self.next_height += 1;
+        Ok(Some(Block::decode(&payload)?))
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8], height: u64) -> Result<(), ArchiveError> {
+        self.inner.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => ArchiveError::Truncated(height),
+            _ => ArchiveError::Io(e),
+        })
+    }
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+    type Item = Result<Block, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let record = self.read_record().transpose();
+        self.failed = matches!(record, Some(Err(_)));
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{BlockHeader, BlockType};
+    use crate::transaction::Transaction;
+
+    fn block(nonce: u64) -> Block {
+        Block {
+            header: BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [0; 32], fruits_root: [0; 32], timestamp: 0, bits: 0, nonce },
+            block_type: BlockType::Block,
+            fruit_header: None,
+            fruits: Vec::new(),
+            transactions: vec![Transaction { inputs: Vec::new(), outputs: Vec::new(), lock_time: 0 }],
+        }
+    }
+
+    #[test]
+    fn test_archive_round_trip_and_corruption() -> Result<(), ArchiveError> {
+        let mut writer = ArchiveWriter::new(Vec::new(), Network::Regtest, 7)?;
+        for nonce in 0..3 {
+            writer.write_block(&block(nonce))?;
+        }
+        let (bytes, written) = writer.finish()?;
+        assert_eq!(written, 3);
+
+        let reader = ArchiveReader::new(bytes.as_slice())?;
+        assert_eq!((reader.network(), reader.start_height()), (Network::Regtest, 7));
+        let nonces = reader.map(|b| b.map(|b| b.header.nonce)).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(nonces, vec![0, 1, 2]);
+
+        // A flipped byte in the last record's payload
+        let mut corrupt = bytes.clone();
+        let index = corrupt.len() - CHECKSUM_LEN - 1;
+        corrupt[index] ^= 1;
+        let results = ArchiveReader::new(corrupt.as_slice())?.collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert!(matches!(results[2], Err(ArchiveError::ChecksumMismatch(9))));
+
+        let truncated = ArchiveReader::new(&bytes[..bytes.len() - 2])?.last();
+        assert!(matches!(truncated, Some(Err(ArchiveError::Truncated(9)))));
+        assert!(matches!(ArchiveReader::new(&b"nope"[..]), Err(ArchiveError::BadMagic)));
+        Ok(())
+    }
+}
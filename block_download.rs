@@ -0,0 +1,307 @@
+use crate::blockchain::BlockHash;
+use crate::protocol::PeerId;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// Blocks asked of one peer at a time, so a staller holds up only a few
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+// A peer with blocks in flight that delivers none of them for this long is stalling
+pub const BLOCK_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+// Stalls a peer may cause before it is disconnected
+pub const MAX_STALLS: u32 = 3;
+
+struct PeerDownloads {
+    in_flight: HashSet<BlockHash>,
+    // When the peer last delivered a block, or was given work with nothing in flight
+    last_progress: Instant,
+    stalls: u32,
+}
+
+// What `check_stalls` wants done
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StallActions {
+    // Getdata to send: blocks taken from stalling peers, given to others that announced them
+    pub requests: Vec<(PeerId, Vec<BlockHash>)>,
+    // Peers that stalled MAX_STALLS times, already forgotten here
+    pub disconnect: Vec<PeerId>,
+}
+
+// Schedules block downloads over the peers that announced each block, and notices peers that stop
+// delivering. Without it one peer sitting on a request would halt initial block download for as
+// long as it stays connected. A stalled request goes to another peer that has the block, and the
+// staller isn't asked for that block again.
+pub struct BlockDownloads {
+    peers: HashMap<PeerId, PeerDownloads>,
+    // Peers known to have each block we still want
+    sources: HashMap<BlockHash, HashSet<PeerId>>,
+    in_flight: HashMap<BlockHash, PeerId>,
+    // Height of every block still wanted, whether queued or in flight
+    wanted: HashMap<BlockHash, u64>,
+    // Wanted but not in flight, because every peer that has them is busy or none is known yet.
+    // Lowest height first, so the chain can be connected as blocks arrive.
+    queued: BTreeSet<(u64, BlockHash)>,
+}
+
+impl BlockDownloads {
+    pub fn new() -> Self {
+        BlockDownloads { peers: HashMap::new(), sources: HashMap::new(), in_flight: HashMap::new(), wanted: HashMap::new(), queued: BTreeSet::new() }
+    }
+
+    pub fn peer_connected(&mut self, peer: PeerId, now: Instant) {
+        self.peers.insert(peer, PeerDownloads { in_flight: HashSet::new(), last_progress: now, stalls: 0 });
+    }
+
+    // The peer's blocks go back in the queue; the caller follows up with `schedule`
+    pub fn peer_disconnected(&mut self, peer: PeerId) {
+        for sources in self.sources.values_mut() {
+            sources.remove(&peer);
+        }
+        if let Some(downloads) = self.peers.remove(&peer) {
+            for hash in downloads.in_flight {
+                self.requeue(hash);
+            }
+        }
+    }
+
+    fn requeue(&mut self, hash: BlockHash) {
+        self.in_flight.remove(&hash);
+        if let Some(height) = self.wanted.get(&hash) {
+            self.queued.insert((*height, hash));
+        }
+    }
+
+    // `peer` announced `hash`, a block we don't have, by inv or headers
+    pub fn announced(&mut self, peer: PeerId, hash: BlockHash) {
+        if self.peers.contains_key(&peer) {
+            self.sources.entry(hash).or_default().insert(peer);
+        }
+    }
+
+    // Blocks to download, with their heights
+    pub fn want(&mut self, blocks: impl IntoIterator<Item = (u64, BlockHash)>) {
+        for (height, hash) in blocks {
+            if self.wanted.insert(hash, height).is_none() {
+                self.queued.insert((height, hash));
+            }
+        }
+    }
+
+    // Whether `hash` is being downloaded from anyone, so an announcement needn't trigger another getdata
+    pub fn is_in_flight(&self, hash: &BlockHash) -> bool {
+        self.in_flight.contains_key(hash)
+    }
+
+    // A block arrived and is about to be validated. Frees the peer's slot and counts as progress,
+    // but the block stays wanted until `validated`, so a bad body can be fetched again. False if it
+    // wasn't asked of this peer, which the caller may treat as unsolicited.
+    pub fn received(&mut self, peer: PeerId, hash: &BlockHash, now: Instant) -> bool {
+        if self.in_flight.get(hash) != Some(&peer) {
+            return false;
+        }
+        self.in_flight.remove(hash);
+        if let Some(downloads) = self.peers.get_mut(&peer) {
+            downloads.in_flight.remove(hash);
+            downloads.last_progress = now;
+        }
+        true
+    }
+
+    // The block passed validation, from whichever peer sent it, and is no longer wanted
+    pub fn validated(&mut self, hash: &BlockHash) {
+        if let Some(height) = self.wanted.remove(hash) {
+            self.queued.remove(&(height, *hash));
+        }
+        self.sources.remove(hash);
+        if let Some(holder) = self.in_flight.remove(hash) {
+            if let Some(downloads) = self.peers.get_mut(&holder) {
+                downloads.in_flight.remove(hash);
+            }
+        }
+    }
+
+    // The block `peer` sent failed validation. The peer isn't asked for it again, and unless
+    // another peer has it in flight it goes back in the queue; the caller follows up with `schedule`.
+    pub fn failed(&mut self, peer: PeerId, hash: &BlockHash) {
+        if let Some(sources) = self.sources.get_mut(hash) {
+            sources.remove(&peer);
+        }
+        if !self.in_flight.contains_key(hash) {
+            self.requeue(*hash);
+        }
+    }
+
+    // Gives queued blocks to peers that announced them and have room, least busy first. Returns
+    // the getdata to send to each.
+    pub fn schedule(&mut self, now: Instant) -> Vec<(PeerId, Vec<BlockHash>)> {
+        let mut load = self.peers.iter().map(|(peer, downloads)| (*peer, downloads.in_flight.len())).collect::<HashMap<_, _>>();
+        let mut room = load.values().map(|count| MAX_BLOCKS_IN_FLIGHT_PER_PEER.saturating_sub(*count)).sum::<usize>();
+        let mut assigned = Vec::new();
+        for &(height, hash) in &self.queued {
+            // Once every peer is full the rest of the queue can't be given out
+            if room == 0 {
+                break;
+            }
+            let Some(sources) = self.sources.get(&hash) else { continue };
+            let peer = sources.iter()
+                .filter_map(|peer| Some((*peer, *load.get(peer)?)))
+                .filter(|(_, count)| *count < MAX_BLOCKS_IN_FLIGHT_PER_PEER)
+                .min_by_key(|(peer, count)| (*count, *peer))
+                .map(|(peer, _)| peer);
+            let Some(peer) = peer else { continue };
+            *load.get_mut(&peer).expect("peer was just found") += 1;
+            room -= 1;
+            assigned.push((height, hash, peer));
+        }
+
+        let mut requests: HashMap<PeerId, Vec<BlockHash>> = HashMap::new();
+        for (height, hash, peer) in assigned {
+            let downloads = self.peers.get_mut(&peer).expect("peer was just found");
+            // The stall clock starts with the first block in flight, not when the peer connected
+            if downloads.in_flight.is_empty() {
+                downloads.last_progress = now;
+            }
+            downloads.in_flight.insert(hash);
+            self.in_flight.insert(hash, peer);
+            self.queued.remove(&(height, hash));
+            requests.entry(peer).or_default().push(hash);
+        }
+        let mut requests = requests.into_iter().collect::<Vec<_>>();
+        requests.sort_by_key(|(peer, _)| *peer);
+        requests
+    }
+
+    // Takes every block from peers that have delivered nothing for BLOCK_STALL_TIMEOUT and hands
+    // them to other peers, disconnecting those that have now stalled MAX_STALLS times
+    pub fn check_stalls(&mut self, now: Instant) -> StallActions {
+        let stalled = self.peers.iter()
+            .filter(|(_, downloads)| !downloads.in_flight.is_empty() && now.saturating_duration_since(downloads.last_progress) >= BLOCK_STALL_TIMEOUT)
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+        let mut actions = StallActions::default();
+        for peer in stalled {
+            let downloads = self.peers.get_mut(&peer).expect("stalled peer is connected");
+            downloads.stalls += 1;
+            let stalls = downloads.stalls;
+            for hash in std::mem::take(&mut downloads.in_flight) {
+                log::debug!("Peer {} stalled on block {}", peer, hex::encode(hash));
+                if let Some(sources) = self.sources.get_mut(&hash) {
+                    sources.remove(&peer);
+                }
+                self.requeue(hash);
+            }
+            if stalls >= MAX_STALLS {
+                log::info!("Disconnecting peer {} after {} block download stalls", peer, stalls);
+                self.peer_disconnected(peer);
+                actions.disconnect.push(peer);
+            }
+        }
+        actions.disconnect.sort_unstable();
+        actions.requests = self.schedule(now);
+        actions
+    }
+
+    pub fn stalls(&self, peer: PeerId) -> u32 {
+        self.peers.get(&peer).map_or(0, |downloads| downloads.stalls)
+    }
+
+    pub fn in_flight_count(&self, peer: PeerId) -> usize {
+        self.peers.get(&peer).map_or(0, |downloads| downloads.in_flight.len())
+    }
+}
+
+impl Default for BlockDownloads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(i: u8) -> BlockHash {
+        [i; 32]
+    }
+
+    #[test]
+    fn test_stalled_blocks_move_to_another_peer_and_stallers_are_dropped() {
+        let start = Instant::now();
+        let mut downloads = BlockDownloads::new();
+        downloads.peer_connected(1, start);
+        downloads.peer_connected(2, start);
+        for i in 0..20 {
+            downloads.announced(1, hash(i));
+        }
+        downloads.want((0..20).map(|i| (i as u64, hash(i))));
+
+        // Peer 1 is the only source, and is given blocks up to its limit
+        let requests = downloads.schedule(start);
+        assert_eq!(requests, vec![(1, (0..MAX_BLOCKS_IN_FLIGHT_PER_PEER as u8).map(hash).collect())]);
+        assert!(downloads.is_in_flight(&hash(0)) && !downloads.is_in_flight(&hash(19)));
+
+        // Delivering a block restarts the clock; a block asked of someone else doesn't count
+        let delivered = start + BLOCK_STALL_TIMEOUT / 2;
+        assert!(downloads.received(1, &hash(0), delivered));
+        downloads.validated(&hash(0));
+        assert!(!downloads.received(2, &hash(1), delivered));
+        downloads.validated(&hash(1));
+        let actions = downloads.check_stalls(start + BLOCK_STALL_TIMEOUT);
+        assert_eq!(actions.requests, vec![(1, vec![hash(16), hash(17)])]);
+        assert_eq!(downloads.stalls(1), 0);
+
+        // Once peer 1 stalls, its blocks go to peer 2, and it is only given blocks it hasn't
+        // stalled on
+        for i in 2..20 {
+            downloads.announced(2, hash(i));
+        }
+        let stalled_at = delivered + BLOCK_STALL_TIMEOUT;
+        let actions = downloads.check_stalls(stalled_at);
+        assert_eq!(actions.requests, vec![(1, vec![hash(18), hash(19)]), (2, (2..18).map(hash).collect())]);
+        assert!(actions.disconnect.is_empty());
+        assert_eq!(downloads.stalls(1), 1);
+
+        // A peer that keeps stalling is disconnected, and what only it had waits for another source
+        let mut now = stalled_at;
+        for stall in 2..=MAX_STALLS {
+            let fresh = hash(100 + stall as u8);
+            downloads.announced(1, fresh);
+            downloads.want([(100 + stall as u64, fresh)]);
+            downloads.schedule(now);
+            now += BLOCK_STALL_TIMEOUT;
+            let actions = downloads.check_stalls(now);
+            assert_eq!(actions.disconnect.contains(&1), stall == MAX_STALLS);
+            assert!(!downloads.is_in_flight(&fresh));
+        }
+        assert_eq!((downloads.stalls(1), downloads.in_flight_count(1)), (0, 0));
+    }
+
+    #[test]
+    fn test_blocks_stay_wanted_until_they_validate() {
+        let start = Instant::now();
+        let mut downloads = BlockDownloads::new();
+        downloads.peer_connected(1, start);
+        downloads.peer_connected(2, start);
+        downloads.announced(1, hash(0));
+        downloads.announced(2, hash(0));
+        downloads.want([(0, hash(0))]);
+        assert_eq!(downloads.schedule(start), vec![(1, vec![hash(0)])]);
+
+        // A bad body is fetched again from another peer, never from the one that sent it
+        assert!(downloads.received(1, &hash(0), start));
+        downloads.failed(1, &hash(0));
+        assert_eq!(downloads.schedule(start), vec![(2, vec![hash(0)])]);
+        assert!(downloads.received(2, &hash(0), start));
+        downloads.failed(2, &hash(0));
+        assert!(downloads.schedule(start).is_empty());
+
+        // Once a good body arrives, even unasked, the block is asked of no one
+        downloads.announced(1, hash(0));
+        assert_eq!(downloads.schedule(start), vec![(1, vec![hash(0)])]);
+        assert!(!downloads.received(2, &hash(0), start));
+        downloads.validated(&hash(0));
+        assert!(!downloads.is_in_flight(&hash(0)));
+        assert_eq!(downloads.in_flight_count(1), 0);
+        downloads.announced(2, hash(0));
+        assert!(downloads.schedule(start).is_empty());
+    }
+}
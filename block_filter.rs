@@ -0,0 +1,218 @@
+use crate::blockchain::{Block, BlockHash};
+use crate::transaction::OutPoint;
+use serde::{Serialize, Deserialize};
+
+// Golomb-Rice parameters from BIP158's basic filter: a false positive rate of about 1 in 784931
+pub const FILTER_P: u8 = 19;
+pub const FILTER_M: u64 = 784_931;
+
+pub type FilterHeader = [u8; 32];
+
+// Golomb-coded set over the output scripts a block creates and the outpoints it spends
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    pub element_count: u64,
+    pub encoded: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn build(block_hash: &BlockHash, elements: &[Vec<u8>]) -> Self {
+        let mut elements = elements.to_vec();
+        elements.sort_unstable();
+        elements.dedup();
+
+        let element_count = elements.len() as u64;
+        let mut values = elements.iter()
+            .map(|element| hash_to_range(block_hash, element, element_count))
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in values {
+            let delta = value - last;
+            last = value;
+            // Quotient in unary, remainder in FILTER_P bits
+            for _ in 0..(delta >> FILTER_P) {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, FILTER_P);
+        }
+
+        BlockFilter { element_count, encoded: writer.finish() }
+    }
+
+    pub fn for_block(block_hash: &BlockHash, block: &Block) -> Self {
+        Self::build(block_hash, &filter_elements(block))
+    }
+
+    // True if any query may be in the set; false positives are possible, false negatives are not
+    pub fn match_any(&self, block_hash: &BlockHash, queries: &[Vec<u8>]) -> bool {
+        if self.element_count == 0 || queries.is_empty() {
+            return false;
+        }
+        let mut targets = queries.iter()
+            .map(|query| hash_to_range(block_hash, query, self.element_count))
+            .collect::<Vec<_>>();
+        targets.sort_unstable();
+
+        let mut reader = BitReader::new(&self.encoded);
+        let mut targets = targets.into_iter().peekable();
+        let mut value = 0;
+        for _ in 0..self.element_count {
+            let delta = match reader.read_golomb() {
+                Some(delta) => delta,
+                None => return false,
+            };
+            value += delta;
+            while let Some(&target) = targets.peek() {
+                if target == value {
+                    return true;
+                }
+                if target > value {
+                    break;
+                }
+                targets.next();
+            }
+            if targets.peek().is_none() {
+                return false;
+            }
+        }
+        false
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        blake3::hash(&bincode::serialize(self).unwrap()).into()
+    }
+
+    // Filter headers chain every block's filter to its parent's so a client can check filters
+    // served by one peer against headers from another
+    pub fn header(&self, previous_header: &FilterHeader) -> FilterHeader {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.hash());
+        hasher.update(previous_header);
+        *hasher.finalize().as_bytes()
+    }
+}
+
+// Output scripts created by the block and the serialized outpoints its transactions spend
+pub fn filter_elements(block: &Block) -> Vec<Vec<u8>> {
+    let mut elements = Vec::new();
+    for tx in &block.transactions {
+        for output in &tx.outputs {
+            if !output.script_pubkey.is_empty() {
+                elements.push(output.script_pubkey.clone());
+            }
+        }
+        for input in &tx.inputs {
+            elements.push(outpoint_element(&input.previous_output));
+        }
+    }
+    elements
+}
+
+pub fn outpoint_element(outpoint: &OutPoint) -> Vec<u8> {
+    let mut element = outpoint.txid.to_vec();
+    element.extend_from_slice(&outpoint.index.to_be_bytes());
+    element
+}
+
+// Keyed by the block hash so collisions can't be precomputed across blocks
+fn hash_to_range(block_hash: &BlockHash, element: &[u8], element_count: u64) -> u64 {
+    let hash = blake3::keyed_hash(block_hash, element);
+    let value = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+    ((value as u128 * (element_count * FILTER_M) as u128) >> 64) as u64
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn read_golomb(&mut self) -> Option<u64> {
+        let mut quotient = 0;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0;
+        for _ in 0..FILTER_P {
+            remainder = (remainder << 1) | self.read_bit()? as u64;
+        }
+        Some((quotient << FILTER_P) | remainder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elements(count: usize, salt: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| blake3::hash(&[&i.to_le_bytes()[..], &[salt]].concat()).as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_filter_matches_every_element() {
+        let block_hash = [7; 32];
+        let members = elements(200, 0);
+        let filter = BlockFilter::build(&block_hash, &members);
+        assert_eq!(filter.element_count, 200);
+        for member in &members {
+            assert!(filter.match_any(&block_hash, std::slice::from_ref(member)));
+        }
+
+        let outsiders = elements(200, 1);
+        let false_positives = outsiders.iter()
+            .filter(|query| filter.match_any(&block_hash, std::slice::from_ref(*query)))
+            .count();
+        assert!(false_positives <= 1);
+        assert!(filter.match_any(&block_hash, &[outsiders[0].clone(), members[150].clone()]));
+    }
+
+    #[test]
+    fn test_empty_filter_matches_nothing() {
+        let filter = BlockFilter::build(&[0; 32], &[]);
+        assert!(filter.encoded.is_empty());
+        assert!(!filter.match_any(&[0; 32], &elements(3, 0)));
+    }
+}
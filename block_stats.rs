@@ -0,0 +1,124 @@
+use crate::blockchain::{Block, BlockHash, BlockUndo};
+use crate::transaction::OutPoint;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+// Percentiles of the fee rates paid in a block, weighted by transaction size
+pub const FEE_RATE_PERCENTILES: [u64; 5] = [10, 25, 50, 75, 90];
+
+// Aggregates of one connected block, for `getblockstats`. Computed once from the block and its
+// undo data and kept in the block stats column family, so they outlive pruning too.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockStats {
+    pub block_hash: BlockHash,
+    pub height: u64,
+    pub time: u64,
+    // Transaction figures leave out the coinbase
+    pub transactions: u64,
+    pub inputs: u64,
+    pub outputs: u64,
+    pub fruits: u64,
+    // Serialized size of the whole block, and of its transactions other than the coinbase
+    pub total_size: u64,
+    pub transactions_size: u64,
+    pub total_out: u64,
+    pub total_fees: u64,
+    // Fee units per byte
+    pub min_fee_rate: f64,
+    pub max_fee_rate: f64,
+    pub average_fee_rate: f64,
+    pub fee_rate_percentiles: [f64; 5],
+    pub subsidy: u64,
+}
+
+impl BlockStats {
+    pub fn compute(block: &Block, undo: &BlockUndo, height: u64, subsidy: u64) -> Result<Self, bincode::Error> {
+        let fees = transaction_fees(block, undo);
+        let mut stats = BlockStats {
+            block_hash: block.hash(),
+            height,
+            time: block.header.timestamp,
+            transactions: fees.len() as u64,
+            inputs: 0,
+            outputs: 0,
+            fruits: block.fruits.len() as u64,
+            total_size: bincode::serialized_size(block)?,
+            transactions_size: 0,
+            total_out: 0,
+            total_fees: fees.iter().sum(),
+            min_fee_rate: 0.0,
+            max_fee_rate: 0.0,
+            average_fee_rate: 0.0,
+            fee_rate_percentiles: [0.0; 5],
+            subsidy,
+        };
+
+        let mut rates = Vec::with_capacity(fees.len());
+        for (tx, fee) in block.transactions.iter().filter(|tx| !tx.is_coinbase()).zip(&fees) {
+            let size = bincode::serialized_size(tx)?;
+            stats.inputs += tx.inputs.len() as u64;
+            stats.outputs += tx.outputs.len() as u64;
+            stats.transactions_size += size;
+            stats.total_out += tx.outputs.iter().map(|output| output.value).sum::<u64>();
+            rates.push((*fee as f64 / size.max(1) as f64, size));
+        }
+        if rates.is_empty() {
+            return Ok(stats);
+        }
+        rates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        stats.min_fee_rate = rates[0].0;
+        stats.max_fee_rate = rates[rates.len() - 1].0;
+        stats.average_fee_rate = stats.total_fees as f64 / stats.transactions_size.max(1) as f64;
+        stats.fee_rate_percentiles = weighted_percentiles(&rates, stats.transactions_size);
+        Ok(stats)
+    }
+}
+
+// Fee of each transaction of a connected block but the coinbase, in block order. Coins spent from
+// earlier blocks are in its undo data; those created and spent within the block come from its own outputs.
+pub fn transaction_fees(block: &Block, undo: &BlockUndo) -> Vec<u64> {
+    let mut values = undo.spent_coins.iter().map(|(outpoint, coin)| (*outpoint, coin.output.value)).collect::<HashMap<_, _>>();
+    let mut fees = Vec::with_capacity(block.transactions.len().saturating_sub(1));
+    for tx in &block.transactions {
+        if !tx.is_coinbase() {
+            let input_value = tx.inputs.iter().filter_map(|input| values.get(&input.previous_output)).sum::<u64>();
+            let output_value = tx.outputs.iter().map(|output| output.value).sum::<u64>();
+            fees.push(input_value.saturating_sub(output_value));
+        }
+        let txid = tx.hash();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            values.insert(OutPoint { txid, index: index as u32 }, output.value);
+        }
+    }
+    fees
+}
+
+// `rates` are (fee rate, size) sorted by rate; each percentile is the rate paid by the
+// transaction holding the byte at that share of the total size
+fn weighted_percentiles(rates: &[(f64, u64)], total_size: u64) -> [f64; 5] {
+    let mut percentiles = [0.0; 5];
+    for (slot, percentile) in percentiles.iter_mut().zip(FEE_RATE_PERCENTILES) {
+        let threshold = total_size * percentile / 100;
+        let mut covered = 0;
+        *slot = rates.iter()
+            .find(|(_, size)| {
+                covered += size;
+                covered > threshold
+            })
+            .map_or(rates[rates.len() - 1].0, |(rate, _)| *rate);
+    }
+    percentiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles_are_weighted_by_size() {
+        // 100 bytes at rate 1, 300 at rate 5, 600 at rate 2
+        let rates = [(1.0, 100), (2.0, 600), (5.0, 300)];
+        assert_eq!(weighted_percentiles(&rates, 1000), [2.0, 2.0, 2.0, 5.0, 5.0]);
+        assert_eq!(weighted_percentiles(&[(3.0, 250)], 250), [3.0; 5]);
+    }
+}
@@ -0,0 +1,745 @@
+use crate::codec;
+use crate::lru_cache::LruCache;
+use crate::node_config::{BlockchainConfig, FsyncPolicy};
+use crate::storage::BlockLocation;
+use lz4::EncoderBuilder;
+use memmap2::Mmap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const BLOCK_FILE_PREFIX: &str = "block_file_";
+const BLOCK_FILE_SUFFIX: &str = ".dat.lz4";
+const UNDO_FILE_PREFIX: &str = "rev_";
+const LZ4_FRAME_MAGIC: u32 = 0x184d_2204;
+const MANIFEST_FILE_NAME: &str = "manifest";
+// Read-ahead of `BlockFileReader`
+const SEQUENTIAL_READ_BUFFER: usize = 4 * 1024 * 1024;
+// Block files kept mapped with `mmap_reads`, least recently read unmapped first
+const MAX_MAPPED_FILES: usize = 64;
+
+// What the blocks directory holds, saved whenever appended blocks are made durable. A file whose
+// length no longer matches its entry had blocks appended after the last save, so its heights are
+// unknown until `record_height` fills them in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BlockManifest {
+    current_file_index: u64,
+    files: BTreeMap<u64, BlockFileInfo>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockFileInfo {
+    pub size: u64,
+    pub blocks: u64,
+    // Blocks from forks count too, so the range may hold more than one block per height
+    pub first_height: u64,
+    pub last_height: u64,
+}
+
+impl BlockFileInfo {
+    fn add(&mut self, height: u64) {
+        if self.blocks == 0 {
+            self.first_height = height;
+            self.last_height = height;
+        } else {
+            self.first_height = self.first_height.min(height);
+            self.last_height = self.last_height.max(height);
+        }
+        self.blocks += 1;
+    }
+}
+
+// Safe to share between threads: appends serialise on the writer lock while reads open their own
+// file handles, since bytes that have been appended are never rewritten
+pub struct BlockStorage {
+    config: BlockchainConfig,
+    // Only advanced with the writer lock held, but readable without it
+    current_file_index: AtomicU64,
+    writer: Mutex<WriterState>,
+    // With `mmap_reads`: maps of the files read most recently. Decoded blocks are cached by the
+    // `Blockchain` above, so there is no cache of decompressed blocks here.
+    maps: Mutex<LruCache<String, Arc<Mmap>>>,
+}
+
+struct WriterState {
+    current_file_size: u64,
+    // End of the space reserved for the current file
+    allocated_until: u64,
+    // Files written since the last sync, and how many blocks went into them
+    unsynced_files: HashSet<String>,
+    // Files whose directory entries haven't been synced yet
+    created_files: HashSet<String>,
+    unsynced_blocks: u64,
+    last_sync: Instant,
+    manifest: BlockManifest,
+    // Files found without a manifest entry or grown since theirs was saved
+    stale_files: HashSet<u64>,
+}
+
+impl BlockStorage {
+    pub fn new(config: BlockchainConfig) -> io::Result<Self> {
+        if !config.read_only {
+            std::fs::create_dir_all(&config.blocks_dir)?;
+        }
+
+        let manifest_path = config.blocks_dir.join(MANIFEST_FILE_NAME);
+        let mut manifest = match std::fs::read(&manifest_path) {
+            Ok(bytes) => codec::decode::<BlockManifest>(&bytes).unwrap_or_else(|e| {
+                log::warn!("Ignoring corrupt {}: {}", manifest_path.display(), e);
+                BlockManifest::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BlockManifest::default(),
+            Err(e) => return Err(e),
+        };
+
+        // Check the manifest against the directory: files it doesn't know about or that grew after
+        // it was saved need their heights filled in, and the newest file is where appends resume
+        let mut stale_files = HashSet::new();
+        let mut on_disk = BTreeMap::new();
+        for entry in std::fs::read_dir(&config.blocks_dir)? {
+            let entry = entry?;
+            if let Some(index) = parse_file_index(&entry.file_name().to_string_lossy()) {
+                on_disk.insert(index, entry.metadata()?.len());
+            }
+        }
+        manifest.files.retain(|index, _| on_disk.contains_key(index));
+        for (&index, &size) in &on_disk {
+            let info = manifest.files.entry(index).or_default();
+            if info.size != size {
+                *info = BlockFileInfo { size, ..BlockFileInfo::default() };
+                stale_files.insert(index);
+            }
+        }
+        let current_file_index = manifest.current_file_index.max(on_disk.keys().next_back().copied().unwrap_or(1)).max(1);
+        manifest.current_file_index = current_file_index;
+        let current_file_size = on_disk.get(&current_file_index).copied().unwrap_or(0);
+
+        Ok(Self {
+            config,
+            current_file_index: AtomicU64::new(current_file_index),
+            writer: Mutex::new(WriterState {
+                current_file_size,
+                allocated_until: 0,
+                unsynced_files: HashSet::new(),
+                created_files: HashSet::new(),
+                unsynced_blocks: 0,
+                last_sync: Instant::now(),
+                manifest,
+                stale_files,
+            }),
+            maps: Mutex::new(LruCache::new(MAX_MAPPED_FILES)),
+        })
+    }
+
+    pub fn blocks_dir(&self) -> &Path {
+        &self.config.blocks_dir
+    }
+
+    // The file new blocks are currently appended to, which pruning must never delete
+    pub fn current_file(&self) -> String {
+        file_name(&self.config, self.current_file_index.load(Ordering::Acquire))
+    }
+
+    // `height` is the block's height, recorded in the manifest against the file it lands in
+    pub fn append_block_to_file(&self, block_data: &[u8], height: u64) -> io::Result<(String, u64)> {
+        self.check_writable()?;
+        // Compress before taking the lock so concurrent writers only contend on the write itself
+        let compressed = self.compress(block_data)?;
+
+        let mut writer = self.writer.lock();
+        if writer.current_file_size >= self.config.max_block_file_size {
+            let index = self.current_file_index.fetch_add(1, Ordering::AcqRel) + 1;
+            writer.manifest.current_file_index = index;
+            writer.current_file_size = 0;
+            writer.allocated_until = 0;
+        }
+        let file_name = self.current_file();
+        let (mut file, byte_offset) = self.open_for_append(&mut writer, &file_name)?;
+        writer.preallocate(&file, byte_offset, compressed.len() as u64, self.config.block_files.preallocate_mb);
+        file.write_all(&compressed)?;
+        writer.current_file_size = byte_offset + compressed.len() as u64;
+        let size = writer.current_file_size;
+        let info = writer.manifest.files.entry(self.current_file_index.load(Ordering::Acquire)).or_default();
+        info.add(height);
+        info.size = size;
+        writer.unsynced_blocks += 1;
+
+        self.finish_append(&mut writer, file, file_name, byte_offset)
+    }
+
+    // Undo records go to the rev file paired with the block's file so both are pruned together
+    pub fn append_undo_to_file(&self, block_file: &str, undo_data: &[u8]) -> io::Result<(String, u64)> {
+        self.check_writable()?;
+        let compressed = self.compress(undo_data)?;
+
+        let mut writer = self.writer.lock();
+        let file_name = undo_file_name(block_file);
+        let (mut file, byte_offset) = self.open_for_append(&mut writer, &file_name)?;
+        file.write_all(&compressed)?;
+
+        self.finish_append(&mut writer, file, file_name, byte_offset)
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.config.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "block storage is open read-only"));
+        }
+        Ok(())
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = EncoderBuilder::new()
+            .level(self.config.compression_level)
+            .build(Vec::with_capacity(data.len()))?;
+        encoder.write_all(data)?;
+        let (compressed, result) = encoder.finish();
+        result?;
+        Ok(compressed)
+    }
+
+    fn open_for_append(&self, writer: &mut WriterState, file_name: &str) -> io::Result<(File, u64)> {
+        if !std::path::Path::new(file_name).exists() {
+            writer.created_files.insert(file_name.to_string());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_name)?;
+        let byte_offset = file.seek(SeekFrom::End(0))?;
+        Ok((file, byte_offset))
+    }
+
+    // Undo records ride along with their block: they're synced with it but don't count towards a batch
+    fn finish_append(&self, writer: &mut WriterState, file: File, file_name: String, byte_offset: u64) -> io::Result<(String, u64)> {
+        if self.config.block_files.fsync == FsyncPolicy::PerBlock {
+            file.sync_data()?;
+            if !writer.created_files.is_empty() {
+                // The new directory entry must be durable too or the whole file can vanish
+                File::open(&self.config.blocks_dir)?.sync_all()?;
+                writer.created_files.clear();
+            }
+            writer.unsynced_blocks = 0;
+            writer.last_sync = Instant::now();
+            self.save_manifest(writer)?;
+        } else {
+            writer.unsynced_files.insert(file_name.clone());
+            if self.sync_due(writer) {
+                self.sync_locked(writer)?;
+            }
+        }
+
+        Ok((file_name, byte_offset))
+    }
+
+    fn sync_due(&self, writer: &WriterState) -> bool {
+        let block_files = &self.config.block_files;
+        match block_files.fsync {
+            FsyncPolicy::PerBlock => writer.unsynced_blocks > 0,
+            FsyncPolicy::Interval => writer.last_sync.elapsed() >= Duration::from_secs(block_files.fsync_interval_secs),
+            FsyncPolicy::Batch => writer.unsynced_blocks >= block_files.fsync_batch_blocks,
+        }
+    }
+
+    // Forces every block appended so far to disk; called on shutdown whatever the policy
+    pub fn sync(&self) -> io::Result<()> {
+        if self.config.read_only {
+            return Ok(());
+        }
+        self.sync_locked(&mut self.writer.lock())
+    }
+
+    fn sync_locked(&self, writer: &mut WriterState) -> io::Result<()> {
+        for file_name in writer.unsynced_files.drain() {
+            File::open(&file_name)?.sync_data()?;
+        }
+        File::open(&self.config.blocks_dir)?.sync_all()?;
+        writer.created_files.clear();
+        writer.unsynced_blocks = 0;
+        writer.last_sync = Instant::now();
+        self.save_manifest(writer)
+    }
+
+    // Written to a temporary file first so a crash never leaves a truncated manifest
+    fn save_manifest(&self, writer: &WriterState) -> io::Result<()> {
+        let path = self.config.blocks_dir.join(MANIFEST_FILE_NAME);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&codec::encode(&writer.manifest).map_err(io::Error::other)?)?;
+        file.sync_data()?;
+        std::fs::rename(tmp, path)
+    }
+
+    // Block files by index with their manifest entries, oldest first
+    pub fn files(&self) -> Vec<(String, BlockFileInfo)> {
+        let writer = self.writer.lock();
+        writer.manifest.files.iter().map(|(&index, info)| (file_name(&self.config, index), *info)).collect()
+    }
+
+    // Whether some files' heights are incomplete; see `record_height`
+    pub fn has_stale_files(&self) -> bool {
+        !self.writer.lock().stale_files.is_empty()
+    }
+
+    // Fills in the heights of files the manifest was stale for, from the blocks the index places in
+    // them; `finish_recording_heights` then saves the result
+    pub fn record_height(&self, file_name: &str, height: u64) {
+        let Some(index) = file_index(file_name) else { return };
+        let mut writer = self.writer.lock();
+        if writer.stale_files.contains(&index) {
+            writer.manifest.files.entry(index).or_default().add(height);
+        }
+    }
+
+    pub fn finish_recording_heights(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock();
+        writer.stale_files.clear();
+        self.save_manifest(&writer)
+    }
+
+    // Drops a pruned file from the manifest
+    pub fn forget_file(&self, file_name: &str) -> io::Result<()> {
+        self.maps.lock().remove(&file_name.to_string());
+        let Some(index) = file_index(file_name) else { return Ok(()) };
+        let mut writer = self.writer.lock();
+        writer.manifest.files.remove(&index);
+        writer.stale_files.remove(&index);
+        self.save_manifest(&writer)
+    }
+
+    pub fn read_block_from_file(&self, location: &BlockLocation) -> io::Result<Vec<u8>> {
+        if self.config.block_files.mmap_reads {
+            return self.read_mapped(location);
+        }
+        let mut file = File::open(&location.file_name)?;
+        file.seek(SeekFrom::Start(location.byte_offset))?;
+
+        let mut decoder = lz4::Decoder::new(file)?;
+        let mut decompressed_data = Vec::new();
+        decoder.read_to_end(&mut decompressed_data)?;
+
+        Ok(decompressed_data)
+    }
+
+    fn read_mapped(&self, location: &BlockLocation) -> io::Result<Vec<u8>> {
+        let offset = location.byte_offset as usize;
+        let mut map = self.map(&location.file_name, false)?;
+        // Blocks appended after the file was mapped lie past the end of the map
+        if map.get(offset..).and_then(lz4_frame_len).is_none() {
+            map = self.map(&location.file_name, true)?;
+        }
+        let frame = map.get(offset..).unwrap_or_default();
+        let len = lz4_frame_len(frame).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("no complete block at offset {} of {}", offset, location.file_name))
+        })?;
+        let mut decoder = lz4::Decoder::new(&frame[..len])?;
+        let mut decompressed_data = Vec::new();
+        decoder.read_to_end(&mut decompressed_data)?;
+        Ok(decompressed_data)
+    }
+
+    fn map(&self, file_name: &str, refresh: bool) -> io::Result<Arc<Mmap>> {
+        let mut maps = self.maps.lock();
+        if let Some(map) = maps.get(&file_name.to_string()).filter(|_| !refresh) {
+            return Ok(Arc::clone(map));
+        }
+        let file = File::open(file_name)?;
+        // SAFETY: block files are only ever appended to and are deleted whole, so bytes already
+        // mapped are never truncated or rewritten
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        maps.insert(file_name.to_string(), Arc::clone(&map), 1);
+        Ok(map)
+    }
+
+    // Undo records use the same framing as blocks
+    pub fn read_undo_from_file(&self, location: &BlockLocation) -> io::Result<Vec<u8>> {
+        self.read_block_from_file(location)
+    }
+}
+
+impl WriterState {
+    // Reserves disk space in `preallocate_mb` chunks ahead of the write position so block files
+    // don't fragment and a full disk shows up before a block is half written. The reservation
+    // doesn't change the file length, so appends and offsets are unaffected.
+    fn preallocate(&mut self, file: &File, offset: u64, incoming: u64, preallocate_mb: u64) {
+        let chunk = preallocate_mb * 1024 * 1024;
+        if chunk == 0 || offset + incoming <= self.allocated_until {
+            return;
+        }
+        let start = offset.max(self.allocated_until);
+        let length = (offset + incoming - start).div_ceil(chunk) * chunk;
+        match fallocate_keep_size(file, start, length) {
+            Ok(()) => self.allocated_until = start + length,
+            Err(e) => {
+                // Preallocation is an optimisation; the write itself will report a full disk
+                log::debug!("Block file preallocation failed: {}", e);
+                self.allocated_until = u64::MAX;
+            }
+        }
+    }
+}
+
+fn file_path(config: &BlockchainConfig, index: u64) -> PathBuf {
+    config.blocks_dir.join(format!("{}{}{}", BLOCK_FILE_PREFIX, index, BLOCK_FILE_SUFFIX))
+}
+
+fn file_name(config: &BlockchainConfig, index: u64) -> String {
+    file_path(config, index).to_str().unwrap().to_string()
+}
+
+// rev_<n>.dat.lz4 next to block_file_<n>.dat.lz4
+pub fn undo_file_name(block_file: &str) -> String {
+    let path = std::path::Path::new(block_file);
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let undo_name = match name.strip_prefix(BLOCK_FILE_PREFIX) {
+        Some(rest) => format!("{}{}", UNDO_FILE_PREFIX, rest),
+        None => format!("{}{}", UNDO_FILE_PREFIX, name),
+    };
+    path.with_file_name(undo_name).to_str().unwrap().to_string()
+}
+
+// Block files in `dir` in the order they were written, e.g. another node's blocks directory
+pub fn block_files_in(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(index) = parse_file_index(&entry.file_name().to_string_lossy()) {
+            files.push((index, entry.path()));
+        }
+    }
+    files.sort_unstable();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+// Every block in a block file, in append order, found by walking its lz4 frames since there is no
+// index to go by. A damaged frame ends the walk; blocks before it are still returned.
+pub fn read_block_file(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let data = std::fs::read(path)?;
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let Some(len) = lz4_frame_len(&data[offset..]) else {
+            log::warn!("{} has a damaged block at offset {}, ignoring the rest of the file", path.display(), offset);
+            break;
+        };
+        let mut decoder = lz4::Decoder::new(&data[offset..offset + len])?;
+        let mut block = Vec::new();
+        decoder.read_to_end(&mut block)?;
+        blocks.push(block);
+        offset += len;
+    }
+    Ok(blocks)
+}
+
+// Reads blocks at known locations through one open handle per file, buffering ahead so a run of
+// blocks stored one after another costs a few large reads. Skips the read cache, which a scan
+// over many blocks would only flush.
+pub struct BlockFileReader {
+    file: Option<(String, BufReader<File>)>,
+}
+
+impl BlockFileReader {
+    pub fn new() -> Self {
+        BlockFileReader { file: None }
+    }
+
+    pub fn read(&mut self, location: &BlockLocation) -> io::Result<Vec<u8>> {
+        if !matches!(&self.file, Some((name, _)) if *name == location.file_name) {
+            let reader = BufReader::with_capacity(SEQUENTIAL_READ_BUFFER, File::open(&location.file_name)?);
+            self.file = Some((location.file_name.clone(), reader));
+        }
+        let (_, reader) = self.file.as_mut().expect("file was just opened");
+        // Within the buffer when the block follows the one read last
+        let position = reader.stream_position()?;
+        reader.seek_relative(location.byte_offset as i64 - position as i64)?;
+        let mut decoder = lz4::Decoder::new(reader)?;
+        let mut block = Vec::new();
+        decoder.read_to_end(&mut block)?;
+        Ok(block)
+    }
+}
+
+impl Default for BlockFileReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Length of the lz4 frame at the start of `buf`, from its header and block size fields
+fn lz4_frame_len(buf: &[u8]) -> Option<usize> {
+    if u32::from_le_bytes(buf.get(..4)?.try_into().ok()?) != LZ4_FRAME_MAGIC {
+        return None;
+    }
+    let flags = *buf.get(4)?;
+    let block_checksums = flags & 0x10 != 0;
+    let content_size = flags & 0x08 != 0;
+    let content_checksum = flags & 0x04 != 0;
+    let dictionary_id = flags & 0x01 != 0;
+    // Magic, FLG and BD bytes, optional fields, then the header checksum byte
+    let mut pos = 6 + if content_size { 8 } else { 0 } + if dictionary_id { 4 } else { 0 } + 1;
+    loop {
+        let size = u32::from_le_bytes(buf.get(pos..pos + 4)?.try_into().ok()?);
+        pos += 4;
+        if size == 0 {
+            break;
+        }
+        // The high bit only marks a block stored uncompressed
+        pos += (size & 0x7fff_ffff) as usize + if block_checksums { 4 } else { 0 };
+    }
+    if content_checksum {
+        pos += 4;
+    }
+    (pos <= buf.len()).then_some(pos)
+}
+
+fn file_index(file_name: &str) -> Option<u64> {
+    parse_file_index(&Path::new(file_name).file_name()?.to_string_lossy())
+}
+
+fn parse_file_index(name: &str) -> Option<u64> {
+    name.strip_prefix(BLOCK_FILE_PREFIX)?.strip_suffix(BLOCK_FILE_SUFFIX)?.parse().ok()
+}
+
+#[cfg(target_os = "linux")]
+fn fallocate_keep_size(file: &File, offset: u64, length: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let result = unsafe {
+        libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, offset as libc::off_t, length as libc::off_t)
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fallocate_keep_size(_file: &File, _offset: u64, _length: u64) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "preallocation is only implemented on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config(datadir: &std::path::Path) -> BlockchainConfig {
+        let mut config = BlockchainConfig::load(datadir).unwrap();
+        config.max_block_file_size = 4096;
+        config.block_files.fsync = FsyncPolicy::PerBlock;
+        config.block_files.preallocate_mb = 1;
+        config
+    }
+
+    fn block(i: u32) -> Vec<u8> {
+        (0..512).map(|j| (i * 31 + j) as u8).collect()
+    }
+
+    // What a crash leaves behind: the files as they are on disk while the store is still open, before
+    // anything a shutdown would do
+    fn crash_copy(from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_committed_blocks_survive_crash() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let config = config(temp_dir.path());
+
+        let storage = BlockStorage::new(config.clone())?;
+        let mut locations = Vec::new();
+        for i in 0..20 {
+            let (file_name, byte_offset) = storage.append_block_to_file(&block(i), i as u64)?;
+            locations.push(BlockLocation { file_name, byte_offset });
+        }
+        // Only what append_block_to_file made durable may be relied on, so the store is reopened
+        // from the directory as it stands, without `sync` or a drop having run
+        let crashed_dir = TempDir::new()?;
+        let mut crashed = config.clone();
+        crashed.blocks_dir = crashed_dir.path().join("blocks");
+        crash_copy(&config.blocks_dir, &crashed.blocks_dir)?;
+        let on_crashed = |location: &BlockLocation| BlockLocation {
+            file_name: crashed.blocks_dir.join(Path::new(&location.file_name).file_name().unwrap()).to_str().unwrap().to_string(),
+            byte_offset: location.byte_offset,
+        };
+
+        // A torn write at the end of the last file must not disturb the blocks before it
+        let last_file = on_crashed(locations.last().unwrap()).file_name;
+        OpenOptions::new().append(true).open(last_file)?.write_all(&[0x04, 0x22, 0x4d])?;
+
+        let reopened = BlockStorage::new(crashed.clone())?;
+        for (i, location) in locations.iter().enumerate() {
+            assert_eq!(reopened.read_block_from_file(&on_crashed(location))?, block(i as u32));
+        }
+        // The manifest was saved with every block, so only the torn file needs its heights rebuilt
+        let in_last_file = locations.iter().filter(|location| location.file_name == locations[19].file_name).count() as u64;
+        assert!(reopened.has_stale_files());
+        assert_eq!(reopened.files().iter().map(|(_, info)| info.blocks).sum::<u64>(), 20 - in_last_file);
+
+        // Walking the files without the index finds the same blocks, in order
+        let walked = block_files_in(&crashed.blocks_dir)?.iter()
+            .map(|path| read_block_file(path))
+            .collect::<io::Result<Vec<_>>>()?
+            .concat();
+        assert_eq!(walked, (0..20).map(block).collect::<Vec<_>>());
+        drop(storage);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_records_pair_with_their_block_file() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let mut config = config(temp_dir.path());
+        config.block_files.fsync = FsyncPolicy::Batch;
+        config.block_files.fsync_batch_blocks = 2;
+        let storage = BlockStorage::new(config)?;
+
+        let (block_file, _) = storage.append_block_to_file(&block(0), 0)?;
+        let (undo_file, byte_offset) = storage.append_undo_to_file(&block_file, &block(100))?;
+        assert_eq!(undo_file, undo_file_name(&block_file));
+        assert!(Path::new(&undo_file).file_name().unwrap().to_string_lossy().starts_with(UNDO_FILE_PREFIX));
+        assert_eq!(storage.read_undo_from_file(&BlockLocation { file_name: undo_file, byte_offset })?, block(100));
+        // An undo record isn't a block, so the batch still waits for a second block
+        assert_eq!(storage.writer.lock().unsynced_blocks, 1);
+        storage.append_block_to_file(&block(1), 1)?;
+        assert_eq!(storage.writer.lock().unsynced_blocks, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preallocation_keeps_file_length() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let storage = BlockStorage::new(config(temp_dir.path()))?;
+        let (file_name, byte_offset) = storage.append_block_to_file(&block(0), 0)?;
+        let (_, second_offset) = storage.append_block_to_file(&block(1), 1)?;
+
+        // The reserved megabyte must not show up as file length or the second block would land after it
+        assert_eq!(byte_offset, 0);
+        assert!(second_offset > 0 && second_offset < 1024);
+        assert!(std::fs::metadata(&file_name)?.len() < 2048);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_appends_and_reads() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let storage = std::sync::Arc::new(BlockStorage::new(config(temp_dir.path()))?);
+
+        let writers = (0..4u32)
+            .map(|t| {
+                let storage = std::sync::Arc::clone(&storage);
+                std::thread::spawn(move || {
+                    (0..25u32)
+                        .map(|i| {
+                            let data = block(t * 100 + i);
+                            let (file_name, byte_offset) = storage.append_block_to_file(&data, i as u64).unwrap();
+                            // Read back immediately while the other writers keep appending
+                            let location = BlockLocation { file_name, byte_offset };
+                            assert_eq!(storage.read_block_from_file(&location).unwrap(), data);
+                            (location, data)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let written = writers.into_iter().flat_map(|w| w.join().unwrap()).collect::<Vec<_>>();
+        let mut locations = written.iter().map(|(location, _)| (location.file_name.clone(), location.byte_offset)).collect::<Vec<_>>();
+        locations.sort();
+        locations.dedup();
+        assert_eq!(locations.len(), 100);
+
+        // A reopened store resumes after the newest file instead of reusing the first one
+        let current = storage.current_file();
+        drop(storage);
+        assert_eq!(BlockStorage::new(config(temp_dir.path()))?.current_file(), current);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mapped_reads_follow_appends() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let mut config = config(temp_dir.path());
+        config.max_block_file_size = 1024 * 1024;
+        config.block_files.mmap_reads = true;
+        let storage = BlockStorage::new(config)?;
+
+        let (file_name, byte_offset) = storage.append_block_to_file(&block(0), 0)?;
+        let first = BlockLocation { file_name, byte_offset };
+        assert_eq!(storage.read_block_from_file(&first)?, block(0));
+        // Appended after the file was mapped, so the read has to map it again
+        let (file_name, byte_offset) = storage.append_block_to_file(&block(1), 1)?;
+        assert_eq!(file_name, first.file_name);
+        assert_eq!(storage.read_block_from_file(&BlockLocation { file_name, byte_offset })?, block(1));
+        assert_eq!(storage.read_block_from_file(&first)?, block(0));
+        assert_eq!(storage.maps.lock().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mapped_files_are_bounded() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let mut config = config(temp_dir.path());
+        // One block per file
+        config.max_block_file_size = 1;
+        config.block_files.mmap_reads = true;
+        let storage = BlockStorage::new(config)?;
+        let count = MAX_MAPPED_FILES as u32 + 8;
+        let mut locations = Vec::new();
+        for i in 0..count {
+            let (file_name, byte_offset) = storage.append_block_to_file(&block(i), i as u64)?;
+            locations.push(BlockLocation { file_name, byte_offset });
+        }
+        for (i, location) in locations.iter().enumerate() {
+            assert_eq!(storage.read_block_from_file(location)?, block(i as u32));
+        }
+        assert_eq!(storage.maps.lock().len(), MAX_MAPPED_FILES);
+        // The least recently read files were unmapped and map again on demand
+        assert_eq!(storage.read_block_from_file(&locations[0])?, block(0));
+        assert_eq!(storage.maps.lock().len(), MAX_MAPPED_FILES);
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_keeps_file_heights() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let config = config(temp_dir.path());
+        let storage = BlockStorage::new(config.clone())?;
+        for i in 0..40 {
+            storage.append_block_to_file(&block(i), 100 + i as u64)?;
+        }
+        let files = storage.files();
+        assert!(files.len() > 1);
+        assert_eq!(files.iter().map(|(_, info)| info.blocks).sum::<u64>(), 40);
+        assert_eq!((files[0].1.first_height, files.last().unwrap().1.last_height), (100, 139));
+        drop(storage);
+
+        let reopened = BlockStorage::new(config.clone())?;
+        assert_eq!(reopened.files(), files);
+        assert!(!reopened.has_stale_files());
+        drop(reopened);
+
+        // A file that grew after the manifest was saved has unknown heights until they're recorded
+        let last = &files.last().unwrap().0;
+        OpenOptions::new().append(true).open(last)?.write_all(&[0])?;
+        let reopened = BlockStorage::new(config.clone())?;
+        assert!(reopened.has_stale_files() && reopened.files().last().unwrap().1.blocks == 0);
+        reopened.record_height(last, 139);
+        reopened.finish_recording_heights()?;
+        assert_eq!(reopened.files().last().unwrap().1.last_height, 139);
+        drop(reopened);
+
+        // File indexes aren't limited to 32 bits
+        let far = file_name(&config, u32::MAX as u64 + 1);
+        File::create(&far)?;
+        let (appended, _) = BlockStorage::new(config)?.append_block_to_file(&block(40), 140)?;
+        assert_eq!(appended, far);
+        Ok(())
+    }
+}
@@ -0,0 +1,2824 @@
+use crate::address_index::{self, AddressEvent, AddressUtxo};
+use crate::block_archive::{ArchiveReader, ArchiveWriter};
+use crate::block_filter::{BlockFilter, FilterHeader};
+use crate::block_stats::BlockStats;
+use crate::block_storage::{self, undo_file_name, BlockFileReader, BlockStorage};
+use crate::chain_params::{ChainParams, UTXO_COMMITMENT_DEPLOYMENT};
+use crate::codec::{self, CodecError};
+use crate::disk_monitor::{DiskMonitor, DiskState};
+use crate::header_index::{HeaderIndex, IndexedHeader};
+use crate::jobs::JobProgress;
+use crate::light_client::HeaderChain;
+use crate::lru_cache::LruCache;
+use crate::merkle::{self, MerkleBranch};
+use crate::network_time::{self, NetworkTime};
+use crate::node_config::{BlockchainConfig, IndexConfig};
+use crate::pow;
+use crate::protocol;
+use crate::reward::{self, MinerPayout, RewardError};
+use crate::snapshot::{Snapshot, SnapshotBuilder, SnapshotChunk, SnapshotCommitment, SnapshotError, SnapshotLoad, SnapshotLoadProgress, SnapshotManifest};
+use crate::spent_index::{self, SpentInfo};
+use crate::storage::{self, BlockLocation, DatabaseStats, KeyValue, ScanError, Storage, CF_ADDRESS_INDEX, CF_ADDRESS_UTXOS, CF_BLOCK_FILTERS, CF_BLOCK_HEIGHTS, CF_BLOCK_STATS, CF_FRUIT_INDEX, CF_HEIGHT_INDEX, CF_META, CF_SPENT_INDEX, CF_UTXO, META_ADDRESS_INDEX_TIP, META_INVALID_BLOCKS, META_MIGRATION_HEIGHT, META_REINDEX_TARGET, META_SNAPSHOT_BASE, META_SNAPSHOT_MANIFEST, META_SNAPSHOT_PROGRESS, META_SPENT_INDEX_TIP, META_UTXO_BEST_BLOCK, META_UTXO_SET_SUMMARY, SCHEMA_VERSION};
+use crate::transaction::{Coin, OutPoint, Transaction, TxHash, TxOutput};
+use crate::utxo_cache::{UtxoCache, UtxoCacheStats, UtxoView};
+use crate::validation::{self, BlockValidator, SignatureCheck, ValidationError};
+use crate::versionbits::{ThresholdState, VersionBitsTracker};
+use blake3;
+use parking_lot::{Mutex, RwLock};
+use primitive_types::U256;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+pub type BlockHash = [u8; 32];
+
+// Number of recent blocks whose timestamps make up the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// Cap on blocks `load_blocks` holds while they wait for their parent
+const MAX_PENDING_LOAD_BLOCKS: usize = 10_000;
+// Events a slow subscriber can fall behind by before it starts missing them
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+// The node is in initial block download until its tip is at most this old
+pub const MAX_TIP_AGE_SECS: u64 = 24 * 60 * 60;
+// ... and until its best header is at most this many blocks ahead of the tip
+pub const MAX_HEADERS_AHEAD_OF_TIP: u64 = 24;
+// Blocks timestamped further than this past network-adjusted time are refused
+pub const MAX_FUTURE_BLOCK_TIME_SECS: u64 = 2 * 60 * 60;
+// Blocks `connect_blocks` checks signatures for ahead of the one it is connecting
+pub(crate) const SIGNATURE_PIPELINE_DEPTH: usize = 16;
+// How often paused bulk sync looks for freed disk space
+const DISK_RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+// Blocks between reindex progress reports
+const REINDEX_PROGRESS_INTERVAL: u64 = 10_000;
+// Blocks below the tip checked against the UTXO set after the database needed repairing
+const REPAIR_VERIFY_DEPTH: u64 = 288;
+// Blocks `stream_blocks` locates before reading them together on a blocking thread
+const BLOCK_STREAM_BATCH: usize = 16;
+// Decoded blocks `stream_blocks` keeps ready ahead of its consumer
+const BLOCK_STREAM_READ_AHEAD: usize = 64;
+// Numbers the secondary directories of read-only chainstates opened by this process
+static SECONDARY_INSTANCES: AtomicU64 = AtomicU64::new(0);
+
+// Headers written before the version field existed read back as this version
+pub const LEGACY_HEADER_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    // Deployment signals when the top bits are VERSIONBITS_TOP_BITS
+    pub version: u32,
+    pub previous_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
+    // Commits to the fruits a block includes, so headers alone authenticate the whole block
+    pub fruits_root: [u8; 32],
+    pub timestamp: u64,
+    pub bits: u32,
+    pub nonce: u64,
+}
+
+// Header layout before codec format 2
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacyBlockHeader {
+    previous_hash: [u8; 32],
+    merkle_root: [u8; 32],
+    fruits_root: [u8; 32],
+    timestamp: u64,
+    bits: u32,
+    nonce: u64,
+}
+
+impl From<LegacyBlockHeader> for BlockHeader {
+    fn from(legacy: LegacyBlockHeader) -> Self {
+        BlockHeader {
+            version: LEGACY_HEADER_VERSION,
+            previous_hash: legacy.previous_hash,
+            merkle_root: legacy.merkle_root,
+            fruits_root: legacy.fruits_root,
+            timestamp: legacy.timestamp,
+            bits: legacy.bits,
+            nonce: legacy.nonce,
+        }
+    }
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> BlockHash {
+        blake3::hash(&self.hashed_bytes()).into()
+    }
+
+    // Legacy headers hash without the version so blocks mined before it keep their hashes
+    fn hashed_bytes(&self) -> Vec<u8> {
+        if self.version == LEGACY_HEADER_VERSION {
+            let legacy = LegacyBlockHeader {
+                previous_hash: self.previous_hash,
+                merkle_root: self.merkle_root,
+                fruits_root: self.fruits_root,
+                timestamp: self.timestamp,
+                bits: self.bits,
+                nonce: self.nonce,
+            };
+            return bincode::serialize(&legacy).unwrap();
+        }
+        bincode::serialize(self).unwrap()
+    }
+
+    // The hashed bytes up to the nonce, which comes last as 8 little-endian bytes. Hashers absorb
+    // these once and then only hash each nonce on top.
+    pub fn nonce_prefix(&self) -> Vec<u8> {
+        let mut bytes = self.hashed_bytes();
+        bytes.truncate(bytes.len() - 8);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, CodecError> {
+        match codec::split_version(bytes)? {
+            (1, payload) => Ok(codec::decode_payload::<LegacyBlockHeader>(payload)?.into()),
+            (_, payload) => codec::decode_payload(payload),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockType {
+    Block,
+    Fruit,
+}
+
+// Identifies a fruit and the recent block it hangs from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FruitHeader {
+    pub hang_from: BlockHash,
+    pub miner_public_key: [u8; 32],
+    pub timestamp: u64,
+    pub nonce: u64,
+}
+
+// The header, fruit header and each fruit and transaction are framed, so any of them can gain
+// fields without breaking older decoders
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    #[serde(with = "codec::framed")]
+    pub header: BlockHeader,
+    pub block_type: BlockType,
+    // Present only on fruits
+    #[serde(with = "codec::framed")]
+    pub fruit_header: Option<FruitHeader>,
+    // Fruits included by a full block
+    #[serde(with = "codec::framed_seq")]
+    pub fruits: Vec<FruitHeader>,
+    #[serde(with = "codec::framed_seq")]
+    pub transactions: Vec<Transaction>,
+}
+
+// Format 2 layout, before a block's parts were framed
+#[derive(Deserialize)]
+struct UnframedBlock {
+    header: BlockHeader,
+    block_type: BlockType,
+    fruit_header: Option<FruitHeader>,
+    fruits: Vec<FruitHeader>,
+    transactions: Vec<Transaction>,
+}
+
+impl From<UnframedBlock> for Block {
+    fn from(unframed: UnframedBlock) -> Self {
+        Block {
+            header: unframed.header,
+            block_type: unframed.block_type,
+            fruit_header: unframed.fruit_header,
+            fruits: unframed.fruits,
+            transactions: unframed.transactions,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LegacyBlock {
+    header: LegacyBlockHeader,
+    block_type: BlockType,
+    fruit_header: Option<FruitHeader>,
+    fruits: Vec<FruitHeader>,
+    transactions: Vec<Transaction>,
+}
+
+impl From<LegacyBlock> for Block {
+    fn from(legacy: LegacyBlock) -> Self {
+        Block {
+            header: legacy.header.into(),
+            block_type: legacy.block_type,
+            fruit_header: legacy.fruit_header,
+            fruits: legacy.fruits,
+            transactions: legacy.transactions,
+        }
+    }
+}
+
+// Coins a block spent from the UTXO set, in spending order, so disconnecting it can put them back
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlockUndo {
+    #[serde(with = "codec::framed_seq")]
+    pub spent_coins: Vec<(OutPoint, Coin)>,
+}
+
+// Undo records written before they were versioned: plain bincode with unframed coins
+#[derive(Deserialize)]
+struct LegacyBlockUndo {
+    spent_coins: Vec<(OutPoint, Coin)>,
+}
+
+// Tags a versioned undo record. A legacy record starts with its coin count instead, which would
+// have to be in the billions to collide.
+const UNDO_MAGIC: [u8; 4] = *b"undo";
+
+impl BlockUndo {
+    pub fn encode(&self) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = UNDO_MAGIC.to_vec();
+        bytes.extend(codec::encode(self)?);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, CodecError> {
+        match bytes.strip_prefix(&UNDO_MAGIC) {
+            Some(record) => codec::decode(record),
+            None => {
+                let legacy: LegacyBlockUndo = bincode::deserialize(bytes)?;
+                Ok(BlockUndo { spent_coins: legacy.spent_coins })
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedBlock {
+    pub block: Block,
+    pub public_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+impl FruitHeader {
+    pub fn hash(&self) -> [u8; 32] {
+        blake3::hash(&bincode::serialize(self).unwrap()).into()
+    }
+}
+
+impl Block {
+    // A block is identified by its header, which commits to everything else through the roots
+    pub fn hash(&self) -> BlockHash {
+        self.header.hash()
+    }
+
+    // A fruit's header roots don't cover its fruit header, so a fruit is identified by both: two
+    // fruits differing only in what they hang from or who mined them must not share an identity
+    pub fn fruit_id(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.hash());
+        if let Some(fruit_header) = &self.fruit_header {
+            hasher.update(&fruit_header.hash());
+        }
+        hasher.finalize().into()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, CodecError> {
+        match codec::split_version(bytes)? {
+            (1, payload) => Ok(codec::decode_payload::<LegacyBlock>(payload)?.into()),
+            (2, payload) => Ok(codec::decode_payload::<UnframedBlock>(payload)?.into()),
+            (_, payload) => codec::decode_payload(payload),
+        }
+    }
+
+    // Proof that `txid` is committed to by this block's merkle root
+    pub fn transaction_proof(&self, txid: &TxHash) -> Option<MerkleBranch> {
+        let leaves = self.transactions.iter().map(Transaction::hash).collect::<Vec<_>>();
+        let index = leaves.iter().position(|hash| hash == txid)?;
+        MerkleBranch::new(&leaves, index)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainTip {
+    pub hash: BlockHash,
+    // `None` until the first block is connected
+    pub height: Option<u64>,
+}
+
+impl ChainTip {
+    fn empty() -> Self {
+        ChainTip { hash: [0; 32], height: None } // Initialize with genesis block hash
+    }
+}
+
+// Changes to the active chain, in the order they were made
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    BlockConnected { block: Arc<Block>, height: u64 },
+    BlockDisconnected { block: Arc<Block>, height: u64 },
+}
+
+pub struct Blockchain {
+    params: ChainParams,
+    storage: Storage,
+    block_storage: BlockStorage,
+    validator: BlockValidator,
+    utxo_cache: tokio::sync::Mutex<UtxoCache>,
+    block_cache: Mutex<BlockCache>,
+    chain_tip: Arc<RwLock<ChainTip>>,
+    // Headers of every block in the chainstate, for ancestor and median time lookups without the database
+    headers: RwLock<HeaderIndex>,
+    recent_fruits: RwLock<RecentFruits>,
+    versionbits: RwLock<VersionBitsTracker>,
+    read_only: bool,
+    // Optional indexes maintained alongside the chain
+    indexes: IndexConfig,
+    events: broadcast::Sender<ChainEvent>,
+    // Latches to false once the tip is recent, so a stall later doesn't send the node back into IBD
+    initial_block_download: AtomicBool,
+    // Set from the start of a reindex until it reaches the old tip; blocks can't be connected meanwhile
+    reindex_pending: AtomicBool,
+    // Held while a block is connected or disconnected, so two blocks can't both be checked against
+    // the same tip and then both connected
+    connect_lock: tokio::sync::Mutex<()>,
+    // Fed with peer clock samples by the networking layer
+    network_time: Arc<NetworkTime>,
+    disk_monitor: Arc<DiskMonitor>,
+}
+
+// Fruits included by the last `fruit_freshness_window` blocks of the active chain. A fruit can only
+// be included while it is fresh, so this is every earlier inclusion a new block could duplicate.
+#[derive(Default)]
+struct RecentFruits {
+    blocks: VecDeque<Vec<[u8; 32]>>,
+    included: HashSet<[u8; 32]>,
+}
+
+impl RecentFruits {
+    fn push_back(&mut self, fruits: &[FruitHeader], window: u64) {
+        let hashes = fruits.iter().map(FruitHeader::hash).collect::<Vec<_>>();
+        self.included.extend(&hashes);
+        self.blocks.push_back(hashes);
+        if self.blocks.len() as u64 > window {
+            self.pop_front();
+        }
+    }
+
+    fn pop_front(&mut self) {
+        for hash in self.blocks.pop_front().unwrap_or_default() {
+            self.included.remove(&hash);
+        }
+    }
+
+    fn pop_back(&mut self) {
+        for hash in self.blocks.pop_back().unwrap_or_default() {
+            self.included.remove(&hash);
+        }
+    }
+
+    fn push_front(&mut self, fruits: &[FruitHeader]) {
+        let hashes = fruits.iter().map(FruitHeader::hash).collect::<Vec<_>>();
+        self.included.extend(&hashes);
+        self.blocks.push_front(hashes);
+    }
+}
+
+// Why a known fruit was left out of a block template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FruitExclusion {
+    // Its header's hash doesn't meet the fruit target
+    InsufficientWork,
+    // Hangs from a block that is unknown, off the active chain or outside the freshness window
+    Stale,
+    AlreadyIncluded,
+    Duplicate,
+    // Policy: the template already holds `max_fruits_per_block` fruits
+    BlockFull,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FruitSelection {
+    pub included: Vec<FruitHeader>,
+    pub excluded: Vec<(FruitHeader, FruitExclusion)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ImportSummary {
+    // Accepted, whether onto the active chain or kept on a fork
+    pub connected: u64,
+    // Already on the active chain or stored
+    pub skipped: u64,
+    // Rejected by validation; `load_blocks` carries on past them
+    pub invalid: u64,
+    // Still waiting for a parent when the source ran out
+    pub unconnected: u64,
+}
+
+// Decoded blocks by hash, weighed by their encoded size, so the tip, the blocks a reorg would
+// disconnect and whatever RPC clients keep asking for skip the disk and the decoder
+struct BlockCache {
+    blocks: LruCache<BlockHash, Arc<Block>>,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl BlockCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+// Outcome of `verify_chain`. Problems are collected rather than returned as errors so a single
+// pass reports every damaged block.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    pub level: u8,
+    pub checked: u64,
+    // Lowest height checked; the check runs down from the tip
+    pub lowest_height: Option<u64>,
+    // Height of the first pruned block reached, where the check had to stop
+    pub pruned_at: Option<u64>,
+    // Stopped by a cancel request before `depth` blocks were checked
+    pub cancelled: bool,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    // A cancelled check vouches for nothing below where it stopped
+    pub fn is_ok(&self) -> bool {
+        !self.cancelled && self.problems.is_empty()
+    }
+
+    fn problem(&mut self, height: u64, message: String) {
+        self.problems.push(VerifyProblem { height, message });
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyProblem {
+    pub height: u64,
+    pub message: String,
+}
+
+// Activity over the last `blocks` blocks of the active chain, from `getchainstats`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChainStats {
+    pub start_height: u64,
+    pub end_height: u64,
+    pub blocks: u64,
+    pub average_block_interval_secs: f64,
+    pub target_block_interval_secs: u64,
+    // Hashes per second needed to produce the window's work in the time it took
+    pub network_hashrate: f64,
+    // Blocks with cached stats or whose body and undo data are still stored; fruit, fee and
+    // transaction figures cover only these
+    pub blocks_with_data: u64,
+    pub fruits_per_block: f64,
+    pub average_fees: f64,
+    // Excluding coinbases
+    pub transactions: u64,
+    pub transactions_per_second: f64,
+}
+
+// The UTXO set as of `best_block`, for comparing chainstate between nodes
+#[derive(Debug, Clone)]
+pub struct UtxoSetInfo {
+    pub height: Option<u64>,
+    pub best_block: BlockHash,
+    pub coins: u64,
+    pub total_amount: u64,
+    pub hash: [u8; 32],
+}
+
+// How a chain tip from `chain_tips` stands against the active chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TipStatus {
+    Active,
+    // Every block of its branch is stored and was valid when last connected
+    ValidFork,
+    // Some block of its branch is marked invalid, or descends from one that is
+    Invalid,
+    // Some block of its branch has no stored data, having been pruned
+    HeadersOnly,
+}
+
+impl TipStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TipStatus::Active => "active",
+            TipStatus::ValidFork => "valid-fork",
+            TipStatus::Invalid => "invalid",
+            TipStatus::HeadersOnly => "headers-only",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainTipInfo {
+    pub hash: BlockHash,
+    pub height: u64,
+    // Blocks between the tip and the active chain; 0 for the active tip
+    pub branch_len: u64,
+    pub status: TipStatus,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeploymentInfo {
+    pub name: &'static str,
+    pub bit: u8,
+    pub start_time: u64,
+    pub timeout: u64,
+    // State for the block after the tip
+    pub state: ThresholdState,
+    // Blocks signalling so far in the current period
+    pub count: u64,
+    pub period: u64,
+    pub threshold: u64,
+}
+
+impl Blockchain {
+    pub async fn new(config: BlockchainConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let storage = if config.read_only {
+            let instance = SECONDARY_INSTANCES.fetch_add(1, Ordering::Relaxed);
+            let secondary_path = std::env::temp_dir().join(format!("xcore-secondary-{}-{}", std::process::id(), instance));
+            Storage::open_secondary(&config.db_path, &secondary_path, &config.database).await?
+        } else {
+            Self::open_storage(&config).await?
+        };
+        let block_storage = BlockStorage::new(config.clone())?;
+        let params = config.chain_params()?;
+        let validator = BlockValidator::new(config.verification_threads, params.clone())?;
+        let utxo_cache = tokio::sync::Mutex::new(UtxoCache::new(
+            storage.clone(),
+            config.utxo_cache_mb,
+            config.utxo_flush_interval_secs,
+        ));
+        let block_cache = Mutex::new(BlockCache { blocks: LruCache::new(config.block_cache_mb * 1024 * 1024), hits: 0, misses: 0 });
+        let tip = if config.read_only {
+            Self::flushed_tip(&storage).await?.unwrap_or_else(ChainTip::empty)
+        } else {
+            match storage.get_best_height().await? {
+                Some((height, hash)) => ChainTip { hash, height: Some(height) },
+                None => ChainTip::empty(),
+            }
+        };
+        let chain_tip = Arc::new(RwLock::new(tip));
+        let versionbits = VersionBitsTracker::new(params.deployments.clone(), params.signalling_period, params.signalling_threshold);
+        let blockchain = Self {
+            params,
+            storage,
+            block_storage,
+            validator,
+            utxo_cache,
+            block_cache,
+            chain_tip,
+            headers: RwLock::new(HeaderIndex::new()),
+            recent_fruits: RwLock::new(RecentFruits::default()),
+            versionbits: RwLock::new(versionbits),
+            read_only: config.read_only,
+            indexes: config.indexes.clone(),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            initial_block_download: AtomicBool::new(true),
+            reindex_pending: AtomicBool::new(false),
+            connect_lock: tokio::sync::Mutex::new(()),
+            network_time: Arc::new(NetworkTime::new()),
+            disk_monitor: Arc::new(DiskMonitor::new(&config)),
+        };
+        blockchain.migrate_storage_format().await?;
+        blockchain.load_utxo_set_summary().await?;
+        blockchain.load_header_index().await?;
+        blockchain.load_recent_fruits().await?;
+        blockchain.load_versionbits().await?;
+        blockchain.load_indexes().await?;
+        blockchain.recover_chainstate().await?;
+        blockchain.index_block_file_heights().await?;
+        Ok(blockchain)
+    }
+
+    // Opens the chainstate, repairing it if damaged. If even repair fails the damaged database is
+    // moved aside, and the block files with it for `recover_chainstate` to reconnect.
+    async fn open_storage(config: &BlockchainConfig) -> Result<Storage, Box<dyn std::error::Error>> {
+        let error = match Storage::new(&config.db_path, &config.database).await {
+            Err(e) if storage::is_corruption(&*e) => e,
+            result => return result,
+        };
+        log::error!("Chainstate database could not be repaired ({}); rebuilding it from the block files", error);
+        let damaged = format!("{}.corrupt-{}", config.db_path, network_time::local_time());
+        std::fs::rename(&config.db_path, &damaged)?;
+        log::warn!("Damaged database moved to {}; delete it once the rebuild finishes", damaged);
+
+        let recovery_dir = recovery_blocks_dir(&config.blocks_dir);
+        if recovery_dir.exists() {
+            // An earlier rebuild was interrupted; its source is still there and the blocks directory
+            // only holds copies of some of it
+            std::fs::remove_dir_all(&config.blocks_dir)?;
+        } else if config.blocks_dir.exists() {
+            std::fs::rename(&config.blocks_dir, &recovery_dir)?;
+        }
+        Storage::new(&config.db_path, &config.database).await
+    }
+
+    // Fills in the block file heights the manifest lost: it was missing, written by an older
+    // version, or not saved after the last blocks went in before a crash
+    async fn index_block_file_heights(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.read_only || !self.block_storage.has_stale_files() {
+            return Ok(());
+        }
+        if let Some(best) = self.get_chain_height() {
+            log::info!("Indexing block file heights up to {}", best);
+            for (height, hash) in self.get_block_hashes(0..best + 1).await?.iter().enumerate() {
+                if let Some(location) = self.storage.retrieve_block_location(hash).await? {
+                    self.block_storage.record_height(&location.file_name, height as u64);
+                }
+            }
+        }
+        Ok(self.block_storage.finish_recording_heights()?)
+    }
+
+    // Finishes recovering from a damaged database. Block files `open_storage` moved aside are
+    // reconnected, and kept until every block in them is back. After a repair, the top of the chain
+    // is checked and the node reindexes if the repair lost data. Interrupted rebuilds resume on the
+    // next start, skipping blocks already connected.
+    async fn recover_chainstate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.read_only {
+            return Ok(());
+        }
+        if self.storage.get_meta(META_REINDEX_TARGET).await?.is_some() {
+            self.reindex_pending.store(true, Ordering::SeqCst);
+            log::warn!("A reindex was stopped at height {:?}; run reindex again to finish it", self.get_chain_height());
+            return Ok(());
+        }
+        let recovery_dir = recovery_blocks_dir(self.block_storage.blocks_dir());
+        if recovery_dir.exists() {
+            log::info!("Rebuilding the chainstate from the block files in {}", recovery_dir.display());
+            let result = self.load_blocks(&recovery_dir).await;
+            self.sync_block_files()?;
+            self.flush_utxo_cache().await?;
+            let summary = result?;
+            log::info!(
+                "Rebuilt the chainstate to height {:?}: {} blocks connected, {} invalid, {} without a parent",
+                self.get_chain_height(), summary.connected, summary.invalid, summary.unconnected,
+            );
+            // The directory may hold the only copy of the blocks that didn't connect
+            if summary.invalid > 0 || summary.unconnected > 0 {
+                return Err(format!(
+                    "{} blocks in {} could not be reconnected; the directory is kept so they aren't lost. Add the missing blocks to it, or remove it to carry on from height {:?}",
+                    summary.invalid + summary.unconnected, recovery_dir.display(), self.get_chain_height(),
+                ).into());
+            }
+            std::fs::remove_dir_all(&recovery_dir)?;
+        } else if self.storage.was_repaired() || !self.tip_undo_is_readable().await? {
+            match self.verify_chain(REPAIR_VERIFY_DEPTH, 3, &JobProgress::new()).await {
+                Ok(report) if report.is_ok() => return Ok(()),
+                Ok(report) => {
+                    for problem in &report.problems {
+                        log::warn!("Chainstate at height {}: {}", problem.height, problem.message);
+                    }
+                }
+                Err(e) => log::warn!("Could not check the chainstate: {}", e),
+            }
+            log::warn!("Chainstate is inconsistent, reindexing from the block files");
+            let blocks = self.reindex(&JobProgress::new()).await?;
+            log::info!("Reindexed {} blocks", blocks);
+        }
+        Ok(())
+    }
+
+    // A crash after a block's undo location was stored but before the undo record reached the disk
+    // leaves a tip that can never be disconnected. A tip whose block isn't stored has nothing to undo.
+    async fn tip_undo_is_readable(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let tip = *self.chain_tip.read();
+        if tip.height.is_none() || self.storage.retrieve_block_location(&tip.hash).await?.is_none() {
+            return Ok(true);
+        }
+        let readable = self.get_undo(&tip.hash).await.ok().flatten().is_some();
+        if !readable {
+            log::warn!("Chain tip {} has no readable undo data", hex::encode(tip.hash));
+        }
+        Ok(readable)
+    }
+
+    // Databases written before SCHEMA_VERSION 1 hold plain bincode blocks; rewrite them in the
+    // versioned encoding. Progress is recorded per block so an interrupted run picks up where it stopped.
+    // Schema 2 adds the version field to the header index; format 1 block files decode as they are.
+    async fn migrate_storage_format(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let schema = self.storage.get_schema_version().await?.unwrap_or(0);
+        if schema >= SCHEMA_VERSION {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(format!("chainstate uses schema {} and needs migrating to {}; start the node read-write once", schema, SCHEMA_VERSION).into());
+        }
+
+        if let Some(best) = self.get_chain_height().filter(|_| schema < 1) {
+            let resume_from = match self.storage.get_meta(META_MIGRATION_HEIGHT).await? {
+                Some(bytes) => u64::from_be_bytes(bytes.as_slice().try_into()?) + 1,
+                None => 0,
+            };
+            log::info!("Migrating blocks {}..={} to storage format {}", resume_from, best, codec::FORMAT_VERSION);
+
+            let mut legacy_files = HashSet::new();
+            let mut rewritten_files = HashSet::new();
+            let hashes = self.get_block_hashes(resume_from..best + 1).await?;
+            for (offset, hash) in hashes.iter().enumerate() {
+                // Pruned blocks have nothing left to migrate
+                let location = match self.storage.retrieve_block_location(hash).await? {
+                    Some(location) => location,
+                    None => continue,
+                };
+                let block: Block = bincode::deserialize::<LegacyBlock>(&self.block_storage.read_block_from_file(&location)?)?.into();
+                let height = resume_from + offset as u64;
+                let (file_name, byte_offset) = self.block_storage.append_block_to_file(&codec::encode(&block)?, height)?;
+                // The new copy must be durable before the index stops pointing at the old one
+                self.block_storage.sync()?;
+                rewritten_files.insert(file_name.clone());
+                legacy_files.insert(location.file_name);
+                self.storage.store_migrated_block_location(hash, &BlockLocation { file_name, byte_offset }, height).await?;
+            }
+
+            // Rev files stay: undo records are unaffected and still referenced
+            for file_name in legacy_files.difference(&rewritten_files) {
+                match std::fs::remove_file(file_name) {
+                    Ok(()) => self.block_storage.forget_file(file_name)?,
+                    Err(e) => log::warn!("Could not remove migrated block file {}: {}", file_name, e),
+                }
+            }
+        }
+
+        self.storage.migrate_header_index().await?;
+        self.storage.set_schema_version(SCHEMA_VERSION).await?;
+        self.storage.delete_meta(META_MIGRATION_HEIGHT).await
+    }
+
+    async fn load_utxo_set_summary(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let stored = self.storage.get_utxo_set_summary().await?;
+        let summary = match stored {
+            Some(summary) => summary,
+            None => {
+                log::info!("Hashing the UTXO set; this only happens once");
+                let summary = self.storage.summarize_utxo_set().await?;
+                if !self.read_only {
+                    self.storage.put_meta(META_UTXO_SET_SUMMARY, summary.to_bytes()).await?;
+                }
+                summary
+            }
+        };
+        let tip = *self.chain_tip.read();
+        let mut utxos = self.utxo_cache.lock().await;
+        utxos.set_summary(summary);
+        utxos.set_best_block(tip.height.map(|_| tip.hash));
+        Ok(())
+    }
+
+    async fn load_header_index(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = HeaderIndex::new();
+        for (_, header) in self.storage.load_headers().await? {
+            index.insert(header);
+        }
+        log::info!("Loaded {} headers", index.len());
+        if let Some(invalid) = self.storage.get_meta(META_INVALID_BLOCKS).await? {
+            for hash in invalid.chunks_exact(32) {
+                index.set_invalid(hash.try_into()?, true);
+            }
+        }
+        *self.headers.write() = index;
+        let tip = *self.chain_tip.read();
+        self.update_header_index(tip.height.map(|_| tip.hash)).await
+    }
+
+    // Moves the index's active chain to `tip`, first adding any headers between it and the headers
+    // already indexed, such as those of blocks connected before the header index was kept
+    async fn update_header_index(&self, tip: Option<BlockHash>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut missing = Vec::new();
+        let mut next = tip.filter(|hash| !self.headers.read().contains(hash));
+        while let Some(hash) = next {
+            let stored = self.storage.get_header(&hash).await?;
+            let header = match stored {
+                Some((_, header)) => header,
+                None => {
+                    let header = self.get_block(&hash).await?
+                        .ok_or_else(|| format!("header of block {} is missing and its block is pruned; resync the node", hex::encode(hash)))?
+                        .header.clone();
+                    let height = self.storage.get_block_height(&hash).await?;
+                    if let (false, Some(height)) = (self.read_only, height) {
+                        self.storage.store_header(&hash, height, &header).await?;
+                    }
+                    header
+                }
+            };
+            next = Some(header.previous_hash).filter(|previous| *previous != [0; 32] && !self.headers.read().contains(previous));
+            missing.push(header);
+        }
+
+        let mut index = self.headers.write();
+        for header in missing.into_iter().rev() {
+            index.insert(header);
+        }
+        if !index.set_tip(tip.as_ref()) {
+            return Err("chain tip is missing from the header index".into());
+        }
+        Ok(())
+    }
+
+    // Reads the window's blocks off the header index's active chain, which a read-only node's height
+    // index may have moved past
+    async fn load_recent_fruits(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut recent = RecentFruits::default();
+        if let Some(tip_height) = self.get_chain_height() {
+            let start = tip_height.saturating_sub(self.params.fruit_freshness_window - 1);
+            let hashes = {
+                let headers = self.headers.read();
+                (start..tip_height + 1).map_while(|height| headers.at_height(height).map(|entry| entry.hash)).collect::<Vec<_>>()
+            };
+            for hash in hashes {
+                recent.push_back(&self.get_block_fruits(&hash).await?, self.params.fruit_freshness_window);
+            }
+        }
+        *self.recent_fruits.write() = recent;
+        Ok(())
+    }
+
+    // Deployment states depend on every period boundary so far, so replay the signals of the whole chain
+    async fn load_versionbits(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tracker = self.new_versionbits_tracker();
+        if !self.params.deployments.is_empty() {
+            let headers = self.headers.read();
+            let mut height = 0;
+            while let Some(entry) = headers.at_height(height) {
+                tracker.block_connected(height, entry.header.version, headers.median_time_past(&entry.hash).unwrap_or(0));
+                height += 1;
+            }
+        }
+        *self.versionbits.write() = tracker;
+        Ok(())
+    }
+
+    fn new_versionbits_tracker(&self) -> VersionBitsTracker {
+        VersionBitsTracker::new(self.params.deployments.clone(), self.params.signalling_period, self.params.signalling_threshold)
+    }
+
+    // Every tip in the header index, highest first
+    pub async fn chain_tips(&self) -> Result<Vec<ChainTipInfo>, Box<dyn std::error::Error>> {
+        let tips = {
+            let headers = self.headers.read();
+            headers.tips().into_iter()
+                .map(|tip| (tip.hash, tip.height, headers.branch(&tip.hash).iter().map(|entry| entry.hash).collect::<Vec<_>>(), headers.is_invalid(&tip.hash)))
+                .collect::<Vec<_>>()
+        };
+        let mut infos = Vec::with_capacity(tips.len());
+        for (hash, height, branch, invalid) in tips {
+            let status = if branch.is_empty() {
+                TipStatus::Active
+            } else if invalid {
+                TipStatus::Invalid
+            } else {
+                let mut status = TipStatus::ValidFork;
+                for hash in &branch {
+                    if self.storage.retrieve_block_location(hash).await?.is_none() {
+                        status = TipStatus::HeadersOnly;
+                        break;
+                    }
+                }
+                status
+            };
+            infos.push(ChainTipInfo { hash, height, branch_len: branch.len() as u64, status });
+        }
+        infos.sort_by_key(|info| std::cmp::Reverse(info.height));
+        Ok(infos)
+    }
+
+    // Marks `block_hash` invalid, disconnects it and everything above it, and moves to the best
+    // remaining chain. The mark is stored, so the block and its descendants stay refused across
+    // restarts until `reconsider_block`.
+    pub async fn invalidate_block(&self, block_hash: &BlockHash) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        match self.headers.read().get(block_hash) {
+            None => return Err("block is not in the header index".into()),
+            Some(entry) if entry.height == 0 => return Err("the genesis block cannot be invalidated".into()),
+            Some(_) => {}
+        }
+        while self.headers.read().is_active(block_hash) {
+            self.disconnect_block().await?;
+        }
+        self.headers.write().set_invalid(block_hash, true);
+        self.store_invalid_blocks().await?;
+        log::warn!("Block {} marked invalid", hex::encode(block_hash));
+        self.activate_best_chain().await?;
+        Ok(())
+    }
+
+    // Clears the invalid marks on `block_hash`, its ancestors and its descendants, then switches to
+    // their chain if it now has the most work
+    pub async fn reconsider_block(&self, block_hash: &BlockHash) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        if !self.headers.write().reconsider(block_hash) {
+            return Err("block is not in the header index".into());
+        }
+        self.store_invalid_blocks().await?;
+        self.activate_best_chain().await?;
+        Ok(())
+    }
+
+    async fn store_invalid_blocks(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let invalid = self.headers.read().invalid_blocks();
+        if invalid.is_empty() {
+            self.storage.delete_meta(META_INVALID_BLOCKS).await
+        } else {
+            self.storage.put_meta(META_INVALID_BLOCKS, invalid.concat()).await
+        }
+    }
+
+    // Reorganises onto the fork with the most work, if it has more than the active chain, by
+    // reconnecting its stored blocks, which validates them again. A block failing that is marked
+    // invalid and the search starts over, so this ends on the best chain that validates. Returns
+    // the blocks that failed, with why.
+    async fn activate_best_chain(&self) -> Result<Vec<(BlockHash, ValidationError)>, Box<dyn std::error::Error>> {
+        let mut failed = Vec::new();
+        loop {
+            let Some((fork_height, branch)) = self.best_fork().await? else { break };
+            log::info!("Switching to fork of {} blocks from height {}", branch.len(), fork_height);
+            while self.get_chain_height().is_some_and(|height| height > fork_height) {
+                self.disconnect_block().await?;
+            }
+            for hash in branch {
+                let block = self.get_block(&hash).await?.ok_or("fork block is missing from block storage")?;
+                let failure = match self.connect_block(block, None).await {
+                    Ok(()) => None,
+                    // Only failures that are the block's own fault condemn it
+                    Err(e) => match e.downcast::<ValidationError>() {
+                        Ok(e) if e.misbehavior() > 0 => Some(*e),
+                        Ok(e) => return Err(e as Box<dyn std::error::Error>),
+                        Err(e) => return Err(e),
+                    },
+                };
+                if let Some(reason) = failure {
+                    log::warn!("Block {} failed validation and is marked invalid: {}", hex::encode(hash), reason);
+                    self.headers.write().set_invalid(&hash, true);
+                    self.store_invalid_blocks().await?;
+                    failed.push((hash, reason));
+                    break;
+                }
+            }
+        }
+        Ok(failed)
+    }
+
+    // Fork point height and blocks, oldest first, of the branch with the most work beyond the
+    // active chain's. A branch counts up to its first block that isn't stored or is marked invalid,
+    // so headers held without their blocks only matter once the blocks arrive.
+    async fn best_fork(&self) -> Result<Option<(u64, Vec<BlockHash>)>, Box<dyn std::error::Error>> {
+        let (tip_work, candidates) = {
+            let headers = self.headers.read();
+            let Some(tip) = headers.tip() else { return Ok(None) };
+            let mut candidates = headers.tips().into_iter()
+                .filter(|leaf| leaf.chain_work > tip.chain_work)
+                .filter_map(|leaf| Some((leaf.chain_work, leaf.hash, headers.find_fork(&tip.hash, &leaf.hash)?.height)))
+                .collect::<Vec<_>>();
+            candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.0));
+            (tip.chain_work, candidates)
+        };
+        for (_, leaf, fork_height) in candidates {
+            let mut branch = Vec::new();
+            let mut branch_work = U256::zero();
+            for height in fork_height + 1.. {
+                let next = {
+                    let headers = self.headers.read();
+                    headers.ancestor(&leaf, height)
+                        .filter(|entry| !headers.is_marked_invalid(&entry.hash))
+                        .map(|entry| (entry.hash, entry.chain_work))
+                };
+                let Some((hash, work)) = next else { break };
+                if self.storage.retrieve_block_location(&hash).await?.is_none() {
+                    break;
+                }
+                branch.push(hash);
+                branch_work = work;
+            }
+            if branch_work > tip_work {
+                return Ok(Some((fork_height, branch)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Median timestamp of the last MEDIAN_TIME_SPAN blocks ending at the tip
+    pub fn median_time_past(&self) -> u64 {
+        let headers = self.headers.read();
+        headers.tip().and_then(|tip| headers.median_time_past(&tip.hash)).unwrap_or(0)
+    }
+
+    // Ancestor of `block_hash` at `height`, on the active chain or a fork
+    pub fn get_ancestor(&self, block_hash: &BlockHash, height: u64) -> Option<IndexedHeader> {
+        self.headers.read().ancestor(block_hash, height).cloned()
+    }
+
+    // Last block of the active chain that `block_hash` descends from
+    pub fn find_fork(&self, block_hash: &BlockHash) -> Option<IndexedHeader> {
+        let headers = self.headers.read();
+        headers.find_fork(&headers.tip()?.hash, block_hash).cloned()
+    }
+
+    // Locator for a getheaders request, describing the active chain from its tip
+    pub fn block_locator(&self) -> Vec<BlockHash> {
+        let headers = self.headers.read();
+        headers.tip().map(|tip| headers.locator(&tip.hash)).unwrap_or_default()
+    }
+
+    // Answer to a peer's getheaders: the active chain after the last block it shares with us
+    pub fn headers_for_locator(&self, locator: &[BlockHash], stop_hash: &BlockHash) -> Result<Vec<BlockHeader>, Box<dyn std::error::Error>> {
+        if locator.len() > protocol::MAX_LOCATOR_SIZE {
+            return Err(format!("locator has {} hashes, more than the {} allowed", locator.len(), protocol::MAX_LOCATOR_SIZE).into());
+        }
+        Ok(self.headers.read().headers_after(locator, stop_hash, protocol::MAX_HEADERS_PER_MESSAGE))
+    }
+
+    // Header version for the next block, signalling every deployment still in progress
+    pub fn next_block_version(&self) -> u32 {
+        self.versionbits.read().block_version()
+    }
+
+    pub fn is_deployment_active(&self, name: &str) -> bool {
+        self.versionbits.read().is_active(name)
+    }
+
+    pub fn deployment_info(&self) -> Vec<DeploymentInfo> {
+        let tracker = self.versionbits.read();
+        tracker.deployments().iter().zip(tracker.current_states())
+            .map(|(deployment, &state)| DeploymentInfo {
+                name: deployment.name,
+                bit: deployment.bit,
+                start_time: deployment.start_time,
+                timeout: deployment.timeout,
+                state,
+                count: tracker.current_count(deployment.name).unwrap_or(0),
+                period: self.params.signalling_period,
+                threshold: self.params.signalling_threshold,
+            })
+            .collect()
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn get_chain_tip(&self) -> BlockHash {
+        self.chain_tip.read().hash
+    }
+
+    // Builds any enabled index from the stored blocks when it was just enabled, or when it fell
+    // behind the chain while disabled or through an interrupted write
+    async fn load_indexes(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.read_only {
+            return Ok(());
+        }
+        let tip = *self.chain_tip.read();
+        let tip_height = match tip.height {
+            Some(height) => height,
+            None => return Ok(()),
+        };
+        let mut stale = IndexConfig::default();
+        for (enabled, rebuild, meta, cfs) in [
+            (self.indexes.address, &mut stale.address, META_ADDRESS_INDEX_TIP, &[CF_ADDRESS_INDEX, CF_ADDRESS_UTXOS][..]),
+            (self.indexes.spent, &mut stale.spent, META_SPENT_INDEX_TIP, &[CF_SPENT_INDEX][..]),
+        ] {
+            if enabled && self.storage.get_meta(meta).await?.as_deref() != Some(&tip.hash[..]) {
+                *rebuild = true;
+                for cf in cfs {
+                    self.storage.clear_cf(cf).await?;
+                }
+                self.storage.delete_meta(meta).await?;
+            }
+        }
+        if stale == IndexConfig::default() {
+            return Ok(());
+        }
+
+        log::info!("Building indexes {:?} for blocks 0..={}", stale, tip_height);
+        for (height, hash) in self.get_block_hashes(0..tip_height + 1).await?.iter().enumerate() {
+            let block = self.get_block(hash).await?
+                .ok_or_else(|| format!("block at height {} is missing (pruned?), cannot build indexes", height))?;
+            // Only the address index needs the coins each block spent
+            let spent_coins = match stale.address {
+                true => self.get_undo(hash).await?
+                    .ok_or_else(|| format!("block at height {} has no undo data, cannot build the address index", height))?
+                    .spent_coins,
+                false => Vec::new(),
+            };
+            self.update_indexes(&stale, &block, *hash, height as u64, &spent_coins, true).await?;
+        }
+        Ok(())
+    }
+
+    // Applies a block's changes to each of `indexes` together with the index's new tip, which is the
+    // block itself when connecting and its parent when disconnecting
+    async fn update_indexes(&self, indexes: &IndexConfig, block: &Block, block_hash: BlockHash, height: u64, spent_coins: &[(OutPoint, Coin)], connect: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let indexed_tip = match (connect, height) {
+            (true, _) => Some(block_hash.to_vec()),
+            (false, 0) => None,
+            (false, _) => Some(block.header.previous_hash.to_vec()),
+        };
+        let mut ops = Vec::new();
+        if indexes.address {
+            ops.extend(address_index::block_changes(block, height, spent_coins, connect));
+            ops.push((CF_META, META_ADDRESS_INDEX_TIP.to_vec(), indexed_tip.clone()));
+        }
+        if indexes.spent {
+            ops.extend(spent_index::block_changes(block, height, connect));
+            ops.push((CF_META, META_SPENT_INDEX_TIP.to_vec(), indexed_tip));
+        }
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.storage.write_index_batch(ops).await
+    }
+
+    fn check_address_index(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.indexes.address {
+            return Err("address index is disabled; set indexes.address = true and restart".into());
+        }
+        Ok(())
+    }
+
+    // Every output paying `script` and every input spending one, oldest first
+    pub async fn address_history(&self, script: &[u8]) -> Result<Vec<AddressEvent>, Box<dyn std::error::Error>> {
+        self.check_address_index()?;
+        let mut entries = Box::pin(self.storage.iter_prefix(CF_ADDRESS_INDEX, address_index::script_hash(script).to_vec()));
+        let mut events = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let (key, value) = entry.map_err(|e| e as Box<dyn std::error::Error>)?;
+            events.push(AddressEvent::decode(&key, &value).ok_or("corrupt address index entry")?);
+        }
+        Ok(events)
+    }
+
+    // Confirmed outputs paying `script` that are still unspent
+    pub async fn address_utxos(&self, script: &[u8]) -> Result<Vec<AddressUtxo>, Box<dyn std::error::Error>> {
+        self.check_address_index()?;
+        let mut entries = Box::pin(self.storage.iter_prefix(CF_ADDRESS_UTXOS, address_index::script_hash(script).to_vec()));
+        let mut utxos = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let (key, value) = entry.map_err(|e| e as Box<dyn std::error::Error>)?;
+            utxos.push(AddressUtxo::decode(&key, &value).ok_or("corrupt address utxo entry")?);
+        }
+        utxos.sort_by_key(|utxo| utxo.height);
+        Ok(utxos)
+    }
+
+    // The input on the active chain that spent `outpoint`, if any
+    pub async fn get_spent_info(&self, outpoint: &OutPoint) -> Result<Option<SpentInfo>, Box<dyn std::error::Error>> {
+        if !self.indexes.spent {
+            return Err("spent index is disabled; set indexes.spent = true and restart".into());
+        }
+        match self.storage.get_index_entry(CF_SPENT_INDEX, spent_index::key(outpoint)).await? {
+            Some(value) => Ok(Some(SpentInfo::decode(&value).ok_or("corrupt spent index entry")?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_chain_height(&self) -> Option<u64> {
+        self.chain_tip.read().height
+    }
+
+    // Timestamp of the tip block, None while the chain is empty
+    pub fn tip_time(&self) -> Option<u64> {
+        self.headers.read().tip().map(|tip| tip.header.timestamp)
+    }
+
+    // Whether the node is still catching up with the network: its tip is old, or `accept_headers`
+    // has indexed headers well past the tip whose blocks are still to download. Subsystems that act
+    // on the tip, like mining and fee estimation, should wait until this is false.
+    pub fn is_initial_block_download(&self) -> bool {
+        if !self.initial_block_download.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = self.network_time.adjusted_time();
+        let caught_up = {
+            let headers = self.headers.read();
+            headers.tip().is_some_and(|tip| {
+                let headers_ahead = headers.best_header().map_or(0, |best| best.height.saturating_sub(tip.height));
+                tip.header.timestamp + MAX_TIP_AGE_SECS >= now && headers_ahead <= MAX_HEADERS_AHEAD_OF_TIP
+            })
+        };
+        if caught_up && self.initial_block_download.swap(false, Ordering::Relaxed) {
+            log::info!("Leaving initial block download at height {}", self.get_chain_height().unwrap_or(0));
+        }
+        !caught_up
+    }
+
+    // Height of the best valid header, which runs ahead of the chain height while blocks download
+    pub fn best_header_height(&self) -> Option<u64> {
+        self.headers.read().best_header().map(|entry| entry.height)
+    }
+
+    pub fn network_time(&self) -> &Arc<NetworkTime> {
+        &self.network_time
+    }
+
+    pub fn disk_monitor(&self) -> &Arc<DiskMonitor> {
+        &self.disk_monitor
+    }
+
+    // Timestamp for a block mined now: network-adjusted time, but always past the median time past
+    pub fn next_block_timestamp(&self) -> u64 {
+        self.network_time.adjusted_time().max(self.median_time_past() + 1)
+    }
+
+    // First stage of accepting a block: whether `header` could extend the tip, checked from the
+    // header alone so an announced block can be refused before its body is downloaded
+    pub async fn check_header(&self, header: &BlockHeader) -> Result<(), Box<dyn std::error::Error>> {
+        validation::check_header(header)?;
+        self.check_header_context(header).await
+    }
+
+    // The header checks that need the chain: it extends the tip, isn't too far in the future and
+    // has the expected difficulty
+    async fn check_header_context(&self, header: &BlockHeader) -> Result<(), Box<dyn std::error::Error>> {
+        let tip = *self.chain_tip.read();
+        if tip.height.is_some() && header.previous_hash != tip.hash {
+            return Err(ValidationError::PrevBlockMismatch { expected: tip.hash, actual: header.previous_hash }.into());
+        }
+        let max_timestamp = self.network_time.adjusted_time() + MAX_FUTURE_BLOCK_TIME_SECS;
+        if header.timestamp > max_timestamp {
+            return Err(ValidationError::TimestampTooNew { timestamp: header.timestamp, max: max_timestamp }.into());
+        }
+        let expected_bits = self.next_block_bits(header.timestamp).await?;
+        if header.bits != expected_bits {
+            return Err(ValidationError::BadBits { expected: expected_bits, actual: header.bits }.into());
+        }
+        Ok(())
+    }
+
+    // Checks run from cheapest to most expensive: the header, the block's structure, fruits against
+    // recent blocks, signatures, and finally inputs against the UTXO set. Errors are
+    // `ValidationError`s where the block is at fault; `ValidationError::misbehavior` says how much
+    // to hold that against the peer that sent it.
+    //
+    // A block building on another block than the tip is stored if its branch may overtake the
+    // active chain. Either way the chain then moves to whichever branch has the most work, which
+    // may take in blocks stored earlier that were waiting on this one.
+    pub async fn add_block(&self, block: Block) -> Result<(), Box<dyn std::error::Error>> {
+        let block = Arc::new(block);
+        let off_tip = match self.connect_block(Arc::clone(&block), None).await {
+            Ok(()) => false,
+            Err(e) if matches!(e.downcast_ref::<ValidationError>(), Some(ValidationError::PrevBlockMismatch { .. })) => true,
+            Err(e) => return Err(e),
+        };
+        let block_hash = block.hash();
+        if off_tip {
+            self.store_fork_block(&block).await?;
+        }
+        let failed = self.activate_best_chain().await?;
+        match failed.into_iter().find(|(hash, _)| *hash == block_hash) {
+            Some((_, reason)) => Err(reason.into()),
+            None => Ok(()),
+        }
+    }
+
+    // Adds headers a peer sent to the header index and stores them, each once it passes the checks
+    // it can without its block: proof of work, a known parent that isn't invalid, its timestamp and
+    // its difficulty. Stops at the first that fails; returns how many were new. A batch forking off
+    // with too little work is refused whole. Like `add_block`, ends on the branch with the most work
+    // among the blocks stored.
+    pub async fn accept_headers(&self, headers: &[BlockHeader]) -> Result<usize, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        self.headers.read().check_fork_work(headers, self.params.minimum_chain_work)?;
+        let mut accepted = 0;
+        for header in headers {
+            let hash = header.hash();
+            let height = self.accept_header(header, &hash)?;
+            if let Some(height) = height {
+                self.storage.store_header(&hash, height, header).await?;
+                accepted += 1;
+            }
+        }
+        if accepted > 0 {
+            self.activate_best_chain().await?;
+        }
+        Ok(accepted)
+    }
+
+    // Height `header` was indexed at, or None if it already was
+    fn accept_header(&self, header: &BlockHeader, hash: &BlockHash) -> Result<Option<u64>, ValidationError> {
+        validation::check_header(header)?;
+        let mut headers = self.headers.write();
+        if headers.contains(hash) {
+            return if headers.is_invalid(hash) { Err(ValidationError::MarkedInvalid(*hash)) } else { Ok(None) };
+        }
+        let parent = headers.get(&header.previous_hash).ok_or(ValidationError::UnknownParent(header.previous_hash))?;
+        if headers.is_invalid(&parent.hash) {
+            return Err(ValidationError::MarkedInvalid(parent.hash));
+        }
+        let max_timestamp = self.network_time.adjusted_time() + MAX_FUTURE_BLOCK_TIME_SECS;
+        if header.timestamp > max_timestamp {
+            return Err(ValidationError::TimestampTooNew { timestamp: header.timestamp, max: max_timestamp });
+        }
+        let expected_bits = self.bits_after(&headers, parent, header.timestamp);
+        if header.bits != expected_bits {
+            return Err(ValidationError::BadBits { expected: expected_bits, actual: header.bits });
+        }
+        Ok(headers.insert(header.clone()))
+    }
+
+    // `next_block_bits` for a block on `parent`, which needn't be the tip
+    fn bits_after(&self, headers: &HeaderIndex, parent: &IndexedHeader, timestamp: u64) -> u32 {
+        let ancestors = pow::retarget_heights(&self.params, parent.height).into_iter()
+            .filter_map(|height| headers.ancestor(&parent.hash, height))
+            .map(|entry| (entry.header.timestamp, entry.header.bits))
+            .collect::<Vec<_>>();
+        pow::next_bits(&self.params, parent.height, &ancestors, timestamp)
+    }
+
+    // Stores a block that doesn't build on the tip, without connecting it, once its header is
+    // accepted and it passes the checks that don't need its parent's UTXO set. Blocks whose branch
+    // can't overtake the active chain aren't worth the disk space and are dropped.
+    async fn store_fork_block(&self, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        let block_hash = block.hash();
+        self.headers.read().check_fork_work(std::slice::from_ref(&block.header), self.params.minimum_chain_work)?;
+        if let Some(height) = self.accept_header(&block.header, &block_hash)? {
+            self.storage.store_header(&block_hash, height, &block.header).await?;
+        }
+        let _connecting = self.connect_lock.lock().await;
+        if self.storage.retrieve_block_location(&block_hash).await?.is_some() {
+            return Ok(());
+        }
+        let height = {
+            let headers = self.headers.read();
+            match headers.get(&block_hash) {
+                Some(entry) if headers.may_overtake(&block_hash) => entry.height,
+                _ => return Ok(()),
+            }
+        };
+        validation::check_block_structure(block, &self.params)?;
+        self.validator.verify_signatures(block)?;
+
+        let block_data = codec::encode(block)?;
+        self.disk_monitor.check_before_write(block_data.len() as u64)?;
+        let (file_name, byte_offset) = self.block_storage.append_block_to_file(&block_data, height)?;
+        self.storage.store_block_location(&block_hash, &BlockLocation { file_name, byte_offset }).await?;
+        log::debug!("Stored block {} at height {} off the active chain", hex::encode(block_hash), height);
+        Ok(())
+    }
+
+    // Whether the block itself is stored, on the active chain or not
+    pub async fn has_block(&self, block_hash: &BlockHash) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.storage.retrieve_block_location(block_hash).await?.is_some())
+    }
+
+    // `signatures` is a check already started by `BlockValidator::spawn_signature_check`, awaited
+    // in its place among the stages; without one the signatures are checked here
+    async fn connect_block(&self, block: Arc<Block>, signatures: Option<SignatureCheck>) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        // Checked before waiting as well, as a reindex holds the lock until it finishes
+        if self.reindex_pending.load(Ordering::SeqCst) {
+            return Err("the chain is being reindexed".into());
+        }
+        let _connecting = self.connect_lock.lock().await;
+        if self.reindex_pending.load(Ordering::SeqCst) {
+            return Err("the chain is being reindexed".into());
+        }
+        let block_hash = block.hash();
+        if self.headers.read().is_invalid(&block_hash) {
+            return Err(ValidationError::MarkedInvalid(block_hash).into());
+        }
+        self.check_header(&block.header).await?;
+        validation::check_block_structure(&block, &self.params)?;
+
+        let height = self.get_chain_height().map_or(0, |h| h + 1);
+        self.check_block_fruits(&block, height).await?;
+        match signatures {
+            Some(check) => check.await.map_err(|_| "signature check was abandoned")??,
+            None => self.validator.verify_signatures(&block)?,
+        }
+
+        let block_data = codec::encode(&*block)?;
+        // Before touching the UTXO set, so a full disk never leaves it ahead of the block files.
+        // Undo data and index entries take about as much again as the block.
+        self.disk_monitor.check_before_write(2 * block_data.len() as u64)?;
+        let window_fruits = self.reward_window_fruits().await?;
+        let undo = self.connect_transactions(&block, height, &window_fruits).await?;
+
+        // Store block in file system
+        let (file_name, byte_offset) = self.block_storage.append_block_to_file(&block_data, height)?;
+        self.store_undo(&block_hash, &file_name, &undo).await?;
+
+        // Store block location in database
+        let location = BlockLocation { file_name, byte_offset };
+        self.storage.store_block_location(&block_hash, &location).await?;
+
+        self.connect_tip(block_hash, &block).await?;
+        self.update_indexes(&self.indexes, &block, block_hash, height, &undo.spent_coins, true).await?;
+        self.block_cache.lock().blocks.insert(block_hash, Arc::clone(&block), block_data.len());
+        // Nobody listening is not an error
+        let _ = self.events.send(ChainEvent::BlockConnected { block, height });
+        Ok(())
+    }
+
+    // Every check `add_block` makes, short of proof of work, on a block that is neither connected
+    // nor stored: pools test a template with it before mining on it. Errors are as for `add_block`.
+    pub async fn check_block_proposal(&self, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        if self.reindex_pending.load(Ordering::SeqCst) {
+            return Err("the chain is being reindexed".into());
+        }
+        self.check_header_context(&block.header).await?;
+        validation::check_block_structure(block, &self.params)?;
+        let height = self.get_chain_height().map_or(0, |h| h + 1);
+        self.check_block_fruits(block, height).await?;
+        self.validator.verify_signatures(block)?;
+        let window_fruits = self.reward_window_fruits().await?;
+        let utxos = self.utxo_cache.lock().await;
+        self.check_transactions(&mut utxos.view(), block, height, &window_fruits).await?;
+        Ok(())
+    }
+
+    async fn check_block_fruits(&self, block: &Block, height: u64) -> Result<(), Box<dyn std::error::Error>> {
+        for (index, fruit) in block.fruits.iter().enumerate() {
+            match self.check_fruit(fruit, height).await? {
+                None => {}
+                Some(FruitExclusion::AlreadyIncluded) => return Err(ValidationError::FruitAlreadyIncluded(index).into()),
+                Some(FruitExclusion::InsufficientWork) => return Err(ValidationError::FruitProofOfWork(index).into()),
+                Some(_) => return Err(ValidationError::StaleFruit(index).into()),
+            }
+        }
+        Ok(())
+    }
+
+    // Connects `blocks` in order, skipping any already on the active chain and stopping at the first
+    // that fails. Signatures of the next SIGNATURE_PIPELINE_DEPTH blocks are checked on the
+    // verification pool while the current one connects, so bulk sync isn't held up by one thread
+    // checking signatures and then waiting on the database.
+    pub async fn connect_blocks<E>(&self, blocks: impl IntoIterator<Item = Result<Block, E>>) -> Result<ImportSummary, Box<dyn std::error::Error>>
+    where
+        E: Into<Box<dyn std::error::Error>>,
+    {
+        let mut summary = ImportSummary::default();
+        let mut blocks = blocks.into_iter();
+        let mut ahead = VecDeque::with_capacity(SIGNATURE_PIPELINE_DEPTH);
+        loop {
+            self.wait_for_disk_space().await?;
+            while ahead.len() < SIGNATURE_PIPELINE_DEPTH {
+                let Some(block) = blocks.next() else { break };
+                let block = block.map_err(Into::into)?;
+                if self.storage.get_block_height(&block.hash()).await?.is_some() {
+                    summary.skipped += 1;
+                    continue;
+                }
+                let block = Arc::new(block);
+                let check = self.validator.spawn_signature_check(Arc::clone(&block));
+                ahead.push_back((block, check));
+            }
+            let Some((block, check)) = ahead.pop_front() else { break };
+            self.connect_block(block, Some(check)).await?;
+            summary.connected += 1;
+        }
+        // Blocks stored off the tip while these connected may now lead the most work
+        self.activate_best_chain().await?;
+        Ok(summary)
+    }
+
+    // Holds bulk sync while free space is under `disk.low_free_mb`, failing if it gets critical
+    async fn wait_for_disk_space(&self) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            match self.disk_monitor.check()? {
+                DiskState::Ok => return Ok(()),
+                DiskState::Low => tokio::time::sleep(DISK_RECHECK_INTERVAL).await,
+                DiskState::Critical => return Err("disk space is below disk.min_free_mb".into()),
+            }
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    async fn store_undo(&self, block_hash: &BlockHash, block_file: &str, undo: &BlockUndo) -> Result<(), Box<dyn std::error::Error>> {
+        let (file_name, byte_offset) = self.block_storage.append_undo_to_file(block_file, &undo.encode()?)?;
+        self.storage.store_undo_location(block_hash, &BlockLocation { file_name, byte_offset }).await
+    }
+
+    // Whether the block's undo data is still stored, without reading it
+    pub async fn has_undo(&self, block_hash: &BlockHash) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.storage.get_undo_location(block_hash).await?.is_some())
+    }
+
+    pub async fn get_undo(&self, block_hash: &BlockHash) -> Result<Option<BlockUndo>, Box<dyn std::error::Error>> {
+        match self.storage.get_undo_location(block_hash).await? {
+            Some(location) => Ok(Some(BlockUndo::decode(&self.block_storage.read_undo_from_file(&location)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Aggregates of an active-chain block, from the block stats column family or else computed and
+    // cached there. None if the block isn't on the active chain, or was pruned before its stats were cached.
+    pub async fn get_block_stats(&self, block_hash: &BlockHash) -> Result<Option<BlockStats>, Box<dyn std::error::Error>> {
+        if let Some(stats) = self.storage.get_block_stats(block_hash).await? {
+            return Ok(Some(stats));
+        }
+        let Some(height) = self.storage.get_block_height(block_hash).await? else { return Ok(None) };
+        let Some(block) = self.get_block(block_hash).await? else { return Ok(None) };
+        let Some(undo) = self.get_undo(block_hash).await? else { return Ok(None) };
+        let stats = BlockStats::compute(&block, &undo, height, reward::block_subsidy(&self.params, height))?;
+        if !self.read_only {
+            self.storage.store_block_stats(&stats).await?;
+        }
+        Ok(Some(stats))
+    }
+
+    // Statistics over the `window` blocks ending at the tip. Interval and hashrate come from the
+    // header index; the rest needs the blocks themselves, so pruned ones are left out of it.
+    pub async fn chain_stats(&self, window: u64) -> Result<ChainStats, Box<dyn std::error::Error>> {
+        let mut stats = ChainStats { target_block_interval_secs: self.params.target_block_spacing_secs, ..ChainStats::default() };
+        let Some(tip_height) = self.get_chain_height() else { return Ok(stats) };
+        let start = tip_height.saturating_sub(window.max(1) - 1);
+        let entries = {
+            let headers = self.headers.read();
+            (start..=tip_height).filter_map(|height| headers.at_height(height).cloned()).collect::<Vec<_>>()
+        };
+        let (Some(first), Some(last)) = (entries.first(), entries.last()) else { return Ok(stats) };
+        stats.start_height = first.height;
+        stats.end_height = last.height;
+        stats.blocks = entries.len() as u64;
+
+        let span = last.header.timestamp.saturating_sub(first.header.timestamp);
+        if span > 0 {
+            stats.average_block_interval_secs = span as f64 / (entries.len() - 1) as f64;
+            // The first block's work was done before the window started
+            let work = entries[1..].iter().map(|entry| work_to_f64(pow::block_work(entry.header.bits))).sum::<f64>();
+            stats.network_hashrate = work / span as f64;
+        }
+
+        let (mut fruits, mut fees) = (0u64, 0u64);
+        for entry in &entries {
+            let Some(block_stats) = self.get_block_stats(&entry.hash).await? else { continue };
+            stats.blocks_with_data += 1;
+            fruits += block_stats.fruits;
+            fees += block_stats.total_fees;
+            stats.transactions += block_stats.transactions;
+        }
+        if stats.blocks_with_data > 0 {
+            stats.fruits_per_block = fruits as f64 / stats.blocks_with_data as f64;
+            stats.average_fees = fees as f64 / stats.blocks_with_data as f64;
+        }
+        if span > 0 {
+            stats.transactions_per_second = stats.transactions as f64 / span as f64;
+        }
+        Ok(stats)
+    }
+
+    async fn connect_tip(&self, block_hash: BlockHash, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        let header = &block.header;
+        let height = self.get_chain_height().map_or(0, |h| h + 1);
+        self.storage.store_height_hash(height, block_hash).await?;
+        self.storage.store_block_height(&block_hash, height).await?;
+        self.storage.store_header(&block_hash, height, header).await?;
+        if !block.fruits.is_empty() {
+            self.storage.store_block_fruits(&block_hash, &block.fruits).await?;
+        }
+        let previous_filter = match height {
+            0 => None,
+            _ => self.storage.get_block_filter(&header.previous_hash).await?,
+        };
+        let previous_filter_header = match previous_filter {
+            Some((_, filter_header)) => filter_header,
+            None if height == 0 => [0; 32],
+            // A snapshot's base block was never downloaded, so the filter chain starts over above it
+            None if self.storage.get_meta(META_SNAPSHOT_BASE).await?.as_deref() == Some(&header.previous_hash[..]) => [0; 32],
+            None => return Err("parent block has no compact filter".into()),
+        };
+        let filter = BlockFilter::for_block(&block_hash, block);
+        let filter_header = filter.header(&previous_filter_header);
+        self.storage.store_block_filter(&block_hash, &filter, &filter_header).await?;
+
+        self.recent_fruits.write().push_back(&block.fruits, self.params.fruit_freshness_window);
+
+        let median_time_past = {
+            let mut headers = self.headers.write();
+            headers.insert(header.clone());
+            headers.set_tip(Some(&block_hash));
+            headers.median_time_past(&block_hash).unwrap_or(0)
+        };
+        self.versionbits.write().block_connected(height, header.version, median_time_past);
+
+        // Update chain tip
+        let mut chain_tip = self.chain_tip.write();
+        *chain_tip = ChainTip { hash: block_hash, height: Some(height) };
+
+        Ok(())
+    }
+
+    // Steps the tip back to its parent, undoing its UTXO changes and dropping it from the indexes
+    pub async fn disconnect_block(&self) -> Result<Option<Arc<Block>>, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let _connecting = self.connect_lock.lock().await;
+        let tip = *self.chain_tip.read();
+        let height = match tip.height {
+            Some(height) => height,
+            None => return Ok(None),
+        };
+        let block = self.get_block(&tip.hash).await?
+            .ok_or("chain tip block is missing from block storage")?;
+        let undo = self.get_undo(&tip.hash).await?
+            .ok_or("chain tip has no undo data (pruned?), cannot disconnect it")?;
+
+        self.update_indexes(&self.indexes, &block, tip.hash, height, &undo.spent_coins, false).await?;
+        self.disconnect_transactions(&block, undo).await?;
+
+        self.storage.delete_height_hash(height).await?;
+        self.storage.delete_block_height(&tip.hash).await?;
+        self.storage.delete_block_fruits(&tip.hash).await?;
+        self.storage.delete_block_filter(&tip.hash).await?;
+        self.storage.delete_block_stats(&tip.hash).await?;
+
+        // The header stays indexed in case the block comes back
+        self.headers.write().set_tip(height.checked_sub(1).map(|_| &block.header.previous_hash));
+        let reentering_hash = match height.checked_sub(self.params.fruit_freshness_window) {
+            Some(h) => self.storage.get_hash_at_height(h).await?,
+            None => None,
+        };
+        let reentering_fruits = match reentering_hash {
+            Some(hash) => Some(self.get_block_fruits(&hash).await?),
+            None => None,
+        };
+        {
+            let mut recent_fruits = self.recent_fruits.write();
+            recent_fruits.pop_back();
+            if let Some(fruits) = reentering_fruits {
+                recent_fruits.push_front(&fruits);
+            }
+        }
+        self.versionbits.write().block_disconnected(height, block.header.version);
+
+        let mut chain_tip = self.chain_tip.write();
+        *chain_tip = ChainTip { hash: block.header.previous_hash, height: height.checked_sub(1) };
+        drop(chain_tip);
+
+        let _ = self.events.send(ChainEvent::BlockDisconnected { block: Arc::clone(&block), height });
+        Ok(Some(block))
+    }
+
+    // Checks every input against the UTXO set before touching it, so a bad block leaves the cache unchanged.
+    // Returns the coins the block spent from the existing set.
+    // `window_fruits` are the fruits of the blocks before this one that share in its reward
+    async fn connect_transactions(&self, block: &Block, height: u64, window_fruits: &[FruitHeader]) -> Result<BlockUndo, Box<dyn std::error::Error>> {
+        let median_time_past = self.median_time_past();
+        let mut utxos = self.utxo_cache.lock().await;
+        let mut view = utxos.view();
+        let created = self.check_transactions(&mut view, block, height, window_fruits).await?;
+        let loaded = view.into_loaded();
+        utxos.keep(loaded);
+
+        let new_coin = |tx: &Transaction, output: &TxOutput| Coin {
+            output: output.clone(),
+            height,
+            median_time_past,
+            is_coinbase: tx.is_coinbase(),
+        };
+        let mut undo = BlockUndo::default();
+        for tx in &block.transactions {
+            for input in &tx.inputs {
+                let outpoint = input.previous_output;
+                if let Some(coin) = utxos.spend(&outpoint).await? {
+                    // Coins created earlier in this block vanish with it and need no undo
+                    if !created.contains_key(&outpoint) {
+                        undo.spent_coins.push((outpoint, coin));
+                    }
+                }
+            }
+            let txid = tx.hash();
+            for (index, output) in tx.outputs.iter().enumerate().filter(|(_, output)| !reward::is_utxo_commitment(output)) {
+                utxos.add(OutPoint { txid, index: index as u32 }, new_coin(tx, output)).await?;
+            }
+        }
+        utxos.set_best_block(Some(block.hash()));
+
+        if utxos.needs_flush() {
+            utxos.flush().await?;
+        }
+        Ok(undo)
+    }
+
+    // Checks `block`'s transactions against the UTXO set without changing it, returning the coins
+    // they create for one another
+    async fn check_transactions(&self, utxos: &mut UtxoView<'_>, block: &Block, height: u64, window_fruits: &[FruitHeader]) -> Result<HashMap<OutPoint, Coin>, Box<dyn std::error::Error>> {
+        let median_time_past = self.median_time_past();
+        let new_coin = |tx: &Transaction, output: &TxOutput| Coin {
+            output: output.clone(),
+            height,
+            median_time_past,
+            is_coinbase: tx.is_coinbase(),
+        };
+
+        let mut created: HashMap<OutPoint, Coin> = HashMap::new();
+        let mut spent = HashSet::new();
+        let mut fees = 0u64;
+        for (index, tx) in block.transactions.iter().enumerate() {
+            if !tx.is_final(height, median_time_past) {
+                return Err(ValidationError::NonFinalTransaction(index).into());
+            }
+
+            let mut coins = Vec::with_capacity(tx.inputs.len());
+            for (input_index, input) in tx.inputs.iter().enumerate() {
+                let outpoint = input.previous_output;
+                let coin = match created.get(&outpoint) {
+                    Some(coin) => Some(coin.clone()),
+                    None => utxos.get(&outpoint).await?,
+                };
+                match coin {
+                    Some(coin) if spent.insert(outpoint) => {
+                        validation::check_input_script(index, input_index, input, &coin.output)?;
+                        coins.push(coin);
+                    }
+                    _ => return Err(ValidationError::MissingInput(outpoint).into()),
+                }
+            }
+            if !tx.sequence_lock(&coins).is_satisfied(height, median_time_past) {
+                return Err(ValidationError::SequenceLockNotSatisfied(index).into());
+            }
+            if !tx.is_coinbase() {
+                let fee = validation::transaction_fee(index, tx, coins.iter().map(|coin| coin.output.value))?;
+                fees = fees.checked_add(fee).ok_or(ValidationError::Coinbase(RewardError::Overflow))?;
+            }
+
+            // A transaction repeating an earlier one's txid may only do so once that one's outputs
+            // are all spent (BIP30); otherwise its coins would overwrite the earlier ones. UTXO
+            // commitments never become coins.
+            let txid = tx.hash();
+            for (i, output) in tx.outputs.iter().enumerate().filter(|(_, output)| !reward::is_utxo_commitment(output)) {
+                let outpoint = OutPoint { txid, index: i as u32 };
+                if !spent.contains(&outpoint) && (created.contains_key(&outpoint) || utxos.get(&outpoint).await?.is_some()) {
+                    return Err(ValidationError::OverwritesUnspentOutput(index).into());
+                }
+                created.insert(outpoint, new_coin(tx, output));
+            }
+        }
+
+        if let Some(coinbase) = block.transactions.first().filter(|tx| tx.is_coinbase()) {
+            let total_reward = reward::block_reward(&self.params, height, fees).map_err(ValidationError::Coinbase)?;
+            let payouts = reward::fruit_payouts(&self.params, total_reward, window_fruits.iter().chain(&block.fruits));
+            let commitment = self.is_deployment_active(UTXO_COMMITMENT_DEPLOYMENT).then(|| utxos.summary().hash.digest());
+            reward::check_coinbase(coinbase, &payouts, total_reward, commitment.as_ref()).map_err(ValidationError::Coinbase)?;
+        }
+        Ok(created)
+    }
+
+    async fn disconnect_transactions(&self, block: &Block, undo: BlockUndo) -> Result<(), Box<dyn std::error::Error>> {
+        let mut utxos = self.utxo_cache.lock().await;
+        for tx in block.transactions.iter().rev() {
+            let txid = tx.hash();
+            for (index, _) in tx.outputs.iter().enumerate().filter(|(_, output)| !reward::is_utxo_commitment(output)) {
+                utxos.spend(&OutPoint { txid, index: index as u32 }).await?;
+            }
+        }
+        for (outpoint, coin) in undo.spent_coins {
+            utxos.restore(outpoint, coin);
+        }
+        utxos.set_best_block(Some(block.header.previous_hash).filter(|hash| *hash != [0; 32]));
+
+        if utxos.needs_flush() {
+            utxos.flush().await?;
+        }
+        Ok(())
+    }
+
+    // Nothing is ever dirty on a read-only node
+    pub async fn flush_utxo_cache(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.utxo_cache.lock().await.flush().await
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.read_only {
+            return Err("chainstate is open read-only".into());
+        }
+        Ok(())
+    }
+
+    // Read-only nodes: follows the chainstate up to where the primary last flushed its UTXO set and
+    // returns the new tip height. The height index runs ahead of that, so following it would pair a
+    // tip with coins that don't match it yet. Only the blocks that left or joined the active chain
+    // since the last call are replayed.
+    pub async fn refresh(&self) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        self.storage.catch_up().await?;
+        let old = *self.chain_tip.read();
+        let tip = match Self::flushed_tip(&self.storage).await? {
+            Some(tip) => tip,
+            None => return Ok(old.height),
+        };
+        if tip == old {
+            return Ok(tip.height);
+        }
+        self.update_header_index(tip.height.map(|_| tip.hash)).await?;
+
+        // The old tip's branch, newest first, then the active chain from where it forked
+        let (disconnected, connected) = {
+            let headers = self.headers.read();
+            let disconnected = headers.branch(&old.hash).iter().map(|entry| (entry.height, entry.header.version)).collect::<Vec<_>>();
+            let fork_height = match disconnected.last() {
+                Some((height, _)) => *height,
+                None => old.height.map_or(0, |height| height + 1),
+            };
+            let connected = (fork_height..tip.height.map_or(0, |height| height + 1))
+                .filter_map(|height| headers.at_height(height))
+                .map(|entry| (entry.hash, entry.height, entry.header.version, headers.median_time_past(&entry.hash).unwrap_or(0)))
+                .collect::<Vec<_>>();
+            (disconnected, connected)
+        };
+        {
+            let mut versionbits = self.versionbits.write();
+            for &(height, version) in &disconnected {
+                versionbits.block_disconnected(height, version);
+            }
+            for &(_, height, version, median_time_past) in &connected {
+                versionbits.block_connected(height, version, median_time_past);
+            }
+        }
+
+        // Cached coins may have been spent by the primary
+        {
+            let mut utxos = self.utxo_cache.lock().await;
+            utxos.clear();
+            if let Some(summary) = self.storage.get_utxo_set_summary().await? {
+                utxos.set_summary(summary);
+            }
+        }
+        *self.chain_tip.write() = tip;
+        if disconnected.is_empty() && (connected.len() as u64) < self.params.fruit_freshness_window {
+            let mut fruits = Vec::with_capacity(connected.len());
+            for (hash, ..) in &connected {
+                fruits.push(self.get_block_fruits(hash).await?);
+            }
+            let mut recent_fruits = self.recent_fruits.write();
+            for fruits in &fruits {
+                recent_fruits.push_back(fruits, self.params.fruit_freshness_window);
+            }
+        } else {
+            self.load_recent_fruits().await?;
+        }
+        Ok(tip.height)
+    }
+
+    // Tip the primary last flushed the UTXO set at; `None` while that block's height isn't visible
+    // yet. Databases from before the flushed tip was recorded fall back to the height index.
+    async fn flushed_tip(storage: &Storage) -> Result<Option<ChainTip>, Box<dyn std::error::Error>> {
+        let best_block = storage.get_utxo_best_block().await?;
+        match best_block {
+            Some(hash) => Ok(storage.get_block_height(&hash).await?.map(|height| ChainTip { hash, height: Some(height) })),
+            None => Ok(Some(match storage.get_best_height().await? {
+                Some((height, hash)) => ChainTip { hash, height: Some(height) },
+                None => ChainTip::empty(),
+            })),
+        }
+    }
+
+    // Makes every appended block durable regardless of the configured fsync policy
+    pub fn sync_block_files(&self) -> std::io::Result<()> {
+        self.block_storage.sync()
+    }
+
+    // Checkpoints the chainstate database into `destination` while the node keeps running. Cached
+    // coins are flushed first so the copy matches the tip. Block files aren't copied: they're only
+    // appended to, so copying them any time after the checkpoint gives a consistent set.
+    pub async fn backup_chainstate(&self, destination: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.flush_utxo_cache().await?;
+        self.sync_block_files()?;
+        self.storage.create_checkpoint(destination).await
+    }
+
+    pub async fn database_stats(&self) -> Result<DatabaseStats, Box<dyn std::error::Error>> {
+        self.storage.get_statistics().await
+    }
+
+    // Compacts `cf`, or every column family when it is `None`
+    pub async fn compact_database(&self, cf: Option<&'static str>) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let cfs = match cf {
+            Some(cf) => vec![cf],
+            None => storage::column_families().to_vec(),
+        };
+        for cf in cfs {
+            self.storage.compact_range(cf).await?;
+        }
+        Ok(())
+    }
+
+    // Fee each transaction of `package` pays, after checking its signatures, its sequence locks
+    // (BIP68) and that every input exists exactly once: in an earlier transaction of the package, in
+    // the mempool via `pool_output`, or in the UTXO set. Unconfirmed outputs count as confirmed in
+    // the next block, the earliest a package spending them could be.
+    pub async fn package_fees<F>(&self, package: &[Transaction], pool_output: F) -> Result<Vec<u64>, Box<dyn std::error::Error>>
+    where
+        F: Fn(&OutPoint) -> Option<TxOutput>,
+    {
+        let next_height = self.get_chain_height().map_or(0, |height| height + 1);
+        let median_time_past = self.median_time_past();
+        let unconfirmed = |output: TxOutput| Coin { output, height: next_height, median_time_past, is_coinbase: false };
+        let mut created: HashMap<OutPoint, &TxOutput> = HashMap::new();
+        let mut spent = HashSet::new();
+        let mut fees = Vec::with_capacity(package.len());
+        for (index, tx) in package.iter().enumerate() {
+            if tx.is_coinbase() {
+                return Err(format!("package transaction {} is a coinbase", index).into());
+            }
+            self.validator.verify_transaction(tx).map_err(|source| ValidationError::Transaction { index, source })?;
+
+            let mut coins = Vec::with_capacity(tx.inputs.len());
+            for (input_index, input) in tx.inputs.iter().enumerate() {
+                let outpoint = input.previous_output;
+                let coin = match created.get(&outpoint) {
+                    Some(output) => Some(unconfirmed((*output).clone())),
+                    None => match pool_output(&outpoint) {
+                        Some(output) => Some(unconfirmed(output)),
+                        None => self.utxo_cache.lock().await.get(&outpoint).await?,
+                    },
+                };
+                match coin {
+                    Some(coin) if spent.insert(outpoint) => {
+                        validation::check_input_script(index, input_index, input, &coin.output)?;
+                        coins.push(coin);
+                    }
+                    _ => return Err(ValidationError::MissingInput(outpoint).into()),
+                }
+            }
+            if !tx.sequence_lock(&coins).is_satisfied(next_height, median_time_past) {
+                return Err(ValidationError::SequenceLockNotSatisfied(index).into());
+            }
+            fees.push(validation::transaction_fee(index, tx, coins.iter().map(|coin| coin.output.value))?);
+
+            let txid = tx.hash();
+            for (i, output) in tx.outputs.iter().enumerate() {
+                created.insert(OutPoint { txid, index: i as u32 }, output);
+            }
+        }
+        Ok(fees)
+    }
+
+    pub async fn get_coin(&self, outpoint: &OutPoint) -> Result<Option<Coin>, Box<dyn std::error::Error>> {
+        self.utxo_cache.lock().await.get(outpoint).await
+    }
+
+    pub async fn utxo_set_info(&self) -> UtxoSetInfo {
+        let utxos = self.utxo_cache.lock().await;
+        let tip = *self.chain_tip.read();
+        let summary = utxos.summary();
+        UtxoSetInfo {
+            height: tip.height,
+            best_block: tip.hash,
+            coins: summary.coins,
+            total_amount: summary.total_amount,
+            hash: summary.hash.digest(),
+        }
+    }
+
+    // Snapshot of the UTXO set at the tip for peers to sync from, its chunks written to a directory
+    // under `dir` named after the tip. The coins are read back from the database after a flush;
+    // another flush landing while they are read would show as a set hash differing from the one
+    // taken with the first.
+    pub async fn create_snapshot(&self, dir: &Path) -> Result<Snapshot, Box<dyn std::error::Error>> {
+        let (tip, expected, recent_fruits) = {
+            let mut utxos = self.utxo_cache.lock().await;
+            if !self.read_only {
+                utxos.flush().await?;
+            }
+            let tip = *self.chain_tip.read();
+            let height = tip.height.ok_or("the chain is empty")?;
+            let window = self.params.fruit_freshness_window.max(self.params.fruit_reward_window);
+            let mut recent_fruits = Vec::new();
+            let hashes = self.get_block_hashes(height.saturating_sub(window - 1)..height + 1).await?;
+            for hash in hashes {
+                recent_fruits.push(self.get_block_fruits(&hash).await?);
+            }
+            (tip, utxos.summary().hash.digest(), recent_fruits)
+        };
+
+        let mut builder = SnapshotBuilder::new(&dir.join(hex::encode(tip.hash)), tip.hash, tip.height.unwrap_or(0))?;
+        let mut entries = Box::pin(self.storage.iter_prefix(CF_UTXO, Vec::new()));
+        while let Some(entry) = entries.next().await {
+            let (key, value) = entry.map_err(|e| e as Box<dyn std::error::Error>)?;
+            builder.push(storage::utxo_outpoint(&key).ok_or("corrupt UTXO key")?, bincode::deserialize(&value)?)?;
+        }
+        let snapshot = builder.finish(recent_fruits)?;
+        if snapshot.utxo_set_hash != expected {
+            return Err("the UTXO set changed while the snapshot was taken; try again".into());
+        }
+        Ok(snapshot)
+    }
+
+    // A snapshot load that was interrupted, to carry on with or abort
+    pub async fn snapshot_load(&self) -> Result<Option<SnapshotLoad>, Box<dyn std::error::Error>> {
+        let Some(manifest) = self.storage.get_meta(META_SNAPSHOT_MANIFEST).await? else { return Ok(None) };
+        let progress = match self.storage.get_meta(META_SNAPSHOT_PROGRESS).await? {
+            Some(bytes) => bincode::deserialize(&bytes)?,
+            None => SnapshotLoadProgress::default(),
+        };
+        Ok(Some(SnapshotLoad { manifest: bincode::deserialize(&manifest)?, progress }))
+    }
+
+    // Adds `chunk`, the next of `manifest`'s and already checked against it, to the chainstate of a
+    // node with no blocks yet. Each chunk's coins are written with the load's progress, so only one
+    // chunk is held at a time and a load that stops part way carries on from `snapshot_load`.
+    // Coins out of key order throw the load away, as a set not matching the commitment does.
+    pub async fn load_snapshot_chunk(&self, manifest: &SnapshotManifest, chunk: &SnapshotChunk) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        if self.get_chain_height().is_some() {
+            return Err("a snapshot can only be loaded into an empty chainstate".into());
+        }
+        if self.indexes.address || self.indexes.spent {
+            return Err("the address and spent indexes need every block; disable them to load a snapshot".into());
+        }
+        let mut utxos = self.utxo_cache.lock().await;
+        let mut load = match self.snapshot_load().await? {
+            Some(load) if load.manifest == *manifest => load,
+            Some(_) => return Err("another snapshot is being loaded; abort that load first".into()),
+            None if utxos.summary().coins > 0 => return Err("the chainstate holds coins from an unknown load; abort it first".into()),
+            None => SnapshotLoad::new(manifest.clone()),
+        };
+        let mut summary = utxos.summary().clone();
+        let first = load.progress.next_chunk == 0;
+        if let Err(e) = load.add_chunk(chunk, &mut summary) {
+            // Coins out of order mean the manifest itself is bad, not just this chunk
+            if matches!(e, SnapshotError::UnsortedCoins(_)) {
+                drop(utxos);
+                self.abort_snapshot_load().await?;
+            }
+            return Err(e.into());
+        }
+
+        let mut progress = vec![(META_SNAPSHOT_PROGRESS, bincode::serialize(&load.progress)?)];
+        if first {
+            progress.push((META_SNAPSHOT_MANIFEST, bincode::serialize(manifest)?));
+        }
+        self.storage.write_snapshot_coins(chunk.coins.clone(), &summary, progress).await?;
+        utxos.set_summary(summary);
+        Ok(())
+    }
+
+    // Installs the loaded snapshot as the chainstate once every chunk is in, so the node carries on
+    // from the snapshot's base instead of replaying the chain. `headers` run from genesis to the
+    // base and get the checks a light client makes; the snapshot's fruits are checked against them.
+    // A set that doesn't match `commitment` is thrown away, for another peer's manifest to be tried.
+    // Blocks below the base are never downloaded, so they can't be served, disconnected or
+    // reindexed, and the optional indexes, which need every block, must be off.
+    pub async fn finish_snapshot_load(&self, headers: &[BlockHeader], commitment: &SnapshotCommitment) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let load = self.snapshot_load().await?.ok_or("no snapshot is being loaded")?;
+        let manifest = &load.manifest;
+        HeaderChain::new(self.params.clone()).accept_headers(headers)?;
+        if headers.len() as u64 != manifest.height + 1 || headers.last().map(BlockHeader::hash) != Some(manifest.block_hash) {
+            return Err("headers do not lead from genesis to the snapshot's base block".into());
+        }
+        let window = self.params.fruit_freshness_window.max(self.params.fruit_reward_window);
+        let fruit_blocks = &headers[(manifest.height + 1).saturating_sub(window) as usize..];
+        if fruit_blocks.len() != manifest.recent_fruits.len() {
+            return Err("snapshot carries fruits for the wrong number of blocks".into());
+        }
+        for (header, fruits) in fruit_blocks.iter().zip(&manifest.recent_fruits) {
+            if calculate_fruits_root(fruits) != header.fruits_root {
+                return Err(format!("snapshot fruits do not match block {}", hex::encode(header.hash())).into());
+            }
+        }
+        let summary = self.utxo_cache.lock().await.summary().clone();
+        match load.check(&summary, commitment) {
+            Ok(()) => {}
+            Err(e @ SnapshotError::Incomplete(_)) => return Err(e.into()),
+            Err(e) => {
+                self.abort_snapshot_load().await?;
+                return Err(e.into());
+            }
+        }
+
+        for (header, fruits) in fruit_blocks.iter().zip(&manifest.recent_fruits).filter(|(_, fruits)| !fruits.is_empty()) {
+            self.storage.store_block_fruits(&header.hash(), fruits).await?;
+        }
+        for (height, header) in headers.iter().enumerate() {
+            let hash = header.hash();
+            self.storage.store_header(&hash, height as u64, header).await?;
+            self.storage.store_block_height(&hash, height as u64).await?;
+            self.storage.store_height_hash(height as u64, hash).await?;
+        }
+        self.storage.put_meta(META_UTXO_BEST_BLOCK, manifest.block_hash.to_vec()).await?;
+        self.storage.put_meta(META_SNAPSHOT_BASE, manifest.block_hash.to_vec()).await?;
+        self.storage.delete_meta(META_SNAPSHOT_PROGRESS).await?;
+        self.storage.delete_meta(META_SNAPSHOT_MANIFEST).await?;
+
+        log::info!("Loaded a snapshot of {} coins at height {}", summary.coins, manifest.height);
+        *self.chain_tip.write() = ChainTip { hash: manifest.block_hash, height: Some(manifest.height) };
+        self.utxo_cache.lock().await.set_best_block(Some(manifest.block_hash));
+        self.load_header_index().await?;
+        self.load_recent_fruits().await?;
+        self.load_versionbits().await
+    }
+
+    // Throws away the coins of a snapshot load, finished or not, that hasn't become the chainstate.
+    // The progress goes first, so a node stopped part way still knows to finish clearing the coins.
+    pub async fn abort_snapshot_load(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        if self.get_chain_height().is_some() {
+            return Err("the chainstate already has a tip; there is no snapshot load to abort".into());
+        }
+        self.storage.delete_meta(META_SNAPSHOT_PROGRESS).await?;
+        self.storage.delete_meta(META_SNAPSHOT_MANIFEST).await?;
+        let mut utxos = self.utxo_cache.lock().await;
+        utxos.clear();
+        self.storage.clear_cf(CF_UTXO).await?;
+        self.storage.delete_meta(META_UTXO_SET_SUMMARY).await?;
+        self.storage.delete_meta(META_UTXO_BEST_BLOCK).await?;
+        Ok(())
+    }
+
+    // Block the chainstate was loaded from a snapshot at, if it was; nothing below it is stored
+    pub async fn snapshot_base(&self) -> Result<Option<BlockHash>, Box<dyn std::error::Error>> {
+        match self.storage.get_meta(META_SNAPSHOT_BASE).await? {
+            Some(bytes) => Ok(Some(BlockHash::try_from(bytes.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn utxo_cache_stats(&self) -> UtxoCacheStats {
+        self.utxo_cache.lock().await.stats()
+    }
+
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        let cache = self.block_cache.lock();
+        BlockCacheStats { hits: cache.hits, misses: cache.misses, entries: cache.blocks.len(), bytes: cache.blocks.weight() }
+    }
+
+    // Shared with the block cache, so a hit copies nothing
+    pub async fn get_block(&self, block_hash: &BlockHash) -> Result<Option<Arc<Block>>, Box<dyn std::error::Error>> {
+        {
+            let mut cache = self.block_cache.lock();
+            if let Some(block) = cache.blocks.get(block_hash).cloned() {
+                cache.hits += 1;
+                return Ok(Some(block));
+            }
+            cache.misses += 1;
+        }
+        if let Some(location) = self.storage.retrieve_block_location(block_hash).await? {
+            let block_data = self.block_storage.read_block_from_file(&location)?;
+            let block = Arc::new(Block::decode(&block_data)?);
+            self.block_cache.lock().blocks.insert(*block_hash, Arc::clone(&block), block_data.len());
+            Ok(Some(block))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Height of a block on the active chain, used to place fruits relative to the tip
+    pub async fn get_block_height(&self, block_hash: &BlockHash) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        self.storage.get_block_height(block_hash).await
+    }
+
+    pub async fn get_block_fruits(&self, block_hash: &BlockHash) -> Result<Vec<FruitHeader>, Box<dyn std::error::Error>> {
+        Ok(self.storage.get_block_fruits(block_hash).await?.unwrap_or_default())
+    }
+
+    // Whether `fruit` may go in the active-chain block at `height`: it must meet the fruit target,
+    // hang from an ancestor no more than `fruit_freshness_window` blocks below the parent and not
+    // already be in a recent block
+    async fn check_fruit(&self, fruit: &FruitHeader, height: u64) -> Result<Option<FruitExclusion>, Box<dyn std::error::Error>> {
+        if !pow::check_proof_of_work(&fruit.hash(), self.params.fruit_bits) {
+            return Ok(Some(FruitExclusion::InsufficientWork));
+        }
+        let fresh = match self.storage.get_block_height(&fruit.hang_from).await? {
+            Some(anchor) => anchor < height && anchor + self.params.fruit_freshness_window + 1 >= height,
+            None => false,
+        };
+        if !fresh {
+            return Ok(Some(FruitExclusion::Stale));
+        }
+        if self.recent_fruits.read().included.contains(&fruit.hash()) {
+            return Ok(Some(FruitExclusion::AlreadyIncluded));
+        }
+        Ok(None)
+    }
+
+    // Picks fruits for the next block. Every eligible candidate is included up to the
+    // `max_fruits_per_block` policy limit; the rest are returned with the reason they were left out.
+    pub async fn select_fruits(&self, candidates: impl IntoIterator<Item = FruitHeader>) -> Result<FruitSelection, Box<dyn std::error::Error>> {
+        let height = self.get_chain_height().map_or(0, |h| h + 1);
+        let mut selection = FruitSelection::default();
+        let mut seen = HashSet::new();
+        for fruit in candidates {
+            let exclusion = if !seen.insert(fruit.hash()) {
+                Some(FruitExclusion::Duplicate)
+            } else if let Some(reason) = self.check_fruit(&fruit, height).await? {
+                Some(reason)
+            } else if selection.included.len() >= self.params.max_fruits_per_block {
+                Some(FruitExclusion::BlockFull)
+            } else {
+                None
+            };
+            match exclusion {
+                Some(reason) => {
+                    log::debug!("Leaving fruit {} out of the template: {:?}", hex::encode(fruit.hash()), reason);
+                    selection.excluded.push((fruit, reason));
+                }
+                None => selection.included.push(fruit),
+            }
+        }
+        Ok(selection)
+    }
+
+    // Fruits included by the blocks before the next one that, together with its own fruits,
+    // share in its reward
+    pub async fn reward_window_fruits(&self) -> Result<Vec<FruitHeader>, Box<dyn std::error::Error>> {
+        let next_height = self.get_chain_height().map_or(0, |h| h + 1);
+        let start = next_height.saturating_sub(self.params.fruit_reward_window - 1);
+        let mut fruits = Vec::new();
+        let hashes = self.get_block_hashes(start..next_height).await?;
+        for hash in hashes {
+            fruits.extend(self.get_block_fruits(&hash).await?);
+        }
+        Ok(fruits)
+    }
+
+    // The next block's coinbase: the fruit miners' shares of the subsidy plus `fees` first, as
+    // consensus requires, then the block miner's part divided as `payout` says
+    pub async fn create_coinbase(&self, fees: u64, fruits: &[FruitHeader], payout: &MinerPayout) -> Result<Transaction, Box<dyn std::error::Error>> {
+        let height = self.get_chain_height().map_or(0, |h| h + 1);
+        let total_reward = reward::block_reward(&self.params, height, fees)?;
+        let window = self.reward_window_fruits().await?;
+        let payouts = reward::fruit_payouts(&self.params, total_reward, window.iter().chain(fruits));
+        let mut outputs = reward::coinbase_outputs(total_reward, payouts, payout);
+        if let Some(hash) = self.next_utxo_commitment().await {
+            outputs.push(reward::utxo_commitment_output(&hash));
+        }
+        Ok(Transaction {
+            inputs: Vec::new(),
+            outputs,
+            // Keeps coinbase txids unique across heights
+            lock_time: height as u32,
+        })
+    }
+
+    // The UTXO set hash the next block's coinbase must commit to, while commitments are active
+    pub async fn next_utxo_commitment(&self) -> Option<[u8; 32]> {
+        if !self.is_deployment_active(UTXO_COMMITMENT_DEPLOYMENT) {
+            return None;
+        }
+        Some(self.utxo_cache.lock().await.summary().hash.digest())
+    }
+
+    pub async fn get_transaction_proof(&self, block_hash: &BlockHash, txid: &TxHash) -> Result<Option<(BlockHeader, MerkleBranch)>, Box<dyn std::error::Error>> {
+        let block = match self.get_block(block_hash).await? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        Ok(block.transaction_proof(txid).map(|branch| (block.header.clone(), branch)))
+    }
+
+    // Writes blocks `from..=to` of the active chain to `out` as a block archive
+    pub async fn export_blocks<W: Write>(&self, from: u64, to: u64, out: W) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut writer = ArchiveWriter::new(out, self.params.network, from)?;
+        for height in from..=to {
+            let block = self.get_block_by_height(height).await?
+                .ok_or_else(|| format!("block at height {} is not available (past the tip or pruned)", height))?;
+            writer.write_block(&block)?;
+        }
+        Ok(writer.finish()?.1)
+    }
+
+    // Validates and connects the blocks of a block archive in order, skipping any already on the
+    // active chain. Stops at the first block that fails, keeping everything connected before it.
+    pub async fn import_blocks<R: Read>(&self, archive: R) -> Result<ImportSummary, Box<dyn std::error::Error>> {
+        let reader = ArchiveReader::new(archive)?;
+        if reader.network() != self.params.network {
+            return Err(format!("archive holds {:?} blocks but this node runs {:?}", reader.network(), self.params.network).into());
+        }
+        self.connect_blocks(reader).await
+    }
+
+    // Bootstraps from a local copy of the chain: another node's blocks directory or a block archive.
+    // Every block is fully validated, ones already on the active chain are skipped, and blocks read
+    // before their parent wait for it.
+    pub async fn load_blocks(&self, source: &Path) -> Result<ImportSummary, Box<dyn std::error::Error>> {
+        let mut pending = HashMap::new();
+        let mut summary = ImportSummary::default();
+        if source.is_dir() {
+            if source.canonicalize()? == self.block_storage.blocks_dir().canonicalize()? {
+                return Err("cannot load blocks from the node's own blocks directory".into());
+            }
+            let files = block_storage::block_files_in(source)?;
+            for (index, path) in files.iter().enumerate() {
+                log::info!("Loading blocks from {} ({}/{}, height {:?})", path.display(), index + 1, files.len(), self.get_chain_height());
+                for bytes in block_storage::read_block_file(path)? {
+                    match Block::decode(&bytes) {
+                        Ok(block) => self.load_block(block, &mut pending, &mut summary).await?,
+                        Err(e) => {
+                            log::warn!("Skipping undecodable block in {}: {}", path.display(), e);
+                            summary.invalid += 1;
+                        }
+                    }
+                }
+            }
+        } else {
+            let reader = ArchiveReader::new(BufReader::new(std::fs::File::open(source)?))?;
+            if reader.network() != self.params.network {
+                return Err(format!("archive holds {:?} blocks but this node runs {:?}", reader.network(), self.params.network).into());
+            }
+            for block in reader {
+                self.load_block(block?, &mut pending, &mut summary).await?;
+            }
+        }
+        Ok(summary)
+    }
+
+    // Adds `block` once its parent is indexed, then any blocks that were waiting on it. Waiting
+    // blocks are kept by parent, any number per parent, and counted in `summary.unconnected`.
+    async fn load_block(&self, block: Block, pending: &mut HashMap<BlockHash, Vec<Block>>, summary: &mut ImportSummary) -> Result<(), Box<dyn std::error::Error>> {
+        let mut ready = vec![block];
+        while let Some(block) = ready.pop() {
+            let block_hash = block.hash();
+            let parent_known = self.get_chain_height().is_none() || self.headers.read().contains(&block.header.previous_hash);
+            if self.storage.get_block_height(&block_hash).await?.is_some() || self.has_block(&block_hash).await? {
+                summary.skipped += 1;
+            } else if !parent_known {
+                if summary.unconnected < MAX_PENDING_LOAD_BLOCKS as u64 {
+                    pending.entry(block.header.previous_hash).or_default().push(block);
+                    summary.unconnected += 1;
+                }
+                continue;
+            } else if let Err(e) = self.add_block(block).await {
+                log::warn!("Rejected loaded block {}: {}", hex::encode(block_hash), e);
+                summary.invalid += 1;
+            } else {
+                summary.connected += 1;
+            }
+            if let Some(children) = pending.remove(&block_hash) {
+                summary.unconnected -= children.len() as u64;
+                ready.extend(children);
+            }
+        }
+        Ok(())
+    }
+
+    // Hash of the active-chain block at `height`
+    pub async fn get_block_hash(&self, height: u64) -> Result<Option<BlockHash>, Box<dyn std::error::Error>> {
+        self.storage.get_hash_at_height(height).await
+    }
+
+    pub async fn get_block_by_height(&self, height: u64) -> Result<Option<Arc<Block>>, Box<dyn std::error::Error>> {
+        let hash = self.storage.get_hash_at_height(height).await?;
+        match hash {
+            Some(hash) => self.get_block(&hash).await,
+            None => Ok(None),
+        }
+    }
+
+    // Compact filters and filter headers of the active chain for `range`
+    pub async fn get_block_filters(&self, range: Range<u64>) -> Result<Vec<(BlockHash, BlockFilter, FilterHeader)>, Box<dyn std::error::Error>> {
+        let mut filters = Vec::new();
+        let hashes = self.get_block_hashes(range).await?;
+        for hash in hashes {
+            match self.storage.get_block_filter(&hash).await? {
+                Some((filter, header)) => filters.push((hash, filter, header)),
+                None => break,
+            }
+        }
+        Ok(filters)
+    }
+
+    // Headers of the active chain for `range`, served to light clients
+    pub async fn get_headers(&self, range: Range<u64>) -> Result<Vec<BlockHeader>, Box<dyn std::error::Error>> {
+        // The index only trails the chain tip while it is being loaded or rebuilt
+        {
+            let index = self.headers.read();
+            let tip = *self.chain_tip.read();
+            if index.tip().map(|entry| entry.hash) == tip.height.map(|_| tip.hash) {
+                return Ok(range.map_while(|height| index.at_height(height).map(|entry| entry.header.clone())).collect());
+            }
+        }
+        let mut headers = Vec::new();
+        let hashes = self.get_block_hashes(range).await?;
+        for hash in hashes {
+            match self.get_header(&hash).await? {
+                Some(header) => headers.push(header),
+                None => break,
+            }
+        }
+        Ok(headers)
+    }
+
+    // Blocks connected before the header index existed only have their header in the block file
+    pub async fn get_header(&self, block_hash: &BlockHash) -> Result<Option<BlockHeader>, Box<dyn std::error::Error>> {
+        if let Some(entry) = self.headers.read().get(block_hash) {
+            return Ok(Some(entry.header.clone()));
+        }
+        if let Some((_, header)) = self.storage.get_header(block_hash).await? {
+            return Ok(Some(header));
+        }
+        Ok(self.get_block(block_hash).await?.map(|block| block.header.clone()))
+    }
+
+    // Height of an indexed header, whether or not its block is stored yet
+    pub fn header_height(&self, block_hash: &BlockHash) -> Option<u64> {
+        self.headers.read().get(block_hash).map(|entry| entry.height)
+    }
+
+    // Compact bits the next block on the active chain must carry if it is stamped with `timestamp`
+    pub async fn next_block_bits(&self, timestamp: u64) -> Result<u32, Box<dyn std::error::Error>> {
+        let headers = self.headers.read();
+        Ok(match headers.tip() {
+            Some(tip) => self.bits_after(&headers, tip, timestamp),
+            None => self.params.genesis_bits,
+        })
+    }
+
+    // Hashes of the active chain for `range`, stopping early at the tip
+    pub async fn get_block_hashes(&self, range: Range<u64>) -> Result<Vec<BlockHash>, Box<dyn std::error::Error>> {
+        let from = range.start.to_be_bytes().to_vec();
+        let to = range.end.to_be_bytes().to_vec();
+        let mut entries = Box::pin(self.storage.iter_range(CF_HEIGHT_INDEX, from, to));
+
+        let mut hashes = Vec::with_capacity(range.end.saturating_sub(range.start).min(1024) as usize);
+        while let Some(entry) = entries.next().await {
+            let (_, value) = entry.map_err(|e| e as Box<dyn std::error::Error>)?;
+            hashes.push(value.as_ref().try_into()?);
+        }
+        Ok(hashes)
+    }
+
+    // Blocks of the active chain for `range` with their heights, in order and stopping early at the
+    // tip, for analytics jobs and other scans. Blocks are read ahead through the block files on a
+    // blocking thread, bypassing the block cache. A reorg while the stream is read may leave it
+    // yielding blocks of the old chain; an error ends it.
+    pub fn stream_blocks(&self, range: Range<u64>) -> impl Stream<Item = Result<(u64, Block), ScanError>> {
+        let storage = self.storage.clone();
+        let (sender, receiver) = mpsc::channel(BLOCK_STREAM_READ_AHEAD);
+        tokio::spawn(async move {
+            let mut entries = Box::pin(storage.iter_range(CF_HEIGHT_INDEX, range.start.to_be_bytes().to_vec(), range.end.to_be_bytes().to_vec()));
+            let mut reader = BlockFileReader::new();
+            loop {
+                let mut batch = Vec::with_capacity(BLOCK_STREAM_BATCH);
+                let mut failure = None;
+                while batch.len() < BLOCK_STREAM_BATCH {
+                    let Some(entry) = entries.next().await else { break };
+                    match locate_block(&storage, entry).await {
+                        Ok(located) => batch.push(located),
+                        Err(e) => {
+                            failure = Some(e);
+                            break;
+                        }
+                    }
+                }
+                if batch.is_empty() && failure.is_none() {
+                    return;
+                }
+                let read = task::spawn_blocking(move || {
+                    let blocks = batch.into_iter()
+                        .map(|(height, hash, location)| {
+                            let block = Block::decode(&reader.read(&location)?)?;
+                            if block.hash() != hash {
+                                return Err(format!("block file entry for height {} is not the block the height index names", height).into());
+                            }
+                            Ok((height, block))
+                        })
+                        .collect::<Vec<Result<_, ScanError>>>();
+                    (reader, blocks)
+                })
+                .await;
+                let blocks = match read {
+                    Ok((returned, blocks)) => {
+                        reader = returned;
+                        blocks
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e.into())).await;
+                        return;
+                    }
+                };
+                for block in blocks.into_iter().chain(failure.map(Err)) {
+                    let failed = block.is_err();
+                    // The receiver was dropped, so nobody wants the rest of the range
+                    if sender.send(block).await.is_err() || failed {
+                        return;
+                    }
+                }
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+
+    // Rebuilds the UTXO set, height index and any optional indexes by reconnecting the active chain
+    // from the block files. A cancel through `progress` stops it between blocks with the chainstate
+    // consistent up to there, and the next call carries on towards the same tip.
+    pub async fn reindex(&self, progress: &JobProgress) -> Result<u64, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        if self.storage.get_meta(META_SNAPSHOT_BASE).await?.is_some() {
+            return Err("the chain was loaded from a snapshot, so the blocks below its base were never downloaded; resync to reindex".into());
+        }
+        let saved_target = self.storage.get_meta(META_REINDEX_TARGET).await?;
+        // Refuse new blocks, then wait out any connect already under way, so nothing writes into
+        // the chainstate while it is cleared and rebuilt
+        self.reindex_pending.store(true, Ordering::SeqCst);
+        let _connecting = self.connect_lock.lock().await;
+        let target = match saved_target {
+            Some(bytes) => BlockHash::try_from(bytes.as_slice())?,
+            None => {
+                let tip = *self.chain_tip.read();
+                if tip.height.is_none() {
+                    self.reindex_pending.store(false, Ordering::SeqCst);
+                    return Ok(0);
+                }
+                self.storage.put_meta(META_REINDEX_TARGET, tip.hash.to_vec()).await?;
+                self.clear_chainstate().await?;
+                tip.hash
+            }
+        };
+        let best = self.headers.read().get(&target).ok_or("reindex target is missing from the header index")?.height;
+        let start = self.get_chain_height().map_or(0, |height| height + 1);
+        progress.set_total(best + 1);
+
+        let mut connected = 0;
+        for height in start..=best {
+            if progress.is_cancelled() {
+                break;
+            }
+            progress.set_done(height);
+            if height > 0 && height % REINDEX_PROGRESS_INTERVAL == 0 {
+                log::info!("Reindexing: height {} of {} ({:.1}%)", height, best, height as f64 * 100.0 / best as f64);
+            }
+            let hash = self.headers.read().ancestor(&target, height).map(|entry| entry.hash)
+                .ok_or_else(|| format!("header at height {} is missing, cannot reindex", height))?;
+            let block = self.get_block(&hash).await?
+                .ok_or_else(|| format!("block at height {} is missing (pruned?), cannot reindex", height))?;
+            self.validator.validate_block(&block)?;
+            let window_fruits = self.reward_window_fruits().await?;
+            let undo = self.connect_transactions(&block, height, &window_fruits).await?;
+            // Undo data is determined by the chain alone, so existing records stay valid if they can be read
+            if self.get_undo(&hash).await.ok().flatten().is_none() {
+                let location = self.storage.retrieve_block_location(&hash).await?
+                    .ok_or_else(|| format!("block at height {} has no location", height))?;
+                self.store_undo(&hash, &location.file_name, &undo).await?;
+            }
+            self.connect_tip(hash, &block).await?;
+            self.update_indexes(&self.indexes, &block, hash, height, &undo.spent_coins, true).await?;
+            connected += 1;
+        }
+
+        self.flush_utxo_cache().await?;
+        if self.get_chain_height() == Some(best) {
+            progress.set_done(best + 1);
+            self.storage.delete_meta(META_REINDEX_TARGET).await?;
+            self.reindex_pending.store(false, Ordering::SeqCst);
+        } else {
+            log::warn!("Reindex stopped at height {:?} of {}", self.get_chain_height(), best);
+        }
+        Ok(connected)
+    }
+
+    // Empties everything `reindex` rebuilds
+    async fn clear_chainstate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.utxo_cache.lock().await.clear();
+        self.storage.clear_cf(CF_UTXO).await?;
+        self.storage.delete_meta(META_UTXO_SET_SUMMARY).await?;
+        self.storage.delete_meta(META_UTXO_BEST_BLOCK).await?;
+        self.storage.clear_cf(CF_HEIGHT_INDEX).await?;
+        self.storage.clear_cf(CF_BLOCK_HEIGHTS).await?;
+        self.storage.clear_cf(CF_FRUIT_INDEX).await?;
+        self.storage.clear_cf(CF_BLOCK_FILTERS).await?;
+        self.storage.clear_cf(CF_BLOCK_STATS).await?;
+        self.storage.clear_cf(CF_ADDRESS_INDEX).await?;
+        self.storage.clear_cf(CF_ADDRESS_UTXOS).await?;
+        self.storage.delete_meta(META_ADDRESS_INDEX_TIP).await?;
+        self.storage.clear_cf(CF_SPENT_INDEX).await?;
+        self.storage.delete_meta(META_SPENT_INDEX_TIP).await?;
+        *self.chain_tip.write() = ChainTip::empty();
+        self.headers.write().set_tip(None);
+        *self.recent_fruits.write() = RecentFruits::default();
+        *self.versionbits.write() = self.new_versionbits_tracker();
+        Ok(())
+    }
+
+    // Re-reads the top `depth` blocks of the active chain from disk and checks them, each level adding
+    // to the one below: 0 the block decodes, hashes to its index entries and links to its parent;
+    // 1 structure and proof of work; 2 signatures; 3 the UTXO set against the blocks and their undo data
+    pub async fn verify_chain(&self, depth: u64, level: u8, progress: &JobProgress) -> Result<VerifyReport, Box<dyn std::error::Error>> {
+        let mut report = VerifyReport { level, ..VerifyReport::default() };
+        let tip = match self.get_chain_height() {
+            Some(height) => height,
+            None => return Ok(report),
+        };
+        progress.set_total(depth.min(tip + 1));
+        // The UTXO checks need every block above the current one, so they stop at the first unreadable block
+        let mut check_utxos = level >= 3;
+        // Outpoints spent by the blocks already checked, all of them above the current one
+        let mut spent_above = HashSet::new();
+
+        for height in ((tip + 1).saturating_sub(depth)..=tip).rev() {
+            if progress.is_cancelled() {
+                report.cancelled = true;
+                break;
+            }
+            let hash = match self.storage.get_hash_at_height(height).await? {
+                Some(hash) => hash,
+                None => {
+                    report.problem(height, "missing from the height index".into());
+                    check_utxos = false;
+                    continue;
+                }
+            };
+            let location = match self.storage.retrieve_block_location(&hash).await? {
+                Some(location) => location,
+                None => {
+                    report.pruned_at = Some(height);
+                    break;
+                }
+            };
+            report.checked += 1;
+            report.lowest_height = Some(height);
+            progress.set_done(report.checked);
+
+            let block = self.block_storage.read_block_from_file(&location)
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| Block::decode(&bytes).map_err(|e| e.to_string()));
+            let block = match block {
+                Ok(block) if block.hash() == hash => block,
+                Ok(block) => {
+                    report.problem(height, format!("{} holds block {} instead of {}", location.file_name, hex::encode(block.hash()), hex::encode(hash)));
+                    check_utxos = false;
+                    continue;
+                }
+                Err(e) => {
+                    report.problem(height, format!("block {} is unreadable: {}", hex::encode(hash), e));
+                    check_utxos = false;
+                    continue;
+                }
+            };
+
+            if self.storage.get_block_height(&hash).await? != Some(height) {
+                report.problem(height, "block height index disagrees with the height index".into());
+            }
+            if let Some((_, header)) = self.storage.get_header(&hash).await? {
+                if header != block.header {
+                    report.problem(height, "stored header differs from the block's header".into());
+                }
+            }
+            if height > 0 && self.storage.get_hash_at_height(height - 1).await? != Some(block.header.previous_hash) {
+                report.problem(height, "block does not build on the block below it".into());
+            }
+
+            if level >= 1 {
+                if let Err(e) = validation::check_block_structure(&block, &self.params) {
+                    report.problem(height, e.to_string());
+                }
+                if !pow::check_proof_of_work(&hash, block.header.bits) {
+                    report.problem(height, "block hash does not meet its proof of work target".into());
+                }
+            }
+            if level >= 2 {
+                if let Err(e) = self.validator.verify_signatures(&block) {
+                    report.problem(height, e.to_string());
+                }
+            }
+            if check_utxos {
+                for message in self.verify_block_utxos(height, &block, &mut spent_above).await? {
+                    report.problem(height, message);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // Outputs of `block` that nothing above it spends, UTXO commitments aside, must be in the UTXO
+    // set, and the coins its undo data records must be exactly the existing ones it spends and must
+    // no longer be in the set
+    async fn verify_block_utxos(&self, height: u64, block: &Block, spent_above: &mut HashSet<OutPoint>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut problems = Vec::new();
+        let mut created = HashSet::new();
+        let mut spent = HashSet::new();
+        let mut spent_existing = Vec::new();
+        for tx in &block.transactions {
+            for input in &tx.inputs {
+                spent.insert(input.previous_output);
+                if !created.contains(&input.previous_output) {
+                    spent_existing.push(input.previous_output);
+                }
+            }
+            let txid = tx.hash();
+            created.extend((0..tx.outputs.len()).map(|index| OutPoint { txid, index: index as u32 }));
+        }
+
+        let mut utxos = self.utxo_cache.lock().await;
+        for tx in &block.transactions {
+            let txid = tx.hash();
+            for (index, output) in tx.outputs.iter().enumerate().filter(|(_, output)| !reward::is_utxo_commitment(output)) {
+                let outpoint = OutPoint { txid, index: index as u32 };
+                if spent.contains(&outpoint) || spent_above.contains(&outpoint) {
+                    continue;
+                }
+                match utxos.get(&outpoint).await? {
+                    Some(coin) if coin.height == height && coin.output == *output => {}
+                    Some(_) => problems.push(format!("UTXO set entry for {}:{} does not match the block", hex::encode(txid), index)),
+                    None => problems.push(format!("unspent output {}:{} is missing from the UTXO set", hex::encode(txid), index)),
+                }
+            }
+        }
+
+        // As a string, since the boxed error can't be held across the awaits below
+        match self.get_undo(&block.hash()).await.map_err(|e| e.to_string()) {
+            Ok(Some(undo)) => {
+                if !undo.spent_coins.iter().map(|(outpoint, _)| outpoint).eq(spent_existing.iter()) {
+                    problems.push(format!("undo data records {} spent coins that don't match the {} the block spends", undo.spent_coins.len(), spent_existing.len()));
+                }
+                for (outpoint, coin) in &undo.spent_coins {
+                    if coin.height >= height {
+                        problems.push(format!("undo data says {}:{} was created at height {}", hex::encode(outpoint.txid), outpoint.index, coin.height));
+                    }
+                    if utxos.get(outpoint).await?.is_some() {
+                        problems.push(format!("spent output {}:{} is still in the UTXO set", hex::encode(outpoint.txid), outpoint.index));
+                    }
+                }
+            }
+            Ok(None) => problems.push("undo data is missing".into()),
+            Err(e) => problems.push(format!("undo data is unreadable: {}", e)),
+        }
+
+        spent_above.extend(spent);
+        Ok(problems)
+    }
+
+    // Deletes block files whose newest block is more than `keep_blocks` below the tip
+    pub async fn prune(&self, keep_blocks: u64) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let best = match self.get_chain_height() {
+            Some(height) if height >= keep_blocks => height,
+            _ => return Ok(Vec::new()),
+        };
+        let prune_below = best - keep_blocks;
+
+        // The manifest says which heights each file holds, so only those need looking up
+        let current_file = self.block_storage.current_file();
+        let mut pruned = Vec::new();
+        for (file_name, info) in self.block_storage.files() {
+            if info.blocks == 0 || info.last_height >= prune_below || file_name == current_file {
+                continue;
+            }
+            // Drop the locations first so a crash mid-prune leaves an orphaned file rather than dangling entries
+            for hash in self.get_block_hashes(info.first_height..info.last_height + 1).await? {
+                if self.storage.retrieve_block_location(&hash).await?.is_some_and(|location| location.file_name == file_name) {
+                    self.storage.delete_block_location(&hash).await?;
+                    self.storage.delete_undo_location(&hash).await?;
+                    self.block_cache.lock().blocks.remove(&hash);
+                }
+            }
+            std::fs::remove_file(&file_name)?;
+            let undo_file = undo_file_name(&file_name);
+            if std::path::Path::new(&undo_file).exists() {
+                std::fs::remove_file(&undo_file)?;
+            }
+            self.block_storage.forget_file(&file_name)?;
+            pruned.push(file_name);
+        }
+        Ok(pruned)
+    }
+}
+
+// Where block files wait while the chainstate is rebuilt from them, beside the blocks directory
+fn recovery_blocks_dir(blocks_dir: &Path) -> PathBuf {
+    blocks_dir.with_extension("recovery")
+}
+
+// Height, hash and file location of a height index entry, for `stream_blocks`
+async fn locate_block(storage: &Storage, entry: Result<KeyValue, ScanError>) -> Result<(u64, BlockHash, BlockLocation), ScanError> {
+    let (key, value) = entry?;
+    let height = u64::from_be_bytes(key.as_ref().try_into()?);
+    let hash = BlockHash::try_from(value.as_ref())?;
+    let location = storage.retrieve_block_location(&hash).await.map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("block at height {} is missing (pruned?)", height))?;
+    Ok((height, hash, location))
+}
+
+fn work_to_f64(work: U256) -> f64 {
+    work.0.iter().rev().fold(0.0, |total, &limb| total * 2f64.powi(64) + limb as f64)
+}
+
+pub fn calculate_merkle_root(transactions: &[Transaction]) -> [u8; 32] {
+    let leaves = transactions.iter().map(Transaction::hash).collect::<Vec<_>>();
+    merkle::merkle_root(&leaves)
+}
+
+pub fn calculate_fruits_root(fruits: &[FruitHeader]) -> [u8; 32] {
+    let leaves = fruits.iter().map(FruitHeader::hash).collect::<Vec<_>>();
+    merkle::merkle_root(&leaves)
+}
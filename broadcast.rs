@@ -0,0 +1,180 @@
+use crate::protocol::PeerId;
+use crate::transaction::TxHash;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_REBROADCAST_INTERVAL: Duration = Duration::from_secs(15 * 60);
+pub const DEFAULT_BROADCAST_EXPIRY: Duration = Duration::from_secs(14 * 24 * 3600);
+
+struct LocalTransaction {
+    submitted_at: Instant,
+    last_broadcast: Instant,
+    broadcasts: u32,
+    // Peers that announced or requested it back to us, so we know they have it
+    seen_by: HashSet<PeerId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BroadcastStatus {
+    pub txid: TxHash,
+    pub age: Duration,
+    pub broadcasts: u32,
+    pub peers_sent: usize,
+    pub peers_seen: usize,
+}
+
+// Keeps locally submitted transactions moving until they confirm or expire. Each peer is only
+// sent an inv for a transaction once per broadcast round; a round ends after `rebroadcast_interval`
+// and re-announces to every peer that hasn't shown us it already has the transaction.
+pub struct BroadcastManager {
+    local: HashMap<TxHash, LocalTransaction>,
+    // Inventory already sent to each connected peer in the current round
+    sent: HashMap<PeerId, HashSet<TxHash>>,
+    rebroadcast_interval: Duration,
+    expiry: Duration,
+}
+
+impl BroadcastManager {
+    pub fn new(rebroadcast_interval: Duration, expiry: Duration) -> Self {
+        BroadcastManager { local: HashMap::new(), sent: HashMap::new(), rebroadcast_interval, expiry }
+    }
+
+    pub fn submit(&mut self, txid: TxHash, now: Instant) {
+        self.local.entry(txid).or_insert(LocalTransaction {
+            submitted_at: now,
+            last_broadcast: now,
+            broadcasts: 0,
+            seen_by: HashSet::new(),
+        });
+    }
+
+    pub fn peer_connected(&mut self, peer: PeerId) {
+        self.sent.entry(peer).or_default();
+    }
+
+    pub fn peer_disconnected(&mut self, peer: PeerId) {
+        self.sent.remove(&peer);
+        for tx in self.local.values_mut() {
+            tx.seen_by.remove(&peer);
+        }
+    }
+
+    // Called when a peer announces or requests one of our transactions. Peers that were never
+    // connected with `peer_connected` aren't tracked.
+    pub fn mark_seen(&mut self, peer: PeerId, txid: &TxHash) {
+        let (Some(tx), Some(sent)) = (self.local.get_mut(txid), self.sent.get_mut(&peer)) else { return };
+        tx.seen_by.insert(peer);
+        sent.insert(*txid);
+    }
+
+    // Inventory to announce to `peer` now; each returned hash is recorded as sent
+    pub fn pending_for_peer(&mut self, peer: PeerId) -> Vec<TxHash> {
+        let sent = self.sent.entry(peer).or_default();
+        let pending = self.local.iter()
+            .filter(|(txid, tx)| !sent.contains(*txid) && !tx.seen_by.contains(&peer))
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<_>>();
+        sent.extend(pending.iter().copied());
+        for txid in &pending {
+            if let Some(tx) = self.local.get_mut(txid) {
+                tx.broadcasts += 1;
+            }
+        }
+        pending
+    }
+
+    // `pending_for_peer` for every connected peer that has something to be sent
+    pub fn pending(&mut self) -> Vec<(PeerId, Vec<TxHash>)> {
+        let peers = self.sent.keys().copied().collect::<Vec<_>>();
+        peers.into_iter()
+            .map(|peer| (peer, self.pending_for_peer(peer)))
+            .filter(|(_, pending)| !pending.is_empty())
+            .collect()
+    }
+
+    // Expires old transactions and starts a new round for those whose interval has elapsed.
+    // Returns the expired hashes so the caller can drop them from its wallet or mempool view.
+    pub fn tick(&mut self, now: Instant) -> Vec<TxHash> {
+        let expired = self.local.iter()
+            .filter(|(_, tx)| now.duration_since(tx.submitted_at) >= self.expiry)
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<_>>();
+        for txid in &expired {
+            self.forget(txid);
+        }
+
+        for (txid, tx) in self.local.iter_mut() {
+            if now.duration_since(tx.last_broadcast) >= self.rebroadcast_interval {
+                tx.last_broadcast = now;
+                for sent in self.sent.values_mut() {
+                    sent.remove(txid);
+                }
+            }
+        }
+        expired
+    }
+
+    // Confirmed transactions no longer need broadcasting
+    pub fn confirmed(&mut self, txids: &[TxHash]) {
+        for txid in txids {
+            self.forget(txid);
+        }
+    }
+
+    pub fn status(&self, now: Instant) -> Vec<BroadcastStatus> {
+        self.local.iter()
+            .map(|(txid, tx)| BroadcastStatus {
+                txid: *txid,
+                age: now.duration_since(tx.submitted_at),
+                broadcasts: tx.broadcasts,
+                peers_sent: self.sent.values().filter(|sent| sent.contains(txid)).count(),
+                peers_seen: tx.seen_by.len(),
+            })
+            .collect()
+    }
+
+    fn forget(&mut self, txid: &TxHash) {
+        self.local.remove(txid);
+        for sent in self.sent.values_mut() {
+            sent.remove(txid);
+        }
+    }
+}
+
+impl Default for BroadcastManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_REBROADCAST_INTERVAL, DEFAULT_BROADCAST_EXPIRY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rebroadcast_skips_peers_that_have_the_transaction() {
+        let start = Instant::now();
+        let mut manager = BroadcastManager::new(Duration::from_secs(60), Duration::from_secs(600));
+        manager.peer_connected(1);
+        manager.peer_connected(2);
+        manager.submit([1; 32], start);
+
+        assert_eq!(manager.pending_for_peer(1), vec![[1; 32]]);
+        assert!(manager.pending_for_peer(1).is_empty());
+        manager.mark_seen(2, &[1; 32]);
+        assert!(manager.pending_for_peer(2).is_empty());
+
+        // A late peer still gets the announcement
+        manager.peer_connected(3);
+        assert_eq!(manager.pending_for_peer(3), vec![[1; 32]]);
+
+        assert!(manager.tick(start + Duration::from_secs(61)).is_empty());
+        let mut pending = manager.pending();
+        pending.sort_unstable();
+        assert_eq!(pending, vec![(1, vec![[1; 32]]), (3, vec![[1; 32]])]);
+        assert!(manager.pending_for_peer(2).is_empty());
+
+        assert_eq!(manager.tick(start + Duration::from_secs(600)), vec![[1; 32]]);
+        assert!(manager.pending_for_peer(1).is_empty());
+    }
+}
@@ -0,0 +1,5 @@
+// Generates the gRPC service and message types from the protobuf definitions
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::compile_protos("proto/xcore.proto")?;
+    Ok(())
+}
@@ -0,0 +1,421 @@
+use crate::blockchain::{Block, Blockchain};
+use arrow::array::{ArrayRef, BooleanBuilder, StringBuilder, UInt32Builder, UInt64Builder};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio_stream::StreamExt;
+
+// Bumped whenever a column is added, removed, renamed or changes meaning, so analysis code can
+// tell which layout it is reading. Recorded in the manifest and in every Parquet file's metadata.
+pub const EXPORT_SCHEMA_VERSION: u32 = 1;
+pub const EXPORT_MANIFEST_FILE_NAME: &str = "manifest.json";
+const SCHEMA_VERSION_KEY: &str = "xcore.schema_version";
+// Rows buffered per table before they are written out as one Parquet row group
+const ROW_GROUP_ROWS: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to serialize block: {0}")]
+    Serialization(#[from] bincode::Error),
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] ArrowError),
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] ParquetError),
+    #[error("Unknown export format '{0}', expected parquet or csv")]
+    UnknownFormat(String),
+    #[error("{} already holds an export", .0.display())]
+    OutputExists(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Parquet,
+    Csv,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = ExportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "parquet" => Ok(ExportFormat::Parquet),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(ExportError::UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ColumnType {
+    U64,
+    U32,
+    Bool,
+    // Hashes and scripts, as lowercase hex strings
+    Hex,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Table {
+    name: &'static str,
+    columns: &'static [(&'static str, ColumnType)],
+}
+
+// Each block of the range, keyed by height
+const BLOCKS: Table = Table {
+    name: "blocks",
+    columns: &[
+        ("height", ColumnType::U64),
+        ("hash", ColumnType::Hex),
+        ("previous_hash", ColumnType::Hex),
+        ("merkle_root", ColumnType::Hex),
+        ("fruits_root", ColumnType::Hex),
+        ("version", ColumnType::U32),
+        ("timestamp", ColumnType::U64),
+        ("bits", ColumnType::U32),
+        ("nonce", ColumnType::U64),
+        ("transactions", ColumnType::U64),
+        ("fruits", ColumnType::U64),
+        ("size", ColumnType::U64),
+    ],
+};
+
+// Keyed by txid, with `position` the transaction's index within its block
+const TRANSACTIONS: Table = Table {
+    name: "transactions",
+    columns: &[
+        ("height", ColumnType::U64),
+        ("position", ColumnType::U32),
+        ("txid", ColumnType::Hex),
+        ("coinbase", ColumnType::Bool),
+        ("inputs", ColumnType::U32),
+        ("outputs", ColumnType::U32),
+        ("output_value", ColumnType::U64),
+        ("lock_time", ColumnType::U32),
+        ("size", ColumnType::U64),
+    ],
+};
+
+// Keyed by (txid, vout), the outpoint later transactions spend it by
+const OUTPUTS: Table = Table {
+    name: "outputs",
+    columns: &[
+        ("height", ColumnType::U64),
+        ("txid", ColumnType::Hex),
+        ("vout", ColumnType::U32),
+        ("value", ColumnType::U64),
+        ("script_pubkey", ColumnType::Hex),
+    ],
+};
+
+enum Cell<'a> {
+    U64(u64),
+    U32(u32),
+    Bool(bool),
+    Hex(&'a [u8]),
+}
+
+enum ColumnBuilder {
+    U64(UInt64Builder),
+    U32(UInt32Builder),
+    Bool(BooleanBuilder),
+    Hex(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(column_type: ColumnType) -> Self {
+        match column_type {
+            ColumnType::U64 => ColumnBuilder::U64(UInt64Builder::new()),
+            ColumnType::U32 => ColumnBuilder::U32(UInt32Builder::new()),
+            ColumnType::Bool => ColumnBuilder::Bool(BooleanBuilder::new()),
+            ColumnType::Hex => ColumnBuilder::Hex(StringBuilder::new()),
+        }
+    }
+
+    fn append(&mut self, cell: &Cell) {
+        match (self, cell) {
+            (ColumnBuilder::U64(builder), Cell::U64(value)) => builder.append_value(*value),
+            (ColumnBuilder::U32(builder), Cell::U32(value)) => builder.append_value(*value),
+            (ColumnBuilder::Bool(builder), Cell::Bool(value)) => builder.append_value(*value),
+            (ColumnBuilder::Hex(builder), Cell::Hex(bytes)) => builder.append_value(hex::encode(bytes)),
+            _ => unreachable!("rows are built to match their table's columns"),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::U64(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::U32(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Bool(builder) => Arc::new(builder.finish()),
+            ColumnBuilder::Hex(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+enum TableWriter {
+    Csv(BufWriter<File>),
+    Parquet { writer: Box<ArrowWriter<File>>, schema: SchemaRef, columns: Vec<ColumnBuilder>, rows: usize },
+}
+
+impl TableWriter {
+    fn create(dir: &Path, table: Table, format: ExportFormat) -> Result<Self, ExportError> {
+        let file = File::create(dir.join(format!("{}.{}", table.name, format.extension())))?;
+        match format {
+            ExportFormat::Csv => {
+                let mut out = BufWriter::new(file);
+                let names = table.columns.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+                writeln!(out, "{}", names.join(","))?;
+                Ok(TableWriter::Csv(out))
+            }
+            ExportFormat::Parquet => {
+                let fields = table.columns.iter()
+                    .map(|(name, column_type)| {
+                        let data_type = match column_type {
+                            ColumnType::U64 => DataType::UInt64,
+                            ColumnType::U32 => DataType::UInt32,
+                            ColumnType::Bool => DataType::Boolean,
+                            ColumnType::Hex => DataType::Utf8,
+                        };
+                        Field::new(*name, data_type, false)
+                    })
+                    .collect::<Vec<_>>();
+                let metadata = HashMap::from([(SCHEMA_VERSION_KEY.to_string(), EXPORT_SCHEMA_VERSION.to_string())]);
+                let schema = Arc::new(Schema::new(fields).with_metadata(metadata));
+                let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+                let writer = Box::new(ArrowWriter::try_new(file, Arc::clone(&schema), Some(properties))?);
+                let columns = table.columns.iter().map(|(_, column_type)| ColumnBuilder::new(*column_type)).collect();
+                Ok(TableWriter::Parquet { writer, schema, columns, rows: 0 })
+            }
+        }
+    }
+
+    fn push(&mut self, row: &[Cell]) -> Result<(), ExportError> {
+        match self {
+            TableWriter::Csv(out) => {
+                for (i, cell) in row.iter().enumerate() {
+                    if i > 0 {
+                        out.write_all(b",")?;
+                    }
+                    match cell {
+                        Cell::U64(value) => write!(out, "{}", value)?,
+                        Cell::U32(value) => write!(out, "{}", value)?,
+                        Cell::Bool(value) => write!(out, "{}", value)?,
+                        Cell::Hex(bytes) => out.write_all(hex::encode(bytes).as_bytes())?,
+                    }
+                }
+                out.write_all(b"\n")?;
+            }
+            TableWriter::Parquet { columns, rows, .. } => {
+                for (column, cell) in columns.iter_mut().zip(row) {
+                    column.append(cell);
+                }
+                *rows += 1;
+                if *rows >= ROW_GROUP_ROWS {
+                    self.flush_row_group()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_row_group(&mut self) -> Result<(), ExportError> {
+        if let TableWriter::Parquet { writer, schema, columns, rows } = self {
+            if *rows > 0 {
+                let arrays = columns.iter_mut().map(ColumnBuilder::finish).collect();
+                writer.write(&RecordBatch::try_new(Arc::clone(schema), arrays)?)?;
+                writer.flush()?;
+                *rows = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), ExportError> {
+        self.flush_row_group()?;
+        match self {
+            TableWriter::Csv(mut out) => out.flush()?,
+            TableWriter::Parquet { writer, .. } => {
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// What an export holds, saved as its manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExportSummary {
+    pub schema_version: u32,
+    pub format: String,
+    pub network: String,
+    pub from: u64,
+    pub to: u64,
+    pub blocks: u64,
+    pub transactions: u64,
+    pub outputs: u64,
+}
+
+struct ExportTables {
+    blocks: TableWriter,
+    transactions: TableWriter,
+    outputs: TableWriter,
+}
+
+impl ExportTables {
+    fn write_block(&mut self, height: u64, block: &Block, summary: &mut ExportSummary) -> Result<(), ExportError> {
+        let header = &block.header;
+        self.blocks.push(&[
+            Cell::U64(height),
+            Cell::Hex(&block.hash()),
+            Cell::Hex(&header.previous_hash),
+            Cell::Hex(&header.merkle_root),
+            Cell::Hex(&header.fruits_root),
+            Cell::U32(header.version),
+            Cell::U64(header.timestamp),
+            Cell::U32(header.bits),
+            Cell::U64(header.nonce),
+            Cell::U64(block.transactions.len() as u64),
+            Cell::U64(block.fruits.len() as u64),
+            Cell::U64(bincode::serialized_size(block)?),
+        ])?;
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let txid = tx.hash();
+            let output_value = tx.outputs.iter().fold(0u64, |sum, output| sum.saturating_add(output.value));
+            self.transactions.push(&[
+                Cell::U64(height),
+                Cell::U32(position as u32),
+                Cell::Hex(&txid),
+                Cell::Bool(tx.is_coinbase()),
+                Cell::U32(tx.inputs.len() as u32),
+                Cell::U32(tx.outputs.len() as u32),
+                Cell::U64(output_value),
+                Cell::U32(tx.lock_time),
+                Cell::U64(bincode::serialized_size(tx)?),
+            ])?;
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                self.outputs.push(&[
+                    Cell::U64(height),
+                    Cell::Hex(&txid),
+                    Cell::U32(vout as u32),
+                    Cell::U64(output.value),
+                    Cell::Hex(&output.script_pubkey),
+                ])?;
+            }
+            summary.transactions += 1;
+            summary.outputs += tx.outputs.len() as u64;
+        }
+        summary.blocks += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), ExportError> {
+        self.blocks.finish()?;
+        self.transactions.finish()?;
+        self.outputs.finish()
+    }
+}
+
+// Writes blocks `range` of the active chain into `dir` as blocks, transactions and outputs tables
+// for pandas, Spark and the like, one file per table, then a manifest recording the schema
+// version and what was exported. Refuses a directory holding an earlier export.
+pub async fn export_chain(blockchain: &Blockchain, range: RangeInclusive<u64>, format: ExportFormat, dir: &Path) -> Result<ExportSummary, Box<dyn std::error::Error>> {
+    let manifest_path = dir.join(EXPORT_MANIFEST_FILE_NAME);
+    if manifest_path.exists() {
+        return Err(ExportError::OutputExists(dir.to_path_buf()).into());
+    }
+    std::fs::create_dir_all(dir)?;
+    let mut tables = ExportTables {
+        blocks: TableWriter::create(dir, BLOCKS, format)?,
+        transactions: TableWriter::create(dir, TRANSACTIONS, format)?,
+        outputs: TableWriter::create(dir, OUTPUTS, format)?,
+    };
+    let mut summary = ExportSummary {
+        schema_version: EXPORT_SCHEMA_VERSION,
+        format: format.extension().to_string(),
+        network: format!("{:?}", blockchain.params().network).to_lowercase(),
+        from: *range.start(),
+        to: *range.end(),
+        blocks: 0,
+        transactions: 0,
+        outputs: 0,
+    };
+
+    let mut blocks = Box::pin(blockchain.stream_blocks(*range.start()..range.end().saturating_add(1)));
+    while let Some(item) = blocks.next().await {
+        let (height, block) = item.map_err(|e| e as Box<dyn std::error::Error>)?;
+        tables.write_block(height, &block, &mut summary)?;
+    }
+    // The stream stops quietly at the tip
+    if summary.blocks < range.end().saturating_sub(*range.start()) + 1 {
+        return Err(format!("block at height {} is past the tip", range.start() + summary.blocks).into());
+    }
+    tables.finish()?;
+    std::fs::write(&manifest_path, serde_json::to_vec_pretty(&summary)?)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Simulation;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_export_writes_every_table_in_both_formats() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..2 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let chain = &sim.node(0).blockchain;
+        let dir = TempDir::new()?;
+
+        let csv = dir.path().join("csv");
+        let summary = export_chain(chain, 1..=2, ExportFormat::Csv, &csv).await?;
+        assert_eq!((summary.blocks, summary.transactions), (2, 2));
+        let blocks = std::fs::read_to_string(csv.join("blocks.csv"))?;
+        let lines = blocks.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("height,hash,") && lines[2].starts_with(&format!("2,{},", hex::encode(chain.get_chain_tip()))));
+        assert_eq!(std::fs::read_to_string(csv.join("outputs.csv"))?.lines().count() as u64, summary.outputs + 1);
+        let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(csv.join(EXPORT_MANIFEST_FILE_NAME))?)?;
+        assert_eq!(manifest["schema_version"], EXPORT_SCHEMA_VERSION);
+        assert!(export_chain(chain, 1..=2, ExportFormat::Csv, &csv).await.is_err());
+
+        let parquet = dir.path().join("parquet");
+        export_chain(chain, 0..=2, ExportFormat::Parquet, &parquet).await?;
+        let rows = |table: &str| -> Result<i64, Box<dyn std::error::Error>> {
+            let reader = SerializedFileReader::new(File::open(parquet.join(format!("{}.parquet", table)))?)?;
+            Ok(reader.metadata().file_metadata().num_rows())
+        };
+        assert_eq!((rows("blocks")?, rows("transactions")?), (3, 3));
+
+        // Past the tip is an error rather than a short export
+        assert!(export_chain(chain, 0..=5, ExportFormat::Csv, &dir.path().join("short")).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+use crate::difficulty::{self, BLOCK_REWARD, GENESIS_BLOCK_DIFFICULTY, MAX_DIFFICULTY_BITS, MIN_DIFFICULTY_BITS};
+use crate::pow;
+use crate::reward::COIN;
+use primitive_types::U256;
+use crate::versionbits::Deployment;
+use config::{Config, ConfigError, File as ConfigFile, FileFormat};
+use serde::{Serialize, Deserialize};
+use std::path::Path;
+use thiserror::Error;
+
+// Coinbases commit to the UTXO set hash once this deployment is active
+pub const UTXO_COMMITMENT_DEPLOYMENT: &str = "utxocommit";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Main,
+    Test,
+    Regtest,
+    // A private network whose parameters come from a chain file
+    Custom,
+}
+
+#[derive(Error, Debug)]
+pub enum ChainParamsError {
+    #[error("Failed to read chain file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse chain file: {0}")]
+    Parse(#[from] ConfigError),
+    #[error("Invalid chain file: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetargetAlgorithm {
+    // Recompute once every `retarget_interval` blocks from the interval's elapsed time
+    Interval,
+    // Linearly weighted moving average of the last `window` solve times, recomputed every block
+    Lwma { window: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    // Identifies the network to peers so nodes of different chains refuse each other
+    pub magic: [u8; 4],
+    pub max_block_size: usize,
+    pub max_transaction_size: usize,
+    pub max_transactions_per_block: usize,
+    // How many blocks back from the tip a fruit may hang and still be included
+    pub fruit_freshness_window: u64,
+    pub max_fruits_per_block: usize,
+    // Percentage of each block's reward paid to the miners of fruits from the last
+    // `fruit_reward_window` blocks, the rest going to the block's miner
+    pub fruit_reward_share_percent: u64,
+    pub fruit_reward_window: u64,
+    // Target a fruit header's hash must meet. Fixed, and far easier than a block's, so fruits
+    // arrive steadily whatever the block difficulty.
+    pub fruit_bits: u32,
+    pub genesis_bits: u32,
+    // Easiest and hardest targets the difficulty may move between, within the global bounds
+    pub pow_limit_bits: u32,
+    pub max_difficulty_bits: u32,
+    // Coinbase subsidy at height 0, halved every `halving_interval` blocks; 0 never halves
+    pub initial_subsidy: u64,
+    pub halving_interval: u64,
+    pub target_block_spacing_secs: u64,
+    pub retarget: RetargetAlgorithm,
+    // A block more than twice the target spacing after its parent may use minimum difficulty
+    pub allow_min_difficulty_blocks: bool,
+    // Blocks between difficulty retargets under `RetargetAlgorithm::Interval`
+    pub retarget_interval: u64,
+    // When nonzero, a block more than this many target spacings after its parent gets half the
+    // difficulty, halved again every `emergency_half_life_secs` after that, so a chain whose
+    // miners left can recover. Unlike min-difficulty blocks this applies on any network.
+    pub emergency_after_spacings: u64,
+    pub emergency_half_life_secs: u64,
+    // Work a header chain forking off ours must show before its headers are kept, so a node still
+    // catching up can't be filled with cheap forks. Zero on regtest, whose chains are short-lived.
+    pub minimum_chain_work: U256,
+    // Soft forks activated by version-bit signalling, counted over periods of `signalling_period` blocks
+    pub deployments: Vec<Deployment>,
+    pub signalling_period: u64,
+    pub signalling_threshold: u64,
+    // Nodes serving UTXO snapshots take one at every height a multiple of this, so a syncing node
+    // knows which one to ask for
+    pub snapshot_interval: u64,
+    // BIP44 coin type level of wallet key paths
+    pub hd_coin_type: u32,
+}
+
+impl ChainParams {
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Main | Network::Test => ChainParams {
+                network,
+                magic: if network == Network::Main { *b"xcMN" } else { *b"xcTN" },
+                max_block_size: 4 * 1024 * 1024,
+                max_transaction_size: 400 * 1024,
+                max_transactions_per_block: 20_000,
+                fruit_freshness_window: 16,
+                max_fruits_per_block: 1_000,
+                fruit_reward_share_percent: 50,
+                fruit_reward_window: 16,
+                fruit_bits: 0x1f00ffff,
+                genesis_bits: GENESIS_BLOCK_DIFFICULTY,
+                pow_limit_bits: MIN_DIFFICULTY_BITS,
+                max_difficulty_bits: MAX_DIFFICULTY_BITS,
+                initial_subsidy: BLOCK_REWARD * COIN,
+                halving_interval: 0,
+                target_block_spacing_secs: 60,
+                retarget: RetargetAlgorithm::Interval,
+                allow_min_difficulty_blocks: network == Network::Test,
+                retarget_interval: 1440,
+                emergency_after_spacings: 0,
+                emergency_half_life_secs: 3600,
+                // A full retarget interval at the starting difficulty, so forking from genesis costs
+                // at least that. Each release raises it to the chain work of a recent block.
+                minimum_chain_work: pow::block_work(GENESIS_BLOCK_DIFFICULTY) * U256::from(1440u64),
+                // Not scheduled yet: signalling starts once a release sets the start time
+                deployments: vec![Deployment { name: UTXO_COMMITMENT_DEPLOYMENT, bit: 1, start_time: u64::MAX, timeout: u64::MAX }],
+                signalling_period: 1440,
+                // 95%
+                signalling_threshold: 1368,
+                // A day of blocks
+                snapshot_interval: 1440,
+                // "XC"; test networks share 1 as in SLIP-44
+                hd_coin_type: if network == Network::Main { 0x5843 } else { 1 },
+            },
+            // Small limits so tests can hit them without building huge blocks. Custom chains start
+            // from these too, without the test deployment.
+            Network::Regtest | Network::Custom => ChainParams {
+                network,
+                magic: *b"xcRT",
+                max_block_size: 1024 * 1024,
+                max_transaction_size: 100 * 1024,
+                max_transactions_per_block: 5_000,
+                fruit_freshness_window: 4,
+                max_fruits_per_block: 100,
+                fruit_reward_share_percent: 50,
+                fruit_reward_window: 4,
+                fruit_bits: MIN_DIFFICULTY_BITS,
+                genesis_bits: GENESIS_BLOCK_DIFFICULTY,
+                pow_limit_bits: MIN_DIFFICULTY_BITS,
+                max_difficulty_bits: MAX_DIFFICULTY_BITS,
+                initial_subsidy: BLOCK_REWARD * COIN,
+                halving_interval: 0,
+                target_block_spacing_secs: 60,
+                retarget: RetargetAlgorithm::Interval,
+                allow_min_difficulty_blocks: false,
+                retarget_interval: 144,
+                emergency_after_spacings: 0,
+                emergency_half_life_secs: 3600,
+                minimum_chain_work: U256::zero(),
+                // Always open so tests can exercise activation
+                deployments: match network {
+                    Network::Regtest => vec![
+                        Deployment { name: "testdummy", bit: 28, start_time: 0, timeout: u64::MAX },
+                        Deployment { name: UTXO_COMMITMENT_DEPLOYMENT, bit: 1, start_time: 0, timeout: u64::MAX },
+                    ],
+                    _ => Vec::new(),
+                },
+                signalling_period: 144,
+                // 75%
+                signalling_threshold: 108,
+                snapshot_interval: 16,
+                hd_coin_type: 1,
+            },
+        }
+    }
+
+    // Parameters of a private network described by the TOML file at `path`. The file's contents
+    // are hashed into the magic, so nodes only connect if their chain files are identical.
+    pub fn from_file(path: &Path) -> Result<Self, ChainParamsError> {
+        let contents = std::fs::read_to_string(path)?;
+        let spec: ChainSpec = Config::builder()
+            .add_source(ConfigFile::from_str(&contents, FileFormat::Toml))
+            .build()?
+            .try_deserialize()?;
+        spec.into_params(contents.as_bytes())
+    }
+}
+
+// Keys a chain file may set; any left out keep their regtest values
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ChainSpec {
+    // Hex, mixed with the file's hash rather than used as is
+    magic: Option<String>,
+    genesis_bits: Option<u32>,
+    pow_limit_bits: Option<u32>,
+    max_difficulty_bits: Option<u32>,
+    allow_min_difficulty_blocks: Option<bool>,
+    target_block_spacing_secs: Option<u64>,
+    retarget_interval: Option<u64>,
+    // Switches retargeting to LWMA over this many blocks
+    lwma_window: Option<u64>,
+    emergency_after_spacings: Option<u64>,
+    emergency_half_life_secs: Option<u64>,
+    // Hex, most significant digit first
+    minimum_chain_work: Option<String>,
+    initial_subsidy: Option<u64>,
+    halving_interval: Option<u64>,
+    max_block_size: Option<usize>,
+    max_transaction_size: Option<usize>,
+    max_transactions_per_block: Option<usize>,
+    fruit_freshness_window: Option<u64>,
+    max_fruits_per_block: Option<usize>,
+    fruit_reward_share_percent: Option<u64>,
+    fruit_reward_window: Option<u64>,
+    fruit_bits: Option<u32>,
+}
+
+impl ChainSpec {
+    fn into_params(self, contents: &[u8]) -> Result<ChainParams, ChainParamsError> {
+        let invalid = |msg: &str| Err(ChainParamsError::Invalid(msg.to_string()));
+        let mut params = ChainParams::for_network(Network::Custom);
+
+        let base_magic = match &self.magic {
+            Some(magic) => hex::decode(magic).ok().filter(|bytes| bytes.len() == 4)
+                .ok_or_else(|| ChainParamsError::Invalid("magic must be 4 hex-encoded bytes".to_string()))?,
+            None => params.magic.to_vec(),
+        };
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&base_magic);
+        hasher.update(contents);
+        params.magic = hasher.finalize().as_bytes()[..4].try_into().expect("blake3 hashes are 32 bytes");
+
+        params.genesis_bits = self.genesis_bits.unwrap_or(params.genesis_bits);
+        params.pow_limit_bits = self.pow_limit_bits.unwrap_or(params.pow_limit_bits);
+        params.max_difficulty_bits = self.max_difficulty_bits.unwrap_or(params.max_difficulty_bits);
+        params.allow_min_difficulty_blocks = self.allow_min_difficulty_blocks.unwrap_or(params.allow_min_difficulty_blocks);
+        params.target_block_spacing_secs = self.target_block_spacing_secs.unwrap_or(params.target_block_spacing_secs);
+        params.retarget_interval = self.retarget_interval.unwrap_or(params.retarget_interval);
+        if let Some(window) = self.lwma_window {
+            params.retarget = RetargetAlgorithm::Lwma { window };
+        }
+        params.emergency_after_spacings = self.emergency_after_spacings.unwrap_or(params.emergency_after_spacings);
+        params.emergency_half_life_secs = self.emergency_half_life_secs.unwrap_or(params.emergency_half_life_secs);
+        if let Some(work) = &self.minimum_chain_work {
+            params.minimum_chain_work = U256::from_str_radix(work, 16)
+                .map_err(|_| ChainParamsError::Invalid("minimum_chain_work must be a hex number of at most 64 digits".to_string()))?;
+        }
+        params.initial_subsidy = self.initial_subsidy.unwrap_or(params.initial_subsidy);
+        params.halving_interval = self.halving_interval.unwrap_or(params.halving_interval);
+        params.max_block_size = self.max_block_size.unwrap_or(params.max_block_size);
+        params.max_transaction_size = self.max_transaction_size.unwrap_or(params.max_transaction_size);
+        params.max_transactions_per_block = self.max_transactions_per_block.unwrap_or(params.max_transactions_per_block);
+        params.fruit_freshness_window = self.fruit_freshness_window.unwrap_or(params.fruit_freshness_window);
+        params.max_fruits_per_block = self.max_fruits_per_block.unwrap_or(params.max_fruits_per_block);
+        params.fruit_reward_share_percent = self.fruit_reward_share_percent.unwrap_or(params.fruit_reward_share_percent);
+        params.fruit_reward_window = self.fruit_reward_window.unwrap_or(params.fruit_reward_window);
+        params.fruit_bits = self.fruit_bits.unwrap_or(params.fruit_bits);
+
+        let target = difficulty::compact_to_target;
+        if target(params.pow_limit_bits) > target(MIN_DIFFICULTY_BITS) || target(params.max_difficulty_bits) < target(MAX_DIFFICULTY_BITS) {
+            return invalid("pow_limit_bits and max_difficulty_bits must lie within the global difficulty bounds");
+        }
+        if target(params.max_difficulty_bits) > target(params.pow_limit_bits) {
+            return invalid("max_difficulty_bits must be at least as hard as pow_limit_bits");
+        }
+        if target(params.genesis_bits) > target(params.pow_limit_bits) || target(params.genesis_bits) < target(params.max_difficulty_bits) {
+            return invalid("genesis_bits must lie between max_difficulty_bits and pow_limit_bits");
+        }
+        if target(params.fruit_bits) > target(MIN_DIFFICULTY_BITS) || target(params.fruit_bits) < target(MAX_DIFFICULTY_BITS) {
+            return invalid("fruit_bits must lie within the global difficulty bounds");
+        }
+        if params.target_block_spacing_secs == 0 || params.retarget_interval == 0 || params.retarget == (RetargetAlgorithm::Lwma { window: 0 }) {
+            return invalid("target_block_spacing_secs, retarget_interval and lwma_window must be greater than zero");
+        }
+        if params.emergency_half_life_secs == 0 {
+            return invalid("emergency_half_life_secs must be greater than zero");
+        }
+        if params.max_transaction_size > params.max_block_size || params.max_transactions_per_block == 0 {
+            return invalid("max_transaction_size must fit in max_block_size and blocks must allow a transaction");
+        }
+        if params.fruit_freshness_window == 0 {
+            return invalid("fruit_freshness_window must be greater than zero");
+        }
+        if params.fruit_reward_share_percent > 100 {
+            return invalid("fruit_reward_share_percent must be at most 100");
+        }
+        Ok(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_file_overrides_regtest_and_sets_magic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("my_net.toml");
+        std::fs::write(&path, "target_block_spacing_secs = 30\nlwma_window = 45\ninitial_subsidy = 1000\nhalving_interval = 100\n").unwrap();
+        let params = ChainParams::from_file(&path).unwrap();
+        assert_eq!(params.network, Network::Custom);
+        assert_eq!((params.target_block_spacing_secs, params.initial_subsidy, params.halving_interval), (30, 1000, 100));
+        assert_eq!(params.retarget, RetargetAlgorithm::Lwma { window: 45 });
+        assert!(params.deployments.is_empty());
+
+        // Any change to the file moves the magic
+        std::fs::write(&path, "target_block_spacing_secs = 31\nlwma_window = 45\ninitial_subsidy = 1000\nhalving_interval = 100\n").unwrap();
+        assert_ne!(ChainParams::from_file(&path).unwrap().magic, params.magic);
+
+        std::fs::write(&path, "fruit_reward_share_percent = 101\n").unwrap();
+        assert!(matches!(ChainParams::from_file(&path), Err(ChainParamsError::Invalid(_))));
+        std::fs::write(&path, "fruit_freshness_window = 0\n").unwrap();
+        assert!(matches!(ChainParams::from_file(&path), Err(ChainParamsError::Invalid(_))));
+        std::fs::write(&path, "block_interval = 30\n").unwrap();
+        assert!(matches!(ChainParams::from_file(&path), Err(ChainParamsError::Parse(_))));
+    }
+}
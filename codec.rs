@@ -0,0 +1,249 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+// Stored and relayed objects start with a format version byte followed by a bincode payload.
+// Later versions may only add fields to the end of the top-level object, or to the end of a nested
+// object framed with `framed`/`framed_seq`; decoders skip trailing bytes they don't know about, so
+// data written by a newer node still reads here. Types whose layout changed otherwise check the
+// version with `split_version` and decode the old layout.
+//   1: initial versioned format
+//   2: block headers gained a leading `version` field
+//   3: a block's header, fruits and transactions are framed
+pub const FORMAT_VERSION: u8 = 3;
+// Oldest version this build can decode; 0 is reserved so unversioned data is never mistaken for it
+pub const MIN_FORMAT_VERSION: u8 = 1;
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Encoded data is empty")]
+    Empty,
+    #[error("Unsupported format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Malformed payload: {0}")]
+    Payload(#[from] bincode::Error),
+}
+
+// Same fixed-width integer layout as `bincode::serialize`, so payloads match the unversioned format
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = Vec::with_capacity(1 + options().serialized_size(value)? as usize);
+    bytes.push(FORMAT_VERSION);
+    options().serialize_into(&mut bytes, value)?;
+    Ok(bytes)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    let (_, payload) = split_version(bytes)?;
+    decode_payload(payload)
+}
+
+pub fn split_version(bytes: &[u8]) -> Result<(u8, &[u8]), CodecError> {
+    let (&version, payload) = bytes.split_first().ok_or(CodecError::Empty)?;
+    if version < MIN_FORMAT_VERSION {
+        return Err(CodecError::UnsupportedVersion(version));
+    }
+    Ok((version, payload))
+}
+
+pub fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CodecError> {
+    Ok(options().deserialize(payload)?)
+}
+
+// Bytes `framed` adds in front of each object: the bincode length of the frame
+pub const FRAME_OVERHEAD: usize = 8;
+
+// `#[serde(with = "codec::framed")]`: the field is written as a length-prefixed frame, so a decoder
+// that knows fewer of its fields skips the rest of the frame instead of misreading what follows.
+// Human-readable formats such as the JSON RPC output get the plain value.
+pub mod framed {
+    use super::{decode_payload, options};
+    use bincode::Options;
+    use serde::de::{DeserializeOwned, Error as _};
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return value.serialize(serializer);
+        }
+        let frame = options().serialize(value).map_err(S::Error::custom)?;
+        serializer.serialize_bytes(&frame)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        if deserializer.is_human_readable() {
+            return T::deserialize(deserializer);
+        }
+        let frame = Vec::<u8>::deserialize(deserializer)?;
+        decode_payload(&frame).map_err(D::Error::custom)
+    }
+}
+
+// `framed` for each element of a `Vec`
+pub mod framed_seq {
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    struct FrameRef<'a, T: Serialize>(#[serde(with = "super::framed")] &'a T);
+
+    #[derive(Deserialize)]
+    #[serde(bound = "T: DeserializeOwned")]
+    struct Frame<T>(#[serde(with = "super::framed")] T);
+
+    pub fn serialize<T: Serialize, S: Serializer>(values: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(values.iter().map(FrameRef))
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<T>, D::Error> {
+        Ok(Vec::<Frame<T>>::deserialize(deserializer)?.into_iter().map(|Frame(value)| value).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Block, BlockHeader, BlockType, BlockUndo, FruitHeader};
+    use crate::protocol::Message;
+    use crate::testutil::arbitrary;
+    use crate::transaction::{Coin, OutPoint, Transaction, TxOutput};
+    use proptest::prelude::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct V1 {
+        a: u32,
+        b: Vec<u8>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct V2 {
+        a: u32,
+        b: Vec<u8>,
+        c: u64,
+    }
+
+    #[test]
+    fn test_newer_payloads_decode_with_older_struct() {
+        let v1 = V1 { a: 7, b: vec![1, 2, 3] };
+        assert_eq!(decode::<V1>(&encode(&v1).unwrap()).unwrap(), v1);
+
+        let mut newer = encode(&V2 { a: 7, b: vec![1, 2, 3], c: 99 }).unwrap();
+        newer[0] = FORMAT_VERSION + 1;
+        assert_eq!(decode::<V1>(&newer).unwrap(), v1);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(bound(serialize = "T: Serialize", deserialize = "T: DeserializeOwned"))]
+    struct Outer<T> {
+        #[serde(with = "framed")]
+        inner: T,
+        #[serde(with = "framed_seq")]
+        items: Vec<T>,
+        tail: u8,
+    }
+
+    #[test]
+    fn test_framed_structs_can_gain_fields() {
+        let newer = Outer {
+            inner: V2 { a: 1, b: vec![2], c: 3 },
+            items: vec![V2 { a: 4, b: vec![], c: 5 }, V2 { a: 6, b: vec![7, 8], c: 9 }],
+            tail: 10,
+        };
+        let older = Outer {
+            inner: V1 { a: 1, b: vec![2] },
+            items: vec![V1 { a: 4, b: vec![] }, V1 { a: 6, b: vec![7, 8] }],
+            tail: 10,
+        };
+        assert_eq!(decode::<Outer<V1>>(&encode(&newer).unwrap()).unwrap(), older);
+        assert_eq!(decode::<Outer<V1>>(&encode(&older).unwrap()).unwrap(), older);
+    }
+
+    #[test]
+    fn test_framed_fields_are_plain_in_json() {
+        let value = Outer { inner: V1 { a: 1, b: vec![2] }, items: vec![V1 { a: 3, b: vec![] }], tail: 4 };
+        let json = serde_json::to_value(&value).unwrap();
+        assert_eq!(json, json!({ "inner": { "a": 1, "b": [2] }, "items": [{ "a": 3, "b": [] }], "tail": 4 }));
+        assert_eq!(serde_json::from_value::<Outer<V1>>(json).unwrap(), value);
+    }
+
+    #[test]
+    fn test_undo_records_are_versioned() {
+        let coin = Coin { output: TxOutput { value: 5, script_pubkey: vec![1, 2] }, height: 3, median_time_past: 4, is_coinbase: false };
+        let undo = BlockUndo { spent_coins: vec![(OutPoint { txid: [7; 32], index: 1 }, coin)] };
+        let encoded = undo.encode().unwrap();
+        assert!(encoded.starts_with(b"undo"));
+        assert_eq!(BlockUndo::decode(&encoded).unwrap().spent_coins, undo.spent_coins);
+
+        // Records written before undo data was versioned are plain bincode
+        let legacy = bincode::serialize(&undo.spent_coins).unwrap();
+        assert_eq!(BlockUndo::decode(&legacy).unwrap().spent_coins, undo.spent_coins);
+    }
+
+    #[test]
+    fn test_unversioned_data_is_rejected() {
+        assert!(matches!(decode::<V1>(&[]), Err(CodecError::Empty)));
+        let mut legacy = bincode::serialize(&V1 { a: 1, b: vec![] }).unwrap();
+        legacy.insert(0, 0);
+        assert!(matches!(decode::<V1>(&legacy), Err(CodecError::UnsupportedVersion(0))));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_blocks_and_transactions_round_trip(block in arbitrary::block(), tx in arbitrary::transaction()) {
+            let encoded = encode(&block).unwrap();
+            let decoded = Block::decode(&encoded).unwrap();
+            prop_assert_eq!(decoded.hash(), block.hash());
+            prop_assert_eq!(encode(&decoded).unwrap(), encoded);
+
+            let encoded = encode(&tx).unwrap();
+            prop_assert_eq!(decode::<Transaction>(&encoded).unwrap(), tx);
+        }
+
+        // Blocks stored before their parts were framed
+        #[test]
+        fn prop_format_2_blocks_still_decode(block in arbitrary::block()) {
+            #[derive(Serialize)]
+            struct Unframed<'a> {
+                header: &'a BlockHeader,
+                block_type: BlockType,
+                fruit_header: &'a Option<FruitHeader>,
+                fruits: &'a Vec<FruitHeader>,
+                transactions: &'a Vec<Transaction>,
+            }
+            let unframed = Unframed {
+                header: &block.header,
+                block_type: block.block_type,
+                fruit_header: &block.fruit_header,
+                fruits: &block.fruits,
+                transactions: &block.transactions,
+            };
+            let mut encoded = vec![2];
+            encoded.extend(bincode::serialize(&unframed).unwrap());
+            let decoded = Block::decode(&encoded).unwrap();
+            prop_assert_eq!(decoded.hash(), block.hash());
+            prop_assert_eq!(encode(&decoded).unwrap(), encode(&block).unwrap());
+        }
+
+        #[test]
+        fn prop_truncated_blocks_are_rejected(block in arbitrary::block(), cut in any::<prop::sample::Index>()) {
+            let encoded = encode(&block).unwrap();
+            prop_assert!(Block::decode(&encoded[..cut.index(encoded.len())]).is_err());
+        }
+
+        // Malformed network input must fail cleanly, never panic
+        #[test]
+        fn prop_garbage_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = Block::decode(&bytes);
+            let _ = decode::<Transaction>(&bytes);
+            let _ = decode::<Message>(&bytes);
+        }
+    }
+}
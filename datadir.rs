@@ -0,0 +1,79 @@
+use crate::node_config::BlockchainConfig;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub const LOCK_FILE_NAME: &str = ".lock";
+
+#[derive(Error, Debug)]
+pub enum DataDirError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0} is in use by another xcored process")]
+    Locked(PathBuf),
+}
+
+// Exclusive claim on a data directory, held until dropped. The OS releases it if the process dies,
+// so a stale lock file never keeps a node from starting.
+#[derive(Debug)]
+pub struct DataDirLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl DataDirLock {
+    pub fn acquire(datadir: &Path) -> Result<Self, DataDirError> {
+        fs::create_dir_all(datadir)?;
+        let path = datadir.join(LOCK_FILE_NAME);
+        let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+        try_lock_exclusive(&file).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => DataDirError::Locked(datadir.to_path_buf()),
+            _ => DataDirError::Io(e),
+        })?;
+        // The pid is informational only; the lock itself is what counts
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(DataDirLock { _file: file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+// Creates the chainstate, block and wallet directories the config points at
+pub fn create_layout(config: &BlockchainConfig) -> io::Result<()> {
+    fs::create_dir_all(&config.db_path)?;
+    fs::create_dir_all(&config.blocks_dir)?;
+    fs::create_dir_all(&config.wallets_dir)
+}
+
+#[cfg(unix)]
+fn try_lock_exclusive(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(unix))]
+fn try_lock_exclusive(_file: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "data directory locking is only implemented on Unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_second_instance_is_refused() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let lock = DataDirLock::acquire(temp_dir.path())?;
+        assert!(matches!(DataDirLock::acquire(temp_dir.path()), Err(DataDirError::Locked(_))));
+
+        drop(lock);
+        DataDirLock::acquire(temp_dir.path())?;
+        Ok(())
+    }
+}
@@ -1,72 +1,94 @@
-
-
-```rust
+use crate::pow;
+use primitive_types::{U256, U512};
 use serde::{Serialize, Deserialize};
 use std::cmp::{min, max};
 
+// Hardest and easiest targets a block may use, as compact bits
 pub const MAX_DIFFICULTY_BITS: u32 = 0x1f000001;
 pub const MIN_DIFFICULTY_BITS: u32 = 0x207fffff;
 pub const GENESIS_BLOCK_DIFFICULTY: u32 = 0x207fffff;
 pub const BLOCK_REWARD: u64 = 50; // 50 XTAL
 
+// Compact bits are a base-256 float: one exponent byte giving the target's length in bytes and a
+// 3-byte mantissa holding its leading bytes. Bit 23 of the mantissa is a sign bit and is never set.
+const MANTISSA_MASK: u32 = 0x007f_ffff;
+const SIGN_BIT: u32 = 0x0080_0000;
+
+// Expands compact `bits` into a 256-bit target. Mantissa bytes that fall outside 256 bits are dropped.
+pub fn compact_to_target(bits: u32) -> U256 {
+    let exponent = bits >> 24;
+    let mantissa = U256::from(bits & MANTISSA_MASK);
+    if exponent <= 3 {
+        mantissa >> (8 * (3 - exponent))
+    } else if exponent > 34 {
+        // Every mantissa byte would be shifted past bit 255
+        U256::zero()
+    } else {
+        let shift = 8 * (exponent - 3) as usize;
+        // Shl on U256 discards overflowing bits, matching the byte-wise expansion
+        mantissa << shift
+    }
+}
+
+// Smallest compact encoding of `target`, rounding it down to three significant bytes
+pub fn target_to_compact(target: U256) -> u32 {
+    let mut exponent = target.bits().div_ceil(8) as u32;
+    let mut mantissa = if exponent <= 3 {
+        target.low_u32() << (8 * (3 - exponent))
+    } else {
+        (target >> (8 * (exponent - 3) as usize)).low_u32()
+    };
+    // Keep the sign bit clear by moving one byte into the exponent
+    if mantissa & SIGN_BIT != 0 {
+        mantissa >>= 8;
+        exponent += 1;
+    }
+    exponent << 24 | mantissa
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Difficulty {
     pub bits: u32,
 }
 
 impl Difficulty {
+    // Clamps `bits` into the allowed range, comparing targets rather than the raw encodings
     pub fn new(bits: u32) -> Self {
-        Difficulty { bits: max(min(bits, MAX_DIFFICULTY_BITS), MIN_DIFFICULTY_BITS) }
-    }
-
-    pub fn target(&self) -> [u8; 16] {
-        let exponent = self.bits >> 24;
-        let mantissa = self.bits & 0x00ffffff;
-        let mut target = [0u8; 16];
-       
-        let significant_bytes = min(exponent as usize, 16);
-        let start_idx = 16 - significant_bytes;
-       
-        target[start_idx] = (mantissa >> 16) as u8;
-        if significant_bytes > 1 {
-            target[start_idx + 1] = ((mantissa >> 8) & 0xff) as u8;
-        }
-        if significant_bytes > 2 {
-            target[start_idx + 2] = (mantissa & 0xff) as u8;
-        }
-       
+        let target = compact_to_target(bits);
+        let bits = if target > compact_to_target(MIN_DIFFICULTY_BITS) {
+            MIN_DIFFICULTY_BITS
+        } else if target < compact_to_target(MAX_DIFFICULTY_BITS) {
+            MAX_DIFFICULTY_BITS
+        } else {
+            bits
+        };
+        Difficulty { bits }
+    }
+
+    // Big-endian target, directly comparable with block hashes
+    pub fn target(&self) -> [u8; 32] {
+        let mut target = [0u8; 32];
+        self.to_target().to_big_endian(&mut target);
         target
     }
 
-    pub fn from_target(target: &[u8; 16]) -> Self {
-        let mut significant_bytes = 0;
-        let mut mantissa = 0u32;
-        for (i, &byte) in target.iter().enumerate() {
-            if byte != 0 {
-                significant_bytes = 16 - i;
-                mantissa = (byte as u32) << 16;
-                if i + 1 < 16 { mantissa |= (target[i + 1] as u32) << 8; }
-                if i + 2 < 16 { mantissa |= target[i + 2] as u32; }
-                break;
-            }
-        }
-        let bits = (significant_bytes as u32) << 24 | (mantissa & 0x00ffffff);
-        Difficulty::new(bits)
+    pub fn from_target(target: &[u8; 32]) -> Self {
+        Difficulty::from_target_u256(U256::from_big_endian(target))
     }
 
     pub fn to_float(&self) -> f64 {
-        let exponent = self.bits >> 24;
-        let mantissa = self.bits & 0x00ffffff;
-        (mantissa as f64) * 2f64.powi(8 * (exponent as i32 - 3))
+        let exponent = (self.bits >> 24) as i32;
+        let mantissa = self.bits & MANTISSA_MASK;
+        (mantissa as f64) * 2f64.powi(8 * (exponent - 3))
     }
 
-    pub fn to_target(&self) -> u128 {
-        let exponent = self.bits >> 24;
-        let mantissa = self.bits & 0x00ffffff;
-        let mut target = 0u128;
-        
-        target |= (mantissa as u128) << (8 * (exponent as u128 - 3));
-        target
+    pub fn to_target(&self) -> U256 {
+        compact_to_target(self.bits)
+    }
+
+    // Expected hashes to meet this difficulty, from the full 256-bit compact target
+    pub fn work(&self) -> U256 {
+        pow::block_work(self.bits)
     }
 
     pub fn relative_difficulty(&self, other: &Difficulty) -> f64 {
@@ -75,26 +97,12 @@ impl Difficulty {
 
     pub fn stem_difficulty(&self) -> Self {
         let target = self.to_target();
-        let stem_target = target.saturating_mul(2); // Double the target (half the difficulty)
-        Difficulty::from_target_u128(stem_target)
+        let stem_target = target.saturating_mul(U256::from(2)); // Double the target (half the difficulty)
+        Difficulty::from_target_u256(stem_target)
     }
 
-    pub fn from_target_u128(target: u128) -> Self {
-        let mut bits = 0u32;
-        let mut shifted_target = target;
-
-        // Find the most significant byte
-        let mut exponent = 32;
-        while shifted_target > 0 && exponent > 3 {
-            shifted_target >>= 8;
-            exponent -= 1;
-        }
-
-        // Extract mantissa (3 most significant bytes)
-        let mantissa = min(target >> (8 * (exponent - 3)), 0x00ffffff) as u32;
-
-        bits = (exponent << 24) | mantissa;
-        Difficulty::new(bits)
+    pub fn from_target_u256(target: U256) -> Self {
+        Difficulty::new(target_to_compact(target))
     }
 }
 
@@ -102,8 +110,202 @@ pub fn adjust_difficulty(current_difficulty: Difficulty, actual_timespan: u64, t
     const MAX_ADJUSTMENT_RATIO: u64 = 4;
     let adjusted_timespan = min(max(actual_timespan, target_timespan / MAX_ADJUSTMENT_RATIO), target_timespan * MAX_ADJUSTMENT_RATIO);
     let current_target = current_difficulty.to_target();
-    let new_target = (current_target as u128 * adjusted_timespan as u128 / target_timespan as u128).min(u128::MAX);
-    let new_difficulty = Difficulty::from_target_u128(new_target);
-    let percent_change = (new_difficulty.bits as f64 - current_difficulty.bits as f64) / current_difficulty.bits as f64 * 100.0;
+    // Widened so an easy target times a long timespan can't overflow
+    let scaled = current_target.full_mul(U256::from(adjusted_timespan)) / U512::from(target_timespan);
+    let new_target = U256::try_from(scaled).unwrap_or(U256::MAX);
+    let new_difficulty = Difficulty::from_target_u256(new_target);
+    // Positive when blocks became harder to find
+    let percent_change = (current_difficulty.to_float() / new_difficulty.to_float() - 1.0) * 100.0;
     (new_difficulty, percent_change)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_compact_round_trips() {
+        // Every exponent with mantissas spread over the normalised range
+        for exponent in 1..=32u32 {
+            for mantissa in (0x008000..=0x7fffffu32).step_by(0x7f1) {
+                let bits = exponent << 24 | mantissa;
+                let target = compact_to_target(bits);
+                if target.is_zero() {
+                    continue;
+                }
+                // Re-encoding gives back a target equal to the original
+                assert_eq!(compact_to_target(target_to_compact(target)), target, "bits {:#010x}", bits);
+                if mantissa >= 0x010000 && exponent >= 3 {
+                    assert_eq!(target_to_compact(target), bits, "bits {:#010x}", bits);
+                }
+            }
+        }
+
+        // Arbitrary targets round down to three significant bytes
+        for shift in 0..256usize {
+            let target = U256::MAX >> shift;
+            let rounded = compact_to_target(target_to_compact(target));
+            assert!(rounded <= target);
+            assert_eq!(rounded.bits(), target.bits());
+        }
+        assert_eq!(target_to_compact(U256::zero()), 0);
+        assert_eq!(compact_to_target(0x1d00ffff), U256::from(0xffff) << 208);
+        assert_eq!(target_to_compact(U256::from(0x80)), 0x02008000);
+    }
+
+    #[test]
+    fn test_new_clamps_by_target() {
+        assert_eq!(Difficulty::new(0x2100ffff).bits, MIN_DIFFICULTY_BITS);
+        assert_eq!(Difficulty::new(0x1d00ffff).bits, MAX_DIFFICULTY_BITS);
+        assert_eq!(Difficulty::new(0x2000ffff).bits, 0x2000ffff);
+
+        let (easier, change) = adjust_difficulty(Difficulty::new(0x2000ffff), 200, 100);
+        assert_eq!(easier.to_target(), Difficulty::new(0x2000ffff).to_target() * 2);
+        assert!(change < 0.0);
+    }
+
+    // Canonical compact bits with the target they encode, as mantissa << shift. Float values are
+    // mantissa * 2^shift exactly. Targets from 2^128 up cover what a 16-byte target can't hold.
+    const GOLDEN_VECTORS: &[(u32, u32, usize)] = &[
+        (0x02008000, 0x80, 0),
+        (0x03123456, 0x123456, 0),
+        (0x04123456, 0x123456, 8),
+        (0x05009234, 0x9234, 16),
+        (0x10123456, 0x123456, 104),
+        (0x1100ffff, 0xffff, 112),
+        (0x11010000, 0x010000, 112),
+        (0x11123456, 0x123456, 112),
+        (0x13123456, 0x123456, 128),
+        (0x1d00ffff, 0xffff, 208),
+        (0x1d010000, 0x010000, 208),
+        (0x1f00ffff, 0xffff, 224),
+        (0x207fffff, 0x7fffff, 232),
+    ];
+
+    #[test]
+    fn test_golden_vectors() {
+        for &(bits, mantissa, shift) in GOLDEN_VECTORS {
+            let target = U256::from(mantissa) << shift;
+            assert_eq!(compact_to_target(bits), target, "bits {:#010x}", bits);
+            assert_eq!(target_to_compact(target), bits, "bits {:#010x}", bits);
+            assert_eq!(Difficulty { bits }.to_float(), mantissa as f64 * 2f64.powi(shift as i32), "bits {:#010x}", bits);
+            // Below 2^128 the target agrees with plain 128-bit arithmetic; from there on it must
+            // keep its high bits rather than wrap
+            if target.bits() <= 128 {
+                assert_eq!(target.as_u128(), (mantissa as u128) << shift, "bits {:#010x}", bits);
+            } else {
+                assert!(shift + 24 > 128 && target > U256::from(u128::MAX), "bits {:#010x}", bits);
+            }
+        }
+
+        // The largest 16-byte target rounds down to three bytes, and 2^128 needs a 17th
+        assert_eq!(target_to_compact(U256::from(u128::MAX)), 0x1100ffff);
+        assert_eq!(target_to_compact(U256::from(u128::MAX) + 1), 0x11010000);
+        assert_eq!(compact_to_target(0x1100ffff), U256::from(0xffffu128 << 112));
+    }
+
+    #[test]
+    fn test_non_canonical_bits() {
+        // The sign bit is ignored, and mantissa bytes shifted below the point are dropped
+        assert_eq!(compact_to_target(0x04923456), U256::from(0x12345600u64));
+        assert_eq!(compact_to_target(0x01003456), U256::zero());
+        assert_eq!(compact_to_target(0x02123456), U256::from(0x1234));
+        // Exponents past 32 keep only what still fits in 256 bits
+        assert_eq!(compact_to_target(0x217fffff), U256::from(0xffff) << 240);
+        assert_eq!(compact_to_target(0x227fffff), U256::from(0xff) << 248);
+        assert_eq!(compact_to_target(0x23ffffff), U256::zero());
+        assert_eq!(compact_to_target(0xff7fffff), U256::zero());
+        // MAX_DIFFICULTY_BITS is a longhand form of 0x1d010000; both name the same target
+        assert_eq!(compact_to_target(MAX_DIFFICULTY_BITS), compact_to_target(0x1d010000));
+        assert_eq!(target_to_compact(compact_to_target(MAX_DIFFICULTY_BITS)), 0x1d010000);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let easiest = compact_to_target(MIN_DIFFICULTY_BITS);
+        let hardest = compact_to_target(MAX_DIFFICULTY_BITS);
+        assert_eq!(hardest, U256::one() << 224);
+        assert_eq!(Difficulty::new(MIN_DIFFICULTY_BITS).bits, MIN_DIFFICULTY_BITS);
+        assert_eq!(Difficulty::new(MAX_DIFFICULTY_BITS).bits, MAX_DIFFICULTY_BITS);
+        assert_eq!(GENESIS_BLOCK_DIFFICULTY, MIN_DIFFICULTY_BITS);
+
+        // One step past either bound clamps to it
+        assert_eq!(Difficulty::from_target_u256((U256::from(0x7fffff) + 1) << 232).bits, MIN_DIFFICULTY_BITS);
+        assert_eq!(Difficulty::from_target_u256(U256::MAX).bits, MIN_DIFFICULTY_BITS);
+        assert_eq!(Difficulty::from_target_u256(hardest - 1).bits, MAX_DIFFICULTY_BITS);
+        assert_eq!(Difficulty::new(0).bits, MAX_DIFFICULTY_BITS);
+        assert_eq!(Difficulty::from_target_u256(easiest).bits, MIN_DIFFICULTY_BITS);
+
+        // Targets in either direction through the big-endian form
+        for bits in [MIN_DIFFICULTY_BITS, 0x1d010000, 0x1f00ffff] {
+            let difficulty = Difficulty::new(bits);
+            assert_eq!(Difficulty::from_target(&difficulty.target()).to_target(), difficulty.to_target());
+        }
+        assert_eq!(Difficulty::new(MAX_DIFFICULTY_BITS).target()[3], 0x01);
+
+        // Halving the difficulty saturates at the easiest target
+        assert_eq!(Difficulty::new(MIN_DIFFICULTY_BITS).stem_difficulty().bits, MIN_DIFFICULTY_BITS);
+        assert_eq!(Difficulty::new(MAX_DIFFICULTY_BITS).stem_difficulty().to_target(), hardest * 2);
+        assert_eq!(Difficulty::new(MAX_DIFFICULTY_BITS).relative_difficulty(&Difficulty::new(0x1f00ffff)), 65535.0);
+
+        // Harder targets mean more work
+        assert!(Difficulty::new(MAX_DIFFICULTY_BITS).work() > Difficulty::new(MIN_DIFFICULTY_BITS).work());
+        assert_eq!(Difficulty::new(MIN_DIFFICULTY_BITS).work(), U256::from(2));
+    }
+
+    #[test]
+    fn test_retarget_agrees_with_the_encoding() {
+        let base = Difficulty::new(0x1f00ffff);
+
+        // On schedule the bits don't move, for every vector within the allowed range
+        for &(bits, _, _) in GOLDEN_VECTORS.iter().filter(|(bits, _, _)| Difficulty::new(*bits).bits == *bits) {
+            let (next, change) = adjust_difficulty(Difficulty::new(bits), 600, 600);
+            assert_eq!((next.bits, change), (bits, 0.0), "bits {:#010x}", bits);
+        }
+
+        // Adjustment is limited to a factor of four either way, exactly for this target
+        let (harder, change) = adjust_difficulty(base, 0, 100);
+        assert_eq!(harder.to_target(), base.to_target() / 4);
+        assert_eq!(harder.bits, 0x1e3fffc0);
+        assert_eq!(change, 300.0);
+        assert_eq!(adjust_difficulty(base, 25, 100).0.bits, harder.bits);
+        let (easier, change) = adjust_difficulty(base, 1000, 100);
+        assert_eq!(easier.to_target(), base.to_target() * 4);
+        assert_eq!(change, -75.0);
+        assert_eq!(adjust_difficulty(base, 400, 100).0.bits, easier.bits);
+
+        // Near the bounds the result clamps instead of overflowing
+        let (next, change) = adjust_difficulty(Difficulty::new(MIN_DIFFICULTY_BITS), 400, 100);
+        assert_eq!((next.bits, change), (MIN_DIFFICULTY_BITS, 0.0));
+        assert_eq!(adjust_difficulty(Difficulty::new(MAX_DIFFICULTY_BITS), 0, 100).0.bits, MAX_DIFFICULTY_BITS);
+
+        // Slower blocks never give a harder target, and the result is always canonical and in range
+        let mut previous = U256::zero();
+        for actual in (0..=500).step_by(7) {
+            let (next, change) = adjust_difficulty(base, actual, 100);
+            assert!(next.to_target() >= previous, "timespan {}", actual);
+            assert_eq!(target_to_compact(next.to_target()), next.bits, "timespan {}", actual);
+            assert_eq!(Difficulty::new(next.bits).bits, next.bits, "timespan {}", actual);
+            assert!((change - (base.to_float() / next.to_float() - 1.0) * 100.0).abs() < 1e-9);
+            previous = next.to_target();
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_any_bits_round_trip_through_target(bits in any::<u32>()) {
+            let target = compact_to_target(bits);
+            prop_assert_eq!(compact_to_target(target_to_compact(target)), target);
+            prop_assert_eq!(target_to_compact(target) & SIGN_BIT, 0);
+        }
+
+        #[test]
+        fn prop_targets_round_down_to_three_bytes(limbs in any::<[u64; 4]>()) {
+            let target = U256(limbs);
+            let rounded = compact_to_target(target_to_compact(target));
+            prop_assert!(rounded <= target);
+            prop_assert_eq!(rounded.bits(), target.bits());
+        }
+    }
+}
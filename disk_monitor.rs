@@ -0,0 +1,138 @@
+use crate::node_config::BlockchainConfig;
+use crate::node_status;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::sync::watch;
+
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskState {
+    Ok,
+    // Under `disk.low_free_mb`: block download pauses until space is freed
+    Low,
+    // Under `disk.min_free_mb`: blocks are refused and the node shuts down
+    Critical,
+}
+
+// Watches free space on the filesystems holding the block files and the chainstate, so the node
+// stops while there is still room to flush cleanly rather than leaving a block half appended
+pub struct DiskMonitor {
+    paths: Vec<PathBuf>,
+    low_bytes: u64,
+    min_bytes: u64,
+    state: watch::Sender<DiskState>,
+}
+
+impl DiskMonitor {
+    pub fn new(config: &BlockchainConfig) -> Self {
+        DiskMonitor {
+            paths: vec![config.blocks_dir.clone(), PathBuf::from(&config.db_path)],
+            low_bytes: config.disk.low_free_mb * MIB,
+            min_bytes: config.disk.min_free_mb * MIB,
+            state: watch::channel(DiskState::Ok).0,
+        }
+    }
+
+    // Least free space across the monitored filesystems
+    pub fn available_bytes(&self) -> io::Result<u64> {
+        let mut available = u64::MAX;
+        for path in &self.paths {
+            available = available.min(node_status::available_space(existing_ancestor(path))?);
+        }
+        Ok(available)
+    }
+
+    // Measures free space again, logging and publishing any change of state
+    pub fn check(&self) -> io::Result<DiskState> {
+        Ok(self.update(self.available_bytes()?))
+    }
+
+    // Called before appending about `incoming` bytes; fails rather than write past the minimum
+    pub fn check_before_write(&self, incoming: u64) -> io::Result<()> {
+        let available = self.available_bytes()?;
+        if self.update(available.saturating_sub(incoming)) == DiskState::Critical {
+            return Err(io::Error::other(format!(
+                "only {} MiB of disk space left, below the {} MiB minimum; free some space and restart",
+                available / MIB, self.min_bytes / MIB,
+            )));
+        }
+        Ok(())
+    }
+
+    fn update(&self, available: u64) -> DiskState {
+        let state = if available < self.min_bytes {
+            DiskState::Critical
+        } else if available < self.low_bytes {
+            DiskState::Low
+        } else {
+            DiskState::Ok
+        };
+        let previous = self.state.send_replace(state);
+        if state != previous {
+            match state {
+                DiskState::Ok => log::info!("Disk space recovered to {} MiB free, resuming block download", available / MIB),
+                DiskState::Low => log::warn!("Disk space low: {} MiB free, pausing block download", available / MIB),
+                DiskState::Critical => log::error!("Disk nearly full: {} MiB free, shutting down before files are damaged", available / MIB),
+            }
+        }
+        state
+    }
+
+    pub fn state(&self) -> DiskState {
+        *self.state.borrow()
+    }
+
+    pub fn download_paused(&self) -> bool {
+        self.state() != DiskState::Ok
+    }
+
+    // Sees every change of state, e.g. to shut down on `Critical`
+    pub fn subscribe(&self) -> watch::Receiver<DiskState> {
+        self.state.subscribe()
+    }
+}
+
+// Directories that aren't created yet live on their parent's filesystem
+fn existing_ancestor(path: &Path) -> &Path {
+    path.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(Path::new("."))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn monitor(dir: &Path, low_free_mb: u64, min_free_mb: u64) -> DiskMonitor {
+        DiskMonitor {
+            paths: vec![dir.join("blocks"), dir.join("chainstate")],
+            low_bytes: low_free_mb * MIB,
+            min_bytes: min_free_mb * MIB,
+            state: watch::channel(DiskState::Ok).0,
+        }
+    }
+
+    #[test]
+    fn test_thresholds_pause_then_refuse_writes() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let available = monitor(temp_dir.path(), 0, 0).available_bytes()?;
+        assert!(available > 0 && available < u64::MAX);
+
+        let roomy = monitor(temp_dir.path(), 0, 0);
+        assert_eq!(roomy.check()?, DiskState::Ok);
+        assert!(roomy.check_before_write(MIB).is_ok());
+
+        let low = monitor(temp_dir.path(), u64::MAX / MIB, 0);
+        let states = low.subscribe();
+        assert_eq!(low.check()?, DiskState::Low);
+        assert!(low.download_paused() && states.has_changed().unwrap());
+        assert!(low.check_before_write(MIB).is_ok());
+        // A write that would eat into the minimum is refused
+        let tight = monitor(temp_dir.path(), u64::MAX / MIB, available / MIB);
+        assert!(tight.check_before_write(2 * MIB).is_err());
+        assert_eq!(tight.state(), DiskState::Critical);
+        Ok(())
+    }
+}
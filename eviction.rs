@@ -0,0 +1,135 @@
+use crate::addrman::network_group;
+use crate::protocol::PeerId;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Inbound peers kept safe from eviction by each criterion, in the order they're applied. An
+// attacker has to beat honest peers on all of them at once to take over every inbound slot.
+const PROTECT_BY_NETGROUP: usize = 4;
+const PROTECT_BY_PING: usize = 8;
+const PROTECT_BY_TX: usize = 4;
+const PROTECT_BY_BLOCK: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct EvictionCandidate {
+    pub peer: PeerId,
+    pub ip: IpAddr,
+    pub connected_at: Instant,
+    // Fastest ping round trip seen on the connection
+    pub min_ping: Option<Duration>,
+    // Last time the peer sent a block or transaction we hadn't seen
+    pub last_block: Option<Instant>,
+    pub last_tx: Option<Instant>,
+    // Trusted or manually added peers are never evicted
+    pub protected: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundDecision {
+    Accept,
+    // Disconnect this peer to make room
+    Evict(PeerId),
+    Refuse,
+}
+
+// Decides which inbound peer makes way for a new inbound connection once the slots are full, as
+// Bitcoin Core does. Refusing newcomers outright would let an attacker who fills the slots first
+// keep them; evicting instead keeps the peers that are hard to fake and drops one from whichever
+// network group holds the most connections.
+pub struct EvictionPolicy {
+    // Secret salt for ranking network groups, so outsiders can't tell which groups are protected
+    key: u64,
+}
+
+impl EvictionPolicy {
+    pub fn new() -> Self {
+        EvictionPolicy { key: rand::thread_rng().gen() }
+    }
+
+    // `inbound` are the inbound peers currently connected
+    pub fn admit_inbound(&self, inbound: &[EvictionCandidate], max_inbound: usize) -> InboundDecision {
+        if inbound.len() < max_inbound {
+            return InboundDecision::Accept;
+        }
+        match self.select_peer_to_evict(inbound) {
+            Some(peer) => InboundDecision::Evict(peer),
+            None => InboundDecision::Refuse,
+        }
+    }
+
+    // None when every candidate is protected
+    pub fn select_peer_to_evict(&self, candidates: &[EvictionCandidate]) -> Option<PeerId> {
+        let mut remaining = candidates.iter().filter(|c| !c.protected).collect::<Vec<_>>();
+        protect(&mut remaining, PROTECT_BY_NETGROUP, |c| self.keyed_group(&c.ip));
+        protect(&mut remaining, PROTECT_BY_PING, |c| c.min_ping.unwrap_or(Duration::MAX));
+        protect(&mut remaining, PROTECT_BY_TX, |c| Reverse(c.last_tx));
+        protect(&mut remaining, PROTECT_BY_BLOCK, |c| Reverse(c.last_block));
+        // Half of the rest by how long they've been connected
+        let longest_connected = remaining.len() / 2;
+        protect(&mut remaining, longest_connected, |c| c.connected_at);
+
+        let mut groups: HashMap<Vec<u8>, Vec<&EvictionCandidate>> = HashMap::new();
+        for candidate in remaining {
+            groups.entry(network_group(&candidate.ip)).or_default().push(candidate);
+        }
+        // The most crowded group loses its newest peer; between groups of equal size, the one
+        // with the newest connection
+        let newest = |peers: &Vec<&EvictionCandidate>| peers.iter().map(|c| c.connected_at).max();
+        let group = groups.values().max_by_key(|peers| (peers.len(), newest(peers)))?;
+        group.iter().max_by_key(|c| c.connected_at).map(|c| c.peer)
+    }
+
+    fn keyed_group(&self, ip: &IpAddr) -> u64 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.key.to_le_bytes());
+        hasher.update(&network_group(ip));
+        u64::from_le_bytes(hasher.finalize().as_bytes()[..8].try_into().unwrap())
+    }
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Drops the `count` best candidates by `key`, lowest first, from the eviction set
+fn protect<K: Ord>(remaining: &mut Vec<&EvictionCandidate>, count: usize, key: impl Fn(&EvictionCandidate) -> K) {
+    remaining.sort_by_key(|c| key(c));
+    remaining.drain(..count.min(remaining.len()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(peer: PeerId, ip: [u8; 4], connected_at: Instant) -> EvictionCandidate {
+        EvictionCandidate { peer, ip: IpAddr::from(ip), connected_at, min_ping: None, last_block: None, last_tx: None, protected: false }
+    }
+
+    #[test]
+    fn test_crowded_network_group_loses_its_newest_peer() {
+        let start = Instant::now();
+        let policy = EvictionPolicy::new();
+        // 20 honest peers on distinct /16s, then 30 from one /16 arriving later
+        let mut inbound = (0..20u8)
+            .map(|i| EvictionCandidate { min_ping: Some(Duration::from_millis(50 + i as u64)), ..candidate(i as u64, [10, i, 0, 1], start + Duration::from_secs(i as u64)) })
+            .collect::<Vec<_>>();
+        inbound.extend((0..30u8).map(|i| candidate(100 + i as u64, [66, 6, i, 1], start + Duration::from_secs(100 + i as u64))));
+
+        assert_eq!(policy.admit_inbound(&inbound, 60), InboundDecision::Accept);
+        assert_eq!(policy.admit_inbound(&inbound, 50), InboundDecision::Evict(129));
+
+        // A peer that just relayed a block is kept even if it's the newest of the crowd
+        inbound[49].last_block = Some(start + Duration::from_secs(200));
+        assert_eq!(policy.select_peer_to_evict(&inbound), Some(128));
+
+        for peer in &mut inbound {
+            peer.protected = true;
+        }
+        assert_eq!(policy.admit_inbound(&inbound, 50), InboundDecision::Refuse);
+    }
+}
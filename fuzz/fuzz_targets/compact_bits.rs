@@ -0,0 +1,11 @@
+#![no_main]
+
+// Every header's bits go through these conversions before the proof of work is checked
+use libfuzzer_sys::fuzz_target;
+use xcore::difficulty::{compact_to_target, target_to_compact, Difficulty};
+
+fuzz_target!(|bits: u32| {
+    let target = compact_to_target(bits);
+    assert_eq!(compact_to_target(target_to_compact(target)), target);
+    let _ = Difficulty::new(bits);
+});
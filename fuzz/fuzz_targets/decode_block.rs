@@ -0,0 +1,16 @@
+#![no_main]
+
+// Blocks arrive from peers and block files; decoding must fail cleanly on anything malformed, and
+// whatever decodes must encode back to the same bytes
+use libfuzzer_sys::fuzz_target;
+use xcore::blockchain::Block;
+use xcore::codec;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(block) = Block::decode(data) {
+        let encoded = codec::encode(&block).expect("decoded blocks encode");
+        let again = Block::decode(&encoded).expect("encoded blocks decode");
+        assert_eq!(again.hash(), block.hash());
+        assert_eq!(codec::encode(&again).expect("decoded blocks encode"), encoded);
+    }
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+// Raw peer bytes reach the frame decoder before anything else and must be rejected without a panic.
+// The input arrives in two pieces, as a socket might deliver it, to cover partly received frames.
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+use xcore::chain_params::{ChainParams, Network};
+use xcore::wire::MessageCodec;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = MessageCodec::new(&ChainParams::for_network(Network::Regtest));
+    let split = data.first().map_or(0, |&b| b as usize % (data.len() + 1));
+    let mut buf = BytesMut::new();
+    for chunk in [&data[..split], &data[split..]] {
+        buf.extend_from_slice(chunk);
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+});
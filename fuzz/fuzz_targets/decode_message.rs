@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xcore::codec;
+use xcore::protocol::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = codec::decode::<Message>(data) {
+        let _ = message.command();
+        let _ = message.required_service();
+        codec::encode(&message).expect("decoded messages encode");
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xcore::codec;
+use xcore::transaction::Transaction;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(tx) = codec::decode::<Transaction>(data) {
+        let encoded = codec::encode(&tx).expect("decoded transactions encode");
+        assert_eq!(codec::decode::<Transaction>(&encoded).expect("encoded transactions decode"), tx);
+        // Signature checks run on untrusted transactions too
+        let _ = tx.verify_signatures();
+    }
+});
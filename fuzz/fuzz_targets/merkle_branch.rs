@@ -0,0 +1,25 @@
+#![no_main]
+
+// Proofs come from untrusted servers: arbitrary branches must not panic, and a branch built from
+// real leaves verifies only the leaf it was built for
+use libfuzzer_sys::fuzz_target;
+use xcore::codec;
+use xcore::merkle::{merkle_root, MerkleBranch};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(branch) = codec::decode_payload::<MerkleBranch>(data) {
+        let _ = branch.compute_root(&[0; 32]);
+    }
+
+    let leaves = data.chunks_exact(32).map(|chunk| chunk.try_into().expect("chunks are 32 bytes")).collect::<Vec<[u8; 32]>>();
+    if leaves.is_empty() {
+        return;
+    }
+    let index = data.len() % leaves.len();
+    let root = merkle_root(&leaves);
+    let branch = MerkleBranch::new(&leaves, index).expect("index is in range");
+    assert!(branch.verify(&root, &leaves[index]));
+    if let Some(other) = leaves.iter().find(|leaf| **leaf != leaves[index]) {
+        assert!(!branch.verify(&root, other));
+    }
+});
@@ -0,0 +1,241 @@
+// Handlers return tonic's `Status` as the service trait requires, large as it is
+#![allow(clippy::result_large_err)]
+
+use crate::blockchain::{self, Blockchain, ChainEvent};
+use crate::mempool::{Mempool, MempoolEvent};
+use crate::rpc_auth::{Permission, RpcAuth};
+use crate::transaction;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+
+// Types and service traits generated from proto/xcore.proto by build.rs
+pub mod proto {
+    tonic::include_proto!("xcore");
+}
+
+use proto::node_server::{Node, NodeServer};
+
+// The gRPC counterpart of the JSON-RPC server for typed clients. Read-only: wallet and
+// transaction submission calls stay on JSON-RPC. Calls carry the same basic credentials as
+// JSON-RPC in their `authorization` metadata and need the read permission.
+pub struct GrpcService {
+    blockchain: Arc<Blockchain>,
+    mempool: Arc<Mutex<Mempool>>,
+    auth: Arc<RpcAuth>,
+}
+
+impl GrpcService {
+    pub fn new(blockchain: Arc<Blockchain>, mempool: Arc<Mutex<Mempool>>, auth: Arc<RpcAuth>) -> Self {
+        GrpcService { blockchain, mempool, auth }
+    }
+
+    // `tls` is the certificate and key JSON-RPC serves HTTPS with
+    pub async fn serve(self, addr: SocketAddr, tls: Option<Identity>, shutdown: CancellationToken) -> Result<(), tonic::transport::Error> {
+        let mut builder = Server::builder();
+        if let Some(identity) = tls {
+            builder = builder.tls_config(ServerTlsConfig::new().identity(identity))?;
+            log::info!("gRPC server listening on {} (TLS)", addr);
+        } else {
+            if !addr.ip().is_loopback() {
+                log::warn!("gRPC server is reachable beyond localhost without TLS; passwords cross the network in the clear");
+            }
+            log::info!("gRPC server listening on {}", addr);
+        }
+        let auth = Arc::clone(&self.auth);
+        builder
+            .add_service(NodeServer::with_interceptor(self, move |request| authorize(&auth, request)))
+            .serve_with_shutdown(addr, async move { shutdown.cancelled().await })
+            .await
+    }
+}
+
+// Runs before every call, streaming ones included
+fn authorize(auth: &RpcAuth, request: Request<()>) -> Result<Request<()>, Status> {
+    let authorization = request.metadata().get("authorization").and_then(|value| value.to_str().ok());
+    let credential = auth.authenticate(authorization).ok_or_else(|| Status::unauthenticated("basic credentials required"))?;
+    if !credential.has_permission(Permission::Read) {
+        return Err(Status::permission_denied(format!("user '{}' lacks the read permission", credential.user())));
+    }
+    Ok(request)
+}
+
+fn hash_param(bytes: &[u8]) -> Result<[u8; 32], Status> {
+    bytes.try_into().map_err(|_| Status::invalid_argument("hashes are 32 bytes"))
+}
+
+fn internal(error: impl std::fmt::Display) -> Status {
+    Status::internal(error.to_string())
+}
+
+fn transaction_message(tx: &transaction::Transaction) -> proto::Transaction {
+    proto::Transaction {
+        txid: tx.hash().to_vec(),
+        inputs: tx.inputs.iter()
+            .map(|input| proto::TxInput {
+                previous_output: Some(proto::OutPoint { txid: input.previous_output.txid.to_vec(), index: input.previous_output.index }),
+                public_key: input.public_key.to_vec(),
+                signature: input.signature.clone(),
+                sequence: input.sequence,
+            })
+            .collect(),
+        outputs: tx.outputs.iter()
+            .map(|output| proto::TxOutput { value: output.value, script_pubkey: output.script_pubkey.clone() })
+            .collect(),
+        lock_time: tx.lock_time,
+    }
+}
+
+fn block_message(block: &blockchain::Block, height: Option<u64>) -> proto::Block {
+    let header = &block.header;
+    proto::Block {
+        hash: block.hash().to_vec(),
+        height,
+        header: Some(proto::BlockHeader {
+            version: header.version,
+            previous_hash: header.previous_hash.to_vec(),
+            merkle_root: header.merkle_root.to_vec(),
+            fruits_root: header.fruits_root.to_vec(),
+            timestamp: header.timestamp,
+            bits: header.bits,
+            nonce: header.nonce,
+        }),
+        fruit_count: block.fruits.len() as u32,
+        transactions: block.transactions.iter().map(transaction_message).collect(),
+    }
+}
+
+#[tonic::async_trait]
+impl Node for GrpcService {
+    async fn get_blockchain_info(&self, _request: Request<proto::GetBlockchainInfoRequest>) -> Result<Response<proto::BlockchainInfo>, Status> {
+        Ok(Response::new(proto::BlockchainInfo {
+            chain: format!("{:?}", self.blockchain.params().network).to_lowercase(),
+            height: self.blockchain.get_chain_height(),
+            best_block_hash: self.blockchain.get_chain_tip().to_vec(),
+            median_time_past: self.blockchain.median_time_past(),
+            initial_block_download: self.blockchain.is_initial_block_download(),
+        }))
+    }
+
+    async fn get_block(&self, request: Request<proto::GetBlockRequest>) -> Result<Response<proto::Block>, Status> {
+        let block = match request.into_inner().id {
+            Some(proto::get_block_request::Id::Hash(hash)) => self.blockchain.get_block(&hash_param(&hash)?).await.map_err(internal)?,
+            Some(proto::get_block_request::Id::Height(height)) => self.blockchain.get_block_by_height(height).await.map_err(internal)?,
+            None => return Err(Status::invalid_argument("give a block hash or height")),
+        };
+        let block = block.ok_or_else(|| Status::not_found("block not found"))?;
+        let height = self.blockchain.get_block_height(&block.hash()).await.map_err(internal)?;
+        Ok(Response::new(block_message(&block, height)))
+    }
+
+    async fn get_mempool_info(&self, _request: Request<proto::GetMempoolInfoRequest>) -> Result<Response<proto::MempoolInfo>, Status> {
+        let info = self.mempool.lock().info();
+        Ok(Response::new(proto::MempoolInfo {
+            size: info.size as u64,
+            bytes: info.bytes as u64,
+            usage_limit_bytes: info.usage_limit_bytes as u64,
+            total_fee: info.total_fee,
+            lowest_fee_rate: info.lowest_fee_rate,
+            non_final: info.non_final as u64,
+            usage: info.usage as u64,
+        }))
+    }
+
+    async fn get_mempool_transaction(&self, request: Request<proto::GetMempoolTransactionRequest>) -> Result<Response<proto::MempoolTransaction>, Status> {
+        let txid = hash_param(&request.into_inner().txid)?;
+        let mempool = self.mempool.lock();
+        let (tx, entry) = mempool.get_transaction(&txid).zip(mempool.entry_info(&txid))
+            .ok_or_else(|| Status::not_found("transaction is not in the mempool"))?;
+        Ok(Response::new(proto::MempoolTransaction {
+            transaction: Some(transaction_message(tx)),
+            fee: entry.fee,
+            fee_rate: entry.fee_rate,
+            size: entry.size as u64,
+            time: entry.time,
+        }))
+    }
+
+    async fn list_mempool_transactions(&self, _request: Request<proto::ListMempoolTransactionsRequest>) -> Result<Response<proto::ListMempoolTransactionsResponse>, Status> {
+        let txids = self.mempool.lock().transaction_hashes().iter().map(|txid| txid.to_vec()).collect();
+        Ok(Response::new(proto::ListMempoolTransactionsResponse { txids }))
+    }
+
+    type SubscribeBlocksStream = Pin<Box<dyn Stream<Item = Result<proto::BlockEvent, Status>> + Send>>;
+
+    async fn subscribe_blocks(&self, request: Request<proto::SubscribeBlocksRequest>) -> Result<Response<Self::SubscribeBlocksStream>, Status> {
+        let include_blocks = request.into_inner().include_blocks;
+        let events = BroadcastStream::new(self.blockchain.subscribe()).map(move |event| {
+            let (block, height, connected) = match event {
+                Ok(ChainEvent::BlockConnected { block, height }) => (block, height, true),
+                Ok(ChainEvent::BlockDisconnected { block, height }) => (block, height, false),
+                // The client can't tell what it missed, so end the stream and let it resubscribe
+                Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                    return Err(Status::data_loss(format!("subscriber fell behind and missed {} events", missed)));
+                }
+            };
+            Ok(proto::BlockEvent {
+                connected,
+                hash: block.hash().to_vec(),
+                height,
+                block: include_blocks.then(|| block_message(&block, connected.then_some(height))),
+            })
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    type SubscribeMempoolStream = Pin<Box<dyn Stream<Item = Result<proto::MempoolEvent, Status>> + Send>>;
+
+    async fn subscribe_mempool(&self, _request: Request<proto::SubscribeMempoolRequest>) -> Result<Response<Self::SubscribeMempoolStream>, Status> {
+        let events = BroadcastStream::new(self.mempool.lock().subscribe()).map(|event| match event {
+            Ok(MempoolEvent::TransactionAdded(tx)) => Ok(proto::MempoolEvent { txid: tx.hash().to_vec(), added: true, removal_reason: String::new() }),
+            Ok(MempoolEvent::TransactionRemoved { txid, reason }) => {
+                Ok(proto::MempoolEvent { txid: txid.to_vec(), added: false, removal_reason: reason.name().to_string() })
+            }
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Err(Status::data_loss(format!("subscriber fell behind and missed {} events", missed))),
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node_config::{RpcConfig, RpcCredentialConfig};
+    use base64::Engine;
+    use tempfile::TempDir;
+
+    fn request(user: &str, password: &str) -> Request<()> {
+        let mut request = Request::new(());
+        let basic = format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password)));
+        request.metadata_mut().insert("authorization", basic.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn test_calls_need_credentials_with_read_permission() -> std::io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut config = RpcConfig { user: Some("admin".to_string()), password: Some("secret".to_string()), ..RpcConfig::default() };
+        for (user, permission) in [("explorer", Permission::Read), ("payer", Permission::Wallet)] {
+            config.credentials.push(RpcCredentialConfig {
+                user: user.to_string(),
+                password: "hunter2".to_string(),
+                permissions: vec![permission],
+                methods: Vec::new(),
+            });
+        }
+        let auth = RpcAuth::new(&config, &temp_dir.path().join(".cookie"))?;
+
+        assert!(authorize(&auth, request("admin", "secret")).is_ok());
+        assert!(authorize(&auth, request("explorer", "hunter2")).is_ok());
+        assert_eq!(authorize(&auth, request("payer", "hunter2")).unwrap_err().code(), tonic::Code::PermissionDenied);
+        assert_eq!(authorize(&auth, request("explorer", "wrong")).unwrap_err().code(), tonic::Code::Unauthenticated);
+        assert_eq!(authorize(&auth, Request::new(())).unwrap_err().code(), tonic::Code::Unauthenticated);
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use bip39::{Language, Mnemonic};
+use ed25519_dalek::SigningKey;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha512;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+// Hierarchical deterministic ed25519 keys as in SLIP-0010, seeded from BIP39 mnemonics. Ed25519
+// has no public child derivation, so every level is hardened and paths are written with `'`.
+pub const HARDENED: u32 = 1 << 31;
+// BIP44 purpose level
+pub const PURPOSE: u32 = 44;
+const MASTER_HMAC_KEY: &[u8] = b"ed25519 seed";
+
+#[derive(Error, Debug)]
+pub enum HdKeyError {
+    #[error("Invalid mnemonic: {0}")]
+    Mnemonic(#[from] bip39::Error),
+    #[error("Mnemonics have 12 or 24 words, not {0}")]
+    WordCount(usize),
+    #[error("Invalid derivation path '{0}'; every level must be hardened, e.g. m/44'/0'/0'")]
+    InvalidPath(String),
+    #[error("Child index {0} is out of range")]
+    IndexOutOfRange(u32),
+}
+
+pub fn generate_mnemonic(word_count: usize) -> Result<Mnemonic, HdKeyError> {
+    let mut entropy = match word_count {
+        12 => vec![0u8; 16],
+        24 => vec![0u8; 32],
+        _ => return Err(HdKeyError::WordCount(word_count)),
+    };
+    rand::thread_rng().fill_bytes(&mut entropy);
+    Ok(Mnemonic::from_entropy_in(Language::English, &entropy)?)
+}
+
+pub fn parse_mnemonic(phrase: &str) -> Result<Mnemonic, HdKeyError> {
+    let mnemonic = Mnemonic::parse_in(Language::English, phrase)?;
+    match mnemonic.word_count() {
+        12 | 24 => Ok(mnemonic),
+        count => Err(HdKeyError::WordCount(count)),
+    }
+}
+
+// Child indexes below HARDENED; derivation applies the hardened flag itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivationPath(Vec<u32>);
+
+impl DerivationPath {
+    pub fn new(indexes: Vec<u32>) -> Result<Self, HdKeyError> {
+        match indexes.iter().find(|&&index| index >= HARDENED) {
+            Some(&index) => Err(HdKeyError::IndexOutOfRange(index)),
+            None => Ok(DerivationPath(indexes)),
+        }
+    }
+
+    // m/44'/coin_type'/account'/change'/index'
+    pub fn bip44(coin_type: u32, account: u32, change: bool, index: u32) -> Result<Self, HdKeyError> {
+        Self::new(vec![PURPOSE, coin_type, account, change as u32, index])
+    }
+
+    pub fn indexes(&self) -> &[u32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            write!(f, "/{}'", index)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = HdKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || HdKeyError::InvalidPath(s.to_string());
+        let mut levels = s.split('/');
+        if levels.next() != Some("m") {
+            return Err(invalid());
+        }
+        let indexes = levels
+            .map(|level| {
+                let digits = level.strip_suffix('\'').or_else(|| level.strip_suffix('h')).ok_or_else(invalid)?;
+                digits.parse::<u32>().map_err(|_| invalid())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(indexes)
+    }
+}
+
+// A private key with its chain code. Deliberately not `Debug` so secrets don't end up in logs.
+#[derive(Clone)]
+pub struct ExtendedKey {
+    secret: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> Self {
+        Self::from_hmac(MASTER_HMAC_KEY, seed)
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self, HdKeyError> {
+        if index >= HARDENED {
+            return Err(HdKeyError::IndexOutOfRange(index));
+        }
+        let mut data = Vec::with_capacity(1 + 32 + 4);
+        data.push(0);
+        data.extend_from_slice(&self.secret);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        Ok(Self::from_hmac(&self.chain_code, &data))
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self, HdKeyError> {
+        path.indexes().iter().try_fold(self.clone(), |key, &index| key.derive_child(index))
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.secret)
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key().verifying_key().to_bytes()
+    }
+
+    fn from_hmac(key: &[u8], data: &[u8]) -> Self {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(data);
+        let output = mac.finalize().into_bytes();
+        ExtendedKey {
+            secret: output[..32].try_into().expect("slice is 32 bytes"),
+            chain_code: output[32..].try_into().expect("slice is 32 bytes"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SLIP-0010 ed25519 test vector 1
+    #[test]
+    fn test_slip10_vector() -> Result<(), HdKeyError> {
+        let master = ExtendedKey::master(&hex::decode("000102030405060708090a0b0c0d0e0f").unwrap());
+        assert_eq!(hex::encode(master.secret), "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7");
+        assert_eq!(hex::encode(master.chain_code), "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb");
+
+        let child = master.derive_path(&"m/0'".parse()?)?;
+        assert_eq!(hex::encode(child.secret), "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3");
+        assert_eq!(hex::encode(child.chain_code), "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69");
+        assert_eq!(hex::encode(child.public_key()), "8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c");
+
+        assert_eq!(DerivationPath::bip44(1, 0, true, 7)?.to_string(), "m/44'/1'/0'/1'/7'");
+        assert!("m/44'/0".parse::<DerivationPath>().is_err());
+        assert!(parse_mnemonic("abandon abandon").is_err());
+        let mnemonic = generate_mnemonic(24)?;
+        assert_eq!(parse_mnemonic(&mnemonic.to_string())?.to_seed(""), mnemonic.to_seed(""));
+        Ok(())
+    }
+}
@@ -0,0 +1,513 @@
+use crate::blockchain::{BlockHash, BlockHeader, MEDIAN_TIME_SPAN};
+use crate::pow;
+use crate::validation::ValidationError;
+use primitive_types::U256;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone)]
+pub struct IndexedHeader {
+    pub hash: BlockHash,
+    pub header: BlockHeader,
+    pub height: u64,
+    // Work of the whole chain ending with this block
+    pub chain_work: U256,
+    parent: Option<usize>,
+    // An ancestor further back, at `skip_height(height)`, so walks to any ancestor take O(log n) steps
+    skip: Option<usize>,
+}
+
+// A fork may fall this many of the best header's blocks behind in work and still have its headers kept
+const FORK_WORK_ALLOWANCE_BLOCKS: u64 = 144;
+
+// Every header of the chainstate kept in memory, forks included, with the active chain indexed by
+// height. Entries are never removed; a disconnected block's header stays for fork detection.
+#[derive(Default)]
+pub struct HeaderIndex {
+    entries: Vec<IndexedHeader>,
+    by_hash: HashMap<BlockHash, usize>,
+    // Entry of each block of the active chain, by height
+    active: Vec<usize>,
+    // Entries an operator marked invalid; their descendants count as invalid too
+    invalid: HashSet<usize>,
+    // Entries no other entry builds on
+    leaves: HashSet<usize>,
+}
+
+impl HeaderIndex {
+    pub fn new() -> Self {
+        HeaderIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &BlockHash) -> Option<&IndexedHeader> {
+        self.by_hash.get(hash).map(|&i| &self.entries[i])
+    }
+
+    // Adds `header` below its parent and returns its height, or `None` if the parent isn't indexed.
+    // Headers already indexed are left as they are.
+    pub fn insert(&mut self, header: BlockHeader) -> Option<u64> {
+        let hash = header.hash();
+        if let Some(entry) = self.get(&hash) {
+            return Some(entry.height);
+        }
+        let (height, parent) = match header.previous_hash {
+            previous if previous == [0; 32] => (0, None),
+            previous => {
+                let parent = *self.by_hash.get(&previous)?;
+                (self.entries[parent].height + 1, Some(parent))
+            }
+        };
+        let skip = parent.and_then(|parent| self.ancestor_index(parent, skip_height(height)));
+        let chain_work = parent.map_or(U256::zero(), |parent| self.entries[parent].chain_work) + pow::block_work(header.bits);
+        if let Some(parent) = parent {
+            self.leaves.remove(&parent);
+        }
+        self.leaves.insert(self.entries.len());
+        self.by_hash.insert(hash, self.entries.len());
+        self.entries.push(IndexedHeader { hash, header, height, chain_work, parent, skip });
+        Some(height)
+    }
+
+    // Refuses `headers`, each building on the one before, when they fork off the best header chain
+    // and the chain they end has less work than `fork_work_threshold`, so a node still catching up
+    // can't be filled with cheap forks. Headers extending the best header are checked one by one as
+    // they're inserted; those already indexed and those that can't be inserted are left for
+    // `insert` and the header checks to deal with. Only headers meeting their own proof-of-work
+    // target count towards the work.
+    pub fn check_fork_work(&self, headers: &[BlockHeader], minimum_chain_work: U256) -> Result<(), ValidationError> {
+        let Some(first) = headers.iter().position(|header| !self.contains(&header.hash())) else { return Ok(()) };
+        let headers = &headers[first..];
+        let best = self.best_header();
+        let mut work = match headers[0].previous_hash {
+            previous if previous == [0; 32] => return Ok(()),
+            previous if best.is_some_and(|best| best.hash == previous) => return Ok(()),
+            previous => match self.get(&previous) {
+                Some(parent) => parent.chain_work,
+                None => return Ok(()),
+            },
+        };
+        let mut previous_hash = headers[0].previous_hash;
+        for header in headers {
+            let hash = header.hash();
+            if header.previous_hash != previous_hash || !pow::check_proof_of_work(&hash, header.bits) {
+                break;
+            }
+            work = work.saturating_add(pow::block_work(header.bits));
+            previous_hash = hash;
+        }
+        let required = self.fork_work_threshold(minimum_chain_work);
+        if work < required {
+            return Err(ValidationError::InsufficientChainWork { work, required });
+        }
+        Ok(())
+    }
+
+    // The chain's minimum work, or the best header's less an allowance for recent forks once that's more
+    pub fn fork_work_threshold(&self, minimum_chain_work: U256) -> U256 {
+        let Some(best) = self.best_header() else { return minimum_chain_work };
+        let allowance = pow::block_work(best.header.bits).saturating_mul(U256::from(FORK_WORK_ALLOWANCE_BLOCKS));
+        minimum_chain_work.max(best.chain_work.saturating_sub(allowance))
+    }
+
+    // The header ending the valid chain with the most work, whether its blocks are connected or not
+    pub fn best_header(&self) -> Option<&IndexedHeader> {
+        self.tips().into_iter().filter(|entry| !self.is_invalid(&entry.hash)).max_by_key(|entry| entry.chain_work)
+    }
+
+    pub fn tip(&self) -> Option<&IndexedHeader> {
+        self.active.last().map(|&i| &self.entries[i])
+    }
+
+    // Makes `tip` the end of the active chain, or empties it for `None`. Only the entries past the
+    // fork with the old active chain are rewritten. False if `tip` isn't indexed.
+    pub fn set_tip(&mut self, tip: Option<&BlockHash>) -> bool {
+        let Some(hash) = tip else {
+            self.active.clear();
+            return true;
+        };
+        let Some(&tip) = self.by_hash.get(hash) else { return false };
+        let mut branch = Vec::new();
+        let mut cursor = Some(tip);
+        while let Some(i) = cursor {
+            if self.active.get(self.entries[i].height as usize) == Some(&i) {
+                break;
+            }
+            branch.push(i);
+            cursor = self.entries[i].parent;
+        }
+        self.active.truncate(cursor.map_or(0, |i| self.entries[i].height as usize + 1));
+        self.active.extend(branch.into_iter().rev());
+        true
+    }
+
+    // Block of the active chain at `height`
+    pub fn at_height(&self, height: u64) -> Option<&IndexedHeader> {
+        self.active.get(usize::try_from(height).ok()?).map(|&i| &self.entries[i])
+    }
+
+    pub fn is_active(&self, hash: &BlockHash) -> bool {
+        self.get(hash).is_some_and(|entry| self.at_height(entry.height).is_some_and(|active| active.hash == *hash))
+    }
+
+    // Ancestor of `hash` at `height`, on whichever chain `hash` is
+    pub fn ancestor(&self, hash: &BlockHash, height: u64) -> Option<&IndexedHeader> {
+        let &start = self.by_hash.get(hash)?;
+        self.ancestor_index(start, height).map(|i| &self.entries[i])
+    }
+
+    // Follows skip pointers while they don't overshoot `height`, and parents otherwise
+    fn ancestor_index(&self, start: usize, height: u64) -> Option<usize> {
+        let mut walk = start;
+        let mut walk_height = self.entries[walk].height;
+        if height > walk_height {
+            return None;
+        }
+        while walk_height > height {
+            let entry = &self.entries[walk];
+            let jump = skip_height(walk_height);
+            let previous_jump = skip_height(walk_height - 1);
+            // Skip unless the parent's pointer lands closer to `height` without passing it
+            let take_skip = jump == height || (jump > height && !(previous_jump + 2 < jump && previous_jump >= height));
+            match entry.skip.filter(|_| take_skip) {
+                Some(skip) => {
+                    walk = skip;
+                    walk_height = jump;
+                }
+                None => {
+                    walk = entry.parent?;
+                    walk_height -= 1;
+                }
+            }
+        }
+        Some(walk)
+    }
+
+    // Last block both `a` and `b` descend from
+    pub fn find_fork(&self, a: &BlockHash, b: &BlockHash) -> Option<&IndexedHeader> {
+        let (&a, &b) = (self.by_hash.get(a)?, self.by_hash.get(b)?);
+        let height = self.entries[a].height.min(self.entries[b].height);
+        let (mut a, mut b) = (self.ancestor_index(a, height)?, self.ancestor_index(b, height)?);
+        while a != b {
+            a = self.entries[a].parent?;
+            b = self.entries[b].parent?;
+        }
+        Some(&self.entries[a])
+    }
+
+    // Hashes from `hash` back to genesis: the ten newest one block apart, then twice as far apart
+    // each step, so a peer finds where its chain forks from this one in a single round trip
+    pub fn locator(&self, hash: &BlockHash) -> Vec<BlockHash> {
+        let Some(&start) = self.by_hash.get(hash) else { return Vec::new() };
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut walk = Some(start);
+        while let Some(i) = walk {
+            let entry = &self.entries[i];
+            locator.push(entry.hash);
+            if entry.height == 0 {
+                break;
+            }
+            if locator.len() > 10 {
+                step *= 2;
+            }
+            walk = self.ancestor_index(i, entry.height.saturating_sub(step));
+        }
+        locator
+    }
+
+    // First hash of `locator` on the active chain. Locators run newest first, so this is the
+    // highest block the peer sending it shares with us.
+    pub fn find_locator_fork(&self, locator: &[BlockHash]) -> Option<&IndexedHeader> {
+        locator.iter().find(|hash| self.is_active(hash)).and_then(|hash| self.get(hash))
+    }
+
+    // Up to `limit` headers of the active chain after the locator's fork, ending early at
+    // `stop_hash`. With no block in common the peer is sent the chain from genesis.
+    pub fn headers_after(&self, locator: &[BlockHash], stop_hash: &BlockHash, limit: usize) -> Vec<BlockHeader> {
+        let mut height = self.find_locator_fork(locator).map_or(0, |fork| fork.height + 1);
+        let mut headers = Vec::new();
+        while let Some(entry) = self.at_height(height).filter(|_| headers.len() < limit) {
+            headers.push(entry.header.clone());
+            if entry.hash == *stop_hash {
+                break;
+            }
+            height += 1;
+        }
+        headers
+    }
+
+    // Blocks no other block builds on, plus the active tip even when a disconnected block does
+    pub fn tips(&self) -> Vec<&IndexedHeader> {
+        let mut tips = self.leaves.iter().copied().chain(self.active.last().copied()).collect::<Vec<_>>();
+        tips.sort_unstable();
+        tips.dedup();
+        tips.into_iter().map(|i| &self.entries[i]).collect()
+    }
+
+    // Blocks from `hash` back to where it joins the active chain, newest first; empty for blocks
+    // on the active chain
+    pub fn branch(&self, hash: &BlockHash) -> Vec<&IndexedHeader> {
+        let mut branch = Vec::new();
+        let mut cursor = self.by_hash.get(hash).copied();
+        while let Some(i) = cursor.filter(|&i| self.active.get(self.entries[i].height as usize) != Some(&i)) {
+            branch.push(&self.entries[i]);
+            cursor = self.entries[i].parent;
+        }
+        branch
+    }
+
+    // Marks or unmarks `hash` as invalid; false if it isn't indexed
+    pub fn set_invalid(&mut self, hash: &BlockHash, invalid: bool) -> bool {
+        let Some(&i) = self.by_hash.get(hash) else { return false };
+        if invalid {
+            self.invalid.insert(i);
+        } else {
+            self.invalid.remove(&i);
+        }
+        true
+    }
+
+    // Unmarks `hash` along with every marked block it descends from or that descends from it; false
+    // if it isn't indexed
+    pub fn reconsider(&mut self, hash: &BlockHash) -> bool {
+        let Some(&i) = self.by_hash.get(hash) else { return false };
+        let height = self.entries[i].height;
+        let related = self.invalid.iter().copied()
+            .filter(|&marked| {
+                let marked_height = self.entries[marked].height;
+                if marked_height >= height {
+                    self.ancestor_index(marked, height) == Some(i)
+                } else {
+                    self.ancestor_index(i, marked_height) == Some(marked)
+                }
+            })
+            .collect::<Vec<_>>();
+        for marked in related {
+            self.invalid.remove(&marked);
+        }
+        true
+    }
+
+    pub fn invalid_blocks(&self) -> Vec<BlockHash> {
+        self.invalid.iter().map(|&i| self.entries[i].hash).collect()
+    }
+
+    // Whether the block of `hash` could end up on the active chain: it has at least the tip's work,
+    // or headers above it reach more
+    pub fn may_overtake(&self, hash: &BlockHash) -> bool {
+        let Some(&i) = self.by_hash.get(hash) else { return false };
+        let tip_work = self.tip().map_or(U256::zero(), |tip| tip.chain_work);
+        let height = self.entries[i].height;
+        self.entries[i].chain_work >= tip_work
+            || self.leaves.iter().any(|&leaf| self.entries[leaf].chain_work > tip_work && self.ancestor_index(leaf, height) == Some(i))
+    }
+
+    // Whether `hash` itself is marked invalid, leaving its ancestors aside
+    pub fn is_marked_invalid(&self, hash: &BlockHash) -> bool {
+        self.by_hash.get(hash).is_some_and(|i| self.invalid.contains(i))
+    }
+
+    // Whether `hash` or a block it descends from is marked invalid. Marked blocks are kept off the
+    // active chain, so only the branch off it needs looking at.
+    pub fn is_invalid(&self, hash: &BlockHash) -> bool {
+        !self.invalid.is_empty() && self.branch(hash).iter().any(|entry| self.invalid.contains(&self.by_hash[&entry.hash]))
+    }
+
+    // Median timestamp of the MEDIAN_TIME_SPAN blocks ending at `hash`
+    pub fn median_time_past(&self, hash: &BlockHash) -> Option<u64> {
+        let mut cursor = self.by_hash.get(hash).copied();
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        while let Some(i) = cursor.filter(|_| timestamps.len() < MEDIAN_TIME_SPAN) {
+            timestamps.push(self.entries[i].header.timestamp);
+            cursor = self.entries[i].parent;
+        }
+        if timestamps.is_empty() {
+            return None;
+        }
+        timestamps.sort_unstable();
+        Some(timestamps[timestamps.len() / 2])
+    }
+}
+
+// Height the skip pointer at `height` leads to. Jumps of mixed lengths let any ancestor be reached
+// in O(log n) steps; this is the same scheme Bitcoin Core uses.
+fn skip_height(height: u64) -> u64 {
+    let invert_lowest_one = |n: u64| n & n.wrapping_sub(1);
+    match height {
+        0 | 1 => 0,
+        h if h & 1 == 1 => invert_lowest_one(invert_lowest_one(h - 1)) + 1,
+        h => invert_lowest_one(h),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn child(parent: &BlockHeader, nonce: u64) -> BlockHeader {
+        BlockHeader { previous_hash: parent.hash(), timestamp: parent.timestamp + 60, nonce, ..parent.clone() }
+    }
+
+    #[test]
+    fn test_ancestors_and_forks() {
+        let genesis = BlockHeader {
+            version: 1,
+            previous_hash: [0; 32],
+            merkle_root: [1; 32],
+            fruits_root: [0; 32],
+            timestamp: 1_700_000_000,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        let mut index = HeaderIndex::new();
+        let mut chain = vec![genesis.clone()];
+        assert_eq!(index.insert(genesis), Some(0));
+        for height in 1..1000u64 {
+            let header = child(chain.last().unwrap(), 0);
+            assert_eq!(index.insert(header.clone()), Some(height));
+            chain.push(header);
+        }
+        // A fork from height 600
+        let mut fork = vec![child(&chain[600], 1)];
+        for _ in 0..50 {
+            fork.push(child(fork.last().unwrap(), 1));
+        }
+        for header in &fork {
+            index.insert(header.clone());
+        }
+        assert_eq!(index.insert(child(&chain[0], 2)).map(|_| index.len()), Some(1052));
+        assert_eq!(index.insert(BlockHeader { previous_hash: [9; 32], ..chain[5].clone() }), None);
+
+        let tip = chain[999].hash();
+        for height in [0, 1, 2, 255, 256, 511, 600, 998, 999] {
+            assert_eq!(index.ancestor(&tip, height).unwrap().hash, chain[height as usize].hash());
+        }
+        assert!(index.ancestor(&tip, 1000).is_none());
+        let fork_tip = fork.last().unwrap().hash();
+        assert_eq!(index.ancestor(&fork_tip, 601).unwrap().hash, fork[0].hash());
+        assert_eq!(index.find_fork(&tip, &fork_tip).unwrap().height, 600);
+
+        assert!(index.set_tip(Some(&tip)));
+        assert_eq!(index.tip().unwrap().height, 999);
+        assert!(index.set_tip(Some(&fork_tip)));
+        assert_eq!((index.tip().unwrap().height, index.at_height(600).unwrap().hash), (651, chain[600].hash()));
+        assert!(!index.is_active(&chain[700].hash()) && index.is_active(&fork[10].hash()));
+
+        // Both forks end in a tip, the active one included
+        let mut tips = index.tips().iter().map(|entry| entry.hash).collect::<Vec<_>>();
+        tips.sort();
+        let mut expected = vec![tip, fork_tip, child(&chain[0], 2).hash()];
+        expected.sort();
+        assert_eq!(tips, expected);
+        assert_eq!(index.branch(&tip).len(), 399);
+        assert!(index.branch(&fork_tip).is_empty());
+        // Every block at these bits adds the same work
+        let work = pow::block_work(chain[0].bits);
+        assert_eq!(index.get(&tip).unwrap().chain_work, work * 1000);
+        assert_eq!(index.get(&fork_tip).unwrap().chain_work, work * 652);
+        // The longer chain may still overtake the active fork, but blocks behind the fork's tip can't
+        assert!(index.may_overtake(&chain[700].hash()) && index.may_overtake(&fork_tip));
+        assert!(!index.may_overtake(&fork[0].hash()) && !index.may_overtake(&child(&chain[0], 2).hash()));
+
+        assert!(index.set_invalid(&chain[800].hash(), true));
+        assert!(index.is_invalid(&tip) && index.is_invalid(&chain[800].hash()));
+        assert!(!index.is_invalid(&chain[799].hash()) && !index.is_invalid(&fork_tip));
+        index.set_invalid(&chain[800].hash(), false);
+        assert!(!index.is_invalid(&tip));
+        // Reconsidering a block clears the marks above and below it, not those on other branches
+        for hash in [chain[800].hash(), chain[900].hash(), fork[5].hash()] {
+            index.set_invalid(&hash, true);
+        }
+        assert!(index.reconsider(&chain[850].hash()));
+        assert_eq!(index.invalid_blocks(), vec![fork[5].hash()]);
+
+        // Timestamps rise by 60 seconds, so the median is 5 blocks back
+        assert_eq!(index.median_time_past(&tip), Some(chain[994].timestamp));
+        assert_eq!(index.median_time_past(&chain[1].hash()), Some(chain[1].timestamp));
+    }
+
+    #[test]
+    fn test_locator_finds_the_fork() {
+        let mut index = HeaderIndex::new();
+        let mut chain = vec![BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [1; 32], fruits_root: [0; 32], timestamp: 1_700_000_000, bits: 0x207fffff, nonce: 0 }];
+        for _ in 1..3000 {
+            chain.push(child(chain.last().unwrap(), 0));
+        }
+        let mut fork = vec![child(&chain[2500], 1)];
+        for _ in 0..99 {
+            fork.push(child(fork.last().unwrap(), 1));
+        }
+        for header in chain.iter().chain(&fork) {
+            index.insert(header.clone());
+        }
+        index.set_tip(Some(&chain[2999].hash()));
+
+        let locator = index.locator(&fork[99].hash());
+        let heights = locator.iter().map(|hash| index.get(hash).unwrap().height).collect::<Vec<_>>();
+        assert_eq!(&heights[..12], &[2600, 2599, 2598, 2597, 2596, 2595, 2594, 2593, 2592, 2591, 2590, 2588]);
+        assert_eq!((heights.last(), heights.len() < 30), (Some(&0), true));
+
+        // The highest locator hash we share is at or below the real fork at 2500
+        let fork_height = index.find_locator_fork(&locator).unwrap().height;
+        assert!(fork_height <= 2500 && fork_height > 2400);
+        let headers = index.headers_after(&locator, &[0; 32], 2000);
+        assert_eq!((headers.len() as u64, &headers[0]), (2999 - fork_height, &chain[fork_height as usize + 1]));
+        assert_eq!(index.headers_after(&locator, &[0; 32], 100).len(), 100);
+        let stop = chain[fork_height as usize + 5].hash();
+        assert_eq!(index.headers_after(&locator, &stop, 2000).len(), 5);
+        // Strangers get the chain from genesis
+        assert_eq!(index.headers_after(&[[7; 32]], &[0; 32], 3)[0], chain[0]);
+    }
+
+    #[test]
+    fn test_cheap_forks_are_refused() {
+        let mined = |mut header: BlockHeader| {
+            while !pow::check_proof_of_work(&header.hash(), header.bits) {
+                header.nonce += 1;
+            }
+            header
+        };
+        let branch = |parent: &BlockHeader, nonce: u64, count: usize| {
+            let mut headers = vec![mined(child(parent, nonce))];
+            while headers.len() < count {
+                headers.push(mined(child(headers.last().unwrap(), nonce)));
+            }
+            headers
+        };
+        let mut index = HeaderIndex::new();
+        let genesis = mined(BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [1; 32], fruits_root: [0; 32], timestamp: 1_700_000_000, bits: 0x207fffff, nonce: 0 });
+        let mut chain = vec![genesis.clone()];
+        chain.extend(branch(&genesis, 0, 299));
+        for header in &chain {
+            index.insert(header.clone());
+        }
+        // Each header adds 2, so the best header has 600 and forks need 600 - 2 * 144
+        let work = pow::block_work(genesis.bits);
+        assert_eq!(index.fork_work_threshold(U256::zero()), work * 156);
+
+        let deep = branch(&chain[10], 1, 5);
+        assert!(matches!(index.check_fork_work(&deep, U256::zero()), Err(ValidationError::InsufficientChainWork { .. })));
+        let recent = branch(&chain[200], 1, 10);
+        assert!(index.check_fork_work(&recent, U256::zero()).is_ok());
+        assert!(index.check_fork_work(&recent, work * 1000).is_err());
+        // Extending the best header, or repeating known headers, is never held back
+        assert!(index.check_fork_work(&branch(&chain[299], 1, 1), work * 1000).is_ok());
+        assert!(index.check_fork_work(&chain[..20], work * 1000).is_ok());
+
+        // Claimed work counts only with the proof of work behind it
+        let mut unmined = child(&chain[10], 2);
+        unmined.bits = 0x1d00ffff;
+        assert!(index.check_fork_work(&[unmined], U256::zero()).is_err());
+    }
+}
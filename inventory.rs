@@ -0,0 +1,119 @@
+use crate::protocol::{Inventory, PeerId};
+use crate::rolling_bloom::RollingBloomFilter;
+use std::collections::HashMap;
+
+// Inventory remembered per peer, about a few minutes of busy transaction relay
+pub const PEER_KNOWN_INVENTORY: usize = 50_000;
+// Inventory remembered as received from anyone
+pub const RECENTLY_RECEIVED_INVENTORY: usize = 100_000;
+const FALSE_POSITIVE_RATE: f64 = 0.000_001;
+
+// Remembers which inventory each peer already has, because it announced, requested or sent it to
+// us or we announced it to it, so nothing is announced to a peer twice. Separately remembers what
+// arrived recently from anyone, so a second peer announcing it doesn't cause another download.
+// A false positive leaves an item unannounced to one peer, which then hears of it from another.
+pub struct InventoryTracker {
+    known: HashMap<PeerId, RollingBloomFilter>,
+    received: RollingBloomFilter,
+}
+
+// A kind byte ahead of the hash, so a block and a fruit with the same hash don't collide
+fn key(item: &Inventory) -> [u8; 33] {
+    let (kind, hash) = match item {
+        Inventory::Transaction(hash) => (b't', hash),
+        Inventory::Block(hash) => (b'b', hash),
+        Inventory::Fruit(hash) => (b'f', hash),
+    };
+    let mut key = [0u8; 33];
+    key[0] = kind;
+    key[1..].copy_from_slice(hash);
+    key
+}
+
+impl InventoryTracker {
+    pub fn new() -> Self {
+        InventoryTracker { known: HashMap::new(), received: RollingBloomFilter::new(RECENTLY_RECEIVED_INVENTORY, FALSE_POSITIVE_RATE) }
+    }
+
+    pub fn peer_disconnected(&mut self, peer: PeerId) {
+        self.known.remove(&peer);
+    }
+
+    fn known_by(&mut self, peer: PeerId) -> &mut RollingBloomFilter {
+        self.known.entry(peer).or_insert_with(|| RollingBloomFilter::new(PEER_KNOWN_INVENTORY, FALSE_POSITIVE_RATE))
+    }
+
+    pub fn mark_known(&mut self, peer: PeerId, item: &Inventory) {
+        self.known_by(peer).insert(&key(item));
+    }
+
+    pub fn knows(&self, peer: PeerId, item: &Inventory) -> bool {
+        self.known.get(&peer).is_some_and(|known| known.contains(&key(item)))
+    }
+
+    // The items of `peer`'s inv worth asking it for, leaving out what arrived recently. The peer
+    // has all of them, so none is announced back to it.
+    pub fn wanted(&mut self, peer: PeerId, items: &[Inventory]) -> Vec<Inventory> {
+        let known = self.known_by(peer);
+        for item in items {
+            known.insert(&key(item));
+        }
+        items.iter().filter(|item| !self.received.contains(&key(item))).copied().collect()
+    }
+
+    // Records an item that arrived, from `peer` if it came over the network
+    pub fn received(&mut self, peer: Option<PeerId>, item: &Inventory) {
+        self.received.insert(&key(item));
+        if let Some(peer) = peer {
+            self.mark_known(peer, item);
+        }
+    }
+
+    // The items `peer` doesn't have yet, each recorded as known to it as the caller is about to
+    // announce them
+    pub fn to_announce(&mut self, peer: PeerId, items: impl IntoIterator<Item = Inventory>) -> Vec<Inventory> {
+        let known = self.known_by(peer);
+        items.into_iter()
+            .filter(|item| {
+                let key = key(item);
+                let new = !known.contains(&key);
+                known.insert(&key);
+                new
+            })
+            .collect()
+    }
+}
+
+impl Default for InventoryTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inventory_is_announced_and_fetched_once() {
+        let mut tracker = InventoryTracker::new();
+        let (tx, block) = (Inventory::Transaction([1; 32]), Inventory::Block([1; 32]));
+
+        // Peer 1 announces both and we fetch both; peer 2 announcing them later gets no getdata
+        assert_eq!(tracker.wanted(1, &[tx, block]), vec![tx, block]);
+        tracker.received(Some(1), &tx);
+        tracker.received(Some(1), &block);
+        assert!(tracker.wanted(2, &[tx, block]).is_empty());
+
+        // Neither peer hears of them from us, while a third does, once
+        assert!(tracker.to_announce(1, [tx, block]).is_empty());
+        assert!(tracker.to_announce(2, [tx]).is_empty());
+        assert_eq!(tracker.to_announce(3, [tx, block, tx]), vec![tx, block]);
+        assert!(tracker.to_announce(3, [tx, block]).is_empty());
+        // The same hash as another kind of item is still new
+        assert_eq!(tracker.to_announce(3, [Inventory::Fruit([1; 32])]), vec![Inventory::Fruit([1; 32])]);
+
+        tracker.peer_disconnected(3);
+        assert!(!tracker.knows(3, &tx) && tracker.knows(1, &tx));
+    }
+}
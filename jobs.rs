@@ -0,0 +1,261 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+pub type JobId = u64;
+
+// Finished jobs kept for `getjobstatus` before the oldest are forgotten
+const MAX_FINISHED_JOBS: usize = 20;
+
+#[derive(Error, Debug)]
+pub enum JobError {
+    #[error("Job {id} ({name}) is still running")]
+    Busy { id: JobId, name: &'static str },
+    #[error("No job {0}")]
+    NotFound(JobId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    // Cancel requested; the job stops at its next safe point
+    Cancelling,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: JobId,
+    pub name: &'static str,
+    pub state: JobState,
+    pub done: u64,
+    pub total: u64,
+    pub percent: f64,
+    pub elapsed_secs: u64,
+    // Estimated from the rate so far; None until there is a rate
+    pub eta_secs: Option<u64>,
+    pub result: Option<Value>,
+    pub error: Option<String>,
+}
+
+// Handed to a long operation so it can report how far it got and notice a cancel request. Work
+// is counted in whatever units the operation likes, usually blocks.
+#[derive(Clone, Default)]
+pub struct JobProgress {
+    inner: Arc<ProgressCounters>,
+}
+
+#[derive(Default)]
+struct ProgressCounters {
+    done: AtomicU64,
+    total: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl JobProgress {
+    // For running an operation in the foreground, where nothing reads the progress
+    pub fn new() -> Self {
+        JobProgress::default()
+    }
+
+    pub fn set_total(&self, total: u64) {
+        self.inner.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn set_done(&self, done: u64) {
+        self.inner.done.store(done, Ordering::Relaxed);
+    }
+
+    // Checked by the operation between units of work; it stops where its data is consistent
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> (u64, u64) {
+        (self.inner.done.load(Ordering::Relaxed), self.inner.total.load(Ordering::Relaxed))
+    }
+}
+
+struct Job {
+    name: &'static str,
+    started: Instant,
+    progress: JobProgress,
+    // Set when the job returns
+    outcome: Option<(Instant, Result<Value, String>)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Job {
+    fn status(&self, id: JobId) -> JobStatus {
+        let (done, total) = self.progress.counts();
+        let cancelled = self.progress.is_cancelled();
+        let (state, result, error) = match &self.outcome {
+            None if cancelled => (JobState::Cancelling, None, None),
+            None => (JobState::Running, None, None),
+            Some((_, Err(e))) => (JobState::Failed, None, Some(e.clone())),
+            Some((_, Ok(value))) if cancelled => (JobState::Cancelled, Some(value.clone()), None),
+            Some((_, Ok(value))) => (JobState::Completed, Some(value.clone()), None),
+        };
+        let elapsed = self.outcome.as_ref().map_or(Instant::now(), |(finished, _)| *finished).saturating_duration_since(self.started);
+        let eta_secs = match (&self.outcome, done) {
+            (None, 1..) if total >= done => Some(elapsed.mul_f64((total - done) as f64 / done as f64).as_secs()),
+            _ => None,
+        };
+        JobStatus {
+            id,
+            name: self.name,
+            state,
+            done,
+            total,
+            percent: if total == 0 { 0.0 } else { done.min(total) as f64 * 100.0 / total as f64 },
+            elapsed_secs: elapsed.as_secs(),
+            eta_secs,
+            result,
+            error,
+        }
+    }
+}
+
+// Runs long maintenance operations such as reindexing on background tasks, one at a time, so the
+// node keeps answering RPC calls while they run and they can be watched and cancelled
+#[derive(Default)]
+pub struct JobManager {
+    jobs: Arc<Mutex<BTreeMap<JobId, Job>>>,
+    next_id: AtomicU64,
+}
+
+impl JobManager {
+    pub fn new() -> Self {
+        JobManager::default()
+    }
+
+    pub fn start<F, Fut>(&self, name: &'static str, job: F) -> Result<JobId, JobError>
+    where
+        F: FnOnce(JobProgress) -> Fut,
+        Fut: Future<Output = Result<Value, String>> + Send + 'static,
+    {
+        let mut jobs = self.jobs.lock();
+        if let Some((id, running)) = jobs.iter().find(|(_, job)| job.outcome.is_none()) {
+            return Err(JobError::Busy { id: *id, name: running.name });
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let progress = JobProgress::new();
+        let future = job(progress.clone());
+        let shared = Arc::clone(&self.jobs);
+        let handle = tokio::spawn(async move {
+            let outcome = future.await;
+            match &outcome {
+                Ok(_) => log::info!("Job {} ({}) finished", id, name),
+                Err(e) => log::error!("Job {} ({}) failed: {}", id, name, e),
+            }
+            if let Some(job) = shared.lock().get_mut(&id) {
+                job.outcome = Some((Instant::now(), outcome));
+                job.handle = None;
+            }
+        });
+        jobs.insert(id, Job { name, started: Instant::now(), progress, outcome: None, handle: Some(handle) });
+        forget_finished(&mut jobs);
+        log::info!("Started job {} ({})", id, name);
+        Ok(id)
+    }
+
+    pub fn status(&self, id: JobId) -> Result<JobStatus, JobError> {
+        self.jobs.lock().get(&id).map(|job| job.status(id)).ok_or(JobError::NotFound(id))
+    }
+
+    // Every job still remembered, oldest first
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.jobs.lock().iter().map(|(id, job)| job.status(*id)).collect()
+    }
+
+    // Asks a running job to stop; false if it had already finished
+    pub fn cancel(&self, id: JobId) -> Result<bool, JobError> {
+        let jobs = self.jobs.lock();
+        let job = jobs.get(&id).ok_or(JobError::NotFound(id))?;
+        if job.outcome.is_some() {
+            return Ok(false);
+        }
+        job.progress.cancel();
+        log::info!("Cancelling job {} ({})", id, job.name);
+        Ok(true)
+    }
+
+    // Cancels whatever is running and waits for it to reach a safe point, before the node flushes
+    // its state and exits
+    pub async fn shutdown(&self) {
+        let handles = self.jobs.lock().values_mut()
+            .filter(|job| job.outcome.is_none())
+            .filter_map(|job| {
+                job.progress.cancel();
+                job.handle.take()
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            if let Err(e) = handle.await {
+                log::error!("Job task ended abnormally: {}", e);
+            }
+        }
+    }
+}
+
+fn forget_finished(jobs: &mut BTreeMap<JobId, Job>) {
+    let finished = jobs.iter().filter(|(_, job)| job.outcome.is_some()).map(|(id, _)| *id).collect::<Vec<_>>();
+    for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+        jobs.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_jobs_report_progress_and_stop_when_cancelled() {
+        let manager = JobManager::new();
+        let id = manager.start("count", |progress| async move {
+            progress.set_total(1_000);
+            let mut done = 0;
+            while !progress.is_cancelled() && done < 1_000 {
+                done += 1;
+                progress.set_done(done);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            Ok(json!(done))
+        }).unwrap();
+        assert!(matches!(manager.start("other", |_| async { Ok(Value::Null) }), Err(JobError::Busy { id: busy, .. }) if busy == id));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let status = manager.status(id).unwrap();
+        assert_eq!((status.state, status.total), (JobState::Running, 1_000));
+        assert!(status.done > 0 && status.eta_secs.is_some());
+
+        assert!(manager.cancel(id).unwrap());
+        manager.shutdown().await;
+        let status = manager.status(id).unwrap();
+        assert_eq!(status.state, JobState::Cancelled);
+        assert!(status.done < 1_000 && status.result == Some(json!(status.done)));
+        assert!(!manager.cancel(id).unwrap());
+        assert!(matches!(manager.status(id + 1), Err(JobError::NotFound(_))));
+
+        // The slot is free again once the job has stopped
+        let failing = manager.start("fail", |_| async { Err("broken".to_string()) }).unwrap();
+        manager.shutdown().await;
+        assert_eq!(manager.status(failing).unwrap().state, JobState::Failed);
+    }
+}
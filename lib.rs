@@ -0,0 +1,60 @@
+pub mod address_index;
+pub mod addrman;
+pub mod block_archive;
+pub mod block_download;
+pub mod block_filter;
+pub mod block_stats;
+pub mod block_storage;
+pub mod broadcast;
+pub mod blockchain;
+pub mod chain_export;
+pub mod chain_params;
+pub mod codec;
+pub mod datadir;
+pub mod difficulty;
+pub mod disk_monitor;
+pub mod eviction;
+pub mod grpc;
+pub mod header_index;
+pub mod jobs;
+pub mod hd_keys;
+pub mod inventory;
+pub mod light_client;
+pub mod lru_cache;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
+pub mod multisig;
+pub mod net;
+pub mod network_time;
+pub mod node_config;
+pub mod node_status;
+pub mod notifications;
+pub mod outbound;
+pub mod policy;
+pub mod pow;
+pub mod pow_backend;
+pub mod protocol;
+pub mod psbt;
+pub mod rate_limit;
+pub mod reward;
+pub mod rolling_bloom;
+pub mod rpc;
+pub mod rpc_auth;
+pub mod scheduler;
+pub mod script_cache;
+pub mod snapshot;
+pub mod spent_index;
+pub mod storage;
+#[cfg(test)]
+pub mod testutil;
+pub mod transaction;
+pub mod transport;
+pub mod trickle;
+pub mod utxo_cache;
+pub mod utxo_set_hash;
+pub mod validation;
+pub mod versionbits;
+pub mod wallet;
+pub mod wallet_history;
+pub mod wire;
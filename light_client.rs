@@ -0,0 +1,403 @@
+use crate::blockchain::{BlockHash, BlockHeader, MEDIAN_TIME_SPAN};
+use crate::chain_params::ChainParams;
+use crate::merkle::MerkleBranch;
+use crate::pow;
+use primitive_types::U256;
+use crate::storage::{Storage, SCHEMA_VERSION};
+use crate::transaction::TxHash;
+use std::collections::HashMap;
+use thiserror::Error;
+
+// A fork may fall this many of our tip's blocks behind in work and still have its headers kept
+const FORK_WORK_ALLOWANCE_BLOCKS: u64 = 144;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum HeaderError {
+    #[error("Header extends unknown parent {}", hex::encode(.0))]
+    UnknownParent(BlockHash),
+    #[error("Genesis header does not match the chain already stored")]
+    GenesisMismatch,
+    #[error("Header hash does not meet its target")]
+    InsufficientProofOfWork,
+    #[error("Header bits {actual:#010x} do not match the expected {expected:#010x}")]
+    BadBits { expected: u32, actual: u32 },
+    #[error("Header timestamp {timestamp} is not after the median time past {median_time_past}")]
+    TimestampTooOld { timestamp: u64, median_time_past: u64 },
+    #[error("Block {} is not in the best header chain", hex::encode(.0))]
+    NotInBestChain(BlockHash),
+    #[error("Merkle branch does not connect the transaction to the block")]
+    BadProof,
+    #[error("Headers do not each build on the one before")]
+    NotContinuous,
+    #[error("Header chain has {work} work, less than the {required} required to store a fork")]
+    InsufficientChainWork { work: U256, required: U256 },
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderEntry {
+    pub header: BlockHeader,
+    pub height: u64,
+    // Total work of the chain ending at this header, genesis included
+    pub chain_work: U256,
+}
+
+// Every valid header seen so far plus the best chain through them, indexed by height.
+// The best chain is the one with the most cumulative work. On equal work the tip seen first is
+// kept, so peers racing blocks at the same height don't flip us back and forth.
+pub struct HeaderChain {
+    params: ChainParams,
+    headers: HashMap<BlockHash, HeaderEntry>,
+    best_chain: Vec<BlockHash>,
+}
+
+impl HeaderChain {
+    pub fn new(params: ChainParams) -> Self {
+        HeaderChain { params, headers: HashMap::new(), best_chain: Vec::new() }
+    }
+
+    pub fn tip(&self) -> Option<&HeaderEntry> {
+        self.best_chain.last().map(|hash| &self.headers[hash])
+    }
+
+    pub fn height(&self) -> Option<u64> {
+        self.tip().map(|entry| entry.height)
+    }
+
+    pub fn get(&self, hash: &BlockHash) -> Option<&HeaderEntry> {
+        self.headers.get(hash)
+    }
+
+    pub fn hash_at_height(&self, height: u64) -> Option<BlockHash> {
+        self.best_chain.get(height as usize).copied()
+    }
+
+    pub fn is_in_best_chain(&self, hash: &BlockHash) -> bool {
+        self.headers.get(hash).is_some_and(|entry| self.hash_at_height(entry.height).as_ref() == Some(hash))
+    }
+
+    // Validates `header` against its parent and adds it, switching the best chain if it now has the most work.
+    // Returns the header's height; headers that are already known are accepted again unchanged.
+    pub fn accept_header(&mut self, header: BlockHeader) -> Result<u64, HeaderError> {
+        let hash = header.hash();
+        if let Some(entry) = self.headers.get(&hash) {
+            return Ok(entry.height);
+        }
+
+        if !pow::check_proof_of_work(&hash, header.bits) {
+            return Err(HeaderError::InsufficientProofOfWork);
+        }
+
+        let work = pow::block_work(header.bits);
+        let (height, chain_work) = if header.previous_hash == [0; 32] {
+            if !self.best_chain.is_empty() {
+                return Err(HeaderError::GenesisMismatch);
+            }
+            if header.bits != self.params.genesis_bits {
+                return Err(HeaderError::BadBits { expected: self.params.genesis_bits, actual: header.bits });
+            }
+            (0, work)
+        } else {
+            let parent = self.headers.get(&header.previous_hash)
+                .ok_or(HeaderError::UnknownParent(header.previous_hash))?;
+
+            let expected = self.expected_bits(&header.previous_hash, parent, header.timestamp);
+            if header.bits != expected {
+                return Err(HeaderError::BadBits { expected, actual: header.bits });
+            }
+
+            let median_time_past = self.median_time_past(&header.previous_hash);
+            if header.timestamp <= median_time_past {
+                return Err(HeaderError::TimestampTooOld { timestamp: header.timestamp, median_time_past });
+            }
+            (parent.height + 1, parent.chain_work + work)
+        };
+
+        self.headers.insert(hash, HeaderEntry { header, height, chain_work });
+        if self.tip().is_none_or(|best| chain_work > best.chain_work) {
+            self.switch_best_chain(hash, height);
+        }
+        Ok(height)
+    }
+
+    // Accepts a batch of headers, each building on the one before, and returns the last one's height.
+    // A batch that forks off the best chain is checked for proof of work alone first, and none of it
+    // is stored unless the chain it ends has at least `fork_work_threshold` work.
+    pub fn accept_headers(&mut self, headers: &[BlockHeader]) -> Result<Option<u64>, HeaderError> {
+        let Some(first) = headers.first() else { return Ok(None) };
+        let mut chain_work = match first.previous_hash {
+            previous if previous == [0; 32] => U256::zero(),
+            previous => self.headers.get(&previous).ok_or(HeaderError::UnknownParent(previous))?.chain_work,
+        };
+        let mut previous_hash = first.previous_hash;
+        let mut fork_parent = None;
+        for header in headers {
+            if header.previous_hash != previous_hash {
+                return Err(HeaderError::NotContinuous);
+            }
+            let hash = header.hash();
+            if !pow::check_proof_of_work(&hash, header.bits) {
+                return Err(HeaderError::InsufficientProofOfWork);
+            }
+            if fork_parent.is_none() && !self.is_in_best_chain(&hash) {
+                fork_parent = Some(header.previous_hash);
+            }
+            chain_work = chain_work.saturating_add(pow::block_work(header.bits));
+            previous_hash = hash;
+        }
+        // Headers extending our tip are fully validated one by one, so only forks are held back
+        let extends_tip = fork_parent.is_none_or(|parent| match self.best_chain.last() {
+            Some(tip) => parent == *tip,
+            None => parent == [0; 32],
+        });
+        let required = self.fork_work_threshold();
+        if !extends_tip && chain_work < required {
+            return Err(HeaderError::InsufficientChainWork { work: chain_work, required });
+        }
+        let mut height = None;
+        for header in headers {
+            height = Some(self.accept_header(header.clone())?);
+        }
+        Ok(height)
+    }
+
+    // The chain's minimum work, or our own work less an allowance for recent forks once that's more
+    fn fork_work_threshold(&self) -> U256 {
+        let Some(tip) = self.tip() else { return self.params.minimum_chain_work };
+        let allowance = pow::block_work(tip.header.bits).saturating_mul(U256::from(FORK_WORK_ALLOWANCE_BLOCKS));
+        self.params.minimum_chain_work.max(tip.chain_work.saturating_sub(allowance))
+    }
+
+    // Confirms `txid` is committed to by a block on the best chain and returns that block's height
+    pub fn verify_transaction(&self, txid: &TxHash, block_hash: &BlockHash, branch: &MerkleBranch) -> Result<u64, HeaderError> {
+        if !self.is_in_best_chain(block_hash) {
+            return Err(HeaderError::NotInBestChain(*block_hash));
+        }
+        let entry = &self.headers[block_hash];
+        if !branch.verify(&entry.header.merkle_root, txid) {
+            return Err(HeaderError::BadProof);
+        }
+        Ok(entry.height)
+    }
+
+    fn expected_bits(&self, parent_hash: &BlockHash, parent: &HeaderEntry, timestamp: u64) -> u32 {
+        let ancestors = pow::retarget_heights(&self.params, parent.height)
+            .into_iter()
+            .filter_map(|height| self.ancestor(parent_hash, height))
+            .map(|entry| (entry.header.timestamp, entry.header.bits))
+            .collect::<Vec<_>>();
+        pow::next_bits(&self.params, parent.height, &ancestors, timestamp)
+    }
+
+    fn median_time_past(&self, hash: &BlockHash) -> u64 {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current = self.headers.get(hash);
+        while let Some(entry) = current {
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            timestamps.push(entry.header.timestamp);
+            current = self.headers.get(&entry.header.previous_hash);
+        }
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    // Ancestor of `hash` at `height`, jumping straight to the best chain once the walk reaches it
+    fn ancestor(&self, hash: &BlockHash, height: u64) -> Option<&HeaderEntry> {
+        let mut hash = *hash;
+        loop {
+            let entry = self.headers.get(&hash)?;
+            if entry.height < height {
+                return None;
+            }
+            if self.is_in_best_chain(&hash) {
+                return self.hash_at_height(height).and_then(|h| self.headers.get(&h));
+            }
+            if entry.height == height {
+                return Some(entry);
+            }
+            hash = entry.header.previous_hash;
+        }
+    }
+
+    fn switch_best_chain(&mut self, tip: BlockHash, height: u64) {
+        let mut branch = Vec::new();
+        let mut hash = tip;
+        let mut current_height = height;
+        // Walk back until the new branch rejoins the current best chain
+        while self.hash_at_height(current_height) != Some(hash) {
+            branch.push(hash);
+            if current_height == 0 {
+                break;
+            }
+            hash = self.headers[&hash].header.previous_hash;
+            current_height -= 1;
+        }
+
+        let fork_height = height + 1 - branch.len() as u64;
+        if fork_height < self.best_chain.len() as u64 {
+            log::info!("Header chain reorganised at height {}", fork_height);
+        }
+        self.best_chain.truncate(fork_height as usize);
+        self.best_chain.extend(branch.into_iter().rev());
+    }
+}
+
+// Headers-only node state: a HeaderChain persisted to the headers column family
+pub struct LightClient {
+    storage: Storage,
+    chain: HeaderChain,
+}
+
+impl LightClient {
+    pub async fn open(storage: Storage, params: ChainParams) -> Result<Self, Box<dyn std::error::Error>> {
+        if storage.get_schema_version().await?.is_none_or(|v| v < SCHEMA_VERSION) {
+            storage.migrate_header_index().await?;
+            storage.set_schema_version(SCHEMA_VERSION).await?;
+        }
+        let mut chain = HeaderChain::new(params);
+        // Stored headers were validated when first accepted and are loaded parents-first
+        for (_, header) in storage.load_headers().await? {
+            chain.accept_header(header)?;
+        }
+        Ok(LightClient { storage, chain })
+    }
+
+    pub fn chain(&self) -> &HeaderChain {
+        &self.chain
+    }
+
+    pub async fn add_header(&mut self, header: BlockHeader) -> Result<u64, Box<dyn std::error::Error>> {
+        let hash = header.hash();
+        let known = self.chain.get(&hash).is_some();
+        let height = self.chain.accept_header(header.clone())?;
+        if !known {
+            self.storage.store_header(&hash, height, &header).await?;
+        }
+        Ok(height)
+    }
+
+    // Adds a batch as HeaderChain::accept_headers does, storing the headers that are new. Headers
+    // accepted before an invalid one in the batch are stored too.
+    pub async fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        let new = headers.iter().filter(|header| self.chain.get(&header.hash()).is_none()).cloned().collect::<Vec<_>>();
+        let result = self.chain.accept_headers(headers);
+        for header in new {
+            let hash = header.hash();
+            let Some(entry) = self.chain.get(&hash) else { break };
+            self.storage.store_header(&hash, entry.height, &header).await?;
+        }
+        Ok(result?)
+    }
+
+    pub fn verify_transaction(&self, txid: &TxHash, block_hash: &BlockHash, branch: &MerkleBranch) -> Result<u64, HeaderError> {
+        self.chain.verify_transaction(txid, block_hash, branch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_params::Network;
+    use crate::difficulty::GENESIS_BLOCK_DIFFICULTY;
+    use crate::versionbits::VERSIONBITS_TOP_BITS;
+
+    fn params() -> ChainParams {
+        ChainParams::for_network(Network::Regtest)
+    }
+
+    fn mine(previous_hash: BlockHash, timestamp: u64, merkle_root: [u8; 32]) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: VERSIONBITS_TOP_BITS,
+            previous_hash,
+            merkle_root,
+            fruits_root: [0; 32],
+            timestamp,
+            bits: GENESIS_BLOCK_DIFFICULTY,
+            nonce: 0,
+        };
+        while !pow::check_proof_of_work(&header.hash(), header.bits) {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    #[test]
+    fn test_most_work_header_chain_wins() {
+        let mut chain = HeaderChain::new(params());
+        let genesis = mine([0; 32], 1, [0; 32]);
+        chain.accept_header(genesis.clone()).unwrap();
+
+        let a1 = mine(genesis.hash(), 2, [1; 32]);
+        let b1 = mine(genesis.hash(), 2, [2; 32]);
+        let b2 = mine(b1.hash(), 3, [2; 32]);
+        assert_eq!(chain.accept_header(a1.clone()), Ok(1));
+        assert_eq!(chain.hash_at_height(1), Some(a1.hash()));
+
+        // Equal work keeps the first-seen tip
+        chain.accept_header(b1.clone()).unwrap();
+        assert_eq!(chain.hash_at_height(1), Some(a1.hash()));
+        assert_eq!(chain.get(&b1.hash()).unwrap().chain_work, chain.tip().unwrap().chain_work);
+        chain.accept_header(b2.clone()).unwrap();
+        assert_eq!(chain.hash_at_height(1), Some(b1.hash()));
+        assert_eq!(chain.height(), Some(2));
+        assert_eq!(chain.tip().unwrap().chain_work, pow::block_work(GENESIS_BLOCK_DIFFICULTY) * 3);
+        assert!(!chain.is_in_best_chain(&a1.hash()));
+
+        let orphan = mine([9; 32], 4, [0; 32]);
+        assert_eq!(chain.accept_header(orphan), Err(HeaderError::UnknownParent([9; 32])));
+    }
+
+    fn mine_chain(previous_hash: BlockHash, start_time: u64, count: u64, merkle_root: [u8; 32]) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for i in 0..count {
+            let previous = headers.last().map_or(previous_hash, BlockHeader::hash);
+            headers.push(mine(previous, start_time + i, merkle_root));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_low_work_forks_are_not_stored() {
+        let mut params = params();
+        params.retarget_interval = 10_000;
+        let work = pow::block_work(GENESIS_BLOCK_DIFFICULTY);
+        let mut chain = HeaderChain::new(params.clone());
+        let main = mine_chain([0; 32], 1, 150, [0; 32]);
+        assert_eq!(chain.accept_headers(&main), Ok(Some(149)));
+        // Refetching part of the best chain is harmless
+        assert_eq!(chain.accept_headers(&main[140..]), Ok(Some(149)));
+
+        // A short fork off genesis is far behind the tip and nothing of it is kept
+        let short = mine_chain(main[0].hash(), 2, 3, [1; 32]);
+        assert_eq!(chain.accept_headers(&short), Err(HeaderError::InsufficientChainWork { work: work * 4, required: work * 6 }));
+        assert!(short.iter().all(|header| chain.get(&header.hash()).is_none()));
+        // Within the allowance it is stored, though still not the best chain
+        let long = mine_chain(main[0].hash(), 2, 5, [1; 32]);
+        assert_eq!(chain.accept_headers(&long), Ok(Some(5)));
+        assert!(chain.get(&long[4].hash()).is_some());
+        assert_eq!(chain.height(), Some(149));
+        assert_eq!(chain.accept_headers(&[main[1].clone(), main[3].clone()]), Err(HeaderError::NotContinuous));
+
+        // Until a node has the network's minimum work only its own chain's headers are kept
+        params.minimum_chain_work = work * 1000;
+        let mut chain = HeaderChain::new(params);
+        assert_eq!(chain.accept_headers(&main[..10]), Ok(Some(9)));
+        let fork = mine_chain(main[4].hash(), 100, 10, [1; 32]);
+        assert!(matches!(chain.accept_headers(&fork), Err(HeaderError::InsufficientChainWork { .. })));
+        assert_eq!(chain.accept_headers(&main[10..]), Ok(Some(149)));
+    }
+
+    #[test]
+    fn test_transaction_proof_against_headers() {
+        let leaves = (0u8..5).map(|i| [i; 32]).collect::<Vec<_>>();
+        let root = crate::merkle::merkle_root(&leaves);
+        let mut chain = HeaderChain::new(params());
+        let genesis = mine([0; 32], 1, root);
+        chain.accept_header(genesis.clone()).unwrap();
+
+        let branch = MerkleBranch::new(&leaves, 3).unwrap();
+        assert_eq!(chain.verify_transaction(&leaves[3], &genesis.hash(), &branch), Ok(0));
+        assert_eq!(chain.verify_transaction(&leaves[2], &genesis.hash(), &branch), Err(HeaderError::BadProof));
+    }
+}
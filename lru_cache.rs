@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// Least-recently-used cache bounded by the total weight of its values, such as their size in
+// bytes. Each access stamps the entry from a counter and the stamps are kept in order, so the
+// oldest entry is always the first one.
+pub struct LruCache<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    order: BTreeMap<u64, K>,
+    next_stamp: u64,
+    capacity: usize,
+    weight: usize,
+}
+
+struct Entry<V> {
+    value: V,
+    weight: usize,
+    stamp: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache { entries: HashMap::new(), order: BTreeMap::new(), next_stamp: 0, capacity, weight: 0 }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.stamp);
+        entry.stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.order.insert(entry.stamp, key.clone());
+        Some(&entry.value)
+    }
+
+    // Evicts the least recently used entries to make room. Values heavier than the whole
+    // capacity aren't kept at all.
+    pub fn insert(&mut self, key: K, value: V, weight: usize) {
+        self.remove(&key);
+        if weight > self.capacity {
+            return;
+        }
+        while self.weight + weight > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.weight -= evicted.weight;
+            }
+        }
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.order.insert(stamp, key.clone());
+        self.entries.insert(key, Entry { value, weight, stamp });
+        self.weight += weight;
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.stamp);
+        self.weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.weight = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Total weight of the entries held
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_goes_first() {
+        let mut cache = LruCache::new(10);
+        cache.insert("a", 1, 4);
+        cache.insert("b", 2, 4);
+        assert_eq!(cache.get(&"a"), Some(&1));
+
+        // "b" is older than "a" now
+        cache.insert("c", 3, 4);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!((cache.len(), cache.weight()), (2, 8));
+
+        cache.insert("a", 10, 2);
+        assert_eq!(cache.get(&"a"), Some(&10));
+        assert_eq!(cache.weight(), 6);
+        cache.insert("huge", 4, 11);
+        assert_eq!(cache.get(&"huge"), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.remove(&"c"), Some(3));
+        assert_eq!(cache.weight(), 2);
+    }
+}
@@ -1,271 +0,0 @@
-Certainly! I'll provide a complete `main.rs` file that incorporates all the elements we've discussed, including the use of `parking_lot` for synchronization. This will give you a comprehensive overview of our blockchain implementation with the latest optimizations.
-
-
-
-```rust
-// main.rs
-
-mod storage;
-
-use storage::Storage;
-use std::io::{self, Read, Write, Seek, SeekFrom};
-use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
-use blake3;
-use serde::{Serialize, Deserialize};
-use lz4::EncoderBuilder;
-use config::{Config, ConfigError, File as ConfigFile};
-use parking_lot::RwLock;
-use std::sync::Arc;
-
-#[derive(Debug, Deserialize, Clone)]
-struct BlockchainConfig {
-    db_path: String,
-    blocks_dir: PathBuf,
-    max_block_file_size: u64,
-    compression_level: u32,
-}
-
-impl BlockchainConfig {
-    fn new() -> Result<Self, ConfigError> {
-        let mut cfg = Config::default();
-        cfg.merge(ConfigFile::with_name("config/default").required(false))?;
-        cfg.merge(config::Environment::with_prefix("APP"))?;
-        cfg.try_into()
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Transaction {
-    data: String,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct BlockHeader {
-    previous_hash: [u8; 32],
-    merkle_root: [u8; 32],
-    timestamp: u64,
-    nonce: u64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Block {
-    header: BlockHeader,
-    transactions: Vec<Transaction>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct BlockLocation {
-    file_name: String,
-    byte_offset: u64,
-}
-
-struct BlockStorage {
-    config: BlockchainConfig,
-    current_file_index: u64,
-    current_file_size: u64,
-}
-
-impl BlockStorage {
-    fn new(config: BlockchainConfig) -> io::Result<Self> {
-        std::fs::create_dir_all(&config.blocks_dir)?;
-        Ok(Self {
-            config,
-            current_file_index: 1,
-            current_file_size: 0,
-        })
-    }
-
-    fn determine_target_file(&mut self) -> String {
-        if self.current_file_size >= self.config.max_block_file_size {
-            self.current_file_index += 1;
-            self.current_file_size = 0;
-        }
-        self.config.blocks_dir.join(format!("block_file_{}.dat.lz4", self.current_file_index))
-            .to_str().unwrap().to_string()
-    }
-
-    fn append_block_to_file(&mut self, block_data: &[u8]) -> io::Result<(String, u64)> {
-        let file_name = self.determine_target_file();
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&file_name)?;
-        
-        let byte_offset = file.seek(SeekFrom::End(0))?;
-        
-        let mut encoder = EncoderBuilder::new()
-            .level(self.config.compression_level)
-            .build(&mut file)?;
-        encoder.write_all(block_data)?;
-        let (_, result) = encoder.finish();
-        result?;
-        
-        let compressed_size = file.seek(SeekFrom::Current(0))? - byte_offset;
-        self.current_file_size += compressed_size;
-        
-        Ok((file_name, byte_offset))
-    }
-
-    fn read_block_from_file(&self, location: &BlockLocation) -> io::Result<Vec<u8>> {
-        let mut file = File::open(&location.file_name)?;
-        file.seek(SeekFrom::Start(location.byte_offset))?;
-        
-        let mut decoder = lz4::Decoder::new(file)?;
-        let mut decompressed_data = Vec::new();
-        decoder.read_to_end(&mut decompressed_data)?;
-        
-        Ok(decompressed_data)
-    }
-}
-
-type BlockHash = [u8; 32];
-
-struct Blockchain {
-    storage: Storage,
-    block_storage: BlockStorage,
-    chain_tip: Arc<RwLock<BlockHash>>,
-}
-
-impl Blockchain {
-    async fn new(config: BlockchainConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let storage = Storage::new(&config.db_path).await?;
-        let block_storage = BlockStorage::new(config.clone())?;
-        let chain_tip = Arc::new(RwLock::new([0; 32])); // Initialize with genesis block hash
-        Ok(Self { storage, block_storage, chain_tip })
-    }
-
-    fn get_chain_tip(&self) -> BlockHash {
-        *self.chain_tip.read()
-    }
-
-    async fn add_block(&self, block: Block) -> Result<(), Box<dyn std::error::Error>> {
-        let block_data = bincode::serialize(&block)?;
-        let block_hash = blake3::hash(&block_data).into();
-        
-        // Store block in file system
-        let (file_name, byte_offset) = self.block_storage.append_block_to_file(&block_data)?;
-        
-        // Store block location in database
-        let location = BlockLocation { file_name, byte_offset };
-        self.storage.store_block_location(&block_hash, &location).await?;
-        
-        // Update chain tip
-        let mut chain_tip = self.chain_tip.write();
-        *chain_tip = block_hash;
-        
-        Ok(())
-    }
-
-    async fn get_block(&self, block_hash: &BlockHash) -> Result<Option<Block>, Box<dyn std::error::Error>> {
-        if let Some(location) = self.storage.retrieve_block_location(block_hash).await? {
-            let block_data = self.block_storage.read_block_from_file(&location)?;
-            let block: Block = bincode::deserialize(&block_data)?;
-            Ok(Some(block))
-        } else {
-            Ok(None)
-        }
-    }
-}
-
-fn calculate_merkle_root(transactions: &[Transaction]) -> [u8; 32] {
-    let mut hasher = blake3::Hasher::new();
-    for transaction in transactions {
-        hasher.update(&bincode::serialize(transaction).unwrap());
-    }
-    *hasher.finalize().as_bytes()
-}
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = BlockchainConfig::new()?;
-    let blockchain = Arc::new(Blockchain::new(config).await?);
-
-    // Example: Create and store a block
-    let transactions = vec![
-        Transaction { data: "Transaction 1".to_string() },
-        Transaction { data: "Transaction 2".to_string() },
-    ];
-
-    let merkle_root = calculate_merkle_root(&transactions);
-
-    let block = Block {
-        header: BlockHeader {
-            previous_hash: blockchain.get_chain_tip(),
-            merkle_root,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)?
-                .as_secs(),
-            nonce: 0, // In a real scenario, this would be calculated during mining
-        },
-        transactions,
-    };
-
-    // Simulate concurrent operations
-    let blockchain_clone = Arc::clone(&blockchain);
-    
-    let add_block_handle = tokio::spawn(async move {
-        blockchain_clone.add_block(block).await
-    });
-
-    let get_tip_handle = tokio::spawn({
-        let blockchain = Arc::clone(&blockchain);
-        async move {
-            let chain_tip = blockchain.get_chain_tip();
-            println!("Current chain tip: {:?}", chain_tip);
-        }
-    });
-
-    // Wait for both operations to complete
-    let _ = tokio::try_join!(add_block_handle, get_tip_handle)?;
-
-    // Retrieve and print the newly added block
-    let chain_tip = blockchain.get_chain_tip();
-    if let Some(retrieved_block) = blockchain.get_block(&chain_tip).await? {
-        println!("Retrieved latest block: {:?}", retrieved_block);
-    } else {
-        println!("Failed to retrieve the latest block");
-    }
-
-    Ok(())
-}
-
-```
-
-
-This complete `main.rs` file incorporates all the elements we've discussed:
-
-1. Configuration management using the `config` crate.
-2. LZ4 compression for block storage.
-3. RocksDB integration for storing block locations (assumed to be implemented in the `storage` module).
-4. `parking_lot` for efficient synchronization of the chain tip.
-5. Asynchronous programming with Tokio.
-6. A basic blockchain structure with blocks, transactions, and hash-based linking.
-7. Concurrent operations demonstration in the `main` function.
-
-Key points about this implementation:
-
-1. Modularity: The code separates concerns into `BlockStorage` (file operations), `Storage` (database operations), and `Blockchain` (high-level blockchain logic).
-
-2. Concurrency: Uses `parking_lot::RwLock` for efficient, concurrent access to the chain tip.
-
-3. Asynchronous Operations: Utilizes Tokio for asynchronous file and database operations.
-
-4. Error Handling: Consistent use of `Result` for error propagation.
-
-5. Serialization: Uses `bincode` for efficient binary serialization of blocks.
-
-6. Hashing: Employs `blake3` for fast and secure hashing.
-
-7. Configuration: Flexible configuration using environment variables and config files.
-
-8. Compression: LZ4 compression for efficient block storage.
-
-This implementation provides a solid foundation for a blockchain system with efficient storage, concurrent access, and a clear structure. It demonstrates how to handle file I/O, database operations, and in-memory state management in a blockchain context.
-
-To use this code, you'll need to:
-1. Implement the `storage` module with the `Storage` struct and its methods.
-2. Create a `config/default.toml` file with the necessary configuration.
-3. Add all required dependencies to your `Cargo.toml` file.
-
-Remember, this is still a simplified blockchain implementation. In a production system, you'd need to add more features like networking, consensus mechanisms, proper mining, and extensive error handling and recovery procedures.​​​​​​​​​​​​​​​​
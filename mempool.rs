@@ -1,11 +1,86 @@
-use crate::transaction::Transaction;
+use crate::transaction::{OutPoint, Transaction, TxHash, TxInput, TxOutput};
 use crate::blockchain::{SignedBlock, FruitHeader, BlockType};
+use crate::codec;
+use crate::merkle::{self, MerkleBranch, MerkleLevels};
+use crate::policy::{PolicyError, RelayPolicy};
+use crate::pow;
+use crate::rolling_bloom::RollingBloomFilter;
 use blake3;
 use hex;
-use rs_merkle::{MerkleTree, MerkleProof, Hasher};
-use std::time::{Duration, Instant};
-use std::collections::{HashMap, VecDeque};
+use rs_merkle::{MerkleTree, Hasher};
+use serde::{Serialize, Deserialize};
+use std::mem::size_of;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 use thiserror::Error;
+use tokio::sync::broadcast;
+
+// The fruit pool is written here periodically and at shutdown when `mempool.persist_fruits` is set,
+// so the work that went into the fruits isn't lost to a restart or a crash
+pub const FRUITS_FILE_NAME: &str = "fruits.dat";
+// Cap on transactions parked until their lock_time passes
+const MAX_NON_FINAL_TRANSACTIONS: usize = 1_000;
+// Cap on fruits held while the block they hang from is unknown
+const MAX_ORPHAN_FRUITS: usize = 500;
+// Most transactions `add_package` takes at once
+pub const MAX_PACKAGE_COUNT: usize = 25;
+// Lower bounds of the fee-rate bands reported by `info`, in fee units per byte
+const FEE_RATE_BANDS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 500.0];
+// Upper bounds of the age bands reported by `info`; older entries fall in a final open-ended band
+const AGE_BANDS_SECS: &[u64] = &[60, 600, 3600, 6 * 3600, 24 * 3600];
+// Events a slow subscriber can fall behind by before it starts missing them
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+// Transactions the rejection and confirmation filters remember, and their false positive rate
+const RECENT_REJECTS_CAPACITY: usize = 120_000;
+const RECENTLY_CONFIRMED_CAPACITY: usize = 48_000;
+const RECENT_FILTER_FALSE_POSITIVE_RATE: f64 = 0.000_001;
+// Reject reasons are kept for the latest rejections only; older ones are just known as rejected
+const MAX_REJECT_REASONS: usize = 1_000;
+// Most pool transactions, counting descendants, a single replacement may evict
+const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+// Removals remembered for `recent_removals`, so a client can ask after a transaction it stopped seeing
+const MAX_RECENT_REMOVALS: usize = 1_000;
+// A merkle tree keeps each leaf and the levels above it, about two hashes per leaf in all
+const MERKLE_TREE_BYTES_PER_LEAF: usize = 2 * size_of::<TxHash>();
+
+#[derive(Debug, Clone)]
+pub enum MempoolEvent {
+    // Entered the pool proper; held non-final transactions are announced once they're promoted
+    TransactionAdded(Arc<Transaction>),
+    // Left the pool proper. Held non-final transactions leave silently, as they were never announced.
+    TransactionRemoved { txid: TxHash, reason: RemovalReason },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemovalReason {
+    // Mined in a block on the active chain
+    Confirmed,
+    // In the pool longer than the maximum age
+    Expired,
+    // Among the lowest fee rates while the pool was over its size limit
+    EvictedForFee,
+    // Double-spent by a transaction paying more, or descended from one that was
+    Replaced,
+    // A reorg lowered the tip below its lock time, so it went back to being held until final
+    ReorgReturned,
+    // Double-spent by a confirmed transaction, or descended from one that was
+    Conflict,
+}
+
+impl RemovalReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RemovalReason::Confirmed => "confirmed",
+            RemovalReason::Expired => "expired",
+            RemovalReason::EvictedForFee => "evicted-for-fee",
+            RemovalReason::Replaced => "replaced",
+            RemovalReason::ReorgReturned => "reorg-returned",
+            RemovalReason::Conflict => "conflict",
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct TransactionHasher;
@@ -16,6 +91,14 @@ impl Hasher for TransactionHasher {
     fn hash(data: &[u8]) -> Self::Hash {
         blake3::hash(data).into()
     }
+
+    // Parents hash under `merkle`'s node prefix; leaves go in already hashed with `merkle::hash_leaf`
+    fn concat_and_hash(left: &Self::Hash, right: Option<&Self::Hash>) -> Self::Hash {
+        match right {
+            Some(right) => merkle::hash_pair(left, right),
+            None => *left,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -30,117 +113,971 @@ pub enum MempoolError {
     TransactionNotFound,
     #[error("Fruit not found")]
     FruitNotFound,
+    #[error("Fruit does not meet the fruit target")]
+    InsufficientFruitWork,
+    #[error("Fruit hangs from height {anchor_height}, outside the freshness window at tip {tip_height}")]
+    StaleFruit { anchor_height: u64, tip_height: u64 },
+    #[error("Fee rate {fee_rate:.3} is below the minimum of {min_fee_rate:.3}")]
+    FeeRateTooLow { fee_rate: f64, min_fee_rate: f64 },
+    #[error("Invalid package: {0}")]
+    InvalidPackage(String),
+    #[error("Transaction was recently rejected: {0}")]
+    RecentlyRejected(String),
+    #[error("Transaction was recently confirmed")]
+    RecentlyConfirmed,
+    #[error("Transaction is already in the mempool")]
+    AlreadyInPool,
+    #[error("Transaction is not final in the next block")]
+    NonFinal,
+    #[error("Spends the same outputs as {0} without paying enough to replace it")]
+    InsufficientReplacementFee(String),
+    #[error("Would replace {count} transactions, more than the limit of {max}")]
+    TooManyReplacements { count: usize, max: usize },
+    #[error("Non-standard transaction: {0}")]
+    Policy(#[from] PolicyError),
+}
+
+impl MempoolError {
+    // Whether the same transaction would fail again until the tip changes. A full pool or a missing
+    // parent may clear up at any moment, so those aren't remembered.
+    fn is_cacheable(&self) -> bool {
+        matches!(self, MempoolError::FeeRateTooLow { .. } | MempoolError::Policy(_))
+    }
+}
+
+struct MempoolEntry {
+    fee: u64,
+    // Serialized size, which fee rates are measured against
+    size: usize,
+    // Memory the transaction takes in the pool, counting its share of the indexes
+    usage: usize,
+    // Wall-clock seconds when the transaction was first received, kept across non-final holding
+    received_at: u64,
+    // Chain tip height when the transaction was accepted
+    height: u64,
+}
+
+impl MempoolEntry {
+    fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.size.max(1) as f64
+    }
+}
+
+// A hash map slot holds the key, the value and a control byte, and about an eighth of the slots are
+// kept empty
+fn map_slot_usage<K, V>() -> usize {
+    (size_of::<K>() + size_of::<V>() + 1) * 8 / 7
+}
+
+fn transaction_heap_usage(tx: &Transaction) -> usize {
+    tx.inputs.capacity() * size_of::<TxInput>()
+        + tx.inputs.iter().map(|input| input.signature.capacity()).sum::<usize>()
+        + tx.outputs.capacity() * size_of::<TxOutput>()
+        + tx.outputs.iter().map(|output| output.script_pubkey.capacity()).sum::<usize>()
+}
+
+// A pooled transaction with its slots in `transactions`, `entries` and `spends`, the queue and the
+// merkle tree
+fn transaction_memory_usage(tx: &Transaction) -> usize {
+    transaction_heap_usage(tx)
+        + map_slot_usage::<TxHash, Transaction>()
+        + map_slot_usage::<TxHash, MempoolEntry>()
+        + tx.inputs.len() * map_slot_usage::<OutPoint, TxHash>()
+        + size_of::<TxHash>()
+        + MERKLE_TREE_BYTES_PER_LEAF
+}
+
+// A pooled fruit with its slots in `fruits` and `fruit_entries`, the queue and the merkle tree
+fn fruit_memory_usage(fruit: &SignedBlock) -> usize {
+    let block = &fruit.block;
+    fruit.signature.capacity()
+        + block.fruits.capacity() * size_of::<FruitHeader>()
+        + block.transactions.capacity() * size_of::<Transaction>()
+        + block.transactions.iter().map(transaction_heap_usage).sum::<usize>()
+        + map_slot_usage::<TxHash, SignedBlock>()
+        + map_slot_usage::<TxHash, FruitEntry>()
+        + size_of::<TxHash>()
+        + MERKLE_TREE_BYTES_PER_LEAF
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeeRateBucket {
+    pub min_fee_rate: f64,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgeBucket {
+    // `None` for the last band, which holds everything older than the previous bound
+    pub max_age_secs: Option<u64>,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MempoolInfo {
+    pub size: usize,
+    // Serialized size of the pooled transactions
+    pub bytes: usize,
+    // Memory the pooled transactions and fruits take, indexes included; this is what the limit applies to
+    pub usage: usize,
+    pub usage_limit_bytes: usize,
+    pub total_fee: u64,
+    // Lowest fee rate in the pool once it is full, zero while there is still room. A full pool turns
+    // transactions away whatever they pay, so this is what the next eviction takes, not a price of entry.
+    pub lowest_fee_rate: f64,
+    pub non_final: usize,
+    pub fruits: usize,
+    pub orphan_fruits: usize,
+    pub fee_rate_buckets: Vec<FeeRateBucket>,
+    pub age_buckets: Vec<AgeBucket>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MempoolEntryInfo {
+    pub size: usize,
+    pub fee: u64,
+    pub fee_rate: f64,
+    pub time: u64,
+    pub age_secs: u64,
+    pub height: u64,
+}
+
+// The pool's transactions in merkle tree order and the root they hash to, taken at one moment so
+// every proof from it verifies against that root even as the pool moves on
+#[derive(Debug, Clone)]
+pub struct MempoolSnapshot {
+    pub txids: Vec<TxHash>,
+    pub merkle_root: [u8; 32],
+}
+
+impl MempoolSnapshot {
+    // A branch for each of `txids`, or `None` for one not in the snapshot. The tree is hashed once
+    // however many are asked for.
+    pub fn proofs(&self, txids: &[TxHash]) -> Vec<Option<MerkleBranch>> {
+        let positions = self.txids.iter().enumerate().map(|(index, txid)| (*txid, index)).collect::<HashMap<_, _>>();
+        let levels = MerkleLevels::new(&self.txids);
+        txids.iter().map(|txid| levels.branch(*positions.get(txid)?)).collect()
+    }
+}
+
+// Outcome of `add_package`. Fees and sizes cover only the newly accepted transactions.
+#[derive(Debug, Clone)]
+pub struct PackageAcceptance {
+    pub accepted: Vec<TxHash>,
+    pub already_in_pool: Vec<TxHash>,
+    pub fee: u64,
+    pub size: usize,
+    pub fee_rate: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MempoolRemoval {
+    pub txid: TxHash,
+    pub reason: RemovalReason,
+    // Wall-clock Unix seconds
+    pub time: u64,
+}
+
+struct NonFinalTransaction {
+    transaction: Transaction,
+    fee: u64,
+    received_at: u64,
+}
+
+struct FruitEntry {
+    size: usize,
+    usage: usize,
+    // Height of the block the fruit hangs from
+    anchor_height: u64,
+    received_at: u64,
+}
+
+// A pooled fruit as written to fruits.dat
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedFruit {
+    pub fruit: SignedBlock,
+    // Wall-clock Unix seconds, so the fruit timeout runs on from where it was
+    pub received_at: u64,
+}
+
+// Reads the fruits saved at the last shutdown; a missing or unreadable file means none
+pub fn read_saved_fruits(path: &Path) -> Vec<SavedFruit> {
+    match std::fs::read(path) {
+        Ok(bytes) => codec::decode(&bytes).unwrap_or_else(|e| {
+            log::warn!("Ignoring corrupt {}: {}", path.display(), e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+struct OrphanFruit {
+    fruit: SignedBlock,
+    // Tip height when the fruit arrived; it expires once the freshness window has passed
+    received_at_height: u64,
 }
 
 pub struct Mempool {
     transaction_merkle_tree: MerkleTree<TransactionHasher>,
     fruit_merkle_tree: MerkleTree<TransactionHasher>,
     transactions: HashMap<[u8; 32], Transaction>,
+    entries: HashMap<[u8; 32], MempoolEntry>,
+    // The pool transaction spending each output, to find double spends
+    spends: HashMap<OutPoint, TxHash>,
     fruits: HashMap<[u8; 32], SignedBlock>,
+    // Held transactions keep the fee and receive time they were submitted with
+    non_final_transactions: HashMap<[u8; 32], NonFinalTransaction>,
+    orphan_fruits: HashMap<[u8; 32], OrphanFruit>,
+    fruit_entries: HashMap<[u8; 32], FruitEntry>,
+    fruit_freshness_window: u64,
+    // The chain's fruit target, which every pooled fruit meets
+    fruit_bits: u32,
+    // Both queues are in receive order
     transaction_queue: VecDeque<[u8; 32]>,
     fruit_queue: VecDeque<[u8; 32]>,
+    // Applies to `memory_usage_bytes`; serialized sizes understate what entries really take
     size_limit_bytes: usize,
     current_size_bytes: usize,
-    transaction_timeout: Duration,
-    fruit_timeout: Duration,
-    last_cleanup: Instant,
+    memory_usage_bytes: usize,
+    // Entries younger than this are never expired to make room, only by age
+    min_age_secs: u64,
+    max_age_secs: u64,
+    fruit_timeout_secs: u64,
+    policy: RelayPolicy,
+    events: broadcast::Sender<MempoolEvent>,
+    // Transactions that failed validation since the tip last changed, so the same transaction
+    // arriving from several peers is only checked once
+    recent_rejects: RollingBloomFilter,
+    reject_reasons: HashMap<[u8; 32], String>,
+    reject_order: VecDeque<[u8; 32]>,
+    // Transactions in recent blocks, which peers may still announce
+    recently_confirmed: RollingBloomFilter,
+    // Oldest first
+    recent_removals: VecDeque<MempoolRemoval>,
+}
+
+// Receive times are wall-clock Unix seconds so they can be reported and compared across restarts
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
 }
 
 impl Mempool {
-    pub fn new(size_limit_mb: usize, transaction_timeout_secs: u64, fruit_timeout_secs: u64) -> Self {
+    pub fn new(size_limit_mb: usize, min_age_secs: u64, max_age_secs: u64, fruit_timeout_secs: u64, policy: RelayPolicy, fruit_freshness_window: u64, fruit_bits: u32) -> Self {
         Mempool {
             transaction_merkle_tree: MerkleTree::<TransactionHasher>::new(),
             fruit_merkle_tree: MerkleTree::<TransactionHasher>::new(),
             transactions: HashMap::new(),
+            entries: HashMap::new(),
+            spends: HashMap::new(),
             fruits: HashMap::new(),
+            non_final_transactions: HashMap::new(),
+            orphan_fruits: HashMap::new(),
+            fruit_entries: HashMap::new(),
+            fruit_freshness_window,
+            fruit_bits,
             transaction_queue: VecDeque::new(),
             fruit_queue: VecDeque::new(),
             size_limit_bytes: size_limit_mb * 1024 * 1024,
             current_size_bytes: 0,
-            transaction_timeout: Duration::from_secs(transaction_timeout_secs),
-            fruit_timeout: Duration::from_secs(fruit_timeout_secs),
-            last_cleanup: Instant::now(),
+            memory_usage_bytes: 0,
+            min_age_secs,
+            max_age_secs,
+            fruit_timeout_secs,
+            policy,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            recent_rejects: RollingBloomFilter::new(RECENT_REJECTS_CAPACITY, RECENT_FILTER_FALSE_POSITIVE_RATE),
+            reject_reasons: HashMap::new(),
+            reject_order: VecDeque::new(),
+            recently_confirmed: RollingBloomFilter::new(RECENTLY_CONFIRMED_CAPACITY, RECENT_FILTER_FALSE_POSITIVE_RATE),
+            recent_removals: VecDeque::new(),
         }
     }
 
-    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), MempoolError> {
+    pub fn subscribe(&self) -> broadcast::Receiver<MempoolEvent> {
+        self.events.subscribe()
+    }
+
+    // `fee` is what the transaction pays over its outputs, worked out by the caller from the UTXO set.
+    // `next_height` and `median_time_past` describe the block the transaction would be mined in;
+    // transactions that aren't final there are held back until `update_tip` makes them final. A
+    // transaction double-spending pool transactions replaces them if it pays more than they and their
+    // descendants do in total, and a higher fee rate than each of them.
+    pub fn add_transaction(&mut self, transaction: Transaction, fee: u64, next_height: u64, median_time_past: u64) -> Result<(), MempoolError> {
+        let txid = transaction.hash();
+        if self.recently_confirmed.contains(&txid) {
+            return Err(MempoolError::RecentlyConfirmed);
+        }
+        if self.recent_rejects.contains(&txid) {
+            let reason = self.reject_reasons.get(&txid).map_or("reason no longer known", String::as_str);
+            return Err(MempoolError::RecentlyRejected(reason.to_string()));
+        }
+        let checked = self.policy.check_transaction(&transaction).map_err(MempoolError::from)
+            .and_then(|_| self.check_fee_rate(fee, bincode::serialized_size(&transaction)? as usize));
+        if let Err(e) = checked {
+            if e.is_cacheable() {
+                self.reject(txid, e.to_string());
+            }
+            return Err(e);
+        }
+        if !transaction.is_final(next_height, median_time_past) {
+            if self.non_final_transactions.len() >= MAX_NON_FINAL_TRANSACTIONS {
+                return Err(MempoolError::PoolFull);
+            }
+            let received_at = unix_time();
+            self.non_final_transactions.insert(transaction.hash(), NonFinalTransaction { transaction, fee, received_at });
+            return Ok(());
+        }
+        self.accept(transaction, fee, unix_time(), next_height.saturating_sub(1))
+    }
+
+    // Whether `add_transaction` would accept `transaction` into the pool now, without adding it or
+    // remembering a rejection. Transactions `add_transaction` would hold until final fail here.
+    pub fn test_accept(&self, transaction: &Transaction, fee: u64, next_height: u64, median_time_past: u64) -> Result<(), MempoolError> {
+        let txid = transaction.hash();
+        if self.recently_confirmed.contains(&txid) {
+            return Err(MempoolError::RecentlyConfirmed);
+        }
+        if self.entries.contains_key(&txid) || self.non_final_transactions.contains_key(&txid) {
+            return Err(MempoolError::AlreadyInPool);
+        }
+        if self.recent_rejects.contains(&txid) {
+            let reason = self.reject_reasons.get(&txid).map_or("reason no longer known", String::as_str);
+            return Err(MempoolError::RecentlyRejected(reason.to_string()));
+        }
+        self.policy.check_transaction(transaction)?;
+        let size = bincode::serialized_size(transaction)? as usize;
+        self.check_fee_rate(fee, size)?;
+        if !transaction.is_final(next_height, median_time_past) {
+            return Err(MempoolError::NonFinal);
+        }
+        let replaced = self.replaced_by(transaction, fee, size)?;
+        if self.memory_usage_bytes.saturating_sub(self.usage_of(&replaced)) + transaction_memory_usage(transaction) > self.size_limit_bytes {
+            return Err(MempoolError::PoolFull);
+        }
+        Ok(())
+    }
+
+    // Accepts `package` as a whole or not at all, judging its fee rate over every transaction not
+    // already in the pool, so a parent paying too little on its own gets in with a child that pays for
+    // both. Each entry pairs a transaction with its fee; parents must come before their children.
+    // Unlike single transactions, non-final packages are rejected rather than held, and packages
+    // double-spending the pool rather than replacing what they conflict with.
+    pub fn add_package(&mut self, package: Vec<(Transaction, u64)>, next_height: u64, median_time_past: u64) -> Result<PackageAcceptance, MempoolError> {
+        if package.is_empty() || package.len() > MAX_PACKAGE_COUNT {
+            return Err(MempoolError::InvalidPackage(format!("must hold between 1 and {} transactions", MAX_PACKAGE_COUNT)));
+        }
+        let hashes = package.iter().map(|(tx, _)| tx.hash()).collect::<Vec<_>>();
+        let mut seen = HashSet::new();
+        let mut package_spends = HashSet::new();
+        for (index, (tx, _)) in package.iter().enumerate() {
+            if !seen.insert(hashes[index]) {
+                return Err(MempoolError::InvalidPackage(format!("transaction {} appears twice", index)));
+            }
+            if tx.inputs.iter().any(|input| hashes[index + 1..].contains(&input.previous_output.txid)) {
+                return Err(MempoolError::InvalidPackage(format!("transaction {} comes before its parent", index)));
+            }
+            if !tx.inputs.iter().all(|input| package_spends.insert(input.previous_output)) {
+                return Err(MempoolError::InvalidPackage(format!("transaction {} spends an output spent earlier in the package", index)));
+            }
+            if !tx.is_final(next_height, median_time_past) {
+                return Err(MempoolError::InvalidPackage(format!("transaction {} is not final", index)));
+            }
+            self.policy.check_transaction(tx)?;
+        }
+        // One child and parents it spends from directly, so the package's fee rate is the child's
+        // reason for carrying them
+        let (child, _) = &package[package.len() - 1];
+        if let Some(index) = hashes[..hashes.len() - 1].iter().position(|hash| !child.inputs.iter().any(|input| input.previous_output.txid == *hash)) {
+            return Err(MempoolError::InvalidPackage(format!("transaction {} is not a parent of the last transaction", index)));
+        }
+
+        // Rejections aren't consulted: a transaction turned away for its fee rate alone may pay enough with its children
+        if let Some(index) = hashes.iter().position(|hash| self.recently_confirmed.contains(hash)) {
+            return Err(MempoolError::InvalidPackage(format!("transaction {} was recently confirmed", index)));
+        }
+
+        let mut acceptance = PackageAcceptance { accepted: Vec::new(), already_in_pool: Vec::new(), fee: 0, size: 0, fee_rate: 0.0 };
+        let received_at = unix_time();
+        let mut new = Vec::with_capacity(package.len());
+        for (index, ((tx, fee), hash)) in package.into_iter().zip(hashes).enumerate() {
+            if self.entries.contains_key(&hash) {
+                acceptance.already_in_pool.push(hash);
+                continue;
+            }
+            if tx.inputs.iter().any(|input| self.spends.contains_key(&input.previous_output)) {
+                return Err(MempoolError::InvalidPackage(format!("transaction {} conflicts with the mempool", index)));
+            }
+            let entry = MempoolEntry {
+                fee,
+                size: bincode::serialized_size(&tx)? as usize,
+                usage: transaction_memory_usage(&tx),
+                received_at,
+                height: next_height.saturating_sub(1),
+            };
+            acceptance.fee += fee;
+            acceptance.size += entry.size;
+            new.push((tx, entry, hash));
+        }
+        if new.is_empty() {
+            return Ok(acceptance);
+        }
+        acceptance.fee_rate = acceptance.fee as f64 / acceptance.size as f64;
+        self.check_fee_rate(acceptance.fee, acceptance.size)?;
+        let usage = new.iter().map(|(_, entry, _)| entry.usage).sum::<usize>();
+        if self.memory_usage_bytes + usage > self.size_limit_bytes {
+            return Err(MempoolError::PoolFull);
+        }
+
+        // Everything that could refuse the package has been checked, so it goes in whole
+        for (tx, entry, hash) in new {
+            self.insert_entry(tx, entry);
+            acceptance.accepted.push(hash);
+        }
+        Ok(acceptance)
+    }
+
+    fn check_fee_rate(&self, fee: u64, size: usize) -> Result<(), MempoolError> {
+        let fee_rate = fee as f64 / size.max(1) as f64;
+        if fee_rate < self.policy.min_relay_fee_rate {
+            return Err(MempoolError::FeeRateTooLow { fee_rate, min_fee_rate: self.policy.min_relay_fee_rate });
+        }
+        Ok(())
+    }
+
+    // Remembers that `txid` failed validation so it isn't checked again until the tip changes.
+    // Callers validating against the chain record their failures here too.
+    pub fn reject(&mut self, txid: TxHash, reason: String) {
+        self.recent_rejects.insert(&txid);
+        if self.reject_reasons.insert(txid, reason).is_none() {
+            self.reject_order.push_back(txid);
+            if self.reject_order.len() > MAX_REJECT_REASONS {
+                if let Some(oldest) = self.reject_order.pop_front() {
+                    self.reject_reasons.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    // `None` if `txid` wasn't rejected since the tip last changed, otherwise the reason if it's still known
+    pub fn recent_rejection(&self, txid: &TxHash) -> Option<Option<&str>> {
+        self.recent_rejects.contains(txid).then(|| self.reject_reasons.get(txid).map(String::as_str))
+    }
+
+    pub fn was_recently_confirmed(&self, txid: &TxHash) -> bool {
+        self.recently_confirmed.contains(txid)
+    }
+
+    // Whether a transaction announced by a peer needs neither requesting nor validating
+    pub fn already_have(&self, txid: &TxHash) -> bool {
+        self.entries.contains_key(txid)
+            || self.non_final_transactions.contains_key(txid)
+            || self.recent_rejects.contains(txid)
+            || self.recently_confirmed.contains(txid)
+    }
+
+    // Drops the transactions of a newly connected block and remembers them as confirmed, along with
+    // whatever spent the same outputs, which can never confirm now
+    pub fn transactions_confirmed(&mut self, transactions: &[Transaction]) {
+        for tx in transactions {
+            let txid = tx.hash();
+            self.recently_confirmed.insert(&txid);
+            self.non_final_transactions.remove(&txid);
+        }
+        self.remove_transactions(transactions);
+
+        // With the block's own transactions gone, any spend left of the outputs they spent is a double spend
+        let spent = transactions.iter()
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.previous_output))
+            .collect::<HashSet<_>>();
+        let conflicts = spent.iter().filter_map(|outpoint| self.spends.get(outpoint).copied()).collect::<Vec<_>>();
+        if !conflicts.is_empty() {
+            self.remove_with_descendants(&conflicts, RemovalReason::Conflict);
+            self.rebuild_merkle_trees();
+        }
+        self.non_final_transactions.retain(|_, held| held.transaction.inputs.iter().all(|input| !spent.contains(&input.previous_output)));
+        self.tip_changed();
+    }
+
+    // Sends pool transactions that are no longer final at the lowered tip back to being held, with
+    // their descendants. `next_height` and `median_time_past` describe the block after the new tip.
+    // Returns how many transactions left the pool. Confirmations are forgotten too, as the
+    // disconnected block's transactions are unconfirmed again and may be resubmitted.
+    pub fn block_disconnected(&mut self, next_height: u64, median_time_past: u64) -> usize {
+        self.recently_confirmed.reset();
+        let non_final = self.transaction_queue.iter()
+            .filter(|hash| self.transactions.get(*hash).is_some_and(|tx| !tx.is_final(next_height, median_time_past)))
+            .copied()
+            .collect::<Vec<_>>();
+        let returned = self.descendants(&non_final);
+        for hash in &returned {
+            let Some(transaction) = self.transactions.get(hash).cloned() else { continue };
+            let Some((fee, received_at)) = self.entries.get(hash).map(|entry| (entry.fee, entry.received_at)) else { continue };
+            self.remove_transaction_by_hash(hash, RemovalReason::ReorgReturned);
+            if self.non_final_transactions.len() < MAX_NON_FINAL_TRANSACTIONS {
+                self.non_final_transactions.insert(*hash, NonFinalTransaction { transaction, fee, received_at });
+            }
+        }
+        if !returned.is_empty() {
+            self.rebuild_merkle_trees();
+        }
+        self.tip_changed();
+        returned.len()
+    }
+
+    // Most recent last; only the latest `MAX_RECENT_REMOVALS` are kept
+    pub fn recent_removals(&self) -> impl Iterator<Item = &MempoolRemoval> {
+        self.recent_removals.iter()
+    }
+
+    // Forgets rejections, which may not hold on the new tip
+    pub fn tip_changed(&mut self) {
+        self.recent_rejects.reset();
+        self.reject_reasons.clear();
+        self.reject_order.clear();
+    }
+
+    pub fn get_transaction(&self, transaction_hash: &TxHash) -> Option<&Transaction> {
+        self.transactions.get(transaction_hash)
+    }
+
+    // Moves held transactions that became final at the new tip into the pool
+    pub fn update_tip(&mut self, next_height: u64, median_time_past: u64) -> usize {
+        let now_final = self.non_final_transactions.iter()
+            .filter(|(_, held)| held.transaction.is_final(next_height, median_time_past))
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+
+        let mut promoted = 0;
+        for hash in now_final {
+            if let Some(held) = self.non_final_transactions.remove(&hash) {
+                if self.accept(held.transaction, held.fee, held.received_at, next_height.saturating_sub(1)).is_ok() {
+                    promoted += 1;
+                }
+            }
+        }
+        promoted
+    }
+
+    pub fn non_final_count(&self) -> usize {
+        self.non_final_transactions.len()
+    }
+
+    // Pool transactions `transaction` would replace, with their descendants: none if it conflicts with
+    // nothing, an error if it doesn't pay enough to replace what it conflicts with. On top of the fees it
+    // takes out of the pool, a replacement pays for its own relay at the minimum rate, so each step of a
+    // replacement chain costs something.
+    fn replaced_by(&self, transaction: &Transaction, fee: u64, size: usize) -> Result<Vec<TxHash>, MempoolError> {
+        let conflicts = transaction.inputs.iter()
+            .filter_map(|input| self.spends.get(&input.previous_output).copied())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        if conflicts.is_empty() {
+            return Ok(conflicts);
+        }
+        let replaced = self.descendants(&conflicts);
+        if replaced.len() > MAX_REPLACEMENT_EVICTIONS {
+            return Err(MempoolError::TooManyReplacements { count: replaced.len(), max: MAX_REPLACEMENT_EVICTIONS });
+        }
+        let fee_rate = fee as f64 / size.max(1) as f64;
+        let replaced_fee = replaced.iter().filter_map(|hash| self.entries.get(hash)).map(|entry| entry.fee).sum::<u64>();
+        let outbid = conflicts.iter().find(|hash| self.entries.get(*hash).is_some_and(|entry| entry.fee_rate() >= fee_rate));
+        // Spending an output of a transaction it replaces would leave it without a parent
+        let spends_replaced = transaction.inputs.iter().any(|input| replaced.contains(&input.previous_output.txid));
+        let relay_fee = (self.policy.min_relay_fee_rate * size as f64).ceil() as u64;
+        if fee < replaced_fee.saturating_add(relay_fee.max(1)) || outbid.is_some() || spends_replaced {
+            return Err(MempoolError::InsufficientReplacementFee(hex::encode(outbid.unwrap_or(&conflicts[0]))));
+        }
+        Ok(replaced)
+    }
+
+    // `roots` and every pool transaction descending from them, parents before children
+    fn descendants(&self, roots: &[TxHash]) -> Vec<TxHash> {
+        let mut seen = roots.iter().copied().collect::<HashSet<_>>();
+        let mut found = roots.to_vec();
+        let mut next = 0;
+        while let Some(hash) = found.get(next).copied() {
+            next += 1;
+            let Some(tx) = self.transactions.get(&hash) else { continue };
+            for index in 0..tx.outputs.len() as u32 {
+                if let Some(&child) = self.spends.get(&OutPoint { txid: hash, index }) {
+                    if seen.insert(child) {
+                        found.push(child);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    fn usage_of(&self, hashes: &[TxHash]) -> usize {
+        hashes.iter().filter_map(|hash| self.entries.get(hash)).map(|entry| entry.usage).sum()
+    }
+
+    // Inserts a final transaction, first taking out whatever it replaces
+    fn accept(&mut self, transaction: Transaction, fee: u64, received_at: u64, height: u64) -> Result<(), MempoolError> {
+        let size = bincode::serialized_size(&transaction)? as usize;
+        let replaced = self.replaced_by(&transaction, fee, size)?;
+        if self.memory_usage_bytes.saturating_sub(self.usage_of(&replaced)) + transaction_memory_usage(&transaction) > self.size_limit_bytes {
+            return Err(MempoolError::PoolFull);
+        }
+        if !replaced.is_empty() {
+            self.remove_with_descendants(&replaced, RemovalReason::Replaced);
+            self.rebuild_merkle_trees();
+        }
+        self.insert_transaction(transaction, fee, received_at, height)
+    }
+
+    fn insert_transaction(&mut self, transaction: Transaction, fee: u64, received_at: u64, height: u64) -> Result<(), MempoolError> {
         let transaction_size = bincode::serialize(&transaction)?.len();
+        let usage = transaction_memory_usage(&transaction);
 
-        if self.current_size_bytes + transaction_size > self.size_limit_bytes {
+        if self.memory_usage_bytes + usage > self.size_limit_bytes {
             return Err(MempoolError::PoolFull);
         }
+        self.insert_entry(transaction, MempoolEntry { fee, size: transaction_size, usage, received_at, height });
+        Ok(())
+    }
 
+    // Adds a transaction already known to fit in the pool
+    fn insert_entry(&mut self, transaction: Transaction, entry: MempoolEntry) {
         let transaction_hash = transaction.hash();
 
-        self.transaction_merkle_tree.insert(transaction_hash);
+        self.transaction_merkle_tree.insert(merkle::hash_leaf(&transaction_hash));
+        for input in &transaction.inputs {
+            self.spends.insert(input.previous_output, transaction_hash);
+        }
         self.transactions.insert(transaction_hash, transaction);
+        self.current_size_bytes += entry.size;
+        self.memory_usage_bytes += entry.usage;
+        self.entries.insert(transaction_hash, entry);
         self.transaction_queue.push_back(transaction_hash);
-        self.current_size_bytes += transaction_size;
         self.transaction_merkle_tree.commit();
 
-        Ok(())
+        // Only pay for the clone when someone is listening
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(MempoolEvent::TransactionAdded(Arc::new(self.transactions[&transaction_hash].clone())));
+        }
+    }
+
+    // `anchor_height` is the active-chain height of the block the fruit hangs from, or `None` if that
+    // block isn't known yet, in which case the fruit is held until `block_connected` sees it
+    pub fn add_fruit(&mut self, fruit: SignedBlock, anchor_height: Option<u64>, tip_height: u64) -> Result<(), MempoolError> {
+        self.check_fruit(&fruit)?;
+        let anchor_height = match anchor_height {
+            Some(height) => height,
+            None => {
+                if self.orphan_fruits.len() >= MAX_ORPHAN_FRUITS {
+                    return Err(MempoolError::PoolFull);
+                }
+                let fruit_hash = fruit.block.fruit_id();
+                self.orphan_fruits.insert(fruit_hash, OrphanFruit { fruit, received_at_height: tip_height });
+                return Ok(());
+            }
+        };
+        self.insert_fruit(fruit, anchor_height, tip_height, unix_time())
     }
 
-    pub fn add_fruit(&mut self, fruit: SignedBlock) -> Result<(), MempoolError> {
-        if fruit.block.block_type != BlockType::Fruit {
+    // What a fruit must be wherever it came from, the network or fruits.dat: a fruit block whose
+    // fruit header meets the fruit target
+    fn check_fruit(&self, fruit: &SignedBlock) -> Result<(), MempoolError> {
+        if fruit.block.block_type != BlockType::Fruit || fruit.block.fruit_header.is_none() {
             return Err(MempoolError::InvalidHash("Not a fruit block".to_string()));
         }
+        if !self.meets_fruit_target(fruit) {
+            return Err(MempoolError::InsufficientFruitWork);
+        }
+        Ok(())
+    }
+
+    fn meets_fruit_target(&self, fruit: &SignedBlock) -> bool {
+        fruit.block.fruit_header.as_ref().is_some_and(|header| pow::check_proof_of_work(&header.hash(), self.fruit_bits))
+    }
+
+    fn insert_fruit(&mut self, fruit: SignedBlock, anchor_height: u64, tip_height: u64, received_at: u64) -> Result<(), MempoolError> {
+        if tip_height.saturating_sub(anchor_height) > self.fruit_freshness_window {
+            return Err(MempoolError::StaleFruit { anchor_height, tip_height });
+        }
 
         let fruit_size = bincode::serialize(&fruit)?.len();
+        let usage = fruit_memory_usage(&fruit);
 
-        if self.current_size_bytes + fruit_size > self.size_limit_bytes {
+        if self.memory_usage_bytes + usage > self.size_limit_bytes {
             return Err(MempoolError::PoolFull);
         }
 
-        let fruit_hash = fruit.block.hash();
+        let fruit_hash = fruit.block.fruit_id();
 
-        self.fruit_merkle_tree.insert(fruit_hash);
+        self.fruit_merkle_tree.insert(merkle::hash_leaf(&fruit_hash));
+        self.fruit_entries.insert(fruit_hash, FruitEntry { size: fruit_size, usage, anchor_height, received_at });
         self.fruits.insert(fruit_hash, fruit);
         self.fruit_queue.push_back(fruit_hash);
         self.current_size_bytes += fruit_size;
+        self.memory_usage_bytes += usage;
         self.fruit_merkle_tree.commit();
 
         Ok(())
     }
 
+    // Promotes orphan fruits hanging from the new block, then drops orphans and pooled fruits
+    // that have fallen out of the freshness window. Returns how many orphans were promoted.
+    pub fn block_connected(&mut self, block_hash: &[u8; 32], height: u64) -> usize {
+        let window = self.fruit_freshness_window;
+
+        let ready = self.orphan_fruits.iter()
+            .filter(|(_, orphan)| orphan.fruit.block.fruit_header.as_ref().map(|h| &h.hang_from) == Some(block_hash))
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+        let mut promoted = 0;
+        for hash in ready {
+            if let Some(orphan) = self.orphan_fruits.remove(&hash) {
+                if self.add_fruit(orphan.fruit, Some(height), height).is_ok() {
+                    promoted += 1;
+                }
+            }
+        }
+
+        self.orphan_fruits.retain(|_, orphan| height.saturating_sub(orphan.received_at_height) <= window);
+
+        let stale = self.fruit_entries.iter()
+            .filter(|(_, entry)| height.saturating_sub(entry.anchor_height) > window)
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+        if !stale.is_empty() {
+            for hash in &stale {
+                self.remove_fruit_by_hash(hash);
+            }
+            self.rebuild_merkle_trees();
+        }
+
+        promoted
+    }
+
+    // Written to a temporary file first like peers.dat. Orphans aren't saved: they expire a
+    // freshness window after arriving, which a restart would reset.
+    pub fn save_fruits(&self, path: &Path) -> std::io::Result<()> {
+        let saved = self.fruit_queue.iter()
+            .filter_map(|hash| Some(SavedFruit { fruit: self.fruits.get(hash)?.clone(), received_at: self.fruit_entries.get(hash)?.received_at }))
+            .collect::<Vec<_>>();
+        let bytes = codec::encode(&saved).map_err(std::io::Error::other)?;
+        let tmp = path.with_extension("dat.tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)
+    }
+
+    // Puts fruits read back from fruits.dat into the pool, each paired with the active-chain height
+    // of the block it hangs from. The file is checked like a peer would be, so fruits that aren't
+    // valid are dropped, as are those whose block left the active chain while the node was down,
+    // that fell out of the freshness window, or that are past the fruit timeout at `now`. Returns
+    // how many were restored.
+    pub fn restore_fruits(&mut self, saved: Vec<(SavedFruit, Option<u64>)>, tip_height: u64, now: u64) -> usize {
+        let mut restored = 0;
+        for (SavedFruit { fruit, received_at }, anchor_height) in saved {
+            let Some(anchor_height) = anchor_height else { continue };
+            if self.check_fruit(&fruit).is_err() || now.saturating_sub(received_at) >= self.fruit_timeout_secs {
+                continue;
+            }
+            if !self.fruits.contains_key(&fruit.block.fruit_id()) && self.insert_fruit(fruit, anchor_height, tip_height, received_at).is_ok() {
+                restored += 1;
+            }
+        }
+        restored
+    }
+
+    pub fn orphan_fruit_count(&self) -> usize {
+        self.orphan_fruits.len()
+    }
+
+    fn remove_fruit_by_hash(&mut self, hash: &[u8; 32]) {
+        self.fruits.remove(hash);
+        self.fruit_queue.retain(|x| x != hash);
+        if let Some(entry) = self.fruit_entries.remove(hash) {
+            self.current_size_bytes = self.current_size_bytes.saturating_sub(entry.size);
+            self.memory_usage_bytes = self.memory_usage_bytes.saturating_sub(entry.usage);
+        }
+    }
+
+    fn remove_transaction_by_hash(&mut self, hash: &[u8; 32], reason: RemovalReason) -> bool {
+        let Some(transaction) = self.transactions.remove(hash) else { return false };
+        for input in &transaction.inputs {
+            if self.spends.get(&input.previous_output) == Some(hash) {
+                self.spends.remove(&input.previous_output);
+            }
+        }
+        self.transaction_queue.retain(|x| x != hash);
+        if let Some(entry) = self.entries.remove(hash) {
+            self.current_size_bytes = self.current_size_bytes.saturating_sub(entry.size);
+            self.memory_usage_bytes = self.memory_usage_bytes.saturating_sub(entry.usage);
+        }
+
+        self.recent_removals.push_back(MempoolRemoval { txid: *hash, reason, time: unix_time() });
+        if self.recent_removals.len() > MAX_RECENT_REMOVALS {
+            self.recent_removals.pop_front();
+        }
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(MempoolEvent::TransactionRemoved { txid: *hash, reason });
+        }
+        true
+    }
+
+    // Children would be left spending outputs that no longer exist, so they go too, for the same
+    // reason. Returns how many transactions were removed; callers rebuild the merkle trees.
+    fn remove_with_descendants(&mut self, roots: &[TxHash], reason: RemovalReason) -> usize {
+        self.descendants(roots).iter()
+            .filter(|hash| self.remove_transaction_by_hash(hash, reason))
+            .count()
+    }
+
     pub fn get_transactions(&self) -> Vec<Transaction> {
         self.transactions.values().cloned().collect()
     }
 
+    pub fn get_fruit(&self, fruit_id: &[u8; 32]) -> Option<&SignedBlock> {
+        self.fruits.get(fruit_id)
+    }
+
     pub fn get_fruits(&self) -> Vec<SignedBlock> {
         self.fruits.values().cloned().collect()
     }
 
-    pub fn cleanup_expired(&mut self) {
-        let now = Instant::now();
-        if now.duration_since(self.last_cleanup) < self.transaction_timeout.min(self.fruit_timeout) {
-            return;
+    // Drops transactions older than `max_age_secs` and fruits older than `fruit_timeout_secs`, then, if
+    // the pool is still over its size limit, the lowest fee rate transactions that are at least
+    // `min_age_secs` old, each with its descendants. `now` is wall-clock Unix seconds; entries stamped
+    // in the future (clock steps) count as new. Returns how many entries were removed.
+    pub fn cleanup_expired(&mut self, now: u64) -> usize {
+        let age = |received_at: u64| now.saturating_sub(received_at);
+
+        let expired = self.transaction_queue.iter()
+            .filter(|hash| self.entries.get(*hash).is_some_and(|entry| age(entry.received_at) >= self.max_age_secs))
+            .copied()
+            .collect::<Vec<_>>();
+        let mut removed = self.remove_with_descendants(&expired, RemovalReason::Expired);
+
+        let held = self.non_final_transactions.len();
+        self.non_final_transactions.retain(|_, held| age(held.received_at) < self.max_age_secs);
+        removed += held - self.non_final_transactions.len();
+
+        let expired_fruits = self.fruit_queue.iter()
+            .filter(|hash| self.fruit_entries.get(*hash).is_some_and(|entry| age(entry.received_at) >= self.fruit_timeout_secs))
+            .copied()
+            .collect::<Vec<_>>();
+        removed += expired_fruits.len();
+        for hash in &expired_fruits {
+            self.remove_fruit_by_hash(hash);
         }
 
-        self.transaction_queue.retain(|hash| {
-            if let Some(tx) = self.transactions.get(hash) {
-                now.duration_since(tx.timestamp()) < self.transaction_timeout
-            } else {
-                false
-            }
-        });
+        // A lowered size limit is enforced here rather than when it is set
+        while self.memory_usage_bytes > self.size_limit_bytes {
+            let cheapest = self.entries.iter()
+                .filter(|(_, entry)| age(entry.received_at) >= self.min_age_secs)
+                .min_by(|(_, a), (_, b)| a.fee_rate().total_cmp(&b.fee_rate()).then(a.received_at.cmp(&b.received_at)))
+                .map(|(hash, _)| *hash);
+            let Some(hash) = cheapest else { break };
+            removed += self.remove_with_descendants(&[hash], RemovalReason::EvictedForFee);
+        }
 
-        self.fruit_queue.retain(|hash| {
-            if let Some(fruit) = self.fruits.get(hash) {
-                now.duration_since(Instant::now() - Duration::from_secs(fruit.block.header.timestamp)) < self.fruit_timeout
-            } else {
-                false
-            }
-        });
+        if removed > 0 {
+            self.rebuild_merkle_trees();
+        }
+        removed
+    }
 
-        self.rebuild_merkle_trees();
-        self.last_cleanup = now;
+    // Applied on config reload; existing entries are kept and the new limit gates further additions
+    pub fn set_size_limit_mb(&mut self, size_limit_mb: usize) {
+        self.size_limit_bytes = size_limit_mb * 1024 * 1024;
+    }
+
+    // Applied on config reload; only gates further additions
+    pub fn set_policy(&mut self, policy: RelayPolicy) {
+        self.policy = policy;
+    }
+
+    // Applied on config reload and used by the next `cleanup_expired`
+    pub fn set_age_limits(&mut self, min_age_secs: u64, max_age_secs: u64) {
+        self.min_age_secs = min_age_secs;
+        self.max_age_secs = max_age_secs;
     }
 
     pub fn current_size_mb(&self) -> f64 {
         self.current_size_bytes as f64 / (1024.0 * 1024.0)
     }
 
+    // What the pooled transactions and fruits take in memory, which the size limit is enforced on.
+    // Held non-final transactions and orphan fruits are capped by count instead.
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage_bytes
+    }
+
+    // Congestion snapshot: pooled transactions grouped by fee rate and by time spent in the pool
+    pub fn info(&self) -> MempoolInfo {
+        let now = unix_time();
+        let mut fee_rate_buckets = FEE_RATE_BANDS.iter()
+            .map(|&min_fee_rate| FeeRateBucket { min_fee_rate, count: 0, bytes: 0 })
+            .collect::<Vec<_>>();
+        let mut age_buckets = AGE_BANDS_SECS.iter()
+            .map(|&max| Some(max))
+            .chain(std::iter::once(None))
+            .map(|max_age_secs| AgeBucket { max_age_secs, count: 0 })
+            .collect::<Vec<_>>();
+
+        let mut bytes = 0;
+        let mut total_fee = 0;
+        for entry in self.entries.values() {
+            bytes += entry.size;
+            total_fee += entry.fee;
+
+            let fee_rate = entry.fee_rate();
+            let band = FEE_RATE_BANDS.iter().rposition(|&min| fee_rate >= min).unwrap_or(0);
+            fee_rate_buckets[band].count += 1;
+            fee_rate_buckets[band].bytes += entry.size;
+
+            let age = now.saturating_sub(entry.received_at);
+            let band = AGE_BANDS_SECS.iter().position(|&max| age < max).unwrap_or(AGE_BANDS_SECS.len());
+            age_buckets[band].count += 1;
+        }
+
+        let lowest_fee_rate = if self.memory_usage_bytes >= self.size_limit_bytes {
+            self.entries.values().map(MempoolEntry::fee_rate).fold(f64::INFINITY, f64::min)
+        } else {
+            0.0
+        };
+
+        MempoolInfo {
+            size: self.entries.len(),
+            bytes,
+            usage: self.memory_usage_bytes,
+            usage_limit_bytes: self.size_limit_bytes,
+            total_fee,
+            lowest_fee_rate: if lowest_fee_rate.is_finite() { lowest_fee_rate } else { 0.0 },
+            non_final: self.non_final_transactions.len(),
+            fruits: self.fruits.len(),
+            orphan_fruits: self.orphan_fruits.len(),
+            fee_rate_buckets,
+            age_buckets,
+        }
+    }
+
+    pub fn entry_info(&self, transaction_hash: &[u8; 32]) -> Option<MempoolEntryInfo> {
+        let entry = self.entries.get(transaction_hash)?;
+        Some(MempoolEntryInfo {
+            size: entry.size,
+            fee: entry.fee,
+            fee_rate: entry.fee_rate(),
+            time: entry.received_at,
+            age_secs: unix_time().saturating_sub(entry.received_at),
+            height: entry.height,
+        })
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions.values()
+    }
+
+    pub fn transaction_hashes(&self) -> Vec<[u8; 32]> {
+        self.transaction_queue.iter().copied().collect()
+    }
+
     pub fn get_transaction_merkle_root(&self) -> [u8; 32] {
         self.transaction_merkle_tree.root().unwrap_or([0; 32])
     }
@@ -149,39 +1086,37 @@ impl Mempool {
         self.fruit_merkle_tree.root().unwrap_or([0; 32])
     }
 
-    pub fn get_transaction_proof(&self, transaction_hash: &[u8; 32]) -> Option<Vec<[u8; 32]>> {
-        let leaves = self.transaction_merkle_tree.leaves()?;
+    pub fn get_transaction_proof(&self, transaction_hash: &[u8; 32]) -> Option<MerkleBranch> {
+        let leaves = self.transaction_hashes();
         let leaf_index = leaves.iter().position(|&x| x == *transaction_hash)?;
-        Some(self.transaction_merkle_tree.proof(&[leaf_index]).proof_hashes().to_vec())
+        MerkleBranch::new(&leaves, leaf_index)
     }
 
-    pub fn get_fruit_proof(&self, fruit_hash: &[u8; 32]) -> Option<Vec<[u8; 32]>> {
-        let leaves = self.fruit_merkle_tree.leaves()?;
+    pub fn get_fruit_proof(&self, fruit_hash: &[u8; 32]) -> Option<MerkleBranch> {
+        let leaves = self.fruit_queue.iter().copied().collect::<Vec<_>>();
         let leaf_index = leaves.iter().position(|&x| x == *fruit_hash)?;
-        Some(self.fruit_merkle_tree.proof(&[leaf_index]).proof_hashes().to_vec())
+        MerkleBranch::new(&leaves, leaf_index)
+    }
+
+    pub fn snapshot(&self) -> MempoolSnapshot {
+        MempoolSnapshot { txids: self.transaction_hashes(), merkle_root: self.get_transaction_merkle_root() }
     }
 
+    // Takes mined transactions out of the pool
     pub fn remove_transactions(&mut self, transactions: &[Transaction]) {
         for tx in transactions {
-            let hash = tx.hash();
-            self.transactions.remove(&hash);
-            self.transaction_queue.retain(|&x| x != hash);
-            if let Some(size) = bincode::serialize(tx).ok().map(|v| v.len()) {
-                self.current_size_bytes = self.current_size_bytes.saturating_sub(size);
-            }
+            self.remove_transaction_by_hash(&tx.hash(), RemovalReason::Confirmed);
         }
         self.rebuild_merkle_trees();
     }
 
     pub fn remove_fruits(&mut self, fruit_headers: &[FruitHeader]) {
         for header in fruit_headers {
-            if let Some(fruit) = self.fruits.values().find(|f| f.block.fruit_header.as_ref() == Some(header)) {
-                let hash = fruit.block.hash();
-                self.fruits.remove(&hash);
-                self.fruit_queue.retain(|&x| x != hash);
-                if let Some(size) = bincode::serialize(fruit).ok().map(|v| v.len()) {
-                    self.current_size_bytes = self.current_size_bytes.saturating_sub(size);
-                }
+            let hash = self.fruits.iter()
+                .find(|(_, f)| f.block.fruit_header.as_ref() == Some(header))
+                .map(|(hash, _)| *hash);
+            if let Some(hash) = hash {
+                self.remove_fruit_by_hash(&hash);
             }
         }
         self.rebuild_merkle_trees();
@@ -192,10 +1127,10 @@ impl Mempool {
         self.fruit_merkle_tree = MerkleTree::<TransactionHasher>::new();
 
         for hash in &self.transaction_queue {
-            self.transaction_merkle_tree.insert(*hash);
+            self.transaction_merkle_tree.insert(merkle::hash_leaf(hash));
         }
         for hash in &self.fruit_queue {
-            self.fruit_merkle_tree.insert(*hash);
+            self.fruit_merkle_tree.insert(merkle::hash_leaf(hash));
         }
 
         self.transaction_merkle_tree.commit();
@@ -205,9 +1140,335 @@ impl Mempool {
     pub fn calculate_merkle_root(items: &[impl AsRef<[u8]>]) -> [u8; 32] {
         let mut tree = MerkleTree::<TransactionHasher>::new();
         for item in items {
-            tree.insert(TransactionHasher::hash(item.as_ref()));
+            tree.insert(merkle::hash_leaf(&TransactionHasher::hash(item.as_ref())));
         }
         tree.commit();
         tree.root().unwrap_or([0; 32])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Block, BlockHeader};
+    use crate::difficulty::MIN_DIFFICULTY_BITS;
+    use crate::transaction::{OutPoint, TxInput, TxOutput};
+    use tempfile::TempDir;
+
+    fn policy(min_relay_fee_rate: f64) -> RelayPolicy {
+        RelayPolicy { min_relay_fee_rate, dust_limit: 0, accept_non_standard: false }
+    }
+
+    fn transaction(value: u64) -> Transaction {
+        Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value, script_pubkey: vec![1; 32] }], lock_time: 0 }
+    }
+
+    #[test]
+    fn test_expiry_uses_receive_time() {
+        let mut mempool = Mempool::new(1, 600, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        mempool.add_transaction(transaction(1), 10, 5, 0).unwrap();
+        mempool.add_transaction(transaction(2), 20, 5, 0).unwrap();
+        let received_at = mempool.entries.values().map(|entry| entry.received_at).max().unwrap();
+        let info = mempool.entry_info(&transaction(1).hash()).unwrap();
+        assert_eq!((info.fee, info.height), (10, 4));
+
+        // A clock that stepped backwards expires nothing
+        assert_eq!(mempool.cleanup_expired(0), 0);
+        assert_eq!(mempool.cleanup_expired(received_at + 1), 0);
+
+        // Over the size limit, only entries past the minimum age make room
+        mempool.set_size_limit_mb(0);
+        assert_eq!(mempool.cleanup_expired(received_at + 1), 0);
+        assert_eq!(mempool.cleanup_expired(received_at + 600), 2);
+        assert_eq!(mempool.current_size_mb(), 0.0);
+
+        mempool.set_size_limit_mb(1);
+        mempool.add_transaction(transaction(3), 30, 5, 0).unwrap();
+        let received_at = mempool.entry_info(&transaction(3).hash()).unwrap().time;
+        assert_eq!(mempool.cleanup_expired(received_at + 3599), 0);
+        assert_eq!(mempool.cleanup_expired(received_at + 3600), 1);
+        assert!(mempool.transaction_hashes().is_empty());
+    }
+
+    #[test]
+    fn test_package_fee_rate_covers_low_fee_parent() {
+        let mut mempool = Mempool::new(1, 600, 3600, 60, policy(1.0), 16, MIN_DIFFICULTY_BITS);
+        let parent = transaction(1);
+        let input = TxInput { previous_output: OutPoint { txid: parent.hash(), index: 0 }, public_key: [0; 32], signature: Vec::new(), sequence: 0 };
+        let child = Transaction { inputs: vec![input], ..transaction(2) };
+
+        assert!(matches!(mempool.add_transaction(parent.clone(), 0, 5, 0), Err(MempoolError::FeeRateTooLow { .. })));
+        assert!(matches!(mempool.add_package(vec![(child.clone(), 1000), (parent.clone(), 0)], 5, 0), Err(MempoolError::InvalidPackage(_))));
+        assert!(matches!(mempool.add_package(vec![(parent.clone(), 0), (child.clone(), 10)], 5, 0), Err(MempoolError::FeeRateTooLow { .. })));
+        assert!(mempool.transaction_hashes().is_empty());
+
+        let acceptance = mempool.add_package(vec![(parent.clone(), 0), (child.clone(), 1000)], 5, 0).unwrap();
+        assert_eq!(acceptance.accepted, vec![parent.hash(), child.hash()]);
+        assert!(acceptance.fee_rate >= 1.0);
+
+        // Resubmitting leaves the pool as it was
+        let acceptance = mempool.add_package(vec![(parent.clone(), 0), (child, 1000)], 5, 0).unwrap();
+        assert!(acceptance.accepted.is_empty());
+        assert_eq!(acceptance.already_in_pool.len(), 2);
+    }
+
+    #[test]
+    fn test_packages_are_a_child_with_its_parents_and_go_in_whole() {
+        let mut mempool = Mempool::new(1, 600, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        let spending = |outputs: &[&Transaction], value: u64| Transaction {
+            inputs: outputs.iter()
+                .map(|tx| TxInput { previous_output: OutPoint { txid: tx.hash(), index: 0 }, public_key: [0; 32], signature: Vec::new(), sequence: 0 })
+                .collect(),
+            ..transaction(value)
+        };
+        let (first, second) = (transaction(1), transaction(2));
+        let child = spending(&[&first, &second], 3);
+
+        // The second transaction isn't spent by the last
+        let grandchild = spending(&[&child], 4);
+        assert!(matches!(mempool.add_package(vec![(first.clone(), 10), (child.clone(), 10), (grandchild, 10)], 5, 0), Err(MempoolError::InvalidPackage(_))));
+        assert!(matches!(mempool.add_package(vec![(first.clone(), 10), (second.clone(), 10), (first.clone(), 10)], 5, 0), Err(MempoolError::InvalidPackage(_))));
+        // Both spend the first transaction's output
+        let sibling = spending(&[&first], 5);
+        let double_spend = spending(&[&first, &sibling], 6);
+        assert!(matches!(mempool.add_package(vec![(first.clone(), 10), (sibling, 10), (double_spend, 10)], 5, 0), Err(MempoolError::InvalidPackage(_))));
+
+        // A conflict with the pool refuses the package before any of it goes in
+        let rival = spending(&[&second], 7);
+        mempool.add_transaction(rival, 10, 5, 0).unwrap();
+        assert!(matches!(mempool.add_package(vec![(first.clone(), 10), (second.clone(), 10), (child.clone(), 10)], 5, 0), Err(MempoolError::InvalidPackage(_))));
+        assert_eq!(mempool.transaction_hashes().len(), 1);
+        assert!(mempool.get_transaction(&first.hash()).is_none());
+    }
+
+    #[test]
+    fn test_rejections_are_cached_until_the_tip_changes() {
+        let mut mempool = Mempool::new(1, 600, 3600, 60, policy(1.0), 16, MIN_DIFFICULTY_BITS);
+        let tx = transaction(1);
+        let txid = tx.hash();
+        assert!(matches!(mempool.add_transaction(tx.clone(), 0, 5, 0), Err(MempoolError::FeeRateTooLow { .. })));
+        assert!(mempool.already_have(&txid));
+        assert!(mempool.recent_rejection(&txid).flatten().is_some_and(|reason| reason.contains("below the minimum")));
+        assert!(matches!(mempool.add_transaction(tx.clone(), 1000, 5, 0), Err(MempoolError::RecentlyRejected(_))));
+
+        mempool.tip_changed();
+        assert_eq!(mempool.recent_rejection(&txid), None);
+        mempool.add_transaction(tx.clone(), 1000, 5, 0).unwrap();
+
+        mempool.transactions_confirmed(std::slice::from_ref(&tx));
+        assert!(mempool.transaction_hashes().is_empty());
+        assert!(mempool.was_recently_confirmed(&txid) && mempool.already_have(&txid));
+        assert!(matches!(mempool.add_transaction(tx.clone(), 1000, 5, 0), Err(MempoolError::RecentlyConfirmed)));
+
+        // Once its block is disconnected the transaction may come back
+        mempool.block_disconnected(5, 0);
+        assert!(!mempool.was_recently_confirmed(&txid));
+        mempool.add_transaction(tx, 1000, 5, 0).unwrap();
+        assert_eq!(mempool.transaction_hashes(), vec![txid]);
+    }
+
+    #[test]
+    fn test_removals_are_announced_with_their_reason() {
+        let mut mempool = Mempool::new(1, 0, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        let mut events = mempool.subscribe();
+        let spend = |txid: TxHash, value: u64| {
+            let input = TxInput { previous_output: OutPoint { txid, index: 0 }, public_key: [0; 32], signature: Vec::new(), sequence: 0 };
+            Transaction { inputs: vec![input], ..transaction(value) }
+        };
+        let original = spend([7; 32], 1);
+        let child = spend(original.hash(), 2);
+        mempool.add_transaction(original.clone(), 100, 5, 0).unwrap();
+        mempool.add_transaction(child.clone(), 100, 5, 0).unwrap();
+
+        // A double spend has to outbid the original and its child together
+        let replacement = spend([7; 32], 3);
+        assert!(matches!(mempool.add_transaction(replacement.clone(), 150, 5, 0), Err(MempoolError::InsufficientReplacementFee(_))));
+        mempool.add_transaction(replacement.clone(), 300, 5, 0).unwrap();
+        assert_eq!(mempool.transaction_hashes(), vec![replacement.hash()]);
+
+        // A block spending the same output some other way conflicts the replacement out
+        let unrelated = spend([8; 32], 4);
+        mempool.add_transaction(unrelated.clone(), 100, 5, 0).unwrap();
+        mempool.transactions_confirmed(&[spend([7; 32], 5)]);
+        assert_eq!(mempool.transaction_hashes(), vec![unrelated.hash()]);
+
+        mempool.set_size_limit_mb(0);
+        assert_eq!(mempool.cleanup_expired(unix_time()), 1);
+
+        let mut removed = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let MempoolEvent::TransactionRemoved { txid, reason } = event {
+                removed.push((txid, reason));
+            }
+        }
+        assert_eq!(removed, vec![
+            (original.hash(), RemovalReason::Replaced),
+            (child.hash(), RemovalReason::Replaced),
+            (replacement.hash(), RemovalReason::Conflict),
+            (unrelated.hash(), RemovalReason::EvictedForFee),
+        ]);
+        assert_eq!(mempool.recent_removals().map(|removal| (removal.txid, removal.reason)).collect::<Vec<_>>(), removed);
+    }
+
+    #[test]
+    fn test_replacements_pay_for_their_relay_and_evict_a_bounded_number() {
+        let mut mempool = Mempool::new(1, 0, 3600, 60, policy(1.0), 16, MIN_DIFFICULTY_BITS);
+        let spend = |txid: TxHash, index: u32, value: u64| {
+            let input = TxInput { previous_output: OutPoint { txid, index }, public_key: [0; 32], signature: Vec::new(), sequence: 0 };
+            Transaction { inputs: vec![input], ..transaction(value) }
+        };
+        let original = spend([7; 32], 0, 1);
+        mempool.add_transaction(original.clone(), 1_000, 5, 0).unwrap();
+
+        // Outbidding the original by a unit isn't enough; the replacement's own size has to be paid for too
+        let replacement = spend([7; 32], 0, 2);
+        let size = bincode::serialized_size(&replacement).unwrap();
+        assert!(matches!(mempool.add_transaction(replacement.clone(), 1_001, 5, 0), Err(MempoolError::InsufficientReplacementFee(_))));
+        assert!(matches!(mempool.add_transaction(replacement.clone(), 1_000 + size - 1, 5, 0), Err(MempoolError::InsufficientReplacementFee(_))));
+        mempool.add_transaction(replacement.clone(), 1_000 + size, 5, 0).unwrap();
+        assert_eq!(mempool.transaction_hashes(), vec![replacement.hash()]);
+
+        // A parent with more children than one replacement may evict can't be replaced at any fee
+        let parent = Transaction {
+            outputs: (0..MAX_REPLACEMENT_EVICTIONS as u64).map(|value| TxOutput { value: value + 1, script_pubkey: vec![1; 32] }).collect(),
+            ..spend([8; 32], 0, 0)
+        };
+        mempool.add_transaction(parent.clone(), 10_000, 5, 0).unwrap();
+        for index in 0..MAX_REPLACEMENT_EVICTIONS as u32 {
+            mempool.add_transaction(spend(parent.hash(), index, 1), 1_000, 5, 0).unwrap();
+        }
+        let rival = spend([8; 32], 0, 3);
+        assert!(matches!(
+            mempool.add_transaction(rival, 10_000_000, 5, 0),
+            Err(MempoolError::TooManyReplacements { count, max: MAX_REPLACEMENT_EVICTIONS }) if count == MAX_REPLACEMENT_EVICTIONS + 1
+        ));
+    }
+
+    fn fruit(nonce: u64) -> SignedBlock {
+        let fruit_header = FruitHeader { hang_from: [nonce as u8; 32], miner_public_key: [1; 32], timestamp: 0, nonce };
+        let header = BlockHeader { version: 1, previous_hash: [nonce as u8; 32], merkle_root: [0; 32], fruits_root: [0; 32], timestamp: 0, bits: 0, nonce };
+        let block = Block { header, block_type: BlockType::Fruit, fruit_header: Some(fruit_header), fruits: Vec::new(), transactions: Vec::new() };
+        mined(SignedBlock { block, public_key: [1; 32], signature: Vec::new() })
+    }
+
+    // Moves the fruit header's nonce on until it meets the fruit target
+    fn mined(mut fruit: SignedBlock) -> SignedBlock {
+        let header = fruit.block.fruit_header.as_mut().unwrap();
+        while !pow::check_proof_of_work(&header.hash(), MIN_DIFFICULTY_BITS) {
+            header.nonce += 1;
+        }
+        fruit
+    }
+
+    #[test]
+    fn test_snapshot_proofs_verify_against_its_root() {
+        let mut mempool = Mempool::new(1, 600, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        for value in 1..=5 {
+            mempool.add_transaction(transaction(value), 10, 5, 0).unwrap();
+        }
+        let snapshot = mempool.snapshot();
+        assert_eq!(snapshot.txids, mempool.transaction_hashes());
+        assert_eq!(snapshot.merkle_root, mempool.get_transaction_merkle_root());
+        let mut wanted = snapshot.txids.clone();
+        wanted.push(transaction(6).hash());
+        let proofs = snapshot.proofs(&wanted);
+        for (txid, proof) in snapshot.txids.iter().zip(&proofs) {
+            assert!(proof.as_ref().unwrap().verify(&snapshot.merkle_root, txid));
+        }
+        assert!(proofs[5].is_none());
+
+        // Proofs keep verifying against the snapshot's root after the pool changes
+        let txid = transaction(1).hash();
+        mempool.remove_transactions(&[transaction(2)]);
+        assert_ne!(mempool.get_transaction_merkle_root(), snapshot.merkle_root);
+        assert!(snapshot.proofs(&[txid])[0].as_ref().unwrap().verify(&snapshot.merkle_root, &txid));
+    }
+
+    #[test]
+    fn test_size_limit_applies_to_memory_usage() {
+        let mut mempool = Mempool::new(1, 0, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        let usage = transaction_memory_usage(&transaction(1));
+        mempool.size_limit_bytes = 3 * usage;
+        for value in 1..=3 {
+            mempool.add_transaction(transaction(value), value * 10, 5, 0).unwrap();
+        }
+
+        // Full on memory while the serialized transactions take a fraction of the limit
+        assert!(matches!(mempool.add_transaction(transaction(4), 40, 5, 0), Err(MempoolError::PoolFull)));
+        let info = mempool.info();
+        assert_eq!((info.usage, mempool.memory_usage()), (3 * usage, 3 * usage));
+        assert!(info.bytes < info.usage / 2);
+        assert!(info.lowest_fee_rate > 0.0);
+
+        // Eviction runs until the pool fits in memory again
+        mempool.size_limit_bytes = 2 * usage;
+        assert_eq!(mempool.cleanup_expired(unix_time()), 1);
+        assert!(mempool.get_transaction(&transaction(1).hash()).is_none());
+        assert_eq!(mempool.memory_usage(), 2 * usage);
+
+        mempool.remove_transactions(&[transaction(2), transaction(3)]);
+        assert_eq!((mempool.memory_usage(), mempool.current_size_mb()), (0, 0.0));
+    }
+
+    #[test]
+    fn test_fruits_differing_only_in_their_fruit_header_are_distinct() {
+        let mut mempool = Mempool::new(1, 600, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        let mut other_miner = fruit(1);
+        other_miner.block.fruit_header.as_mut().unwrap().miner_public_key = [2; 32];
+        let other_miner = mined(other_miner);
+        assert_eq!(other_miner.block.hash(), fruit(1).block.hash());
+        assert_ne!(other_miner.block.fruit_id(), fruit(1).block.fruit_id());
+
+        mempool.add_fruit(fruit(1), Some(10), 10).unwrap();
+        mempool.add_fruit(other_miner.clone(), Some(10), 10).unwrap();
+        assert_eq!(mempool.get_fruits().len(), 2);
+        assert!(mempool.get_fruit_proof(&other_miner.block.fruit_id()).is_some());
+    }
+
+    #[test]
+    fn test_fruits_must_meet_the_fruit_target() {
+        let mut mempool = Mempool::new(1, 600, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        let mut unmined = fruit(1);
+        let header = unmined.block.fruit_header.as_mut().unwrap();
+        while pow::check_proof_of_work(&header.hash(), MIN_DIFFICULTY_BITS) {
+            header.nonce += 1;
+        }
+        // Refused before it could wait as an orphan, too
+        assert!(matches!(mempool.add_fruit(unmined.clone(), Some(10), 10), Err(MempoolError::InsufficientFruitWork)));
+        assert!(matches!(mempool.add_fruit(unmined, None, 10), Err(MempoolError::InsufficientFruitWork)));
+        assert!(mempool.get_fruits().is_empty());
+    }
+
+    #[test]
+    fn test_fruits_survive_a_restart_while_fresh() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join(FRUITS_FILE_NAME);
+        let mut mempool = Mempool::new(1, 600, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        for nonce in 0..4 {
+            mempool.add_fruit(fruit(nonce), Some(10), 10).unwrap();
+        }
+        mempool.save_fruits(&path).unwrap();
+
+        let saved = read_saved_fruits(&path);
+        assert_eq!(saved.len(), 4);
+        let received_at = saved[0].received_at;
+        // The chain moved on while the node was down: one anchor was reorged out and another is now
+        // too deep, leaving two, of which one has since timed out
+        let mut restarted = Mempool::new(1, 600, 3600, 60, policy(0.0), 16, MIN_DIFFICULTY_BITS);
+        let mut anchored = saved.into_iter().zip([None, Some(3), Some(10), Some(10)]).collect::<Vec<_>>();
+        anchored[3].0.received_at = received_at.saturating_sub(60);
+        // Nor is a damaged or doctored file trusted: fruits that lost their work or aren't fruits are dropped
+        let mut unmined = anchored[2].clone();
+        let header = unmined.0.fruit.block.fruit_header.as_mut().unwrap();
+        while pow::check_proof_of_work(&header.hash(), MIN_DIFFICULTY_BITS) {
+            header.nonce += 1;
+        }
+        let mut headerless = anchored[2].clone();
+        headerless.0.fruit.block.fruit_header = None;
+        anchored.splice(0..0, [unmined, headerless]);
+        assert_eq!(restarted.restore_fruits(anchored, 20, received_at), 1);
+        assert_eq!(restarted.get_fruits()[0].block.fruit_id(), fruit(2).block.fruit_id());
+
+        assert!(read_saved_fruits(&temp_dir.path().join("missing.dat")).is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use blake3;
+use serde::{Serialize, Deserialize};
+
+pub type MerkleHash = [u8; 32];
+
+// Same layout as the rs_merkle trees the mempool keeps: parents hash the concatenation of their
+// children and an odd node at the end of a level is promoted unchanged. Leaves and parents hash
+// under different prefixes, so an interior node can't be passed off as a leaf or the other way round.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn hash_leaf(leaf: &MerkleHash) -> MerkleHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(leaf);
+    *hasher.finalize().as_bytes()
+}
+
+pub fn hash_pair(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+fn next_level(level: &[MerkleHash]) -> Vec<MerkleHash> {
+    level.chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => *single,
+            _ => unreachable!("chunks(2) yields one or two items"),
+        })
+        .collect()
+}
+
+pub fn merkle_root(leaves: &[MerkleHash]) -> MerkleHash {
+    if leaves.is_empty() {
+        return [0; 32];
+    }
+    let mut level = leaves.iter().map(hash_leaf).collect::<Vec<_>>();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+// Proof that a leaf sits at `index` in a tree of `leaf_count` leaves; `path` holds the sibling
+// hashes from the bottom up, skipping levels where the node was promoted without a sibling
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBranch {
+    pub index: usize,
+    pub leaf_count: usize,
+    pub path: Vec<MerkleHash>,
+}
+
+impl MerkleBranch {
+    pub fn new(leaves: &[MerkleHash], index: usize) -> Option<Self> {
+        MerkleLevels::new(leaves).branch(index)
+    }
+
+    // Root implied by `leaf` and this branch, or `None` if the branch is malformed
+    pub fn compute_root(&self, leaf: &MerkleHash) -> Option<MerkleHash> {
+        if self.index >= self.leaf_count {
+            return None;
+        }
+
+        let mut hash = hash_leaf(leaf);
+        let mut position = self.index;
+        let mut width = self.leaf_count;
+        let mut siblings = self.path.iter();
+        while width > 1 {
+            if position ^ 1 < width {
+                let sibling = siblings.next()?;
+                hash = if position % 2 == 0 { hash_pair(&hash, sibling) } else { hash_pair(sibling, &hash) };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+
+        if siblings.next().is_some() {
+            return None;
+        }
+        Some(hash)
+    }
+
+    pub fn verify(&self, root: &MerkleHash, leaf: &MerkleHash) -> bool {
+        self.compute_root(leaf).as_ref() == Some(root)
+    }
+}
+
+// Every level of a tree, hashed leaves first, so any number of branches can be taken from one
+// pass over the leaves
+pub struct MerkleLevels {
+    levels: Vec<Vec<MerkleHash>>,
+}
+
+impl MerkleLevels {
+    pub fn new(leaves: &[MerkleHash]) -> Self {
+        let mut levels = vec![leaves.iter().map(hash_leaf).collect::<Vec<_>>()];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let next = next_level(level);
+            levels.push(next);
+        }
+        MerkleLevels { levels }
+    }
+
+    pub fn branch(&self, index: usize) -> Option<MerkleBranch> {
+        let leaf_count = self.levels[0].len();
+        if index >= leaf_count {
+            return None;
+        }
+
+        let mut path = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            if sibling < level.len() {
+                path.push(level[sibling]);
+            }
+            position /= 2;
+        }
+        Some(MerkleBranch { index, leaf_count, path })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    fn leaves(count: usize) -> Vec<MerkleHash> {
+        (0..count).map(|i| blake3::hash(&i.to_le_bytes()).into()).collect()
+    }
+
+    #[test]
+    fn test_every_branch_verifies() {
+        for count in 1..=17 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            let levels = MerkleLevels::new(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let branch = MerkleBranch::new(&leaves, index).unwrap();
+                assert!(branch.verify(&root, leaf), "count {} index {}", count, index);
+                assert_eq!(levels.branch(index), Some(branch));
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampered_branch_is_rejected() {
+        let leaves = leaves(6);
+        let root = merkle_root(&leaves);
+        let branch = MerkleBranch::new(&leaves, 3).unwrap();
+
+        assert!(!branch.verify(&root, &leaves[2]));
+
+        let mut wrong_index = branch.clone();
+        wrong_index.index = 2;
+        assert!(!wrong_index.verify(&root, &leaves[3]));
+
+        let mut extra_hash = branch.clone();
+        extra_hash.path.push([0; 32]);
+        assert!(!extra_hash.verify(&root, &leaves[3]));
+
+        assert!(MerkleBranch::new(&leaves, 6).is_none());
+    }
+
+    #[test]
+    fn test_interior_node_is_not_a_leaf() {
+        let leaves = leaves(4);
+        let root = merkle_root(&leaves);
+        // The left parent, presented as a leaf of a two-leaf tree, doesn't reproduce the root
+        let parent = hash_pair(&hash_leaf(&leaves[0]), &hash_leaf(&leaves[1]));
+        let sibling = hash_pair(&hash_leaf(&leaves[2]), &hash_leaf(&leaves[3]));
+        let forged = MerkleBranch { index: 0, leaf_count: 2, path: vec![sibling] };
+        assert!(!forged.verify(&root, &parent));
+        assert_ne!(merkle_root(&[parent, sibling]), root);
+        assert_ne!(merkle_root(&leaves[..1]), leaves[0]);
+    }
+
+    // Found by prop_arbitrary_branches_never_panic: halving the width as (width + 1) / 2 overflowed
+    #[test]
+    fn test_branch_for_the_largest_tree_does_not_overflow() {
+        let branch = MerkleBranch { index: 0, leaf_count: usize::MAX, path: vec![[0; 32]; 64] };
+        assert!(branch.compute_root(&[1; 32]).is_some());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_branches_verify_only_their_leaf(leaves in vec(any::<[u8; 32]>(), 1..64), index in any::<prop::sample::Index>(), other in any::<[u8; 32]>()) {
+            let index = index.index(leaves.len());
+            let root = merkle_root(&leaves);
+            let branch = MerkleBranch::new(&leaves, index).unwrap();
+            prop_assert!(branch.verify(&root, &leaves[index]));
+            prop_assume!(other != leaves[index]);
+            prop_assert!(!branch.verify(&root, &other));
+        }
+
+        // Branches arrive from peers, so any shape must be handled without panicking
+        #[test]
+        fn prop_arbitrary_branches_never_panic(index in any::<usize>(), leaf_count in any::<usize>(), path in vec(any::<[u8; 32]>(), 0..70), leaf in any::<[u8; 32]>()) {
+            let branch = MerkleBranch { index, leaf_count, path };
+            let _ = branch.compute_root(&leaf);
+        }
+    }
+}
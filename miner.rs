@@ -0,0 +1,326 @@
+use crate::blockchain::{Block, BlockHash, BlockHeader, BlockType, Blockchain, FruitHeader, calculate_fruits_root, calculate_merkle_root};
+use crate::chain_params::{ChainParams, Network};
+use crate::codec;
+use crate::transaction::{OutPoint, Transaction, TxHash};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MiningError {
+    #[error("Node is in initial block download; blocks mined on its tip would be orphaned")]
+    InitialBlockDownload,
+}
+
+pub struct BlockTemplateBuilder {
+    params: ChainParams,
+    // How long filling a block may take before it's returned with the candidates seen so far
+    time_budget: Option<Duration>,
+}
+
+impl BlockTemplateBuilder {
+    pub fn new(params: ChainParams) -> Self {
+        BlockTemplateBuilder { params, time_budget: None }
+    }
+
+    pub fn with_time_budget(mut self, time_budget: Duration) -> Self {
+        self.time_budget = Some(time_budget);
+        self
+    }
+
+    // A builder for extending `blockchain`, refused until it has caught up with the network.
+    // Regtest chains are never recent when a test starts, so they can always be mined.
+    pub fn for_chain(blockchain: &Blockchain) -> Result<Self, MiningError> {
+        let params = blockchain.params();
+        if params.network != Network::Regtest && blockchain.is_initial_block_download() {
+            return Err(MiningError::InitialBlockDownload);
+        }
+        Ok(BlockTemplateBuilder::new(params.clone()))
+    }
+
+    // Fills a block with `candidates` in order, skipping any that would break the consensus size limits
+    // or aren't final at `height` given the parent's `median_time_past`. `fruits` come from
+    // `Blockchain::select_fruits` and must already be paid for by `coinbase`. `version` comes from
+    // `Blockchain::next_block_version` so the block signals for deployments in progress, and
+    // `timestamp` from `Blockchain::next_block_timestamp` so it follows network-adjusted time.
+    // Candidates left over when the time budget runs out are not looked at.
+    #[allow(clippy::too_many_arguments)]
+    pub fn build(
+        &self,
+        version: u32,
+        previous_hash: BlockHash,
+        height: u64,
+        median_time_past: u64,
+        timestamp: u64,
+        bits: u32,
+        coinbase: Transaction,
+        fruits: Vec<FruitHeader>,
+        candidates: impl IntoIterator<Item = Transaction>,
+    ) -> Result<Block, bincode::Error> {
+        let mut block = Block {
+            header: BlockHeader {
+                version,
+                previous_hash,
+                merkle_root: [0; 32],
+                fruits_root: [0; 32],
+                timestamp,
+                bits,
+                nonce: 0,
+            },
+            block_type: BlockType::Block,
+            fruit_header: None,
+            fruits,
+            transactions: vec![coinbase],
+        };
+        let mut block_size = bincode::serialized_size(&block)? as usize;
+        self.fill(&mut block, &mut block_size, height, median_time_past, candidates, &mut |_| true)?;
+
+        block.header.merkle_root = calculate_merkle_root(&block.transactions);
+        block.header.fruits_root = calculate_fruits_root(&block.fruits);
+        Ok(block)
+    }
+
+    // Appends the candidates that fit and that `admit` lets through, returning how many were added
+    fn fill(
+        &self,
+        block: &mut Block,
+        block_size: &mut usize,
+        height: u64,
+        median_time_past: u64,
+        candidates: impl IntoIterator<Item = Transaction>,
+        admit: &mut dyn FnMut(&Transaction) -> bool,
+    ) -> Result<usize, bincode::Error> {
+        let deadline = self.time_budget.map(|budget| Instant::now() + budget);
+        let mut added = 0;
+        for tx in candidates {
+            if block.transactions.len() >= self.params.max_transactions_per_block {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
+            }
+            if !tx.is_final(height, median_time_past) {
+                continue;
+            }
+            let tx_size = bincode::serialized_size(&tx)? as usize;
+            if tx_size > self.params.max_transaction_size || *block_size + codec::FRAME_OVERHEAD + tx_size > self.params.max_block_size {
+                continue;
+            }
+            if !admit(&tx) {
+                continue;
+            }
+            *block_size += codec::FRAME_OVERHEAD + tx_size;
+            block.transactions.push(tx);
+            added += 1;
+        }
+        Ok(added)
+    }
+}
+
+// The block being mined on the current tip, kept between requests. New transactions and fruits are
+// folded into it as they arrive instead of assembling the block again from the whole mempool; a
+// new tip, or a transaction in it leaving the mempool, throws it away. A transaction only goes in
+// after every unconfirmed parent, and never alongside another spend of the same output.
+pub struct TemplateCache {
+    builder: BlockTemplateBuilder,
+    template: Option<CachedTemplate>,
+}
+
+struct CachedTemplate {
+    block: Block,
+    size: usize,
+    height: u64,
+    median_time_past: u64,
+    txids: HashSet<TxHash>,
+    spent: HashSet<OutPoint>,
+}
+
+impl TemplateCache {
+    pub fn new(builder: BlockTemplateBuilder) -> Self {
+        TemplateCache { builder, template: None }
+    }
+
+    // The cached template if it still extends `previous_hash`
+    pub fn current(&self, previous_hash: &BlockHash) -> Option<&Block> {
+        self.template.as_ref()
+            .map(|template| &template.block)
+            .filter(|block| &block.header.previous_hash == previous_hash)
+    }
+
+    pub fn tip_changed(&mut self) {
+        self.template = None;
+    }
+
+    // Throws the template away if a transaction in it is no longer in the pool: evicted, expired,
+    // replaced or double-spent, so anything built on it may be invalid
+    pub fn check_pool(&mut self, in_pool: impl Fn(&TxHash) -> bool) {
+        if self.template.as_ref().is_some_and(|template| !template.txids.iter().all(&in_pool)) {
+            self.template = None;
+        }
+    }
+
+    // Assembles a fresh template from the whole mempool; arguments are as for `BlockTemplateBuilder::build`,
+    // and `in_pool` as for `add_transactions`
+    #[allow(clippy::too_many_arguments)]
+    pub fn refresh(
+        &mut self,
+        version: u32,
+        previous_hash: BlockHash,
+        height: u64,
+        median_time_past: u64,
+        timestamp: u64,
+        bits: u32,
+        coinbase: Transaction,
+        fruits: Vec<FruitHeader>,
+        candidates: impl IntoIterator<Item = Transaction>,
+        in_pool: impl Fn(&TxHash) -> bool,
+    ) -> Result<&Block, bincode::Error> {
+        let block = self.builder.build(version, previous_hash, height, median_time_past, timestamp, bits, coinbase, fruits, std::iter::empty())?;
+        let size = bincode::serialized_size(&block)? as usize;
+        self.template = Some(CachedTemplate { block, size, height, median_time_past, txids: HashSet::new(), spent: HashSet::new() });
+        self.add_transactions(candidates, in_pool)?;
+        Ok(&self.template.as_ref().expect("template was just built").block)
+    }
+
+    // Appends newly arrived transactions that fit, returning how many were added. `in_pool` tells
+    // which txids are unconfirmed; spending one of those needs it in the template first. Does
+    // nothing without a template; the next `refresh` picks them up from the mempool.
+    pub fn add_transactions(&mut self, candidates: impl IntoIterator<Item = Transaction>, in_pool: impl Fn(&TxHash) -> bool) -> Result<usize, bincode::Error> {
+        let Some(template) = self.template.as_mut() else {
+            return Ok(0);
+        };
+        let (txids, spent) = (&mut template.txids, &mut template.spent);
+        let added = self.builder.fill(
+            &mut template.block,
+            &mut template.size,
+            template.height,
+            template.median_time_past,
+            candidates,
+            &mut |tx| {
+                let txid = tx.hash();
+                let admissible = !txids.contains(&txid)
+                    && tx.inputs.iter().all(|input| {
+                        let parent = &input.previous_output.txid;
+                        !spent.contains(&input.previous_output) && (txids.contains(parent) || !in_pool(parent))
+                    });
+                if admissible {
+                    txids.insert(txid);
+                    spent.extend(tx.inputs.iter().map(|input| input.previous_output));
+                }
+                admissible
+            },
+        )?;
+        if added > 0 {
+            template.block.header.merkle_root = calculate_merkle_root(&template.block.transactions);
+        }
+        Ok(added)
+    }
+
+    // Swaps in a new fruit selection with the coinbase paying for it. A larger coinbase may push
+    // the block over the size limit, in which case the most recently added transactions make room.
+    pub fn set_fruits(&mut self, fruits: Vec<FruitHeader>, coinbase: Transaction) -> Result<(), bincode::Error> {
+        let Some(template) = self.template.as_mut() else {
+            return Ok(());
+        };
+        let block = &mut template.block;
+        block.fruits = fruits;
+        block.transactions[0] = coinbase;
+        template.size = bincode::serialized_size(&*block)? as usize;
+        while template.size > self.builder.params.max_block_size && block.transactions.len() > 1 {
+            if let Some(tx) = block.transactions.pop() {
+                template.size -= codec::FRAME_OVERHEAD + bincode::serialized_size(&tx)? as usize;
+                template.txids.remove(&tx.hash());
+                for input in &tx.inputs {
+                    template.spent.remove(&input.previous_output);
+                }
+            }
+        }
+        block.header.fruits_root = calculate_fruits_root(&block.fruits);
+        block.header.merkle_root = calculate_merkle_root(&block.transactions);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxOutput;
+
+    fn transaction(value: u64) -> Transaction {
+        Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value, script_pubkey: vec![1; 32] }], lock_time: 0 }
+    }
+
+    #[test]
+    fn test_cached_template_grows_until_the_tip_changes() {
+        let params = ChainParams::for_network(Network::Regtest);
+        let mut cache = TemplateCache::new(BlockTemplateBuilder::new(params));
+        let tip = [1; 32];
+        let block = cache.refresh(1, tip, 1, 0, 100, 0x207fffff, transaction(0), Vec::new(), vec![transaction(1)], |_| false).unwrap();
+        assert_eq!(block.transactions.len(), 2);
+
+        assert_eq!(cache.add_transactions(vec![transaction(1), transaction(2)], |_| false).unwrap(), 1);
+        let block = cache.current(&tip).unwrap();
+        assert_eq!(block.transactions.len(), 3);
+        assert_eq!(block.header.merkle_root, calculate_merkle_root(&block.transactions));
+        assert!(cache.current(&[2; 32]).is_none());
+
+        cache.tip_changed();
+        assert!(cache.current(&tip).is_none());
+        assert_eq!(cache.add_transactions(vec![transaction(3)], |_| false).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cached_template_keeps_parents_first_and_refuses_conflicts() {
+        let spending = |outpoint: OutPoint, value: u64| Transaction {
+            inputs: vec![crate::transaction::TxInput { previous_output: outpoint, public_key: [0; 32], signature: Vec::new(), sequence: 0 }],
+            ..transaction(value)
+        };
+        let parent = spending(OutPoint { txid: [9; 32], index: 0 }, 1);
+        let child = spending(OutPoint { txid: parent.hash(), index: 0 }, 2);
+        let rival = spending(OutPoint { txid: parent.hash(), index: 0 }, 3);
+        let pool = [parent.hash(), child.hash(), rival.hash()];
+        let in_pool = |txid: &TxHash| pool.contains(txid);
+
+        let mut cache = TemplateCache::new(BlockTemplateBuilder::new(ChainParams::for_network(Network::Regtest)));
+        let tip = [1; 32];
+        // The child's parent is unconfirmed and not in the block, so the child can't be either
+        let block = cache.refresh(1, tip, 1, 0, 100, 0x207fffff, transaction(0), Vec::new(), vec![child.clone()], in_pool).unwrap();
+        assert_eq!(block.transactions.len(), 1);
+
+        assert_eq!(cache.add_transactions(vec![parent.clone(), child.clone(), rival], in_pool).unwrap(), 2);
+        let hashes = cache.current(&tip).unwrap().transactions.iter().map(Transaction::hash).collect::<Vec<_>>();
+        assert_eq!(hashes[1..], [parent.hash(), child.hash()]);
+
+        cache.check_pool(in_pool);
+        assert!(cache.current(&tip).is_some());
+        // The child was replaced or evicted, so the template can't be trusted any more
+        cache.check_pool(|txid| *txid == parent.hash());
+        assert!(cache.current(&tip).is_none());
+    }
+
+    #[test]
+    fn test_template_keeps_to_the_size_limits() {
+        let mut params = ChainParams::for_network(Network::Regtest);
+        params.max_transactions_per_block = 3;
+        let mut large = transaction(1);
+        large.outputs[0].script_pubkey = vec![1; params.max_transaction_size];
+        let builder = BlockTemplateBuilder::new(params.clone());
+        let block = builder.build(1, [1; 32], 1, 0, 100, 0x207fffff, transaction(0), Vec::new(), std::iter::once(large).chain((2..10).map(transaction))).unwrap();
+        assert_eq!(block.transactions.iter().map(|tx| tx.outputs[0].value).collect::<Vec<_>>(), vec![0, 2, 3]);
+
+        params.max_transactions_per_block = 100;
+        params.max_block_size = bincode::serialized_size(&block).unwrap() as usize;
+        let builder = BlockTemplateBuilder::new(params.clone());
+        let block = builder.build(1, [1; 32], 1, 0, 100, 0x207fffff, transaction(0), Vec::new(), (2..10).map(transaction)).unwrap();
+        assert_eq!(block.transactions.len(), 3);
+        assert!(bincode::serialized_size(&block).unwrap() as usize <= params.max_block_size);
+    }
+
+    #[test]
+    fn test_exhausted_time_budget_stops_assembly() {
+        let params = ChainParams::for_network(Network::Regtest);
+        let builder = BlockTemplateBuilder::new(params).with_time_budget(Duration::ZERO);
+        let block = builder.build(1, [1; 32], 1, 0, 100, 0x207fffff, transaction(0), Vec::new(), (1..100).map(transaction)).unwrap();
+        assert_eq!(block.transactions.len(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+use ed25519_dalek::{Signature, Verifier, VerifyingKey, SIGNATURE_LENGTH};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+// An m-of-n multisig output's script is
+//   MULTISIG_SCRIPT_TAG | m | n | n sorted, distinct 32-byte public keys
+// and its hex encoding is the address it's paid to. An input spending one puts the script's id in
+// `TxInput::public_key` and a `MultisigWitness` in `TxInput::signature`; single-key signatures are
+// always SIGNATURE_LENGTH bytes and a witness never is, so the two can't be mistaken for each other.
+pub const MULTISIG_SCRIPT_TAG: u8 = 0x6d;
+const WITNESS_TAG: u8 = 0x77;
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum MultisigError {
+    #[error("{required}-of-{keys} is not a valid multisig threshold")]
+    InvalidThreshold { required: usize, keys: usize },
+    #[error("Multisig scripts take at most {MAX_MULTISIG_KEYS} keys, not {0}")]
+    TooManyKeys(usize),
+    #[error("The same key appears twice")]
+    DuplicateKey,
+    #[error("Malformed multisig witness")]
+    MalformedWitness,
+    #[error("Key {0} is not part of the script")]
+    UnknownKey(usize),
+    #[error("{found} of {required} required signatures are valid")]
+    NotEnoughSignatures { found: usize, required: usize },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MultisigScript {
+    required: u8,
+    // Sorted, so every cosigner derives the same script from the same keys
+    keys: Vec<[u8; 32]>,
+}
+
+impl MultisigScript {
+    pub fn new(required: usize, mut keys: Vec<[u8; 32]>) -> Result<Self, MultisigError> {
+        if keys.len() > MAX_MULTISIG_KEYS {
+            return Err(MultisigError::TooManyKeys(keys.len()));
+        }
+        if required == 0 || required > keys.len() {
+            return Err(MultisigError::InvalidThreshold { required, keys: keys.len() });
+        }
+        keys.sort_unstable();
+        if keys.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(MultisigError::DuplicateKey);
+        }
+        Ok(MultisigScript { required: required as u8, keys })
+    }
+
+    pub fn required(&self) -> usize {
+        self.required as usize
+    }
+
+    pub fn keys(&self) -> &[[u8; 32]] {
+        &self.keys
+    }
+
+    pub fn to_script(&self) -> Vec<u8> {
+        let mut script = Vec::with_capacity(3 + 32 * self.keys.len());
+        script.extend([MULTISIG_SCRIPT_TAG, self.required, self.keys.len() as u8]);
+        for key in &self.keys {
+            script.extend_from_slice(key);
+        }
+        script
+    }
+
+    // None unless `script` is a canonical multisig script
+    pub fn from_script(script: &[u8]) -> Option<Self> {
+        let (&[tag, required, count], keys) = script.split_first_chunk::<3>()?;
+        if tag != MULTISIG_SCRIPT_TAG || keys.len() != 32 * count as usize {
+            return None;
+        }
+        let keys = keys.chunks_exact(32).map(|key| key.try_into().expect("32-byte chunks")).collect::<Vec<_>>();
+        let parsed = Self::new(required as usize, keys).ok()?;
+        // Unsorted keys would give a second script for the same key set
+        (parsed.to_script() == script).then_some(parsed)
+    }
+
+    // What a spending input puts in `public_key`, tying its witness to this output
+    pub fn script_id(&self) -> [u8; 32] {
+        blake3::hash(&self.to_script()).into()
+    }
+
+    pub fn address(&self) -> String {
+        hex::encode(self.to_script())
+    }
+
+    fn is_canonical(&self) -> bool {
+        Self::new(self.required(), self.keys.clone()).is_ok_and(|canonical| canonical == *self)
+    }
+}
+
+// The key a spending input names for an output paying `script`: the key itself for single-key
+// outputs, the script id for multisig ones. None for scripts the node can't sign for.
+pub fn spending_key(script: &[u8]) -> Option<[u8; 32]> {
+    match script.try_into() {
+        Ok(key) => Some(key),
+        Err(_) => MultisigScript::from_script(script).map(|script| script.script_id()),
+    }
+}
+
+// Signatures by key position in the script. Partially signed transactions carry one with fewer
+// than `required` signatures until enough cosigners have signed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MultisigWitness {
+    pub script: MultisigScript,
+    pub signatures: Vec<(u8, Vec<u8>)>,
+}
+
+impl MultisigWitness {
+    pub fn new(script: MultisigScript) -> Self {
+        MultisigWitness { script, signatures: Vec::new() }
+    }
+
+    // As large as a complete witness for `script`, for sizing fees before anyone has signed
+    pub fn placeholder(script: MultisigScript) -> Self {
+        let signatures = (0..script.required).map(|position| (position, vec![0; SIGNATURE_LENGTH])).collect();
+        MultisigWitness { script, signatures }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![WITNESS_TAG];
+        bytes.extend(bincode::serialize(self).expect("witnesses always serialize"));
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, MultisigError> {
+        match bytes.split_first() {
+            Some((&WITNESS_TAG, payload)) if bytes.len() != SIGNATURE_LENGTH => {
+                let witness: Self = bincode::deserialize(payload).map_err(|_| MultisigError::MalformedWitness)?;
+                // Trailing bytes would let anyone change a spend's txid without touching its signatures
+                if !witness.script.is_canonical() || witness.encode() != bytes {
+                    return Err(MultisigError::MalformedWitness);
+                }
+                Ok(witness)
+            }
+            _ => Err(MultisigError::MalformedWitness),
+        }
+    }
+
+    // Adds or replaces the signature by the key at `position`, keeping them in key order
+    pub fn add_signature(&mut self, position: usize, signature: Vec<u8>) -> Result<(), MultisigError> {
+        if position >= self.script.keys.len() {
+            return Err(MultisigError::UnknownKey(position));
+        }
+        let position = position as u8;
+        self.signatures.retain(|(existing, _)| *existing != position);
+        self.signatures.push((position, signature));
+        self.signatures.sort_by_key(|(position, _)| *position);
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.signatures.len() >= self.script.required()
+    }
+
+    // Keeps only the first `required` signatures, all a spend may carry
+    pub fn trimmed(&self) -> Self {
+        MultisigWitness { script: self.script.clone(), signatures: self.signatures.iter().take(self.script.required()).cloned().collect() }
+    }
+
+    // Consensus check: exactly `required` signatures of `message`, by distinct keys in key order
+    pub fn verify(&self, message: &[u8; 32]) -> Result<(), MultisigError> {
+        let required = self.script.required();
+        if self.signatures.len() != required || !self.signatures.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            return Err(MultisigError::MalformedWitness);
+        }
+        for (found, (position, signature)) in self.signatures.iter().enumerate() {
+            let key = self.script.keys.get(*position as usize).ok_or(MultisigError::UnknownKey(*position as usize))?;
+            let valid = VerifyingKey::from_bytes(key).ok()
+                .zip(Signature::from_slice(signature).ok())
+                .is_some_and(|(key, signature)| key.verify(message, &signature).is_ok());
+            if !valid {
+                return Err(MultisigError::NotEnoughSignatures { found, required });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn test_two_of_three_script_and_witness() {
+        let signers = [1u8, 2, 3].map(|seed| SigningKey::from_bytes(&[seed; 32]));
+        let keys = signers.iter().map(|key| key.verifying_key().to_bytes()).collect::<Vec<_>>();
+        let script = MultisigScript::new(2, keys.iter().rev().copied().collect()).unwrap();
+        assert_eq!(script, MultisigScript::new(2, keys.clone()).unwrap());
+        assert_eq!(MultisigScript::from_script(&script.to_script()), Some(script.clone()));
+        assert_eq!(spending_key(&script.to_script()), Some(script.script_id()));
+        assert_eq!(MultisigScript::new(4, keys.clone()), Err(MultisigError::InvalidThreshold { required: 4, keys: 3 }));
+        assert_eq!(MultisigScript::new(1, vec![keys[0], keys[0]]), Err(MultisigError::DuplicateKey));
+
+        let message = [9; 32];
+        let position = |key: &SigningKey| script.keys().iter().position(|k| *k == key.verifying_key().to_bytes()).unwrap();
+        let mut witness = MultisigWitness::new(script.clone());
+        witness.add_signature(position(&signers[2]), signers[2].sign(&message).to_bytes().to_vec()).unwrap();
+        assert!(!witness.is_complete());
+        assert!(witness.verify(&message).is_err());
+
+        witness.add_signature(position(&signers[0]), signers[0].sign(&message).to_bytes().to_vec()).unwrap();
+        let decoded = MultisigWitness::decode(&witness.encode()).unwrap();
+        assert!(decoded.is_complete());
+        decoded.verify(&message).unwrap();
+        assert!(decoded.verify(&[8; 32]).is_err());
+
+        // A signature from a key outside the script can't stand in for a cosigner's
+        let mut forged = witness.clone();
+        forged.signatures[0].1 = SigningKey::from_bytes(&[4; 32]).sign(&message).to_bytes().to_vec();
+        assert!(matches!(forged.verify(&message), Err(MultisigError::NotEnoughSignatures { .. })));
+    }
+}
@@ -0,0 +1,1734 @@
+use crate::addrman::AddrManager;
+use crate::block_download::BlockDownloads;
+use crate::blockchain::{Block, BlockHash, BlockHeader, Blockchain, ChainEvent, SignedBlock};
+use crate::broadcast::BroadcastManager;
+use crate::chain_params::{ChainParams, UTXO_COMMITMENT_DEPLOYMENT};
+use crate::eviction::{EvictionCandidate, EvictionPolicy, InboundDecision};
+use crate::inventory::InventoryTracker;
+use crate::light_client::HeaderChain;
+use crate::mempool::{Mempool, MempoolEvent};
+use crate::network_time::local_time;
+use crate::node_config::NetworkConfig;
+use crate::outbound::{ConnectionType, OutboundSlots};
+use crate::protocol::{Handshake, HandshakeError, Inventory, Message, NegotiatedPeer, NetAddress, PeerId, VersionMessage, MAX_HEADERS_PER_MESSAGE, NODE_UTXO_SNAPSHOTS, PROTOCOL_VERSION};
+use crate::rate_limit::{self, PeerRateLimiter, RateLimitError, SendQueue};
+use crate::rpc::MAX_FILTERS_PER_REQUEST;
+use crate::snapshot::{Snapshot, SnapshotError, SnapshotSync};
+use crate::transaction::{Transaction, TxHash};
+use crate::trickle::TrickleRelay;
+use crate::transport::{NodeKey, SecureReader, SecureStream, SecureWriter, TransportError};
+use crate::validation::ValidationError;
+use crate::wire::{MessageCodec, WireError};
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+use tokio_util::sync::CancellationToken;
+
+// Peer-to-peer networking: accepts inbound connections, keeps the outbound slots filled from the
+// address manager, and relays blocks, transactions and fruits. Each connection runs the version
+// handshake, inside a Noise session when encryption is on, then passes every message through the
+// rate limits before it is handled. Replies go through the peer's bounded send queue, drained by
+// a writer task of its own so a slow reader never holds up the rest of the node. Optionally the
+// node serves UTXO snapshots, or loads its own chainstate from them before downloading blocks.
+pub const USER_AGENT: &str = concat!("/xcore:", env!("CARGO_PKG_VERSION"), "/");
+// How long a new connection has to complete the version handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How often free outbound slots are topped up from the address manager
+const CONNECT_INTERVAL: Duration = Duration::from_secs(5);
+// How long an address rests after a connection attempt before it is picked again
+const RETRY_AFTER_SECS: u64 = 10 * 60;
+// How often block downloads are checked for peers that stopped delivering
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// How often connected peers are pinged, which gives the round trips eviction ranks them by
+const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+// How often our own transactions are checked for a new broadcast round, see `BroadcastManager`
+const REBROADCAST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Misbehavior score at which a peer is disconnected, see `ValidationError::misbehavior`
+const DISCONNECT_SCORE: u32 = 100;
+// Snapshots kept for serving. The one before the newest lets a node part way through loading it
+// finish after a newer one is taken.
+const SNAPSHOTS_KEPT: usize = 2;
+// Snapshot chunks asked of one peer at a time, well inside its snapshot request rate limit
+const SNAPSHOT_CHUNKS_IN_FLIGHT: usize = 4;
+
+#[derive(Error, Debug)]
+pub enum NetError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Wire error: {0}")]
+    Wire(#[from] WireError),
+    #[error("Handshake failed: {0}")]
+    Handshake(#[from] HandshakeError),
+    #[error("Handshake timed out")]
+    HandshakeTimeout,
+    #[error("Connection attempt timed out")]
+    ConnectTimeout,
+    #[error("{0}")]
+    RateLimit(#[from] RateLimitError),
+    #[error("Peer sent {0}, which was not negotiated")]
+    NotNegotiated(&'static str),
+    #[error("Transport error: {0}")]
+    Transport(#[from] TransportError),
+    #[error("Encrypted message does not hold exactly one frame")]
+    PartialFrame,
+    #[error("Peer closed the connection")]
+    Closed,
+    #[error("Snapshot error: {0}")]
+    Snapshot(#[from] SnapshotError),
+    // The node failed handling a message, not the peer
+    #[error("Chain error: {0}")]
+    Chain(String),
+}
+
+fn chain_error(e: Box<dyn std::error::Error>) -> NetError {
+    NetError::Chain(e.to_string())
+}
+
+// Splits a chain error into the `ValidationError` a peer's data caused and our own failures
+fn validation_error(e: Box<dyn std::error::Error>) -> Result<ValidationError, NetError> {
+    match e.downcast::<ValidationError>() {
+        Ok(e) => Ok(*e),
+        Err(e) => Err(chain_error(e)),
+    }
+}
+
+// Splits a failed snapshot load into a `SnapshotError`, which may be down to the snapshot, and our
+// own failures
+fn snapshot_error(e: Box<dyn std::error::Error>) -> NetError {
+    match e.downcast::<SnapshotError>() {
+        Ok(e) => NetError::Snapshot(*e),
+        Err(e) => chain_error(e),
+    }
+}
+
+// Node key and the peer keys let in, for connections under Noise
+struct Encryption {
+    key: NodeKey,
+    trusted: HashSet<Vec<u8>>,
+}
+
+// Receiving end of a connection, in the clear or under Noise. Encrypted messages each carry one
+// wire frame.
+enum MessageReader {
+    Plain(FramedRead<OwnedReadHalf, MessageCodec>),
+    Encrypted { reader: SecureReader<ReadHalf<TcpStream>>, codec: MessageCodec },
+}
+
+impl MessageReader {
+    // None once the peer has closed the connection
+    async fn next(&mut self) -> Option<Result<Message, NetError>> {
+        match self {
+            MessageReader::Plain(reader) => reader.next().await.map(|message| message.map_err(NetError::from)),
+            MessageReader::Encrypted { reader, codec } => {
+                let frame = match reader.recv(codec.max_frame_len()).await {
+                    Ok(frame) => frame,
+                    Err(TransportError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                let mut frame = BytesMut::from(&frame[..]);
+                Some(match codec.decode(&mut frame) {
+                    Ok(Some(message)) if frame.is_empty() => Ok(message),
+                    Ok(_) => Err(NetError::PartialFrame),
+                    Err(e) => Err(e.into()),
+                })
+            }
+        }
+    }
+}
+
+enum MessageWriter {
+    Plain(OwnedWriteHalf),
+    Encrypted(SecureWriter<WriteHalf<TcpStream>>),
+}
+
+impl MessageWriter {
+    async fn write(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        match self {
+            MessageWriter::Plain(writer) => writer.write_all(frame).await?,
+            MessageWriter::Encrypted(writer) => writer.send(frame).await?,
+        }
+        Ok(())
+    }
+}
+
+struct Peer {
+    address: SocketAddr,
+    inbound: bool,
+    // False on block-relay-only connections, which carry no transactions, fruits or addresses
+    relay: bool,
+    // Set once the handshake completes
+    negotiated: Option<NegotiatedPeer>,
+    // What the invalid headers and blocks it sent count against it
+    misbehavior: u32,
+    connected_at: Instant,
+    // Nonce and send time of the ping awaiting its pong
+    ping: Option<(u64, Instant)>,
+    min_ping: Option<Duration>,
+    // Last time the peer sent a block or transaction we took in
+    last_block: Option<Instant>,
+    last_tx: Option<Instant>,
+    queue: SendQueue,
+    // Wakes the peer's writer when `queue` gains a message
+    wake: Arc<Notify>,
+    // Cancelled to drop the connection
+    disconnect: CancellationToken,
+}
+
+impl Peer {
+    fn new(address: SocketAddr, inbound: bool, relay: bool, wake: Arc<Notify>, disconnect: CancellationToken) -> Self {
+        Peer {
+            address,
+            inbound,
+            relay,
+            negotiated: None,
+            misbehavior: 0,
+            connected_at: Instant::now(),
+            ping: None,
+            min_ping: None,
+            last_block: None,
+            last_tx: None,
+            queue: SendQueue::default(),
+            wake,
+            disconnect,
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.negotiated.is_some()
+    }
+}
+
+// The chainstate being loaded from peers' snapshots. Headers from genesis are gathered until a peer
+// has no more; the block after the latest snapshot height below the best header is then fetched
+// for the commitment in its coinbase, and the snapshot at its parent chunk by chunk.
+struct SnapshotBootstrap {
+    headers: HeaderChain,
+    // Headers from genesis to the committing block, once it is picked
+    chain: Vec<BlockHeader>,
+    block: Option<Block>,
+    sync: Option<SnapshotSync>,
+    // Chunks asked for, and the peer each was asked of
+    requested: HashMap<u32, PeerId>,
+    // Peers that answered notfound for the snapshot, not asked for its chunks again
+    lacking: HashSet<PeerId>,
+}
+
+impl SnapshotBootstrap {
+    fn new(params: ChainParams) -> Self {
+        SnapshotBootstrap { headers: HeaderChain::new(params), chain: Vec::new(), block: None, sync: None, requested: HashMap::new(), lacking: HashSet::new() }
+    }
+
+    fn committing(&self) -> Option<BlockHash> {
+        self.chain.last().map(BlockHeader::hash)
+    }
+}
+
+pub struct PeerManager {
+    blockchain: Arc<Blockchain>,
+    mempool: Arc<Mutex<Mempool>>,
+    addrman: Arc<Mutex<AddrManager>>,
+    config: NetworkConfig,
+    services: u64,
+    codec: MessageCodec,
+    encryption: Option<Encryption>,
+    // Sent in every version message so a connection to ourselves is recognised
+    nonce: u64,
+    next_peer_id: AtomicU64,
+    peers: Mutex<HashMap<PeerId, Peer>>,
+    eviction: EvictionPolicy,
+    outbound: Mutex<OutboundSlots>,
+    // Locked after `peers` or `trickle` when both are held
+    inventory: Mutex<InventoryTracker>,
+    trickle: Mutex<TrickleRelay>,
+    // Wakes the trickle task when `trickle` gains an announcement
+    trickle_wake: Notify,
+    // Transactions submitted through this node, announced until they confirm. Locked before `trickle`.
+    broadcast: Mutex<BroadcastManager>,
+    // Saved block-relay-only peers still to connect to, ahead of the address manager's picks
+    anchors: Mutex<Vec<NetAddress>>,
+    downloads: Mutex<BlockDownloads>,
+    // Where snapshots are taken for peers, see `with_snapshots`, and those kept, oldest first
+    snapshot_dir: Option<PathBuf>,
+    snapshots: Mutex<VecDeque<Snapshot>>,
+    // Set until the chainstate is loaded, see `with_snapshot_sync`. Held across the loading of chunks.
+    bootstrap: tokio::sync::Mutex<Option<SnapshotBootstrap>>,
+    shutdown: CancellationToken,
+}
+
+impl PeerManager {
+    // `services` are the service flags this node advertises, see `protocol::local_services`
+    pub fn new(blockchain: Arc<Blockchain>, mempool: Arc<Mutex<Mempool>>, addrman: Arc<Mutex<AddrManager>>, config: NetworkConfig, services: u64, shutdown: CancellationToken) -> Self {
+        let codec = MessageCodec::new(blockchain.params());
+        let outbound = OutboundSlots::from_config(&config, Instant::now());
+        PeerManager {
+            blockchain,
+            mempool,
+            addrman,
+            config,
+            services,
+            codec,
+            encryption: None,
+            nonce: rand::random(),
+            next_peer_id: AtomicU64::new(0),
+            peers: Mutex::new(HashMap::new()),
+            eviction: EvictionPolicy::new(),
+            outbound: Mutex::new(outbound),
+            inventory: Mutex::new(InventoryTracker::new()),
+            trickle: Mutex::new(TrickleRelay::new(Instant::now())),
+            trickle_wake: Notify::new(),
+            broadcast: Mutex::new(BroadcastManager::default()),
+            anchors: Mutex::new(Vec::new()),
+            downloads: Mutex::new(BlockDownloads::new()),
+            snapshot_dir: None,
+            snapshots: Mutex::new(VecDeque::new()),
+            bootstrap: tokio::sync::Mutex::new(None),
+            shutdown,
+        }
+    }
+
+    // Runs every connection, inbound and outbound, under Noise with `key`. Peers must present one
+    // of the `trusted` keys, or any key if it's empty.
+    pub fn with_encryption(mut self, key: NodeKey, trusted: HashSet<Vec<u8>>) -> Self {
+        self.encryption = Some(Encryption { key, trusted });
+        self
+    }
+
+    // Fills the block-relay-only slots from `anchors`, saved by the last run, before anything else
+    pub fn with_anchors(mut self, anchors: Vec<NetAddress>) -> Self {
+        self.anchors = Mutex::new(anchors);
+        self
+    }
+
+    // Takes a snapshot of the UTXO set into `dir` at each `ChainParams::snapshot_interval` height
+    // once caught up, for peers to sync from. `services` should include NODE_UTXO_SNAPSHOTS.
+    // Snapshots an earlier run left in `dir` are removed, as their manifests weren't kept.
+    pub fn with_snapshots(mut self, dir: PathBuf) -> Self {
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Removing old snapshots from {} failed: {}", dir.display(), e);
+            }
+        }
+        self.snapshot_dir = Some(dir);
+        self
+    }
+
+    // Loads the UTXO set of an empty chainstate from peers' snapshots rather than downloading every
+    // block, see `SnapshotBootstrap`. Blocks are downloaded as usual once it is loaded, or straight
+    // away if the chain is too short for a snapshot or commits to none.
+    pub fn with_snapshot_sync(mut self) -> Self {
+        self.bootstrap = tokio::sync::Mutex::new(Some(SnapshotBootstrap::new(self.blockchain.params().clone())));
+        self
+    }
+
+    // The block-relay-only peers to save as anchors, see `outbound::save_anchors`. Peers dropped by
+    // shutdown still count, so after `run` returns these are the ones connected when it began.
+    pub fn anchors(&self) -> Vec<NetAddress> {
+        self.outbound.lock().anchors()
+    }
+
+    // Keeps announcing `txid`, a transaction submitted through this node and accepted into the
+    // mempool, to peers that don't have it: to each new peer, and again every rebroadcast round
+    // until it confirms or expires
+    pub fn broadcast_transaction(&self, txid: TxHash) {
+        self.broadcast.lock().submit(txid, Instant::now());
+        self.queue_broadcasts();
+    }
+
+    // Accepts inbound connections on `listener` and keeps the outbound slots filled until shutdown.
+    // Once the inbound slots are full a newcomer takes the place of a peer `EvictionPolicy` picks.
+    pub async fn run(self: Arc<Self>, listener: TcpListener) {
+        tokio::spawn(Arc::clone(&self).relay_blocks());
+        tokio::spawn(Arc::clone(&self).relay_transactions());
+        tokio::spawn(Arc::clone(&self).trickle_transactions());
+        tokio::spawn(Arc::clone(&self).rebroadcast_transactions());
+        tokio::spawn(Arc::clone(&self).fill_outbound());
+        tokio::spawn(Arc::clone(&self).ping_peers());
+        tokio::spawn(Arc::clone(&self).watch_downloads());
+        if self.snapshot_dir.is_some() {
+            tokio::spawn(Arc::clone(&self).take_snapshots());
+        }
+        loop {
+            let (stream, address) = tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("Accepting a peer connection failed: {}", e);
+                        continue;
+                    }
+                },
+            };
+            match self.eviction.admit_inbound(&self.eviction_candidates(), self.config.max_inbound) {
+                InboundDecision::Accept => {}
+                InboundDecision::Evict(id) => {
+                    log::debug!("Evicting peer {} to make room for {}", id, address);
+                    if let Some(peer) = self.peers.lock().get(&id) {
+                        peer.disconnect.cancel();
+                    }
+                }
+                InboundDecision::Refuse => {
+                    log::debug!("Refusing {}: all {} inbound slots are taken by peers kept from eviction", address, self.config.max_inbound);
+                    continue;
+                }
+            }
+            let id = self.next_peer_id.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(Arc::clone(&self).serve(id, stream, address, None));
+        }
+    }
+
+    // Opens an outbound connection of type `kind` to `address` and serves it in the background.
+    // The connection takes one of the outbound slots until it closes.
+    pub async fn connect(self: &Arc<Self>, address: NetAddress, kind: ConnectionType) -> Result<(), NetError> {
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address.addr)).await.map_err(|_| NetError::ConnectTimeout)??;
+        let id = self.next_peer_id.fetch_add(1, Ordering::Relaxed);
+        self.outbound.lock().connected(id, address, kind, Instant::now());
+        tokio::spawn(Arc::clone(self).serve(id, stream, address.addr, Some(kind)));
+        Ok(())
+    }
+
+    // Peers past the handshake
+    pub fn peer_count(&self) -> usize {
+        self.peers.lock().values().filter(|peer| peer.is_ready()).count()
+    }
+
+    // Inbound peers still connected. Peers that authenticated with a trusted key are never evicted.
+    fn eviction_candidates(&self) -> Vec<EvictionCandidate> {
+        let protected = self.encryption.as_ref().is_some_and(|encryption| !encryption.trusted.is_empty());
+        self.peers.lock().iter()
+            .filter(|(_, peer)| peer.inbound && !peer.disconnect.is_cancelled())
+            .map(|(&id, peer)| EvictionCandidate {
+                peer: id,
+                ip: peer.address.ip(),
+                connected_at: peer.connected_at,
+                min_ping: peer.min_ping,
+                last_block: peer.last_block,
+                last_tx: peer.last_tx,
+                protected,
+            })
+            .collect()
+    }
+
+    fn is_connected(&self, address: &SocketAddr) -> bool {
+        self.peers.lock().values().any(|peer| peer.address == *address)
+    }
+
+    // `relay` asks the peer to announce transactions to us
+    fn version_message(&self, relay: bool) -> VersionMessage {
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: self.services,
+            // Our own clock, not the adjusted one, so peers' offsets don't feed back into each other
+            timestamp: local_time(),
+            nonce: self.nonce,
+            user_agent: USER_AGENT.to_string(),
+            start_height: self.blockchain.get_chain_height(),
+            relay,
+        }
+    }
+
+    // `kind` is None for inbound connections
+    async fn serve(self: Arc<Self>, id: PeerId, stream: TcpStream, address: SocketAddr, kind: Option<ConnectionType>) {
+        match self.open(stream, kind.is_none()).await {
+            Ok((reader, writer)) => self.exchange_messages(id, address, kind, reader, writer).await,
+            Err(e) => log::debug!("Connection with {} failed: {}", address, e),
+        }
+        // Outbound peers dropped by shutdown keep their slots, to be saved as anchors
+        if kind.is_some() && !self.shutdown.is_cancelled() {
+            self.outbound.lock().disconnected(id);
+        }
+    }
+
+    async fn exchange_messages(self: &Arc<Self>, id: PeerId, address: SocketAddr, kind: Option<ConnectionType>, mut reader: MessageReader, writer: MessageWriter) {
+        let relay = kind.is_none_or(ConnectionType::relays_transactions);
+        let wake = Arc::new(Notify::new());
+        let disconnect = self.shutdown.child_token();
+        self.peers.lock().insert(id, Peer::new(address, kind.is_none(), relay, Arc::clone(&wake), disconnect.clone()));
+
+        let writer = tokio::spawn(Arc::clone(self).write_queued(id, writer, wake, disconnect.clone()));
+        if let Err(e) = self.read_messages(id, address, kind, &mut reader, &disconnect).await {
+            log::debug!("Peer {} ({}) disconnected: {}", id, address, e);
+        }
+        disconnect.cancel();
+        let _ = writer.await;
+
+        let was_ready = self.peers.lock().remove(&id).is_some_and(|peer| peer.is_ready());
+        self.inventory.lock().peer_disconnected(id);
+        self.trickle.lock().peer_disconnected(id);
+        self.broadcast.lock().peer_disconnected(id);
+        if was_ready {
+            log::info!("Peer {} ({}) disconnected", id, address);
+            self.downloads.lock().peer_disconnected(id);
+            self.schedule_downloads();
+            if let Some(bootstrap) = self.bootstrap.lock().await.as_mut() {
+                bootstrap.requested.retain(|_, peer| *peer != id);
+                bootstrap.lacking.remove(&id);
+                self.request_snapshot_chunks(bootstrap);
+            }
+        }
+    }
+
+    // Splits `stream` between the peer's reader and writer, after the Noise handshake if encryption is on
+    async fn open(&self, stream: TcpStream, inbound: bool) -> Result<(MessageReader, MessageWriter), NetError> {
+        let Some(encryption) = &self.encryption else {
+            let (reader, writer) = stream.into_split();
+            return Ok((MessageReader::Plain(FramedRead::new(reader, self.codec.clone())), MessageWriter::Plain(writer)));
+        };
+        let handshake = async {
+            match inbound {
+                true => SecureStream::accept(stream, &encryption.key, &encryption.trusted).await,
+                false => SecureStream::connect(stream, &encryption.key, &encryption.trusted).await,
+            }
+        };
+        let secure = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await.map_err(|_| NetError::HandshakeTimeout)??;
+        let (reader, writer) = secure.into_split();
+        Ok((MessageReader::Encrypted { reader, codec: self.codec.clone() }, MessageWriter::Encrypted(writer)))
+    }
+
+    async fn write_queued(self: Arc<Self>, id: PeerId, mut writer: MessageWriter, wake: Arc<Notify>, disconnect: CancellationToken) {
+        loop {
+            let frame = self.peers.lock().get_mut(&id).and_then(|peer| peer.queue.pop());
+            let Some(frame) = frame else {
+                tokio::select! {
+                    _ = disconnect.cancelled() => return,
+                    _ = wake.notified() => continue,
+                }
+            };
+            let written = tokio::select! {
+                _ = disconnect.cancelled() => return,
+                written = writer.write(&frame) => written,
+            };
+            if let Err(e) = written {
+                log::debug!("Writing to peer {} failed: {}", id, e);
+                disconnect.cancel();
+                return;
+            }
+        }
+    }
+
+    async fn read_messages(&self, id: PeerId, address: SocketAddr, kind: Option<ConnectionType>, reader: &mut MessageReader, disconnect: &CancellationToken) -> Result<(), NetError> {
+        let relay = kind.is_none_or(ConnectionType::relays_transactions);
+        let negotiated = tokio::select! {
+            _ = disconnect.cancelled() => return Ok(()),
+            negotiated = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.handshake(id, reader, relay)) => negotiated.map_err(|_| NetError::HandshakeTimeout)??,
+        };
+        log::info!("Connected to peer {} ({}, {}) at height {:?}", id, address, negotiated.user_agent, negotiated.start_height);
+        if let Some(peer) = self.peers.lock().get_mut(&id) {
+            peer.negotiated = Some(negotiated.clone());
+        }
+        // Only peers we picked sample the clock, so connecting in numbers can't shift our time
+        if kind.is_some() {
+            self.blockchain.network_time().add_sample(address.ip(), negotiated.time_offset);
+            self.addrman.lock().good(&address, local_time());
+        }
+        if kind == Some(ConnectionType::FullRelay) {
+            self.send(id, Message::GetAddr);
+        }
+        self.downloads.lock().peer_connected(id, Instant::now());
+        if relay && negotiated.relay {
+            self.trickle.lock().peer_connected(id, kind.is_none(), Instant::now());
+            self.broadcast.lock().peer_connected(id);
+            self.queue_broadcasts();
+        }
+        match self.bootstrap.lock().await.as_mut() {
+            Some(bootstrap) => self.join_bootstrap(id, bootstrap),
+            None => self.send(id, Message::GetHeaders { locator: self.blockchain.block_locator(), stop_hash: [0; 32] }),
+        }
+        self.ping(id);
+
+        let mut limiter = PeerRateLimiter::new(Instant::now());
+        loop {
+            let message = tokio::select! {
+                _ = disconnect.cancelled() => return Ok(()),
+                message = reader.next() => message.ok_or(NetError::Closed)??,
+            };
+            rate_limit::check_message_entries(&message)?;
+            limiter.check(&message, Instant::now())?;
+            if !negotiated.accepts(&message) {
+                return Err(NetError::NotNegotiated(message.command()));
+            }
+            self.handle(id, address, relay, message).await?;
+        }
+    }
+
+    async fn handshake(&self, id: PeerId, reader: &mut MessageReader, relay: bool) -> Result<NegotiatedPeer, NetError> {
+        let mut handshake = Handshake::new(self.version_message(relay));
+        self.send(id, handshake.start());
+        while !handshake.is_complete() {
+            let message = reader.next().await.ok_or(NetError::Closed)??;
+            for reply in handshake.on_message(message)? {
+                self.send(id, reply);
+            }
+        }
+        Ok(handshake.negotiated().expect("handshake is complete"))
+    }
+
+    // Without `relay`, transactions, fruits and addresses the peer sends are ignored
+    async fn handle(&self, id: PeerId, address: SocketAddr, relay: bool, message: Message) -> Result<(), NetError> {
+        match message {
+            Message::Version(_) => return Err(HandshakeError::DuplicateVersion.into()),
+            Message::Ping(nonce) => self.send(id, Message::Pong(nonce)),
+            Message::Pong(nonce) => {
+                if let Some(peer) = self.peers.lock().get_mut(&id) {
+                    if let Some((_, sent_at)) = peer.ping.filter(|&(sent, _)| sent == nonce) {
+                        let round_trip = sent_at.elapsed();
+                        peer.min_ping = Some(peer.min_ping.map_or(round_trip, |min_ping| min_ping.min(round_trip)));
+                        peer.ping = None;
+                    }
+                }
+            }
+            Message::GetAddr if relay => {
+                let addresses = self.addrman.lock().get_addr(local_time());
+                self.send(id, Message::Addr(addresses));
+            }
+            Message::Addr(addresses) if relay => {
+                self.addrman.lock().add(&addresses, address.ip(), local_time());
+            }
+            // Until the chainstate is loaded, headers and blocks only serve the snapshot sync
+            Message::Headers(_) | Message::Block(_) | Message::NotFound(_) | Message::Snapshot(_) | Message::SnapshotChunk(_) if self.bootstrap.lock().await.is_some() => {
+                self.receive_bootstrap(id, message).await?;
+            }
+            Message::Inv(items) => self.receive_inv(id, relay, items).await?,
+            Message::GetData(items) => self.serve_data(id, items).await?,
+            Message::NotFound(items) => {
+                let now = Instant::now();
+                let mut downloads = self.downloads.lock();
+                for item in items {
+                    if let Inventory::Block(hash) = item {
+                        if downloads.received(id, &hash, now) {
+                            downloads.failed(id, &hash);
+                        }
+                    }
+                }
+                drop(downloads);
+                self.schedule_downloads();
+            }
+            Message::GetHeaders { locator, stop_hash } => {
+                let headers = self.blockchain.headers_for_locator(&locator, &stop_hash).map_err(chain_error)?;
+                self.send(id, Message::Headers(headers));
+            }
+            Message::Headers(headers) => self.receive_headers(id, headers).await?,
+            Message::Block(block) => self.receive_block(id, block).await?,
+            Message::Tx(tx) if relay => self.receive_transaction(id, tx).await,
+            Message::Fruit(fruit) if relay => self.receive_fruit(id, fruit).await?,
+            Message::GetCFilters { start_height, count } => {
+                let end = start_height.saturating_add(count.min(MAX_FILTERS_PER_REQUEST));
+                for (block_hash, filter, _) in self.blockchain.get_block_filters(start_height..end).await.map_err(chain_error)? {
+                    self.send(id, Message::CFilter { block_hash, filter });
+                }
+            }
+            Message::GetCFHeaders { start_height, count } => {
+                let end = start_height.saturating_add(count.min(MAX_FILTERS_PER_REQUEST));
+                let filters = self.blockchain.get_block_filters(start_height..end).await.map_err(chain_error)?;
+                self.send(id, Message::CFHeaders(filters.into_iter().map(|(block_hash, _, header)| (block_hash, header)).collect()));
+            }
+            Message::GetSnapshot { block_hash } => {
+                let manifest = self.snapshots.lock().iter().find(|snapshot| snapshot.manifest.block_hash == block_hash).map(|snapshot| snapshot.manifest.clone());
+                self.send(id, match manifest {
+                    Some(manifest) => Message::Snapshot(manifest),
+                    None => Message::NotFound(vec![Inventory::Block(block_hash)]),
+                });
+            }
+            Message::GetSnapshotChunk { block_hash, index } => self.serve_snapshot_chunk(id, block_hash, index).await,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Asks for the headers leading to announced blocks we lack, and for the transactions and fruits.
+    // Items that arrived lately from another peer aren't fetched again.
+    async fn receive_inv(&self, id: PeerId, relay: bool, items: Vec<Inventory>) -> Result<(), NetError> {
+        // Transactions can't be checked against a UTXO set that is still catching up
+        let initial_block_download = self.blockchain.is_initial_block_download();
+        self.mark_broadcast_seen(id, &items);
+        let items = self.inventory.lock().wanted(id, &items);
+        let mut unknown_block = false;
+        let mut wanted = Vec::new();
+        for item in items {
+            match item {
+                Inventory::Block(hash) => {
+                    if !self.blockchain.has_block(&hash).await.map_err(chain_error)? {
+                        self.downloads.lock().announced(id, hash);
+                        unknown_block = true;
+                    }
+                }
+                Inventory::Transaction(txid) => {
+                    if relay && !initial_block_download && !self.mempool.lock().already_have(&txid) {
+                        wanted.push(item);
+                    }
+                }
+                Inventory::Fruit(fruit_id) => {
+                    if relay && self.mempool.lock().get_fruit(&fruit_id).is_none() {
+                        wanted.push(item);
+                    }
+                }
+            }
+        }
+        if unknown_block {
+            self.send(id, Message::GetHeaders { locator: self.blockchain.block_locator(), stop_hash: [0; 32] });
+        }
+        if !wanted.is_empty() {
+            self.send(id, Message::GetData(wanted));
+        }
+        Ok(())
+    }
+
+    async fn serve_data(&self, id: PeerId, items: Vec<Inventory>) -> Result<(), NetError> {
+        self.mark_broadcast_seen(id, &items);
+        let mut not_found = Vec::new();
+        for item in items {
+            let message = match item {
+                Inventory::Block(hash) => self.blockchain.get_block(&hash).await.map_err(chain_error)?.map(|block| Message::Block((*block).clone())),
+                Inventory::Transaction(txid) => self.mempool.lock().get_transaction(&txid).cloned().map(Message::Tx),
+                Inventory::Fruit(fruit_id) => self.mempool.lock().get_fruit(&fruit_id).cloned().map(Message::Fruit),
+            };
+            match message {
+                Some(message) => {
+                    self.inventory.lock().mark_known(id, &item);
+                    self.send(id, message);
+                }
+                None => not_found.push(item),
+            }
+        }
+        if !not_found.is_empty() {
+            self.send(id, Message::NotFound(not_found));
+        }
+        Ok(())
+    }
+
+    // Headers pass every check they can without their blocks before any block is asked for, so a
+    // peer can't make us download bodies for headers without proof of work. The sender becomes a
+    // source of every block the headers lead to that we don't have. A full batch means it has
+    // more, asked for from the last header on.
+    async fn receive_headers(&self, id: PeerId, headers: Vec<BlockHeader>) -> Result<(), NetError> {
+        let rejected = match self.blockchain.accept_headers(&headers).await {
+            Ok(_) => None,
+            Err(e) => Some(validation_error(e)?),
+        };
+        if let Some(e) = rejected {
+            log::info!("Headers from peer {} refused: {}", id, e);
+            self.misbehaving(id, &e);
+            return Ok(());
+        }
+        let mut wanted = Vec::new();
+        for header in &headers {
+            let hash = header.hash();
+            if !self.blockchain.has_block(&hash).await.map_err(chain_error)? {
+                let height = self.blockchain.header_height(&hash).ok_or_else(|| NetError::Chain("accepted header went missing".to_string()))?;
+                wanted.push((height, hash));
+            }
+        }
+        {
+            let mut downloads = self.downloads.lock();
+            for &(_, hash) in &wanted {
+                downloads.announced(id, hash);
+            }
+            downloads.want(wanted);
+        }
+        self.schedule_downloads();
+        if headers.len() == MAX_HEADERS_PER_MESSAGE {
+            let last = headers[headers.len() - 1].hash();
+            self.send(id, Message::GetHeaders { locator: vec![last], stop_hash: [0; 32] });
+        }
+        Ok(())
+    }
+
+    // A block counts as downloaded once it validates; one that doesn't is asked of another peer
+    async fn receive_block(&self, id: PeerId, block: Block) -> Result<(), NetError> {
+        let hash = block.hash();
+        self.downloads.lock().received(id, &hash, Instant::now());
+        if self.blockchain.has_block(&hash).await.map_err(chain_error)? {
+            self.downloads.lock().validated(&hash);
+            self.schedule_downloads();
+            return Ok(());
+        }
+        let rejected = match self.blockchain.add_block(block).await {
+            Ok(()) => None,
+            Err(e) => Some(validation_error(e)?),
+        };
+        match rejected {
+            None => {
+                let now = Instant::now();
+                self.inventory.lock().received(Some(id), &Inventory::Block(hash));
+                self.downloads.lock().validated(&hash);
+                self.outbound.lock().block_received(id, now);
+                if let Some(peer) = self.peers.lock().get_mut(&id) {
+                    peer.last_block = Some(now);
+                }
+            }
+            // Not a block we can place; the sender's headers past our locator lead to it
+            Some(ValidationError::UnknownParent(_)) => {
+                self.send(id, Message::GetHeaders { locator: self.blockchain.block_locator(), stop_hash: [0; 32] });
+            }
+            Some(e) => {
+                log::info!("Block {} from peer {} is invalid: {}", hex::encode(hash), id, e);
+                self.downloads.lock().failed(id, &hash);
+                self.misbehaving(id, &e);
+            }
+        }
+        self.schedule_downloads();
+        Ok(())
+    }
+
+    async fn receive_transaction(&self, id: PeerId, tx: Transaction) {
+        let txid = tx.hash();
+        self.inventory.lock().received(Some(id), &Inventory::Transaction(txid));
+        if self.mempool.lock().already_have(&txid) {
+            return;
+        }
+        let fee = self.blockchain
+            .package_fees(std::slice::from_ref(&tx), |outpoint| {
+                let mempool = self.mempool.lock();
+                mempool.get_transaction(&outpoint.txid)?.outputs.get(outpoint.index as usize).cloned()
+            })
+            .await
+            .map_err(|e| e.to_string());
+        let next_height = self.blockchain.get_chain_height().map_or(0, |height| height + 1);
+        let outcome = fee.and_then(|fees| {
+            self.mempool.lock().add_transaction(tx, fees[0], next_height, self.blockchain.median_time_past()).map_err(|e| e.to_string())
+        });
+        match outcome {
+            Ok(()) => {
+                if let Some(peer) = self.peers.lock().get_mut(&id) {
+                    peer.last_tx = Some(Instant::now());
+                }
+            }
+            Err(e) => log::debug!("Transaction {} from peer {} refused: {}", hex::encode(txid), id, e),
+        }
+    }
+
+    async fn receive_fruit(&self, id: PeerId, fruit: SignedBlock) -> Result<(), NetError> {
+        let fruit_id = fruit.block.fruit_id();
+        self.inventory.lock().received(Some(id), &Inventory::Fruit(fruit_id));
+        let Some(hang_from) = fruit.block.fruit_header.as_ref().map(|header| header.hang_from) else { return Ok(()) };
+        let anchor_height = self.blockchain.get_block_height(&hang_from).await.map_err(chain_error)?;
+        let tip_height = self.blockchain.get_chain_height().unwrap_or(0);
+        let added = self.mempool.lock().add_fruit(fruit, anchor_height, tip_height);
+        match added {
+            Ok(()) => self.announce(Inventory::Fruit(fruit_id), |_, peer| peer.relay && peer.is_ready()),
+            Err(e) => log::debug!("Fruit {} from peer {} refused: {}", hex::encode(fruit_id), id, e),
+        }
+        Ok(())
+    }
+
+    // Chunks are read from disk off the async threads. A chunk of a snapshot no longer kept is
+    // answered with notfound for its block, as its manifest would be.
+    async fn serve_snapshot_chunk(&self, id: PeerId, block_hash: BlockHash, index: u32) {
+        let snapshot = self.snapshots.lock().iter().find(|snapshot| snapshot.manifest.block_hash == block_hash).cloned();
+        let chunk = match snapshot {
+            Some(snapshot) => tokio::task::spawn_blocking(move || snapshot.chunk(&block_hash, index)).await
+                .map_err(|e| e.to_string())
+                .and_then(|chunk| chunk.map_err(|e| e.to_string()))
+                // Most likely removed just now, as a newer snapshot replaced it
+                .unwrap_or_else(|e| {
+                    log::debug!("Reading chunk {} of snapshot {} failed: {}", index, hex::encode(block_hash), e);
+                    None
+                }),
+            None => None,
+        };
+        self.send(id, match chunk {
+            Some(chunk) => Message::SnapshotChunk(chunk),
+            None => Message::NotFound(vec![Inventory::Block(block_hash)]),
+        });
+    }
+
+    // Takes a snapshot at each snapshot height the chain reaches once caught up
+    async fn take_snapshots(self: Arc<Self>) {
+        let mut events = self.blockchain.subscribe();
+        loop {
+            let event = tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                event = events.recv() => event,
+            };
+            match event {
+                Ok(ChainEvent::BlockConnected { .. }) if !self.blockchain.is_initial_block_download() => {
+                    if let Err(e) = self.take_snapshot().await {
+                        log::warn!("Taking a UTXO snapshot failed: {}", e);
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    // Snapshot of the UTXO set at the tip, if the tip is at a snapshot height and the block after it
+    // will commit to the set
+    async fn take_snapshot(&self) -> Result<(), NetError> {
+        let Some(dir) = &self.snapshot_dir else { return Ok(()) };
+        let interval = self.blockchain.params().snapshot_interval;
+        let height = self.blockchain.get_chain_height().unwrap_or(0);
+        if height == 0 || height % interval != 0 || !self.blockchain.is_deployment_active(UTXO_COMMITMENT_DEPLOYMENT) {
+            return Ok(());
+        }
+        let tip = self.blockchain.get_chain_tip();
+        if self.snapshots.lock().iter().any(|snapshot| snapshot.manifest.block_hash == tip) {
+            return Ok(());
+        }
+        let snapshot = self.blockchain.create_snapshot(dir).await.map_err(chain_error)?;
+        // The tip moved on while the set was read
+        if snapshot.manifest.height % interval != 0 {
+            return Ok(snapshot.remove()?);
+        }
+        log::info!("Took a snapshot of {} coins at height {} for peers to sync from", snapshot.manifest.coins, snapshot.manifest.height);
+        let removed = {
+            let mut snapshots = self.snapshots.lock();
+            snapshots.push_back(snapshot);
+            let excess = snapshots.len().saturating_sub(SNAPSHOTS_KEPT);
+            snapshots.drain(..excess).collect::<Vec<_>>()
+        };
+        for snapshot in removed {
+            snapshot.remove()?;
+        }
+        Ok(())
+    }
+
+    // Brings a peer that just connected into the snapshot sync at the stage it has reached
+    fn join_bootstrap(&self, id: PeerId, bootstrap: &mut SnapshotBootstrap) {
+        match (bootstrap.committing(), &bootstrap.sync) {
+            (None, _) => {
+                let locator = bootstrap.headers.tip().map(|tip| vec![tip.header.hash()]).unwrap_or_default();
+                self.send(id, Message::GetHeaders { locator, stop_hash: [0; 32] });
+            }
+            (Some(committing), None) => self.send(id, Message::GetData(vec![Inventory::Block(committing)])),
+            (Some(_), Some(sync)) if !sync.has_manifest() => self.send(id, Message::GetSnapshot { block_hash: sync.commitment().block_hash }),
+            (Some(_), Some(_)) => self.request_snapshot_chunks(bootstrap),
+        }
+    }
+
+    // Messages the snapshot sync takes over until the chainstate is loaded
+    async fn receive_bootstrap(&self, id: PeerId, message: Message) -> Result<(), NetError> {
+        let mut slot = self.bootstrap.lock().await;
+        let Some(bootstrap) = slot.as_mut() else { return Ok(()) };
+        match message {
+            Message::Headers(headers) => self.bootstrap_headers(id, headers, &mut slot),
+            Message::Block(block) if bootstrap.block.is_none() && bootstrap.committing() == Some(block.hash()) => {
+                self.bootstrap_block(block, &mut slot).await?;
+            }
+            Message::NotFound(items) => {
+                let Some(sync) = &bootstrap.sync else { return Ok(()) };
+                if items.contains(&Inventory::Block(sync.commitment().block_hash)) {
+                    bootstrap.lacking.insert(id);
+                    bootstrap.requested.retain(|_, peer| *peer != id);
+                    self.request_snapshot_chunks(bootstrap);
+                }
+            }
+            Message::Snapshot(manifest) => {
+                let Some(sync) = bootstrap.sync.as_mut().filter(|sync| !sync.has_manifest()) else { return Ok(()) };
+                match sync.accept_manifest(manifest) {
+                    // Its set was found not to be the committed one
+                    Err(SnapshotError::RejectedManifest(_)) => return Ok(()),
+                    result => result?,
+                }
+                self.request_snapshot_chunks(bootstrap);
+            }
+            Message::SnapshotChunk(chunk) => {
+                // Only chunks asked of this peer, so none of a manifest rejected since
+                if bootstrap.requested.get(&chunk.index) != Some(&id) {
+                    return Ok(());
+                }
+                bootstrap.requested.remove(&chunk.index);
+                let Some(sync) = bootstrap.sync.as_mut() else { return Ok(()) };
+                sync.accept_chunk(chunk)?;
+                self.load_snapshot_chunks(&mut slot).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Gathers headers from genesis until a peer has no more, then asks for the block committing to
+    // the set at the latest snapshot height below the best header
+    fn bootstrap_headers(&self, id: PeerId, headers: Vec<BlockHeader>, slot: &mut Option<SnapshotBootstrap>) {
+        let Some(bootstrap) = slot.as_mut().filter(|bootstrap| bootstrap.chain.is_empty()) else { return };
+        if let Err(e) = bootstrap.headers.accept_headers(&headers) {
+            log::info!("Headers from peer {} refused: {}", id, e);
+            return;
+        }
+        if headers.len() == MAX_HEADERS_PER_MESSAGE {
+            self.send(id, Message::GetHeaders { locator: vec![headers[headers.len() - 1].hash()], stop_hash: [0; 32] });
+            return;
+        }
+        let Some(best) = bootstrap.headers.height() else { return };
+        let interval = self.blockchain.params().snapshot_interval;
+        let base = best.saturating_sub(1) / interval * interval;
+        if base == 0 {
+            log::info!("The chain is too short for a snapshot; downloading every block");
+            self.end_bootstrap(slot);
+            return;
+        }
+        bootstrap.chain = (0..=base + 1)
+            .filter_map(|height| bootstrap.headers.hash_at_height(height).and_then(|hash| bootstrap.headers.get(&hash)))
+            .map(|entry| entry.header.clone())
+            .collect();
+        log::info!("Syncing the UTXO set from a snapshot at height {}", base);
+        if let Some(committing) = bootstrap.committing() {
+            self.send_where(Message::GetData(vec![Inventory::Block(committing)]), |_, peer| peer.is_ready());
+        }
+    }
+
+    // Takes the commitment from the committing block's coinbase and asks for the snapshot, carrying
+    // on with a load of the same snapshot this node was stopped in
+    async fn bootstrap_block(&self, block: Block, slot: &mut Option<SnapshotBootstrap>) -> Result<(), NetError> {
+        let Some(bootstrap) = slot.as_mut() else { return Ok(()) };
+        let mut sync = match SnapshotSync::for_block(&bootstrap.chain, &block) {
+            Ok(sync) => sync,
+            Err(SnapshotError::NoCommitment(hash)) => {
+                log::info!("Block {} commits to no UTXO set; downloading every block", hex::encode(hash));
+                self.end_bootstrap(slot);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(load) = self.blockchain.snapshot_load().await.map_err(chain_error)? {
+            if sync.resume(&load).is_err() {
+                self.blockchain.abort_snapshot_load().await.map_err(chain_error)?;
+            }
+        }
+        if !sync.has_manifest() {
+            self.send_where(Message::GetSnapshot { block_hash: sync.commitment().block_hash }, |_, peer| peer.is_ready());
+        }
+        bootstrap.block = Some(block);
+        bootstrap.sync = Some(sync);
+        self.load_snapshot_chunks(slot).await
+    }
+
+    // Spreads the chunks still missing over the peers offering snapshots, up to
+    // SNAPSHOT_CHUNKS_IN_FLIGHT each
+    fn request_snapshot_chunks(&self, bootstrap: &mut SnapshotBootstrap) {
+        let Some(sync) = &bootstrap.sync else { return };
+        let Some(block_hash) = sync.manifest().map(|manifest| manifest.block_hash) else { return };
+        let mut missing = sync.missing_chunks().into_iter().filter(|index| !bootstrap.requested.contains_key(index)).collect::<Vec<_>>();
+        let sources = self.peers.lock().iter()
+            .filter(|(id, peer)| !bootstrap.lacking.contains(id) && peer.negotiated.as_ref().is_some_and(|negotiated| negotiated.has_service(NODE_UTXO_SNAPSHOTS)))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in sources {
+            let in_flight = bootstrap.requested.values().filter(|&&peer| peer == id).count();
+            let count = SNAPSHOT_CHUNKS_IN_FLIGHT.saturating_sub(in_flight).min(missing.len());
+            for index in missing.drain(..count) {
+                bootstrap.requested.insert(index, id);
+                self.send(id, Message::GetSnapshotChunk { block_hash, index });
+            }
+        }
+    }
+
+    // Loads the chunks that are next in line and asks for more. Once every one is in, the snapshot
+    // becomes the chainstate and the committing block goes on top of it like any block after it. A
+    // set that isn't the committed one has its manifest rejected and another asked for.
+    async fn load_snapshot_chunks(&self, slot: &mut Option<SnapshotBootstrap>) -> Result<(), NetError> {
+        let Some(bootstrap) = slot.as_mut() else { return Ok(()) };
+        let Some(sync) = bootstrap.sync.as_mut() else { return Ok(()) };
+        let Some(manifest) = sync.manifest().cloned() else { return Ok(()) };
+        let commitment = *sync.commitment();
+        let mut loaded = Ok(());
+        while loaded.is_ok() {
+            let Some(chunk) = sync.next_chunk() else { break };
+            loaded = self.blockchain.load_snapshot_chunk(&manifest, &chunk).await.map_err(snapshot_error);
+        }
+        if loaded.is_ok() && sync.is_complete() {
+            loaded = self.blockchain.finish_snapshot_load(&bootstrap.chain[..bootstrap.chain.len() - 1], &commitment).await.map_err(snapshot_error);
+        }
+        match loaded {
+            Ok(()) if sync.is_complete() => {}
+            Ok(()) => {
+                self.request_snapshot_chunks(bootstrap);
+                return Ok(());
+            }
+            Err(NetError::Snapshot(e @ (SnapshotError::UnsortedCoins(_) | SnapshotError::TotalsMismatch { .. } | SnapshotError::SetHashMismatch { .. }))) => {
+                log::warn!("Snapshot at block {} rejected: {}", hex::encode(commitment.block_hash), e);
+                sync.reject_manifest();
+                bootstrap.requested.clear();
+                self.send_where(Message::GetSnapshot { block_hash: commitment.block_hash }, |_, peer| peer.is_ready());
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+
+        let block = bootstrap.block.take().ok_or_else(|| NetError::Chain("committing block went missing".to_string()))?;
+        if let Err(e) = self.blockchain.add_block(block).await {
+            log::warn!("Committing block refused on top of the snapshot: {}", validation_error(e)?);
+        }
+        log::info!("Loaded the UTXO set from a snapshot at height {}", commitment.height);
+        self.end_bootstrap(slot);
+        Ok(())
+    }
+
+    // Leaves the snapshot sync for block download from the chainstate as it stands
+    fn end_bootstrap(&self, slot: &mut Option<SnapshotBootstrap>) {
+        *slot = None;
+        self.send_where(Message::GetHeaders { locator: self.blockchain.block_locator(), stop_hash: [0; 32] }, |_, peer| peer.is_ready());
+    }
+
+    // Counts what `e` says against peer `id`, disconnecting it once that reaches `DISCONNECT_SCORE`
+    fn misbehaving(&self, id: PeerId, e: &ValidationError) {
+        let score = e.misbehavior();
+        if score == 0 {
+            return;
+        }
+        let mut peers = self.peers.lock();
+        let Some(peer) = peers.get_mut(&id) else { return };
+        peer.misbehavior = peer.misbehavior.saturating_add(score);
+        if peer.misbehavior >= DISCONNECT_SCORE {
+            log::info!("Disconnecting peer {} for misbehavior: {}", id, e);
+            peer.disconnect.cancel();
+        }
+    }
+
+    async fn watch_downloads(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(STALL_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+            self.check_stalls(Instant::now());
+        }
+    }
+
+    // Asks other peers for the blocks stalling peers sit on, and drops repeat stallers
+    fn check_stalls(&self, now: Instant) {
+        let actions = self.downloads.lock().check_stalls(now);
+        for (peer, hashes) in actions.requests {
+            self.send(peer, Message::GetData(hashes.into_iter().map(Inventory::Block).collect()));
+        }
+        let peers = self.peers.lock();
+        for id in actions.disconnect {
+            if let Some(peer) = peers.get(&id) {
+                peer.disconnect.cancel();
+            }
+        }
+    }
+
+    // Pings peer `id` unless a ping to it is still unanswered
+    fn ping(&self, id: PeerId) {
+        let nonce = rand::random();
+        match self.peers.lock().get_mut(&id) {
+            Some(peer) if peer.ping.is_none() => peer.ping = Some((nonce, Instant::now())),
+            _ => return,
+        }
+        self.send(id, Message::Ping(nonce));
+    }
+
+    async fn ping_peers(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PING_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+            let ready = self.peers.lock().iter().filter(|(_, peer)| peer.is_ready()).map(|(&id, _)| id).collect::<Vec<_>>();
+            for id in ready {
+                self.ping(id);
+            }
+        }
+    }
+
+    // Asks peers for the next blocks to download, up to what each may have in flight
+    fn schedule_downloads(&self) {
+        let requests = self.downloads.lock().schedule(Instant::now());
+        for (peer, hashes) in requests {
+            self.send(peer, Message::GetData(hashes.into_iter().map(Inventory::Block).collect()));
+        }
+    }
+
+    fn send(&self, id: PeerId, message: Message) {
+        self.send_where(message, |peer_id, _| peer_id == id);
+    }
+
+    // Queues `message` for every peer `select` picks that may receive it. A peer whose queue
+    // overflows has stopped reading and is disconnected.
+    fn send_where(&self, message: Message, select: impl Fn(PeerId, &Peer) -> bool) {
+        let targets = self.peers.lock().iter()
+            .filter(|&(&id, peer)| peer.negotiated.as_ref().is_none_or(|negotiated| negotiated.can_send(&message)) && select(id, peer))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        if targets.is_empty() {
+            return;
+        }
+        let command = message.command();
+        let mut frame = BytesMut::new();
+        if let Err(e) = self.codec.clone().encode(message, &mut frame) {
+            log::warn!("Failed to encode a {} message: {}", command, e);
+            return;
+        }
+        let mut peers = self.peers.lock();
+        for id in targets {
+            let Some(peer) = peers.get_mut(&id) else { continue };
+            match peer.queue.push(frame.to_vec()) {
+                Ok(()) => peer.wake.notify_one(),
+                Err(e) => {
+                    log::info!("Disconnecting peer {}: {}", id, e);
+                    peer.disconnect.cancel();
+                }
+            }
+        }
+    }
+
+    // Announces `item` to the peers `select` picks that don't have it yet
+    fn announce(&self, item: Inventory, select: impl Fn(PeerId, &Peer) -> bool) {
+        self.send_where(Message::Inv(vec![item]), |id, peer| select(id, peer) && !self.inventory.lock().to_announce(id, [item]).is_empty());
+    }
+
+    // Announces each block the active chain takes in, once the node has caught up
+    async fn relay_blocks(self: Arc<Self>) {
+        let mut events = self.blockchain.subscribe();
+        loop {
+            let event = tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                event = events.recv() => event,
+            };
+            match event {
+                Ok(ChainEvent::BlockConnected { block, .. }) => {
+                    self.broadcast.lock().confirmed(&block.transactions.iter().map(Transaction::hash).collect::<Vec<_>>());
+                    if !self.blockchain.is_initial_block_download() {
+                        self.announce(Inventory::Block(block.hash()), |_, peer| peer.is_ready());
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    // Queues transactions entering the mempool for announcement to peers that asked for
+    // transaction relay, see `TrickleRelay`, and drops those that leave before their turn
+    async fn relay_transactions(self: Arc<Self>) {
+        let mut events = self.mempool.lock().subscribe();
+        loop {
+            let event = tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                event = events.recv() => event,
+            };
+            match event {
+                Ok(MempoolEvent::TransactionAdded(tx)) => {
+                    // The peer it came from already knows it, see `InventoryTracker::received`
+                    self.trickle.lock().announce(tx.hash(), None);
+                    self.trickle_wake.notify_one();
+                }
+                Ok(MempoolEvent::TransactionRemoved { txid, .. }) => self.trickle.lock().forget(&txid),
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    // Sends each peer its queued transaction announcements when its turn comes
+    async fn trickle_transactions(self: Arc<Self>) {
+        loop {
+            let wakeup = self.trickle.lock().next_wakeup();
+            let sleep = async {
+                match wakeup {
+                    Some(wakeup) => tokio::time::sleep_until(wakeup.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = self.trickle_wake.notified() => continue,
+                _ = sleep => {}
+            }
+            let due = {
+                let mut trickle = self.trickle.lock();
+                trickle.due(Instant::now(), &mut self.inventory.lock())
+            };
+            for (peer, txids) in due {
+                self.send(peer, Message::Inv(txids.into_iter().map(Inventory::Transaction).collect()));
+            }
+        }
+    }
+
+    // Starts a new broadcast round for our own transactions whose interval has passed, and stops
+    // broadcasting those that expired unconfirmed
+    async fn rebroadcast_transactions(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(REBROADCAST_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+            let expired = self.broadcast.lock().tick(Instant::now());
+            for txid in expired {
+                log::info!("Stopped broadcasting transaction {}, which didn't confirm in time", hex::encode(txid));
+            }
+            self.queue_broadcasts();
+        }
+    }
+
+    // Queues our own transactions for the peers that haven't been sent them this round. They go
+    // out through `trickle` with relayed ones, so they can't be told apart.
+    fn queue_broadcasts(&self) {
+        let pending = self.broadcast.lock().pending();
+        if pending.is_empty() {
+            return;
+        }
+        let mut trickle = self.trickle.lock();
+        for (peer, txids) in pending {
+            for txid in txids {
+                trickle.announce_to(peer, txid);
+            }
+        }
+        drop(trickle);
+        self.trickle_wake.notify_one();
+    }
+
+    // A peer announcing or asking for one of our transactions has it, or soon will
+    fn mark_broadcast_seen(&self, id: PeerId, items: &[Inventory]) {
+        let mut broadcast = self.broadcast.lock();
+        for item in items {
+            if let Inventory::Transaction(txid) = item {
+                broadcast.mark_seen(id, txid);
+            }
+        }
+    }
+
+    // Connects while outbound slots are free, block-relay-only ones first, to saved anchors and
+    // then addresses from the address manager. Once in a while a full-relay peer that hasn't been
+    // giving us blocks makes way for a fresh address.
+    async fn fill_outbound(self: Arc<Self>) {
+        // Each attempt can fail, so a round is bounded rather than run until the slots are full
+        let attempts = self.config.max_peers.saturating_sub(self.config.max_inbound);
+        let mut interval = tokio::time::interval(CONNECT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+            let rotated = self.outbound.lock().peer_to_rotate(Instant::now());
+            if let Some(id) = rotated {
+                log::debug!("Rotating out outbound peer {}", id);
+                if let Some(peer) = self.peers.lock().get(&id) {
+                    peer.disconnect.cancel();
+                }
+            }
+            for _ in 0..attempts {
+                let Some(kind) = self.outbound.lock().next_to_open() else { break };
+                let anchor = match kind {
+                    ConnectionType::BlockRelayOnly => self.anchors.lock().pop(),
+                    ConnectionType::FullRelay => None,
+                };
+                let address = match anchor {
+                    Some(anchor) => anchor,
+                    None => match self.addrman.lock().select(local_time(), RETRY_AFTER_SECS) {
+                        Some(address) => address,
+                        None => break,
+                    },
+                };
+                if self.is_connected(&address.addr) {
+                    continue;
+                }
+                self.addrman.lock().attempt(&address.addr, local_time());
+                if let Err(e) = self.connect(address, kind).await {
+                    log::debug!("Connecting to {} failed: {}", address.addr, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_download::{BLOCK_STALL_TIMEOUT, MAX_STALLS};
+    use crate::chain_params::Network;
+    use crate::node_config::{BlockchainConfig, DiskConfig};
+    use crate::policy::RelayPolicy;
+    use crate::protocol::NODE_NETWORK;
+    use crate::rate_limit::MAX_INV_PER_MESSAGE;
+    use crate::testutil::{self, START_TIME};
+    use crate::transaction::{OutPoint, TxInput, TxOutput};
+    use crate::validation;
+    use tempfile::TempDir;
+
+    // A node on an empty regtest chain in its own datadir
+    async fn test_node() -> Result<(PeerManager, TempDir), Box<dyn std::error::Error>> {
+        let datadir = TempDir::new()?;
+        let mut config = BlockchainConfig::load(datadir.path())?;
+        config.chain = Network::Regtest;
+        config.disk = DiskConfig { low_free_mb: 0, min_free_mb: 0 };
+        let blockchain = Arc::new(Blockchain::new(config.clone()).await?);
+        let params = blockchain.params().clone();
+        let mempool = Mempool::new(1, 600, 3600, 60, RelayPolicy { min_relay_fee_rate: 0.0, dust_limit: 0, accept_non_standard: false }, params.fruit_freshness_window, params.fruit_bits);
+        let manager = PeerManager::new(blockchain, Arc::new(Mutex::new(mempool)), Arc::new(Mutex::new(AddrManager::new())), config.network, NODE_NETWORK, CancellationToken::new());
+        Ok((manager, datadir))
+    }
+
+    // Mines `count` blocks on `node`, the first also going to `others` so they share a genesis block
+    async fn mine(node: &PeerManager, count: u64, others: &[&PeerManager]) -> Result<(), Box<dyn std::error::Error>> {
+        for i in 0..count {
+            let block = testutil::build_block(&node.blockchain, Vec::new(), &[1; 32], START_TIME + i).await?;
+            if i == 0 {
+                for other in others {
+                    other.blockchain.add_block(block.clone()).await?;
+                }
+            }
+            node.blockchain.add_block(block).await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_same_tip(a: &PeerManager, b: &PeerManager) -> Result<(), tokio::time::error::Elapsed> {
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while a.blockchain.get_chain_tip() != b.blockchain.get_chain_tip() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await
+    }
+
+    fn node_address(addr: SocketAddr) -> NetAddress {
+        NetAddress { addr, services: NODE_NETWORK, last_seen: 0 }
+    }
+
+    async fn listen(manager: &Arc<PeerManager>) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(Arc::clone(manager).run(listener));
+        Ok(address)
+    }
+
+    #[tokio::test]
+    async fn test_a_new_node_downloads_the_chain_from_its_peer() -> Result<(), Box<dyn std::error::Error>> {
+        let (synced, _synced_dir) = test_node().await?;
+        let (fresh, _fresh_dir) = test_node().await?;
+        mine(&synced, 20, &[&fresh]).await?;
+        let (synced, fresh) = (Arc::new(synced), Arc::new(fresh));
+
+        let address = listen(&synced).await?;
+        fresh.connect(node_address(address), ConnectionType::FullRelay).await?;
+        wait_for_same_tip(&fresh, &synced).await?;
+        assert_eq!(fresh.blockchain.get_chain_height(), Some(19));
+        assert_eq!((fresh.peer_count(), synced.peer_count()), (1, 1));
+        // The clock is sampled from the outbound side only
+        assert_eq!(fresh.blockchain.network_time().sample_count(), 1);
+        assert_eq!(synced.blockchain.network_time().sample_count(), 0);
+        synced.shutdown.cancel();
+        fresh.shutdown.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_a_new_node_loads_its_chainstate_from_a_peers_snapshot() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, server_dir) = test_node().await?;
+        let (fresh, _fresh_dir) = test_node().await?;
+        let mut server = server.with_snapshots(server_dir.path().join(crate::snapshot::SNAPSHOTS_DIR_NAME));
+        server.services |= NODE_UTXO_SNAPSHOTS;
+        // UTXO commitments activate after three signalling periods, and the block after the
+        // snapshot height commits to the set
+        let params = server.blockchain.params().clone();
+        let base = (3 * params.signalling_period).div_ceil(params.snapshot_interval) * params.snapshot_interval;
+        mine(&server, base + 1, &[]).await?;
+        server.take_snapshot().await?;
+        let base_hash = server.snapshots.lock().back().map(|snapshot| snapshot.manifest.block_hash).ok_or("no snapshot was taken")?;
+        assert_eq!(server.blockchain.get_block_hash(base).await?, Some(base_hash));
+        let block = testutil::build_block(&server.blockchain, Vec::new(), &[1; 32], START_TIME + base + 1).await?;
+        server.blockchain.add_block(block).await?;
+        let (server, fresh) = (Arc::new(server), Arc::new(fresh.with_snapshot_sync()));
+
+        let address = listen(&server).await?;
+        fresh.connect(node_address(address), ConnectionType::FullRelay).await?;
+        wait_for_same_tip(&fresh, &server).await?;
+        assert_eq!(fresh.blockchain.snapshot_base().await?, Some(base_hash));
+        assert!(fresh.blockchain.get_block_by_height(1).await?.is_none());
+        let (loaded, served) = (fresh.blockchain.utxo_set_info().await, server.blockchain.utxo_set_info().await);
+        assert_eq!((loaded.coins, loaded.total_amount, loaded.hash), (served.coins, served.total_amount, served.hash));
+        assert!(fresh.bootstrap.lock().await.is_none());
+        server.shutdown.cancel();
+        fresh.shutdown.cancel();
+        Ok(())
+    }
+
+    // A peer driven by hand, past the handshake with the node at `address`
+    struct RawPeer {
+        reader: FramedRead<OwnedReadHalf, MessageCodec>,
+        writer: OwnedWriteHalf,
+        codec: MessageCodec,
+    }
+
+    impl RawPeer {
+        async fn connect(address: SocketAddr, codec: MessageCodec) -> Result<RawPeer, Box<dyn std::error::Error>> {
+            let (reader, writer) = TcpStream::connect(address).await?.into_split();
+            let mut peer = RawPeer { reader: FramedRead::new(reader, codec.clone()), writer, codec };
+            let version = VersionMessage { version: PROTOCOL_VERSION, services: 0, timestamp: local_time(), nonce: 1, user_agent: "/xcore-test/".to_string(), start_height: None, relay: true };
+            peer.send(Message::Version(version)).await?;
+            assert!(matches!(peer.reader.next().await, Some(Ok(Message::Version(_)))));
+            assert!(matches!(peer.reader.next().await, Some(Ok(Message::Verack))));
+            peer.send(Message::Verack).await?;
+            assert!(matches!(peer.reader.next().await, Some(Ok(Message::GetHeaders { .. }))));
+            Ok(peer)
+        }
+
+        async fn send(&mut self, message: Message) -> Result<(), Box<dyn std::error::Error>> {
+            let mut frame = BytesMut::new();
+            self.codec.encode(message, &mut frame)?;
+            self.writer.write_all(&frame).await?;
+            Ok(())
+        }
+
+        // The next message other than a ping, which is answered
+        async fn receive(&mut self) -> Result<Message, Box<dyn std::error::Error>> {
+            loop {
+                match self.reader.next().await.ok_or("node closed the connection")?? {
+                    Message::Ping(nonce) => self.send(Message::Pong(nonce)).await?,
+                    message => return Ok(message),
+                }
+            }
+        }
+
+        // Whether the node drops the connection, skipping anything it sends first
+        async fn is_disconnected(&mut self) -> Result<bool, tokio::time::error::Elapsed> {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    match self.reader.next().await {
+                        Some(Ok(_)) => continue,
+                        _ => return true,
+                    }
+                }
+            }).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_anchors_are_connected_first_as_block_relay_only_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let (synced, _synced_dir) = test_node().await?;
+        let (fresh, _fresh_dir) = test_node().await?;
+        mine(&synced, 5, &[&fresh]).await?;
+        let synced = Arc::new(synced);
+        let anchor = node_address(listen(&synced).await?);
+        let fresh = Arc::new(fresh.with_anchors(vec![anchor]));
+
+        listen(&fresh).await?;
+        wait_for_same_tip(&fresh, &synced).await?;
+        assert_eq!(fresh.anchors(), vec![anchor]);
+        // Asked not to announce transactions, and never asked for addresses
+        let negotiated = synced.peers.lock().values().map(|peer| peer.negotiated.clone()).collect::<Vec<_>>();
+        assert!(matches!(&negotiated[..], [Some(NegotiatedPeer { relay: false, .. })]));
+
+        // Still the anchor once shutdown has dropped the connection
+        fresh.shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !fresh.peers.lock().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await?;
+        assert_eq!(fresh.anchors(), vec![anchor]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_a_peer_exceeding_the_inv_limit_is_disconnected() -> Result<(), Box<dyn std::error::Error>> {
+        let (node, _datadir) = test_node().await?;
+        let node = Arc::new(node);
+        let address = listen(&node).await?;
+        let mut peer = RawPeer::connect(address, node.codec.clone()).await?;
+        peer.send(Message::Ping(7)).await?;
+        assert!(matches!(peer.receive().await?, Message::Pong(7)));
+
+        peer.send(Message::Inv(vec![Inventory::Transaction([1; 32]); MAX_INV_PER_MESSAGE + 1])).await?;
+        assert!(peer.is_disconnected().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_headers_without_proof_of_work_get_their_sender_disconnected() -> Result<(), Box<dyn std::error::Error>> {
+        let (node, _datadir) = test_node().await?;
+        mine(&node, 1, &[]).await?;
+        let node = Arc::new(node);
+        let address = listen(&node).await?;
+        let genesis = node.blockchain.get_header(&node.blockchain.get_chain_tip()).await?.expect("the tip has a header");
+        let mut forged = BlockHeader { previous_hash: genesis.hash(), timestamp: genesis.timestamp + 1, ..genesis };
+        while validation::check_header(&forged).is_ok() {
+            forged.nonce += 1;
+        }
+
+        // A header that only fails on our own view of the chain isn't held against the peer
+        let mut honest = RawPeer::connect(address, node.codec.clone()).await?;
+        let mut unknown_parent = BlockHeader { previous_hash: [7; 32], ..forged.clone() };
+        while validation::check_header(&unknown_parent).is_err() {
+            unknown_parent.nonce += 1;
+        }
+        honest.send(Message::Headers(vec![unknown_parent])).await?;
+        honest.send(Message::Ping(7)).await?;
+        assert!(matches!(honest.receive().await?, Message::Pong(7)));
+
+        let mut peer = RawPeer::connect(address, node.codec.clone()).await?;
+        peer.send(Message::Headers(vec![forged.clone()])).await?;
+        assert!(peer.is_disconnected().await?);
+        assert_eq!(node.blockchain.header_height(&forged.hash()), None);
+        assert!(node.downloads.lock().schedule(Instant::now()).is_empty());
+        Ok(())
+    }
+
+    // A node whose tip is from just now, which takes it out of initial block download so it
+    // fetches and relays transactions
+    async fn caught_up_node() -> Result<(PeerManager, TempDir), Box<dyn std::error::Error>> {
+        let (node, datadir) = test_node().await?;
+        let genesis = testutil::build_block(&node.blockchain, Vec::new(), &[1; 32], local_time()).await?;
+        node.blockchain.add_block(genesis).await?;
+        Ok((node, datadir))
+    }
+
+    // Spends a coin that doesn't exist, so only the mempool takes it, given its fee
+    fn stray_transaction() -> Transaction {
+        Transaction {
+            inputs: vec![TxInput { previous_output: OutPoint { txid: [9; 32], index: 0 }, public_key: [1; 32], signature: vec![0; 64], sequence: 0 }],
+            outputs: vec![TxOutput { value: 1, script_pubkey: vec![1; 32] }],
+            lock_time: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocks_a_peer_stalls_on_are_asked_of_another_until_it_is_dropped() -> Result<(), Box<dyn std::error::Error>> {
+        let (miner, _miner_dir) = test_node().await?;
+        let (node, _datadir) = test_node().await?;
+        mine(&miner, 5, &[&node]).await?;
+        let headers = miner.blockchain.get_headers(1..5).await?;
+        let hashes = headers.iter().map(BlockHeader::hash).collect::<Vec<_>>();
+        let node = Arc::new(node);
+        let address = listen(&node).await?;
+        let requested = |message: Message| matches!(message, Message::GetData(items) if items == hashes.iter().copied().map(Inventory::Block).collect::<Vec<_>>());
+
+        // The first peer to announce the blocks is asked for them and never sends them
+        let mut staller = RawPeer::connect(address, node.codec.clone()).await?;
+        staller.send(Message::Headers(headers.clone())).await?;
+        assert!(requested(staller.receive().await?));
+        let mut second = RawPeer::connect(address, node.codec.clone()).await?;
+        second.send(Message::Headers(headers.clone())).await?;
+        second.send(Message::Ping(7)).await?;
+        assert!(matches!(second.receive().await?, Message::Pong(7)));
+
+        let start = Instant::now();
+        node.check_stalls(start + BLOCK_STALL_TIMEOUT);
+        assert!(requested(second.receive().await?));
+        // Each stall takes the peer off the blocks' sources until it announces them again
+        for round in 1..=MAX_STALLS {
+            node.check_stalls(start + BLOCK_STALL_TIMEOUT * (round + 1));
+            if round < MAX_STALLS {
+                second.send(Message::Headers(headers.clone())).await?;
+                assert!(requested(second.receive().await?));
+            }
+        }
+        assert!(second.is_disconnected().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_a_transaction_one_peer_sent_is_not_fetched_from_another() -> Result<(), Box<dyn std::error::Error>> {
+        let (node, _datadir) = caught_up_node().await?;
+        let node = Arc::new(node);
+        let address = listen(&node).await?;
+        let mut first = RawPeer::connect(address, node.codec.clone()).await?;
+        let mut second = RawPeer::connect(address, node.codec.clone()).await?;
+
+        // Refused, but it arrived all the same
+        let tx = stray_transaction();
+        first.send(Message::Tx(tx.clone())).await?;
+        first.send(Message::Ping(7)).await?;
+        assert!(matches!(first.receive().await?, Message::Pong(7)));
+
+        let other = Inventory::Transaction([2; 32]);
+        second.send(Message::Inv(vec![Inventory::Transaction(tx.hash()), other])).await?;
+        assert!(matches!(second.receive().await?, Message::GetData(items) if items == vec![other]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transactions_are_trickled_to_peers_that_do_not_have_them() -> Result<(), Box<dyn std::error::Error>> {
+        let (node, _datadir) = caught_up_node().await?;
+        let node = Arc::new(node);
+        let address = listen(&node).await?;
+        let mut first = RawPeer::connect(address, node.codec.clone()).await?;
+        let mut second = RawPeer::connect(address, node.codec.clone()).await?;
+        let tx = stray_transaction();
+        let txid = tx.hash();
+        second.send(Message::Inv(vec![Inventory::Transaction(txid)])).await?;
+        assert!(matches!(second.receive().await?, Message::GetData(_)));
+
+        node.mempool.lock().add_transaction(tx, 1000, 1, 0)?;
+        // Inbound peers' turns come every few seconds on average
+        let announced = tokio::time::timeout(Duration::from_secs(60), first.receive()).await??;
+        assert!(matches!(announced, Message::Inv(items) if items == vec![Inventory::Transaction(txid)]));
+        // The peer that announced it to us shared the turn but was left out
+        second.send(Message::Ping(7)).await?;
+        assert!(matches!(second.receive().await?, Message::Pong(7)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_our_own_transactions_are_announced_to_peers_that_connect_later() -> Result<(), Box<dyn std::error::Error>> {
+        let (node, _datadir) = caught_up_node().await?;
+        let node = Arc::new(node);
+        let address = listen(&node).await?;
+        let tx = stray_transaction();
+        let txid = tx.hash();
+        node.mempool.lock().add_transaction(tx, 1000, 1, 0)?;
+        node.broadcast_transaction(txid);
+
+        // Relayed transactions only go to the peers there when they arrive
+        let mut peer = RawPeer::connect(address, node.codec.clone()).await?;
+        let announced = tokio::time::timeout(Duration::from_secs(60), peer.receive()).await??;
+        assert!(matches!(announced, Message::Inv(items) if items == vec![Inventory::Transaction(txid)]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_a_newcomer_takes_the_place_of_an_inbound_peer_once_slots_are_full() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut node, _datadir) = test_node().await?;
+        // Enough inbound peers that some are left once eviction has protected the rest
+        node.config.max_inbound = 24;
+        let node = Arc::new(node);
+        let address = listen(&node).await?;
+        let mut peers = Vec::new();
+        for _ in 0..24 {
+            peers.push(RawPeer::connect(address, node.codec.clone()).await?);
+        }
+        assert_eq!(node.peer_count(), 24);
+
+        let mut newcomer = RawPeer::connect(address, node.codec.clone()).await?;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while node.peers.lock().len() > 24 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await?;
+        assert_eq!(node.peer_count(), 24);
+        newcomer.send(Message::Ping(7)).await?;
+        assert!(matches!(newcomer.receive().await?, Message::Pong(7)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_peers_sync_and_untrusted_keys_are_refused() -> Result<(), Box<dyn std::error::Error>> {
+        let (synced, _synced_dir) = test_node().await?;
+        let (trusted, _trusted_dir) = test_node().await?;
+        let (stranger, _stranger_dir) = test_node().await?;
+        mine(&synced, 5, &[&trusted, &stranger]).await?;
+        let trusted_key = NodeKey::generate()?;
+        let synced = Arc::new(synced.with_encryption(NodeKey::generate()?, HashSet::from([trusted_key.public_key().to_vec()])));
+        let trusted = Arc::new(trusted.with_encryption(trusted_key, HashSet::new()));
+        let stranger = Arc::new(stranger.with_encryption(NodeKey::generate()?, HashSet::new()));
+
+        let address = listen(&synced).await?;
+        trusted.connect(node_address(address), ConnectionType::FullRelay).await?;
+        wait_for_same_tip(&trusted, &synced).await?;
+        assert_eq!(trusted.peer_count(), 1);
+
+        // The Noise handshake fails before either side sends a version message
+        stranger.connect(node_address(address), ConnectionType::FullRelay).await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!((stranger.peer_count(), synced.peer_count()), (0, 1));
+        assert_eq!(stranger.blockchain.get_chain_height(), Some(0));
+        Ok(())
+    }
+}
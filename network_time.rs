@@ -0,0 +1,115 @@
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Peers sampled at most; later peers don't move the offset
+const MAX_SAMPLES: usize = 200;
+// Samples needed before the median is trusted over the local clock
+const MIN_SAMPLES: usize = 5;
+// Largest correction applied to the local clock. A median further out than this is more likely
+// peers lying than the clock being wrong, so the local clock is used as is.
+pub const MAX_TIME_OFFSET_SECS: i64 = 70 * 60;
+// Median offsets beyond this are reported as clock skew
+pub const CLOCK_SKEW_WARNING_SECS: i64 = 5 * 60;
+
+pub fn local_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// Network-adjusted time: the local clock corrected by the median offset peers reported in their
+// version messages, one sample per peer address
+#[derive(Default)]
+pub struct NetworkTime {
+    samples: Mutex<Samples>,
+}
+
+#[derive(Default)]
+struct Samples {
+    sources: HashSet<IpAddr>,
+    offsets: Vec<i64>,
+    offset: i64,
+    median: i64,
+}
+
+impl NetworkTime {
+    pub fn new() -> Self {
+        NetworkTime::default()
+    }
+
+    // `offset` is the peer's clock minus ours, in seconds, from `NegotiatedPeer::time_offset`
+    pub fn add_sample(&self, source: IpAddr, offset: i64) {
+        let mut samples = self.samples.lock();
+        if samples.offsets.len() >= MAX_SAMPLES || !samples.sources.insert(source) {
+            return;
+        }
+        samples.offsets.push(offset);
+        if samples.offsets.len() < MIN_SAMPLES {
+            return;
+        }
+
+        let mut sorted = samples.offsets.clone();
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2];
+        let was_skewed = samples.median.abs() > CLOCK_SKEW_WARNING_SECS;
+        samples.median = median;
+        samples.offset = if median.abs() <= MAX_TIME_OFFSET_SECS { median } else { 0 };
+        if median.abs() > CLOCK_SKEW_WARNING_SECS && !was_skewed {
+            log::warn!("Peers' clocks are {} seconds {} ours; check that the system date and time are correct", median.abs(), if median > 0 { "ahead of" } else { "behind" });
+        }
+    }
+
+    // Seconds added to the local clock
+    pub fn offset(&self) -> i64 {
+        self.samples.lock().offset
+    }
+
+    pub fn adjusted_time(&self) -> u64 {
+        local_time().saturating_add_signed(self.offset())
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.samples.lock().offsets.len()
+    }
+
+    pub fn warning(&self) -> Option<String> {
+        let median = self.samples.lock().median;
+        (median.abs() > CLOCK_SKEW_WARNING_SECS).then(|| {
+            let applied = if median.abs() <= MAX_TIME_OFFSET_SECS { "" } else { ", too far to correct" };
+            format!("The local clock differs from peers' by {} seconds{}; check the system clock", median, applied)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn peer(i: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, i))
+    }
+
+    #[test]
+    fn test_median_offset_is_bounded_and_needs_enough_peers() {
+        let time = NetworkTime::new();
+        for (i, offset) in [600, 600, -10, 600].into_iter().enumerate() {
+            time.add_sample(peer(i as u8), offset);
+        }
+        assert_eq!(time.offset(), 0);
+        // A peer reconnecting doesn't count twice
+        time.add_sample(peer(0), 600);
+        assert_eq!((time.sample_count(), time.offset()), (4, 0));
+
+        time.add_sample(peer(4), 5);
+        assert_eq!(time.offset(), 600);
+        assert!(time.warning().is_some());
+
+        let far = NetworkTime::new();
+        for i in 0..5 {
+            far.add_sample(peer(i), 2 * MAX_TIME_OFFSET_SECS);
+        }
+        assert_eq!(far.offset(), 0);
+        assert!(far.warning().unwrap().contains("too far to correct"));
+    }
+}
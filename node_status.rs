@@ -0,0 +1,133 @@
+use crate::mempool::MempoolInfo;
+use crate::node_config::BlockchainConfig;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Default free space below which the node warns and reports itself unhealthy
+pub const LOW_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+// A tip this far ahead of the local clock means one of the two is wrong
+pub const MAX_CLOCK_SKEW_SECS: u64 = 2 * 60 * 60;
+
+// Reported by getnodeinfo, and in brief by the RPC server's /health endpoint
+#[derive(Debug, Serialize)]
+pub struct NodeInfo {
+    pub chain: String,
+    pub blocks: Option<u64>,
+    // Best valid header, ahead of `blocks` while their blocks download
+    pub headers: Option<u64>,
+    pub best_block_hash: String,
+    pub last_block_time: Option<u64>,
+    // Estimated from block timestamps, from 0 to 1
+    pub sync_progress: f64,
+    pub initial_block_download: bool,
+    // Blocks the tip's age suggests are still to come, zero once out of initial block download
+    pub estimated_blocks_remaining: u64,
+    pub peers: PeerCounts,
+    pub mempool: MempoolInfo,
+    pub disk: DiskUsage,
+    pub warnings: Vec<String>,
+    // Seconds network-adjusted time is ahead of the local clock
+    pub time_offset: i64,
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerCounts {
+    // Peers past the handshake, zero on a read-only node
+    pub connected: usize,
+    // Addresses in the address manager, and those we have connected to before
+    pub known: usize,
+    pub tried: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiskUsage {
+    pub blocks_bytes: u64,
+    pub chainstate_bytes: u64,
+    pub wallets_bytes: u64,
+    // Free space on the filesystem holding the block files
+    pub available_bytes: u64,
+}
+
+impl DiskUsage {
+    // Walks the data directories, so callers on the runtime should use spawn_blocking
+    pub fn measure(config: &BlockchainConfig) -> io::Result<Self> {
+        Ok(DiskUsage {
+            blocks_bytes: dir_size(&config.blocks_dir)?,
+            chainstate_bytes: dir_size(Path::new(&config.db_path))?,
+            wallets_bytes: dir_size(&config.wallets_dir)?,
+            available_bytes: available_space(&config.blocks_dir)?,
+        })
+    }
+}
+
+// How far the chain has got from genesis towards the present, by block time, from 0 to 1
+pub fn sync_progress(genesis_time: u64, tip_time: u64, now: u64) -> f64 {
+    if now <= genesis_time {
+        return 1.0;
+    }
+    (tip_time.saturating_sub(genesis_time) as f64 / (now - genesis_time) as f64).min(1.0)
+}
+
+// Problems an operator should look at, in plain words
+pub fn warnings(tip_time: Option<u64>, now: u64, disk: &DiskUsage, low_disk_bytes: u64) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if disk.available_bytes < low_disk_bytes {
+        warnings.push(format!("Low disk space: {} MiB free for block files", disk.available_bytes / (1024 * 1024)));
+    }
+    if let Some(tip_time) = tip_time.filter(|&time| time > now + MAX_CLOCK_SKEW_SECS) {
+        warnings.push(format!("The best block is {} seconds in the future; check the system clock", tip_time - now));
+    }
+    warnings
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(size)
+}
+
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// Unknown, so never reported as low
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_and_warnings() {
+        assert_eq!(sync_progress(1_000, 1_000, 3_000), 0.0);
+        assert_eq!(sync_progress(1_000, 2_000, 3_000), 0.5);
+        assert_eq!(sync_progress(1_000, 5_000, 3_000), 1.0);
+
+        let roomy = DiskUsage { available_bytes: 10 * LOW_DISK_BYTES, ..DiskUsage::default() };
+        assert!(warnings(Some(1_000), 1_000, &roomy, LOW_DISK_BYTES).is_empty());
+        assert_eq!(warnings(Some(1_000 + MAX_CLOCK_SKEW_SECS + 1), 1_000, &roomy, LOW_DISK_BYTES).len(), 1);
+        assert_eq!(warnings(None, 1_000, &DiskUsage::default(), LOW_DISK_BYTES).len(), 1);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::blockchain::{Block, ChainEvent};
+use crate::codec::{self, CodecError};
+use crate::mempool::MempoolEvent;
+use crate::node_config::NotificationConfig;
+use crate::transaction::Transaction;
+use std::collections::HashMap;
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// Publishes new blocks and transactions on ZeroMQ PUB sockets for indexers and payment processors.
+// Messages have three parts as in bitcoind: the topic name, the body, and a little-endian u32
+// sequence number per topic so subscribers can tell when they missed messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    HashBlock,
+    HashTx,
+    RawBlock,
+    RawTx,
+}
+
+impl Topic {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Topic::HashBlock => "hashblock",
+            Topic::HashTx => "hashtx",
+            Topic::RawBlock => "rawblock",
+            Topic::RawTx => "rawtx",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum NotificationError {
+    #[error("ZeroMQ error on {endpoint}: {source}")]
+    Zmq { endpoint: String, source: zmq::Error },
+    #[error("Codec error: {0}")]
+    Codec(#[from] CodecError),
+}
+
+pub struct NotificationPublisher {
+    // Sockets close when their context is dropped, so it lives as long as they do
+    _context: zmq::Context,
+    // Each socket with the endpoint it's bound to
+    sockets: Vec<(String, zmq::Socket)>,
+    // Each topic and the index of the socket it's published on
+    topics: Vec<(Topic, usize)>,
+    sequences: HashMap<Topic, u32>,
+}
+
+impl NotificationPublisher {
+    // Binds one socket per distinct endpoint; None when no topic is configured
+    pub fn bind(config: &NotificationConfig) -> Result<Option<Self>, NotificationError> {
+        let configured = [
+            (Topic::HashBlock, &config.hashblock),
+            (Topic::HashTx, &config.hashtx),
+            (Topic::RawBlock, &config.rawblock),
+            (Topic::RawTx, &config.rawtx),
+        ];
+        if configured.iter().all(|(_, endpoint)| endpoint.is_none()) {
+            return Ok(None);
+        }
+
+        let context = zmq::Context::new();
+        let mut sockets = Vec::new();
+        let mut endpoints: HashMap<&str, usize> = HashMap::new();
+        let mut topics = Vec::new();
+        for (topic, endpoint) in configured {
+            let Some(endpoint) = endpoint.as_deref() else { continue };
+            let index = match endpoints.get(endpoint) {
+                Some(&index) => index,
+                None => {
+                    let zmq_error = |source| NotificationError::Zmq { endpoint: endpoint.to_string(), source };
+                    let socket = context.socket(zmq::PUB).map_err(zmq_error)?;
+                    socket.set_sndhwm(config.high_water_mark).map_err(zmq_error)?;
+                    socket.bind(endpoint).map_err(zmq_error)?;
+                    sockets.push((endpoint.to_string(), socket));
+                    endpoints.insert(endpoint, sockets.len() - 1);
+                    sockets.len() - 1
+                }
+            };
+            log::info!("Publishing {} notifications on {}", topic.name(), endpoint);
+            topics.push((topic, index));
+        }
+        Ok(Some(NotificationPublisher { _context: context, sockets, topics, sequences: HashMap::new() }))
+    }
+
+    pub fn block_connected(&mut self, block: &Block) -> Result<(), NotificationError> {
+        self.publish(Topic::HashBlock, || Ok(block.hash().to_vec()))?;
+        self.publish(Topic::RawBlock, || Ok(codec::encode(block)?))
+    }
+
+    pub fn transaction_added(&mut self, tx: &Transaction) -> Result<(), NotificationError> {
+        self.publish(Topic::HashTx, || Ok(tx.hash().to_vec()))?;
+        self.publish(Topic::RawTx, || Ok(codec::encode(tx)?))
+    }
+
+    // The body is only built when the topic is configured
+    fn publish(&mut self, topic: Topic, body: impl FnOnce() -> Result<Vec<u8>, NotificationError>) -> Result<(), NotificationError> {
+        let Some(&(_, index)) = self.topics.iter().find(|(configured, _)| *configured == topic) else { return Ok(()) };
+        let body = body()?;
+        let sequence = self.sequences.entry(topic).or_insert(0);
+        let parts: [&[u8]; 3] = [topic.name().as_bytes(), &body, &sequence.to_le_bytes()];
+        let (endpoint, socket) = &self.sockets[index];
+        // A subscriber past its high water mark misses the message rather than stalling the node
+        match socket.send_multipart(parts, zmq::DONTWAIT) {
+            Ok(()) | Err(zmq::Error::EAGAIN) => {}
+            Err(source) => return Err(NotificationError::Zmq { endpoint: endpoint.clone(), source }),
+        }
+        *sequence = sequence.wrapping_add(1);
+        Ok(())
+    }
+}
+
+// Forwards chain and mempool events to the publisher until the node shuts down
+pub async fn run(mut publisher: NotificationPublisher, mut chain: broadcast::Receiver<ChainEvent>, mut mempool: broadcast::Receiver<MempoolEvent>) {
+    loop {
+        let result = tokio::select! {
+            event = chain.recv() => match event {
+                Ok(ChainEvent::BlockConnected { block, .. }) => publisher.block_connected(&block),
+                Ok(ChainEvent::BlockDisconnected { .. }) => Ok(()),
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("Notification publisher fell behind and skipped {} chain events", missed);
+                    Ok(())
+                }
+                Err(RecvError::Closed) => return,
+            },
+            event = mempool.recv() => match event {
+                Ok(MempoolEvent::TransactionAdded(tx)) => publisher.transaction_added(&tx),
+                Ok(MempoolEvent::TransactionRemoved { .. }) => Ok(()),
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("Notification publisher fell behind and skipped {} mempool events", missed);
+                    Ok(())
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+        if let Err(e) = result {
+            log::error!("Failed to publish notification: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{BlockHeader, BlockType};
+    use crate::transaction::TxOutput;
+
+    #[test]
+    fn test_topics_share_sockets_by_endpoint_and_count_their_own_sequence() -> Result<(), Box<dyn std::error::Error>> {
+        assert!(NotificationPublisher::bind(&NotificationConfig::default())?.is_none());
+
+        let transactions = "inproc://xcore-notifications-tx".to_string();
+        let config = NotificationConfig {
+            hashtx: Some(transactions.clone()),
+            rawtx: Some(transactions),
+            hashblock: Some("inproc://xcore-notifications-block".to_string()),
+            ..NotificationConfig::default()
+        };
+        let mut publisher = NotificationPublisher::bind(&config)?.expect("topics are configured");
+        assert_eq!(publisher.sockets.len(), 2);
+
+        let tx = Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value: 1, script_pubkey: vec![1; 32] }], lock_time: 0 };
+        let header = BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [0; 32], fruits_root: [0; 32], timestamp: 0, bits: 0, nonce: 0 };
+        let block = Block { header, block_type: BlockType::Block, fruit_header: None, fruits: Vec::new(), transactions: vec![tx.clone()] };
+        publisher.transaction_added(&tx)?;
+        publisher.transaction_added(&tx)?;
+        publisher.block_connected(&block)?;
+        // Unconfigured topics publish nothing, so their sequence never starts
+        let sequence = |topic| publisher.sequences.get(&topic).copied();
+        assert_eq!([sequence(Topic::HashTx), sequence(Topic::RawTx), sequence(Topic::HashBlock), sequence(Topic::RawBlock)], [Some(2), Some(2), Some(1), None]);
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use crate::codec;
+use crate::node_config::NetworkConfig;
+use crate::protocol::{NetAddress, PeerId};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const ANCHORS_FILE_NAME: &str = "anchors.dat";
+// Block-relay-only peers saved at shutdown and connected to first on the next start, so an
+// attacker who poisons the address table while we're down still doesn't get every outbound slot
+pub const MAX_ANCHORS: usize = 2;
+// How often one full-relay outbound peer is swapped for a fresh address
+pub const ROTATION_INTERVAL: Duration = Duration::from_secs(20 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionType {
+    FullRelay,
+    // Blocks only: no transactions or addresses, which makes the connection hard to spot from
+    // the outside and so hard to target
+    BlockRelayOnly,
+}
+
+impl ConnectionType {
+    // Sent as `relay` in our version message
+    pub fn relays_transactions(self) -> bool {
+        self == ConnectionType::FullRelay
+    }
+}
+
+struct OutboundPeer {
+    address: NetAddress,
+    kind: ConnectionType,
+    connected_at: Instant,
+    // Last time the peer sent us a block we didn't have
+    last_block: Option<Instant>,
+}
+
+// Outbound connections by type, with the slots each type may fill
+pub struct OutboundSlots {
+    full_relay: usize,
+    block_relay: usize,
+    peers: HashMap<PeerId, OutboundPeer>,
+    last_rotation: Instant,
+}
+
+impl OutboundSlots {
+    pub fn new(full_relay: usize, block_relay: usize, now: Instant) -> Self {
+        OutboundSlots { full_relay, block_relay, peers: HashMap::new(), last_rotation: now }
+    }
+
+    // Outbound slots are what `max_inbound` leaves of `max_peers`; the block-relay-only ones come out of them
+    pub fn from_config(config: &NetworkConfig, now: Instant) -> Self {
+        let outbound = config.max_peers.saturating_sub(config.max_inbound);
+        Self::new(outbound.saturating_sub(config.block_relay_connections), config.block_relay_connections.min(outbound), now)
+    }
+
+    pub fn count(&self, kind: ConnectionType) -> usize {
+        self.peers.values().filter(|peer| peer.kind == kind).count()
+    }
+
+    // Type of the next connection to open, if any slot is free. Block-relay-only slots are few and
+    // guard against partitioning, so they're filled first.
+    pub fn next_to_open(&self) -> Option<ConnectionType> {
+        if self.count(ConnectionType::BlockRelayOnly) < self.block_relay {
+            Some(ConnectionType::BlockRelayOnly)
+        } else if self.count(ConnectionType::FullRelay) < self.full_relay {
+            Some(ConnectionType::FullRelay)
+        } else {
+            None
+        }
+    }
+
+    pub fn connected(&mut self, peer: PeerId, address: NetAddress, kind: ConnectionType, now: Instant) {
+        self.peers.insert(peer, OutboundPeer { address, kind, connected_at: now, last_block: None });
+    }
+
+    pub fn disconnected(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+    }
+
+    pub fn block_received(&mut self, peer: PeerId, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(&peer) {
+            peer.last_block = Some(now);
+        }
+    }
+
+    // Block-relay-only peers connected the longest, to save as anchors at shutdown
+    pub fn anchors(&self) -> Vec<NetAddress> {
+        let mut peers = self.peers.values().filter(|peer| peer.kind == ConnectionType::BlockRelayOnly).collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.connected_at);
+        peers.into_iter().take(MAX_ANCHORS).map(|peer| peer.address).collect()
+    }
+
+    // Once every ROTATION_INTERVAL with all full-relay slots taken, the full-relay peer that has
+    // gone longest without giving us a block, for the caller to disconnect and replace with a new
+    // address. Turning peers over slowly limits how long an eclipse that got in can last.
+    pub fn peer_to_rotate(&mut self, now: Instant) -> Option<PeerId> {
+        if now.saturating_duration_since(self.last_rotation) < ROTATION_INTERVAL || self.count(ConnectionType::FullRelay) < self.full_relay {
+            return None;
+        }
+        self.last_rotation = now;
+        self.peers.iter()
+            .filter(|(_, peer)| peer.kind == ConnectionType::FullRelay)
+            .min_by_key(|(_, peer)| (peer.last_block.unwrap_or(peer.connected_at), peer.connected_at))
+            .map(|(id, _)| *id)
+    }
+}
+
+// Written to a temporary file first like peers.dat
+pub fn save_anchors(path: &Path, anchors: &[NetAddress]) -> std::io::Result<()> {
+    let bytes = codec::encode(&anchors).map_err(std::io::Error::other)?;
+    let tmp = path.with_extension("dat.tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(tmp, path)
+}
+
+// Reads anchors.dat and deletes it, so if an anchor makes the node crash it isn't tried on every
+// start after. A missing or unreadable file means no anchors.
+pub fn take_anchors(path: &Path) -> Vec<NetAddress> {
+    let anchors = match std::fs::read(path) {
+        Ok(bytes) => codec::decode::<Vec<NetAddress>>(&bytes).unwrap_or_else(|e| {
+            log::warn!("Ignoring corrupt {}: {}", path.display(), e);
+            Vec::new()
+        }),
+        Err(_) => return Vec::new(),
+    };
+    if let Err(e) = std::fs::remove_file(path) {
+        log::warn!("Could not remove {}: {}", path.display(), e);
+    }
+    anchors.into_iter().take(MAX_ANCHORS).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tempfile::TempDir;
+
+    fn address(i: u8) -> NetAddress {
+        NetAddress { addr: SocketAddr::from(([10, i, 0, 1], 9333)), services: 1, last_seen: 0 }
+    }
+
+    #[test]
+    fn test_anchors_persist_and_peers_rotate() {
+        let start = Instant::now();
+        let mut slots = OutboundSlots::new(2, 2, start);
+        for i in 0..4u8 {
+            let kind = slots.next_to_open().unwrap();
+            assert_eq!(kind, if i < 2 { ConnectionType::BlockRelayOnly } else { ConnectionType::FullRelay });
+            slots.connected(i as PeerId, address(i), kind, start + Duration::from_secs(i as u64));
+        }
+        assert_eq!(slots.next_to_open(), None);
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(ANCHORS_FILE_NAME);
+        save_anchors(&path, &slots.anchors()).unwrap();
+        assert_eq!(take_anchors(&path), vec![address(0), address(1)]);
+        assert!(take_anchors(&path).is_empty());
+
+        // Peer 3 delivered a block, so peer 2 is the one rotated out
+        slots.block_received(3, start + Duration::from_secs(60));
+        assert_eq!(slots.peer_to_rotate(start + Duration::from_secs(60)), None);
+        assert_eq!(slots.peer_to_rotate(start + ROTATION_INTERVAL), Some(2));
+        assert_eq!(slots.peer_to_rotate(start + ROTATION_INTERVAL), None);
+        slots.disconnected(2);
+        assert_eq!(slots.next_to_open(), Some(ConnectionType::FullRelay));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::multisig::MultisigScript;
+use crate::node_config::MempoolConfig;
+use crate::transaction::Transaction;
+use serde::Serialize;
+use thiserror::Error;
+
+// Relay policy: what this node accepts into its mempool and passes on, on top of consensus rules.
+// Blocks may still contain transactions that fail these checks.
+
+// Outputs worth less than this cost more to spend than they're worth at a few times the default fee rate
+pub const DEFAULT_DUST_LIMIT: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptType {
+    // Pays a 32-byte ed25519 public key, as wallets and coinbases do
+    PublicKey,
+    // m-of-n over 32-byte keys, see multisig.rs
+    Multisig,
+    NonStandard,
+}
+
+pub fn script_type(script: &[u8]) -> ScriptType {
+    match script.len() {
+        32 => ScriptType::PublicKey,
+        _ if MultisigScript::from_script(script).is_some() => ScriptType::Multisig,
+        _ => ScriptType::NonStandard,
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum PolicyError {
+    #[error("Output {index} pays {value}, below the dust limit of {limit}")]
+    Dust { index: usize, value: u64, limit: u64 },
+    #[error("Output {0} has a non-standard script")]
+    NonStandardScript(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayPolicy {
+    // Fee units per byte a transaction must pay on its own, or a package as a whole
+    pub min_relay_fee_rate: f64,
+    pub dust_limit: u64,
+    pub accept_non_standard: bool,
+}
+
+impl RelayPolicy {
+    pub fn from_config(config: &MempoolConfig) -> Self {
+        RelayPolicy {
+            min_relay_fee_rate: config.min_fee_rate,
+            dust_limit: config.dust_limit,
+            accept_non_standard: config.accept_non_standard,
+        }
+    }
+
+    // Checks that don't depend on the fee or the UTXO set
+    pub fn check_transaction(&self, tx: &Transaction) -> Result<(), PolicyError> {
+        for (index, output) in tx.outputs.iter().enumerate() {
+            if output.value < self.dust_limit {
+                return Err(PolicyError::Dust { index, value: output.value, limit: self.dust_limit });
+            }
+            if !self.accept_non_standard && script_type(&output.script_pubkey) == ScriptType::NonStandard {
+                return Err(PolicyError::NonStandardScript(index));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxOutput;
+
+    #[test]
+    fn test_dust_and_non_standard_outputs_are_refused() {
+        let mut policy = RelayPolicy { min_relay_fee_rate: 1.0, dust_limit: DEFAULT_DUST_LIMIT, accept_non_standard: false };
+        let tx = |value, script_pubkey| Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value: 1_000, script_pubkey: vec![1; 32] }, TxOutput { value, script_pubkey }], lock_time: 0 };
+
+        assert_eq!(policy.check_transaction(&tx(DEFAULT_DUST_LIMIT, vec![2; 32])), Ok(()));
+        assert_eq!(policy.check_transaction(&tx(DEFAULT_DUST_LIMIT - 1, vec![2; 32])), Err(PolicyError::Dust { index: 1, value: DEFAULT_DUST_LIMIT - 1, limit: DEFAULT_DUST_LIMIT }));
+        assert_eq!(policy.check_transaction(&tx(1_000, vec![2; 20])), Err(PolicyError::NonStandardScript(1)));
+
+        policy.accept_non_standard = true;
+        assert_eq!(policy.check_transaction(&tx(1_000, vec![2; 20])), Ok(()));
+    }
+}
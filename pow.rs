@@ -0,0 +1,272 @@
+use crate::blockchain::BlockHash;
+use crate::chain_params::{ChainParams, RetargetAlgorithm};
+use crate::difficulty::{self, adjust_difficulty, Difficulty};
+use primitive_types::{U256, U512};
+
+// Expands compact `bits` into a 32-byte big-endian target
+pub fn compact_to_target(bits: u32) -> [u8; 32] {
+    let mut target = [0u8; 32];
+    difficulty::compact_to_target(bits).to_big_endian(&mut target);
+    target
+}
+
+// Hashes are compared as 256-bit big-endian numbers
+pub fn check_proof_of_work(hash: &BlockHash, bits: u32) -> bool {
+    let target = compact_to_target(bits);
+    target != [0u8; 32] && hash <= &target
+}
+
+// Expected number of hashes to find a block at `bits`: 2^256 / (target + 1), written as
+// (~target / (target + 1)) + 1 so it fits in 256 bits
+pub fn block_work(bits: u32) -> U256 {
+    let target = difficulty::compact_to_target(bits);
+    if target.is_zero() {
+        return U256::zero();
+    }
+    (!target / (target + 1)) + 1
+}
+
+// Heights of the ancestors whose (timestamp, bits) `next_bits` needs for the block after
+// `parent_height`, oldest first
+pub fn retarget_heights(params: &ChainParams, parent_height: u64) -> Vec<u64> {
+    match params.retarget {
+        // The whole interval so far and the one before it, to find the last block not mined at
+        // minimum or emergency difficulty even when the interval started with one
+        RetargetAlgorithm::Interval if params.allow_min_difficulty_blocks || params.emergency_after_spacings != 0 => {
+            ((parent_height - parent_height % params.retarget_interval).saturating_sub(params.retarget_interval)..=parent_height).collect()
+        }
+        RetargetAlgorithm::Interval if (parent_height + 1) % params.retarget_interval != 0 => vec![parent_height],
+        RetargetAlgorithm::Interval => vec![interval_start_height(params, parent_height), parent_height],
+        RetargetAlgorithm::Lwma { window } => (parent_height.saturating_sub(window)..=parent_height).collect(),
+    }
+}
+
+// Compact bits a block with `timestamp` must carry on top of `parent_height`. `ancestors` holds
+// (timestamp, bits) for each height returned by `retarget_heights`, in the same order.
+pub fn next_bits(params: &ChainParams, parent_height: u64, ancestors: &[(u64, u32)], timestamp: u64) -> u32 {
+    let Some(&(parent_timestamp, _)) = ancestors.last() else {
+        return params.genesis_bits;
+    };
+    // Test networks let anyone unstick the chain once blocks have stopped for a while
+    if params.allow_min_difficulty_blocks && timestamp > parent_timestamp + 2 * params.target_block_spacing_secs {
+        return params.pow_limit_bits;
+    }
+    let bits = match params.retarget {
+        RetargetAlgorithm::Interval if (parent_height + 1) % params.retarget_interval != 0 => last_regular_bits(params, ancestors),
+        RetargetAlgorithm::Interval => {
+            let base_bits = last_regular_bits(params, ancestors);
+            let target_timespan = params.target_block_spacing_secs * params.retarget_interval;
+            // The interval's first block, after any ancestors from before it
+            let (start_timestamp, _) = ancestors[ancestors.len().saturating_sub(params.retarget_interval as usize)];
+            let actual_timespan = parent_timestamp.saturating_sub(start_timestamp);
+            let (next, _) = adjust_difficulty(Difficulty::new(base_bits), actual_timespan, target_timespan);
+            next.bits
+        }
+        RetargetAlgorithm::Lwma { window } => lwma_next_bits(params, window, ancestors),
+    };
+    clamp_bits(params, emergency_bits(params, bits, timestamp.saturating_sub(parent_timestamp)))
+}
+
+// Eases `bits` for a block `elapsed` seconds after its parent once the chain has stalled: the
+// target doubles on passing the threshold and again every half-life after it
+fn emergency_bits(params: &ChainParams, bits: u32, elapsed: u64) -> u32 {
+    if !is_emergency_block(params, elapsed) {
+        return bits;
+    }
+    let threshold = params.emergency_after_spacings.saturating_mul(params.target_block_spacing_secs);
+    let doublings = (elapsed - threshold) / params.emergency_half_life_secs + 1;
+    let target = difficulty::compact_to_target(bits);
+    // Shifting past the top bit means the target has long since passed the pow limit
+    let eased = if doublings >= 256 - target.bits() as u64 { U256::MAX } else { target << doublings as usize };
+    Difficulty::from_target_u256(eased).bits
+}
+
+// Keeps `bits` between the chain's own difficulty bounds
+fn clamp_bits(params: &ChainParams, bits: u32) -> u32 {
+    let target = difficulty::compact_to_target(bits);
+    if target > difficulty::compact_to_target(params.pow_limit_bits) {
+        params.pow_limit_bits
+    } else if target < difficulty::compact_to_target(params.max_difficulty_bits) {
+        params.max_difficulty_bits
+    } else {
+        bits
+    }
+}
+
+fn is_min_difficulty_block(params: &ChainParams, bits: u32) -> bool {
+    params.allow_min_difficulty_blocks && bits == params.pow_limit_bits
+}
+
+// Whether a block `elapsed` seconds after its parent had its difficulty eased by `emergency_bits`
+fn is_emergency_block(params: &ChainParams, elapsed: u64) -> bool {
+    params.emergency_after_spacings != 0 && elapsed > params.emergency_after_spacings.saturating_mul(params.target_block_spacing_secs)
+}
+
+// Whether the ancestor at `index` was mined at the difficulty the schedule gave it. Eased bits must
+// not carry over, or one stalled block, real or with a gamed timestamp, would lower the difficulty
+// for good. The oldest ancestor's parent isn't known, so it counts as regular unless min-difficulty.
+fn is_regular(params: &ChainParams, ancestors: &[(u64, u32)], index: usize) -> bool {
+    let (timestamp, bits) = ancestors[index];
+    let eased = index > 0 && is_emergency_block(params, timestamp.saturating_sub(ancestors[index - 1].0));
+    !is_min_difficulty_block(params, bits) && !eased
+}
+
+// Bits of the newest ancestor mined on the regular schedule, or the oldest one if none was
+fn last_regular_bits(params: &ChainParams, ancestors: &[(u64, u32)]) -> u32 {
+    (0..ancestors.len()).rev()
+        .find(|&index| is_regular(params, ancestors, index))
+        .map_or(ancestors[0].1, |index| ancestors[index].1)
+}
+
+fn lwma_next_bits(params: &ChainParams, window: u64, ancestors: &[(u64, u32)]) -> u32 {
+    // Keep the starting difficulty until there is a full window of solve times
+    if (ancestors.len() as u64) <= window {
+        return last_regular_bits(params, ancestors);
+    }
+    let spacing = params.target_block_spacing_secs;
+    let mut weighted_solvetimes = 0u64;
+    let mut target_sum = U256::zero();
+    // Min-difficulty and emergency blocks count with the target of the regular block before them
+    let mut regular_bits = ancestors[0].1;
+    for (i, pair) in ancestors.windows(2).enumerate() {
+        // Clamped so a single out-of-order or stalled timestamp can't swing the result
+        let solvetime = pair[1].0.saturating_sub(pair[0].0).clamp(1, 6 * spacing);
+        weighted_solvetimes += solvetime * (i as u64 + 1);
+        if is_regular(params, ancestors, i + 1) {
+            regular_bits = pair[1].1;
+        }
+        target_sum += difficulty::compact_to_target(regular_bits);
+    }
+    // The weighted sum when every block arrives exactly on schedule
+    let expected = window * (window + 1) / 2 * spacing;
+    let average_target = target_sum / U256::from(window);
+    let next = average_target.full_mul(U256::from(weighted_solvetimes)) / U512::from(expected);
+    Difficulty::from_target_u256(U256::try_from(next).unwrap_or(U256::MAX)).bits
+}
+
+// Height of the first block in the retarget interval that ends at `parent_height`
+pub fn interval_start_height(params: &ChainParams, parent_height: u64) -> u64 {
+    parent_height.saturating_sub(params.retarget_interval - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_params::Network;
+    use crate::difficulty::MIN_DIFFICULTY_BITS;
+
+    #[test]
+    fn test_block_work() {
+        // Bitcoin's difficulty-1 target needs 2^32 + 2^16 + 1 hashes
+        assert_eq!(block_work(0x1d00ffff), U256::from(0x1_0001_0001u64));
+        // Regtest's target is just under 2^255
+        assert_eq!(block_work(0x207fffff), U256::from(2));
+        assert!(block_work(0x1c00ffff) > block_work(0x1d00ffff));
+        assert_eq!(block_work(0), U256::zero());
+    }
+
+    #[test]
+    fn test_lwma_tracks_solve_times() {
+        let mut params = ChainParams::for_network(Network::Regtest);
+        params.retarget = RetargetAlgorithm::Lwma { window: 10 };
+        let bits = 0x2000ffff;
+        assert_eq!(retarget_heights(&params, 25), (15..=25).collect::<Vec<_>>());
+
+        let on_schedule = (0..11).map(|i| (i * 60, bits)).collect::<Vec<_>>();
+        assert_eq!(next_bits(&params, 25, &on_schedule, 660), bits);
+        // Blocks twice as slow as the target spacing double the target
+        let slow = (0..11).map(|i| (i * 120, bits)).collect::<Vec<_>>();
+        assert_eq!(difficulty::compact_to_target(next_bits(&params, 25, &slow, 1320)), difficulty::compact_to_target(bits) * 2);
+        // Not enough history yet
+        assert_eq!(next_bits(&params, 3, &slow[..4], 480), bits);
+    }
+
+    #[test]
+    fn test_min_difficulty_blocks_on_testnet() {
+        let mut params = ChainParams::for_network(Network::Test);
+        params.retarget_interval = 10;
+        let bits = 0x2000ffff;
+        // Interval starting at height 10, with a min-difficulty block at height 12
+        let ancestors = (0..12).map(|i| (i * 60, bits)).chain([(900, MIN_DIFFICULTY_BITS)]).collect::<Vec<_>>();
+        assert_eq!(retarget_heights(&params, 12), (0..=12).collect::<Vec<_>>());
+
+        // More than twice the spacing after the parent allows minimum difficulty
+        assert_eq!(next_bits(&params, 12, &ancestors, 1021), MIN_DIFFICULTY_BITS);
+        // Otherwise the last regular block's bits apply, not the min-difficulty parent's
+        assert_eq!(next_bits(&params, 12, &ancestors, 960), bits);
+
+        params.network = Network::Main;
+        params.allow_min_difficulty_blocks = false;
+        assert_eq!(next_bits(&params, 12, &[(660, bits)], 1021), bits);
+    }
+
+    #[test]
+    fn test_min_difficulty_lookback_crosses_the_interval_start() {
+        let mut params = ChainParams::for_network(Network::Test);
+        params.retarget_interval = 10;
+        let bits = 0x2000ffff;
+        // Every block of the interval from height 10 was mined at minimum difficulty
+        let mut ancestors = (0..10).map(|i| (i * 60, bits)).collect::<Vec<_>>();
+        ancestors.extend((10..20).map(|i| (i * 180, MIN_DIFFICULTY_BITS)));
+        assert_eq!(retarget_heights(&params, 15), (0..=15).collect::<Vec<_>>());
+        assert_eq!(next_bits(&params, 15, &ancestors[..16], 2760), bits);
+
+        // The retarget at the next boundary starts from the regular bits, over this interval's timespan
+        let (expected, _) = adjust_difficulty(Difficulty::new(bits), 19 * 180 - 10 * 180, 600);
+        assert_eq!(next_bits(&params, 19, &ancestors, 19 * 180 + 60), clamp_bits(&params, expected.bits));
+    }
+
+    #[test]
+    fn test_emergency_difficulty_after_a_stall() {
+        let mut params = ChainParams::for_network(Network::Regtest);
+        let bits = 0x1f00ffff;
+        let target = difficulty::compact_to_target(bits);
+        // Off unless the network asks for it
+        assert_eq!(next_bits(&params, 12, &[(0, bits)], 100_000), bits);
+
+        params.emergency_after_spacings = 10;
+        params.emergency_half_life_secs = 300;
+        assert_eq!(next_bits(&params, 12, &[(0, bits)], 600), bits);
+        assert_eq!(difficulty::compact_to_target(next_bits(&params, 12, &[(0, bits)], 601)), target * 2);
+        assert_eq!(difficulty::compact_to_target(next_bits(&params, 12, &[(0, bits)], 1200)), target * 8);
+        // A long enough stall ends at the pow limit, never past it
+        assert_eq!(next_bits(&params, 12, &[(0, bits)], 100_000), params.pow_limit_bits);
+
+        // It eases whatever the retarget gave, here at an interval boundary with blocks on schedule
+        let ancestors = [(0, bits), (144 * 60, bits)];
+        assert_eq!(next_bits(&params, 143, &ancestors, 145 * 60), bits);
+        assert_eq!(difficulty::compact_to_target(next_bits(&params, 143, &ancestors, 144 * 60 + 601)), target * 2);
+    }
+
+    #[test]
+    fn test_blocks_after_an_emergency_block_return_to_regular_bits() {
+        let mut params = ChainParams::for_network(Network::Regtest);
+        params.retarget_interval = 10;
+        params.emergency_after_spacings = 3;
+        params.emergency_half_life_secs = 600;
+        let bits = 0x1f00ffff;
+        assert_eq!(retarget_heights(&params, 12), (0..=12).collect::<Vec<_>>());
+
+        // Height 12 came an hour after its parent and was mined at eased bits
+        let mut ancestors = (0..12).map(|i| (i * 60, bits)).collect::<Vec<_>>();
+        let stalled = 11 * 60 + 3600;
+        let eased = next_bits(&params, 11, &ancestors, stalled);
+        assert_ne!(eased, bits);
+        ancestors.push((stalled, eased));
+        assert_eq!(next_bits(&params, 12, &ancestors, stalled + 60), bits);
+        ancestors.push((stalled + 60, bits));
+        assert_eq!(next_bits(&params, 13, &ancestors, stalled + 120), bits);
+
+        // Nor does the retarget at the next boundary start from the eased bits
+        ancestors.extend((14..20).map(|i| (stalled + (i - 12) * 60, bits)));
+        let (expected, _) = adjust_difficulty(Difficulty::new(bits), stalled + 7 * 60 - 10 * 60, 600);
+        assert_eq!(next_bits(&params, 19, &ancestors, stalled + 8 * 60), clamp_bits(&params, expected.bits));
+
+        // The same holds under LWMA
+        params.retarget = RetargetAlgorithm::Lwma { window: 10 };
+        let mut on_schedule = (0..11).map(|i| (i * 60, bits)).collect::<Vec<_>>();
+        on_schedule[10] = (9 * 60 + 3600, next_bits(&params, 8, &on_schedule[..10], 9 * 60 + 3600));
+        let all_regular = on_schedule.iter().map(|&(timestamp, _)| (timestamp, bits)).collect::<Vec<_>>();
+        assert_eq!(next_bits(&params, 10, &on_schedule, 9 * 60 + 3660), next_bits(&params, 10, &all_regular, 9 * 60 + 3660));
+    }
+}
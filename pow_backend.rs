@@ -0,0 +1,188 @@
+use crate::blockchain::BlockHeader;
+use crate::pow;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// Pluggable proof-of-work hashers for miners built on the crate. In-node mining is out of scope:
+// the node only hands out templates through getblocktemplate, so nothing in it calls
+// `PowDispatcher`.
+
+// Hashes a CPU thread tries between checks for newer work
+const CANCEL_CHECK_INTERVAL: u64 = 4096;
+
+// A slice of the nonce space for one header. `nonce_prefix` is everything hashed before the nonce,
+// so a hasher absorbs it once and then only appends each nonce as 8 little-endian bytes.
+#[derive(Debug, Clone)]
+pub struct Work {
+    pub job_id: u64,
+    pub nonce_prefix: Vec<u8>,
+    // Big-endian; a hash at or below it solves the block
+    pub target: [u8; 32],
+    pub nonces: RangeInclusive<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Solution {
+    pub job_id: u64,
+    pub nonce: u64,
+}
+
+// Anything that can search nonces: the CPU hasher below, or a driver for GPUs, FPGAs or a remote rig.
+// `submit` returns straight away and solutions are sent as they're found. They're checked before
+// use, so a faulty device can waste time but can't get a bad block through.
+pub trait PowBackend: Send + Sync {
+    fn name(&self) -> &str;
+    // Replaces any work in progress
+    fn submit(&self, work: Work, solutions: mpsc::UnboundedSender<Solution>);
+    fn cancel(&self);
+}
+
+pub struct CpuBackend {
+    threads: u64,
+    // Bumped by every submit and cancel; threads stop once it moves past the value they started with
+    generation: Arc<AtomicU64>,
+}
+
+impl CpuBackend {
+    pub fn new(threads: usize) -> Self {
+        CpuBackend { threads: threads.max(1) as u64, generation: Arc::new(AtomicU64::new(0)) }
+    }
+}
+
+impl PowBackend for CpuBackend {
+    fn name(&self) -> &str {
+        "cpu"
+    }
+
+    fn submit(&self, work: Work, solutions: mpsc::UnboundedSender<Solution>) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut midstate = blake3::Hasher::new();
+        midstate.update(&work.nonce_prefix);
+        let work = Arc::new(work);
+
+        for thread in 0..self.threads {
+            let current = Arc::clone(&self.generation);
+            let midstate = midstate.clone();
+            let work = Arc::clone(&work);
+            let solutions = solutions.clone();
+            let step = self.threads;
+            std::thread::spawn(move || {
+                // Threads take interleaved nonces so they finish the range together
+                let mut nonce = work.nonces.start().checked_add(thread);
+                let mut tried = 0u64;
+                while let Some(n) = nonce.filter(|n| n <= work.nonces.end()) {
+                    if tried % CANCEL_CHECK_INTERVAL == 0 && current.load(Ordering::Relaxed) != generation {
+                        return;
+                    }
+                    let mut hasher = midstate.clone();
+                    hasher.update(&n.to_le_bytes());
+                    if hasher.finalize().as_bytes() <= &work.target && solutions.send(Solution { job_id: work.job_id, nonce: n }).is_err() {
+                        return;
+                    }
+                    tried += 1;
+                    nonce = n.checked_add(step);
+                }
+            });
+        }
+    }
+
+    fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for CpuBackend {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+// Splits each header's nonce space evenly across the backends and waits for the first valid solution
+pub struct PowDispatcher {
+    backends: Vec<Box<dyn PowBackend>>,
+    next_job_id: AtomicU64,
+}
+
+impl PowDispatcher {
+    pub fn new(backends: Vec<Box<dyn PowBackend>>) -> Self {
+        PowDispatcher { backends, next_job_id: AtomicU64::new(0) }
+    }
+
+    // `header` with a nonce meeting its bits, or `None` if the backends searched their ranges without
+    // success or were cancelled. Dropping the future leaves the backends running until `cancel`.
+    pub async fn solve(&self, mut header: BlockHeader) -> Option<BlockHeader> {
+        let target = pow::compact_to_target(header.bits);
+        if self.backends.is_empty() || target == [0; 32] {
+            return None;
+        }
+        let job_id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
+        let nonce_prefix = header.nonce_prefix();
+        let (sender, mut solutions) = mpsc::unbounded_channel();
+        let share = u64::MAX / self.backends.len() as u64;
+        for (i, backend) in self.backends.iter().enumerate() {
+            let start = i as u64 * share;
+            let end = if i + 1 == self.backends.len() { u64::MAX } else { start + share - 1 };
+            backend.submit(Work { job_id, nonce_prefix: nonce_prefix.clone(), target, nonces: start..=end }, sender.clone());
+        }
+        drop(sender);
+
+        // Ends once every backend has finished and dropped its sender
+        while let Some(solution) = solutions.recv().await {
+            if solution.job_id != job_id {
+                continue;
+            }
+            header.nonce = solution.nonce;
+            if pow::check_proof_of_work(&header.hash(), header.bits) {
+                self.cancel();
+                return Some(header);
+            }
+            log::warn!("Mining backend returned nonce {} which doesn't meet the target", solution.nonce);
+        }
+        None
+    }
+
+    pub fn cancel(&self) {
+        for backend in &self.backends {
+            backend.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cpu_backends_find_a_valid_nonce() {
+        let dispatcher = PowDispatcher::new(vec![Box::new(CpuBackend::new(2)), Box::new(CpuBackend::new(1))]);
+        let header = BlockHeader {
+            version: 2,
+            previous_hash: [1; 32],
+            merkle_root: [2; 32],
+            fruits_root: [3; 32],
+            timestamp: 1_700_000_000,
+            bits: 0x1f00ffff,
+            nonce: 0,
+        };
+        assert_eq!(header.nonce_prefix().len(), bincode::serialized_size(&header).unwrap() as usize - 8);
+
+        let solved = dispatcher.solve(header.clone()).await.unwrap();
+        assert!(pow::check_proof_of_work(&solved.hash(), solved.bits));
+        assert_eq!(BlockHeader { nonce: 0, ..solved }, header);
+    }
+    #[tokio::test]
+    async fn test_cpu_backend_tries_the_last_nonce() {
+        let (sender, mut solutions) = mpsc::unbounded_channel();
+        let work = Work { job_id: 7, nonce_prefix: vec![1, 2, 3], target: [0xff; 32], nonces: u64::MAX - 2..=u64::MAX };
+        let backend = CpuBackend::new(2);
+        backend.submit(work, sender);
+        let mut nonces = Vec::new();
+        while let Some(solution) = solutions.recv().await {
+            nonces.push(solution.nonce);
+        }
+        nonces.sort_unstable();
+        assert_eq!(nonces, vec![u64::MAX - 2, u64::MAX - 1, u64::MAX]);
+    }
+}
@@ -0,0 +1,355 @@
+use crate::block_filter::{BlockFilter, FilterHeader};
+use crate::blockchain::{Block, BlockHash, BlockHeader, SignedBlock};
+use crate::node_config::{BlockchainConfig, NodeMode};
+use crate::snapshot::{SnapshotChunk, SnapshotManifest};
+use crate::transaction::{Transaction, TxHash};
+use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
+use thiserror::Error;
+
+// Version 2 added service-flag negotiation and compact filter messages. Version 3 asks for headers
+// by block locator instead of by height, which older peers can't parse. Version 4 frames a block's
+// parts (codec format 3).
+pub const PROTOCOL_VERSION: u32 = 4;
+pub const MIN_PEER_PROTOCOL_VERSION: u32 = 4;
+
+// Identifies a connection for as long as it lasts; never reused within a run
+pub type PeerId = u64;
+
+// Service flags advertised in the version message
+pub const NODE_NETWORK: u64 = 1 << 0;
+// Serves only recent blocks because older ones were pruned
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 1;
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 2;
+pub const NODE_FRUIT_RELAY: u64 = 1 << 3;
+// Keeps a recent UTXO snapshot for new nodes to sync from
+pub const NODE_UTXO_SNAPSHOTS: u64 = 1 << 4;
+
+// `from_snapshot` says whether the chainstate was loaded from a snapshot, or is about to be, leaving
+// the blocks below its base missing just as pruning would
+pub fn local_services(config: &BlockchainConfig, from_snapshot: bool) -> u64 {
+    let snapshots = if config.network.serve_snapshots { NODE_UTXO_SNAPSHOTS } else { 0 };
+    match config.mode {
+        NodeMode::Light => 0,
+        NodeMode::Full if config.pruning.enabled || from_snapshot => NODE_NETWORK_LIMITED | NODE_COMPACT_FILTERS | NODE_FRUIT_RELAY | snapshots,
+        NodeMode::Full => NODE_NETWORK | NODE_COMPACT_FILTERS | NODE_FRUIT_RELAY | snapshots,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VersionMessage {
+    pub version: u32,
+    pub services: u64,
+    pub timestamp: u64,
+    // Random per connection, used to detect connecting to ourselves
+    pub nonce: u64,
+    pub user_agent: String,
+    pub start_height: Option<u64>,
+    // Whether the peer wants unsolicited transaction invs
+    pub relay: bool,
+}
+
+// Most addresses a single addr message may carry
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+// Most headers a headers message may carry; a full one means the sender has more
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+// Most hashes a getheaders locator may carry, enough for a chain of 2^90 blocks
+pub const MAX_LOCATOR_SIZE: usize = 101;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetAddress {
+    pub addr: SocketAddr,
+    pub services: u64,
+    // Unix time the address was last known to be reachable
+    pub last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Inventory {
+    Transaction(TxHash),
+    Block(BlockHash),
+    Fruit(BlockHash),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    Version(VersionMessage),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    Addr(Vec<NetAddress>),
+    GetAddr,
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
+    Tx(Transaction),
+    Block(Block),
+    Fruit(SignedBlock),
+    // Headers after the last `locator` hash on the receiver's active chain, up to `stop_hash`
+    // inclusive or MAX_HEADERS_PER_MESSAGE. A zero `stop_hash` asks for as many as fit.
+    GetHeaders { locator: Vec<BlockHash>, stop_hash: BlockHash },
+    Headers(Vec<BlockHeader>),
+    GetCFilters { start_height: u64, count: u64 },
+    CFilter { block_hash: BlockHash, filter: BlockFilter },
+    GetCFHeaders { start_height: u64, count: u64 },
+    CFHeaders(Vec<(BlockHash, FilterHeader)>),
+    // The manifest of the receiver's snapshot at `block_hash`, answered with notfound for the block
+    // when it has none
+    GetSnapshot { block_hash: BlockHash },
+    Snapshot(SnapshotManifest),
+    GetSnapshotChunk { block_hash: BlockHash, index: u32 },
+    SnapshotChunk(SnapshotChunk),
+}
+
+impl Message {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Addr(_) => "addr",
+            Message::GetAddr => "getaddr",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::NotFound(_) => "notfound",
+            Message::Tx(_) => "tx",
+            Message::Block(_) => "block",
+            Message::Fruit(_) => "fruit",
+            Message::GetHeaders { .. } => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::GetCFilters { .. } => "getcfilters",
+            Message::CFilter { .. } => "cfilter",
+            Message::GetCFHeaders { .. } => "getcfheaders",
+            Message::CFHeaders(_) => "cfheaders",
+            Message::GetSnapshot { .. } => "getsnapshot",
+            Message::Snapshot(_) => "snapshot",
+            Message::GetSnapshotChunk { .. } => "getsnapchunk",
+            Message::SnapshotChunk(_) => "snapchunk",
+        }
+    }
+
+    // Service the node handling this message must offer, for message types that are optional
+    pub fn required_service(&self) -> Option<u64> {
+        match self {
+            Message::Fruit(_) => Some(NODE_FRUIT_RELAY),
+            Message::Inv(items) | Message::GetData(items) if items.iter().any(|i| matches!(i, Inventory::Fruit(_))) => Some(NODE_FRUIT_RELAY),
+            Message::GetCFilters { .. } | Message::CFilter { .. } | Message::GetCFHeaders { .. } | Message::CFHeaders(_) => Some(NODE_COMPACT_FILTERS),
+            Message::GetSnapshot { .. } | Message::Snapshot(_) | Message::GetSnapshotChunk { .. } | Message::SnapshotChunk(_) => Some(NODE_UTXO_SNAPSHOTS),
+            _ => None,
+        }
+    }
+
+    // Answers to a request for an optional service, which only the side offering it sends
+    fn is_served(&self) -> bool {
+        matches!(self, Message::CFilter { .. } | Message::CFHeaders(_) | Message::Snapshot(_) | Message::SnapshotChunk(_))
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum HandshakeError {
+    #[error("Peer protocol version {version} is below the minimum {minimum}")]
+    ObsoleteVersion { version: u32, minimum: u32 },
+    #[error("Received {0} before the handshake completed")]
+    UnexpectedMessage(&'static str),
+    #[error("Peer sent a second version message")]
+    DuplicateVersion,
+    #[error("Connected to ourselves")]
+    SelfConnection,
+}
+
+// What both sides agreed on once version and verack have been exchanged
+#[derive(Debug, Clone, PartialEq)]
+pub struct NegotiatedPeer {
+    pub version: u32,
+    pub local_services: u64,
+    pub remote_services: u64,
+    pub user_agent: String,
+    pub start_height: Option<u64>,
+    pub relay: bool,
+    // The peer's clock minus ours in seconds, from the timestamps in the two version messages
+    pub time_offset: i64,
+}
+
+impl NegotiatedPeer {
+    pub fn has_service(&self, service: u64) -> bool {
+        self.remote_services & service == service
+    }
+
+    fn offers(&self, service: u64) -> bool {
+        self.local_services & service == service
+    }
+
+    // Whether we may send `message` to this peer: requests go to a peer offering the service, and
+    // answers only come from us if we advertised it
+    pub fn can_send(&self, message: &Message) -> bool {
+        match message.required_service() {
+            Some(service) if message.is_served() => self.offers(service),
+            Some(service) => self.has_service(service),
+            None => !matches!(message, Message::Tx(_)) || self.relay,
+        }
+    }
+
+    // Whether an incoming `message` fits what was advertised: requests for a service we offer, and
+    // answers from a peer that offers it
+    pub fn accepts(&self, message: &Message) -> bool {
+        match message.required_service() {
+            Some(service) if message.is_served() => self.has_service(service),
+            Some(service) => self.offers(service),
+            None => true,
+        }
+    }
+}
+
+// Version/verack exchange for one connection. Both sides send version first, answer the peer's
+// version with verack, and treat the connection as established once they have seen both.
+pub struct Handshake {
+    local: VersionMessage,
+    remote: Option<VersionMessage>,
+    verack_received: bool,
+}
+
+impl Handshake {
+    pub fn new(local: VersionMessage) -> Self {
+        Handshake { local, remote: None, verack_received: false }
+    }
+
+    // The message that opens the handshake
+    pub fn start(&self) -> Message {
+        Message::Version(self.local.clone())
+    }
+
+    // Handles a message received before the handshake completed, returning any replies
+    pub fn on_message(&mut self, message: Message) -> Result<Vec<Message>, HandshakeError> {
+        match message {
+            Message::Version(_) if self.remote.is_some() => Err(HandshakeError::DuplicateVersion),
+            Message::Version(remote) => {
+                if remote.nonce == self.local.nonce {
+                    return Err(HandshakeError::SelfConnection);
+                }
+                if remote.version < MIN_PEER_PROTOCOL_VERSION {
+                    return Err(HandshakeError::ObsoleteVersion { version: remote.version, minimum: MIN_PEER_PROTOCOL_VERSION });
+                }
+                self.remote = Some(remote);
+                Ok(vec![Message::Verack])
+            }
+            Message::Verack if self.remote.is_some() => {
+                self.verack_received = true;
+                Ok(Vec::new())
+            }
+            other => Err(HandshakeError::UnexpectedMessage(other.command())),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remote.is_some() && self.verack_received
+    }
+
+    pub fn negotiated(&self) -> Option<NegotiatedPeer> {
+        if !self.verack_received {
+            return None;
+        }
+        let remote = self.remote.as_ref()?;
+        Some(NegotiatedPeer {
+            version: remote.version.min(self.local.version),
+            local_services: self.local.services,
+            remote_services: remote.services,
+            user_agent: remote.user_agent.clone(),
+            start_height: remote.start_height,
+            relay: remote.relay,
+            time_offset: remote.timestamp as i64 - self.local.timestamp as i64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn version(nonce: u64, services: u64, version: u32) -> VersionMessage {
+        VersionMessage {
+            version,
+            services,
+            timestamp: 0,
+            nonce,
+            user_agent: "/xcore-test/".to_string(),
+            start_height: Some(10),
+            relay: true,
+        }
+    }
+
+    #[test]
+    fn test_handshake_negotiates_services() {
+        let mut handshake = Handshake::new(version(1, NODE_NETWORK | NODE_COMPACT_FILTERS, PROTOCOL_VERSION));
+        assert_eq!(handshake.on_message(Message::Verack).unwrap_err(), HandshakeError::UnexpectedMessage("verack"));
+
+        let replies = handshake.on_message(Message::Version(version(2, NODE_NETWORK_LIMITED, PROTOCOL_VERSION))).unwrap();
+        assert!(matches!(replies.as_slice(), [Message::Verack]));
+        assert!(!handshake.is_complete());
+        handshake.on_message(Message::Verack).unwrap();
+
+        let peer = handshake.negotiated().unwrap();
+        let filters = Message::GetCFilters { start_height: 0, count: 1 };
+        // We serve filters but the pruned peer doesn't
+        assert!(peer.accepts(&filters));
+        assert!(!peer.can_send(&filters));
+        assert!(peer.can_send(&Message::Ping(1)));
+    }
+
+    #[test]
+    fn test_nodes_missing_old_blocks_advertise_limited_service() {
+        let datadir = TempDir::new().unwrap();
+        let mut config = BlockchainConfig::load(datadir.path()).unwrap();
+        config.mode = NodeMode::Full;
+        config.pruning.enabled = false;
+        let block_service = |config: &BlockchainConfig, from_snapshot| local_services(config, from_snapshot) & (NODE_NETWORK | NODE_NETWORK_LIMITED);
+        assert_eq!(block_service(&config, false), NODE_NETWORK);
+        // Blocks below a snapshot's base were never downloaded
+        assert_eq!(block_service(&config, true), NODE_NETWORK_LIMITED);
+        config.pruning.enabled = true;
+        assert_eq!(block_service(&config, false), NODE_NETWORK_LIMITED);
+        // Snapshots are only offered by nodes set up to take them
+        assert_eq!(local_services(&config, false) & NODE_UTXO_SNAPSHOTS, 0);
+        config.network.serve_snapshots = true;
+        assert_eq!(local_services(&config, false) & NODE_UTXO_SNAPSHOTS, NODE_UTXO_SNAPSHOTS);
+    }
+
+    #[test]
+    fn test_filters_are_served_by_the_side_offering_them() {
+        let filter = Message::CFilter { block_hash: [1; 32], filter: BlockFilter::build(&[1; 32], &[]) };
+        let request = Message::GetCFilters { start_height: 0, count: 1 };
+
+        // A full node answering a light client that offers nothing
+        let mut handshake = Handshake::new(version(1, NODE_NETWORK | NODE_COMPACT_FILTERS, PROTOCOL_VERSION));
+        handshake.on_message(Message::Version(version(2, 0, PROTOCOL_VERSION))).unwrap();
+        handshake.on_message(Message::Verack).unwrap();
+        let server = handshake.negotiated().unwrap();
+        assert!(server.accepts(&request));
+        assert!(server.can_send(&filter));
+        assert!(!server.accepts(&filter));
+
+        // The light client's side of the same connection
+        let mut handshake = Handshake::new(version(2, 0, PROTOCOL_VERSION));
+        handshake.on_message(Message::Version(version(1, NODE_NETWORK | NODE_COMPACT_FILTERS, PROTOCOL_VERSION))).unwrap();
+        handshake.on_message(Message::Verack).unwrap();
+        let client = handshake.negotiated().unwrap();
+        assert!(client.can_send(&request));
+        assert!(client.accepts(&filter));
+        assert!(!client.accepts(&request));
+        assert!(!client.can_send(&filter));
+    }
+
+    #[test]
+    fn test_handshake_rejects_old_and_self_connections() {
+        let mut handshake = Handshake::new(version(1, NODE_NETWORK, PROTOCOL_VERSION));
+        assert_eq!(
+            handshake.on_message(Message::Version(version(2, NODE_NETWORK, 1))).unwrap_err(),
+            HandshakeError::ObsoleteVersion { version: 1, minimum: MIN_PEER_PROTOCOL_VERSION },
+        );
+        assert_eq!(
+            handshake.on_message(Message::Version(version(1, NODE_NETWORK, PROTOCOL_VERSION))).unwrap_err(),
+            HandshakeError::SelfConnection,
+        );
+    }
+}
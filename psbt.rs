@@ -0,0 +1,262 @@
+use crate::codec::{self, CodecError};
+use crate::multisig::{MultisigError, MultisigScript, MultisigWitness};
+use crate::transaction::{Transaction, TransactionError, TxOutput};
+use ed25519_dalek::{Signer, SigningKey};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+// Transactions passed between a wallet that can see coins and the machines that hold the keys for
+// them. Alongside the unsigned transaction each input carries the output it spends, so a signer
+// can check amounts and fee without a copy of the chain, and where known the key path to sign with.
+// The roles are those of BIP174: a creator builds it, signers add signatures, a combiner merges
+// copies signed by different parties and a finalizer turns it into a transaction to broadcast.
+// Inputs spending a multisig output carry an encoded `MultisigWitness` as their signature, which
+// collects cosigners' signatures until it has enough.
+const PSBT_MAGIC: [u8; 4] = *b"XPST";
+
+#[derive(Error, Debug)]
+pub enum PsbtError {
+    #[error("Codec error: {0}")]
+    Codec(#[from] CodecError),
+    #[error("Not a partially signed transaction")]
+    BadMagic,
+    #[error("Transaction has {inputs} inputs but {entries} input entries")]
+    InputCountMismatch { inputs: usize, entries: usize },
+    #[error("Outputs are worth more than the inputs they spend")]
+    OutputsExceedInputs,
+    #[error("Cannot combine partially signed transactions for different transactions")]
+    TransactionMismatch,
+    #[error("Input {0} is not signed")]
+    MissingSignature(usize),
+    #[error("Transaction error: {0}")]
+    Transaction(#[from] TransactionError),
+    #[error("Input {index}: {source}")]
+    Multisig { index: usize, source: MultisigError },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PsbtInput {
+    pub spent_output: TxOutput,
+    // e.g. m/44'/1'/0'/0'/3' for keys a seed can re-derive; None for imported keys
+    pub key_path: Option<String>,
+    pub signature: Option<Vec<u8>>,
+}
+
+impl PsbtInput {
+    pub fn multisig_script(&self) -> Option<MultisigScript> {
+        MultisigScript::from_script(&self.spent_output.script_pubkey)
+    }
+
+    // The signatures collected so far for a multisig input; None for single-key ones
+    fn witness(&self, index: usize) -> Result<Option<MultisigWitness>, PsbtError> {
+        let Some(script) = self.multisig_script() else { return Ok(None) };
+        let witness = match &self.signature {
+            Some(bytes) => MultisigWitness::decode(bytes).map_err(|source| PsbtError::Multisig { index, source })?,
+            None => MultisigWitness::new(script.clone()),
+        };
+        if witness.script != script {
+            return Err(PsbtError::Multisig { index, source: MultisigError::MalformedWitness });
+        }
+        Ok(Some(witness))
+    }
+
+    fn is_signed(&self, index: usize) -> bool {
+        match self.witness(index) {
+            Ok(Some(witness)) => witness.is_complete(),
+            Ok(None) => self.signature.is_some(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartiallySignedTransaction {
+    pub tx: Transaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+impl PartiallySignedTransaction {
+    // Signatures already in `tx` are moved into the input entries
+    pub fn new(mut tx: Transaction, mut inputs: Vec<PsbtInput>) -> Result<Self, PsbtError> {
+        if tx.inputs.len() != inputs.len() {
+            return Err(PsbtError::InputCountMismatch { inputs: tx.inputs.len(), entries: inputs.len() });
+        }
+        for (tx_input, input) in tx.inputs.iter_mut().zip(&mut inputs) {
+            let signature = std::mem::take(&mut tx_input.signature);
+            if !signature.is_empty() {
+                input.signature = Some(signature);
+            }
+        }
+        Ok(PartiallySignedTransaction { tx, inputs })
+    }
+
+    // Multisig inputs count once they have as many signatures as their script requires
+    pub fn is_complete(&self) -> bool {
+        self.inputs.iter().enumerate().all(|(index, input)| input.is_signed(index))
+    }
+
+    // Signs every input spending from `key`, or from a multisig script it is one of the keys of,
+    // returning how many were signed
+    pub fn sign(&mut self, key: &SigningKey) -> Result<usize, PsbtError> {
+        let public_key = key.verifying_key().to_bytes();
+        let message = self.tx.signature_hash()?;
+        let mut signed = 0;
+        for (index, (tx_input, input)) in self.tx.inputs.iter().zip(&mut self.inputs).enumerate() {
+            if let Some(mut witness) = input.witness(index)? {
+                let Some(position) = witness.script.keys().iter().position(|cosigner| *cosigner == public_key) else { continue };
+                witness.add_signature(position, key.sign(&message).to_bytes().to_vec())
+                    .map_err(|source| PsbtError::Multisig { index, source })?;
+                input.signature = Some(witness.encode());
+                signed += 1;
+            } else if tx_input.public_key == public_key {
+                input.signature = Some(key.sign(&message).to_bytes().to_vec());
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+
+    // Takes the signatures `other` has and this copy lacks, including cosigners' signatures of
+    // multisig inputs
+    pub fn combine(&mut self, other: &Self) -> Result<(), PsbtError> {
+        if self.tx != other.tx {
+            return Err(PsbtError::TransactionMismatch);
+        }
+        for (index, (input, other_input)) in self.inputs.iter_mut().zip(&other.inputs).enumerate() {
+            if let (Some(mut witness), Some(other_witness)) = (input.witness(index)?, other_input.witness(index)?) {
+                for (position, signature) in other_witness.signatures {
+                    if witness.signatures.iter().all(|(existing, _)| *existing != position) {
+                        witness.add_signature(position as usize, signature).map_err(|source| PsbtError::Multisig { index, source })?;
+                    }
+                }
+                if !witness.signatures.is_empty() {
+                    input.signature = Some(witness.encode());
+                }
+            } else if input.signature.is_none() {
+                input.signature = other_input.signature.clone();
+            }
+        }
+        Ok(())
+    }
+
+    // The signed transaction, once every input has a valid signature. Multisig inputs keep only
+    // as many signatures as their script requires.
+    pub fn finalize(&self) -> Result<Transaction, PsbtError> {
+        let mut tx = self.tx.clone();
+        for (index, (tx_input, input)) in tx.inputs.iter_mut().zip(&self.inputs).enumerate() {
+            if !input.is_signed(index) {
+                return Err(PsbtError::MissingSignature(index));
+            }
+            tx_input.signature = match input.witness(index)? {
+                Some(witness) => witness.trimmed().encode(),
+                None => input.signature.clone().ok_or(PsbtError::MissingSignature(index))?,
+            };
+        }
+        tx.verify_signatures()?;
+        Ok(tx)
+    }
+
+    // The transaction with whatever signatures have been collected, unsigned inputs left empty, for
+    // passing on to a signer that works on raw transactions rather than PSBTs
+    pub fn partial_transaction(&self) -> Transaction {
+        let mut tx = self.tx.clone();
+        for (tx_input, input) in tx.inputs.iter_mut().zip(&self.inputs) {
+            tx_input.signature = input.signature.clone().unwrap_or_default();
+        }
+        tx
+    }
+
+    pub fn fee(&self) -> Result<u64, PsbtError> {
+        let input_value = self.inputs.iter().try_fold(0u64, |sum, input| sum.checked_add(input.spent_output.value));
+        let output_value = self.tx.outputs.iter().try_fold(0u64, |sum, output| sum.checked_add(output.value));
+        input_value.zip(output_value)
+            .and_then(|(input_value, output_value)| input_value.checked_sub(output_value))
+            .ok_or(PsbtError::OutputsExceedInputs)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PsbtError> {
+        let mut bytes = PSBT_MAGIC.to_vec();
+        bytes.extend(codec::encode(self)?);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PsbtError> {
+        let payload = bytes.strip_prefix(&PSBT_MAGIC[..]).ok_or(PsbtError::BadMagic)?;
+        let psbt: Self = codec::decode(payload)?;
+        Self::new(psbt.tx, psbt.inputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{OutPoint, TxInput, SEQUENCE_FINAL};
+
+    #[test]
+    fn test_sign_combine_finalize() -> Result<(), PsbtError> {
+        let keys = [SigningKey::from_bytes(&[1; 32]), SigningKey::from_bytes(&[2; 32])];
+        let inputs = keys.iter().enumerate()
+            .map(|(index, key)| TxInput {
+                previous_output: OutPoint { txid: [5; 32], index: index as u32 },
+                public_key: key.verifying_key().to_bytes(),
+                signature: Vec::new(),
+                sequence: SEQUENCE_FINAL,
+            })
+            .collect();
+        let tx = Transaction { inputs, outputs: vec![TxOutput { value: 150, script_pubkey: vec![3; 32] }], lock_time: 0 };
+        let entries = keys.iter()
+            .map(|key| PsbtInput { spent_output: TxOutput { value: 100, script_pubkey: key.verifying_key().to_bytes().to_vec() }, key_path: None, signature: None })
+            .collect();
+        let unsigned = PartiallySignedTransaction::new(tx, entries)?;
+        assert_eq!(unsigned.fee()?, 50);
+
+        // Each party signs its own copy
+        let mut first = PartiallySignedTransaction::from_bytes(&unsigned.to_bytes()?)?;
+        let mut second = first.clone();
+        assert_eq!(first.sign(&keys[0])?, 1);
+        assert_eq!(second.sign(&keys[1])?, 1);
+        assert!(matches!(first.finalize(), Err(PsbtError::MissingSignature(1))));
+
+        first.combine(&second)?;
+        assert!(first.is_complete());
+        first.finalize()?.verify_signatures()?;
+
+        let mut other = unsigned.clone();
+        other.tx.lock_time = 1;
+        assert!(matches!(first.combine(&other), Err(PsbtError::TransactionMismatch)));
+        assert!(matches!(PartiallySignedTransaction::from_bytes(b"nope"), Err(PsbtError::BadMagic)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_multisig_input_collects_cosigner_signatures() -> Result<(), PsbtError> {
+        let keys = [1u8, 2, 3].map(|seed| SigningKey::from_bytes(&[seed; 32]));
+        let script = MultisigScript::new(2, keys.iter().map(|key| key.verifying_key().to_bytes()).collect()).unwrap();
+        let input = TxInput {
+            previous_output: OutPoint { txid: [5; 32], index: 0 },
+            public_key: script.script_id(),
+            signature: Vec::new(),
+            sequence: SEQUENCE_FINAL,
+        };
+        let tx = Transaction { inputs: vec![input], outputs: vec![TxOutput { value: 90, script_pubkey: vec![3; 32] }], lock_time: 0 };
+        let entry = PsbtInput { spent_output: TxOutput { value: 100, script_pubkey: script.to_script() }, key_path: None, signature: None };
+        let unsigned = PartiallySignedTransaction::new(tx, vec![entry])?;
+
+        // All three cosigners sign separately; any two would do
+        let mut copies = [unsigned.clone(), unsigned.clone(), unsigned];
+        for (copy, key) in copies.iter_mut().zip(&keys) {
+            assert_eq!(copy.sign(key)?, 1);
+            assert!(!copy.is_complete());
+        }
+        assert!(matches!(copies[0].finalize(), Err(PsbtError::MissingSignature(0))));
+        let [mut combined, second, third] = copies;
+        combined.combine(&second)?;
+        combined.combine(&third)?;
+        assert!(combined.is_complete());
+
+        let tx = combined.finalize()?;
+        tx.verify_signatures()?;
+        assert_eq!(MultisigWitness::decode(&tx.inputs[0].signature).unwrap().signatures.len(), 2);
+        Ok(())
+    }
+}
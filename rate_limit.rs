@@ -0,0 +1,197 @@
+use crate::protocol::{Message, MAX_ADDR_PER_MESSAGE, MAX_HEADERS_PER_MESSAGE, MAX_LOCATOR_SIZE};
+use std::collections::VecDeque;
+use std::time::Instant;
+use thiserror::Error;
+
+// Largest inv or getdata a peer may send in one message
+pub const MAX_INV_PER_MESSAGE: usize = 50_000;
+// Payload cap for every message that isn't a block, fruit or transaction
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+// Room for the block's envelope on top of the consensus block size
+const BLOCK_MESSAGE_OVERHEAD: usize = 1024;
+// Bytes queued for a single peer before it is considered too slow to keep
+pub const MAX_SEND_QUEUE_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RateLimitError {
+    #[error("Peer exceeded the {0} rate limit")]
+    Flooding(&'static str),
+    #[error("{command} carries {count} entries, more than the {max} allowed")]
+    TooManyEntries { command: &'static str, count: usize, max: usize },
+    #[error("Send queue exceeded {0} bytes")]
+    SendQueueOverflow(usize),
+}
+
+// Allows bursts up to `capacity` and a sustained `refill_per_sec`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, refill_per_sec: f64, now: Instant) -> Self {
+        TokenBucket { capacity, tokens: capacity, refill_per_sec, last_refill: now }
+    }
+
+    pub fn try_consume(&mut self, cost: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+}
+
+// Inbound limits for one peer. Inventory costs one token per entry, header requests one per message.
+// Snapshot requests cost one each from a smaller bucket, as a chunk is a megabyte to send.
+pub struct PeerRateLimiter {
+    inv: TokenBucket,
+    getdata: TokenBucket,
+    headers: TokenBucket,
+    snapshots: TokenBucket,
+}
+
+impl PeerRateLimiter {
+    pub fn new(now: Instant) -> Self {
+        PeerRateLimiter {
+            inv: TokenBucket::new(MAX_INV_PER_MESSAGE as f64, 1_000.0, now),
+            getdata: TokenBucket::new(MAX_INV_PER_MESSAGE as f64, 1_000.0, now),
+            headers: TokenBucket::new(20.0, 2.0, now),
+            snapshots: TokenBucket::new(16.0, 4.0, now),
+        }
+    }
+
+    pub fn check(&mut self, message: &Message, now: Instant) -> Result<(), RateLimitError> {
+        let allowed = match message {
+            Message::Inv(items) => self.inv.try_consume(items.len() as f64, now),
+            Message::GetData(items) => self.getdata.try_consume(items.len() as f64, now),
+            Message::GetHeaders { .. } | Message::GetCFHeaders { .. } | Message::GetCFilters { .. } => self.headers.try_consume(1.0, now),
+            Message::GetSnapshot { .. } | Message::GetSnapshotChunk { .. } => self.snapshots.try_consume(1.0, now),
+            _ => true,
+        };
+        if allowed { Ok(()) } else { Err(RateLimitError::Flooding(message.command())) }
+    }
+}
+
+// Largest payload a `command` message may have on a chain with `max_block_size` blocks. The wire
+// codec applies it to the length in the frame header, so oversized messages are never buffered.
+// A transaction may take up a whole block.
+pub fn max_message_size(command: &str, max_block_size: usize) -> usize {
+    match command {
+        "block" | "fruit" => max_block_size + BLOCK_MESSAGE_OVERHEAD,
+        "tx" => (max_block_size + BLOCK_MESSAGE_OVERHEAD).max(MAX_MESSAGE_SIZE),
+        _ => MAX_MESSAGE_SIZE,
+    }
+}
+
+// Entry-count limits checked after decoding
+pub fn check_message_entries(message: &Message) -> Result<(), RateLimitError> {
+    let (count, max) = match message {
+        Message::Inv(items) | Message::GetData(items) | Message::NotFound(items) => (items.len(), MAX_INV_PER_MESSAGE),
+        Message::Addr(addresses) => (addresses.len(), MAX_ADDR_PER_MESSAGE),
+        Message::Headers(headers) => (headers.len(), MAX_HEADERS_PER_MESSAGE),
+        Message::GetHeaders { locator, .. } => (locator.len(), MAX_LOCATOR_SIZE),
+        _ => return Ok(()),
+    };
+    if count > max {
+        return Err(RateLimitError::TooManyEntries { command: message.command(), count, max });
+    }
+    Ok(())
+}
+
+// Outbound messages waiting for a peer's socket. A peer that stops reading fills it up and is
+// disconnected rather than letting its backlog grow without bound.
+pub struct SendQueue {
+    messages: VecDeque<Vec<u8>>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl SendQueue {
+    pub fn new(max_bytes: usize) -> Self {
+        SendQueue { messages: VecDeque::new(), bytes: 0, max_bytes }
+    }
+
+    pub fn push(&mut self, message: Vec<u8>) -> Result<(), RateLimitError> {
+        if self.bytes + message.len() > self.max_bytes {
+            return Err(RateLimitError::SendQueueOverflow(self.max_bytes));
+        }
+        self.bytes += message.len();
+        self.messages.push_back(message);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let message = self.messages.pop_front()?;
+        self.bytes -= message.len();
+        Some(message)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Default for SendQueue {
+    fn default() -> Self {
+        Self::new(MAX_SEND_QUEUE_BYTES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Inventory;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket_throttles_and_refills() {
+        let start = Instant::now();
+        let mut limiter = PeerRateLimiter::new(start);
+        let request = Message::GetHeaders { locator: vec![[0; 32]], stop_hash: [0; 32] };
+        for _ in 0..20 {
+            assert!(limiter.check(&request, start).is_ok());
+        }
+        assert_eq!(limiter.check(&request, start), Err(RateLimitError::Flooding("getheaders")));
+        assert!(limiter.check(&request, start + Duration::from_secs(1)).is_ok());
+
+        let inv = Message::Inv(vec![Inventory::Transaction([0; 32]); MAX_INV_PER_MESSAGE]);
+        assert!(limiter.check(&inv, start).is_ok());
+        assert!(limiter.check(&Message::Inv(vec![Inventory::Transaction([1; 32])]), start).is_err());
+    }
+
+    #[test]
+    fn test_entry_counts_are_capped() {
+        assert!(check_message_entries(&Message::Inv(vec![Inventory::Block([0; 32]); MAX_INV_PER_MESSAGE])).is_ok());
+        assert_eq!(
+            check_message_entries(&Message::GetData(vec![Inventory::Block([0; 32]); MAX_INV_PER_MESSAGE + 1])),
+            Err(RateLimitError::TooManyEntries { command: "getdata", count: MAX_INV_PER_MESSAGE + 1, max: MAX_INV_PER_MESSAGE }),
+        );
+        let locator = vec![[0; 32]; MAX_LOCATOR_SIZE + 1];
+        assert!(check_message_entries(&Message::GetHeaders { locator, stop_hash: [0; 32] }).is_err());
+    }
+
+    #[test]
+    fn test_send_queue_overflow() {
+        let mut queue = SendQueue::new(10);
+        queue.push(vec![0; 6]).unwrap();
+        assert_eq!(queue.push(vec![0; 6]), Err(RateLimitError::SendQueueOverflow(10)));
+        assert_eq!(queue.pop().map(|m| m.len()), Some(6));
+        queue.push(vec![0; 10]).unwrap();
+        assert_eq!(queue.bytes(), 10);
+    }
+}
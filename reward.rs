@@ -0,0 +1,224 @@
+use crate::blockchain::FruitHeader;
+use crate::chain_params::ChainParams;
+use crate::transaction::{Transaction, TxOutput};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+// Base units per XTAL
+pub const COIN: u64 = 100_000_000;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RewardError {
+    #[error("Coinbase pays {actual} but the block may claim at most {max}")]
+    CoinbaseTooLarge { actual: u64, max: u64 },
+    #[error("Coinbase does not start with the {expected} required fruit miner payouts")]
+    BadFruitPayouts { expected: usize },
+    #[error("Reward amounts overflow")]
+    Overflow,
+    #[error("Miner payout shares add up to {0}%, not 100%")]
+    BadPayoutSplit(u64),
+    #[error("Coinbase does not end with the commitment to UTXO set {}", hex::encode(.0))]
+    BadUtxoCommitment([u8; 32]),
+}
+
+// Leads the script of the coinbase output committing to the UTXO set
+pub const UTXO_COMMITMENT_TAG: &[u8; 4] = b"utxo";
+
+pub fn block_subsidy(params: &ChainParams, height: u64) -> u64 {
+    let halvings = height.checked_div(params.halving_interval).unwrap_or(0);
+    params.initial_subsidy.checked_shr(halvings.try_into().unwrap_or(u32::MAX)).unwrap_or(0)
+}
+
+// Subsidy plus `fees`: what a block at `height` may pay out
+pub fn block_reward(params: &ChainParams, height: u64, fees: u64) -> Result<u64, RewardError> {
+    block_subsidy(params, height).checked_add(fees).ok_or(RewardError::Overflow)
+}
+
+// Fruit miners are paid to their 32-byte public key
+pub fn fruit_payout_script(miner_public_key: &[u8; 32]) -> Vec<u8> {
+    miner_public_key.to_vec()
+}
+
+// Outputs a block's coinbase owes fruit miners. `fruit_reward_share_percent` of `total_reward`
+// (subsidy plus fees) is split between the miners of `window_fruits` in proportion to how many of
+// those fruits each mined. Outputs are ordered by public key; rounding dust stays with the block miner.
+pub fn fruit_payouts<'a>(params: &ChainParams, total_reward: u64, window_fruits: impl IntoIterator<Item = &'a FruitHeader>) -> Vec<TxOutput> {
+    let mut counts = BTreeMap::new();
+    for fruit in window_fruits {
+        *counts.entry(fruit.miner_public_key).or_insert(0u64) += 1;
+    }
+    let fruit_count: u64 = counts.values().sum();
+    if fruit_count == 0 {
+        return Vec::new();
+    }
+
+    let pool = total_reward as u128 * params.fruit_reward_share_percent as u128 / 100;
+    counts.into_iter()
+        .map(|(key, count)| TxOutput {
+            value: (pool * count as u128 / fruit_count as u128) as u64,
+            script_pubkey: fruit_payout_script(&key),
+        })
+        .filter(|output| output.value > 0)
+        .collect()
+}
+
+// Where the block miner's part of the reward goes: one script, or several each taking a whole
+// percentage, as when a host mines on a client's behalf for a cut. Consensus only fixes the fruit
+// payouts leading the coinbase, so any split is valid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinerPayout {
+    shares: Vec<(Vec<u8>, u64)>,
+}
+
+impl MinerPayout {
+    pub fn single(script: Vec<u8>) -> Self {
+        MinerPayout { shares: vec![(script, 100)] }
+    }
+
+    // Every share must be above zero and together they must make 100
+    pub fn split(shares: Vec<(Vec<u8>, u64)>) -> Result<Self, RewardError> {
+        let total = shares.iter().try_fold(0u64, |sum, (_, percent)| sum.checked_add(*percent)).ok_or(RewardError::Overflow)?;
+        if total != 100 || shares.iter().any(|(_, percent)| *percent == 0) {
+            return Err(RewardError::BadPayoutSplit(total));
+        }
+        Ok(MinerPayout { shares })
+    }
+
+    pub fn shares(&self) -> &[(Vec<u8>, u64)] {
+        &self.shares
+    }
+
+    // `value` divided by percentage, with rounding dust going to the first share. Later shares
+    // that round down to nothing are left out.
+    pub fn outputs(&self, value: u64) -> Vec<TxOutput> {
+        let mut outputs = self.shares.iter()
+            .map(|(script, percent)| TxOutput { value: (value as u128 * *percent as u128 / 100) as u64, script_pubkey: script.clone() })
+            .collect::<Vec<_>>();
+        let paid: u64 = outputs.iter().map(|output| output.value).sum();
+        outputs[0].value += value - paid;
+        outputs.into_iter()
+            .enumerate()
+            .filter(|(index, output)| *index == 0 || output.value > 0)
+            .map(|(_, output)| output)
+            .collect()
+    }
+}
+
+// The fruit payouts followed by the remainder of the reward to the block miner
+pub fn coinbase_outputs(total_reward: u64, payouts: Vec<TxOutput>, miner: &MinerPayout) -> Vec<TxOutput> {
+    let paid: u64 = payouts.iter().map(|output| output.value).sum();
+    let mut outputs = payouts;
+    outputs.extend(miner.outputs(total_reward - paid));
+    outputs
+}
+
+// Zero-value output, last in the coinbase, committing to the hash of the UTXO set as the block's
+// parent left it. No key matches its script, so like an OP_RETURN output it never enters the set.
+pub fn utxo_commitment_output(utxo_set_hash: &[u8; 32]) -> TxOutput {
+    TxOutput { value: 0, script_pubkey: [&UTXO_COMMITMENT_TAG[..], &utxo_set_hash[..]].concat() }
+}
+
+// Whether `output` is shaped like a UTXO commitment, in any transaction; such outputs are left
+// out of the UTXO set
+pub fn is_utxo_commitment(output: &TxOutput) -> bool {
+    output.value == 0 && output.script_pubkey.len() == UTXO_COMMITMENT_TAG.len() + 32 && output.script_pubkey.starts_with(UTXO_COMMITMENT_TAG)
+}
+
+// The UTXO set hash a coinbase commits to, if it ends with a commitment
+pub fn utxo_commitment(coinbase: &Transaction) -> Option<[u8; 32]> {
+    let output = coinbase.outputs.last().filter(|output| is_utxo_commitment(output))?;
+    output.script_pubkey[UTXO_COMMITMENT_TAG.len()..].try_into().ok()
+}
+
+// Consensus check: the coinbase leads with exactly `payouts`, claims no more than `total_reward`
+// and, once commitments are active, ends with `utxo_commitment` after at least one miner output
+pub fn check_coinbase(coinbase: &Transaction, payouts: &[TxOutput], total_reward: u64, utxo_commitment: Option<&[u8; 32]>) -> Result<(), RewardError> {
+    if coinbase.outputs.len() < payouts.len() || coinbase.outputs[..payouts.len()] != *payouts {
+        return Err(RewardError::BadFruitPayouts { expected: payouts.len() });
+    }
+    if let Some(hash) = utxo_commitment {
+        if coinbase.outputs.len() <= payouts.len() || coinbase.outputs.last() != Some(&utxo_commitment_output(hash)) {
+            return Err(RewardError::BadUtxoCommitment(*hash));
+        }
+    }
+    let actual = coinbase.outputs.iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+        .ok_or(RewardError::Overflow)?;
+    if actual > total_reward {
+        return Err(RewardError::CoinbaseTooLarge { actual, max: total_reward });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_params::Network;
+
+    fn fruit(miner: u8) -> FruitHeader {
+        FruitHeader { hang_from: [0; 32], miner_public_key: [miner; 32], timestamp: 0, nonce: miner as u64 }
+    }
+
+    #[test]
+    fn test_fruit_miners_share_reward() {
+        let params = ChainParams::for_network(Network::Regtest);
+        let fruits = [fruit(2), fruit(1), fruit(2), fruit(2)];
+        let reward = 1_000;
+        let payouts = fruit_payouts(&params, reward, &fruits);
+        let pool = reward * params.fruit_reward_share_percent / 100;
+        assert_eq!(payouts, vec![
+            TxOutput { value: pool / 4, script_pubkey: vec![1; 32] },
+            TxOutput { value: pool * 3 / 4, script_pubkey: vec![2; 32] },
+        ]);
+
+        let coinbase = Transaction { inputs: Vec::new(), outputs: coinbase_outputs(reward, payouts.clone(), &MinerPayout::single(vec![9])), lock_time: 0 };
+        assert_eq!(check_coinbase(&coinbase, &payouts, reward, None), Ok(()));
+        assert_eq!(check_coinbase(&coinbase, &payouts, reward - 1, None), Err(RewardError::CoinbaseTooLarge { actual: reward, max: reward - 1 }));
+
+        // Skipping a fruit miner is rejected
+        let greedy = Transaction { inputs: Vec::new(), outputs: coinbase_outputs(reward, Vec::new(), &MinerPayout::single(vec![9])), lock_time: 0 };
+        assert_eq!(check_coinbase(&greedy, &payouts, reward, None), Err(RewardError::BadFruitPayouts { expected: 2 }));
+        assert!(fruit_payouts(&params, reward, std::iter::empty()).is_empty());
+    }
+
+    #[test]
+    fn test_coinbase_commits_to_utxo_set() {
+        let payouts = vec![TxOutput { value: 10, script_pubkey: vec![5; 32] }];
+        let mut coinbase = Transaction { inputs: Vec::new(), outputs: coinbase_outputs(100, payouts.clone(), &MinerPayout::single(vec![9])), lock_time: 0 };
+        assert_eq!(utxo_commitment(&coinbase), None);
+        assert_eq!(check_coinbase(&coinbase, &payouts, 100, Some(&[3; 32])), Err(RewardError::BadUtxoCommitment([3; 32])));
+
+        coinbase.outputs.push(utxo_commitment_output(&[3; 32]));
+        assert_eq!(utxo_commitment(&coinbase), Some([3; 32]));
+        assert!(is_utxo_commitment(&coinbase.outputs[2]));
+        assert!(!is_utxo_commitment(&TxOutput { value: 1, ..coinbase.outputs[2].clone() }));
+        assert!(!is_utxo_commitment(&TxOutput { value: 0, script_pubkey: UTXO_COMMITMENT_TAG.to_vec() }));
+        assert_eq!(check_coinbase(&coinbase, &payouts, 100, Some(&[3; 32])), Ok(()));
+        assert_eq!(check_coinbase(&coinbase, &payouts, 100, Some(&[4; 32])), Err(RewardError::BadUtxoCommitment([4; 32])));
+        // Before activation a commitment is just another output
+        assert_eq!(check_coinbase(&coinbase, &payouts, 100, None), Ok(()));
+    }
+
+    #[test]
+    fn test_miner_reward_split_by_percentage() {
+        let host = MinerPayout::split(vec![(vec![1], 70), (vec![2], 30)]).unwrap();
+        let payouts = vec![TxOutput { value: 10, script_pubkey: vec![5; 32] }];
+        assert_eq!(coinbase_outputs(111, payouts.clone(), &host), vec![
+            payouts[0].clone(),
+            TxOutput { value: 71, script_pubkey: vec![1] },
+            TxOutput { value: 30, script_pubkey: vec![2] },
+        ]);
+        assert_eq!(host.outputs(1), vec![TxOutput { value: 1, script_pubkey: vec![1] }]);
+        assert_eq!(MinerPayout::split(vec![(vec![1], 70), (vec![2], 20)]), Err(RewardError::BadPayoutSplit(90)));
+        assert_eq!(MinerPayout::split(vec![(vec![1], 100), (vec![2], 0)]), Err(RewardError::BadPayoutSplit(100)));
+    }
+
+    #[test]
+    fn test_block_reward_refuses_to_overflow() {
+        let params = ChainParams::for_network(Network::Regtest);
+        let subsidy = block_subsidy(&params, 0);
+        assert_eq!(block_reward(&params, 0, 7), Ok(subsidy + 7));
+        assert_eq!(block_reward(&params, 0, u64::MAX - subsidy), Ok(u64::MAX));
+        assert_eq!(block_reward(&params, 0, u64::MAX - subsidy + 1), Err(RewardError::Overflow));
+    }
+}
@@ -0,0 +1,114 @@
+use rand::RngCore;
+
+// Remembers roughly the last `capacity` items inserted in bounded memory, with false positives
+// but no false negatives for recent items. Items go into the current generation; once it holds
+// half the capacity it replaces the previous generation, so the oldest items age out in bulk.
+pub struct RollingBloomFilter {
+    current: Generation,
+    previous: Generation,
+    generation_size: usize,
+    hash_count: u32,
+    // Random per filter so peers can't craft items that collide in every node's filter
+    key: [u8; 32],
+}
+
+struct Generation {
+    bits: Vec<u64>,
+    items: usize,
+}
+
+impl Generation {
+    fn new(bit_count: usize) -> Self {
+        Generation { bits: vec![0; bit_count.div_ceil(64)], items: 0 }
+    }
+
+    fn bit_count(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    fn clear(&mut self) {
+        self.bits.fill(0);
+        self.items = 0;
+    }
+}
+
+impl RollingBloomFilter {
+    // Sized so each generation of `capacity / 2` items has about `false_positive_rate` on its own
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let generation_size = (capacity / 2).max(1);
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = (-(generation_size as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let hash_count = ((bit_count as f64 / generation_size as f64) * ln2).round().clamp(1.0, 32.0) as u32;
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        RollingBloomFilter {
+            current: Generation::new(bit_count),
+            previous: Generation::new(bit_count),
+            generation_size,
+            hash_count,
+            key,
+        }
+    }
+
+    // Bit positions by double hashing two halves of one keyed hash
+    fn positions(&self, item: &[u8], bit_count: u64) -> impl Iterator<Item = u64> {
+        let hash = blake3::keyed_hash(&self.key, item);
+        let bytes = hash.as_bytes();
+        let h1 = u64::from_le_bytes(bytes[..8].try_into().expect("slice is 8 bytes"));
+        let h2 = u64::from_le_bytes(bytes[8..16].try_into().expect("slice is 8 bytes")) | 1;
+        (0..self.hash_count as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        if self.current.items >= self.generation_size {
+            std::mem::swap(&mut self.current, &mut self.previous);
+            self.current.clear();
+        }
+        let bit_count = self.current.bit_count();
+        for position in self.positions(item, bit_count).collect::<Vec<_>>() {
+            self.current.bits[(position / 64) as usize] |= 1 << (position % 64);
+        }
+        self.current.items += 1;
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        let bit_count = self.current.bit_count();
+        [&self.current, &self.previous].into_iter().any(|generation| {
+            generation.items > 0
+                && self.positions(item, bit_count).all(|position| generation.bits[(position / 64) as usize] & (1 << (position % 64)) != 0)
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.current.clear();
+        self.previous.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(i: u32) -> [u8; 4] {
+        i.to_le_bytes()
+    }
+
+    #[test]
+    fn test_recent_items_are_kept_and_old_ones_roll_out() {
+        let mut filter = RollingBloomFilter::new(1_000, 0.000_001);
+        for i in 0..1_000 {
+            filter.insert(&item(i));
+        }
+        assert!((500..1_000).all(|i| filter.contains(&item(i))));
+
+        for i in 1_000..2_000 {
+            filter.insert(&item(i));
+        }
+        assert!((1_500..2_000).all(|i| filter.contains(&item(i))));
+        assert!((0..500).filter(|&i| filter.contains(&item(i))).count() < 5);
+        assert!((10_000..20_000).filter(|&i| filter.contains(&item(i))).count() < 5);
+
+        filter.reset();
+        assert!(!filter.contains(&item(1_999)));
+    }
+}
@@ -0,0 +1,1519 @@
+use crate::address_index::AddressEventKind;
+use crate::addrman::AddrManager;
+use crate::blockchain::{calculate_merkle_root, Block, BlockHash, Blockchain, ChainStats};
+use crate::codec;
+use crate::jobs::{JobError, JobManager};
+use crate::mempool::Mempool;
+use crate::merkle::MerkleBranch;
+use crate::miner::{BlockTemplateBuilder, TemplateCache};
+use crate::multisig::{self, MultisigScript, MultisigWitness};
+use crate::net::PeerManager;
+use crate::node_config::ConfigHandle;
+use crate::node_status::{self, DiskUsage, NodeInfo, PeerCounts};
+use crate::policy;
+use crate::storage;
+use crate::psbt::{PartiallySignedTransaction, PsbtInput};
+use crate::rpc_auth::{Credential, RpcAuth};
+use crate::transaction::{Coin, OutPoint, Transaction, TxHash, TxInput, TxOutput, SEQUENCE_FINAL};
+use crate::validation::{self, ValidationError};
+use crate::wallet::{self, KeyChain, Wallet, Wallets};
+use crate::wallet_history::{HistoryEntry, LabelTarget};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{routing::{get, post}, Json, Router};
+use ed25519_dalek::SigningKey;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+// Most headers a single getheaders call returns
+pub const MAX_HEADERS_PER_REQUEST: u64 = 2000;
+pub const MAX_FILTERS_PER_REQUEST: u64 = 1000;
+// Most txids a single getmempoolsnapshot call proves
+pub const MAX_SNAPSHOT_PROOFS_PER_REQUEST: usize = 1000;
+// Blocks getchainstats looks back over by default and at most; every block in the window is read from disk
+pub const DEFAULT_CHAIN_STATS_WINDOW: u64 = 1000;
+pub const MAX_CHAIN_STATS_WINDOW: u64 = 10_000;
+// verifychain defaults, as for `xcored verify-chain`
+pub const DEFAULT_VERIFY_DEPTH: u64 = 6;
+pub const DEFAULT_VERIFY_LEVEL: u64 = 3;
+// Walking the data directories is slow, so getnodeinfo and /health reuse a measurement this young
+pub const DISK_USAGE_MAX_AGE: Duration = Duration::from_secs(60);
+
+// Standard JSON-RPC 2.0 error codes
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+// Server-defined: a transaction or package was rejected by validation or mempool policy
+pub const VERIFY_REJECTED: i32 = -26;
+// Server-defined: the credential used may not call this method
+pub const METHOD_FORBIDDEN: i32 = -32004;
+// Server-defined: the call ran past its timeout and was abandoned
+pub const TIMED_OUT: i32 = -32005;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RpcRequest {
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    pub fn error(id: Value, error: RpcError) -> Self {
+        RpcResponse { jsonrpc: "2.0".to_string(), id, result: None, error: Some(error) }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn invalid_params(message: impl std::fmt::Display) -> Self {
+        RpcError { code: INVALID_PARAMS, message: message.to_string() }
+    }
+
+    pub fn internal(error: impl std::fmt::Display) -> Self {
+        RpcError { code: INTERNAL_ERROR, message: error.to_string() }
+    }
+
+    pub fn rejected(error: impl std::fmt::Display) -> Self {
+        RpcError { code: VERIFY_REJECTED, message: error.to_string() }
+    }
+}
+
+pub struct RpcServer {
+    blockchain: Arc<Blockchain>,
+    mempool: Arc<Mutex<Mempool>>,
+    wallets: Arc<tokio::sync::Mutex<Wallets>>,
+    addrman: Arc<Mutex<AddrManager>>,
+    // None on a read-only node, which doesn't join the network
+    peers: Option<Arc<PeerManager>>,
+    // Reindex, verifychain and wallet rescans run here rather than inside the call
+    jobs: Arc<JobManager>,
+    config: ConfigHandle,
+    auth: Arc<RpcAuth>,
+    limits: RpcLimits,
+    shutdown: CancellationToken,
+    started: Instant,
+    // Held while measuring, so probes arriving together share one directory walk
+    disk_usage: tokio::sync::Mutex<Option<(Instant, DiskUsage)>>,
+    // The last getblocktemplate block, extended by later calls until the tip moves on
+    template_cache: tokio::sync::Mutex<Option<TemplateCache>>,
+    // The last getchainstats result with the tip and window it was computed for. Held while
+    // computing, so calls arriving together read the window's blocks once.
+    chain_stats: tokio::sync::Mutex<Option<(BlockHash, u64, ChainStats)>>,
+}
+
+// Calls run on a bounded number of workers so a burst of requests can't pile up unbounded work,
+// and methods with their own limit queue for a slot before taking a worker, so a backlog of slow
+// calls waits without holding workers that quick calls need
+struct RpcLimits {
+    workers: Arc<Semaphore>,
+    methods: HashMap<String, Arc<Semaphore>>,
+    timeout: Duration,
+    method_timeouts: HashMap<String, Duration>,
+    batch_limit: usize,
+}
+
+impl RpcServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(blockchain: Arc<Blockchain>, mempool: Arc<Mutex<Mempool>>, wallets: Arc<tokio::sync::Mutex<Wallets>>, addrman: Arc<Mutex<AddrManager>>, peers: Option<Arc<PeerManager>>, jobs: Arc<JobManager>, config: ConfigHandle, auth: Arc<RpcAuth>, shutdown: CancellationToken) -> Self {
+        let rpc = config.get().rpc;
+        let limits = RpcLimits {
+            workers: Arc::new(Semaphore::new(rpc.max_concurrent)),
+            methods: rpc.method_limits.iter().map(|(method, limit)| (method.clone(), Arc::new(Semaphore::new(*limit)))).collect(),
+            timeout: Duration::from_secs(rpc.timeout_secs),
+            method_timeouts: rpc.method_timeouts.iter().map(|(method, secs)| (method.clone(), Duration::from_secs(*secs))).collect(),
+            batch_limit: rpc.batch_limit,
+        };
+        RpcServer { blockchain, mempool, wallets, addrman, peers, jobs, config, auth, limits, shutdown, started: Instant::now(), disk_usage: tokio::sync::Mutex::new(None), template_cache: tokio::sync::Mutex::new(None), chain_stats: tokio::sync::Mutex::new(None) }
+    }
+
+    // Runs one call within its method's concurrency limit and timeout
+    async fn execute(self: &Arc<Self>, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        let method_permit = match self.limits.methods.get(method) {
+            Some(semaphore) => Some(Arc::clone(semaphore).acquire_owned().await.map_err(RpcError::internal)?),
+            None => None,
+        };
+        let worker = Arc::clone(&self.limits.workers).acquire_owned().await.map_err(RpcError::internal)?;
+        let timeout = self.limits.method_timeouts.get(method).copied().unwrap_or(self.limits.timeout);
+        let server = Arc::clone(self);
+        let (name, params) = (method.to_string(), params.to_vec());
+        run_detached(method, timeout, async move {
+            // A call that outlives its timeout keeps its slots until it finishes
+            let _permits = (method_permit, worker);
+            server.dispatch(&name, &params).await
+        })
+        .await
+    }
+
+    // Everything an operator checks first, gathered in one place for getnodeinfo and /health
+    async fn node_info(&self) -> Result<NodeInfo, RpcError> {
+        let low_disk_bytes = self.config.get().disk.low_free_mb * 1024 * 1024;
+        let disk = self.disk_usage().await?;
+        let height = self.blockchain.get_chain_height();
+        let tip_time = self.blockchain.tip_time();
+        let genesis_time = match height {
+            Some(_) => self.blockchain.get_headers(0..1).await.map_err(RpcError::internal)?.first().map(|header| header.timestamp),
+            None => None,
+        };
+        let network_time = self.blockchain.network_time();
+        let now = network_time.adjusted_time();
+        let sync_progress = match (genesis_time, tip_time) {
+            (Some(genesis_time), Some(tip_time)) => node_status::sync_progress(genesis_time, tip_time, now),
+            _ => 0.0,
+        };
+        let initial_block_download = self.blockchain.is_initial_block_download();
+        let (known_peers, tried_peers) = {
+            let addrman = self.addrman.lock();
+            (addrman.len(), addrman.tried_count())
+        };
+        Ok(NodeInfo {
+            chain: format!("{:?}", self.blockchain.params().network).to_lowercase(),
+            blocks: height,
+            headers: self.blockchain.best_header_height(),
+            best_block_hash: hex::encode(self.blockchain.get_chain_tip()),
+            last_block_time: tip_time,
+            sync_progress,
+            initial_block_download,
+            estimated_blocks_remaining: match tip_time.filter(|_| initial_block_download) {
+                Some(tip_time) => now.saturating_sub(tip_time) / self.blockchain.params().target_block_spacing_secs.max(1),
+                None => 0,
+            },
+            peers: PeerCounts { connected: self.peers.as_ref().map_or(0, |peers| peers.peer_count()), known: known_peers, tried: tried_peers },
+            mempool: self.mempool.lock().info(),
+            warnings: node_status::warnings(tip_time, now, &disk, low_disk_bytes).into_iter().chain(network_time.warning()).collect(),
+            disk,
+            time_offset: network_time.offset(),
+            uptime_secs: self.started.elapsed().as_secs(),
+        })
+    }
+
+    // Has the peer manager announce transactions submitted here until they confirm
+    fn broadcast(&self, txids: &[TxHash]) {
+        if let Some(peers) = &self.peers {
+            for txid in txids {
+                peers.broadcast_transaction(*txid);
+            }
+        }
+    }
+
+    async fn disk_usage(&self) -> Result<DiskUsage, RpcError> {
+        let mut cached = self.disk_usage.lock().await;
+        if let Some((_, disk)) = cached.as_ref().filter(|(measured, _)| measured.elapsed() < DISK_USAGE_MAX_AGE) {
+            return Ok(disk.clone());
+        }
+        let config = self.config.get();
+        let disk = tokio::task::spawn_blocking(move || DiskUsage::measure(&config))
+            .await
+            .map_err(RpcError::internal)?
+            .map_err(RpcError::internal)?;
+        *cached = Some((Instant::now(), disk.clone()));
+        Ok(disk)
+    }
+
+    // Recomputed only once the tip moves or a different window is asked for
+    async fn chain_stats(&self, window: u64) -> Result<ChainStats, RpcError> {
+        let mut cached = self.chain_stats.lock().await;
+        let tip = self.blockchain.get_chain_tip();
+        if let Some((_, _, stats)) = cached.as_ref().filter(|(hash, cached_window, _)| *hash == tip && *cached_window == window) {
+            return Ok(stats.clone());
+        }
+        let stats = self.blockchain.chain_stats(window).await.map_err(RpcError::internal)?;
+        *cached = Some((tip, window, stats.clone()));
+        Ok(stats)
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> std::io::Result<()> {
+        let shutdown = self.shutdown.clone();
+        let rpc = self.config.get().rpc;
+        let app = Router::new()
+            .route("/", post(handle_request))
+            .route("/health", get(handle_health))
+            .with_state(Arc::new(self));
+
+        if let (Some(cert), Some(key)) = (rpc.tls_cert, rpc.tls_key) {
+            let tls = axum_server::tls_rustls::RustlsConfig::from_pem_file(cert, key).await?;
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.cancelled().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            log::info!("RPC server listening on {} (TLS)", addr);
+            return axum_server::bind_rustls(addr, tls).handle(handle).serve(app.into_make_service()).await;
+        }
+
+        if !addr.ip().is_loopback() {
+            log::warn!("RPC server is reachable beyond localhost without TLS; passwords cross the network in the clear");
+        }
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("RPC server listening on {}", addr);
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { shutdown.cancelled().await })
+            .await
+    }
+
+    pub async fn dispatch(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        match method {
+            "getblockcount" => Ok(json!(self.blockchain.get_chain_height())),
+            "getbestblockhash" => Ok(json!(hex::encode(self.blockchain.get_chain_tip()))),
+            "getblockhash" => {
+                let height = param_u64(params, 0)?;
+                match self.blockchain.get_block_hash(height).await.map_err(RpcError::internal)? {
+                    Some(hash) => Ok(json!(hex::encode(hash))),
+                    None => Err(RpcError::invalid_params("Block height out of range")),
+                }
+            }
+            "getblock" => {
+                let hash = param_hash(params, 0)?;
+                let verbose = params.get(1).and_then(Value::as_bool).unwrap_or(true);
+                let block = self.blockchain.get_block(&hash).await.map_err(RpcError::internal)?
+                    .ok_or_else(|| RpcError::invalid_params("Block not found"))?;
+                if verbose {
+                    serde_json::to_value(&*block).map_err(RpcError::internal)
+                } else {
+                    Ok(json!(hex::encode(codec::encode(&*block).map_err(RpcError::internal)?)))
+                }
+            }
+            "getheaders" => {
+                let start = param_u64(params, 0)?;
+                let count = param_u64(params, 1)?.min(MAX_HEADERS_PER_REQUEST);
+                let headers = self.blockchain.get_headers(start..start.saturating_add(count)).await.map_err(RpcError::internal)?;
+                let encoded = headers.iter()
+                    .map(|header| codec::encode(header).map(hex::encode))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(RpcError::internal)?;
+                Ok(json!(encoded))
+            }
+            "gettxoutsetinfo" => {
+                let info = self.blockchain.utxo_set_info().await;
+                Ok(json!({
+                    "height": info.height,
+                    "bestblock": hex::encode(info.best_block),
+                    "txouts": info.coins,
+                    "total_amount": info.total_amount,
+                    "utxo_set_hash": hex::encode(info.hash),
+                }))
+            }
+            "getdbinfo" => {
+                let stats = self.blockchain.database_stats().await.map_err(RpcError::internal)?;
+                let mut info = serde_json::to_value(stats).map_err(RpcError::internal)?;
+                let block_cache = self.blockchain.block_cache_stats();
+                // Separate from the RocksDB block cache above, which holds table blocks
+                info["decoded_block_cache"] = json!({
+                    "hits": block_cache.hits,
+                    "misses": block_cache.misses,
+                    "hit_rate": block_cache.hit_rate(),
+                    "entries": block_cache.entries,
+                    "bytes": block_cache.bytes,
+                });
+                Ok(info)
+            }
+            "compactdb" => {
+                let cf = match params.first().and_then(Value::as_str) {
+                    Some(name) => Some(storage::column_family(name)
+                        .ok_or_else(|| RpcError::invalid_params(format!("Unknown column family '{}'", name)))?),
+                    None => None,
+                };
+                self.blockchain.compact_database(cf).await.map_err(RpcError::internal)?;
+                serde_json::to_value(self.blockchain.database_stats().await.map_err(RpcError::internal)?).map_err(RpcError::internal)
+            }
+            "backupchainstate" => {
+                let destination = param_path(params, 0)?;
+                self.blockchain.backup_chainstate(&destination).await.map_err(RpcError::internal)?;
+                Ok(json!({ "path": destination, "height": self.blockchain.get_chain_height() }))
+            }
+            "getchainstats" => {
+                let window = params.first().and_then(Value::as_u64).unwrap_or(DEFAULT_CHAIN_STATS_WINDOW).min(MAX_CHAIN_STATS_WINDOW);
+                serde_json::to_value(self.chain_stats(window).await?).map_err(RpcError::internal)
+            }
+            // By height or block hash
+            "getblockstats" => {
+                let hash = match params.first().and_then(Value::as_u64) {
+                    Some(height) => self.blockchain.get_block_hash(height).await.map_err(RpcError::internal)?
+                        .ok_or_else(|| RpcError::invalid_params("Block height out of range"))?,
+                    None => param_hash(params, 0)?,
+                };
+                let stats = self.blockchain.get_block_stats(&hash).await.map_err(RpcError::internal)?
+                    .ok_or_else(|| RpcError::invalid_params("Block is not on the active chain or its data has been pruned"))?;
+                serde_json::to_value(stats).map_err(RpcError::internal)
+            }
+            "getchaintips" => {
+                let tips = self.blockchain.chain_tips().await.map_err(RpcError::internal)?;
+                Ok(json!(tips.iter().map(|tip| json!({
+                    "height": tip.height,
+                    "hash": hex::encode(tip.hash),
+                    "branchlen": tip.branch_len,
+                    "status": tip.status.name(),
+                })).collect::<Vec<_>>()))
+            }
+            "invalidateblock" => {
+                let hash = param_hash(params, 0)?;
+                self.blockchain.invalidate_block(&hash).await.map_err(RpcError::rejected)?;
+                Ok(Value::Null)
+            }
+            "reconsiderblock" => {
+                let hash = param_hash(params, 0)?;
+                self.blockchain.reconsider_block(&hash).await.map_err(RpcError::rejected)?;
+                Ok(Value::Null)
+            }
+            "getdeploymentinfo" => Ok(json!({
+                "height": self.blockchain.get_chain_height(),
+                "next_block_version": self.blockchain.next_block_version(),
+                "deployments": self.blockchain.deployment_info(),
+            })),
+            "getcfilters" => {
+                let start = param_u64(params, 0)?;
+                let count = param_u64(params, 1)?.min(MAX_FILTERS_PER_REQUEST);
+                let filters = self.blockchain.get_block_filters(start..start.saturating_add(count)).await.map_err(RpcError::internal)?;
+                let filters = filters.iter()
+                    .map(|(hash, filter, _)| Ok(json!({
+                        "blockhash": hex::encode(hash),
+                        "n": filter.element_count,
+                        "filter": hex::encode(codec::encode(filter)?),
+                    })))
+                    .collect::<Result<Vec<_>, codec::CodecError>>()
+                    .map_err(RpcError::internal)?;
+                Ok(json!(filters))
+            }
+            "getcfheaders" => {
+                let start = param_u64(params, 0)?;
+                let count = param_u64(params, 1)?.min(MAX_FILTERS_PER_REQUEST);
+                let filters = self.blockchain.get_block_filters(start..start.saturating_add(count)).await.map_err(RpcError::internal)?;
+                Ok(json!(filters.iter()
+                    .map(|(hash, filter, header)| json!({
+                        "blockhash": hex::encode(hash),
+                        "filterhash": hex::encode(filter.hash()),
+                        "header": hex::encode(header),
+                    }))
+                    .collect::<Vec<_>>()))
+            }
+            "gettxoutproof" => {
+                let txid = param_hash(params, 0)?;
+                let block_hash = param_hash(params, 1)?;
+                let (header, branch) = self.blockchain.get_transaction_proof(&block_hash, &txid).await
+                    .map_err(RpcError::internal)?
+                    .ok_or_else(|| RpcError::invalid_params("Transaction not found in block"))?;
+                Ok(json!({
+                    "blockhash": hex::encode(block_hash),
+                    "merkleroot": hex::encode(header.merkle_root),
+                    "index": branch.index,
+                    "leafcount": branch.leaf_count,
+                    "path": branch.path.iter().map(hex::encode).collect::<Vec<_>>(),
+                }))
+            }
+            "verifytxoutproof" => {
+                let txid = param_hash(params, 0)?;
+                let root = param_hash(params, 1)?;
+                let index = param_u64(params, 2)? as usize;
+                let leaf_count = param_u64(params, 3)? as usize;
+                let path = params.get(4)
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| RpcError::invalid_params("Parameter 4 must be an array of hex hashes"))?;
+                let path = (0..path.len()).map(|i| param_hash(path, i)).collect::<Result<Vec<_>, _>>()?;
+                Ok(json!(MerkleBranch { index, leaf_count, path }.verify(&root, &txid)))
+            }
+            "getaddressbalance" => {
+                let script = param_script(params, 0)?;
+                let utxos = self.blockchain.address_utxos(&script).await.map_err(RpcError::internal)?;
+                let history = self.blockchain.address_history(&script).await.map_err(RpcError::internal)?;
+                let received = history.iter()
+                    .filter(|event| event.kind == AddressEventKind::Funding)
+                    .fold(0u64, |sum, event| sum.saturating_add(event.amount));
+                Ok(json!({
+                    "balance": utxos.iter().fold(0u64, |sum, utxo| sum.saturating_add(utxo.amount)),
+                    "received": received,
+                    "utxos": utxos.len(),
+                }))
+            }
+            "getaddressutxos" => {
+                let script = param_script(params, 0)?;
+                let utxos = self.blockchain.address_utxos(&script).await.map_err(RpcError::internal)?;
+                Ok(json!(utxos.iter().map(|utxo| json!({
+                    "txid": hex::encode(utxo.outpoint.txid),
+                    "vout": utxo.outpoint.index,
+                    "amount": utxo.amount,
+                    "height": utxo.height,
+                })).collect::<Vec<_>>()))
+            }
+            "getaddresshistory" => {
+                let script = param_script(params, 0)?;
+                let history = self.blockchain.address_history(&script).await.map_err(RpcError::internal)?;
+                Ok(json!(history.iter().map(|event| match (event.kind, event.spent_outpoint) {
+                    (AddressEventKind::Spending, Some(spent)) => json!({
+                        "type": "spending",
+                        "txid": hex::encode(event.txid),
+                        "vin": event.index,
+                        "height": event.height,
+                        "amount": event.amount,
+                        "spent_txid": hex::encode(spent.txid),
+                        "spent_vout": spent.index,
+                    }),
+                    _ => json!({
+                        "type": "funding",
+                        "txid": hex::encode(event.txid),
+                        "vout": event.index,
+                        "height": event.height,
+                        "amount": event.amount,
+                    }),
+                }).collect::<Vec<_>>()))
+            }
+            "getspentinfo" => {
+                let txid = param_hash(params, 0)?;
+                let index = param_u64(params, 1)?;
+                let index = u32::try_from(index).map_err(|_| RpcError::invalid_params("Output index out of range"))?;
+                let info = self.blockchain.get_spent_info(&OutPoint { txid, index }).await.map_err(RpcError::internal)?
+                    .ok_or_else(|| RpcError::invalid_params("Output is unspent or unknown"))?;
+                Ok(json!({
+                    "txid": hex::encode(info.txid),
+                    "vin": info.input_index,
+                    "height": info.height,
+                }))
+            }
+            "getmempoolinfo" => serde_json::to_value(self.mempool.lock().info()).map_err(RpcError::internal),
+            "getnodeinfo" => serde_json::to_value(self.node_info().await?).map_err(RpcError::internal),
+            // [hex transaction]; adds it to the mempool, unless it's there already, and announces
+            // it until it confirms
+            "sendrawtransaction" => {
+                let tx = param_transaction(params, 0)?;
+                let txid = tx.hash();
+                if self.mempool.lock().get_transaction(&txid).is_none() {
+                    let fees = self.blockchain
+                        .package_fees(std::slice::from_ref(&tx), |outpoint| {
+                            let mempool = self.mempool.lock();
+                            mempool.get_transaction(&outpoint.txid)?.outputs.get(outpoint.index as usize).cloned()
+                        })
+                        .await
+                        .map_err(RpcError::rejected)?;
+                    let next_height = self.blockchain.get_chain_height().map_or(0, |height| height + 1);
+                    let median_time_past = self.blockchain.median_time_past();
+                    self.mempool.lock().add_transaction(tx, fees[0], next_height, median_time_past).map_err(RpcError::rejected)?;
+                }
+                self.broadcast(&[txid]);
+                Ok(json!(hex::encode(txid)))
+            }
+            "submitpackage" => {
+                let encoded = params.first()
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| RpcError::invalid_params("Parameter 0 must be an array of hex transactions: parents first, then the child spending them"))?;
+                let package = encoded.iter().enumerate()
+                    .map(|(index, value)| {
+                        value.as_str()
+                            .and_then(|hex_str| hex::decode(hex_str).ok())
+                            .and_then(|bytes| codec::decode::<Transaction>(&bytes).ok())
+                            .ok_or_else(|| RpcError::invalid_params(format!("Transaction {} is not a hex-encoded transaction", index)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let fees = self.blockchain
+                    .package_fees(&package, |outpoint| {
+                        let mempool = self.mempool.lock();
+                        mempool.get_transaction(&outpoint.txid)?.outputs.get(outpoint.index as usize).cloned()
+                    })
+                    .await
+                    .map_err(RpcError::rejected)?;
+                let next_height = self.blockchain.get_chain_height().map_or(0, |height| height + 1);
+                let median_time_past = self.blockchain.median_time_past();
+                let acceptance = self.mempool.lock()
+                    .add_package(package.into_iter().zip(fees).collect(), next_height, median_time_past)
+                    .map_err(RpcError::rejected)?;
+                self.broadcast(&acceptance.accepted);
+                Ok(json!({
+                    "accepted": acceptance.accepted.iter().map(hex::encode).collect::<Vec<_>>(),
+                    "already_in_pool": acceptance.already_in_pool.iter().map(hex::encode).collect::<Vec<_>>(),
+                    "fee": acceptance.fee,
+                    "size": acceptance.size,
+                    "fee_rate": acceptance.fee_rate,
+                }))
+            }
+            "getrawmempool" => {
+                let verbose = params.first().and_then(Value::as_bool).unwrap_or(false);
+                let mempool = self.mempool.lock();
+                let hashes = mempool.transaction_hashes();
+                if verbose {
+                    let entries = hashes.iter()
+                        .filter_map(|hash| Some((hex::encode(hash), json!(mempool.entry_info(hash)?))))
+                        .collect::<serde_json::Map<_, _>>();
+                    Ok(Value::Object(entries))
+                } else {
+                    Ok(json!(hashes.iter().map(hex::encode).collect::<Vec<_>>()))
+                }
+            }
+            "getmempoolsnapshot" => {
+                // Optionally txids to prove against the snapshot's root
+                let wanted = match params.first() {
+                    Some(Value::Null) | None => Vec::new(),
+                    Some(Value::Array(txids)) if txids.len() > MAX_SNAPSHOT_PROOFS_PER_REQUEST => {
+                        return Err(RpcError::invalid_params(format!("At most {} txids can be proved per call", MAX_SNAPSHOT_PROOFS_PER_REQUEST)));
+                    }
+                    Some(Value::Array(txids)) => (0..txids.len()).map(|i| param_hash(txids, i)).collect::<Result<Vec<_>, _>>()?,
+                    Some(_) => return Err(RpcError::invalid_params("Parameter 0 must be an array of hex txids")),
+                };
+                let snapshot = self.mempool.lock().snapshot();
+                // In the layout gettxoutproof uses, so verifytxoutproof checks them against the root;
+                // null for a txid that isn't in the pool
+                let proofs = wanted.iter().zip(snapshot.proofs(&wanted))
+                    .map(|(txid, proof)| {
+                        let proof = proof.map(|branch| json!({
+                            "index": branch.index,
+                            "leafcount": branch.leaf_count,
+                            "path": branch.path.iter().map(hex::encode).collect::<Vec<_>>(),
+                        }));
+                        (hex::encode(txid), proof.unwrap_or(Value::Null))
+                    })
+                    .collect::<serde_json::Map<_, _>>();
+                Ok(json!({
+                    "merkleroot": hex::encode(snapshot.merkle_root),
+                    "transactions": snapshot.txids.iter().map(hex::encode).collect::<Vec<_>>(),
+                    "proofs": proofs,
+                }))
+            }
+            "getmempoolremovals" => {
+                // Optionally just the removals of one transaction, which may have left more than once
+                let txid = match params.first() {
+                    Some(Value::Null) | None => None,
+                    Some(_) => Some(param_hash(params, 0)?),
+                };
+                let mempool = self.mempool.lock();
+                let removals = mempool.recent_removals()
+                    .filter(|removal| txid.is_none_or(|txid| removal.txid == txid))
+                    .map(|removal| json!({
+                        "txid": hex::encode(removal.txid),
+                        "reason": removal.reason.name(),
+                        "time": removal.time,
+                    }))
+                    .collect::<Vec<_>>();
+                Ok(json!(removals))
+            }
+            "getblocktemplate" => {
+                let request = params.first().cloned().unwrap_or(Value::Null);
+                match request.get("mode").and_then(Value::as_str) {
+                    None | Some("template") => self.block_template().await,
+                    Some("proposal") => {
+                        let block = request.get("data")
+                            .and_then(Value::as_str)
+                            .and_then(|hex_str| hex::decode(hex_str).ok())
+                            .and_then(|bytes| Block::decode(&bytes).ok())
+                            .ok_or_else(|| RpcError::invalid_params("Proposal data must be a hex-encoded block"))?;
+                        self.check_proposal(&block).await
+                    }
+                    Some(mode) => Err(RpcError::invalid_params(format!("Unknown mode '{}', expected template or proposal", mode))),
+                }
+            }
+            "listwallets" => Ok(json!(self.wallets.lock().await.names())),
+            "getnewaddress" => self.new_wallet_address(params, KeyChain::Receive).await,
+            "getrawchangeaddress" => self.new_wallet_address(params, KeyChain::Change).await,
+            "getbalance" => {
+                let mut wallets = self.wallets.lock().await;
+                let wallet = wallets.get_mut(param_wallet(params, 0)).map_err(RpcError::invalid_params)?;
+                serde_json::to_value(wallet.balance()).map_err(RpcError::internal)
+            }
+            "listtransactions" => {
+                let count = params.first().and_then(Value::as_u64).unwrap_or(10) as usize;
+                let skip = params.get(1).and_then(Value::as_u64).unwrap_or(0) as usize;
+                let wallets = self.wallets.lock().await;
+                let wallet = wallets.get(param_wallet(params, 2)).map_err(RpcError::invalid_params)?;
+                let history = wallet.history().map_err(RpcError::internal)?;
+                // The most recent `count` after skipping `skip`, listed oldest first
+                let end = history.len().saturating_sub(skip);
+                let entries = history[end.saturating_sub(count)..end].iter()
+                    .map(|entry| self.history_json(wallet, entry))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(json!(entries))
+            }
+            "gettransaction" => {
+                let txid = param_hash(params, 0)?;
+                let wallets = self.wallets.lock().await;
+                let wallet = wallets.get(param_wallet(params, 1)).map_err(RpcError::invalid_params)?;
+                let entry = wallet.transaction(&txid).map_err(RpcError::internal)?
+                    .ok_or_else(|| RpcError::invalid_params("Transaction not found in the wallet"))?;
+                self.history_json(wallet, &entry)
+            }
+            "setlabel" | "settransactionlabel" => {
+                self.check_writable()?;
+                let id = param_hash(params, 0)?;
+                let label = params.get(1).and_then(Value::as_str)
+                    .ok_or_else(|| RpcError::invalid_params("Parameter 1 must be a label, empty to remove it"))?;
+                let target = if method == "setlabel" { LabelTarget::Address(id) } else { LabelTarget::Transaction(id) };
+                let wallets = self.wallets.lock().await;
+                let wallet = wallets.get(param_wallet(params, 2)).map_err(RpcError::invalid_params)?;
+                wallet.set_label(target, label).map_err(RpcError::internal)?;
+                Ok(Value::Null)
+            }
+            "listlabels" => {
+                let wallets = self.wallets.lock().await;
+                let wallet = wallets.get(param_wallet(params, 0)).map_err(RpcError::invalid_params)?;
+                let labels = wallet.labels().map_err(RpcError::internal)?.into_iter()
+                    .map(|(target, label)| match target {
+                        LabelTarget::Address(address) => json!({ "address": hex::encode(address), "label": label }),
+                        LabelTarget::Transaction(txid) => json!({ "txid": hex::encode(txid), "label": label }),
+                    })
+                    .collect::<Vec<_>>();
+                Ok(json!(labels))
+            }
+            "importpubkey" => {
+                self.check_writable()?;
+                let key = param_hash(params, 0)?;
+                let mut wallets = self.wallets.lock().await;
+                let wallet = wallets.get_mut(param_wallet(params, 1)).map_err(RpcError::invalid_params)?;
+                Ok(json!(wallet.import_key(key).map_err(RpcError::internal)?))
+            }
+            // [start_height, stop_height, wallet]; runs as a job and returns its id
+            "rescanblockchain" => {
+                self.check_writable()?;
+                let start = params.first().and_then(Value::as_u64).unwrap_or(0);
+                let stop = params.get(1).and_then(Value::as_u64);
+                if stop.is_some_and(|stop| stop < start) {
+                    return Err(RpcError::invalid_params("stop_height is below start_height"));
+                }
+                let name = param_wallet(params, 2).map(str::to_string);
+                // Fail now rather than in the job if there's no such wallet
+                self.wallets.lock().await.get(name.as_deref()).map_err(RpcError::invalid_params)?;
+                let (blockchain, wallets) = (Arc::clone(&self.blockchain), Arc::clone(&self.wallets));
+                let id = self.jobs.start("rescanblockchain", |progress| async move {
+                    let summary = wallet::rescan(&wallets, name.as_deref(), &blockchain, start, stop, &progress).await.map_err(|e| e.to_string())?;
+                    serde_json::to_value(summary).map_err(|e| e.to_string())
+                }).map_err(job_error)?;
+                Ok(json!(id))
+            }
+            "backupwallet" => {
+                let destination = param_path(params, 0)?;
+                let wallets = self.wallets.lock().await;
+                let wallet = wallets.get(param_wallet(params, 1)).map_err(RpcError::invalid_params)?;
+                Ok(json!(wallet.backup(&destination).map_err(RpcError::internal)?))
+            }
+            "walletcreatefundedpsbt" => {
+                self.check_writable()?;
+                let outputs = param_outputs(params, 0)?;
+                let policy = self.config.get().mempool.clone();
+                let fee_rate = match params.get(1).filter(|value| !value.is_null()) {
+                    Some(value) => value.as_f64().filter(|rate| rate.is_finite() && *rate >= 0.0)
+                        .ok_or_else(|| RpcError::invalid_params("Parameter 1 must be a non-negative fee rate"))?,
+                    None => policy.min_fee_rate,
+                };
+                let mut wallets = self.wallets.lock().await;
+                let wallet = wallets.get_mut(param_wallet(params, 2)).map_err(RpcError::invalid_params)?;
+                let psbt = wallet.create_psbt(outputs, fee_rate, policy.dust_limit).map_err(RpcError::internal)?;
+                Ok(json!({
+                    "psbt": hex::encode(psbt.to_bytes().map_err(RpcError::internal)?),
+                    "fee": psbt.fee().map_err(RpcError::internal)?,
+                }))
+            }
+            "createpsbt" => {
+                let (tx, spent_outputs) = self.unsigned_transaction(params).await?;
+                let entries = spent_outputs.into_iter()
+                    .map(|spent_output| PsbtInput { spent_output, key_path: None, signature: None })
+                    .collect();
+                let psbt = PartiallySignedTransaction::new(tx, entries).map_err(RpcError::internal)?;
+                Ok(json!(hex::encode(psbt.to_bytes().map_err(RpcError::internal)?)))
+            }
+            // The address an m-of-n multisig over the given keys is paid to, in any key order
+            "createmultisig" => {
+                let required = param_u64(params, 0)?;
+                let keys = params.get(1)
+                    .and_then(Value::as_array)
+                    .and_then(|keys| keys.iter().map(|key| {
+                        key.as_str().and_then(|key| hex::decode(key).ok()).and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    }).collect::<Option<Vec<_>>>())
+                    .ok_or_else(|| RpcError::invalid_params("Parameter 1 must be an array of 32-byte hex public keys"))?;
+                let script = MultisigScript::new(required as usize, keys).map_err(RpcError::invalid_params)?;
+                Ok(json!({
+                    "address": script.address(),
+                    "required": script.required(),
+                    "keys": script.keys().iter().map(hex::encode).collect::<Vec<_>>(),
+                    "script_id": hex::encode(script.script_id()),
+                }))
+            }
+            "walletprocesspsbt" => {
+                let mut psbt = param_psbt(params, 0)?;
+                let wallets = self.wallets.lock().await;
+                let wallet = wallets.get(param_wallet(params, 1)).map_err(RpcError::invalid_params)?;
+                let signed = wallet.sign_psbt(&mut psbt).map_err(RpcError::internal)?;
+                Ok(json!({
+                    "psbt": hex::encode(psbt.to_bytes().map_err(RpcError::internal)?),
+                    "signed": signed,
+                    "complete": psbt.is_complete(),
+                }))
+            }
+            "combinepsbt" => {
+                let encoded = params.first()
+                    .and_then(Value::as_array)
+                    .filter(|encoded| !encoded.is_empty())
+                    .ok_or_else(|| RpcError::invalid_params("Parameter 0 must be a non-empty array of hex PSBTs"))?;
+                let mut combined = param_psbt(encoded, 0)?;
+                for index in 1..encoded.len() {
+                    combined.combine(&param_psbt(encoded, index)?).map_err(RpcError::invalid_params)?;
+                }
+                Ok(json!(hex::encode(combined.to_bytes().map_err(RpcError::internal)?)))
+            }
+            "finalizepsbt" => {
+                let psbt = param_psbt(params, 0)?;
+                if !psbt.is_complete() {
+                    return Ok(json!({ "psbt": hex::encode(psbt.to_bytes().map_err(RpcError::internal)?), "complete": false }));
+                }
+                let tx = psbt.finalize().map_err(RpcError::rejected)?;
+                Ok(json!({ "hex": hex::encode(codec::encode(&tx).map_err(RpcError::internal)?), "complete": true }))
+            }
+            // Raw transactions, for integrators that build and sign without a wallet. Inputs name
+            // the key or multisig script their coin pays, so the coins must be in the chain or pool.
+            "createrawtransaction" => {
+                let (tx, _) = self.unsigned_transaction(params).await?;
+                Ok(json!(hex::encode(codec::encode(&tx).map_err(RpcError::internal)?)))
+            }
+            "decoderawtransaction" => {
+                let tx = param_transaction(params, 0)?;
+                let inputs = tx.inputs.iter()
+                    .map(|input| {
+                        let mut decoded = json!({
+                            "txid": hex::encode(input.previous_output.txid),
+                            "vout": input.previous_output.index,
+                            "public_key": hex::encode(input.public_key),
+                            "signature": hex::encode(&input.signature),
+                            "sequence": input.sequence,
+                        });
+                        if let Ok(witness) = MultisigWitness::decode(&input.signature) {
+                            decoded["multisig"] = json!({
+                                "address": witness.script.address(),
+                                "required": witness.script.required(),
+                                "signatures": witness.signatures.len(),
+                            });
+                        }
+                        decoded
+                    })
+                    .collect::<Vec<_>>();
+                let outputs = tx.outputs.iter()
+                    .map(|output| json!({
+                        "value": output.value,
+                        "address": hex::encode(&output.script_pubkey),
+                        "type": policy::script_type(&output.script_pubkey),
+                    }))
+                    .collect::<Vec<_>>();
+                Ok(json!({
+                    "txid": hex::encode(tx.hash()),
+                    "size": bincode::serialized_size(&tx).map_err(RpcError::internal)?,
+                    "lock_time": tx.lock_time,
+                    "inputs": inputs,
+                    "outputs": outputs,
+                }))
+            }
+            // [hex transaction, [hex 32-byte secret keys]]; signs every input one of the keys can,
+            // keeping signatures already there, and lists inputs still unsigned
+            "signrawtransactionwithkey" => {
+                let tx = param_transaction(params, 0)?;
+                let keys = params.get(1)
+                    .and_then(Value::as_array)
+                    .and_then(|keys| keys.iter().map(|key| {
+                        key.as_str().and_then(|key| hex::decode(key).ok()).and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    }).collect::<Option<Vec<_>>>())
+                    .ok_or_else(|| RpcError::invalid_params("Parameter 1 must be an array of 32-byte hex secret keys"))?;
+                let mut errors = Vec::new();
+                let mut entries = Vec::with_capacity(tx.inputs.len());
+                for (index, input) in tx.inputs.iter().enumerate() {
+                    let spent_output = match self.lookup_output(&input.previous_output).await? {
+                        Some(output) => {
+                            if let Err(e) = validation::check_input_script(0, index, input, &output) {
+                                errors.push(json!({ "vin": index, "error": e.to_string() }));
+                            }
+                            output
+                        }
+                        None => {
+                            errors.push(json!({ "vin": index, "error": "Spends an unknown or spent output" }));
+                            // Still signable with the key the input names
+                            TxOutput { value: 0, script_pubkey: input.public_key.to_vec() }
+                        }
+                    };
+                    entries.push(PsbtInput { spent_output, key_path: None, signature: None });
+                }
+                let mut psbt = PartiallySignedTransaction::new(tx, entries).map_err(RpcError::internal)?;
+                for key in &keys {
+                    psbt.sign(&SigningKey::from_bytes(key)).map_err(RpcError::invalid_params)?;
+                }
+                for (index, input) in psbt.inputs.iter().enumerate() {
+                    if input.signature.is_none() {
+                        errors.push(json!({ "vin": index, "error": "None of the keys can sign this input" }));
+                    }
+                }
+                let finalized = if psbt.is_complete() && errors.is_empty() {
+                    psbt.finalize().map_err(|e| errors.push(json!({ "vin": Value::Null, "error": e.to_string() }))).ok()
+                } else {
+                    None
+                };
+                let complete = finalized.is_some();
+                let tx = finalized.unwrap_or_else(|| psbt.partial_transaction());
+                Ok(json!({
+                    "hex": hex::encode(codec::encode(&tx).map_err(RpcError::internal)?),
+                    "complete": complete,
+                    "errors": errors,
+                }))
+            }
+            // [[hex transactions]]; whether each would be accepted into the mempool now, without
+            // adding it. Each is judged on its own, though later ones may spend earlier ones' outputs.
+            "testmempoolaccept" => {
+                let encoded = params.first()
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| RpcError::invalid_params("Parameter 0 must be an array of hex transactions"))?;
+                let transactions = (0..encoded.len())
+                    .map(|index| param_transaction(encoded, index))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let next_height = self.blockchain.get_chain_height().map_or(0, |height| height + 1);
+                let median_time_past = self.blockchain.median_time_past();
+                let mut created = HashMap::new();
+                let mut results = Vec::with_capacity(transactions.len());
+                for tx in &transactions {
+                    let result = self.test_mempool_accept(tx, &created, next_height, median_time_past).await?;
+                    if result["allowed"] == json!(true) {
+                        let txid = tx.hash();
+                        created.extend(tx.outputs.iter().enumerate().map(|(index, output)| (OutPoint { txid, index: index as u32 }, output.clone())));
+                    }
+                    results.push(result);
+                }
+                Ok(json!(results))
+            }
+            "reloadconfig" => {
+                let report = self.config.reload().map_err(RpcError::internal)?;
+                serde_json::to_value(report).map_err(RpcError::internal)
+            }
+            // Long chain maintenance runs as a background job; these return its id for getjobstatus
+            "reindex" => {
+                let blockchain = Arc::clone(&self.blockchain);
+                let id = self.jobs.start("reindex", |progress| async move {
+                    blockchain.reindex(&progress).await.map(|blocks| json!({ "blocks": blocks })).map_err(|e| e.to_string())
+                }).map_err(job_error)?;
+                Ok(json!(id))
+            }
+            "verifychain" => {
+                let depth = params.first().and_then(Value::as_u64).unwrap_or(DEFAULT_VERIFY_DEPTH);
+                let level = params.get(1).and_then(Value::as_u64).unwrap_or(DEFAULT_VERIFY_LEVEL);
+                if level > 3 {
+                    return Err(RpcError::invalid_params("Level must be between 0 and 3"));
+                }
+                let blockchain = Arc::clone(&self.blockchain);
+                let id = self.jobs.start("verifychain", |progress| async move {
+                    let report = blockchain.verify_chain(depth, level as u8, &progress).await.map_err(|e| e.to_string())?;
+                    serde_json::to_value(report).map_err(|e| e.to_string())
+                }).map_err(job_error)?;
+                Ok(json!(id))
+            }
+            // One job by id, or every job still remembered
+            "getjobstatus" => match params.first().and_then(Value::as_u64) {
+                Some(id) => serde_json::to_value(self.jobs.status(id).map_err(job_error)?).map_err(RpcError::internal),
+                None => serde_json::to_value(self.jobs.list()).map_err(RpcError::internal),
+            },
+            // A cancelled reindex leaves the chain at the height it reached and refuses new blocks
+            // until reindex is run again
+            "canceljob" => Ok(json!(self.jobs.cancel(param_u64(params, 0)?).map_err(job_error)?)),
+            "stop" => {
+                self.shutdown.cancel();
+                Ok(json!("xcored stopping"))
+            }
+            _ => Err(RpcError { code: METHOD_NOT_FOUND, message: format!("Method not found: {}", method) }),
+        }
+    }
+
+    fn history_json(&self, wallet: &Wallet, entry: &HistoryEntry) -> Result<Value, RpcError> {
+        let confirmations = match (entry.height, self.blockchain.get_chain_height()) {
+            (Some(height), Some(tip)) if height <= tip => tip - height + 1,
+            _ => 0,
+        };
+        Ok(json!({
+            "txid": hex::encode(entry.txid),
+            "direction": entry.direction(),
+            "status": wallet.status(entry),
+            "received": entry.received,
+            "sent": entry.sent,
+            "fee": entry.fee,
+            "confirmations": confirmations,
+            "height": entry.height,
+            "blockhash": entry.block_hash.map(hex::encode),
+            "blocktime": entry.block_time,
+            "time": entry.first_seen,
+            "label": wallet.label(LabelTarget::Transaction(entry.txid)).map_err(RpcError::internal)?,
+        }))
+    }
+
+    // Spending from the mempool is allowed, so chains of unconfirmed transactions can be built
+    async fn lookup_output(&self, outpoint: &OutPoint) -> Result<Option<TxOutput>, RpcError> {
+        let pool_output = self.mempool.lock().get_transaction(&outpoint.txid)
+            .and_then(|tx| tx.outputs.get(outpoint.index as usize).cloned());
+        match pool_output {
+            Some(output) => Ok(Some(output)),
+            None => Ok(self.blockchain.get_coin(outpoint).await.map_err(RpcError::internal)?.map(|coin| coin.output)),
+        }
+    }
+
+    // As lookup_output, with outputs still in the mempool treated as confirming in the next block
+    async fn lookup_coin(&self, outpoint: &OutPoint, next_height: u64, median_time_past: u64) -> Result<Option<Coin>, RpcError> {
+        let pool_output = self.mempool.lock().get_transaction(&outpoint.txid)
+            .and_then(|tx| tx.outputs.get(outpoint.index as usize).cloned());
+        match pool_output {
+            Some(output) => Ok(Some(Coin { output, height: next_height, median_time_past, is_coinbase: false })),
+            None => self.blockchain.get_coin(outpoint).await.map_err(RpcError::internal),
+        }
+    }
+
+    // [inputs, outputs, lock_time] as an unsigned transaction and the outputs its inputs spend
+    async fn unsigned_transaction(&self, params: &[Value]) -> Result<(Transaction, Vec<TxOutput>), RpcError> {
+        let inputs = param_inputs(params, 0)?;
+        let outputs = param_outputs(params, 1)?;
+        let lock_time = match params.get(2) {
+            Some(value) => value.as_u64().and_then(|value| u32::try_from(value).ok())
+                .ok_or_else(|| RpcError::invalid_params("Parameter 2 must be a 32-bit lock time"))?,
+            None => 0,
+        };
+        let mut tx = Transaction { inputs: Vec::with_capacity(inputs.len()), outputs, lock_time };
+        let mut spent_outputs = Vec::with_capacity(inputs.len());
+        for (index, (previous_output, sequence)) in inputs.into_iter().enumerate() {
+            let spent_output = self.lookup_output(&previous_output).await?
+                .ok_or_else(|| RpcError::invalid_params(format!("Input {} spends an unknown or spent output", index)))?;
+            let public_key = multisig::spending_key(&spent_output.script_pubkey)
+                .ok_or_else(|| RpcError::invalid_params(format!("Input {} spends an output that doesn't pay to a public key or multisig script", index)))?;
+            tx.inputs.push(TxInput { previous_output, public_key, signature: Vec::new(), sequence });
+            spent_outputs.push(spent_output);
+        }
+        Ok((tx, spent_outputs))
+    }
+
+    // One testmempoolaccept result. Input problems are all reported, each against its input, before
+    // the transaction as a whole is judged; `created` holds outputs of earlier transactions tested.
+    // A block for the next height filled from the mempool, with its parts broken out for pools that
+    // build their own coinbase. The block miner's share goes to mining.payouts, or failing that to
+    // the first receive address of the only loaded wallet.
+    async fn block_template(&self) -> Result<Value, RpcError> {
+        let builder = BlockTemplateBuilder::for_chain(&self.blockchain).map_err(RpcError::rejected)?;
+        // Templates are for anyone with read access, so they never pay a wallet's address
+        let payout = self.config.get().mining.payout().map_err(RpcError::internal)?
+            .ok_or_else(|| RpcError::invalid_params("No mining.payouts configured for templates to pay"))?;
+
+        let (candidates, fees, fruits) = {
+            let mempool = self.mempool.lock();
+            let hashes = mempool.transaction_hashes();
+            let candidates = hashes.iter().filter_map(|hash| mempool.get_transaction(hash).cloned()).collect::<Vec<_>>();
+            let fees = hashes.iter().filter_map(|hash| Some((*hash, mempool.entry_info(hash)?.fee))).collect::<HashMap<_, _>>();
+            let fruits = mempool.get_fruits().into_iter().filter_map(|fruit| fruit.block.fruit_header).collect::<Vec<_>>();
+            (candidates, fees, fruits)
+        };
+        let fruits = self.blockchain.select_fruits(fruits).await.map_err(RpcError::internal)?.included;
+        let in_pool = |txid: &[u8; 32]| fees.contains_key(txid);
+
+        let tip = self.blockchain.get_chain_tip();
+        let height = self.blockchain.get_chain_height().map_or(0, |height| height + 1);
+        let median_time_past = self.blockchain.median_time_past();
+        let version = self.blockchain.next_block_version();
+        let timestamp = self.blockchain.next_block_timestamp();
+        let bits = self.blockchain.next_block_bits(timestamp).await.map_err(RpcError::internal)?;
+        // Which transactions fit isn't known until the block is filled, so the coinbase paying their
+        // fees replaces a placeholder afterwards
+        let placeholder = self.blockchain.create_coinbase(0, &fruits, &payout).await.map_err(RpcError::internal)?;
+        let mut cache = self.template_cache.lock().await;
+        let cache = cache.get_or_insert_with(|| TemplateCache::new(builder));
+        cache.check_pool(in_pool);
+        if cache.current(&tip).is_some() {
+            cache.add_transactions(candidates, in_pool).map_err(RpcError::internal)?;
+            cache.set_fruits(fruits, placeholder).map_err(RpcError::internal)?;
+        } else {
+            cache.refresh(version, tip, height, median_time_past, timestamp, bits, placeholder, fruits, candidates, in_pool).map_err(RpcError::internal)?;
+        }
+        let mut block = cache.current(&tip).cloned().ok_or_else(|| RpcError::internal("template was just built"))?;
+        // A cached block keeps its transactions but takes this call's header fields
+        block.header.version = version;
+        block.header.timestamp = timestamp;
+        block.header.bits = bits;
+        let total_fees = block.transactions[1..].iter().filter_map(|tx| fees.get(&tx.hash())).sum::<u64>();
+        block.transactions[0] = self.blockchain.create_coinbase(total_fees, &block.fruits, &payout).await.map_err(RpcError::internal)?;
+        block.header.merkle_root = calculate_merkle_root(&block.transactions);
+
+        let encode = |tx: &Transaction| codec::encode(tx).map(hex::encode).map_err(RpcError::internal);
+        let transactions = block.transactions[1..].iter()
+            .map(|tx| Ok(json!({ "txid": hex::encode(tx.hash()), "data": encode(tx)?, "fee": fees.get(&tx.hash()) })))
+            .collect::<Result<Vec<_>, RpcError>>()?;
+        Ok(json!({
+            "version": block.header.version,
+            "previousblockhash": hex::encode(block.header.previous_hash),
+            "height": height,
+            "curtime": timestamp,
+            "mintime": median_time_past + 1,
+            "bits": format!("{:08x}", bits),
+            "coinbasevalue": block.transactions[0].outputs.iter().map(|output| output.value).sum::<u64>(),
+            "coinbasetxn": { "data": encode(&block.transactions[0])? },
+            // Hash for the coinbase's last output to commit to, when commitments are active
+            "utxocommitment": self.blockchain.next_utxo_commitment().await.map(hex::encode),
+            "transactions": transactions,
+            "fruits": block.fruits.iter().map(|fruit| hex::encode(fruit.hash())).collect::<Vec<_>>(),
+            // The whole block with a zero nonce, ready to grind
+            "block": hex::encode(codec::encode(&block).map_err(RpcError::internal)?),
+        }))
+    }
+
+    // Null when `block` would be accepted on the current tip, otherwise why not: a short reason
+    // code and the full validation error. Proof of work isn't checked.
+    async fn check_proposal(&self, block: &Block) -> Result<Value, RpcError> {
+        let Err(e) = self.blockchain.check_block_proposal(block).await else { return Ok(Value::Null) };
+        match e.downcast_ref::<ValidationError>().and_then(|error| Some((error.reject_reason()?, error))) {
+            Some((reason, error)) => Ok(json!({ "reason": reason, "message": error.to_string() })),
+            None => Err(RpcError::internal(e)),
+        }
+    }
+
+    async fn test_mempool_accept(&self, tx: &Transaction, created: &HashMap<OutPoint, TxOutput>, next_height: u64, median_time_past: u64) -> Result<Value, RpcError> {
+        let size = bincode::serialized_size(tx).map_err(RpcError::internal)?;
+        let mut result = json!({
+            "txid": hex::encode(tx.hash()),
+            "allowed": false,
+            "reason": Value::Null,
+            "fee": Value::Null,
+            "size": size,
+            "fee_rate": Value::Null,
+            "input_errors": [],
+        });
+        if tx.is_coinbase() {
+            result["reason"] = json!("Coinbase transactions are only valid in blocks");
+            return Ok(result);
+        }
+        let message = match tx.signature_hash() {
+            Ok(message) => message,
+            Err(e) => {
+                result["reason"] = json!(e.to_string());
+                return Ok(result);
+            }
+        };
+
+        let mut input_errors = Vec::new();
+        let mut seen = HashSet::new();
+        let mut input_value = 0u64;
+        let mut coins = Vec::with_capacity(tx.inputs.len());
+        for (index, input) in tx.inputs.iter().enumerate() {
+            let mut fail = |reason: &str, message: String| input_errors.push(json!({ "vin": index, "reason": reason, "message": message }));
+            if !seen.insert(input.previous_output) {
+                fail("duplicate-input", "Spends the same output as an earlier input".to_string());
+                continue;
+            }
+            let spent = match created.get(&input.previous_output) {
+                Some(output) => Some(Coin { output: output.clone(), height: next_height, median_time_past, is_coinbase: false }),
+                None => self.lookup_coin(&input.previous_output, next_height, median_time_past).await?,
+            };
+            match spent {
+                Some(coin) => {
+                    input_value = input_value.saturating_add(coin.output.value);
+                    if let Err(e) = validation::check_input_script(0, index, input, &coin.output) {
+                        fail("script-mismatch", e.to_string());
+                    }
+                    coins.push(coin);
+                }
+                None => fail("missing-input", format!("Output {}:{} is unknown or spent", hex::encode(input.previous_output.txid), input.previous_output.index)),
+            }
+            if let Err(e) = tx.verify_input(index, &message) {
+                fail("bad-signature", e.to_string());
+            }
+        }
+        if !input_errors.is_empty() {
+            result["reason"] = json!(format!("{} of {} inputs are invalid", input_errors.len(), tx.inputs.len()));
+            result["input_errors"] = json!(input_errors);
+            return Ok(result);
+        }
+        if !tx.sequence_lock(&coins).is_satisfied(next_height, median_time_past) {
+            result["reason"] = json!("Sequence locks (BIP68) aren't satisfied yet");
+            return Ok(result);
+        }
+
+        let Some(fee) = tx.outputs.iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .and_then(|output_value| input_value.checked_sub(output_value)) else {
+            result["reason"] = json!("Outputs are worth more than the inputs they spend");
+            return Ok(result);
+        };
+        result["fee"] = json!(fee);
+        result["fee_rate"] = json!(fee as f64 / size.max(1) as f64);
+        match self.mempool.lock().test_accept(tx, fee, next_height, median_time_past) {
+            Ok(()) => result["allowed"] = json!(true),
+            Err(e) => result["reason"] = json!(e.to_string()),
+        }
+        Ok(result)
+    }
+
+    // Wallet calls that update the wallet file
+    fn check_writable(&self) -> Result<(), RpcError> {
+        if self.config.get().read_only {
+            return Err(RpcError::internal("This call updates the wallet file, which a read-only node can't do"));
+        }
+        Ok(())
+    }
+
+    async fn new_wallet_address(&self, params: &[Value], chain: KeyChain) -> Result<Value, RpcError> {
+        self.check_writable()?;
+        let mut wallets = self.wallets.lock().await;
+        let wallet = wallets.get_mut(param_wallet(params, 0)).map_err(RpcError::invalid_params)?;
+        let address = wallet.new_address(chain).map_err(RpcError::internal)?;
+        serde_json::to_value(address).map_err(RpcError::internal)
+    }
+}
+
+// Takes a single request or a batch of them, answering a batch with an array of responses in
+// the same order. Calls in a batch run concurrently, each within the worker and method limits.
+// Unauthenticated so load balancers and container runtimes can probe it; 503 while the node is in
+// initial block download or has warnings
+async fn handle_health(State(server): State<Arc<RpcServer>>) -> Response {
+    match server.node_info().await {
+        Ok(info) => {
+            let status = if !info.initial_block_download && info.warnings.is_empty() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+            let body = json!({
+                "status": if status == StatusCode::OK { "ok" } else { "degraded" },
+                "height": info.blocks,
+                "initialblockdownload": info.initial_block_download,
+                "warnings": info.warnings,
+            });
+            (status, Json(body)).into_response()
+        }
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "error", "error": e.message }))).into_response(),
+    }
+}
+
+async fn handle_request(State(server): State<Arc<RpcServer>>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    let authorization = headers.get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    let credential = match server.auth.authenticate(authorization) {
+        Some(credential) => credential.clone(),
+        None => return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Basic realm=\"xcored\"")]).into_response(),
+    };
+
+    let batch = match body {
+        Value::Array(batch) => batch,
+        single => return Json(handle_call(&server, &credential, single).await).into_response(),
+    };
+    if batch.is_empty() || batch.len() > server.limits.batch_limit {
+        let message = format!("Batches must hold between 1 and {} requests", server.limits.batch_limit);
+        return Json(RpcResponse::error(Value::Null, RpcError { code: INVALID_REQUEST, message })).into_response();
+    }
+    let mut calls = JoinSet::new();
+    // Which request each task answers, so one that panics still gets an error response
+    let mut requests = HashMap::new();
+    let count = batch.len();
+    for (index, call) in batch.into_iter().enumerate() {
+        let server = Arc::clone(&server);
+        let credential = credential.clone();
+        let id = call.get("id").cloned().unwrap_or(Value::Null);
+        let task = calls.spawn(async move { (index, handle_call(&server, &credential, call).await) });
+        requests.insert(task.id(), (index, id));
+    }
+    let mut responses = Vec::with_capacity(count);
+    while let Some(joined) = calls.join_next().await {
+        match joined {
+            Ok(response) => responses.push(response),
+            Err(e) => {
+                log::error!("RPC batch call panicked: {}", e);
+                if let Some((index, id)) = requests.remove(&e.id()) {
+                    responses.push((index, RpcResponse::error(id, RpcError::internal("Call failed unexpectedly"))));
+                }
+            }
+        }
+    }
+    responses.sort_by_key(|(index, _)| *index);
+    Json(responses.into_iter().map(|(_, response)| response).collect::<Vec<_>>()).into_response()
+}
+
+// Runs a call in its own task, so a timeout or a dropped connection stops the wait without cutting
+// a state-changing call such as a wallet send or invalidateblock off half way
+async fn run_detached(method: &str, timeout: Duration, call: impl Future<Output = Result<Value, RpcError>> + Send + 'static) -> Result<Value, RpcError> {
+    match tokio::time::timeout(timeout, tokio::spawn(call)).await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(e)) => {
+            log::error!("RPC call {} panicked: {}", method, e);
+            Err(RpcError::internal(format!("{} failed unexpectedly", method)))
+        }
+        Err(_) => Err(RpcError { code: TIMED_OUT, message: format!("{} timed out after {} seconds and finishes in the background", method, timeout.as_secs()) }),
+    }
+}
+
+async fn handle_call(server: &Arc<RpcServer>, credential: &Credential, call: Value) -> RpcResponse {
+    let request: RpcRequest = match serde_json::from_value(call) {
+        Ok(request) => request,
+        Err(e) => return RpcResponse::error(Value::Null, RpcError { code: INVALID_REQUEST, message: e.to_string() }),
+    };
+    let outcome = if credential.allows(&request.method) {
+        server.execute(&request.method, &request.params).await
+    } else {
+        log::debug!("RPC user '{}' may not call {}", credential.user(), request.method);
+        Err(RpcError { code: METHOD_FORBIDDEN, message: format!("Method {} is not permitted for this user", request.method) })
+    };
+    match outcome {
+        Ok(result) => RpcResponse { jsonrpc: "2.0".to_string(), id: request.id, result: Some(result), error: None },
+        Err(error) => RpcResponse::error(request.id, error),
+    }
+}
+
+fn job_error(error: JobError) -> RpcError {
+    match error {
+        JobError::NotFound(_) => RpcError::invalid_params(error),
+        JobError::Busy { .. } => RpcError::internal(error),
+    }
+}
+
+pub fn param_u64(params: &[Value], index: usize) -> Result<u64, RpcError> {
+    params.get(index)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be a non-negative integer", index)))
+}
+
+// Parameter `index` as an object mapping hex addresses to amounts. Addresses are 32-byte keys or
+// multisig scripts.
+pub fn param_outputs(params: &[Value], index: usize) -> Result<Vec<TxOutput>, RpcError> {
+    params.get(index)
+        .and_then(Value::as_object)
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be an object of hex addresses to amounts", index)))?
+        .iter()
+        .map(|(address, amount)| {
+            let script_pubkey = hex::decode(address).ok().filter(|script| multisig::spending_key(script).is_some())
+                .ok_or_else(|| RpcError::invalid_params(format!("'{}' is not a 32-byte or multisig hex address", address)))?;
+            let value = amount.as_u64()
+                .ok_or_else(|| RpcError::invalid_params(format!("Amount for {} must be a non-negative integer", address)))?;
+            Ok(TxOutput { value, script_pubkey })
+        })
+        .collect()
+}
+
+// Parameter `index` as an array of {"txid", "vout", "sequence"} objects; the sequence defaults to
+// final, opting out of relative lock-times
+pub fn param_inputs(params: &[Value], index: usize) -> Result<Vec<(OutPoint, u32)>, RpcError> {
+    let inputs = params.get(index)
+        .and_then(Value::as_array)
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be an array of {{\"txid\", \"vout\"}} objects", index)))?;
+    inputs.iter().enumerate()
+        .map(|(position, input)| {
+            let txid = input.get("txid").and_then(Value::as_str)
+                .and_then(|txid| hex::decode(txid).ok())
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok());
+            let vout = input.get("vout").and_then(Value::as_u64).and_then(|vout| u32::try_from(vout).ok());
+            let sequence = match input.get("sequence") {
+                Some(sequence) => sequence.as_u64().and_then(|sequence| u32::try_from(sequence).ok()),
+                None => Some(SEQUENCE_FINAL),
+            };
+            match (txid, vout, sequence) {
+                (Some(txid), Some(index), Some(sequence)) => Ok((OutPoint { txid, index }, sequence)),
+                _ => Err(RpcError::invalid_params(format!("Input {} needs a txid, a vout and optionally a 32-bit sequence", position))),
+            }
+        })
+        .collect()
+}
+
+pub fn param_transaction(params: &[Value], index: usize) -> Result<Transaction, RpcError> {
+    params.get(index)
+        .and_then(Value::as_str)
+        .and_then(|hex_str| hex::decode(hex_str).ok())
+        .and_then(|bytes| codec::decode::<Transaction>(&bytes).ok())
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be a hex-encoded transaction", index)))
+}
+
+// Parameter `index` as a hex locking script; addresses are the 32-byte scripts they pay to
+pub fn param_script(params: &[Value], index: usize) -> Result<Vec<u8>, RpcError> {
+    params.get(index)
+        .and_then(Value::as_str)
+        .and_then(|hex_str| hex::decode(hex_str).ok())
+        .filter(|script| !script.is_empty())
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be a hex address or script", index)))
+}
+
+pub fn param_psbt(params: &[Value], index: usize) -> Result<PartiallySignedTransaction, RpcError> {
+    params.get(index)
+        .and_then(Value::as_str)
+        .and_then(|hex_str| hex::decode(hex_str).ok())
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be a hex PSBT", index)))
+        .and_then(|bytes| PartiallySignedTransaction::from_bytes(&bytes).map_err(RpcError::invalid_params))
+}
+
+// Paths are used as given, relative to the node's working directory
+pub fn param_path(params: &[Value], index: usize) -> Result<PathBuf, RpcError> {
+    params.get(index).and_then(Value::as_str).filter(|path| !path.is_empty()).map(PathBuf::from)
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be a destination path", index)))
+}
+
+// The wallet a call applies to, which may be left out when only one is loaded
+pub fn param_wallet(params: &[Value], index: usize) -> Option<&str> {
+    params.get(index).and_then(Value::as_str)
+}
+
+pub fn param_hash(params: &[Value], index: usize) -> Result<BlockHash, RpcError> {
+    let hex_str = params.get(index)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params(format!("Parameter {} must be a hex string", index)))?;
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(hex_str, &mut hash)
+        .map_err(|_| RpcError::invalid_params(format!("Parameter {} is not a 32-byte hex hash", index)))?;
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_params::Network;
+    use crate::node_config::{BlockchainConfig, DiskConfig};
+    use crate::policy::RelayPolicy;
+    use crate::protocol::NODE_NETWORK;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tempfile::TempDir;
+
+    // A server on an empty regtest chain, credentials and all, in its own datadir
+    async fn test_server() -> Result<(Arc<RpcServer>, TempDir), Box<dyn std::error::Error>> {
+        let datadir = TempDir::new()?;
+        let mut config = BlockchainConfig::load(datadir.path())?;
+        config.chain = Network::Regtest;
+        config.disk = DiskConfig { low_free_mb: 0, min_free_mb: 0 };
+        let blockchain = Arc::new(Blockchain::new(config.clone()).await?);
+        let params = blockchain.params().clone();
+        let mempool = Mempool::new(1, 600, 3600, 60, RelayPolicy { min_relay_fee_rate: 0.0, dust_limit: 0, accept_non_standard: false }, params.fruit_freshness_window, params.fruit_bits);
+        let wallets = Wallets::load(&config.wallets_dir)?;
+        let auth = Arc::new(RpcAuth::new(&config.rpc, &datadir.path().join(crate::rpc_auth::COOKIE_FILE_NAME))?);
+        let mempool = Arc::new(Mutex::new(mempool));
+        let addrman = Arc::new(Mutex::new(AddrManager::new()));
+        let peers = PeerManager::new(Arc::clone(&blockchain), Arc::clone(&mempool), Arc::clone(&addrman), config.network.clone(), NODE_NETWORK, CancellationToken::new());
+        let server = RpcServer::new(
+            blockchain,
+            mempool,
+            Arc::new(tokio::sync::Mutex::new(wallets)),
+            addrman,
+            Some(Arc::new(peers)),
+            Arc::new(JobManager::new()),
+            ConfigHandle::new(datadir.path().to_path_buf(), config),
+            auth,
+            CancellationToken::new(),
+        );
+        Ok((Arc::new(server), datadir))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timed_out_calls_finish_and_panics_become_errors() {
+        let finished = Arc::new(AtomicBool::new(false));
+        let call = {
+            let finished = Arc::clone(&finished);
+            async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                finished.store(true, Ordering::SeqCst);
+                Ok(Value::Null)
+            }
+        };
+        let error = run_detached("sendtoaddress", Duration::from_secs(30), call).await.unwrap_err();
+        assert_eq!(error.code, TIMED_OUT);
+        assert!(!finished.load(Ordering::SeqCst));
+        // The caller stopped waiting but the call itself carries on to the end
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(finished.load(Ordering::SeqCst));
+
+        let error = run_detached("getblock", Duration::from_secs(30), async { panic!("bad block") }).await.unwrap_err();
+        assert_eq!(error.code, INTERNAL_ERROR);
+        assert_eq!(run_detached("getblockcount", Duration::from_secs(30), async { Ok(json!(7)) }).await.unwrap(), json!(7));
+    }
+
+    #[tokio::test]
+    async fn test_disk_usage_is_measured_once_and_reused() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, _datadir) = test_server().await?;
+        let first = server.disk_usage().await.map_err(|e| e.message)?;
+        let blocks_dir = server.config.get().blocks_dir;
+        std::fs::create_dir_all(&blocks_dir)?;
+        std::fs::write(blocks_dir.join("extra.dat"), vec![0u8; 4096])?;
+        // A probe soon after sees the earlier walk, not the new file
+        assert_eq!(server.disk_usage().await.map_err(|e| e.message)?.blocks_bytes, first.blocks_bytes);
+
+        *server.disk_usage.lock().await = None;
+        assert_eq!(server.disk_usage().await.map_err(|e| e.message)?.blocks_bytes, first.blocks_bytes + 4096);
+
+        let info = serde_json::to_value(server.node_info().await.map_err(|e| e.message)?)?;
+        assert_eq!((&info["blocks"], &info["headers"], &info["peers"]["connected"]), (&Value::Null, &Value::Null, &json!(0)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chain_stats_are_reused_until_the_tip_or_window_changes() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, _datadir) = test_server().await?;
+        let tip = server.blockchain.get_chain_tip();
+        let marked = ChainStats { blocks: 42, ..ChainStats::default() };
+        *server.chain_stats.lock().await = Some((tip, DEFAULT_CHAIN_STATS_WINDOW, marked));
+        let stats = server.dispatch("getchainstats", &[]).await.map_err(|e| e.message)?;
+        assert_eq!(stats["blocks"], 42);
+
+        // Another window is computed afresh and replaces the cached one
+        let stats = server.dispatch("getchainstats", &[json!(10)]).await.map_err(|e| e.message)?;
+        assert_eq!(stats["blocks"], 0);
+        assert!(server.chain_stats.lock().await.as_ref().is_some_and(|(hash, window, _)| *hash == tip && *window == 10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_templates_need_a_configured_payout() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, _datadir) = test_server().await?;
+        // Even with a wallet to hand, a template doesn't pay it
+        let wallets_dir = server.config.get().wallets_dir;
+        drop(Wallet::create_watch_only(&wallets_dir, "miner", vec![[9; 32]])?);
+        *server.wallets.lock().await = Wallets::load(&wallets_dir)?;
+        let error = server.dispatch("getblocktemplate", &[]).await.unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+        assert!(error.message.contains("mining.payouts"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mempool_snapshot_proves_a_bounded_number_of_txids() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, _datadir) = test_server().await?;
+        let unknown = hex::encode([1; 32]);
+        let result = server.dispatch("getmempoolsnapshot", &[json!([unknown])]).await.map_err(|e| e.message)?;
+        assert_eq!(result["proofs"][&unknown], Value::Null);
+
+        let too_many = vec![json!(unknown); MAX_SNAPSHOT_PROOFS_PER_REQUEST + 1];
+        let error = server.dispatch("getmempoolsnapshot", &[Value::Array(too_many)]).await.unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mempool_accept_checks_relative_lock_times() -> Result<(), Box<dyn std::error::Error>> {
+        use ed25519_dalek::{Signer, SigningKey};
+        let (server, _datadir) = test_server().await?;
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let parent_output = OutPoint { txid: [1; 32], index: 0 };
+        let created = HashMap::from([(parent_output, TxOutput { value: 10_000, script_pubkey: key.verifying_key().to_bytes().to_vec() })]);
+        let spend = |sequence: u32| -> Result<Transaction, Box<dyn std::error::Error>> {
+            let mut tx = Transaction {
+                inputs: vec![TxInput { previous_output: parent_output, public_key: key.verifying_key().to_bytes(), signature: Vec::new(), sequence }],
+                outputs: vec![TxOutput { value: 9_000, script_pubkey: key.verifying_key().to_bytes().to_vec() }],
+                lock_time: 0,
+            };
+            let message = tx.signature_hash()?;
+            tx.inputs[0].signature = key.sign(&message).to_bytes().to_vec();
+            Ok(tx)
+        };
+
+        // An output created earlier in the package confirms in the next block at the soonest
+        let waiting = server.test_mempool_accept(&spend(1)?, &created, 1, 0).await.map_err(|e| e.message)?;
+        assert_eq!(waiting["allowed"], false);
+        assert_eq!(waiting["reason"], "Sequence locks (BIP68) aren't satisfied yet");
+        let ready = server.test_mempool_accept(&spend(0)?, &created, 1, 0).await.map_err(|e| e.message)?;
+        assert_eq!(ready["allowed"], true, "{}", ready["reason"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use crate::node_config::{RpcConfig, RpcCredentialConfig};
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// HTTP basic authentication for the RPC server. The credential in `rpc.user`/`rpc.password` may
+// call anything; without one the server writes a random password to a cookie file in the datadir
+// that local tools such as xcore-cli read instead. `rpc.credentials` adds users limited to groups
+// of methods and named extras.
+pub const COOKIE_FILE_NAME: &str = ".cookie";
+// Read-only nodes share the primary's datadir, so they write their cookie under another name
+pub const READ_ONLY_COOKIE_FILE_NAME: &str = ".readonly.cookie";
+pub const COOKIE_USER: &str = "__cookie__";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    // Chain, mempool and index queries, and PSBT and raw transaction calls that don't touch a wallet or take keys
+    Read,
+    // Wallet calls, signing with caller-supplied keys and transaction submission
+    Wallet,
+    // Node control, backups, which write to the node's filesystem, and anything not listed
+    Admin,
+}
+
+pub fn method_permission(method: &str) -> Permission {
+    match method {
+        "getblockcount" | "getbestblockhash" | "getblockhash" | "getblock" | "getheaders" | "gettxoutsetinfo"
+        | "getdbinfo" | "getchainstats" | "getchaintips" | "getblockstats" | "getjobstatus" | "getdeploymentinfo" | "getcfilters" | "getcfheaders" | "gettxoutproof" | "verifytxoutproof"
+        | "getaddressbalance" | "getaddressutxos" | "getaddresshistory" | "getspentinfo"
+        | "getmempoolinfo" | "getrawmempool" | "getmempoolremovals" | "getmempoolsnapshot" | "getblocktemplate" | "getnodeinfo" | "createmultisig" | "createpsbt" | "combinepsbt" | "finalizepsbt"
+        | "createrawtransaction" | "decoderawtransaction" | "testmempoolaccept" => Permission::Read,
+        "submitpackage" | "listwallets" | "getnewaddress" | "getrawchangeaddress" | "getbalance" | "listtransactions"
+        | "gettransaction" | "setlabel" | "settransactionlabel" | "listlabels" | "importpubkey" | "rescanblockchain"
+        | "walletcreatefundedpsbt" | "walletprocesspsbt" | "signrawtransactionwithkey" => Permission::Wallet,
+        _ => Permission::Admin,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Credential {
+    user: String,
+    password: String,
+    permissions: HashSet<Permission>,
+    methods: HashSet<String>,
+}
+
+impl Credential {
+    fn full_access(user: String, password: String) -> Self {
+        let permissions = [Permission::Read, Permission::Wallet, Permission::Admin].into_iter().collect();
+        Credential { user, password, permissions, methods: HashSet::new() }
+    }
+
+    fn from_config(config: &RpcCredentialConfig) -> Self {
+        Credential {
+            user: config.user.clone(),
+            password: config.password.clone(),
+            permissions: config.permissions.iter().copied().collect(),
+            methods: config.methods.iter().cloned().collect(),
+        }
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    pub fn allows(&self, method: &str) -> bool {
+        self.permissions.contains(&method_permission(method)) || self.methods.contains(method)
+    }
+}
+
+pub struct RpcAuth {
+    credentials: Vec<Credential>,
+    // Written by `new` and removed again on drop
+    cookie_path: Option<PathBuf>,
+}
+
+impl RpcAuth {
+    pub fn new(config: &RpcConfig, cookie_path: &Path) -> io::Result<Self> {
+        let mut credentials = config.credentials.iter().map(Credential::from_config).collect::<Vec<_>>();
+        let cookie_path = match (&config.user, &config.password) {
+            (Some(user), Some(password)) => {
+                credentials.push(Credential::full_access(user.clone(), password.clone()));
+                None
+            }
+            _ => {
+                let mut secret = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                let password = hex::encode(secret);
+                write_cookie(cookie_path, &password)?;
+                credentials.push(Credential::full_access(COOKIE_USER.to_string(), password));
+                Some(cookie_path.to_path_buf())
+            }
+        };
+        Ok(RpcAuth { credentials, cookie_path })
+    }
+
+    // The credential an `Authorization: Basic ...` header value proves, if any
+    pub fn authenticate(&self, authorization: Option<&str>) -> Option<&Credential> {
+        let encoded = authorization?.strip_prefix("Basic ")?;
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        // Check every credential so timing doesn't reveal which users exist; `next_back` would stop early
+        #[allow(clippy::double_ended_iterator_last)]
+        self.credentials.iter()
+            .filter(|credential| constant_time_eq(credential.user.as_bytes(), user.as_bytes()) & constant_time_eq(credential.password.as_bytes(), password.as_bytes()))
+            .last()
+    }
+}
+
+impl Drop for RpcAuth {
+    fn drop(&mut self) {
+        if let Some(path) = &self.cookie_path {
+            if let Err(e) = fs::remove_file(path) {
+                log::warn!("Could not remove RPC cookie {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn write_cookie(path: &Path, password: &str) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).truncate(true).write(true);
+    // Only the node's own user may read it
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    write!(file, "{}:{}", COOKIE_USER, password)
+}
+
+// The user and password a running node wrote to its cookie file
+pub fn read_cookie(path: &Path) -> io::Result<(String, String)> {
+    let contents = fs::read_to_string(path)?;
+    contents.trim().split_once(':')
+        .map(|(user, password)| (user.to_string(), password.to_string()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not an RPC cookie", path.display())))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password)))
+    }
+
+    #[test]
+    fn test_cookie_and_method_permissions() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cookie_path = temp_dir.path().join(COOKIE_FILE_NAME);
+        let mut config = RpcConfig::default();
+        config.credentials.push(RpcCredentialConfig {
+            user: "explorer".to_string(),
+            password: "hunter2".to_string(),
+            permissions: vec![Permission::Read],
+            methods: vec!["getbalance".to_string()],
+        });
+
+        let auth = RpcAuth::new(&config, &cookie_path)?;
+        let (user, password) = read_cookie(&cookie_path)?;
+        let cookie = auth.authenticate(Some(&basic(&user, &password))).expect("cookie is accepted");
+        assert!(cookie.allows("stop"));
+        assert!(auth.authenticate(Some(&basic(&user, "wrong"))).is_none());
+        assert!(auth.authenticate(None).is_none());
+
+        let explorer = auth.authenticate(Some(&basic("explorer", "hunter2"))).expect("configured credential is accepted");
+        assert!(explorer.allows("getblock") && explorer.allows("getbalance"));
+        assert!(!explorer.allows("getnewaddress") && !explorer.allows("stop") && !explorer.allows("someunknownmethod"));
+        assert!(explorer.allows("createrawtransaction") && !explorer.allows("signrawtransactionwithkey"));
+
+        drop(auth);
+        assert!(!cookie_path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// Each wait is stretched or shortened by up to this share of the period so jobs started together
+// drift apart instead of hitting the database at the same moment every time
+const JITTER_FRACTION: f64 = 0.1;
+
+// Periodic node maintenance. Every job runs on its own tokio task; a failed run is logged and the job
+// keeps its schedule.
+pub struct Scheduler {
+    jobs: Vec<(&'static str, JoinHandle<()>)>,
+    stop: watch::Sender<bool>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler { jobs: Vec::new(), stop: watch::channel(false).0 }
+    }
+
+    // Runs `job` roughly every `period`, the first time one period after scheduling
+    pub fn schedule<F, Fut, E>(&mut self, name: &'static str, period: Duration, mut job: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: std::fmt::Display,
+    {
+        let mut stop = self.stop.subscribe();
+        let handle = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(jittered(period)) => {}
+                    _ = stop.changed() => break,
+                }
+                // Not raced against `stop`: a flush cut off halfway would leave its work half done
+                if let Err(e) = job().await {
+                    log::error!("Scheduled job {} failed: {}", name, e);
+                }
+            }
+        });
+        self.jobs.push((name, handle));
+    }
+
+    pub fn job_names(&self) -> Vec<&'static str> {
+        self.jobs.iter().map(|(name, _)| *name).collect()
+    }
+
+    // Stops every job, letting runs already under way finish first; callers do their own final
+    // flush afterwards
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(true);
+        for (name, handle) in self.jobs {
+            if let Err(e) = handle.await {
+                log::error!("Scheduled job {} ended abnormally: {}", name, e);
+            }
+        }
+    }
+}
+
+fn jittered(period: Duration) -> Duration {
+    let factor = rand::thread_rng().gen_range(1.0 - JITTER_FRACTION..=1.0 + JITTER_FRACTION);
+    period.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_jobs_repeat_until_shutdown() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule("count", Duration::from_secs(10), {
+            let runs = Arc::clone(&runs);
+            move || {
+                let runs = Arc::clone(&runs);
+                async move {
+                    // Failures don't stop the schedule
+                    match runs.fetch_add(1, Ordering::SeqCst) {
+                        0 => Err("first run fails"),
+                        _ => Ok(()),
+                    }
+                }
+            }
+        });
+        assert_eq!(scheduler.job_names(), vec!["count"]);
+
+        // Waits are at most 11 seconds with jitter
+        tokio::time::sleep(Duration::from_secs(100)).await;
+        scheduler.shutdown().await;
+        let after_shutdown = runs.load(Ordering::SeqCst);
+        assert!(after_shutdown >= 9);
+        tokio::time::sleep(Duration::from_secs(100)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), after_shutdown);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_lets_a_run_under_way_finish() {
+        let (started, finished) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule("flush", Duration::from_secs(10), {
+            let (started, finished) = (Arc::clone(&started), Arc::clone(&finished));
+            move || {
+                let (started, finished) = (Arc::clone(&started), Arc::clone(&finished));
+                async move {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_secs(30)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, std::convert::Infallible>(())
+                }
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(12)).await;
+        assert_eq!((started.load(Ordering::SeqCst), finished.load(Ordering::SeqCst)), (1, 0));
+        scheduler.shutdown().await;
+        assert_eq!((started.load(Ordering::SeqCst), finished.load(Ordering::SeqCst)), (1, 1));
+    }
+}
@@ -0,0 +1,109 @@
+use crate::lru_cache::LruCache;
+use crate::transaction::{Transaction, TransactionError, TxHash};
+use parking_lot::Mutex;
+
+// Inputs remembered as valid, a little more than a full mempool's worth
+pub const SCRIPT_CACHE_ENTRIES: usize = 200_000;
+// The rules inputs are checked under. It is part of every key, so bumping it when those rules
+// change leaves no input cached as valid under the old ones.
+pub const SCRIPT_VERIFY_FLAGS: u32 = 1;
+
+// (txid, input index, flags)
+type CacheKey = (TxHash, u32, u32);
+
+// Inputs whose signature or multisig witness already checked out. The txid covers the
+// signatures, so a cached input is byte for byte the one that was checked. Inputs checked on
+// mempool admission are cached there, and a block confirming those transactions connects without
+// checking them again.
+pub struct ScriptCache {
+    valid: Mutex<LruCache<CacheKey, ()>>,
+}
+
+impl ScriptCache {
+    pub fn new(entries: usize) -> Self {
+        ScriptCache { valid: Mutex::new(LruCache::new(entries)) }
+    }
+
+    pub fn contains(&self, txid: &TxHash, input: usize, flags: u32) -> bool {
+        self.valid.lock().get(&(*txid, input as u32, flags)).is_some()
+    }
+
+    // Checks every input of `tx` that isn't cached yet. With `store`, as on mempool admission,
+    // the inputs that pass are cached; block validation only looks inputs up, since a connected
+    // block's transactions aren't checked again.
+    pub fn verify(&self, tx: &Transaction, flags: u32, store: bool) -> Result<(), TransactionError> {
+        let txid = tx.hash();
+        let mut sighash = None;
+        for i in 0..tx.inputs.len() {
+            if self.contains(&txid, i, flags) {
+                continue;
+            }
+            // Only hashed once an input actually needs checking
+            let message = match sighash {
+                Some(message) => message,
+                None => *sighash.insert(tx.signature_hash()?),
+            };
+            tx.verify_input(i, &message)?;
+            if store {
+                self.valid.lock().insert((txid, i as u32, flags), (), 1);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{OutPoint, TxInput, TxOutput, SEQUENCE_FINAL};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signed(key: &SigningKey, inputs: u32) -> Transaction {
+        let mut tx = Transaction {
+            inputs: (0..inputs)
+                .map(|index| TxInput {
+                    previous_output: OutPoint { txid: [1; 32], index },
+                    public_key: key.verifying_key().to_bytes(),
+                    signature: Vec::new(),
+                    sequence: SEQUENCE_FINAL,
+                })
+                .collect(),
+            outputs: vec![TxOutput { value: 10, script_pubkey: vec![2; 32] }],
+            lock_time: 0,
+        };
+        let message = tx.signature_hash().unwrap();
+        for input in &mut tx.inputs {
+            input.signature = key.sign(&message).to_bytes().to_vec();
+        }
+        tx
+    }
+
+    #[test]
+    fn test_inputs_checked_on_admission_are_not_checked_again() {
+        let cache = ScriptCache::new(SCRIPT_CACHE_ENTRIES);
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let tx = signed(&key, 2);
+        let txid = tx.hash();
+
+        // Block validation reads the cache but doesn't fill it
+        cache.verify(&tx, SCRIPT_VERIFY_FLAGS, false).unwrap();
+        assert!(!cache.contains(&txid, 0, SCRIPT_VERIFY_FLAGS));
+        cache.verify(&tx, SCRIPT_VERIFY_FLAGS, true).unwrap();
+        assert!(cache.contains(&txid, 0, SCRIPT_VERIFY_FLAGS) && cache.contains(&txid, 1, SCRIPT_VERIFY_FLAGS));
+        // Entries only count under the rules they were checked with
+        assert!(!cache.contains(&txid, 0, SCRIPT_VERIFY_FLAGS + 1));
+
+        // A changed signature is a different txid, so the cache vouches for none of its inputs;
+        // the one still valid is cached under the new txid
+        let mut forged = tx.clone();
+        forged.inputs[1].signature[0] ^= 1;
+        assert!(matches!(cache.verify(&forged, SCRIPT_VERIFY_FLAGS, true), Err(TransactionError::InvalidSignature(1))));
+        assert!(cache.contains(&forged.hash(), 0, SCRIPT_VERIFY_FLAGS) && !cache.contains(&forged.hash(), 1, SCRIPT_VERIFY_FLAGS));
+
+        // Bounded, evicting the least recently used inputs first
+        let bound = ScriptCache::new(2);
+        bound.verify(&tx, SCRIPT_VERIFY_FLAGS, true).unwrap();
+        bound.verify(&signed(&SigningKey::from_bytes(&[8; 32]), 1), SCRIPT_VERIFY_FLAGS, true).unwrap();
+        assert!(!bound.contains(&txid, 0, SCRIPT_VERIFY_FLAGS) && bound.contains(&txid, 1, SCRIPT_VERIFY_FLAGS));
+    }
+}
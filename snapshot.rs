@@ -0,0 +1,526 @@
+use crate::blockchain::{calculate_merkle_root, Block, BlockHash, BlockHeader, FruitHeader};
+use crate::reward;
+use crate::transaction::{Coin, OutPoint, Transaction};
+use crate::utxo_set_hash::UtxoSetSummary;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// Directory under the datadir that snapshots served to peers are taken into
+pub const SNAPSHOTS_DIR_NAME: &str = "snapshots";
+// Encoded coins per chunk, so a chunk message stays well inside the wire payload cap
+pub const SNAPSHOT_CHUNK_BYTES: usize = 1024 * 1024;
+// Chunks a manifest may list: 16 GiB of coins, in half a megabyte of chunk hashes
+pub const MAX_SNAPSHOT_CHUNKS: usize = 16 * 1024;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Snapshot is of block {} at height {height}, not the one requested", hex::encode(.block_hash))]
+    WrongBlock { block_hash: BlockHash, height: u64 },
+    #[error("Snapshot lists {0} chunks, more than the {MAX_SNAPSHOT_CHUNKS} allowed")]
+    TooManyChunks(usize),
+    #[error("Manifest {} already failed to produce the committed set", hex::encode(.0))]
+    RejectedManifest([u8; 32]),
+    #[error("No manifest has been accepted yet")]
+    NoManifest,
+    #[error("Snapshot has no chunk {0}")]
+    UnknownChunk(u32),
+    #[error("Chunk {0} does not match its hash in the manifest")]
+    ChunkHashMismatch(u32),
+    #[error("Chunk {actual} was loaded out of turn; chunk {expected} is next")]
+    OutOfOrderChunk { expected: u32, actual: u32 },
+    #[error("Coins of chunk {0} are out of order or follow the previous chunk's")]
+    UnsortedCoins(u32),
+    #[error("{0} chunks are still missing")]
+    Incomplete(usize),
+    #[error("Snapshot holds {actual_coins} coins worth {actual_amount}, not the {coins} worth {total_amount} its manifest claims")]
+    TotalsMismatch { coins: u64, total_amount: u64, actual_coins: u64, actual_amount: u64 },
+    #[error("UTXO set hash {} does not match the committed {}", hex::encode(.actual), hex::encode(.expected))]
+    SetHashMismatch { expected: [u8; 32], actual: [u8; 32] },
+    #[error("Block {} is not the last of the headers or doesn't match its merkle root", hex::encode(.0))]
+    BadCommittingBlock(BlockHash),
+    #[error("Coinbase of block {} commits to no UTXO set", hex::encode(.0))]
+    NoCommitment(BlockHash),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Codec error: {0}")]
+    Codec(#[from] bincode::Error),
+}
+
+// What a syncing node trusts the snapshot to reproduce: the UTXO set hash at a block of the header
+// chain it follows, as committed to by the coinbase of the block after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotCommitment {
+    pub block_hash: BlockHash,
+    pub height: u64,
+    pub utxo_set_hash: [u8; 32],
+}
+
+impl SnapshotCommitment {
+    // What the coinbase of the block with `header` at `height` commits to once UTXO commitments are
+    // active: the set as its parent left it. The caller checks the coinbase belongs to the block,
+    // as `SnapshotSync::for_block` does with the whole block.
+    pub fn from_coinbase(header: &BlockHeader, height: u64, coinbase: &Transaction) -> Option<Self> {
+        Some(SnapshotCommitment {
+            block_hash: header.previous_hash,
+            height: height.checked_sub(1)?,
+            utxo_set_hash: reward::utxo_commitment(coinbase)?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotManifest {
+    pub block_hash: BlockHash,
+    pub height: u64,
+    pub coins: u64,
+    pub total_amount: u64,
+    pub chunk_hashes: Vec<[u8; 32]>,
+    // Fruits of the blocks up to the base, oldest first, as far back as fruit freshness and reward
+    // sharing look. The base block's header commits to its own and every earlier block's.
+    pub recent_fruits: Vec<Vec<FruitHeader>>,
+}
+
+impl SnapshotManifest {
+    pub fn hash(&self) -> [u8; 32] {
+        blake3::hash(&bincode::serialize(self).unwrap()).into()
+    }
+}
+
+// Coins in key order, txid then output index; chunks follow on from one another
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotChunk {
+    pub block_hash: BlockHash,
+    pub index: u32,
+    pub coins: Vec<(OutPoint, Coin)>,
+}
+
+impl SnapshotChunk {
+    pub fn hash(&self) -> [u8; 32] {
+        blake3::hash(&bincode::serialize(&self.coins).unwrap()).into()
+    }
+}
+
+// A snapshot served by the node that took it. The chunks were written to files in `dir` as the
+// set was read, so only the manifest is kept in memory.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub manifest: SnapshotManifest,
+    pub utxo_set_hash: [u8; 32],
+    dir: PathBuf,
+}
+
+impl Snapshot {
+    pub fn commitment(&self) -> SnapshotCommitment {
+        SnapshotCommitment { block_hash: self.manifest.block_hash, height: self.manifest.height, utxo_set_hash: self.utxo_set_hash }
+    }
+
+    pub fn chunk(&self, block_hash: &BlockHash, index: u32) -> Result<Option<SnapshotChunk>, SnapshotError> {
+        if *block_hash != self.manifest.block_hash || index as usize >= self.manifest.chunk_hashes.len() {
+            return Ok(None);
+        }
+        let bytes = std::fs::read(chunk_path(&self.dir, index))?;
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+
+    // Deletes the chunk files once the snapshot is no longer served
+    pub fn remove(self) -> Result<(), SnapshotError> {
+        Ok(std::fs::remove_dir_all(&self.dir)?)
+    }
+}
+
+fn chunk_path(dir: &Path, index: u32) -> PathBuf {
+    dir.join(format!("chunk_{:05}.dat", index))
+}
+
+// Cuts coins, pushed in key order, into chunks, writing each to `dir` once it is full
+pub struct SnapshotBuilder {
+    dir: PathBuf,
+    block_hash: BlockHash,
+    height: u64,
+    summary: UtxoSetSummary,
+    chunk_hashes: Vec<[u8; 32]>,
+    current: Vec<(OutPoint, Coin)>,
+    current_bytes: usize,
+}
+
+impl SnapshotBuilder {
+    pub fn new(dir: &Path, block_hash: BlockHash, height: u64) -> Result<Self, SnapshotError> {
+        std::fs::create_dir_all(dir)?;
+        Ok(SnapshotBuilder {
+            dir: dir.to_path_buf(),
+            block_hash,
+            height,
+            summary: UtxoSetSummary::default(),
+            chunk_hashes: Vec::new(),
+            current: Vec::new(),
+            current_bytes: 0,
+        })
+    }
+
+    pub fn push(&mut self, outpoint: OutPoint, coin: Coin) -> Result<(), SnapshotError> {
+        let size = bincode::serialized_size(&(outpoint, &coin))? as usize;
+        if !self.current.is_empty() && self.current_bytes + size > SNAPSHOT_CHUNK_BYTES {
+            self.cut()?;
+        }
+        self.summary.insert(&outpoint, &coin);
+        self.current.push((outpoint, coin));
+        self.current_bytes += size;
+        Ok(())
+    }
+
+    fn cut(&mut self) -> Result<(), SnapshotError> {
+        if self.chunk_hashes.len() == MAX_SNAPSHOT_CHUNKS {
+            return Err(SnapshotError::TooManyChunks(MAX_SNAPSHOT_CHUNKS + 1));
+        }
+        let chunk = SnapshotChunk { block_hash: self.block_hash, index: self.chunk_hashes.len() as u32, coins: std::mem::take(&mut self.current) };
+        std::fs::write(chunk_path(&self.dir, chunk.index), bincode::serialize(&chunk)?)?;
+        self.chunk_hashes.push(chunk.hash());
+        self.current_bytes = 0;
+        Ok(())
+    }
+
+    pub fn finish(mut self, recent_fruits: Vec<Vec<FruitHeader>>) -> Result<Snapshot, SnapshotError> {
+        if !self.current.is_empty() {
+            self.cut()?;
+        }
+        let manifest = SnapshotManifest {
+            block_hash: self.block_hash,
+            height: self.height,
+            coins: self.summary.coins,
+            total_amount: self.summary.total_amount,
+            chunk_hashes: self.chunk_hashes,
+            recent_fruits,
+        };
+        Ok(Snapshot { manifest, utxo_set_hash: self.summary.hash.digest(), dir: self.dir })
+    }
+}
+
+// How far loading a snapshot's chunks into the chainstate has got, saved with each chunk's coins
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct SnapshotLoadProgress {
+    pub next_chunk: u32,
+    last_key: Option<OutPoint>,
+}
+
+// A snapshot being loaded chunk by chunk, so only one chunk's coins are in memory at a time. Each
+// chunk has already been checked against the manifest; the coins are checked to run on in key
+// order across chunks, and the finished set against the commitment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotLoad {
+    pub manifest: SnapshotManifest,
+    pub progress: SnapshotLoadProgress,
+}
+
+impl SnapshotLoad {
+    pub fn new(manifest: SnapshotManifest) -> Self {
+        SnapshotLoad { manifest, progress: SnapshotLoadProgress::default() }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.progress.next_chunk as usize == self.manifest.chunk_hashes.len()
+    }
+
+    // Adds the coins of `chunk`, which must be the next one, to `summary`. On an error neither
+    // changes.
+    pub fn add_chunk(&mut self, chunk: &SnapshotChunk, summary: &mut UtxoSetSummary) -> Result<(), SnapshotError> {
+        if chunk.block_hash != self.manifest.block_hash {
+            return Err(SnapshotError::WrongBlock { block_hash: chunk.block_hash, height: self.manifest.height });
+        }
+        if chunk.index != self.progress.next_chunk {
+            return Err(SnapshotError::OutOfOrderChunk { expected: self.progress.next_chunk, actual: chunk.index });
+        }
+        // Strictly ascending keys rule out a coin counted twice
+        let mut last_key = self.progress.last_key;
+        for (outpoint, _) in &chunk.coins {
+            if last_key.is_some_and(|last| (last.txid, last.index) >= (outpoint.txid, outpoint.index)) {
+                return Err(SnapshotError::UnsortedCoins(chunk.index));
+            }
+            last_key = Some(*outpoint);
+        }
+        for (outpoint, coin) in &chunk.coins {
+            summary.insert(outpoint, coin);
+        }
+        self.progress = SnapshotLoadProgress { next_chunk: chunk.index + 1, last_key };
+        Ok(())
+    }
+
+    // Checks the finished set, as `summary` describes it, against the manifest's totals and then
+    // the commitment it was fetched for
+    pub fn check(&self, summary: &UtxoSetSummary, commitment: &SnapshotCommitment) -> Result<(), SnapshotError> {
+        let manifest = &self.manifest;
+        if manifest.block_hash != commitment.block_hash || manifest.height != commitment.height {
+            return Err(SnapshotError::WrongBlock { block_hash: manifest.block_hash, height: manifest.height });
+        }
+        if !self.is_complete() {
+            return Err(SnapshotError::Incomplete(manifest.chunk_hashes.len().saturating_sub(self.progress.next_chunk as usize)));
+        }
+        if (summary.coins, summary.total_amount) != (manifest.coins, manifest.total_amount) {
+            return Err(SnapshotError::TotalsMismatch {
+                coins: manifest.coins,
+                total_amount: manifest.total_amount,
+                actual_coins: summary.coins,
+                actual_amount: summary.total_amount,
+            });
+        }
+        let actual = summary.hash.digest();
+        if actual != commitment.utxo_set_hash {
+            return Err(SnapshotError::SetHashMismatch { expected: commitment.utxo_set_hash, actual });
+        }
+        Ok(())
+    }
+}
+
+// Client side of snapshot sync. The first manifest for the committed block is taken; each chunk is
+// checked against the manifest's hash for it as it arrives, so a peer sending a bad chunk is
+// caught at once and it can be fetched again elsewhere. Chunks are handed out in order for
+// loading one at a time. Only the finished set can be checked against the commitment; a manifest
+// that fails that check is rejected, and the next one offered is taken instead.
+pub struct SnapshotSync {
+    commitment: SnapshotCommitment,
+    manifest: Option<SnapshotManifest>,
+    // Chunks that came in ahead of `next_chunk`
+    chunks: HashMap<u32, SnapshotChunk>,
+    next_chunk: u32,
+    rejected: HashSet<[u8; 32]>,
+}
+
+impl SnapshotSync {
+    fn new(commitment: SnapshotCommitment) -> Self {
+        SnapshotSync { commitment, manifest: None, chunks: HashMap::new(), next_chunk: 0, rejected: HashSet::new() }
+    }
+
+    // Syncs to the set `block`'s coinbase commits to. `headers` lead from genesis to `block` and
+    // have been checked by the caller; the coinbase is tied to the last of them by the merkle root,
+    // so the commitment is as good as the header chain rather than the peer that sent it.
+    pub fn for_block(headers: &[BlockHeader], block: &Block) -> Result<Self, SnapshotError> {
+        let hash = block.hash();
+        if headers.last() != Some(&block.header) || calculate_merkle_root(&block.transactions) != block.header.merkle_root {
+            return Err(SnapshotError::BadCommittingBlock(hash));
+        }
+        let coinbase = block.transactions.first().filter(|tx| tx.is_coinbase()).ok_or(SnapshotError::NoCommitment(hash))?;
+        let commitment = SnapshotCommitment::from_coinbase(&block.header, headers.len() as u64 - 1, coinbase)
+            .ok_or(SnapshotError::NoCommitment(hash))?;
+        Ok(SnapshotSync::new(commitment))
+    }
+
+    pub fn commitment(&self) -> &SnapshotCommitment {
+        &self.commitment
+    }
+
+    pub fn manifest(&self) -> Option<&SnapshotManifest> {
+        self.manifest.as_ref()
+    }
+
+    pub fn has_manifest(&self) -> bool {
+        self.manifest.is_some()
+    }
+
+    // Carries on with the manifest of a load that was interrupted, from the chunk it stopped at
+    pub fn resume(&mut self, load: &SnapshotLoad) -> Result<(), SnapshotError> {
+        self.manifest = None;
+        self.chunks.clear();
+        self.accept_manifest(load.manifest.clone())?;
+        self.next_chunk = load.progress.next_chunk;
+        Ok(())
+    }
+
+    // Takes `manifest` if none has been yet; a later one is ignored
+    pub fn accept_manifest(&mut self, manifest: SnapshotManifest) -> Result<(), SnapshotError> {
+        if manifest.block_hash != self.commitment.block_hash || manifest.height != self.commitment.height {
+            return Err(SnapshotError::WrongBlock { block_hash: manifest.block_hash, height: manifest.height });
+        }
+        if manifest.chunk_hashes.len() > MAX_SNAPSHOT_CHUNKS {
+            return Err(SnapshotError::TooManyChunks(manifest.chunk_hashes.len()));
+        }
+        let hash = manifest.hash();
+        if self.rejected.contains(&hash) {
+            return Err(SnapshotError::RejectedManifest(hash));
+        }
+        self.manifest.get_or_insert(manifest);
+        Ok(())
+    }
+
+    // Chunks still to fetch, in order
+    pub fn missing_chunks(&self) -> Vec<u32> {
+        let count = self.manifest.as_ref().map_or(0, |manifest| manifest.chunk_hashes.len() as u32);
+        (self.next_chunk..count).filter(|index| !self.chunks.contains_key(index)).collect()
+    }
+
+    // Whether every chunk has been handed out for loading
+    pub fn is_complete(&self) -> bool {
+        self.manifest.as_ref().is_some_and(|manifest| self.next_chunk as usize == manifest.chunk_hashes.len())
+    }
+
+    pub fn accept_chunk(&mut self, chunk: SnapshotChunk) -> Result<(), SnapshotError> {
+        let manifest = self.manifest.as_ref().ok_or(SnapshotError::NoManifest)?;
+        if chunk.block_hash != manifest.block_hash {
+            return Err(SnapshotError::WrongBlock { block_hash: chunk.block_hash, height: manifest.height });
+        }
+        let expected = manifest.chunk_hashes.get(chunk.index as usize).ok_or(SnapshotError::UnknownChunk(chunk.index))?;
+        if chunk.hash() != *expected {
+            return Err(SnapshotError::ChunkHashMismatch(chunk.index));
+        }
+        if chunk.index >= self.next_chunk {
+            self.chunks.insert(chunk.index, chunk);
+        }
+        Ok(())
+    }
+
+    // The next chunk to load, once it has arrived
+    pub fn next_chunk(&mut self) -> Option<SnapshotChunk> {
+        let chunk = self.chunks.remove(&self.next_chunk)?;
+        self.next_chunk += 1;
+        Some(chunk)
+    }
+
+    // Drops the manifest after the set it describes failed its final check, so the sync starts
+    // over with the next manifest offered
+    pub fn reject_manifest(&mut self) {
+        if let Some(manifest) = self.manifest.take() {
+            self.rejected.insert(manifest.hash());
+        }
+        self.chunks.clear();
+        self.next_chunk = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockType;
+    use crate::transaction::TxOutput;
+    use tempfile::TempDir;
+
+    fn coins(count: u32) -> Vec<(OutPoint, Coin)> {
+        (0..count)
+            .map(|i| {
+                let mut txid = [0; 32];
+                txid[..4].copy_from_slice(&i.to_be_bytes());
+                let coin = Coin { output: TxOutput { value: i as u64, script_pubkey: vec![7; 200] }, height: 1, median_time_past: 0, is_coinbase: false };
+                (OutPoint { txid, index: 0 }, coin)
+            })
+            .collect()
+    }
+
+    fn snapshot(dir: &Path, coins: &[(OutPoint, Coin)]) -> Snapshot {
+        let mut builder = SnapshotBuilder::new(dir, [9; 32], 10).unwrap();
+        for (outpoint, coin) in coins {
+            builder.push(*outpoint, coin.clone()).unwrap();
+        }
+        builder.finish(Vec::new()).unwrap()
+    }
+
+    fn chunks(snapshot: &Snapshot) -> Vec<SnapshotChunk> {
+        (0..snapshot.manifest.chunk_hashes.len() as u32)
+            .map(|index| snapshot.chunk(&snapshot.manifest.block_hash, index).unwrap().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot_chunks_are_checked_against_manifest_and_commitment() {
+        let dir = TempDir::new().unwrap();
+        let coins = coins(8_000);
+        let served = snapshot(&dir.path().join("served"), &coins);
+        let served_chunks = chunks(&served);
+        assert!(served_chunks.len() > 1);
+        assert!(served_chunks.iter().all(|chunk| bincode::serialized_size(&chunk.coins).unwrap() as usize <= SNAPSHOT_CHUNK_BYTES + 8));
+        assert!(served.chunk(&[8; 32], 0).unwrap().is_none());
+
+        let mut sync = SnapshotSync::new(served.commitment());
+        assert!(matches!(sync.accept_chunk(served_chunks[0].clone()), Err(SnapshotError::NoManifest)));
+        let mut wrong = served.manifest.clone();
+        wrong.height += 1;
+        assert!(matches!(sync.accept_manifest(wrong), Err(SnapshotError::WrongBlock { .. })));
+        sync.accept_manifest(served.manifest.clone()).unwrap();
+
+        // A tampered chunk is refused and fetched again
+        let mut tampered = served_chunks[1].clone();
+        tampered.coins[0].1.output.value += 1;
+        assert!(matches!(sync.accept_chunk(tampered), Err(SnapshotError::ChunkHashMismatch(1))));
+
+        // Chunks arriving out of order are loaded in order, one at a time
+        let mut load = SnapshotLoad::new(served.manifest.clone());
+        let mut summary = UtxoSetSummary::default();
+        for chunk in served_chunks.iter().rev() {
+            sync.accept_chunk(chunk.clone()).unwrap();
+        }
+        let first = sync.next_chunk().unwrap();
+        assert!(matches!(load.add_chunk(&served_chunks[1], &mut summary), Err(SnapshotError::OutOfOrderChunk { expected: 0, actual: 1 })));
+        load.add_chunk(&first, &mut summary).unwrap();
+
+        // An interrupted load carries on from the chunk after the last one it saved
+        let mut resumed = SnapshotSync::new(served.commitment());
+        resumed.resume(&load).unwrap();
+        assert_eq!(resumed.missing_chunks(), (1..served_chunks.len() as u32).collect::<Vec<_>>());
+
+        while let Some(chunk) = sync.next_chunk() {
+            load.add_chunk(&chunk, &mut summary).unwrap();
+        }
+        assert!(sync.is_complete() && load.is_complete());
+        load.check(&summary, &served.commitment()).unwrap();
+        assert_eq!(summary.hash.digest(), served.utxo_set_hash);
+
+        // A manifest consistent with its own chunks but not the committed set fails at the end,
+        // and isn't taken again
+        let short = snapshot(&dir.path().join("short"), &coins[1..]);
+        let mut sync = SnapshotSync::new(served.commitment());
+        sync.accept_manifest(short.manifest.clone()).unwrap();
+        let mut load = SnapshotLoad::new(short.manifest.clone());
+        let mut summary = UtxoSetSummary::default();
+        for chunk in chunks(&short) {
+            sync.accept_chunk(chunk).unwrap();
+        }
+        while let Some(chunk) = sync.next_chunk() {
+            load.add_chunk(&chunk, &mut summary).unwrap();
+        }
+        assert!(matches!(load.check(&summary, &served.commitment()), Err(SnapshotError::SetHashMismatch { .. })));
+        sync.reject_manifest();
+        assert!(matches!(sync.accept_manifest(short.manifest.clone()), Err(SnapshotError::RejectedManifest(_))));
+        sync.accept_manifest(served.manifest.clone()).unwrap();
+        assert_eq!(sync.missing_chunks().len(), served_chunks.len());
+    }
+
+    #[test]
+    fn test_coins_must_run_on_in_key_order() {
+        let coins = coins(4);
+        let manifest = SnapshotManifest { block_hash: [9; 32], height: 10, coins: 4, total_amount: 6, chunk_hashes: vec![[0; 32]; 2], recent_fruits: Vec::new() };
+        let mut load = SnapshotLoad::new(manifest);
+        let mut summary = UtxoSetSummary::default();
+        load.add_chunk(&SnapshotChunk { block_hash: [9; 32], index: 0, coins: coins[2..].to_vec() }, &mut summary).unwrap();
+
+        // Coins from before the last chunk's are refused, leaving the load as it was
+        let behind = SnapshotChunk { block_hash: [9; 32], index: 1, coins: coins[..2].to_vec() };
+        assert!(matches!(load.add_chunk(&behind, &mut summary), Err(SnapshotError::UnsortedCoins(1))));
+        assert_eq!((load.progress.next_chunk, summary.coins), (1, 2));
+    }
+
+    #[test]
+    fn test_commitment_comes_from_the_committing_blocks_coinbase() {
+        let dir = TempDir::new().unwrap();
+        let served = snapshot(dir.path(), &coins(10));
+        let payout = TxOutput { value: 50, script_pubkey: vec![1; 32] };
+        let block_paying = |outputs: Vec<TxOutput>| {
+            let coinbase = Transaction { inputs: Vec::new(), outputs, lock_time: 0 };
+            let header = BlockHeader { version: 1, previous_hash: [9; 32], merkle_root: calculate_merkle_root(std::slice::from_ref(&coinbase)), fruits_root: [0; 32], timestamp: 0, bits: 0, nonce: 0 };
+            Block { header, block_type: BlockType::Block, fruit_header: None, fruits: Vec::new(), transactions: vec![coinbase] }
+        };
+        let block = block_paying(vec![payout.clone(), reward::utxo_commitment_output(&served.utxo_set_hash)]);
+        let headers = vec![block.header.clone(); 12];
+        assert_eq!(SnapshotSync::for_block(&headers, &block).unwrap().commitment(), &served.commitment());
+
+        // The block must end the header chain and carry the transactions its header commits to
+        let mut other = headers.clone();
+        other[11].nonce += 1;
+        assert!(matches!(SnapshotSync::for_block(&other, &block), Err(SnapshotError::BadCommittingBlock(hash)) if hash == block.hash()));
+        let mut tampered = block.clone();
+        tampered.transactions[0].outputs[1] = reward::utxo_commitment_output(&[0; 32]);
+        assert!(matches!(SnapshotSync::for_block(&headers, &tampered), Err(SnapshotError::BadCommittingBlock(hash)) if hash == block.hash()));
+
+        let uncommitted = block_paying(vec![payout]);
+        let headers = vec![uncommitted.header.clone(); 12];
+        assert!(matches!(SnapshotSync::for_block(&headers, &uncommitted), Err(SnapshotError::NoCommitment(hash)) if hash == uncommitted.hash()));
+    }
+}
@@ -0,0 +1,69 @@
+use crate::blockchain::Block;
+use crate::storage::{IndexOp, CF_SPENT_INDEX};
+use crate::transaction::{OutPoint, TxHash};
+
+// Optional explorer index from each spent outpoint to the input that spent it on the active chain:
+//   spent_index: txid, output index -> spending txid, input index, height
+const ENTRY_LEN: usize = 32 + 4 + 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpentInfo {
+    pub txid: TxHash,
+    pub input_index: u32,
+    pub height: u64,
+}
+
+impl SpentInfo {
+    fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(ENTRY_LEN);
+        value.extend_from_slice(&self.txid);
+        value.extend_from_slice(&self.input_index.to_be_bytes());
+        value.extend_from_slice(&self.height.to_be_bytes());
+        value
+    }
+
+    pub fn decode(value: &[u8]) -> Option<Self> {
+        if value.len() != ENTRY_LEN {
+            return None;
+        }
+        Some(SpentInfo {
+            txid: value[..32].try_into().ok()?,
+            input_index: u32::from_be_bytes(value[32..36].try_into().ok()?),
+            height: u64::from_be_bytes(value[36..].try_into().ok()?),
+        })
+    }
+}
+
+pub fn key(outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = Vec::with_capacity(32 + 4);
+    key.extend_from_slice(&outpoint.txid);
+    key.extend_from_slice(&outpoint.index.to_be_bytes());
+    key
+}
+
+// Index writes for connecting (`connect`) or disconnecting the block at `height`. Each outpoint is
+// spent at most once on the active chain, so disconnecting only has to delete what connecting wrote.
+pub fn block_changes(block: &Block, height: u64, connect: bool) -> Vec<IndexOp> {
+    let mut ops = Vec::new();
+    for tx in &block.transactions {
+        let txid = tx.hash();
+        for (input_index, input) in tx.inputs.iter().enumerate() {
+            let info = SpentInfo { txid, input_index: input_index as u32, height };
+            ops.push((CF_SPENT_INDEX, key(&input.previous_output), connect.then(|| info.encode())));
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spent_info_round_trip() {
+        let info = SpentInfo { txid: [3; 32], input_index: 2, height: 1_000_000 };
+        assert_eq!(SpentInfo::decode(&info.encode()), Some(info));
+        assert_eq!(SpentInfo::decode(&[0; 12]), None);
+        assert_eq!(key(&OutPoint { txid: [1; 32], index: 258 })[32..], [0, 0, 1, 2]);
+    }
+}
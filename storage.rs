@@ -1,49 +1,290 @@
-Certainly! I'll provide a complete `storage.rs` file that implements the `Storage` struct we've been referencing in our `main.rs`. This implementation will use RocksDB for storing block locations, with LZ4 compression enabled.
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{BlockBasedOptions, Cache, DB, ErrorKind, DBCompactionStyle, Direction, IteratorMode, Options, ColumnFamilyDescriptor, ReadOptions, SliceTransform, WriteBatch};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_stream::{Stream, wrappers::ReceiverStream};
+use serde::{Serialize, Deserialize};
+use crate::block_filter::{BlockFilter, FilterHeader};
+use crate::block_stats::BlockStats;
+use crate::blockchain::{BlockHeader, FruitHeader, LegacyBlockHeader};
+use crate::node_config::{CompactionStyle, DatabaseConfig, DatabaseProfile};
+use crate::transaction::{Coin, OutPoint};
+use crate::utxo_set_hash::UtxoSetSummary;
 
+pub const CF_BLOCK_LOCATIONS: &str = "default";
+pub const CF_UTXO: &str = "utxo";
+pub const CF_HEIGHT_INDEX: &str = "height_index";
+pub const CF_BLOCK_HEIGHTS: &str = "block_heights";
+pub const CF_FRUIT_INDEX: &str = "fruit_index";
+pub const CF_HEADERS: &str = "headers";
+pub const CF_BLOCK_FILTERS: &str = "block_filters";
+// Cached `getblockstats` results, filled on first request
+pub const CF_BLOCK_STATS: &str = "block_stats";
+pub const CF_UNDO_LOCATIONS: &str = "undo_locations";
+pub const CF_META: &str = "meta";
+// Optional explorer indexes, empty unless enabled in the config
+pub const CF_ADDRESS_INDEX: &str = "address_index";
+pub const CF_ADDRESS_UTXOS: &str = "address_utxos";
+pub const CF_SPENT_INDEX: &str = "spent_index";
 
+const COLUMN_FAMILIES: &[&str] = &[
+    CF_BLOCK_LOCATIONS, CF_UTXO, CF_HEIGHT_INDEX, CF_BLOCK_HEIGHTS, CF_FRUIT_INDEX, CF_HEADERS, CF_BLOCK_FILTERS, CF_BLOCK_STATS,
+    CF_UNDO_LOCATIONS, CF_META, CF_ADDRESS_INDEX, CF_ADDRESS_UTXOS, CF_SPENT_INDEX,
+];
 
-```rust
-// storage.rs
+// Column families read by key rather than scanned; only these get bloom filters
+const POINT_LOOKUP_CFS: &[&str] = &[
+    CF_BLOCK_LOCATIONS, CF_UTXO, CF_BLOCK_HEIGHTS, CF_FRUIT_INDEX, CF_HEADERS, CF_BLOCK_FILTERS, CF_BLOCK_STATS,
+    CF_UNDO_LOCATIONS, CF_SPENT_INDEX,
+];
 
-use rocksdb::{DB, Options, ColumnFamilyDescriptor, SliceTransform};
-use std::sync::Arc;
-use tokio::task;
-use serde::{Serialize, Deserialize};
+// Layout of the stored data; 1 is the first with version-prefixed block encoding, 2 the first
+// whose header index entries carry the header version
+pub const SCHEMA_VERSION: u32 = 2;
+pub const META_SCHEMA_VERSION: &[u8] = b"schema_version";
+// Height up to which an interrupted format migration has already rewritten blocks
+pub const META_MIGRATION_HEIGHT: &[u8] = b"migration_height";
+// Coin count, total amount and set hash of the UTXO column family, written in the same batch as it
+pub const META_UTXO_SET_SUMMARY: &[u8] = b"utxo_set_summary";
+// Block the UTXO column family was last flushed at, also written with it. The height index runs
+// ahead of unflushed coins, so read-only nodes follow this instead.
+pub const META_UTXO_BEST_BLOCK: &[u8] = b"utxo_best_block";
+// Hashes of the blocks the optional indexes are up to date with
+pub const META_ADDRESS_INDEX_TIP: &[u8] = b"address_index_tip";
+pub const META_SPENT_INDEX_TIP: &[u8] = b"spent_index_tip";
+// Tip the chain had when a reindex started, kept until the reindex has reconnected up to it
+pub const META_REINDEX_TARGET: &[u8] = b"reindex_target";
+// Blocks an operator marked invalid, their 32-byte hashes one after another
+pub const META_INVALID_BLOCKS: &[u8] = b"invalid_blocks";
+// Hash of the block a UTXO snapshot was loaded at; blocks below it were never downloaded
+pub const META_SNAPSHOT_BASE: &[u8] = b"snapshot_base";
+// Manifest of a snapshot whose coins are being loaded, and how far the load has got
+pub const META_SNAPSHOT_MANIFEST: &[u8] = b"snapshot_manifest";
+pub const META_SNAPSHOT_PROGRESS: &[u8] = b"snapshot_progress";
+// Bincode (height, header) entries written before headers had a version field
+const LEGACY_HEADER_ENTRY_LEN: usize = 8 + 3 * 32 + 8 + 4 + 8;
+
+// How many key/value pairs a scan may buffer ahead of its consumer
+const SCAN_CHANNEL_CAPACITY: usize = 256;
+
+pub type KeyValue = (Box<[u8]>, Box<[u8]>);
+// A put, or a delete when the value is None, in one of the column families
+pub type IndexOp = (&'static str, Vec<u8>, Option<Vec<u8>>);
+pub type ScanError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Clone)]
 pub struct Storage {
     db: Arc<DB>,
+    // Opening needed `DB::repair`, so recent writes may have been lost
+    repaired: bool,
+    // Removed once the last handle to a secondary is dropped
+    _secondary_dir: Option<Arc<SecondaryDir>>,
+}
+
+struct SecondaryDir(PathBuf);
+
+impl Drop for SecondaryDir {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.0) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Could not remove secondary database directory {}: {}", self.0.display(), e);
+            }
+            _ => {}
+        }
+    }
+}
+
+// RocksDB options after applying the config's overrides to its profile
+#[derive(Debug, Clone, PartialEq)]
+struct Tuning {
+    block_cache_mb: usize,
+    write_buffer_mb: usize,
+    max_open_files: i32,
+    bloom_filter_bits: f64,
+    compaction_style: CompactionStyle,
+    // Index and filter blocks are charged to the block cache instead of held outside it
+    cache_index_and_filter_blocks: bool,
+    compaction_readahead_mb: usize,
+}
+
+impl Tuning {
+    fn resolve(config: &DatabaseConfig) -> Self {
+        let preset = match config.profile {
+            DatabaseProfile::Ssd => Tuning {
+                block_cache_mb: 256,
+                write_buffer_mb: 64,
+                max_open_files: -1,
+                bloom_filter_bits: 10.0,
+                compaction_style: CompactionStyle::Level,
+                cache_index_and_filter_blocks: false,
+                compaction_readahead_mb: 0,
+            },
+            DatabaseProfile::SpinningDisk => Tuning {
+                block_cache_mb: 512,
+                write_buffer_mb: 128,
+                max_open_files: -1,
+                bloom_filter_bits: 10.0,
+                compaction_style: CompactionStyle::Universal,
+                cache_index_and_filter_blocks: false,
+                // Large sequential reads keep compaction from seeking back and forth
+                compaction_readahead_mb: 2,
+            },
+            DatabaseProfile::LowMemory => Tuning {
+                block_cache_mb: 16,
+                write_buffer_mb: 8,
+                max_open_files: 256,
+                bloom_filter_bits: 10.0,
+                compaction_style: CompactionStyle::Level,
+                cache_index_and_filter_blocks: true,
+                compaction_readahead_mb: 0,
+            },
+        };
+        Tuning {
+            block_cache_mb: config.block_cache_mb.unwrap_or(preset.block_cache_mb),
+            write_buffer_mb: config.write_buffer_mb.unwrap_or(preset.write_buffer_mb),
+            max_open_files: config.max_open_files.unwrap_or(preset.max_open_files),
+            bloom_filter_bits: config.bloom_filter_bits.unwrap_or(preset.bloom_filter_bits),
+            compaction_style: config.compaction_style.unwrap_or(preset.compaction_style),
+            ..preset
+        }
+    }
+
+    // Options shared by the database and every column family; `cache` is shared between them
+    fn cf_options(&self, name: &str, cache: &Cache) -> Options {
+        let mut table = BlockBasedOptions::default();
+        table.set_block_cache(cache);
+        table.set_cache_index_and_filter_blocks(self.cache_index_and_filter_blocks);
+        if self.bloom_filter_bits > 0.0 && POINT_LOOKUP_CFS.contains(&name) {
+            table.set_bloom_filter(self.bloom_filter_bits, false);
+        }
+
+        let mut opts = Options::default();
+        opts.set_block_based_table_factory(&table);
+        opts.set_write_buffer_size(self.write_buffer_mb * 1024 * 1024);
+        opts.set_compaction_style(match self.compaction_style {
+            CompactionStyle::Level => DBCompactionStyle::Level,
+            CompactionStyle::Universal => DBCompactionStyle::Universal,
+        });
+        opts.set_compression_type(rocksdb::DBCompressionType::Lz4);
+        opts.set_bottommost_compression_type(rocksdb::DBCompressionType::Lz4);
+        opts
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ColumnFamilyStats {
+    pub name: &'static str,
+    pub estimated_keys: u64,
+    pub total_sst_bytes: u64,
+    // SST files still referenced by the current version, excluding ones awaiting deletion
+    pub live_sst_bytes: u64,
+    pub memtable_bytes: u64,
+    pub pending_compaction_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct DatabaseStats {
+    pub column_families: Vec<ColumnFamilyStats>,
+    // The block cache is shared by every column family
+    pub block_cache_usage_bytes: u64,
+    pub block_cache_capacity_bytes: u64,
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    pub block_cache_hit_rate: f64,
+    pub bloom_filter_useful: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct BlockLocation {
     pub file_name: String,
     pub byte_offset: u64,
 }
 
+// Whether opening failed because the files are damaged, as opposed to e.g. the database being locked
+pub fn is_corruption(error: &(dyn std::error::Error + 'static)) -> bool {
+    error.downcast_ref::<rocksdb::Error>().is_some_and(|e| e.kind() == ErrorKind::Corruption)
+}
+
 impl Storage {
-    pub async fn new(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let path = path.to_owned();
+    // Follows the database of a node that has it open for writing. Writes fail; `catch_up` picks up
+    // what the primary has written since. RocksDB keeps the secondary's own logs under `secondary_path`,
+    // which is emptied first and removed when the storage is dropped.
+    pub async fn open_secondary(path: &str, secondary_path: &Path, config: &DatabaseConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = PathBuf::from(path);
+        // Left behind by a process that didn't exit cleanly
+        if secondary_path.exists() {
+            std::fs::remove_dir_all(secondary_path)?;
+        }
+        std::fs::create_dir_all(secondary_path)?;
+        let secondary_dir = Arc::new(SecondaryDir(secondary_path.to_owned()));
+        let secondary_path = secondary_path.to_owned();
+        let tuning = Tuning::resolve(config);
         let db = task::spawn_blocking(move || {
-            let mut opts = Options::default();
+            let cache = Cache::new_lru_cache(tuning.block_cache_mb * 1024 * 1024);
+            let mut opts = tuning.cf_options(CF_BLOCK_LOCATIONS, &cache);
+            // Secondaries must keep every table file open to follow the primary
+            opts.set_max_open_files(-1);
+            opts.enable_statistics();
+            DB::open_cf_as_secondary(&opts, path, secondary_path, COLUMN_FAMILIES)
+        })
+        .await??;
+
+        Ok(Self { db: Arc::new(db), repaired: false, _secondary_dir: Some(secondary_dir) })
+    }
+
+    pub async fn catch_up(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || db.try_catch_up_with_primary()).await??;
+        Ok(())
+    }
+
+    pub async fn new(path: &str, config: &DatabaseConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.to_owned();
+        let tuning = Tuning::resolve(config);
+        let (db, repaired) = task::spawn_blocking(move || {
+            let cache = Cache::new_lru_cache(tuning.block_cache_mb * 1024 * 1024);
+            let mut opts = tuning.cf_options(CF_BLOCK_LOCATIONS, &cache);
             opts.create_if_missing(true);
-            opts.set_compression_type(rocksdb::DBCompressionType::Lz4);
-            opts.set_bottommost_compression_type(rocksdb::DBCompressionType::Lz4);
-            
+            opts.create_missing_column_families(true);
+            // Ticker counters behind the cache hit rates in `get_statistics`
+            opts.enable_statistics();
+            opts.set_max_open_files(tuning.max_open_files);
+            if tuning.compaction_readahead_mb > 0 {
+                opts.set_compaction_readahead_size(tuning.compaction_readahead_mb * 1024 * 1024);
+            }
+
             // Optimize for point lookups
             opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(32)); // Assuming 32-byte block hashes
 
-            let cf_opts = Options::default();
-            let cf = ColumnFamilyDescriptor::new("default", cf_opts);
+            let cfs = || COLUMN_FAMILIES.iter()
+                .map(|name| ColumnFamilyDescriptor::new(*name, tuning.cf_options(name, &cache)))
+                .collect::<Vec<_>>();
 
-            DB::open_cf_descriptors(&opts, path, vec![cf])
+            match DB::open_cf_descriptors(&opts, &path, cfs()) {
+                // Repair salvages what it can from the table files and rewrites the manifest
+                Err(e) if e.kind() == ErrorKind::Corruption => {
+                    log::warn!("Database at {} is corrupted ({}), attempting repair", path, e);
+                    DB::repair(&opts, &path)?;
+                    let db = DB::open_cf_descriptors(&opts, &path, cfs())?;
+                    log::warn!("Database at {} repaired; recently written data may be missing", path);
+                    Ok((db, true))
+                }
+                result => result.map(|db| (db, false)),
+            }
         })
         .await??;
 
-        Ok(Self { db: Arc::new(db) })
+        Ok(Self { db: Arc::new(db), repaired, _secondary_dir: None })
+    }
+
+    pub fn was_repaired(&self) -> bool {
+        self.repaired
     }
 
     pub async fn store_block_location(&self, block_hash: &[u8], location: &BlockLocation) -> Result<(), Box<dyn std::error::Error>> {
+        let block_hash = block_hash.to_vec();
         let db = Arc::clone(&self.db);
         let location_bytes = bincode::serialize(location)?;
         task::spawn_blocking(move || {
@@ -54,6 +295,7 @@ impl Storage {
     }
 
     pub async fn retrieve_block_location(&self, block_hash: &[u8]) -> Result<Option<BlockLocation>, Box<dyn std::error::Error>> {
+        let block_hash = block_hash.to_vec();
         let db = Arc::clone(&self.db);
         let result = task::spawn_blocking(move || {
             db.get(block_hash)
@@ -67,6 +309,7 @@ impl Storage {
     }
 
     pub async fn delete_block_location(&self, block_hash: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let block_hash = block_hash.to_vec();
         let db = Arc::clone(&self.db);
         task::spawn_blocking(move || {
             db.delete(block_hash)
@@ -75,15 +318,674 @@ impl Storage {
         .map_err(|e| e.into())
     }
 
-    pub async fn get_latest_block_hash(&self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    pub async fn get_meta(&self, key: &'static [u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let value = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_META).expect("meta column family is always opened");
+            db.get_cf(cf, key)
+        })
+        .await??;
+        Ok(value)
+    }
+
+    pub async fn put_meta(&self, key: &'static [u8], value: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
         let db = Arc::clone(&self.db);
         task::spawn_blocking(move || {
-            let mut iter = db.iterator(rocksdb::IteratorMode::End);
-            iter.next().map(|(key, _)| key.to_vec())
+            let cf = db.cf_handle(CF_META).expect("meta column family is always opened");
+            db.put_cf(cf, key, value)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn delete_meta(&self, key: &'static [u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_META).expect("meta column family is always opened");
+            db.delete_cf(cf, key)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_schema_version(&self) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+        match self.get_meta(META_SCHEMA_VERSION).await? {
+            Some(bytes) => Ok(Some(u32::from_be_bytes(bytes.as_slice().try_into()?))),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_schema_version(&self, version: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.put_meta(META_SCHEMA_VERSION, version.to_be_bytes().to_vec()).await
+    }
+
+    // Moves a block to its rewritten location and records migration progress in one atomic write
+    pub async fn store_migrated_block_location(&self, block_hash: &[u8], location: &BlockLocation, height: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = block_hash.to_vec();
+        let value = bincode::serialize(location)?;
+        task::spawn_blocking(move || {
+            let mut batch = WriteBatch::default();
+            batch.put(key, value);
+            let cf = db.cf_handle(CF_META).expect("meta column family is always opened");
+            batch.put_cf(cf, META_MIGRATION_HEIGHT, height.to_be_bytes());
+            db.write(batch)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    // Where a block's undo record sits in the rev files, keyed by block hash
+    pub async fn store_undo_location(&self, block_hash: &[u8; 32], location: &BlockLocation) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let value = bincode::serialize(location)?;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_UNDO_LOCATIONS).expect("undo locations column family is always opened");
+            db.put_cf(cf, key, value)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_undo_location(&self, block_hash: &[u8; 32]) -> Result<Option<BlockLocation>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let result = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_UNDO_LOCATIONS).expect("undo locations column family is always opened");
+            db.get_cf(cf, key)
+        })
+        .await??;
+
+        match result {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_undo_location(&self, block_hash: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_UNDO_LOCATIONS).expect("undo locations column family is always opened");
+            db.delete_cf(cf, key)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<Coin>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = utxo_key(outpoint);
+        let result = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_UTXO).expect("utxo column family is always opened");
+            db.get_cf(cf, key)
+        })
+        .await??;
+
+        match result {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_utxo_set_summary(&self) -> Result<Option<UtxoSetSummary>, Box<dyn std::error::Error>> {
+        match self.get_meta(META_UTXO_SET_SUMMARY).await? {
+            Some(bytes) => Ok(Some(UtxoSetSummary::from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_utxo_best_block(&self) -> Result<Option<[u8; 32]>, Box<dyn std::error::Error>> {
+        match self.get_meta(META_UTXO_BEST_BLOCK).await? {
+            Some(bytes) => Ok(Some(decode_hash(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Hashes the whole UTXO column family, for databases written before the summary was kept
+    pub async fn summarize_utxo_set(&self) -> Result<UtxoSetSummary, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || -> Result<UtxoSetSummary, Box<dyn std::error::Error + Send + Sync>> {
+            let cf = db.cf_handle(CF_UTXO).expect("utxo column family is always opened");
+            let mut summary = UtxoSetSummary::default();
+            for item in db.iterator_cf(cf, IteratorMode::Start) {
+                let (key, value) = item?;
+                let outpoint = OutPoint {
+                    txid: key[..32].try_into()?,
+                    index: u32::from_be_bytes(key[32..].try_into()?),
+                };
+                summary.insert(&outpoint, &bincode::deserialize(&value)?);
+            }
+            Ok(summary)
+        })
+        .await?
+        .map_err(|e| e as Box<dyn std::error::Error>)
+    }
+
+    // Applies all changes, the summary of the resulting set and the block it is as of, when known, in
+    // a single atomic WriteBatch; `None` deletes the entry
+    pub async fn write_utxo_batch(&self, changes: Vec<(OutPoint, Option<Coin>)>, summary: &UtxoSetSummary, best_block: Option<[u8; 32]>) -> Result<(), Box<dyn std::error::Error>> {
+        let meta = best_block.map(|hash| (META_UTXO_BEST_BLOCK, hash.to_vec())).into_iter().collect();
+        self.write_utxo_changes(changes, summary, meta).await
+    }
+
+    // Adds a snapshot chunk's coins along with the load's progress, so a load interrupted between
+    // chunks carries on from a set that matches it
+    pub async fn write_snapshot_coins(&self, coins: Vec<(OutPoint, Coin)>, summary: &UtxoSetSummary, progress: Vec<(&'static [u8], Vec<u8>)>) -> Result<(), Box<dyn std::error::Error>> {
+        let changes = coins.into_iter().map(|(outpoint, coin)| (outpoint, Some(coin))).collect();
+        self.write_utxo_changes(changes, summary, progress).await
+    }
+
+    async fn write_utxo_changes(&self, changes: Vec<(OutPoint, Option<Coin>)>, summary: &UtxoSetSummary, meta_entries: Vec<(&'static [u8], Vec<u8>)>) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let mut encoded = Vec::with_capacity(changes.len());
+        for (outpoint, coin) in changes {
+            let value = match coin {
+                Some(coin) => Some(bincode::serialize(&coin)?),
+                None => None,
+            };
+            encoded.push((utxo_key(&outpoint), value));
+        }
+        let summary = summary.to_bytes();
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_UTXO).expect("utxo column family is always opened");
+            let mut batch = WriteBatch::default();
+            for (key, value) in encoded {
+                match value {
+                    Some(value) => batch.put_cf(cf, key, value),
+                    None => batch.delete_cf(cf, key),
+                }
+            }
+            let meta = db.cf_handle(CF_META).expect("meta column family is always opened");
+            batch.put_cf(meta, META_UTXO_SET_SUMMARY, summary);
+            for (key, value) in meta_entries {
+                batch.put_cf(meta, key, value);
+            }
+            db.write(batch)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_index_entry(&self, cf: &'static str, key: Vec<u8>) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let value = task::spawn_blocking(move || {
+            let handle = db.cf_handle(cf).expect("column family is always opened");
+            db.get_cf(handle, key)
+        })
+        .await??;
+        Ok(value)
+    }
+
+    // Applies index changes, and any meta entries recording how far they reach, in one WriteBatch
+    pub async fn write_index_batch(&self, ops: Vec<IndexOp>) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || {
+            let mut batch = WriteBatch::default();
+            for (cf, key, value) in ops {
+                let handle = db.cf_handle(cf).expect("column family is always opened");
+                match value {
+                    Some(value) => batch.put_cf(handle, key, value),
+                    None => batch.delete_cf(handle, key),
+                }
+            }
+            db.write(batch)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    // Deletes every entry in `cf` in bounded batches, used when rebuilding derived state
+    pub async fn clear_cf(&self, cf: &'static str) -> Result<(), Box<dyn std::error::Error>> {
+        const CLEAR_BATCH_SIZE: usize = 10_000;
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || -> Result<(), rocksdb::Error> {
+            let handle = db.cf_handle(cf).expect("column family is always opened");
+            loop {
+                let keys = db.iterator_cf(handle, IteratorMode::Start)
+                    .take(CLEAR_BATCH_SIZE)
+                    .map(|item| item.map(|(key, _)| key))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if keys.is_empty() {
+                    return Ok(());
+                }
+                let mut batch = WriteBatch::default();
+                for key in keys {
+                    batch.delete_cf(handle, key);
+                }
+                db.write(batch)?;
+            }
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    // Compacts all of `cf`, dropping deleted and overwritten entries. Blocks until it finishes.
+    pub async fn compact_range(&self, cf: &'static str) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || {
+            let handle = db.cf_handle(cf).expect("column family is always opened");
+            db.compact_range_cf(handle, None::<&[u8]>, None::<&[u8]>);
+        })
+        .await?;
+        Ok(())
+    }
+
+    // Consistent copy of the database at `path` while it stays open for writing. Table files are hard
+    // linked when `path` is on the same filesystem, so this is cheap; `path` must not exist yet.
+    pub async fn create_checkpoint(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let path = path.to_owned();
+        task::spawn_blocking(move || Checkpoint::new(&db)?.create_checkpoint(path)).await??;
+        Ok(())
+    }
+
+    pub async fn get_statistics(&self) -> Result<DatabaseStats, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || -> Result<DatabaseStats, rocksdb::Error> {
+            let mut stats = DatabaseStats::default();
+            for name in COLUMN_FAMILIES {
+                let handle = db.cf_handle(name).expect("column family is always opened");
+                let property = |key: &str| db.property_int_value_cf(handle, key).map(Option::unwrap_or_default);
+                stats.column_families.push(ColumnFamilyStats {
+                    name,
+                    estimated_keys: property("rocksdb.estimate-num-keys")?,
+                    total_sst_bytes: property("rocksdb.total-sst-files-size")?,
+                    live_sst_bytes: property("rocksdb.live-sst-files-size")?,
+                    memtable_bytes: property("rocksdb.cur-size-all-mem-tables")?,
+                    pending_compaction_bytes: property("rocksdb.estimate-pending-compaction-bytes")?,
+                });
+            }
+            stats.block_cache_usage_bytes = db.property_int_value("rocksdb.block-cache-usage")?.unwrap_or_default();
+            stats.block_cache_capacity_bytes = db.property_int_value("rocksdb.block-cache-capacity")?.unwrap_or_default();
+
+            let tickers = db.property_value("rocksdb.options-statistics")?.unwrap_or_default();
+            stats.block_cache_hits = ticker_count(&tickers, "rocksdb.block.cache.hit");
+            stats.block_cache_misses = ticker_count(&tickers, "rocksdb.block.cache.miss");
+            stats.bloom_filter_useful = ticker_count(&tickers, "rocksdb.bloom.filter.useful");
+            let lookups = stats.block_cache_hits + stats.block_cache_misses;
+            if lookups > 0 {
+                stats.block_cache_hit_rate = stats.block_cache_hits as f64 / lookups as f64;
+            }
+            Ok(stats)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn store_height_hash(&self, height: u64, block_hash: [u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEIGHT_INDEX).expect("height index column family is always opened");
+            db.put_cf(cf, height.to_be_bytes(), block_hash)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_hash_at_height(&self, height: u64) -> Result<Option<[u8; 32]>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let result = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEIGHT_INDEX).expect("height index column family is always opened");
+            db.get_cf(cf, height.to_be_bytes())
+        })
+        .await??;
+
+        result.map(|bytes| decode_hash(&bytes)).transpose()
+    }
+
+    pub async fn delete_height_hash(&self, height: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEIGHT_INDEX).expect("height index column family is always opened");
+            db.delete_cf(cf, height.to_be_bytes())
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn store_block_height(&self, block_hash: &[u8; 32], height: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_HEIGHTS).expect("block heights column family is always opened");
+            db.put_cf(cf, key, height.to_be_bytes())
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_block_height(&self, block_hash: &[u8; 32]) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let result = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_HEIGHTS).expect("block heights column family is always opened");
+            db.get_cf(cf, key)
+        })
+        .await??;
+
+        match result {
+            Some(bytes) => Ok(Some(u64::from_be_bytes(bytes.as_slice().try_into()?))),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_block_height(&self, block_hash: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_HEIGHTS).expect("block heights column family is always opened");
+            db.delete_cf(cf, key)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    // Fruits included by a connected block, keyed by the including block's hash
+    pub async fn store_block_fruits(&self, block_hash: &[u8; 32], fruits: &[FruitHeader]) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let value = bincode::serialize(fruits)?;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_FRUIT_INDEX).expect("fruit index column family is always opened");
+            db.put_cf(cf, key, value)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_block_fruits(&self, block_hash: &[u8; 32]) -> Result<Option<Vec<FruitHeader>>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let result = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_FRUIT_INDEX).expect("fruit index column family is always opened");
+            db.get_cf(cf, key)
+        })
+        .await??;
+
+        match result {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_block_fruits(&self, block_hash: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_FRUIT_INDEX).expect("fruit index column family is always opened");
+            db.delete_cf(cf, key)
         })
-        .await
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    // Compact filter and filter header of a connected block, keyed by the block hash
+    pub async fn store_block_filter(&self, block_hash: &[u8; 32], filter: &BlockFilter, header: &FilterHeader) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let value = bincode::serialize(&(filter, header))?;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_FILTERS).expect("block filters column family is always opened");
+            db.put_cf(cf, key, value)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_block_filter(&self, block_hash: &[u8; 32]) -> Result<Option<(BlockFilter, FilterHeader)>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let result = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_FILTERS).expect("block filters column family is always opened");
+            db.get_cf(cf, key)
+        })
+        .await??;
+
+        match result {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_block_filter(&self, block_hash: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_FILTERS).expect("block filters column family is always opened");
+            db.delete_cf(cf, key)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn store_block_stats(&self, stats: &BlockStats) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = stats.block_hash;
+        let value = bincode::serialize(stats)?;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_STATS).expect("block stats column family is always opened");
+            db.put_cf(cf, key, value)
+        })
+        .await?
         .map_err(|e| e.into())
     }
+
+    pub async fn get_block_stats(&self, block_hash: &[u8; 32]) -> Result<Option<BlockStats>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let result = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_STATS).expect("block stats column family is always opened");
+            db.get_cf(cf, key)
+        })
+        .await??;
+
+        match result {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_block_stats(&self, block_hash: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_BLOCK_STATS).expect("block stats column family is always opened");
+            db.delete_cf(cf, key)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    // Header index keyed by block hash. Light nodes keep only this; full nodes also write every
+    // connected block and every header accepted from peers here, forks included, so headers
+    // outlive pruned block files and the branches we know of survive a restart.
+    pub async fn store_header(&self, block_hash: &[u8; 32], height: u64, header: &BlockHeader) -> Result<(), Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let value = bincode::serialize(&(height, header))?;
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEADERS).expect("headers column family is always opened");
+            db.put_cf(cf, key, value)
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+
+    pub async fn get_header(&self, block_hash: &[u8; 32]) -> Result<Option<(u64, BlockHeader)>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let key = *block_hash;
+        let value = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEADERS).expect("headers column family is always opened");
+            db.get_cf(cf, key)
+        })
+        .await??;
+        Ok(value.map(|value| bincode::deserialize(&value)).transpose()?)
+    }
+
+    // Rewrites header index entries from before BlockHeader had a version field. Entries are told
+    // apart by length, so running it again after an interruption only converts what is left.
+    pub async fn migrate_header_index(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let entries = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEADERS).expect("headers column family is always opened");
+            db.iterator_cf(cf, IteratorMode::Start)
+                .filter(|item| item.as_ref().map_or(true, |(_, value)| value.len() == LEGACY_HEADER_ENTRY_LEN))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await??;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        let mut rewritten = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            let (height, legacy) = bincode::deserialize::<(u64, LegacyBlockHeader)>(&value)?;
+            rewritten.push((key, bincode::serialize(&(height, BlockHeader::from(legacy)))?));
+        }
+        let count = rewritten.len();
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEADERS).expect("headers column family is always opened");
+            let mut batch = WriteBatch::default();
+            for (key, value) in rewritten {
+                batch.put_cf(cf, key, value);
+            }
+            db.write(batch)
+        })
+        .await??;
+        log::info!("Migrated {} headers to the versioned header layout", count);
+        Ok(count)
+    }
+
+    pub async fn load_headers(&self) -> Result<Vec<(u64, BlockHeader)>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let values = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEADERS).expect("headers column family is always opened");
+            let mut read_opts = ReadOptions::default();
+            read_opts.set_total_order_seek(true);
+            db.iterator_cf_opt(cf, read_opts, IteratorMode::Start)
+                .map(|item| item.map(|(_, value)| value))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await??;
+
+        let mut headers = values.iter()
+            .map(|value| bincode::deserialize::<(u64, BlockHeader)>(value))
+            .collect::<Result<Vec<_>, _>>()?;
+        headers.sort_by_key(|(height, _)| *height);
+        Ok(headers)
+    }
+
+    // Highest indexed height and its hash, used to restore the chain tip on startup
+    pub async fn get_best_height(&self) -> Result<Option<(u64, [u8; 32])>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let last = task::spawn_blocking(move || {
+            let cf = db.cf_handle(CF_HEIGHT_INDEX).expect("height index column family is always opened");
+            db.iterator_cf(cf, IteratorMode::End).next().transpose()
+        })
+        .await??;
+
+        match last {
+            Some((key, value)) => {
+                let height = u64::from_be_bytes(key.as_ref().try_into()?);
+                Ok(Some((height, decode_hash(&value)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Streams every entry of `cf` whose key starts with `prefix`, in key order
+    pub fn iter_prefix(&self, cf: &str, prefix: Vec<u8>) -> impl Stream<Item = Result<KeyValue, ScanError>> {
+        self.scan(cf, prefix.clone(), move |key| key.starts_with(&prefix))
+    }
+
+    // Streams entries of `cf` with `from <= key < to`, in key order
+    pub fn iter_range(&self, cf: &str, from: Vec<u8>, to: Vec<u8>) -> impl Stream<Item = Result<KeyValue, ScanError>> {
+        self.scan(cf, from, move |key| key < to.as_slice())
+    }
+
+    fn scan<F>(&self, cf: &str, start: Vec<u8>, mut in_bounds: F) -> impl Stream<Item = Result<KeyValue, ScanError>>
+    where
+        F: FnMut(&[u8]) -> bool + Send + 'static,
+    {
+        let db = Arc::clone(&self.db);
+        let cf = cf.to_owned();
+        let (tx, rx) = mpsc::channel(SCAN_CHANNEL_CAPACITY);
+
+        task::spawn_blocking(move || {
+            let handle = match db.cf_handle(&cf) {
+                Some(handle) => handle,
+                None => {
+                    let _ = tx.blocking_send(Err(format!("unknown column family: {}", cf).into()));
+                    return;
+                }
+            };
+
+            // The fixed-size prefix extractor would otherwise confine iteration to a single 32-byte prefix
+            let mut read_opts = ReadOptions::default();
+            read_opts.set_total_order_seek(true);
+
+            let iter = db.iterator_cf_opt(handle, read_opts, IteratorMode::From(&start, Direction::Forward));
+            for item in iter {
+                let item = match item {
+                    Ok((key, value)) if in_bounds(&key) => Ok((key, value)),
+                    Ok(_) => break,
+                    Err(e) => Err(e.into()),
+                };
+                let failed = item.is_err();
+                // The receiver was dropped, so nobody wants the rest of the scan
+                if tx.blocking_send(item).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+
+    pub async fn get_latest_block_hash(&self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let db = Arc::clone(&self.db);
+        let latest = task::spawn_blocking(move || {
+            let mut iter = db.iterator(rocksdb::IteratorMode::End);
+            iter.next().transpose().map(|entry| entry.map(|(key, _)| key.to_vec()))
+        })
+        .await??;
+        Ok(latest)
+    }
+}
+
+// Looks up a column family by name, for callers that take it from user input
+pub fn column_family(name: &str) -> Option<&'static str> {
+    COLUMN_FAMILIES.iter().copied().find(|cf| *cf == name)
+}
+
+pub fn column_families() -> &'static [&'static str] {
+    COLUMN_FAMILIES
+}
+
+// Reads one counter from RocksDB's statistics dump, where tickers appear as "<name> COUNT : <n>"
+fn ticker_count(statistics: &str, ticker: &str) -> u64 {
+    statistics.lines()
+        .find_map(|line| line.strip_prefix(ticker)?.strip_prefix(" COUNT : ")?.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn decode_hash(bytes: &[u8]) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    Ok(bytes.try_into()?)
+}
+
+// txid followed by the big-endian output index, so the 32-byte prefix extractor groups a transaction's outputs
+fn utxo_key(outpoint: &OutPoint) -> [u8; 36] {
+    let mut key = [0u8; 36];
+    key[..32].copy_from_slice(&outpoint.txid);
+    key[32..].copy_from_slice(&outpoint.index.to_be_bytes());
+    key
+}
+
+pub fn utxo_outpoint(key: &[u8]) -> Option<OutPoint> {
+    Some(OutPoint { txid: key.get(..32)?.try_into().ok()?, index: u32::from_be_bytes(key.get(32..)?.try_into().ok()?) })
 }
 
 #[cfg(test)]
@@ -91,10 +993,20 @@ mod tests {
     use super::*;
     use tempfile::TempDir;
 
+    #[test]
+    fn test_profile_overrides() {
+        let config = DatabaseConfig { profile: DatabaseProfile::LowMemory, block_cache_mb: Some(32), ..Default::default() };
+        let tuning = Tuning::resolve(&config);
+        assert_eq!(tuning.block_cache_mb, 32);
+        assert_eq!(tuning.write_buffer_mb, 8);
+        assert!(tuning.cache_index_and_filter_blocks);
+        assert_eq!(Tuning::resolve(&DatabaseConfig::default()).compaction_style, CompactionStyle::Level);
+    }
+
     #[tokio::test]
     async fn test_storage_operations() -> Result<(), Box<dyn std::error::Error>> {
         let temp_dir = TempDir::new()?;
-        let storage = Storage::new(temp_dir.path().to_str().unwrap()).await?;
+        let storage = Storage::new(temp_dir.path().to_str().unwrap(), &DatabaseConfig::default()).await?;
 
         let block_hash = vec![0u8; 32];
         let location = BlockLocation {
@@ -116,66 +1028,81 @@ mod tests {
 
         Ok(())
     }
-}
-```
-
-Let's break down the key components of this `storage.rs` file:
-
-1. Imports:
-   - We're using `rocksdb` for the database operations.
-   - `tokio::task` is used for running blocking operations asynchronously.
-   - `serde` for serialization and deserialization.
-
-2. `Storage` struct:
-   - Wraps an `Arc<DB>` to allow shared access to the RocksDB instance.
 
-3. `BlockLocation` struct:
-   - Represents the location of a block in the file system.
-   - It's serializable and deserializable for easy storage in RocksDB.
-
-4. `Storage::new`:
-   - Creates a new RocksDB instance with LZ4 compression enabled.
-   - Uses a prefix extractor for optimized point lookups.
-   - Runs the DB opening operation in a blocking task to avoid blocking the async runtime.
-
-5. CRUD Operations:
-   - `store_block_location`: Stores a block's location in the database.
-   - `retrieve_block_location`: Retrieves a block's location from the database.
-   - `delete_block_location`: Deletes a block's location from the database.
-   - All operations use `task::spawn_blocking` to run RocksDB operations off the async runtime.
+    #[tokio::test]
+    async fn test_secondary_catches_up_and_removes_its_directory() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("chainstate");
+        let secondary_path = temp_dir.path().join("secondary");
+        let primary = Storage::new(path.to_str().unwrap(), &DatabaseConfig::default()).await?;
+        primary.store_height_hash(0, [1; 32]).await?;
 
-6. `get_latest_block_hash`:
-   - A utility method to get the hash of the latest block in the database.
-   - This could be useful for maintaining the chain tip.
+        // Whatever an earlier run left there is cleared rather than reused
+        std::fs::create_dir_all(secondary_path.join("stale"))?;
+        let secondary = Storage::open_secondary(path.to_str().unwrap(), &secondary_path, &DatabaseConfig::default()).await?;
+        assert!(!secondary_path.join("stale").exists());
+        assert_eq!(secondary.get_best_height().await?, Some((0, [1; 32])));
 
-7. Error Handling:
-   - All methods return `Result<T, Box<dyn std::error::Error>>` for comprehensive error handling.
+        primary.write_utxo_batch(Vec::new(), &UtxoSetSummary::default(), Some([2; 32])).await?;
+        primary.store_height_hash(1, [2; 32]).await?;
+        secondary.catch_up().await?;
+        assert_eq!(secondary.get_utxo_best_block().await?, Some([2; 32]));
+        assert_eq!(secondary.get_best_height().await?, Some((1, [2; 32])));
+        assert!(secondary.store_height_hash(2, [3; 32]).await.is_err());
 
-8. Tests:
-   - Includes a basic test suite to verify the CRUD operations.
-   - Uses `tempfile` to create a temporary directory for testing.
+        let clone = secondary.clone();
+        drop(secondary);
+        assert!(secondary_path.exists());
+        drop(clone);
+        assert!(!secondary_path.exists());
+        Ok(())
+    }
 
-Key Points:
+    #[tokio::test]
+    async fn test_compaction_and_statistics() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let storage = Storage::new(temp_dir.path().to_str().unwrap(), &DatabaseConfig::default()).await?;
+        for height in 0..100u64 {
+            storage.store_height_hash(height, [height as u8; 32]).await?;
+        }
 
-- Asynchronous Design: All database operations are wrapped in `task::spawn_blocking` to ensure they don't block the async runtime.
-- LZ4 Compression: Enabled for both normal and bottommost levels of RocksDB for efficient storage.
-- Optimized for Blockchain: Uses a prefix extractor optimized for 32-byte block hashes, which is typical in blockchain systems.
-- Thread-Safe: The use of `Arc` allows the `Storage` instance to be safely shared between threads or async tasks.
+        storage.compact_range(CF_HEIGHT_INDEX).await?;
+        let stats = storage.get_statistics().await?;
+        assert_eq!(stats.column_families.iter().map(|cf| cf.name).collect::<Vec<_>>(), COLUMN_FAMILIES);
+        let heights = stats.column_families.iter().find(|cf| cf.name == CF_HEIGHT_INDEX).unwrap();
+        assert!(heights.live_sst_bytes > 0);
+        assert!(stats.block_cache_capacity_bytes > 0);
+        assert_eq!(ticker_count("rocksdb.block.cache.hit COUNT : 42\n", "rocksdb.block.cache.hit"), 42);
+        assert_eq!(ticker_count("rocksdb.block.cache.miss COUNT : 7\n", "rocksdb.block.cache.hit"), 0);
+        Ok(())
+    }
 
-To use this `storage.rs` in your project:
+    async fn collect_scan(scan: impl Stream<Item = Result<KeyValue, ScanError>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>, ScanError> {
+        use tokio_stream::StreamExt;
+        Box::pin(scan).map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec()))).collect().await
+    }
 
-1. Ensure you have the necessary dependencies in your `Cargo.toml`:
-   ```toml
-   [dependencies]
-   rocksdb = { version = "0.21.0", features = ["lz4"] }
-   tokio = { version = "1.0", features = ["full"] }
-   serde = { version = "1.0", features = ["derive"] }
-   bincode = "1.3"
+    #[tokio::test]
+    async fn test_prefix_and_range_scans() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let storage = Storage::new(temp_dir.path().to_str().unwrap(), &DatabaseConfig::default()).await?;
+        for height in 0..10u64 {
+            storage.store_height_hash(height, [height as u8; 32]).await?;
+            storage.store_block_height(&[height as u8; 32], height).await?;
+        }
 
-   [dev-dependencies]
-   tempfile = "3.2"
-   ```
+        // `to` is exclusive and keys come back in order
+        let range = collect_scan(storage.iter_range(CF_HEIGHT_INDEX, 3u64.to_be_bytes().to_vec(), 7u64.to_be_bytes().to_vec())).await.unwrap();
+        assert_eq!(range.iter().map(|(key, _)| u64::from_be_bytes(key.as_slice().try_into().unwrap())).collect::<Vec<_>>(), vec![3, 4, 5, 6]);
+        assert_eq!(range[0].1, vec![3; 32]);
 
-2. You can now use this `Storage` struct in your `main.rs` or other parts of your blockchain implementation to handle block location storage and retrieval.
+        // Only keys under the prefix, even though later keys exist
+        let prefixed = collect_scan(storage.iter_prefix(CF_BLOCK_HEIGHTS, vec![5])).await.unwrap();
+        assert_eq!(prefixed.len(), 1);
+        assert_eq!(prefixed[0].0, vec![5; 32]);
+        assert!(collect_scan(storage.iter_prefix(CF_BLOCK_HEIGHTS, vec![42])).await.unwrap().is_empty());
 
-This implementation provides a solid foundation for managing block locations in your blockchain system, with efficient storage, compression, and asynchronous operations. It's designed to work well with the rest of your blockchain implementation, particularly the `Blockchain` struct in `main.rs`.​​​​​​​​​​​​​​​​
+        assert!(collect_scan(storage.iter_prefix("no_such_cf", Vec::new())).await.is_err());
+        Ok(())
+    }
+}
\ No newline at end of file
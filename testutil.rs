@@ -0,0 +1,1562 @@
+use crate::block_download::BlockDownloads;
+use crate::blockchain::{Block, BlockHash, BlockHeader, BlockType, Blockchain, FruitHeader, SignedBlock};
+use crate::chain_params::Network;
+use crate::light_client::HeaderChain;
+use crate::mempool::Mempool;
+use crate::miner::BlockTemplateBuilder;
+use crate::node_config::{BlockchainConfig, DiskConfig};
+use crate::policy::RelayPolicy;
+use crate::pow;
+use crate::protocol::{Inventory, Message, PeerId};
+use crate::reward::{self, MinerPayout};
+use crate::snapshot::{Snapshot, SnapshotCommitment, SnapshotError, SnapshotSync};
+use crate::validation::{self, ValidationError};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Deterministic multi-node simulation for consensus and relay tests. Nodes are full regtest
+// chainstates in temporary directories, linked by in-memory queues instead of TCP. Nothing runs
+// in the background: messages are delivered one at a time in order of virtual delivery time, and
+// blocks are stamped with the virtual clock, so a scenario produces the same chains on every run.
+//
+// Links are established without the version handshake. Nodes ask a peer announcing a block they
+// don't have for its headers past their locator, then fetch the blocks of those headers from the
+// peers that announced them, moving on from peers that stall, and the chain moves to whichever
+// branch has the most work. A node added later may instead sync from a peer's UTXO snapshot. Built
+// only for the crate's own tests.
+pub const START_TIME: u64 = 1_700_000_000;
+// Virtual seconds a message spends on a link
+pub const LINK_LATENCY_SECS: u64 = 1;
+// Deliveries `run_until_idle` allows before deciding the nodes will never settle
+const MAX_DELIVERIES: usize = 1_000_000;
+
+pub type NodeId = usize;
+
+struct Envelope {
+    from: NodeId,
+    to: NodeId,
+    message: Message,
+}
+
+// What a node wants sent after handling a message
+enum Outgoing {
+    Reply(Message),
+    // To every peer but the one the message came from
+    Relay(Message),
+    To(NodeId, Message),
+}
+
+fn block_requests(requests: Vec<(PeerId, Vec<BlockHash>)>) -> Vec<Outgoing> {
+    requests.into_iter()
+        .map(|(peer, hashes)| Outgoing::To(peer as NodeId, Message::GetData(hashes.into_iter().map(Inventory::Block).collect())))
+        .collect()
+}
+
+// A snapshot being synced: first the headers up to the block committing to it, then that block,
+// then the snapshot chunk by chunk
+struct PendingSnapshot {
+    committing: BlockHash,
+    headers: Vec<BlockHeader>,
+    block: Option<Block>,
+    sync: Option<SnapshotSync>,
+}
+
+// A block on `chain`'s tip paying `miner_key`, with those of `fruits` that may be included
+pub async fn build_block(chain: &Blockchain, fruits: Vec<SignedBlock>, miner_key: &[u8; 32], timestamp: u64) -> Result<Block, Box<dyn std::error::Error>> {
+    let height = chain.get_chain_height().map_or(0, |h| h + 1);
+    let candidates = fruits.into_iter().filter_map(|fruit| fruit.block.fruit_header);
+    let fruits = chain.select_fruits(candidates).await?.included;
+    let coinbase = chain.create_coinbase(0, &fruits, &MinerPayout::single(reward::fruit_payout_script(miner_key))).await?;
+    let bits = chain.next_block_bits(timestamp).await?;
+    let builder = BlockTemplateBuilder::new(chain.params().clone());
+    let mut block = builder.build(chain.next_block_version(), chain.get_chain_tip(), height, chain.median_time_past(), timestamp, bits, coinbase, fruits, Vec::new())?;
+    while validation::check_header(&block.header).is_err() {
+        block.header.nonce += 1;
+    }
+    Ok(block)
+}
+
+pub struct SimNode {
+    pub blockchain: Blockchain,
+    pub mempool: Mempool,
+    miner_key: [u8; 32],
+    seen_fruits: HashSet<BlockHash>,
+    downloads: BlockDownloads,
+    // Ignores requests for blocks, like a peer holding up block download
+    stalling: bool,
+    // Served to peers that ask for it
+    snapshot: Option<Snapshot>,
+    snapshot_sync: Option<PendingSnapshot>,
+    _datadir: TempDir,
+}
+
+impl SimNode {
+    async fn new(id: NodeId) -> Result<Self, Box<dyn std::error::Error>> {
+        let datadir = TempDir::new()?;
+        let mut config = BlockchainConfig::load(datadir.path())?;
+        config.chain = Network::Regtest;
+        // Simulations shouldn't stall on how full the host's disk is
+        config.disk = DiskConfig { low_free_mb: 0, min_free_mb: 0 };
+        let blockchain = Blockchain::new(config).await?;
+        let mempool = Mempool::new(1, 600, 3600, 60, RelayPolicy { min_relay_fee_rate: 0.0, dust_limit: 0, accept_non_standard: false }, blockchain.params().fruit_freshness_window, blockchain.params().fruit_bits);
+        Ok(SimNode {
+            blockchain,
+            mempool,
+            miner_key: [id as u8 + 1; 32],
+            seen_fruits: HashSet::new(),
+            downloads: BlockDownloads::new(),
+            stalling: false,
+            snapshot: None,
+            snapshot_sync: None,
+            _datadir: datadir,
+        })
+    }
+
+    // A block on the tip paying this node, with every pooled fruit that may be included
+    async fn build_block(&self, timestamp: u64) -> Result<Block, Box<dyn std::error::Error>> {
+        build_block(&self.blockchain, self.mempool.get_fruits(), &self.miner_key, timestamp).await
+    }
+
+    // Adds `block`, which may move the tip to another branch; true if the tip changed
+    async fn connect(&mut self, block: Block) -> Result<bool, Box<dyn std::error::Error>> {
+        let tip = self.blockchain.get_chain_tip();
+        self.blockchain.add_block(block).await?;
+        let new_tip = self.blockchain.get_chain_tip();
+        if new_tip == tip {
+            return Ok(false);
+        }
+        let height = self.blockchain.get_chain_height().unwrap_or(0);
+        self.mempool.block_connected(&new_tip, height);
+        Ok(true)
+    }
+
+    async fn receive(&mut self, from: NodeId, now: Instant, message: Message) -> Result<Vec<Outgoing>, Box<dyn std::error::Error>> {
+        match message {
+            Message::Inv(items) => {
+                let mut unknown = false;
+                for item in items {
+                    if let Inventory::Block(hash) = item {
+                        if !self.blockchain.has_block(&hash).await? {
+                            self.downloads.announced(from as PeerId, hash);
+                            unknown = true;
+                        }
+                    }
+                }
+                Ok(match unknown {
+                    true => vec![Outgoing::Reply(Message::GetHeaders { locator: self.blockchain.block_locator(), stop_hash: [0; 32] })],
+                    false => Vec::new(),
+                })
+            }
+            Message::GetData(_) if self.stalling => Ok(Vec::new()),
+            Message::GetData(items) => {
+                let mut replies = Vec::new();
+                for item in items {
+                    if let Inventory::Block(hash) = item {
+                        if let Some(block) = self.blockchain.get_block(&hash).await? {
+                            replies.push(Outgoing::Reply(Message::Block((*block).clone())));
+                        }
+                    }
+                }
+                Ok(replies)
+            }
+            Message::GetHeaders { locator, stop_hash } => {
+                let headers = self.blockchain.headers_for_locator(&locator, &stop_hash)?;
+                Ok(vec![Outgoing::Reply(Message::Headers(headers))])
+            }
+            Message::Headers(headers) if self.snapshot_sync.as_ref().is_some_and(|pending| pending.headers.is_empty()) => {
+                let pending = self.snapshot_sync.as_mut().ok_or("snapshot went missing")?;
+                if headers.last().map(BlockHeader::hash) != Some(pending.committing) {
+                    return Ok(Vec::new());
+                }
+                HeaderChain::new(self.blockchain.params().clone()).accept_headers(&headers)?;
+                pending.headers = headers;
+                Ok(vec![Outgoing::Reply(Message::GetData(vec![Inventory::Block(pending.committing)]))])
+            }
+            Message::Headers(headers) => self.receive_headers(from, now, headers).await,
+            Message::Block(block) if self.snapshot_sync.as_ref().is_some_and(|pending| pending.block.is_none() && pending.committing == block.hash()) => {
+                let pending = self.snapshot_sync.as_mut().ok_or("snapshot went missing")?;
+                let mut sync = SnapshotSync::for_block(&pending.headers, &block)?;
+                pending.block = Some(block);
+                // Carries on with a load of the same snapshot this node was stopped in
+                if let Some(load) = self.blockchain.snapshot_load().await? {
+                    if sync.resume(&load).is_err() {
+                        self.blockchain.abort_snapshot_load().await?;
+                    }
+                }
+                let block_hash = sync.commitment().block_hash;
+                let requests = match sync.has_manifest() {
+                    true => sync.missing_chunks().into_iter().map(|index| Outgoing::Reply(Message::GetSnapshotChunk { block_hash, index })).collect(),
+                    false => vec![Outgoing::Reply(Message::GetSnapshot { block_hash })],
+                };
+                pending.sync = Some(sync);
+                let mut replies = requests;
+                replies.extend(self.load_snapshot_chunks().await?);
+                Ok(replies)
+            }
+            Message::Block(block) => self.receive_block(from, now, block).await,
+            Message::Fruit(fruit) => {
+                let hash = fruit.block.fruit_id();
+                let Some(header) = fruit.block.fruit_header.clone() else { return Ok(Vec::new()) };
+                if !self.seen_fruits.insert(hash) {
+                    return Ok(Vec::new());
+                }
+                let anchor_height = self.blockchain.get_block_height(&header.hang_from).await?;
+                let tip_height = self.blockchain.get_chain_height().unwrap_or(0);
+                Ok(match self.mempool.add_fruit(fruit.clone(), anchor_height, tip_height) {
+                    Ok(()) => vec![Outgoing::Relay(Message::Fruit(fruit))],
+                    Err(_) => Vec::new(),
+                })
+            }
+            Message::GetSnapshot { block_hash } => Ok(vec![Outgoing::Reply(match self.snapshot.as_ref().filter(|snapshot| snapshot.manifest.block_hash == block_hash) {
+                Some(snapshot) => Message::Snapshot(snapshot.manifest.clone()),
+                None => Message::NotFound(vec![Inventory::Block(block_hash)]),
+            })]),
+            Message::GetSnapshotChunk { block_hash, index } => {
+                let chunk = match self.snapshot.as_ref() {
+                    Some(snapshot) => snapshot.chunk(&block_hash, index)?,
+                    None => None,
+                };
+                Ok(chunk.map(|chunk| Outgoing::Reply(Message::SnapshotChunk(chunk))).into_iter().collect())
+            }
+            Message::Snapshot(manifest) => {
+                let Some(sync) = self.snapshot_sync.as_mut().and_then(|pending| pending.sync.as_mut()).filter(|sync| !sync.has_manifest()) else { return Ok(Vec::new()) };
+                let block_hash = manifest.block_hash;
+                match sync.accept_manifest(manifest) {
+                    Err(SnapshotError::RejectedManifest(_)) => return Ok(Vec::new()),
+                    result => result?,
+                }
+                let requests = sync.missing_chunks().into_iter().map(|index| Outgoing::Reply(Message::GetSnapshotChunk { block_hash, index })).collect();
+                Ok(requests)
+            }
+            Message::SnapshotChunk(chunk) => {
+                let Some(sync) = self.snapshot_sync.as_mut().and_then(|pending| pending.sync.as_mut()) else { return Ok(Vec::new()) };
+                sync.accept_chunk(chunk)?;
+                self.load_snapshot_chunks().await
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    // Loads the chunks that are next in line, and installs the snapshot once every one is in. A
+    // manifest whose coins turn out not to be the committed set is rejected and another asked for.
+    async fn load_snapshot_chunks(&mut self) -> Result<Vec<Outgoing>, Box<dyn std::error::Error>> {
+        let Some(pending) = self.snapshot_sync.as_mut() else { return Ok(Vec::new()) };
+        let Some(sync) = pending.sync.as_mut() else { return Ok(Vec::new()) };
+        let Some(manifest) = sync.manifest().cloned() else { return Ok(Vec::new()) };
+        let commitment = *sync.commitment();
+        let mut result = Ok(());
+        while let Some(chunk) = sync.next_chunk() {
+            result = self.blockchain.load_snapshot_chunk(&manifest, &chunk).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() && sync.is_complete() {
+            result = self.blockchain.finish_snapshot_load(&pending.headers[..pending.headers.len() - 1], &commitment).await;
+        }
+        match result {
+            Ok(()) if sync.is_complete() => {}
+            Ok(()) => return Ok(Vec::new()),
+            Err(e) if matches!(e.downcast_ref::<SnapshotError>(), Some(SnapshotError::UnsortedCoins(_) | SnapshotError::TotalsMismatch { .. } | SnapshotError::SetHashMismatch { .. })) => {
+                sync.reject_manifest();
+                return Ok(vec![Outgoing::Reply(Message::GetSnapshot { block_hash: commitment.block_hash })]);
+            }
+            Err(e) => return Err(e),
+        }
+
+        // The committing block goes on top of the loaded set, like any block after it
+        let pending = self.snapshot_sync.take().ok_or("snapshot went missing")?;
+        self.connect(pending.block.ok_or("committing block went missing")?).await?;
+        Ok(Vec::new())
+    }
+
+    // A block counts as downloaded once it validates; one that doesn't is asked of another peer
+    async fn receive_block(&mut self, from: NodeId, now: Instant, block: Block) -> Result<Vec<Outgoing>, Box<dyn std::error::Error>> {
+        let hash = block.hash();
+        self.downloads.received(from as PeerId, &hash, now);
+        if self.blockchain.has_block(&hash).await? {
+            self.downloads.validated(&hash);
+            return Ok(block_requests(self.downloads.schedule(now)));
+        }
+        match self.connect(block).await {
+            Ok(tip_changed) => {
+                self.downloads.validated(&hash);
+                let mut outgoing = block_requests(self.downloads.schedule(now));
+                if tip_changed {
+                    outgoing.push(Outgoing::Relay(Message::Inv(vec![Inventory::Block(self.blockchain.get_chain_tip())])));
+                }
+                Ok(outgoing)
+            }
+            // Not a block we can place; the sender's headers past our locator lead to it
+            Err(e) if matches!(e.downcast_ref::<ValidationError>(), Some(ValidationError::UnknownParent(_))) => {
+                Ok(vec![Outgoing::Reply(Message::GetHeaders { locator: self.blockchain.block_locator(), stop_hash: [0; 32] })])
+            }
+            Err(e) if e.downcast_ref::<ValidationError>().is_some() => {
+                self.downloads.failed(from as PeerId, &hash);
+                Ok(block_requests(self.downloads.schedule(now)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Simulated forks are far shorter than MAX_HEADERS_PER_MESSAGE, so one headers message always
+    // reaches the sender's tip. The sender becomes a source of every block they lead to.
+    async fn receive_headers(&mut self, from: NodeId, now: Instant, headers: Vec<BlockHeader>) -> Result<Vec<Outgoing>, Box<dyn std::error::Error>> {
+        self.blockchain.accept_headers(&headers).await?;
+        let mut wanted = Vec::new();
+        for header in &headers {
+            let hash = header.hash();
+            if !self.blockchain.has_block(&hash).await? {
+                let height = self.blockchain.header_height(&hash).ok_or("accepted header went missing")?;
+                self.downloads.announced(from as PeerId, hash);
+                wanted.push((height, hash));
+            }
+        }
+        self.downloads.want(wanted);
+        Ok(block_requests(self.downloads.schedule(now)))
+    }
+}
+
+pub struct Simulation {
+    nodes: Vec<SimNode>,
+    now: u64,
+    links: BTreeSet<(NodeId, NodeId)>,
+    // Links a partition has cut; messages in flight across them are lost
+    cut: BTreeSet<(NodeId, NodeId)>,
+    // Keyed by delivery time, then send order
+    queue: BTreeMap<(u64, u64), Envelope>,
+    sent: u64,
+    // Real time standing in for START_TIME, for the nodes' download stall clocks
+    epoch: Instant,
+}
+
+fn link(a: NodeId, b: NodeId) -> (NodeId, NodeId) {
+    (a.min(b), a.max(b))
+}
+
+impl Simulation {
+    // `node_count` unconnected nodes sharing a genesis block mined by node 0
+    pub async fn new(node_count: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut nodes = Vec::with_capacity(node_count);
+        for id in 0..node_count {
+            nodes.push(SimNode::new(id).await?);
+        }
+        let genesis = nodes[0].build_block(START_TIME).await?;
+        for node in &mut nodes {
+            node.connect(genesis.clone()).await?;
+        }
+        Ok(Simulation { nodes, now: START_TIME, links: BTreeSet::new(), cut: BTreeSet::new(), queue: BTreeMap::new(), sent: 0, epoch: Instant::now() })
+    }
+
+    // A node with an empty chainstate, unconnected, for syncing from a snapshot
+    pub async fn add_node(&mut self) -> Result<NodeId, Box<dyn std::error::Error>> {
+        let id = self.nodes.len();
+        self.nodes.push(SimNode::new(id).await?);
+        Ok(id)
+    }
+
+    // Has `id` take a snapshot of its UTXO set at its tip and serve it. Syncing nodes find it
+    // through the coinbase of the block mined on top.
+    pub async fn take_snapshot(&mut self, id: NodeId) -> Result<SnapshotCommitment, Box<dyn std::error::Error>> {
+        let node = &self.nodes[id];
+        let snapshot = node.blockchain.create_snapshot(&node._datadir.path().join("snapshots")).await?;
+        let commitment = snapshot.commitment();
+        self.nodes[id].snapshot = Some(snapshot);
+        Ok(commitment)
+    }
+
+    // Has `id` fetch from `peer` the headers up to `committing`, that block, and then the snapshot
+    // its coinbase commits to. Simulated chains are far shorter than MAX_HEADERS_PER_MESSAGE.
+    pub fn sync_snapshot(&mut self, id: NodeId, peer: NodeId, committing: BlockHash) {
+        self.nodes[id].snapshot_sync = Some(PendingSnapshot { committing, headers: Vec::new(), block: None, sync: None });
+        self.send(id, peer, Message::GetHeaders { locator: Vec::new(), stop_hash: committing });
+    }
+
+    // Nodes with a link between every pair
+    pub async fn fully_connected(node_count: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(node_count).await?;
+        for a in 0..node_count {
+            for b in a + 1..node_count {
+                sim.connect(a, b);
+            }
+        }
+        Ok(sim)
+    }
+
+    pub fn connect(&mut self, a: NodeId, b: NodeId) {
+        if self.links.insert(link(a, b)) {
+            let now = self.instant();
+            self.nodes[a].downloads.peer_connected(b as PeerId, now);
+            self.nodes[b].downloads.peer_connected(a as PeerId, now);
+        }
+    }
+
+    // Removes the link for good; blocks either node was fetching over it are asked of other peers
+    fn disconnect(&mut self, a: NodeId, b: NodeId) {
+        self.links.remove(&link(a, b));
+        self.cut.remove(&link(a, b));
+        let now = self.instant();
+        for (id, peer) in [(a, b), (b, a)] {
+            self.nodes[id].downloads.peer_disconnected(peer as PeerId);
+            let requests = self.nodes[id].downloads.schedule(now);
+            self.dispatch(id, None, block_requests(requests));
+        }
+    }
+
+    // Has `id` ignore requests for blocks from now on
+    pub fn stall(&mut self, id: NodeId) {
+        self.nodes[id].stalling = true;
+    }
+
+    pub fn node(&self, id: NodeId) -> &SimNode {
+        &self.nodes[id]
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    fn instant(&self) -> Instant {
+        self.epoch + Duration::from_secs(self.now - START_TIME)
+    }
+
+    fn peers(&self, id: NodeId) -> Vec<NodeId> {
+        self.links.iter()
+            .filter(|link| !self.cut.contains(link))
+            .filter_map(|&(a, b)| if a == id { Some(b) } else if b == id { Some(a) } else { None })
+            .collect()
+    }
+
+    fn send(&mut self, from: NodeId, to: NodeId, message: Message) {
+        self.queue.insert((self.now + LINK_LATENCY_SECS, self.sent), Envelope { from, to, message });
+        self.sent += 1;
+    }
+
+    fn dispatch(&mut self, from: NodeId, sender: Option<NodeId>, outgoing: Vec<Outgoing>) {
+        for out in outgoing {
+            match out {
+                Outgoing::Reply(message) => {
+                    if let Some(sender) = sender {
+                        self.send(from, sender, message);
+                    }
+                }
+                Outgoing::Relay(message) => {
+                    for peer in self.peers(from).into_iter().filter(|&peer| Some(peer) != sender) {
+                        self.send(from, peer, message.clone());
+                    }
+                }
+                Outgoing::To(peer, message) => self.send(from, peer, message),
+            }
+        }
+    }
+
+    // Delivers the next queued message, moving the clock to its delivery time. Returns false when
+    // nothing is queued.
+    async fn step(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(((deliver_at, _), envelope)) = self.queue.pop_first() else { return Ok(false) };
+        self.now = self.now.max(deliver_at);
+        if self.cut.contains(&link(envelope.from, envelope.to)) {
+            return Ok(true);
+        }
+        let now = self.instant();
+        let outgoing = self.nodes[envelope.to].receive(envelope.from, now, envelope.message).await?;
+        self.dispatch(envelope.to, Some(envelope.from), outgoing);
+        Ok(true)
+    }
+
+    // Delivers everything due in the next `secs` virtual seconds, then has every node check for
+    // stalled block downloads
+    pub async fn advance(&mut self, secs: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let until = self.now + secs;
+        while self.queue.first_key_value().is_some_and(|(&(deliver_at, _), _)| deliver_at <= until) {
+            self.step().await?;
+        }
+        self.now = until;
+        self.check_stalls();
+        Ok(())
+    }
+
+    // Blocks held up by a stalling peer are asked of others, and links to repeat stallers dropped
+    fn check_stalls(&mut self) {
+        let now = self.instant();
+        for id in 0..self.nodes.len() {
+            let actions = self.nodes[id].downloads.check_stalls(now);
+            self.dispatch(id, None, block_requests(actions.requests));
+            for peer in actions.disconnect {
+                self.disconnect(id, peer as NodeId);
+            }
+        }
+    }
+
+    // Delivers messages until none are left
+    pub async fn run_until_idle(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for _ in 0..MAX_DELIVERIES {
+            if !self.step().await? {
+                return Ok(());
+            }
+        }
+        Err(format!("nodes still exchanging messages after {} deliveries", MAX_DELIVERIES).into())
+    }
+
+    // Mines a block on `id`'s tip at the current virtual time and announces it
+    pub async fn mine(&mut self, id: NodeId) -> Result<BlockHash, Box<dyn std::error::Error>> {
+        let block = self.nodes[id].build_block(self.now).await?;
+        let hash = block.hash();
+        self.nodes[id].connect(block).await?;
+        self.dispatch(id, None, vec![Outgoing::Relay(Message::Inv(vec![Inventory::Block(hash)]))]);
+        Ok(hash)
+    }
+
+    // Cuts every link between nodes in different groups. Nodes in no group keep their links.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        let group_of = |id: NodeId| groups.iter().position(|group| group.contains(&id));
+        for &(a, b) in &self.links {
+            if let (Some(group_a), Some(group_b)) = (group_of(a), group_of(b)) {
+                if group_a != group_b {
+                    self.cut.insert((a, b));
+                }
+            }
+        }
+    }
+
+    // Restores all links and has every node announce its tip so the partitions converge
+    pub fn heal(&mut self) {
+        self.cut.clear();
+        for id in 0..self.nodes.len() {
+            let tip = self.nodes[id].blockchain.get_chain_tip();
+            self.dispatch(id, None, vec![Outgoing::Relay(Message::Inv(vec![Inventory::Block(tip)]))]);
+        }
+    }
+
+    // Has `id` receive `count` distinct fruits hanging from its tip, as if a fruit miner had sent them
+    pub async fn flood_fruits(&mut self, id: NodeId, count: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let hang_from = self.nodes[id].blockchain.get_chain_tip();
+        let fruit_bits = self.nodes[id].blockchain.params().fruit_bits;
+        let miner_public_key = [0xf0; 32];
+        let mut fruit_header = FruitHeader { hang_from, miner_public_key, timestamp: self.now, nonce: 0 };
+        for nonce in 0..count {
+            while !pow::check_proof_of_work(&fruit_header.hash(), fruit_bits) {
+                fruit_header.nonce += 1;
+            }
+            let block = Block {
+                header: BlockHeader {
+                    version: 1,
+                    previous_hash: hang_from,
+                    merkle_root: [0; 32],
+                    fruits_root: [0; 32],
+                    timestamp: self.now,
+                    bits: 0,
+                    nonce,
+                },
+                block_type: BlockType::Fruit,
+                fruit_header: Some(fruit_header.clone()),
+                fruits: Vec::new(),
+                transactions: Vec::new(),
+            };
+            fruit_header.nonce += 1;
+            let fruit = SignedBlock { block, public_key: miner_public_key, signature: Vec::new() };
+            let now = self.instant();
+            let outgoing = self.nodes[id].receive(id, now, Message::Fruit(fruit)).await?;
+            self.dispatch(id, None, outgoing);
+        }
+        Ok(())
+    }
+}
+
+// Proptest strategies for consensus types, covering shapes hand-written fixtures don't
+pub mod arbitrary {
+    use crate::blockchain::{Block, BlockHeader, BlockType, FruitHeader};
+    use crate::transaction::{OutPoint, Transaction, TxInput, TxOutput};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    pub fn hash() -> impl Strategy<Value = [u8; 32]> {
+        any::<[u8; 32]>()
+    }
+
+    pub fn transaction() -> impl Strategy<Value = Transaction> {
+        let input = (hash(), any::<u32>(), hash(), vec(any::<u8>(), 0..80), any::<u32>())
+            .prop_map(|(txid, index, public_key, signature, sequence)| TxInput {
+                previous_output: OutPoint { txid, index },
+                public_key,
+                signature,
+                sequence,
+            });
+        let output = (any::<u64>(), vec(any::<u8>(), 0..40)).prop_map(|(value, script_pubkey)| TxOutput { value, script_pubkey });
+        (vec(input, 0..4), vec(output, 0..4), any::<u32>())
+            .prop_map(|(inputs, outputs, lock_time)| Transaction { inputs, outputs, lock_time })
+    }
+
+    pub fn header() -> impl Strategy<Value = BlockHeader> {
+        (any::<u32>(), hash(), hash(), hash(), any::<u64>(), any::<u32>(), any::<u64>())
+            .prop_map(|(version, previous_hash, merkle_root, fruits_root, timestamp, bits, nonce)| BlockHeader {
+                version,
+                previous_hash,
+                merkle_root,
+                fruits_root,
+                timestamp,
+                bits,
+                nonce,
+            })
+    }
+
+    pub fn fruit_header() -> impl Strategy<Value = FruitHeader> {
+        (hash(), hash(), any::<u64>(), any::<u64>())
+            .prop_map(|(hang_from, miner_public_key, timestamp, nonce)| FruitHeader { hang_from, miner_public_key, timestamp, nonce })
+    }
+
+    // Structurally arbitrary: roots and fruit placement aren't required to be consistent
+    pub fn block() -> impl Strategy<Value = Block> {
+        let block_type = prop_oneof![Just(BlockType::Block), Just(BlockType::Fruit)];
+        (header(), block_type, proptest::option::of(fruit_header()), vec(fruit_header(), 0..4), vec(transaction(), 0..4))
+            .prop_map(|(header, block_type, fruit_header, fruits, transactions)| Block { header, block_type, fruit_header, fruits, transactions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_archive::ArchiveWriter;
+    use crate::block_download::BLOCK_STALL_TIMEOUT;
+    use crate::blockchain::{MAX_HEADERS_AHEAD_OF_TIP, SIGNATURE_PIPELINE_DEPTH};
+    use crate::block_storage::BlockStorage;
+    use crate::chain_params::UTXO_COMMITMENT_DEPLOYMENT;
+    use crate::codec;
+    use crate::jobs::JobProgress;
+    use crate::network_time::local_time;
+    use crate::node_config::DatabaseConfig;
+    use crate::snapshot::SnapshotManifest;
+    use crate::storage::Storage;
+    use crate::transaction::OutPoint;
+    use crate::wallet::{self, Wallet, Wallets};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    async fn partition_scenario() -> Result<Vec<BlockHash>, Box<dyn std::error::Error>> {
+        let mut sim = Simulation::fully_connected(4).await?;
+        sim.mine(0).await?;
+        sim.run_until_idle().await?;
+
+        sim.partition(&[&[0, 1], &[2, 3]]);
+        for _ in 0..2 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            sim.mine(2).await?;
+        }
+        sim.run_until_idle().await?;
+        assert_eq!(sim.node(1).blockchain.get_chain_height(), Some(3));
+        assert_eq!(sim.node(3).blockchain.get_chain_height(), Some(4));
+
+        sim.heal();
+        sim.run_until_idle().await?;
+        Ok((0..4).map(|id| sim.node(id).blockchain.get_chain_tip()).collect())
+    }
+
+    #[tokio::test]
+    async fn test_partitions_reorg_to_longest_chain_deterministically() -> Result<(), Box<dyn std::error::Error>> {
+        let tips = partition_scenario().await?;
+        assert!(tips.iter().all(|tip| *tip == tips[0]));
+        assert_eq!(partition_scenario().await?, tips);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fruit_flood_relays_and_confirms() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(3).await?;
+        sim.connect(0, 1);
+        sim.connect(1, 2);
+        sim.flood_fruits(0, 300).await?;
+        sim.run_until_idle().await?;
+        assert!((0..3).all(|id| sim.node(id).mempool.get_fruits().len() == 300));
+
+        sim.mine(2).await?;
+        sim.run_until_idle().await?;
+        let block = sim.node(0).blockchain.get_block_by_height(1).await?.expect("block reached node 0");
+        assert_eq!(block.fruits.len(), sim.node(0).blockchain.params().max_fruits_per_block);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_height_index_follows_connects_and_disconnects() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        let mut mined = vec![sim.node(0).blockchain.get_chain_tip()];
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            mined.push(sim.mine(0).await?);
+        }
+
+        let chain = &sim.node(0).blockchain;
+        assert_eq!(chain.get_block_hashes(0..10).await?, mined);
+        assert_eq!(chain.get_block_hashes(1..3).await?, mined[1..3]);
+        assert_eq!(chain.get_block_by_height(2).await?.map(|block| block.hash()), Some(mined[2]));
+        assert!(chain.get_block_by_height(4).await?.is_none());
+
+        chain.disconnect_block().await?;
+        assert!(chain.get_block_by_height(3).await?.is_none());
+        assert_eq!(chain.get_block_hashes(0..10).await?, mined[..3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_blocks_on_one_tip_connect_once() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        sim.advance(60).await?;
+        let first = sim.node(0).build_block(sim.now()).await?;
+        let second = sim.node(0).build_block(sim.now() + 1).await?;
+        assert_ne!(first.hash(), second.hash());
+
+        let chain = &sim.node(0).blockchain;
+        let (first_hash, second_hash) = (first.hash(), second.hash());
+        let (a, b) = tokio::join!(chain.add_block(first), chain.add_block(second));
+        // Whichever connects first moves the tip; the other is kept as a fork with as much work
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(chain.get_chain_height(), Some(1));
+        assert_eq!(chain.get_block_hashes(0..10).await?.len(), 2);
+        let tip = chain.get_chain_tip();
+        assert!(tip == first_hash || tip == second_hash);
+        assert!(chain.has_block(&first_hash).await? && chain.has_block(&second_hash).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fork_blocks_are_kept_until_their_branch_has_more_work() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(2).await?;
+        sim.advance(60).await?;
+        let ours = sim.mine(0).await?;
+        let mut theirs = Vec::new();
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            sim.mine(1).await?;
+            theirs.push(sim.node(1).blockchain.get_chain_tip());
+        }
+
+        let chain = &sim.node(0).blockchain;
+        for (i, hash) in theirs.iter().enumerate() {
+            let block = sim.node(1).blockchain.get_block(hash).await?.expect("node 1 mined it");
+            chain.add_block((*block).clone()).await?;
+            // The first block only ties with ours, so the tip stays until the branch pulls ahead
+            let expected = if i == 0 { ours } else { *hash };
+            assert_eq!(chain.get_chain_tip(), expected);
+        }
+        assert_eq!(chain.get_chain_height(), Some(3));
+        assert!(chain.has_block(&ours).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_headers_are_checked_before_they_are_indexed() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(2).await?;
+        let mut headers = Vec::new();
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            let hash = sim.mine(1).await?;
+            headers.push(sim.node(1).blockchain.get_header(&hash).await?.expect("node 1 mined it"));
+        }
+
+        let chain = &sim.node(0).blockchain;
+        // Without the headers before it there's nothing to place a block on
+        let block = sim.node(1).blockchain.get_block(&headers[2].hash()).await?.expect("node 1 mined it");
+        let refused = chain.add_block((*block).clone()).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::UnknownParent(_))));
+
+        assert_eq!(chain.accept_headers(&headers).await?, 3);
+        assert_eq!(chain.accept_headers(&headers).await?, 0);
+        assert_eq!(chain.get_chain_height(), Some(0));
+        let mut orphan = headers[0].clone();
+        orphan.previous_hash = [7; 32];
+        let refused = chain.accept_headers(&[orphan]).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::UnknownParent(_))));
+        let mut bad_bits = headers[0].clone();
+        bad_bits.bits = 0x2000ffff;
+        while validation::check_header(&bad_bits).is_err() {
+            bad_bits.nonce += 1;
+        }
+        let refused = chain.accept_headers(&[bad_bits]).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::BadBits { .. })));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_refused_at_the_first_check_they_fail() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        sim.advance(60).await?;
+        sim.mine(0).await?;
+        sim.advance(60).await?;
+        let chain = &sim.node(0).blockchain;
+        let (height, stats) = (chain.get_chain_height(), chain.utxo_cache_stats().await);
+        let block = sim.node(0).build_block(sim.now).await?;
+
+        let mut no_work = block.clone();
+        while validation::check_header(&no_work.header).is_ok() {
+            no_work.header.nonce += 1;
+        }
+        // Emptied after the header is mined, so only the body is wrong
+        let mut empty = block.clone();
+        empty.transactions.clear();
+        for (refused, expected) in [(no_work, ValidationError::InsufficientProofOfWork), (empty, ValidationError::EmptyBlock)] {
+            let error = chain.add_block(refused).await.unwrap_err();
+            let error = error.downcast_ref::<ValidationError>().expect("the block is at fault");
+            assert_eq!(error.to_string(), expected.to_string());
+            assert_eq!(error.misbehavior(), 100);
+        }
+        // Neither got as far as the coins
+        let after = chain.utxo_cache_stats().await;
+        assert_eq!((after.hits, after.misses), (stats.hits, stats.misses));
+
+        assert_eq!(chain.get_chain_height(), height);
+        chain.add_block(block).await?;
+        assert_eq!(chain.get_chain_height(), height.map(|height| height + 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_headers_well_past_the_tip_keep_the_node_in_initial_block_download() -> Result<(), Box<dyn std::error::Error>> {
+        let sim = Simulation::new(2).await?;
+        let (chain, ahead) = (&sim.node(0).blockchain, &sim.node(1).blockchain);
+        let now = local_time();
+        let mut blocks = Vec::new();
+        for i in 0..MAX_HEADERS_AHEAD_OF_TIP + 2 {
+            let block = build_block(ahead, Vec::new(), &[1; 32], now + i).await?;
+            ahead.add_block(block.clone()).await?;
+            blocks.push(block);
+        }
+        assert!(!ahead.is_initial_block_download());
+
+        // A recent tip alone isn't enough while the blocks of the headers past it are missing
+        chain.add_block(blocks[0].clone()).await?;
+        chain.accept_headers(&blocks[1..].iter().map(|block| block.header.clone()).collect::<Vec<_>>()).await?;
+        assert_eq!(chain.best_header_height(), ahead.get_chain_height());
+        assert!(chain.is_initial_block_download());
+        chain.add_block(blocks[1].clone()).await?;
+        assert!(!chain.is_initial_block_download());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocks_arriving_before_their_parent_connect_once_it_does() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(2).await?;
+        let mut blocks = Vec::new();
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            let hash = sim.mine(1).await?;
+            blocks.push(sim.node(1).blockchain.get_block(&hash).await?.expect("node 1 mined it"));
+        }
+
+        let chain = &sim.node(0).blockchain;
+        let headers = blocks.iter().map(|block| block.header.clone()).collect::<Vec<_>>();
+        chain.accept_headers(&headers).await?;
+        for block in blocks[1..].iter().rev() {
+            chain.add_block((**block).clone()).await?;
+            assert_eq!(chain.get_chain_height(), Some(0));
+        }
+        // The missing block lets the ones stored above it connect too
+        chain.add_block((*blocks[0]).clone()).await?;
+        assert_eq!(chain.get_chain_tip(), blocks[2].hash());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocks_held_by_a_stalling_peer_are_fetched_from_another() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(3).await?;
+        sim.connect(0, 1);
+        for _ in 0..5 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        sim.run_until_idle().await?;
+        let tip = sim.node(0).blockchain.get_chain_tip();
+        assert_eq!(sim.node(1).blockchain.get_chain_tip(), tip);
+
+        // Node 2 learns the blocks from node 1 first and asks it for all of them, but gets none
+        sim.stall(1);
+        sim.connect(1, 2);
+        sim.heal();
+        sim.run_until_idle().await?;
+        assert_eq!(sim.node(2).downloads.in_flight_count(1), 5);
+
+        // Node 0 announcing them too doesn't take them from node 1 until it has stalled
+        sim.connect(0, 2);
+        sim.heal();
+        sim.run_until_idle().await?;
+        assert_eq!(sim.node(2).blockchain.get_chain_height(), Some(0));
+        sim.advance(BLOCK_STALL_TIMEOUT.as_secs()).await?;
+        sim.run_until_idle().await?;
+        assert_eq!(sim.node(2).blockchain.get_chain_tip(), tip);
+        assert_eq!((sim.node(2).downloads.stalls(1), sim.node(2).downloads.in_flight_count(1)), (1, 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_with_a_fruit_short_of_the_fruit_target_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::blockchain::calculate_fruits_root;
+        let mut sim = Simulation::new(1).await?;
+        sim.advance(60).await?;
+        let chain = &sim.node(0).blockchain;
+        let mut fruit = FruitHeader { hang_from: chain.get_chain_tip(), miner_public_key: [0xf0; 32], timestamp: sim.now(), nonce: 0 };
+        while pow::check_proof_of_work(&fruit.hash(), chain.params().fruit_bits) {
+            fruit.nonce += 1;
+        }
+        let selection = chain.select_fruits([fruit.clone()]).await?;
+        assert_eq!(selection.excluded.len(), 1);
+
+        let mut block = sim.node(0).build_block(sim.now()).await?;
+        block.fruits.push(fruit);
+        block.header.fruits_root = calculate_fruits_root(&block.fruits);
+        while validation::check_header(&block.header).is_err() {
+            block.header.nonce += 1;
+        }
+        let refused = chain.add_block(block).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::FruitProofOfWork(0))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_with_a_non_final_transaction_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::blockchain::calculate_merkle_root;
+        use crate::transaction::{OutPoint, Transaction, TxInput, TxOutput};
+        use ed25519_dalek::{Signer, SigningKey};
+        let mut sim = Simulation::new(1).await?;
+        sim.advance(60).await?;
+
+        // Locked until after height 5; the block is at height 1
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut tx = Transaction {
+            inputs: vec![TxInput { previous_output: OutPoint { txid: [9; 32], index: 0 }, public_key: key.verifying_key().to_bytes(), signature: Vec::new(), sequence: 0 }],
+            outputs: vec![TxOutput { value: 1, script_pubkey: vec![1; 32] }],
+            lock_time: 5,
+        };
+        let message = tx.signature_hash()?;
+        tx.inputs[0].signature = key.sign(&message).to_bytes().to_vec();
+        let mut block = sim.node(0).build_block(sim.now()).await?;
+        block.transactions.push(tx);
+        block.header.merkle_root = calculate_merkle_root(&block.transactions);
+        while validation::check_header(&block.header).is_err() {
+            block.header.nonce += 1;
+        }
+
+        let refused = sim.node(0).blockchain.add_block(block).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::NonFinalTransaction(1))));
+        assert_eq!(sim.node(0).blockchain.get_chain_height(), Some(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bulk_connect_checks_signatures_ahead_and_stops_at_a_bad_one() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::blockchain::calculate_merkle_root;
+        use crate::transaction::{OutPoint, Transaction, TxInput, TxOutput};
+        use ed25519_dalek::{Signer, SigningKey};
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut sim = Simulation::new(2).await?;
+        sim.nodes[1].miner_key = key.verifying_key().to_bytes();
+        let mut hashes = Vec::new();
+        for _ in 0..9 {
+            sim.advance(60).await?;
+            hashes.push(sim.mine(1).await?);
+        }
+
+        // Block 10 spends the first coinbase, with more blocks after it than are checked ahead
+        let miner = &sim.node(1).blockchain;
+        let coinbase = miner.get_block(&hashes[0]).await?.ok_or("mined block is stored")?.transactions[0].clone();
+        let mut spend = Transaction {
+            inputs: vec![TxInput { previous_output: OutPoint { txid: coinbase.hash(), index: 0 }, public_key: key.verifying_key().to_bytes(), signature: Vec::new(), sequence: 0 }],
+            outputs: vec![TxOutput { value: coinbase.outputs[0].value - 1000, script_pubkey: vec![1; 32] }],
+            lock_time: 0,
+        };
+        let message = spend.signature_hash()?;
+        spend.inputs[0].signature = key.sign(&message).to_bytes().to_vec();
+        sim.advance(60).await?;
+        let mut spending = sim.node(1).build_block(sim.now()).await?;
+        spending.transactions.push(spend);
+        spending.header.merkle_root = calculate_merkle_root(&spending.transactions);
+        while validation::check_header(&spending.header).is_err() {
+            spending.header.nonce += 1;
+        }
+        hashes.push(spending.hash());
+        sim.node(1).blockchain.add_block(spending.clone()).await?;
+        for _ in 0..SIGNATURE_PIPELINE_DEPTH {
+            sim.advance(60).await?;
+            hashes.push(sim.mine(1).await?);
+        }
+        let mut blocks = Vec::new();
+        for hash in &hashes {
+            blocks.push((*sim.node(1).blockchain.get_block(hash).await?.ok_or("mined block is stored")?).clone());
+        }
+
+        let mut forged = spending;
+        forged.transactions[1].inputs[0].signature[0] ^= 1;
+        forged.header.merkle_root = calculate_merkle_root(&forged.transactions);
+        while validation::check_header(&forged.header).is_err() {
+            forged.header.nonce += 1;
+        }
+        let mut with_forgery = blocks.clone();
+        with_forgery[9] = forged;
+        let chain = &sim.node(0).blockchain;
+        let refused = chain.connect_blocks(with_forgery.into_iter().map(Ok::<_, std::io::Error>)).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::Transaction { index: 1, .. })));
+        assert_eq!(chain.get_chain_tip(), hashes[8]);
+
+        let summary = chain.connect_blocks(blocks.into_iter().map(Ok::<_, std::io::Error>)).await?;
+        assert_eq!((summary.connected, summary.skipped), (hashes.len() as u64 - 9, 9));
+        assert_eq!(chain.get_chain_tip(), sim.node(1).blockchain.get_chain_tip());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chain_tips_and_invalidation() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::blockchain::TipStatus;
+        let mut sim = Simulation::fully_connected(2).await?;
+        sim.mine(0).await?;
+        sim.run_until_idle().await?;
+        sim.partition(&[&[0], &[1]]);
+        sim.advance(60).await?;
+        sim.mine(0).await?;
+        for _ in 0..2 {
+            sim.advance(60).await?;
+            sim.mine(1).await?;
+        }
+        sim.heal();
+        sim.run_until_idle().await?;
+
+        let chain = &sim.node(0).blockchain;
+        let summary = |tips: Vec<crate::blockchain::ChainTipInfo>| tips.iter().map(|tip| (tip.height, tip.branch_len, tip.status)).collect::<Vec<_>>();
+        // Node 0's own block at height 2 lost the race and is left as a fork
+        assert_eq!(summary(chain.chain_tips().await?), vec![(3, 0, TipStatus::Active), (2, 1, TipStatus::ValidFork)]);
+
+        // Invalidating the winning branch falls back to the fork, which is revalidated on the way
+        let tip = chain.get_chain_tip();
+        let invalid = chain.get_block_hashes(2..3).await?[0];
+        chain.invalidate_block(&invalid).await?;
+        assert_eq!(chain.get_chain_height(), Some(2));
+        assert_ne!(chain.get_block_hashes(2..3).await?[0], invalid);
+        assert_eq!(summary(chain.chain_tips().await?), vec![(3, 2, TipStatus::Invalid), (2, 0, TipStatus::Active)]);
+        let block = chain.get_block(&invalid).await?.expect("disconnected blocks stay stored");
+        chain.disconnect_block().await?;
+        let refused = chain.add_block((*block).clone()).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::MarkedInvalid(_))));
+
+        // Reconsidering any descendant clears the mark, and its branch has the most work again
+        chain.reconsider_block(&tip).await?;
+        assert_eq!(chain.get_chain_tip(), tip);
+        assert_eq!(summary(chain.chain_tips().await?), vec![(3, 0, TipStatus::Active), (2, 1, TipStatus::ValidFork)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_blocks_follows_the_active_chain() -> Result<(), Box<dyn std::error::Error>> {
+        use tokio_stream::StreamExt;
+        let mut sim = Simulation::fully_connected(2).await?;
+        sim.mine(0).await?;
+        sim.run_until_idle().await?;
+        sim.partition(&[&[0], &[1]]);
+        sim.advance(60).await?;
+        sim.mine(0).await?;
+        for _ in 0..2 {
+            sim.advance(60).await?;
+            sim.mine(1).await?;
+        }
+        sim.heal();
+        sim.run_until_idle().await?;
+
+        // Node 0's block files still hold its own block at height 2, which lost the race
+        let chain = &sim.node(0).blockchain;
+        let streamed = chain.stream_blocks(1..10)
+            .map(|item| item.map(|(height, block)| (height, block.hash())))
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .map_err(|e| e as Box<dyn std::error::Error>)?;
+        let expected = chain.get_block_hashes(1..10).await?.into_iter().zip(1..).map(|(hash, height)| (height, hash)).collect::<Vec<_>>();
+        assert_eq!(streamed.len(), 3);
+        assert_eq!(streamed, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lost_tip_undo_is_rebuilt_on_restart() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..2 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let SimNode { blockchain, _datadir: datadir, .. } = sim.nodes.pop().ok_or("simulation has a node")?;
+        let tip = blockchain.get_chain_tip();
+        blockchain.sync_block_files()?;
+        blockchain.flush_utxo_cache().await?;
+        drop(blockchain);
+
+        // As if the node died before the undo records it had indexed reached the disk
+        for entry in std::fs::read_dir(datadir.path().join("blocks"))? {
+            let path = entry?.path();
+            if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("rev_")) {
+                std::fs::File::create(path)?;
+            }
+        }
+
+        let mut config = BlockchainConfig::load(datadir.path())?;
+        config.chain = Network::Regtest;
+        config.disk = DiskConfig { low_free_mb: 0, min_free_mb: 0 };
+        let blockchain = Blockchain::new(config).await?;
+        assert_eq!(blockchain.get_chain_tip(), tip);
+        assert!(blockchain.get_undo(&tip).await?.is_some());
+        blockchain.disconnect_block().await?;
+        assert_eq!(blockchain.get_chain_height(), Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_new_node_syncs_from_a_snapshot() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        // Regtest activates UTXO commitments after three signalling periods
+        let period = sim.node(0).blockchain.params().signalling_period;
+        for _ in 1..3 * period {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        assert!(sim.node(0).blockchain.is_deployment_active(UTXO_COMMITMENT_DEPLOYMENT));
+        sim.flood_fruits(0, 5).await?;
+        sim.advance(60).await?;
+        sim.mine(0).await?;
+        let commitment = sim.take_snapshot(0).await?;
+        sim.advance(60).await?;
+        let committing = sim.mine(0).await?;
+
+        // The commitment is an output of the coinbase but never a coin
+        let block = sim.node(0).blockchain.get_block(&committing).await?.ok_or("block went missing")?;
+        let coinbase = &block.transactions[0];
+        assert_eq!(reward::utxo_commitment(coinbase), Some(commitment.utxo_set_hash));
+        let last = OutPoint { txid: coinbase.hash(), index: coinbase.outputs.len() as u32 - 1 };
+        assert!(sim.node(0).blockchain.get_coin(&last).await?.is_none());
+
+        let id = sim.add_node().await?;
+        sim.connect(0, id);
+        sim.sync_snapshot(id, 0, committing);
+        sim.run_until_idle().await?;
+        let (served, synced) = (sim.node(0).blockchain.utxo_set_info().await, sim.node(id).blockchain.utxo_set_info().await);
+        assert_eq!((synced.height, synced.best_block, synced.coins, synced.hash), (served.height, served.best_block, served.coins, served.hash));
+        assert!(sim.node(id).blockchain.get_block_by_height(1).await?.is_none());
+        assert_eq!(sim.node(id).blockchain.snapshot_base().await?, Some(commitment.block_hash));
+
+        // New blocks connect on top, paying the fruits included before the snapshot their share
+        sim.advance(60).await?;
+        let tip = sim.mine(0).await?;
+        sim.run_until_idle().await?;
+        assert_eq!(sim.node(id).blockchain.get_chain_tip(), tip);
+        assert_eq!(sim.node(id).blockchain.utxo_set_info().await.hash, sim.node(0).blockchain.utxo_set_info().await.hash);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_snapshot_loads_resume_or_abort() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let commitment = sim.take_snapshot(0).await?;
+        let served = sim.node(0).snapshot.clone().ok_or("snapshot went missing")?;
+        let chunk = served.chunk(&commitment.block_hash, 0)?.ok_or("chunk went missing")?;
+        let headers = sim.node(0).blockchain.headers_for_locator(&[], &commitment.block_hash)?;
+
+        let datadir = TempDir::new()?;
+        let config = || -> Result<BlockchainConfig, Box<dyn std::error::Error>> {
+            let mut config = BlockchainConfig::load(datadir.path())?;
+            config.chain = Network::Regtest;
+            config.disk = DiskConfig { low_free_mb: 0, min_free_mb: 0 };
+            Ok(config)
+        };
+        Blockchain::new(config()?).await?.load_snapshot_chunk(&served.manifest, &chunk).await?;
+
+        // Restarted, the node knows how far it got, and takes no other snapshot's chunks meanwhile
+        let blockchain = Blockchain::new(config()?).await?;
+        let load = blockchain.snapshot_load().await?.ok_or("load went missing")?;
+        assert_eq!((&load.manifest, load.progress.next_chunk), (&served.manifest, 1));
+        let other = SnapshotManifest { recent_fruits: Vec::new(), ..served.manifest.clone() };
+        assert!(blockchain.load_snapshot_chunk(&other, &chunk).await.is_err());
+
+        // A set that doesn't match the commitment is thrown away
+        let wrong = SnapshotCommitment { utxo_set_hash: [0; 32], ..commitment };
+        assert!(blockchain.finish_snapshot_load(&headers, &wrong).await.is_err());
+        assert!(blockchain.snapshot_load().await?.is_none());
+        assert_eq!(blockchain.utxo_set_info().await.coins, 0);
+
+        blockchain.load_snapshot_chunk(&served.manifest, &chunk).await?;
+        blockchain.finish_snapshot_load(&headers, &commitment).await?;
+        assert_eq!(blockchain.get_chain_tip(), commitment.block_hash);
+        assert_eq!(blockchain.snapshot_base().await?, Some(commitment.block_hash));
+        assert!(blockchain.snapshot_load().await?.is_none());
+        assert_eq!(blockchain.utxo_set_info().await.hash, commitment.utxo_set_hash);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only_chainstate_follows_what_the_primary_flushed() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        sim.advance(60).await?;
+        let flushed = sim.mine(0).await?;
+        sim.node(0).blockchain.flush_utxo_cache().await?;
+
+        let mut config = BlockchainConfig::load(sim.node(0)._datadir.path())?;
+        config.chain = Network::Regtest;
+        config.read_only = true;
+        let follower = Blockchain::new(config).await?;
+        assert_eq!(follower.get_chain_tip(), flushed);
+
+        // Coins of these are still only in the primary's cache
+        for _ in 0..2 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        assert_eq!(follower.refresh().await?, Some(1));
+        assert_eq!(follower.get_chain_tip(), flushed);
+
+        let primary = &sim.node(0).blockchain;
+        primary.flush_utxo_cache().await?;
+        assert_eq!(follower.refresh().await?, Some(3));
+        assert_eq!(follower.get_chain_tip(), primary.get_chain_tip());
+        assert_eq!(follower.utxo_set_info().await.hash, primary.utxo_set_info().await.hash);
+        assert_eq!(format!("{:?}", follower.deployment_info()), format!("{:?}", primary.deployment_info()));
+
+        primary.disconnect_block().await?;
+        primary.flush_utxo_cache().await?;
+        assert_eq!(follower.refresh().await?, Some(2));
+        assert_eq!(follower.get_chain_tip(), primary.get_chain_tip());
+        assert_eq!(follower.utxo_set_info().await.hash, primary.utxo_set_info().await.hash);
+        assert_eq!(format!("{:?}", follower.deployment_info()), format!("{:?}", primary.deployment_info()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chainstate_backup_includes_unflushed_coins() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let backups = TempDir::new()?;
+        let destination = backups.path().join("chainstate");
+        let blockchain = &sim.node(0).blockchain;
+        blockchain.backup_chainstate(&destination).await?;
+
+        let backup = Storage::new(destination.to_str().ok_or("non-UTF-8 path")?, &DatabaseConfig::default()).await?;
+        let info = blockchain.utxo_set_info().await;
+        assert_eq!(backup.get_utxo_best_block().await?, Some(info.best_block));
+        let summary = backup.get_utxo_set_summary().await?.ok_or("backup has no UTXO summary")?;
+        assert_eq!((summary.coins, summary.hash.digest()), (info.coins, info.hash));
+        assert_eq!(backup.get_best_height().await?, Some((3, info.best_block)));
+
+        // A checkpoint never overwrites an existing directory
+        assert!(blockchain.backup_chainstate(&destination).await.is_err());
+        Ok(())
+    }
+
+
+    #[tokio::test]
+    async fn test_recovery_blocks_are_kept_until_every_block_reconnects() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        let genesis = sim.node(0).blockchain.get_chain_tip();
+        let mut blocks = vec![sim.node(0).blockchain.get_block(&genesis).await?.ok_or("genesis is stored")?];
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            let hash = sim.mine(0).await?;
+            blocks.push(sim.node(0).blockchain.get_block(&hash).await?.ok_or("mined block is stored")?);
+        }
+
+        // Block files moved aside by `open_storage`, missing the middle block
+        let datadir = TempDir::new()?;
+        let mut config = BlockchainConfig::load(datadir.path())?;
+        config.chain = Network::Regtest;
+        config.disk = DiskConfig { low_free_mb: 0, min_free_mb: 0 };
+        let recovery_dir = config.blocks_dir.with_extension("recovery");
+        let write_recovery = |heights: &[u64]| -> Result<(), Box<dyn std::error::Error>> {
+            let storage = BlockStorage::new(BlockchainConfig { blocks_dir: recovery_dir.clone(), ..config.clone() })?;
+            for height in heights {
+                storage.append_block_to_file(&codec::encode(&*blocks[*height as usize])?, *height)?;
+            }
+            Ok(storage.sync()?)
+        };
+        write_recovery(&[0, 1, 3])?;
+        assert!(Blockchain::new(config.clone()).await.is_err());
+        assert!(recovery_dir.exists());
+
+        // With the missing block supplied the rebuild finishes and the directory goes
+        write_recovery(&[2])?;
+        let blockchain = Blockchain::new(config).await?;
+        assert_eq!(blockchain.get_chain_tip(), blocks[3].hash());
+        assert!(!recovery_dir.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_cache_shares_connected_blocks() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        sim.advance(60).await?;
+        let tip = sim.mine(0).await?;
+        let chain = &sim.node(0).blockchain;
+        let before = chain.block_cache_stats();
+        assert_eq!(before.entries, 2);
+
+        // A connected block is served from the cache, the same allocation each time
+        let first = chain.get_block(&tip).await?.ok_or("tip is stored")?;
+        let second = chain.get_block_by_height(1).await?.ok_or("tip is stored")?;
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(chain.get_block(&[7; 32]).await?.is_none());
+        let after = chain.block_cache_stats();
+        assert_eq!((after.hits - before.hits, after.misses - before.misses), (2, 1));
+        assert_eq!((after.entries, after.bytes), (before.entries, before.bytes));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chain_stats_cover_the_window_ending_at_the_tip() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..4 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let chain = &sim.node(0).blockchain;
+        let stats = chain.chain_stats(3).await?;
+        assert_eq!((stats.start_height, stats.end_height, stats.blocks, stats.blocks_with_data), (2, 4, 3, 3));
+        assert_eq!(stats.average_block_interval_secs, 60.0);
+        assert!(stats.network_hashrate > 0.0);
+
+        // A window longer than the chain stops at genesis
+        let stats = chain.chain_stats(100).await?;
+        assert_eq!((stats.start_height, stats.blocks), (0, 5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rescan_replays_blocks_without_holding_the_wallets() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let wallets_dir = TempDir::new()?;
+        // Watching the key node 0 mines to
+        Wallet::create_watch_only(wallets_dir.path(), "miner", vec![sim.node(0).miner_key])?;
+        let wallets = tokio::sync::Mutex::new(Wallets::load(wallets_dir.path())?);
+        let chain = &sim.node(0).blockchain;
+
+        let summary = wallet::rescan(&wallets, None, chain, 0, None, &JobProgress::new()).await?;
+        assert_eq!((summary.blocks_scanned, summary.blocks_missing), (4, 0));
+        let balance = wallets.lock().await.get(None)?.balance().confirmed;
+        assert!(balance > 0);
+
+        // Replaying the top blocks counts nothing twice, and other callers get the wallets while it runs
+        let finished = AtomicBool::new(false);
+        let (summary, locked_while_running) = tokio::join!(
+            async {
+                let summary = wallet::rescan(&wallets, None, chain, 2, None, &JobProgress::new()).await;
+                finished.store(true, Ordering::SeqCst);
+                summary
+            },
+            async {
+                let _wallets = wallets.lock().await;
+                !finished.load(Ordering::SeqCst)
+            },
+        );
+        assert_eq!(summary?.blocks_scanned, 2);
+        assert!(locked_while_running);
+        assert_eq!(wallets.lock().await.get(None)?.balance().confirmed, balance);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_loaded_blocks_wait_for_their_parent_alongside_siblings() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(3).await?;
+        sim.advance(60).await?;
+        let first = sim.mine(0).await?;
+        let first_block = sim.node(0).blockchain.get_block(&first).await?.ok_or("mined block is stored")?;
+        sim.nodes[1].connect((*first_block).clone()).await?;
+        sim.advance(60).await?;
+        let fork = sim.mine(1).await?;
+        let second = sim.mine(0).await?;
+        sim.advance(60).await?;
+        let third = sim.mine(0).await?;
+
+        // Every block comes before its parent, and two share one
+        let datadir = TempDir::new()?;
+        let archive = datadir.path().join("blocks.xca");
+        let mut writer = ArchiveWriter::new(std::fs::File::create(&archive)?, Network::Regtest, 0)?;
+        for (id, hash) in [(0, third), (1, fork), (0, second), (0, first)] {
+            let block = sim.node(id).blockchain.get_block(&hash).await?.ok_or("mined block is stored")?;
+            writer.write_block(&block)?;
+        }
+        writer.finish()?;
+
+        let chain = &sim.node(2).blockchain;
+        let summary = chain.load_blocks(&archive).await?;
+        assert_eq!((summary.connected, summary.skipped, summary.invalid, summary.unconnected), (4, 0, 0, 0));
+        assert_eq!(chain.get_chain_tip(), third);
+        Ok(())
+    }
+
+
+    #[tokio::test]
+    async fn test_verify_chain_checks_the_requested_depth() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..4 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let chain = &sim.node(0).blockchain;
+
+        let report = chain.verify_chain(3, 3, &JobProgress::new()).await?;
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!((report.checked, report.lowest_height, report.pruned_at), (3, Some(2), None));
+
+        let report = chain.verify_chain(100, 3, &JobProgress::new()).await?;
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!((report.checked, report.lowest_height), (5, Some(0)));
+
+        let cancelled = JobProgress::new();
+        cancelled.cancel();
+        let report = chain.verify_chain(100, 3, &cancelled).await?;
+        assert!(report.cancelled && !report.is_ok());
+        assert_eq!(report.checked, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_reindex_refuses_blocks_until_it_finishes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let chain = &sim.node(0).blockchain;
+        let before = chain.utxo_set_info().await;
+        let tip = chain.get_block(&before.best_block).await?.ok_or("tip is stored")?;
+
+        let cancelled = JobProgress::new();
+        cancelled.cancel();
+        assert_eq!(chain.reindex(&cancelled).await?, 0);
+        assert_eq!(chain.get_chain_height(), None);
+        let refused = chain.add_block((*tip).clone()).await.unwrap_err();
+        assert!(refused.to_string().contains("reindexed"), "{}", refused);
+
+        assert_eq!(chain.reindex(&JobProgress::new()).await?, 4);
+        let after = chain.utxo_set_info().await;
+        assert_eq!((after.height, after.best_block, after.hash), (before.height, before.best_block, before.hash));
+        sim.advance(60).await?;
+        sim.mine(0).await?;
+        assert_eq!(sim.node(0).blockchain.get_chain_height(), Some(4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_chain_reports_a_damaged_block_and_checks_on_below_it() -> Result<(), Box<dyn std::error::Error>> {
+        let mut sim = Simulation::new(1).await?;
+        for _ in 0..3 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+        let chain = &sim.node(0).blockchain;
+        chain.sync_block_files()?;
+        let tip = chain.get_block(&chain.get_chain_tip()).await?.ok_or("tip is stored")?;
+
+        // Flip a byte in the middle of the tip's record, compressed as block files store it
+        let level = BlockchainConfig::load(sim.node(0)._datadir.path())?.compression_level;
+        let mut encoder = lz4::EncoderBuilder::new().level(level).build(Vec::new())?;
+        encoder.write_all(&codec::encode(&*tip)?)?;
+        let (record, result) = encoder.finish();
+        result?;
+        let mut damaged = false;
+        for entry in std::fs::read_dir(sim.node(0)._datadir.path().join("blocks"))? {
+            let path = entry?.path();
+            let bytes = std::fs::read(&path)?;
+            if let Some(offset) = bytes.windows(record.len()).position(|window| window == record.as_slice()) {
+                let middle = offset + record.len() / 2;
+                let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+                file.seek(SeekFrom::Start(middle as u64))?;
+                file.write_all(&[bytes[middle] ^ 0xff])?;
+                damaged = true;
+            }
+        }
+        assert!(damaged);
+
+        let report = chain.verify_chain(100, 3, &JobProgress::new()).await?;
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.problems.iter().map(|problem| problem.height).collect::<Vec<_>>(), vec![3]);
+        Ok(())
+    }
+
+
+    #[tokio::test]
+    async fn test_block_proposals_are_checked_without_being_connected() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::blockchain::calculate_merkle_root;
+        let mut sim = Simulation::new(1).await?;
+        sim.advance(60).await?;
+        sim.mine(0).await?;
+        sim.advance(60).await?;
+        let chain = &sim.node(0).blockchain;
+        let (tip, stats) = (chain.get_chain_tip(), chain.utxo_cache_stats().await);
+
+        let block = sim.node(0).build_block(sim.now).await?;
+        chain.check_block_proposal(&block).await?;
+        let mut stale = block.clone();
+        stale.header.previous_hash = [0xee; 32];
+        let mut overpaid = block.clone();
+        overpaid.transactions[0].outputs[0].value += 1;
+        overpaid.header.merkle_root = calculate_merkle_root(&overpaid.transactions);
+        for (proposal, reason) in [(stale, "stale-prevblk"), (overpaid, "bad-cb")] {
+            let error = chain.check_block_proposal(&proposal).await.unwrap_err();
+            assert_eq!(error.downcast_ref::<ValidationError>().and_then(ValidationError::reject_reason), Some(reason));
+        }
+
+        // None of it reached the chain or the coins cached for it
+        assert_eq!(chain.get_chain_tip(), tip);
+        assert!(!chain.has_block(&block.hash()).await?);
+        let after = chain.utxo_cache_stats().await;
+        assert_eq!((after.hits, after.misses), (stats.hits, stats.misses));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_package_fees_check_relative_lock_times() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::transaction::{OutPoint, Transaction, TxInput, TxOutput};
+        use ed25519_dalek::{Signer, SigningKey};
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let spend = |previous_output: OutPoint, value: u64, sequence: u32| -> Result<Transaction, Box<dyn std::error::Error>> {
+            let mut tx = Transaction {
+                inputs: vec![TxInput { previous_output, public_key: key.verifying_key().to_bytes(), signature: Vec::new(), sequence }],
+                outputs: vec![TxOutput { value, script_pubkey: key.verifying_key().to_bytes().to_vec() }],
+                lock_time: 0,
+            };
+            let message = tx.signature_hash()?;
+            tx.inputs[0].signature = key.sign(&message).to_bytes().to_vec();
+            Ok(tx)
+        };
+        let mut sim = Simulation::new(1).await?;
+        sim.nodes[0].miner_key = key.verifying_key().to_bytes();
+        sim.advance(60).await?;
+        let mined = sim.mine(0).await?;
+        let coinbase = sim.node(0).blockchain.get_block(&mined).await?.ok_or("mined block is stored")?.transactions[0].clone();
+        let value = coinbase.outputs[0].value;
+        let coin = OutPoint { txid: coinbase.hash(), index: 0 };
+
+        // The coin is at height 1 and needs three confirmations; the next block is at height 2
+        let parent = spend(coin, value - 1000, 3)?;
+        let refused = sim.node(0).blockchain.package_fees(std::slice::from_ref(&parent), |_| None).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::SequenceLockNotSatisfied(0))));
+        for _ in 0..2 {
+            sim.advance(60).await?;
+            sim.mine(0).await?;
+        }
+
+        // A child can't wait on a parent that is only due in the same block
+        let parent_output = OutPoint { txid: parent.hash(), index: 0 };
+        let waiting = spend(parent_output, value - 3000, 1)?;
+        let refused = sim.node(0).blockchain.package_fees(&[parent.clone(), waiting], |_| None).await.unwrap_err();
+        assert!(matches!(refused.downcast_ref::<ValidationError>(), Some(ValidationError::SequenceLockNotSatisfied(1))));
+        let child = spend(parent_output, value - 3000, 0)?;
+        assert_eq!(sim.node(0).blockchain.package_fees(&[parent, child], |_| None).await?, vec![1000, 2000]);
+        Ok(())
+    }
+
+}
@@ -0,0 +1,214 @@
+use serde::{Serialize, Deserialize};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey, SIGNATURE_LENGTH};
+use blake3;
+use crate::multisig::MultisigWitness;
+use thiserror::Error;
+
+pub type TxHash = [u8; 32];
+
+// lock_time values below this are block heights, at or above it unix timestamps
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+// Relative lock-time encoding in `TxInput::sequence`, as in BIP68
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+#[derive(Error, Debug)]
+pub enum TransactionError {
+    #[error("Failed to serialize transaction: {0}")]
+    SerializationError(#[from] bincode::Error),
+    #[error("Input {0} has a malformed public key")]
+    InvalidPublicKey(usize),
+    #[error("Input {0} has a malformed signature")]
+    MalformedSignature(usize),
+    #[error("Input {0} has an invalid signature")]
+    InvalidSignature(usize),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: TxHash,
+    pub index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxInput {
+    pub previous_output: OutPoint,
+    pub public_key: [u8; 32],
+    pub signature: Vec<u8>,
+    pub sequence: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxOutput {
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+    pub lock_time: u32,
+}
+
+// An unspent output together with where in the chain it was created
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub output: TxOutput,
+    pub height: u64,
+    // Median time past of the block before the one that created the output
+    pub median_time_past: u64,
+    pub is_coinbase: bool,
+}
+
+// Earliest point at which all of a transaction's relative lock-times are satisfied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceLock {
+    // The last height/time that is still locked; -1 means no constraint
+    pub min_height: i64,
+    pub min_time: i64,
+}
+
+impl SequenceLock {
+    // `height` is the height of the block being connected, `median_time_past` that of its parent
+    pub fn is_satisfied(&self, height: u64, median_time_past: u64) -> bool {
+        self.min_height < height as i64 && self.min_time < median_time_past as i64
+    }
+}
+
+impl Transaction {
+    pub fn hash(&self) -> TxHash {
+        blake3::hash(&bincode::serialize(self).unwrap()).into()
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // The message every input signs: the transaction with all signatures blanked out
+    pub fn signature_hash(&self) -> Result<[u8; 32], TransactionError> {
+        let mut unsigned = self.clone();
+        for input in &mut unsigned.inputs {
+            input.signature.clear();
+        }
+        Ok(blake3::hash(&bincode::serialize(&unsigned)?).into())
+    }
+
+    // Absolute lock-time check: height-based locks compare against the block height,
+    // time-based ones against the median time past of the previous block
+    pub fn is_final(&self, height: u64, median_time_past: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let limit = if self.lock_time < LOCKTIME_THRESHOLD { height } else { median_time_past };
+        if (self.lock_time as u64) < limit {
+            return true;
+        }
+        self.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL)
+    }
+
+    // `coins` are the outputs spent by each input, in input order
+    pub fn sequence_lock(&self, coins: &[Coin]) -> SequenceLock {
+        let mut lock = SequenceLock { min_height: -1, min_time: -1 };
+        for (input, coin) in self.inputs.iter().zip(coins) {
+            if input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let value = (input.sequence & SEQUENCE_LOCKTIME_MASK) as i64;
+            if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                let min_time = coin.median_time_past as i64 + (value << SEQUENCE_LOCKTIME_GRANULARITY) - 1;
+                lock.min_time = lock.min_time.max(min_time);
+            } else {
+                lock.min_height = lock.min_height.max(coin.height as i64 + value - 1);
+            }
+        }
+        lock
+    }
+
+    pub fn verify_signatures(&self) -> Result<(), TransactionError> {
+        let message = self.signature_hash()?;
+        for i in 0..self.inputs.len() {
+            self.verify_input(i, &message)?;
+        }
+        Ok(())
+    }
+
+    // Checks input `i`'s signature of `message`, which is `signature_hash()`
+    pub fn verify_input(&self, i: usize, message: &[u8; 32]) -> Result<(), TransactionError> {
+        let input = &self.inputs[i];
+        // Multisig spends name the script's id and carry the script and its signatures; that
+        // the spent output pays that script is checked against the coin during validation
+        if input.signature.len() != SIGNATURE_LENGTH {
+            let witness = MultisigWitness::decode(&input.signature)
+                .map_err(|_| TransactionError::MalformedSignature(i))?;
+            if witness.script.script_id() != input.public_key || witness.verify(message).is_err() {
+                return Err(TransactionError::InvalidSignature(i));
+            }
+            return Ok(());
+        }
+        let key = VerifyingKey::from_bytes(&input.public_key)
+            .map_err(|_| TransactionError::InvalidPublicKey(i))?;
+        let signature = Signature::from_slice(&input.signature)
+            .map_err(|_| TransactionError::MalformedSignature(i))?;
+        key.verify(message, &signature)
+            .map_err(|_| TransactionError::InvalidSignature(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_locks(lock_time: u32, sequences: &[u32]) -> Transaction {
+        let inputs = sequences.iter().enumerate().map(|(i, &sequence)| TxInput {
+            previous_output: OutPoint { txid: [i as u8; 32], index: 0 },
+            public_key: [0; 32],
+            signature: Vec::new(),
+            sequence,
+        }).collect();
+        Transaction { inputs, outputs: Vec::new(), lock_time }
+    }
+
+    fn coin(height: u64, median_time_past: u64) -> Coin {
+        Coin { output: TxOutput { value: 1, script_pubkey: Vec::new() }, height, median_time_past, is_coinbase: false }
+    }
+
+    #[test]
+    fn test_absolute_lock_time() {
+        // Height locks are final once the block's height passes them
+        let tx = with_locks(100, &[0]);
+        assert!(!tx.is_final(100, u64::MAX));
+        assert!(tx.is_final(101, 0));
+
+        // Time locks compare against the previous block's median time past, not the height
+        let time = LOCKTIME_THRESHOLD + 1_000;
+        let tx = with_locks(time, &[0]);
+        assert!(!tx.is_final(u64::MAX, time as u64));
+        assert!(tx.is_final(0, time as u64 + 1));
+
+        // Final sequences on every input opt out of the lock
+        assert!(with_locks(100, &[SEQUENCE_FINAL, SEQUENCE_FINAL]).is_final(0, 0));
+        assert!(!with_locks(100, &[SEQUENCE_FINAL, 0]).is_final(0, 0));
+        assert!(with_locks(0, &[0]).is_final(0, 0));
+    }
+
+    #[test]
+    fn test_relative_lock_time() {
+        // Ten blocks after the coin's height, and 2 * 512 seconds after its median time past
+        let tx = with_locks(0, &[10, SEQUENCE_LOCKTIME_TYPE_FLAG | 2]);
+        let lock = tx.sequence_lock(&[coin(50, 0), coin(0, 10_000)]);
+        assert_eq!(lock, SequenceLock { min_height: 59, min_time: 10_000 + 1024 - 1 });
+        assert!(!lock.is_satisfied(59, 20_000));
+        assert!(!lock.is_satisfied(60, 11_023));
+        assert!(lock.is_satisfied(60, 11_024));
+
+        // Disabled inputs add no constraint
+        let tx = with_locks(0, &[SEQUENCE_LOCKTIME_DISABLE_FLAG | 10, SEQUENCE_FINAL]);
+        let lock = tx.sequence_lock(&[coin(50, 0), coin(50, 0)]);
+        assert_eq!(lock, SequenceLock { min_height: -1, min_time: -1 });
+        assert!(lock.is_satisfied(0, 0));
+    }
+}
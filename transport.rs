@@ -0,0 +1,278 @@
+use parking_lot::Mutex;
+use snow::params::NoiseParams;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, TransportState};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+// XX exchanges both static keys during the handshake, so neither side needs the other's key up front
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+pub const NODE_KEY_FILE_NAME: &str = "node_key";
+const KEY_LEN: usize = 32;
+// Noise caps a single transport message at 65535 bytes including the 16-byte tag
+const MAX_NOISE_FRAME: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_FRAME_PAYLOAD: usize = MAX_NOISE_FRAME - TAG_LEN;
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Noise error: {0}")]
+    Noise(#[from] snow::Error),
+    #[error("Invalid node key: {0}")]
+    InvalidKey(String),
+    #[error("Peer key {0} is not in the trusted set")]
+    UntrustedPeer(String),
+    #[error("Malformed frame: {0}")]
+    Malformed(&'static str),
+    #[error("Message of {size} bytes exceeds the {max} byte limit")]
+    MessageTooLarge { size: usize, max: usize },
+}
+
+// Long-lived x25519 identity the node authenticates with on encrypted connections
+#[derive(Clone)]
+pub struct NodeKey {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl NodeKey {
+    pub fn generate() -> Result<Self, TransportError> {
+        let keypair = Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        Ok(NodeKey { private: keypair.private, public: keypair.public })
+    }
+
+    // Reads `<datadir>/node_key`, creating it on first start so the node keeps its identity across restarts
+    pub fn load_or_generate(datadir: &Path) -> Result<Self, TransportError> {
+        let path = datadir.join(NODE_KEY_FILE_NAME);
+        if path.exists() {
+            let private = fs::read(&path)?;
+            if private.len() != KEY_LEN {
+                return Err(TransportError::InvalidKey(format!("{} is {} bytes, expected {}", path.display(), private.len(), KEY_LEN)));
+            }
+            let public = x25519_public(&private)?;
+            return Ok(NodeKey { private, public });
+        }
+
+        let key = Self::generate()?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path)?;
+        file.write_all(&key.private)?;
+        file.sync_all()?;
+        Ok(key)
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(&self.public)
+    }
+}
+
+fn x25519_public(private: &[u8]) -> Result<Vec<u8>, TransportError> {
+    // Derive it with the same DH implementation the handshake uses, so the two always agree
+    let params: NoiseParams = NOISE_PARAMS.parse()?;
+    let mut dh = DefaultResolver.resolve_dh(&params.dh)
+        .ok_or_else(|| TransportError::InvalidKey("no x25519 implementation available".to_string()))?;
+    dh.set(private);
+    Ok(dh.pubkey().to_vec())
+}
+
+// Hex-encoded public keys allowed to connect. An empty set accepts any peer that completes the handshake.
+pub fn parse_trusted_keys(keys: &[String]) -> Result<HashSet<Vec<u8>>, TransportError> {
+    keys.iter()
+        .map(|key| match hex::decode(key) {
+            Ok(bytes) if bytes.len() == KEY_LEN => Ok(bytes),
+            _ => Err(TransportError::InvalidKey(format!("trusted key '{}' must be {} hex-encoded bytes", key, KEY_LEN))),
+        })
+        .collect()
+}
+
+// A peer connection after a completed Noise handshake. Every message is length-prefixed and split
+// into encrypted frames, so an on-path observer sees neither content nor message boundaries.
+pub struct SecureStream<S> {
+    reader: SecureReader<ReadHalf<S>>,
+    writer: SecureWriter<WriteHalf<S>>,
+    remote_key: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SecureStream<S> {
+    pub async fn connect(stream: S, key: &NodeKey, trusted: &HashSet<Vec<u8>>) -> Result<Self, TransportError> {
+        let handshake = Builder::new(NOISE_PARAMS.parse()?).local_private_key(&key.private).build_initiator()?;
+        Self::handshake(stream, handshake, true, trusted).await
+    }
+
+    pub async fn accept(stream: S, key: &NodeKey, trusted: &HashSet<Vec<u8>>) -> Result<Self, TransportError> {
+        let handshake = Builder::new(NOISE_PARAMS.parse()?).local_private_key(&key.private).build_responder()?;
+        Self::handshake(stream, handshake, false, trusted).await
+    }
+
+    async fn handshake(mut stream: S, mut state: HandshakeState, initiator: bool, trusted: &HashSet<Vec<u8>>) -> Result<Self, TransportError> {
+        let mut buffer = vec![0u8; MAX_NOISE_FRAME];
+        // XX is three messages: -> e, <- e ee s es, -> s se
+        let mut sending = initiator;
+        while !state.is_handshake_finished() {
+            if sending {
+                let len = state.write_message(&[], &mut buffer)?;
+                write_frame(&mut stream, &buffer[..len]).await?;
+            } else {
+                let frame = read_frame(&mut stream).await?;
+                state.read_message(&frame, &mut buffer)?;
+            }
+            sending = !sending;
+        }
+
+        let remote_key = state.get_remote_static()
+            .ok_or_else(|| TransportError::InvalidKey("peer sent no static key".to_string()))?
+            .to_vec();
+        if !trusted.is_empty() && !trusted.contains(&remote_key) {
+            return Err(TransportError::UntrustedPeer(hex::encode(&remote_key)));
+        }
+        let transport = Arc::new(Mutex::new(state.into_transport_mode()?));
+        let (read, write) = tokio::io::split(stream);
+        Ok(SecureStream {
+            reader: SecureReader { stream: read, transport: Arc::clone(&transport), buffer },
+            writer: SecureWriter { stream: write, transport, buffer: vec![0u8; MAX_NOISE_FRAME] },
+            remote_key,
+        })
+    }
+
+    pub fn remote_key(&self) -> &[u8] {
+        &self.remote_key
+    }
+
+    pub async fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        self.writer.send(message).await
+    }
+
+    pub async fn recv(&mut self, max_len: usize) -> Result<Vec<u8>, TransportError> {
+        self.reader.recv(max_len).await
+    }
+
+    // Halves for a reader and a writer running independently; they share the session's cipher state
+    pub fn into_split(self) -> (SecureReader<ReadHalf<S>>, SecureWriter<WriteHalf<S>>) {
+        (self.reader, self.writer)
+    }
+}
+
+// Receiving half of a `SecureStream`
+pub struct SecureReader<R> {
+    stream: R,
+    transport: Arc<Mutex<TransportState>>,
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> SecureReader<R> {
+    // Rejects a message as soon as its declared length exceeds `max_len`, before reading the body
+    pub async fn recv(&mut self, max_len: usize) -> Result<Vec<u8>, TransportError> {
+        let mut plaintext = self.read_decrypted().await?;
+        if plaintext.len() < 4 {
+            return Err(TransportError::Malformed("too short for a length prefix"));
+        }
+        let size = u32::from_be_bytes([plaintext[0], plaintext[1], plaintext[2], plaintext[3]]) as usize;
+        if size > max_len {
+            return Err(TransportError::MessageTooLarge { size, max: max_len });
+        }
+        plaintext.drain(..4);
+        while plaintext.len() < size {
+            let more = self.read_decrypted().await?;
+            plaintext.extend_from_slice(&more);
+        }
+        plaintext.truncate(size);
+        Ok(plaintext)
+    }
+
+    async fn read_decrypted(&mut self) -> Result<Vec<u8>, TransportError> {
+        let frame = read_frame(&mut self.stream).await?;
+        let len = self.transport.lock().read_message(&frame, &mut self.buffer)?;
+        Ok(self.buffer[..len].to_vec())
+    }
+}
+
+// Sending half of a `SecureStream`
+pub struct SecureWriter<W> {
+    stream: W,
+    transport: Arc<Mutex<TransportState>>,
+    buffer: Vec<u8>,
+}
+
+impl<W: AsyncWrite + Unpin> SecureWriter<W> {
+    pub async fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        let mut plaintext = Vec::with_capacity(4 + message.len());
+        plaintext.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        plaintext.extend_from_slice(message);
+        for chunk in plaintext.chunks(MAX_FRAME_PAYLOAD) {
+            let len = self.transport.lock().write_message(chunk, &mut self.buffer)?;
+            write_frame(&mut self.stream, &self.buffer[..len]).await?;
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, frame: &[u8]) -> Result<(), TransportError> {
+    stream.write_all(&(frame.len() as u16).to_be_bytes()).await?;
+    stream.write_all(frame).await?;
+    Ok(())
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>, TransportError> {
+    let len = stream.read_u16().await? as usize;
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_encrypted_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let server_key = NodeKey::load_or_generate(dir.path()).unwrap();
+        // Reloading keeps the same identity
+        assert_eq!(NodeKey::load_or_generate(dir.path()).unwrap().public_key(), server_key.public_key());
+        let client_key = NodeKey::generate().unwrap();
+
+        let trusted = parse_trusted_keys(&[client_key.public_key_hex()]).unwrap();
+        let (client_io, server_io) = tokio::io::duplex(1 << 16);
+        let server = tokio::spawn(async move {
+            let mut stream = SecureStream::accept(server_io, &server_key, &trusted).await.unwrap();
+            let message = stream.recv(1 << 20).await.unwrap();
+            stream.send(&message).await.unwrap();
+        });
+
+        let mut client = SecureStream::connect(client_io, &client_key, &HashSet::new()).await.unwrap();
+        // Spans several Noise frames
+        let message: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        client.send(&message).await.unwrap();
+        assert_eq!(client.recv(1 << 20).await.unwrap(), message);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_peer_is_rejected() {
+        let server_key = NodeKey::generate().unwrap();
+        let client_key = NodeKey::generate().unwrap();
+        let trusted = parse_trusted_keys(&[NodeKey::generate().unwrap().public_key_hex()]).unwrap();
+        let (client_io, server_io) = tokio::io::duplex(1 << 16);
+
+        let client = tokio::spawn(async move { SecureStream::connect(client_io, &client_key, &HashSet::new()).await.map(|_| ()) });
+        let result = SecureStream::accept(server_io, &server_key, &trusted).await;
+        assert!(matches!(result, Err(TransportError::UntrustedPeer(_))));
+        let _ = client.await.unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use crate::inventory::InventoryTracker;
+use crate::protocol::{Inventory, PeerId};
+use crate::transaction::TxHash;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// Average gap between transaction announcements to one outbound peer
+pub const OUTBOUND_TRICKLE_INTERVAL: Duration = Duration::from_secs(2);
+// Inbound peers share one schedule with a longer gap, so opening many connections to us gives an
+// observer no more timing samples than opening one
+pub const INBOUND_TRICKLE_INTERVAL: Duration = Duration::from_secs(5);
+// Most transactions announced to a peer at once; the rest wait for its next turn
+pub const MAX_TRICKLE_INVENTORY: usize = 1000;
+
+struct PeerQueue {
+    inbound: bool,
+    // Outbound peers only; inbound ones follow `next_inbound`
+    next_send: Instant,
+    queued: HashSet<TxHash>,
+}
+
+// Delays and batches transaction announcements. Every peer's announcements go out at random
+// times, with exponentially distributed gaps, and in random order. Our own transactions wait in
+// the same queues as relayed ones, so a peer can't tell them apart by when or in what order they
+// were announced, or by which peers heard first.
+pub struct TrickleRelay {
+    peers: HashMap<PeerId, PeerQueue>,
+    next_inbound: Instant,
+}
+
+impl TrickleRelay {
+    pub fn new(now: Instant) -> Self {
+        TrickleRelay { peers: HashMap::new(), next_inbound: now + poisson_delay(INBOUND_TRICKLE_INTERVAL) }
+    }
+
+    pub fn peer_connected(&mut self, peer: PeerId, inbound: bool, now: Instant) {
+        let next_send = now + poisson_delay(OUTBOUND_TRICKLE_INTERVAL);
+        self.peers.insert(peer, PeerQueue { inbound, next_send, queued: HashSet::new() });
+    }
+
+    pub fn peer_disconnected(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+    }
+
+    // Queues `txid` for every peer except the one it came from, if any
+    pub fn announce(&mut self, txid: TxHash, source: Option<PeerId>) {
+        for (peer, queue) in self.peers.iter_mut() {
+            if Some(*peer) != source {
+                queue.queued.insert(txid);
+            }
+        }
+    }
+
+    // Queues `txid` for one peer, e.g. a rebroadcast from `BroadcastManager::pending_for_peer`
+    pub fn announce_to(&mut self, peer: PeerId, txid: TxHash) {
+        if let Some(queue) = self.peers.get_mut(&peer) {
+            queue.queued.insert(txid);
+        }
+    }
+
+    // Drops a transaction that left the mempool before it was announced
+    pub fn forget(&mut self, txid: &TxHash) {
+        for queue in self.peers.values_mut() {
+            queue.queued.remove(txid);
+        }
+    }
+
+    // Announcements due by `now`, shuffled, for each peer whose turn has come. Transactions `known`
+    // says the peer already has are dropped, and those returned are recorded as known to it.
+    pub fn due(&mut self, now: Instant, known: &mut InventoryTracker) -> Vec<(PeerId, Vec<TxHash>)> {
+        let inbound_due = now >= self.next_inbound;
+        if inbound_due {
+            self.next_inbound = now + poisson_delay(INBOUND_TRICKLE_INTERVAL);
+        }
+        let mut rng = rand::thread_rng();
+        let mut batches = Vec::new();
+        for (peer, queue) in self.peers.iter_mut() {
+            let turn = if queue.inbound { inbound_due } else { now >= queue.next_send };
+            if !turn {
+                continue;
+            }
+            if !queue.inbound {
+                queue.next_send = now + poisson_delay(OUTBOUND_TRICKLE_INTERVAL);
+            }
+            queue.queued.retain(|txid| !known.knows(*peer, &Inventory::Transaction(*txid)));
+            if queue.queued.is_empty() {
+                continue;
+            }
+            let mut batch = queue.queued.iter().copied().collect::<Vec<_>>();
+            batch.shuffle(&mut rng);
+            batch.truncate(MAX_TRICKLE_INVENTORY);
+            for txid in &batch {
+                queue.queued.remove(txid);
+                known.mark_known(*peer, &Inventory::Transaction(*txid));
+            }
+            batches.push((*peer, batch));
+        }
+        batches
+    }
+
+    // Earliest time `due` may return anything, for the caller's timer
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        self.peers.values()
+            .filter(|queue| !queue.queued.is_empty())
+            .map(|queue| if queue.inbound { self.next_inbound } else { queue.next_send })
+            .min()
+    }
+
+    pub fn queued(&self, peer: PeerId) -> usize {
+        self.peers.get(&peer).map_or(0, |queue| queue.queued.len())
+    }
+}
+
+// Exponentially distributed with mean `mean`, so announcement times form a Poisson process
+fn poisson_delay(mean: Duration) -> Duration {
+    let uniform = 1.0 - rand::thread_rng().gen::<f64>();
+    mean.mul_f64(-uniform.ln())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txid(i: u32) -> TxHash {
+        let mut txid = [0; 32];
+        txid[..4].copy_from_slice(&i.to_le_bytes());
+        txid
+    }
+
+    #[test]
+    fn test_announcements_are_delayed_batched_and_bounded() {
+        let start = Instant::now();
+        let mut relay = TrickleRelay::new(start);
+        relay.peer_connected(1, false, start);
+        relay.peer_connected(2, true, start);
+        relay.peer_connected(3, true, start);
+
+        let txids = (0..MAX_TRICKLE_INVENTORY as u32 + 10).map(txid).collect::<Vec<_>>();
+        for txid in &txids {
+            relay.announce(*txid, Some(3));
+        }
+        assert_eq!((relay.queued(1), relay.queued(3)), (txids.len(), 0));
+        assert!(relay.next_wakeup().is_some_and(|wakeup| wakeup >= start));
+
+        // Far past any realistic delay, every peer with a queue gets a full batch
+        let mut known = InventoryTracker::new();
+        let later = start + Duration::from_secs(600);
+        let mut batches = relay.due(later, &mut known);
+        batches.sort_by_key(|(peer, _)| *peer);
+        assert_eq!(batches.iter().map(|(peer, batch)| (*peer, batch.len())).collect::<Vec<_>>(), vec![(1, MAX_TRICKLE_INVENTORY), (2, MAX_TRICKLE_INVENTORY)]);
+        // Random order, not the order they were queued in
+        assert_ne!(batches[0].1.as_slice(), &txids[..MAX_TRICKLE_INVENTORY]);
+
+        relay.forget(&txids[0]);
+        let rest = relay.due(later + Duration::from_secs(600), &mut known).into_iter().map(|(_, batch)| batch.len()).sum::<usize>();
+        assert!(rest <= 20 && relay.queued(1) + relay.queued(2) == 0);
+
+        // Nothing is announced twice, nor to a peer that announced it to us after it was queued
+        let fresh = txid(u32::MAX);
+        relay.announce(txids[1], Some(3));
+        relay.announce(fresh, None);
+        known.mark_known(2, &Inventory::Transaction(fresh));
+        let mut batches = relay.due(later + Duration::from_secs(1200), &mut known);
+        batches.sort_by_key(|(peer, _)| *peer);
+        assert_eq!(batches, vec![(1, vec![fresh]), (3, vec![fresh])]);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::storage::Storage;
+use crate::transaction::{Coin, OutPoint};
+use crate::utxo_set_hash::UtxoSetSummary;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+struct CacheEntry {
+    // `None` marks a coin spent since the last flush
+    coin: Option<Coin>,
+    dirty: bool,
+    // Created since the last flush, so the database has never seen it
+    fresh: bool,
+}
+
+impl CacheEntry {
+    fn memory_usage(&self) -> usize {
+        size_of::<OutPoint>() + size_of::<CacheEntry>()
+            + self.coin.as_ref().map_or(0, |c| c.output.script_pubkey.capacity())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UtxoCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub flushes: u64,
+    pub entries_flushed: u64,
+}
+
+impl UtxoCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+pub struct UtxoCache {
+    storage: Storage,
+    entries: HashMap<OutPoint, CacheEntry>,
+    memory_budget_bytes: usize,
+    memory_usage_bytes: usize,
+    flush_interval: Duration,
+    last_flush: Instant,
+    stats: UtxoCacheStats,
+    // Covers unflushed changes too, so it always describes the set as of the chain tip
+    summary: UtxoSetSummary,
+    // Block the cached set is as of, and the one it was last flushed at
+    best_block: Option<[u8; 32]>,
+    flushed_best_block: Option<[u8; 32]>,
+}
+
+// The set as seen through the cache, read-only. Coins it has to load from the database stay in the
+// view, so checking a block that may never connect leaves the cache as it was; one that does connect
+// hands them to the cache with `UtxoCache::keep`.
+pub struct UtxoView<'a> {
+    cache: &'a UtxoCache,
+    loaded: HashMap<OutPoint, Coin>,
+}
+
+impl UtxoView<'_> {
+    pub async fn get(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>, Box<dyn std::error::Error>> {
+        if let Some(entry) = self.cache.entries.get(outpoint) {
+            return Ok(entry.coin.clone());
+        }
+        if let Some(coin) = self.loaded.get(outpoint) {
+            return Ok(Some(coin.clone()));
+        }
+        let coin = self.cache.storage.get_utxo(outpoint).await?;
+        if let Some(coin) = &coin {
+            self.loaded.insert(*outpoint, coin.clone());
+        }
+        Ok(coin)
+    }
+
+    pub fn summary(&self) -> &UtxoSetSummary {
+        &self.cache.summary
+    }
+
+    pub fn into_loaded(self) -> HashMap<OutPoint, Coin> {
+        self.loaded
+    }
+}
+
+impl UtxoCache {
+    pub fn new(storage: Storage, memory_budget_mb: usize, flush_interval_secs: u64) -> Self {
+        UtxoCache {
+            storage,
+            entries: HashMap::new(),
+            memory_budget_bytes: memory_budget_mb * 1024 * 1024,
+            memory_usage_bytes: 0,
+            flush_interval: Duration::from_secs(flush_interval_secs),
+            last_flush: Instant::now(),
+            stats: UtxoCacheStats::default(),
+            summary: UtxoSetSummary::default(),
+            best_block: None,
+            flushed_best_block: None,
+        }
+    }
+
+    pub async fn get(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>, Box<dyn std::error::Error>> {
+        if let Some(entry) = self.entries.get(outpoint) {
+            self.stats.hits += 1;
+            return Ok(entry.coin.clone());
+        }
+
+        self.stats.misses += 1;
+        let coin = self.storage.get_utxo(outpoint).await?;
+        if let Some(coin) = &coin {
+            self.insert(*outpoint, CacheEntry { coin: Some(coin.clone()), dirty: false, fresh: false });
+        }
+        Ok(coin)
+    }
+
+    pub fn view(&self) -> UtxoView<'_> {
+        UtxoView { cache: self, loaded: HashMap::new() }
+    }
+
+    // Caches coins a view loaded, counting them as the misses they were
+    pub fn keep(&mut self, loaded: HashMap<OutPoint, Coin>) {
+        for (outpoint, coin) in loaded {
+            if !self.entries.contains_key(&outpoint) {
+                self.stats.misses += 1;
+                self.insert(outpoint, CacheEntry { coin: Some(coin), dirty: false, fresh: false });
+            }
+        }
+    }
+
+    // Refuses an outpoint that is already unspent, cached or not: validation rejects blocks that
+    // would do that (BIP30), and overwriting would leave the set summary counting both coins
+    pub async fn add(&mut self, outpoint: OutPoint, coin: Coin) -> Result<(), Box<dyn std::error::Error>> {
+        if self.get(&outpoint).await?.is_some() {
+            return Err(format!("output {}:{} is already unspent", hex::encode(outpoint.txid), outpoint.index).into());
+        }
+        // A coin spent since the last flush may still be in the database, so this one must be written
+        // over it rather than dropped if it is spent before the next flush
+        let fresh = self.entries.get(&outpoint).is_none_or(|entry| entry.fresh);
+        self.summary.insert(&outpoint, &coin);
+        self.insert(outpoint, CacheEntry { coin: Some(coin), dirty: true, fresh });
+        Ok(())
+    }
+
+    // Puts back a coin a disconnected block had spent. Unlike `add` the entry is never fresh: the
+    // database may still hold the coin or a pending delete of it, so the restore must be written.
+    pub fn restore(&mut self, outpoint: OutPoint, coin: Coin) {
+        self.summary.insert(&outpoint, &coin);
+        self.insert(outpoint, CacheEntry { coin: Some(coin), dirty: true, fresh: false });
+    }
+
+    // Returns the spent coin, or `None` if it was missing or already spent
+    pub async fn spend(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>, Box<dyn std::error::Error>> {
+        let coin = match self.get(outpoint).await? {
+            Some(coin) => coin,
+            None => return Ok(None),
+        };
+
+        self.summary.remove(outpoint, &coin);
+        let entry = self.remove(outpoint).expect("entry was loaded by get");
+        if !entry.fresh {
+            self.insert(*outpoint, CacheEntry { coin: None, dirty: true, fresh: false });
+        }
+        Ok(Some(coin))
+    }
+
+    pub fn needs_flush(&self) -> bool {
+        self.memory_usage_bytes > self.memory_budget_bytes
+            || self.last_flush.elapsed() >= self.flush_interval
+    }
+
+    pub async fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let changes = self.entries.iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(outpoint, entry)| (*outpoint, entry.coin.clone()))
+            .collect::<Vec<_>>();
+        let flushed = changes.len() as u64;
+
+        if !changes.is_empty() || self.best_block != self.flushed_best_block {
+            self.storage.write_utxo_batch(changes, &self.summary, self.best_block).await?;
+            self.flushed_best_block = self.best_block;
+        }
+
+        self.entries.retain(|_, entry| entry.coin.is_some());
+        for entry in self.entries.values_mut() {
+            entry.dirty = false;
+            entry.fresh = false;
+        }
+        if self.recalculate_usage() > self.memory_budget_bytes {
+            self.entries.clear();
+            self.memory_usage_bytes = 0;
+        }
+
+        self.stats.flushes += 1;
+        self.stats.entries_flushed += flushed;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    // Forgets everything, including unflushed changes, for callers rebuilding the set from scratch
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.memory_usage_bytes = 0;
+        self.summary = UtxoSetSummary::default();
+        self.best_block = None;
+        self.flushed_best_block = None;
+    }
+
+    // Records the block the set is now as of, after connecting or disconnecting one
+    pub fn set_best_block(&mut self, hash: Option<[u8; 32]>) {
+        self.best_block = hash;
+    }
+
+    pub fn summary(&self) -> &UtxoSetSummary {
+        &self.summary
+    }
+
+    // Adopts the summary of the set as last flushed, on startup or after `clear`
+    pub fn set_summary(&mut self, summary: UtxoSetSummary) {
+        self.summary = summary;
+    }
+
+    pub fn stats(&self) -> UtxoCacheStats {
+        self.stats
+    }
+
+    pub fn memory_usage_bytes(&self) -> usize {
+        self.memory_usage_bytes
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, entry: CacheEntry) {
+        self.memory_usage_bytes += entry.memory_usage();
+        if let Some(old) = self.entries.insert(outpoint, entry) {
+            self.memory_usage_bytes = self.memory_usage_bytes.saturating_sub(old.memory_usage());
+        }
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<CacheEntry> {
+        let entry = self.entries.remove(outpoint)?;
+        self.memory_usage_bytes = self.memory_usage_bytes.saturating_sub(entry.memory_usage());
+        Some(entry)
+    }
+
+    fn recalculate_usage(&mut self) -> usize {
+        self.memory_usage_bytes = self.entries.values().map(CacheEntry::memory_usage).sum();
+        self.memory_usage_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node_config::DatabaseConfig;
+    use crate::transaction::TxOutput;
+    use tempfile::TempDir;
+
+    fn coin(value: u64) -> Coin {
+        Coin { output: TxOutput { value, script_pubkey: vec![0x51] }, height: 1, median_time_past: 0, is_coinbase: true }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_outputs_are_refused_whether_cached_or_not() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let storage = Storage::new(temp_dir.path().to_str().unwrap(), &DatabaseConfig::default()).await?;
+        let outpoint = OutPoint { txid: [1; 32], index: 0 };
+        let mut warm = UtxoCache::new(storage.clone(), 16, 60);
+        warm.add(outpoint, coin(50)).await?;
+        warm.flush().await?;
+
+        let mut cold = UtxoCache::new(storage.clone(), 16, 60);
+        cold.set_summary(storage.get_utxo_set_summary().await?.unwrap_or_default());
+        assert!(cold.add(outpoint, coin(70)).await.is_err());
+        assert!(warm.add(outpoint, coin(70)).await.is_err());
+        assert_eq!(cold.summary(), warm.summary());
+        assert_eq!(warm.summary().total_amount, 50);
+        assert_eq!(warm.get(&outpoint).await?, Some(coin(50)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_coin_recreated_after_a_spend_is_deleted_when_spent_again() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let storage = Storage::new(temp_dir.path().to_str().unwrap(), &DatabaseConfig::default()).await?;
+        let outpoint = OutPoint { txid: [1; 32], index: 0 };
+        let mut cache = UtxoCache::new(storage.clone(), 16, 60);
+        cache.add(outpoint, coin(50)).await?;
+        cache.flush().await?;
+
+        // The database still holds the first coin until the next flush deletes it
+        cache.spend(&outpoint).await?;
+        cache.add(outpoint, coin(70)).await?;
+        cache.spend(&outpoint).await?;
+        cache.flush().await?;
+        assert_eq!(storage.get_utxo(&outpoint).await?, None);
+        assert_eq!(cache.summary(), &UtxoSetSummary::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_views_leave_the_cache_alone_until_kept() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let storage = Storage::new(temp_dir.path().to_str().unwrap(), &DatabaseConfig::default()).await?;
+        let (stored, cached) = (OutPoint { txid: [1; 32], index: 0 }, OutPoint { txid: [2; 32], index: 0 });
+        let mut warm = UtxoCache::new(storage.clone(), 16, 60);
+        warm.add(stored, coin(50)).await?;
+        warm.flush().await?;
+        let mut cache = UtxoCache::new(storage, 16, 60);
+        cache.add(cached, coin(70)).await?;
+        let usage = cache.memory_usage_bytes();
+
+        let mut view = cache.view();
+        assert_eq!(view.get(&stored).await?, Some(coin(50)));
+        assert_eq!(view.get(&cached).await?, Some(coin(70)));
+        let loaded = view.into_loaded();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), vec![&stored]);
+        assert_eq!(cache.memory_usage_bytes(), usage);
+
+        let misses = cache.stats().misses;
+        cache.keep(loaded);
+        assert!(cache.memory_usage_bytes() > usage);
+        assert_eq!(cache.stats().misses, misses + 1);
+        assert_eq!(cache.spend(&stored).await?, Some(coin(50)));
+        assert_eq!(cache.stats().misses, misses + 1);
+        Ok(())
+    }
+}
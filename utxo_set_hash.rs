@@ -0,0 +1,157 @@
+use crate::transaction::{Coin, OutPoint};
+use thiserror::Error;
+
+// Multiset hash of the UTXO set: every coin expands to LANES 16-bit lanes through the blake3 XOF and
+// the set hash is their lane-wise sum modulo 2^16 (LtHash). Adding and removing coins commute, so the
+// hash is maintained incrementally and two nodes with the same coins agree whatever order they were
+// connected in. It plays the role MuHash does elsewhere without needing 3072-bit arithmetic.
+const LANES: usize = 1024;
+const DERIVE_KEY_CONTEXT: &str = "xcore utxo set hash v1";
+// coins, total amount, then the lanes
+pub const SUMMARY_LEN: usize = 8 + 8 + LANES * 2;
+
+#[derive(Error, Debug)]
+#[error("UTXO set summary is {0} bytes, expected {SUMMARY_LEN}")]
+pub struct SummaryLengthError(usize);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoSetHash {
+    lanes: Box<[u16; LANES]>,
+}
+
+impl Default for UtxoSetHash {
+    fn default() -> Self {
+        UtxoSetHash { lanes: Box::new([0; LANES]) }
+    }
+}
+
+impl UtxoSetHash {
+    pub fn insert(&mut self, outpoint: &OutPoint, coin: &Coin) {
+        for (lane, value) in self.lanes.iter_mut().zip(element(outpoint, coin)) {
+            *lane = lane.wrapping_add(value);
+        }
+    }
+
+    pub fn remove(&mut self, outpoint: &OutPoint, coin: &Coin) {
+        for (lane, value) in self.lanes.iter_mut().zip(element(outpoint, coin)) {
+            *lane = lane.wrapping_sub(value);
+        }
+    }
+
+    // Short form for display and comparison
+    pub fn digest(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        for lane in self.lanes.iter() {
+            hasher.update(&lane.to_le_bytes());
+        }
+        *hasher.finalize().as_bytes()
+    }
+}
+
+// The coin's fields in a fixed layout, so the hash doesn't change with the storage encoding
+fn element(outpoint: &OutPoint, coin: &Coin) -> [u16; LANES] {
+    let mut hasher = blake3::Hasher::new_derive_key(DERIVE_KEY_CONTEXT);
+    hasher.update(&outpoint.txid);
+    hasher.update(&outpoint.index.to_le_bytes());
+    hasher.update(&coin.height.to_le_bytes());
+    hasher.update(&coin.median_time_past.to_le_bytes());
+    hasher.update(&[coin.is_coinbase as u8]);
+    hasher.update(&coin.output.value.to_le_bytes());
+    hasher.update(&(coin.output.script_pubkey.len() as u64).to_le_bytes());
+    hasher.update(&coin.output.script_pubkey);
+
+    let mut bytes = [0u8; LANES * 2];
+    hasher.finalize_xof().fill(&mut bytes);
+    let mut lanes = [0u16; LANES];
+    for (lane, chunk) in lanes.iter_mut().zip(bytes.chunks_exact(2)) {
+        *lane = u16::from_le_bytes([chunk[0], chunk[1]]);
+    }
+    lanes
+}
+
+// Running totals over the UTXO set, kept alongside it so they are always in step with the coins
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UtxoSetSummary {
+    pub coins: u64,
+    pub total_amount: u64,
+    pub hash: UtxoSetHash,
+}
+
+impl UtxoSetSummary {
+    pub fn insert(&mut self, outpoint: &OutPoint, coin: &Coin) {
+        self.coins = self.coins.wrapping_add(1);
+        self.total_amount = self.total_amount.wrapping_add(coin.output.value);
+        self.hash.insert(outpoint, coin);
+    }
+
+    pub fn remove(&mut self, outpoint: &OutPoint, coin: &Coin) {
+        self.coins = self.coins.wrapping_sub(1);
+        self.total_amount = self.total_amount.wrapping_sub(coin.output.value);
+        self.hash.remove(outpoint, coin);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SUMMARY_LEN);
+        bytes.extend_from_slice(&self.coins.to_be_bytes());
+        bytes.extend_from_slice(&self.total_amount.to_be_bytes());
+        for lane in self.hash.lanes.iter() {
+            bytes.extend_from_slice(&lane.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SummaryLengthError> {
+        if bytes.len() != SUMMARY_LEN {
+            return Err(SummaryLengthError(bytes.len()));
+        }
+        let mut hash = UtxoSetHash::default();
+        for (lane, chunk) in hash.lanes.iter_mut().zip(bytes[16..].chunks_exact(2)) {
+            *lane = u16::from_le_bytes([chunk[0], chunk[1]]);
+        }
+        Ok(UtxoSetSummary {
+            coins: u64::from_be_bytes(bytes[..8].try_into().expect("slice is 8 bytes")),
+            total_amount: u64::from_be_bytes(bytes[8..16].try_into().expect("slice is 8 bytes")),
+            hash,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxOutput;
+
+    fn coin(value: u64) -> Coin {
+        Coin { output: TxOutput { value, script_pubkey: vec![1, 2, 3] }, height: 5, median_time_past: 0, is_coinbase: false }
+    }
+
+    #[test]
+    fn test_hash_is_order_independent_and_invertible() -> Result<(), SummaryLengthError> {
+        let coins = (0..3u32).map(|index| (OutPoint { txid: [7; 32], index }, coin(index as u64 * 10))).collect::<Vec<_>>();
+
+        let mut forward = UtxoSetSummary::default();
+        for (outpoint, coin) in &coins {
+            forward.insert(outpoint, coin);
+        }
+        let mut backward = UtxoSetSummary::default();
+        for (outpoint, coin) in coins.iter().rev() {
+            backward.insert(outpoint, coin);
+        }
+        assert_eq!(forward, backward);
+        assert_eq!((forward.coins, forward.total_amount), (3, 30));
+
+        // Any field of the coin changes the hash
+        let mut other = forward.clone();
+        other.remove(&coins[1].0, &coins[1].1);
+        other.insert(&coins[1].0, &Coin { height: 6, ..coins[1].1.clone() });
+        assert_ne!(other.hash.digest(), forward.hash.digest());
+
+        let restored = UtxoSetSummary::from_bytes(&forward.to_bytes())?;
+        assert_eq!(restored, forward);
+        for (outpoint, coin) in &coins {
+            forward.remove(outpoint, coin);
+        }
+        assert_eq!(forward, UtxoSetSummary::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+use crate::blockchain::{Block, BlockHeader, BlockType, calculate_fruits_root, calculate_merkle_root};
+use crate::chain_params::ChainParams;
+use crate::multisig;
+use crate::pow;
+use crate::reward::RewardError;
+use crate::script_cache::{ScriptCache, SCRIPT_CACHE_ENTRIES, SCRIPT_VERIFY_FLAGS};
+use crate::transaction::{OutPoint, Transaction, TransactionError, TxInput, TxOutput};
+use primitive_types::U256;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::collections::HashSet;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+#[derive(Error, Debug)]
+pub enum ValidationError {
+    #[error("Block contains no transactions")]
+    EmptyBlock,
+    #[error("Block hash does not meet its proof of work target")]
+    InsufficientProofOfWork,
+    #[error("Fruits cannot be connected to the chain as blocks")]
+    NotABlock,
+    #[error("Block includes fruit {0} more than once")]
+    DuplicateFruit(usize),
+    #[error("Block has {count} fruits, more than the maximum of {max}")]
+    TooManyFruits { count: usize, max: usize },
+    #[error("Fruit {0} does not hang from a recent ancestor within the freshness window")]
+    StaleFruit(usize),
+    #[error("Fruit {0} was already included by a recent block")]
+    FruitAlreadyIncluded(usize),
+    #[error("Fruit {0} does not meet the fruit target")]
+    FruitProofOfWork(usize),
+    #[error("Merkle root does not match block transactions")]
+    BadMerkleRoot,
+    #[error("Fruits root does not match block fruits")]
+    BadFruitsRoot,
+    #[error("Block builds on {}, not the chain tip {}", hex::encode(.actual), hex::encode(.expected))]
+    PrevBlockMismatch { expected: [u8; 32], actual: [u8; 32] },
+    #[error("Header builds on unknown block {}", hex::encode(.0))]
+    UnknownParent([u8; 32]),
+    #[error("Block timestamp {timestamp} is later than the allowed {max}")]
+    TimestampTooNew { timestamp: u64, max: u64 },
+    #[error("Block bits {actual:#010x} do not match the expected {expected:#010x}")]
+    BadBits { expected: u32, actual: u32 },
+    #[error("Duplicate transaction at index {0}")]
+    DuplicateTransaction(usize),
+    #[error("Block has {count} transactions, more than the maximum of {max}")]
+    TooManyTransactions { count: usize, max: usize },
+    #[error("Block is {size} bytes, larger than the maximum of {max}")]
+    BlockTooLarge { size: usize, max: usize },
+    #[error("Transaction {index} is {size} bytes, larger than the maximum of {max}")]
+    TransactionTooLarge { index: usize, size: usize, max: usize },
+    #[error("Failed to serialize block: {0}")]
+    Serialization(#[from] bincode::Error),
+    #[error("Only the first transaction may be a coinbase (found one at index {0})")]
+    MisplacedCoinbase(usize),
+    #[error("Transaction {index} failed verification: {source}")]
+    Transaction { index: usize, source: TransactionError },
+    #[error("Input spends missing or already spent output {0:?}")]
+    MissingInput(OutPoint),
+    #[error("Input {input} of transaction {tx} does not name the script of the output it spends")]
+    ScriptMismatch { tx: usize, input: usize },
+    #[error("Input {input} of transaction {tx} spends an output whose script can never be spent")]
+    UnspendableOutput { tx: usize, input: usize },
+    #[error("Transaction {0} is not final at this height and time")]
+    NonFinalTransaction(usize),
+    #[error("Transaction {0} spends an output whose relative lock-time has not expired")]
+    SequenceLockNotSatisfied(usize),
+    #[error("Transaction {0} spends less than its outputs are worth")]
+    OutputsExceedInputs(usize),
+    #[error("Transaction {0} spends inputs worth more than can be counted")]
+    InputValueOverflow(usize),
+    #[error("Transaction {0} creates an output that already exists unspent")]
+    OverwritesUnspentOutput(usize),
+    #[error("Invalid coinbase: {0}")]
+    Coinbase(#[from] RewardError),
+    #[error("Failed to build verification thread pool: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    #[error("Block {} was marked invalid", hex::encode(.0))]
+    MarkedInvalid([u8; 32]),
+    #[error("Headers fork off with chain work {work}, less than the required {required}")]
+    InsufficientChainWork { work: U256, required: U256 },
+}
+
+impl ValidationError {
+    // How much a peer sending a block that fails this way should count against it, out of 100 for
+    // a disconnect. Failures that depend on our own view, like a different tip or clock, cost nothing.
+    pub fn misbehavior(&self) -> u32 {
+        match self {
+            ValidationError::PrevBlockMismatch { .. } | ValidationError::TimestampTooNew { .. } => 0,
+            // We may just not have synced the headers before it yet
+            ValidationError::UnknownParent(_) => 0,
+            ValidationError::Serialization(_) | ValidationError::ThreadPool(_) => 0,
+            // The operator's call rather than a consensus failure
+            ValidationError::MarkedInvalid(_) => 0,
+            // Honest peers can be on a short-lived fork we've already left behind
+            ValidationError::InsufficientChainWork { .. } => 0,
+            // A fruit may have gone stale between the peer seeing the block and us
+            ValidationError::StaleFruit(_) => 10,
+            _ => 100,
+        }
+    }
+
+    // Short code for why a block was refused, as getblocktemplate reports for rejected proposals.
+    // None for failures that aren't the block's fault.
+    pub fn reject_reason(&self) -> Option<&'static str> {
+        Some(match self {
+            ValidationError::PrevBlockMismatch { .. } => "stale-prevblk",
+            ValidationError::UnknownParent(_) => "prev-blk-not-found",
+            ValidationError::InsufficientProofOfWork => "high-hash",
+            ValidationError::BadBits { .. } => "bad-diffbits",
+            ValidationError::TimestampTooNew { .. } => "time-too-new",
+            ValidationError::NotABlock => "bad-blk-type",
+            ValidationError::EmptyBlock | ValidationError::TooManyTransactions { .. } | ValidationError::BlockTooLarge { .. } => "bad-blk-length",
+            ValidationError::DuplicateFruit(_) | ValidationError::TooManyFruits { .. } | ValidationError::StaleFruit(_)
+            | ValidationError::FruitAlreadyIncluded(_) | ValidationError::FruitProofOfWork(_) | ValidationError::BadFruitsRoot => "bad-fruit-set",
+            ValidationError::BadMerkleRoot => "bad-txnmrklroot",
+            ValidationError::DuplicateTransaction(_) => "bad-txns-duplicate",
+            ValidationError::OverwritesUnspentOutput(_) => "bad-txns-BIP30",
+            ValidationError::MisplacedCoinbase(_) | ValidationError::Coinbase(_) => "bad-cb",
+            ValidationError::TransactionTooLarge { .. } | ValidationError::Transaction { .. } | ValidationError::MissingInput(_)
+            | ValidationError::ScriptMismatch { .. } | ValidationError::UnspendableOutput { .. } | ValidationError::NonFinalTransaction(_)
+            | ValidationError::SequenceLockNotSatisfied(_) | ValidationError::OutputsExceedInputs(_)
+            | ValidationError::InputValueOverflow(_) => "bad-txn",
+            ValidationError::MarkedInvalid(_) => "duplicate-invalid",
+            ValidationError::InsufficientChainWork { .. } => "low-work-chain",
+            ValidationError::Serialization(_) | ValidationError::ThreadPool(_) => return None,
+        })
+    }
+}
+
+pub struct BlockValidator {
+    pool: ThreadPool,
+    params: ChainParams,
+    scripts: Arc<ScriptCache>,
+}
+
+impl BlockValidator {
+    // `threads == 0` lets rayon pick one thread per logical CPU
+    pub fn new(threads: usize, params: ChainParams) -> Result<Self, ValidationError> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("sigcheck-{}", i))
+            .build()?;
+        Ok(Self { pool, params, scripts: Arc::new(ScriptCache::new(SCRIPT_CACHE_ENTRIES)) })
+    }
+
+    pub fn validate_block(&self, block: &Block) -> Result<(), ValidationError> {
+        check_block_structure(block, &self.params)?;
+        self.verify_signatures(block)
+    }
+
+    pub fn verify_signatures(&self, block: &Block) -> Result<(), ValidationError> {
+        self.pool.install(|| check_signatures(block, &self.scripts))
+    }
+
+    // Checks a transaction offered to the mempool, caching its inputs so the block that
+    // confirms it doesn't check them again
+    pub fn verify_transaction(&self, tx: &Transaction) -> Result<(), TransactionError> {
+        self.scripts.verify(tx, SCRIPT_VERIFY_FLAGS, true)
+    }
+
+    // Queues `block`'s signatures on the pool and returns at once, so they can be checked while
+    // earlier blocks are still being connected
+    pub fn spawn_signature_check(&self, block: Arc<Block>) -> SignatureCheck {
+        let (sender, receiver) = oneshot::channel();
+        let scripts = Arc::clone(&self.scripts);
+        self.pool.spawn(move || {
+            // The block may have failed another check and been given up on
+            let _ = sender.send(check_signatures(&block, &scripts));
+        });
+        receiver
+    }
+}
+
+// Result of a check started by `BlockValidator::spawn_signature_check`
+pub type SignatureCheck = oneshot::Receiver<Result<(), ValidationError>>;
+
+// Run inside the pool, where par_iter spreads the transactions over its threads. Inputs already
+// checked on mempool admission are skipped.
+fn check_signatures(block: &Block, scripts: &ScriptCache) -> Result<(), ValidationError> {
+    block.transactions
+        .par_iter()
+        .enumerate()
+        .filter(|(_, tx)| !tx.is_coinbase())
+        .try_for_each(|(index, tx)| {
+            scripts.verify(tx, SCRIPT_VERIFY_FLAGS, false)
+                .map_err(|source| ValidationError::Transaction { index, source })
+        })
+}
+
+// The only check a header passes on its own; anything more needs the chain it extends
+pub fn check_header(header: &BlockHeader) -> Result<(), ValidationError> {
+    if !pow::check_proof_of_work(&header.hash(), header.bits) {
+        return Err(ValidationError::InsufficientProofOfWork);
+    }
+    Ok(())
+}
+
+// An input must name the key a single-key output pays, or the script id of a multisig output.
+// `verify_signatures` has already tied that key or id to the signature or witness the input
+// carries. Outputs with any other script can't be spent at all.
+pub fn check_input_script(tx: usize, input: usize, spending: &TxInput, spent: &TxOutput) -> Result<(), ValidationError> {
+    match multisig::spending_key(&spent.script_pubkey) {
+        Some(key) if key == spending.public_key => Ok(()),
+        Some(_) => Err(ValidationError::ScriptMismatch { tx, input }),
+        None => Err(ValidationError::UnspendableOutput { tx, input }),
+    }
+}
+
+// Fee transaction `index` of a block or package pays, given the values of the outputs it spends
+pub fn transaction_fee(index: usize, tx: &Transaction, input_values: impl IntoIterator<Item = u64>) -> Result<u64, ValidationError> {
+    let input_value = input_values.into_iter()
+        .try_fold(0u64, |sum, value| sum.checked_add(value))
+        .ok_or(ValidationError::InputValueOverflow(index))?;
+    tx.outputs.iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+        .and_then(|output_value| input_value.checked_sub(output_value))
+        .ok_or(ValidationError::OutputsExceedInputs(index))
+}
+
+// Cheap checks that don't touch signatures, run before fanning out to the pool
+pub fn check_block_structure(block: &Block, params: &ChainParams) -> Result<(), ValidationError> {
+    if block.block_type != BlockType::Block || block.fruit_header.is_some() {
+        return Err(ValidationError::NotABlock);
+    }
+    if block.fruits.len() > params.max_fruits_per_block {
+        return Err(ValidationError::TooManyFruits { count: block.fruits.len(), max: params.max_fruits_per_block });
+    }
+    let mut seen_fruits = HashSet::with_capacity(block.fruits.len());
+    for (index, fruit) in block.fruits.iter().enumerate() {
+        if !seen_fruits.insert(fruit) {
+            return Err(ValidationError::DuplicateFruit(index));
+        }
+    }
+    if block.transactions.is_empty() {
+        return Err(ValidationError::EmptyBlock);
+    }
+    if block.transactions.len() > params.max_transactions_per_block {
+        return Err(ValidationError::TooManyTransactions {
+            count: block.transactions.len(),
+            max: params.max_transactions_per_block,
+        });
+    }
+    let block_size = bincode::serialized_size(block)? as usize;
+    if block_size > params.max_block_size {
+        return Err(ValidationError::BlockTooLarge { size: block_size, max: params.max_block_size });
+    }
+
+    let mut seen = HashSet::with_capacity(block.transactions.len());
+    for (index, tx) in block.transactions.iter().enumerate() {
+        let tx_size = bincode::serialized_size(tx)? as usize;
+        if tx_size > params.max_transaction_size {
+            return Err(ValidationError::TransactionTooLarge { index, size: tx_size, max: params.max_transaction_size });
+        }
+        if index > 0 && tx.is_coinbase() {
+            return Err(ValidationError::MisplacedCoinbase(index));
+        }
+        if !seen.insert(tx.hash()) {
+            return Err(ValidationError::DuplicateTransaction(index));
+        }
+    }
+
+    if calculate_merkle_root(&block.transactions) != block.header.merkle_root {
+        return Err(ValidationError::BadMerkleRoot);
+    }
+    if calculate_fruits_root(&block.fruits) != block.header.fruits_root {
+        return Err(ValidationError::BadFruitsRoot);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::LEGACY_HEADER_VERSION;
+    use crate::chain_params::Network;
+    use crate::transaction::SEQUENCE_FINAL;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn spend(key: &SigningKey, seed: u8) -> Transaction {
+        let mut tx = Transaction {
+            inputs: vec![TxInput {
+                previous_output: OutPoint { txid: [seed; 32], index: 0 },
+                public_key: key.verifying_key().to_bytes(),
+                signature: Vec::new(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            outputs: vec![TxOutput { value: 10, script_pubkey: vec![seed; 32] }],
+            lock_time: 0,
+        };
+        let message = tx.signature_hash().unwrap();
+        tx.inputs[0].signature = key.sign(&message).to_bytes().to_vec();
+        tx
+    }
+
+    fn block_of(transactions: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: LEGACY_HEADER_VERSION,
+                previous_hash: [0; 32],
+                merkle_root: calculate_merkle_root(&transactions),
+                fruits_root: calculate_fruits_root(&[]),
+                timestamp: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            block_type: BlockType::Block,
+            fruit_header: None,
+            fruits: Vec::new(),
+            transactions,
+        }
+    }
+
+    fn coinbase() -> Transaction {
+        Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value: 50, script_pubkey: vec![0; 32] }], lock_time: 0 }
+    }
+
+    #[tokio::test]
+    async fn test_parallel_check_finds_the_bad_signature() {
+        let validator = BlockValidator::new(4, ChainParams::for_network(Network::Regtest)).unwrap();
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let mut transactions = vec![coinbase()];
+        transactions.extend((1..=64).map(|seed| spend(&key, seed)));
+        let block = block_of(transactions.clone());
+        validator.validate_block(&block).unwrap();
+        validator.spawn_signature_check(Arc::new(block)).await.unwrap().unwrap();
+
+        // Signed by another key: structurally fine, so only the signature pass catches it
+        transactions[40] = spend(&SigningKey::from_bytes(&[4; 32]), 40);
+        transactions[40].inputs[0].public_key = key.verifying_key().to_bytes();
+        let block = block_of(transactions);
+        check_block_structure(&block, &ChainParams::for_network(Network::Regtest)).unwrap();
+        assert!(matches!(validator.validate_block(&block), Err(ValidationError::Transaction { index: 40, .. })));
+        let checked = validator.spawn_signature_check(Arc::new(block)).await.unwrap();
+        assert!(matches!(checked, Err(ValidationError::Transaction { index: 40, .. })));
+    }
+
+    #[test]
+    fn test_input_must_name_the_key_the_output_pays() {
+        let (owner, thief) = (SigningKey::from_bytes(&[3; 32]), SigningKey::from_bytes(&[4; 32]));
+        let paid_to_owner = TxOutput { value: 10, script_pubkey: owner.verifying_key().to_bytes().to_vec() };
+
+        let tx = spend(&owner, 1);
+        check_input_script(0, 0, &tx.inputs[0], &paid_to_owner).unwrap();
+        // A perfectly good signature, just by a key the output doesn't pay
+        let stolen = spend(&thief, 1);
+        stolen.verify_signatures().unwrap();
+        assert!(matches!(check_input_script(0, 0, &stolen.inputs[0], &paid_to_owner), Err(ValidationError::ScriptMismatch { tx: 0, input: 0 })));
+
+        for script in [Vec::new(), vec![0x6a; 20], vec![3; 33]] {
+            let output = TxOutput { value: 10, script_pubkey: script };
+            assert!(matches!(check_input_script(0, 0, &tx.inputs[0], &output), Err(ValidationError::UnspendableOutput { .. })));
+        }
+    }
+
+    #[test]
+    fn test_multisig_output_needs_a_witness_for_its_own_script() {
+        use crate::multisig::{MultisigScript, MultisigWitness};
+
+        let cosigners = [5u8, 6].map(|seed| SigningKey::from_bytes(&[seed; 32]));
+        let script = MultisigScript::new(2, cosigners.iter().map(|key| key.verifying_key().to_bytes()).collect()).unwrap();
+        let paid_to_script = TxOutput { value: 10, script_pubkey: script.to_script() };
+        let witnessed = |script: &MultisigScript, signers: &[&SigningKey]| {
+            let mut tx = spend(signers[0], 1);
+            tx.inputs[0].public_key = script.script_id();
+            tx.inputs[0].signature.clear();
+            let message = tx.signature_hash().unwrap();
+            let mut witness = MultisigWitness::new(script.clone());
+            for signer in signers {
+                let position = script.keys().iter().position(|key| *key == signer.verifying_key().to_bytes()).unwrap();
+                witness.add_signature(position, signer.sign(&message).to_bytes().to_vec()).unwrap();
+            }
+            tx.inputs[0].signature = witness.encode();
+            tx
+        };
+
+        let tx = witnessed(&script, &[&cosigners[0], &cosigners[1]]);
+        tx.verify_signatures().unwrap();
+        check_input_script(0, 0, &tx.inputs[0], &paid_to_script).unwrap();
+
+        // A complete witness for a script the thief controls is valid on its own but not for this output
+        let thief = SigningKey::from_bytes(&[4; 32]);
+        let own_script = MultisigScript::new(1, vec![thief.verifying_key().to_bytes()]).unwrap();
+        let stolen = witnessed(&own_script, &[&thief]);
+        stolen.verify_signatures().unwrap();
+        assert!(matches!(check_input_script(0, 0, &stolen.inputs[0], &paid_to_script), Err(ValidationError::ScriptMismatch { .. })));
+        // Nor can one cosigner spend it with a single-key signature
+        let alone = spend(&cosigners[0], 1);
+        assert!(matches!(check_input_script(0, 0, &alone.inputs[0], &paid_to_script), Err(ValidationError::ScriptMismatch { .. })));
+    }
+
+    #[test]
+    fn test_block_and_transaction_size_limits() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let mut params = ChainParams::for_network(Network::Regtest);
+        params.max_transactions_per_block = 3;
+        let block = block_of(vec![coinbase(), spend(&key, 1), spend(&key, 2), spend(&key, 3)]);
+        assert!(matches!(check_block_structure(&block, &params), Err(ValidationError::TooManyTransactions { count: 4, max: 3 })));
+
+        let params = ChainParams::for_network(Network::Regtest);
+        let mut large = spend(&key, 1);
+        large.outputs[0].script_pubkey = vec![1; params.max_transaction_size];
+        let block = block_of(vec![coinbase(), large]);
+        assert!(matches!(check_block_structure(&block, &params), Err(ValidationError::TransactionTooLarge { index: 1, .. })));
+
+        let block = block_of(vec![coinbase(), spend(&key, 1)]);
+        check_block_structure(&block, &params).unwrap();
+        let mut params = params;
+        params.max_block_size = bincode::serialized_size(&block).unwrap() as usize - 1;
+        assert!(matches!(check_block_structure(&block, &params), Err(ValidationError::BlockTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_structure_is_checked_before_signatures() {
+        let validator = BlockValidator::new(2, ChainParams::for_network(Network::Regtest)).unwrap();
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let mut bad_signature = spend(&key, 2);
+        bad_signature.inputs[0].signature[0] ^= 1;
+
+        let block = block_of(vec![coinbase(), spend(&key, 1), coinbase(), bad_signature]);
+        assert!(matches!(validator.validate_block(&block), Err(ValidationError::MisplacedCoinbase(2))));
+        let mut block = block_of(vec![coinbase(), spend(&key, 1)]);
+        block.header.merkle_root = [0; 32];
+        assert!(matches!(validator.validate_block(&block), Err(ValidationError::BadMerkleRoot)));
+    }
+
+    #[test]
+    fn test_fees_that_overflow_are_refused() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let tx = spend(&key, 1);
+        let paid = tx.outputs.iter().map(|output| output.value).sum::<u64>();
+        assert_eq!(transaction_fee(0, &tx, [paid, 5]).unwrap(), 5);
+        assert!(matches!(transaction_fee(1, &tx, [u64::MAX, 1]), Err(ValidationError::InputValueOverflow(1))));
+        assert!(matches!(transaction_fee(2, &tx, [paid - 1]), Err(ValidationError::OutputsExceedInputs(2))));
+        let mut large = tx.clone();
+        large.outputs.push(TxOutput { value: u64::MAX, script_pubkey: vec![1; 32] });
+        assert!(matches!(transaction_fee(3, &large, [u64::MAX]), Err(ValidationError::OutputsExceedInputs(3))));
+    }
+}
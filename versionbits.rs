@@ -0,0 +1,168 @@
+use serde::Serialize;
+
+// Header versions with these top bits carry deployment signals in the low 29 bits
+pub const VERSIONBITS_TOP_BITS: u32 = 0x2000_0000;
+pub const VERSIONBITS_TOP_MASK: u32 = 0xe000_0000;
+pub const VERSIONBITS_NUM_BITS: u8 = 29;
+
+// A consensus change activated by miner signalling, as in BIP9
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deployment {
+    pub name: &'static str,
+    pub bit: u8,
+    // Median time past from which signalling counts
+    pub start_time: u64,
+    // Median time past after which a deployment that hasn't locked in fails
+    pub timeout: u64,
+}
+
+impl Deployment {
+    pub fn is_signalled_by(&self, version: u32) -> bool {
+        version & VERSIONBITS_TOP_MASK == VERSIONBITS_TOP_BITS && version & (1 << self.bit) != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdState {
+    Defined,
+    Started,
+    LockedIn,
+    Active,
+    Failed,
+}
+
+// Deployment states along the active chain. States change only at period boundaries: the state for
+// period n+1 follows from the state in period n, how many of its blocks signalled, and the median
+// time past of its last block. One row per period is kept so blocks can be disconnected again.
+#[derive(Debug, Clone)]
+pub struct VersionBitsTracker {
+    deployments: Vec<Deployment>,
+    period: u64,
+    threshold: u64,
+    // states[p][d] is deployment d's state for blocks in period p
+    states: Vec<Vec<ThresholdState>>,
+    // counts[p][d] is how many blocks of period p signalled for deployment d
+    counts: Vec<Vec<u64>>,
+}
+
+impl VersionBitsTracker {
+    pub fn new(deployments: Vec<Deployment>, period: u64, threshold: u64) -> Self {
+        let initial = vec![ThresholdState::Defined; deployments.len()];
+        let counts = vec![0; deployments.len()];
+        VersionBitsTracker { deployments, period, threshold, states: vec![initial], counts: vec![counts] }
+    }
+
+    pub fn deployments(&self) -> &[Deployment] {
+        &self.deployments
+    }
+
+    // States that apply to the block after the current tip
+    pub fn current_states(&self) -> &[ThresholdState] {
+        self.states.last().expect("the tracker always has a first period")
+    }
+
+    pub fn state(&self, name: &str) -> Option<ThresholdState> {
+        let index = self.deployments.iter().position(|d| d.name == name)?;
+        Some(self.current_states()[index])
+    }
+
+    pub fn is_active(&self, name: &str) -> bool {
+        self.state(name) == Some(ThresholdState::Active)
+    }
+
+    // Signalling count so far in the current period, for reporting
+    pub fn current_count(&self, name: &str) -> Option<u64> {
+        let index = self.deployments.iter().position(|d| d.name == name)?;
+        Some(self.counts.last().expect("the tracker always has a first period")[index])
+    }
+
+    // Header version a miner should use: the top bits plus every deployment still looking for signals
+    pub fn block_version(&self) -> u32 {
+        self.deployments.iter().zip(self.current_states())
+            .filter(|(_, state)| matches!(state, ThresholdState::Started | ThresholdState::LockedIn))
+            .fold(VERSIONBITS_TOP_BITS, |version, (deployment, _)| version | 1 << deployment.bit)
+    }
+
+    // `median_time_past` is that of the window ending at the connected block itself
+    pub fn block_connected(&mut self, height: u64, version: u32, median_time_past: u64) {
+        let states = self.current_states().to_vec();
+        let counts = self.counts.last_mut().expect("the tracker always has a first period");
+        for (index, deployment) in self.deployments.iter().enumerate() {
+            if states[index] == ThresholdState::Started && deployment.is_signalled_by(version) {
+                counts[index] += 1;
+            }
+        }
+        if (height + 1) % self.period != 0 {
+            return;
+        }
+
+        let next = self.deployments.iter().enumerate()
+            .map(|(index, deployment)| match states[index] {
+                ThresholdState::Defined if median_time_past >= deployment.timeout => ThresholdState::Failed,
+                ThresholdState::Defined if median_time_past >= deployment.start_time => ThresholdState::Started,
+                ThresholdState::Started if counts[index] >= self.threshold => ThresholdState::LockedIn,
+                ThresholdState::Started if median_time_past >= deployment.timeout => ThresholdState::Failed,
+                ThresholdState::LockedIn => ThresholdState::Active,
+                state => state,
+            })
+            .collect();
+        self.states.push(next);
+        self.counts.push(vec![0; self.deployments.len()]);
+    }
+
+    pub fn block_disconnected(&mut self, height: u64, version: u32) {
+        if (height + 1) % self.period == 0 {
+            self.states.pop();
+            self.counts.pop();
+        }
+        let states = self.current_states().to_vec();
+        let counts = self.counts.last_mut().expect("the tracker always has a first period");
+        for (index, deployment) in self.deployments.iter().enumerate() {
+            if states[index] == ThresholdState::Started && deployment.is_signalled_by(version) {
+                counts[index] -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deployment_lifecycle() {
+        let deployment = Deployment { name: "testdummy", bit: 28, start_time: 100, timeout: 10_000 };
+        let mut tracker = VersionBitsTracker::new(vec![deployment], 4, 3);
+        let signal = VERSIONBITS_TOP_BITS | 1 << 28;
+
+        // Period 0 ends before the start time
+        for height in 0..4 {
+            tracker.block_connected(height, signal, 50);
+        }
+        assert_eq!(tracker.state("testdummy"), Some(ThresholdState::Defined));
+        for height in 4..8 {
+            tracker.block_connected(height, VERSIONBITS_TOP_BITS, 200);
+        }
+        assert_eq!(tracker.state("testdummy"), Some(ThresholdState::Started));
+        assert_eq!(tracker.block_version(), signal);
+
+        // Three of four blocks signal, meeting the threshold
+        for (height, version) in (8..12).zip([signal, 1, signal, signal]) {
+            tracker.block_connected(height, version, 300);
+        }
+        assert_eq!(tracker.state("testdummy"), Some(ThresholdState::LockedIn));
+
+        // Disconnecting the boundary block reopens the period with its count intact
+        tracker.block_disconnected(11, signal);
+        assert_eq!(tracker.state("testdummy"), Some(ThresholdState::Started));
+        assert_eq!(tracker.current_count("testdummy"), Some(2));
+        tracker.block_connected(11, signal, 300);
+
+        for height in 12..16 {
+            tracker.block_connected(height, VERSIONBITS_TOP_BITS, 400);
+        }
+        assert!(tracker.is_active("testdummy"));
+        assert_eq!(tracker.block_version(), VERSIONBITS_TOP_BITS);
+    }
+}
@@ -0,0 +1,1254 @@
+use crate::blockchain::{Block, BlockUndo, Blockchain, ChainEvent};
+use crate::codec::{self, CodecError};
+use crate::hd_keys::{DerivationPath, ExtendedKey, HdKeyError, PURPOSE};
+use crate::jobs::JobProgress;
+use crate::multisig::{self, MultisigError, MultisigScript, MultisigWitness};
+use crate::psbt::{PartiallySignedTransaction, PsbtError, PsbtInput};
+use crate::transaction::{OutPoint, Transaction, TxHash, TxInput, TxOutput, SEQUENCE_FINAL};
+use crate::wallet_history::{HistoryEntry, HistoryError, LabelTarget, TxStatus, UnconfirmedTransaction, WalletHistory};
+use bip39::Mnemonic;
+use ed25519_dalek::SIGNATURE_LENGTH;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub const WALLET_FILE_EXTENSION: &str = "wallet";
+// The wallet's history database sits beside its file
+const HISTORY_EXTENSION: &str = "history";
+// Blocks scanned between saves of the wallet file, where an interrupted scan picks up again
+const SCAN_CHECKPOINT_INTERVAL: u64 = 1000;
+
+#[derive(Error, Debug)]
+pub enum WalletError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Codec error: {0}")]
+    Codec(#[from] CodecError),
+    #[error("Key derivation error: {0}")]
+    HdKey(#[from] HdKeyError),
+    #[error("Wallet '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("No wallet named '{0}'")]
+    NotFound(String),
+    #[error("Wallet names may only contain letters, digits, '-' and '_': '{0}'")]
+    InvalidName(String),
+    #[error("{0} wallets are loaded; name the one to use")]
+    NoDefaultWallet(usize),
+    #[error("Wallet '{0}' is watch-only and has no keys to derive")]
+    WatchOnly(String),
+    #[error("Insufficient funds: {available} available, {needed} needed including the fee")]
+    InsufficientFunds { available: u64, needed: u64 },
+    #[error("PSBT error: {0}")]
+    Psbt(#[from] PsbtError),
+    #[error("History error: {0}")]
+    History(#[from] HistoryError),
+    #[error("Multisig error: {0}")]
+    Multisig(#[from] MultisigError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyChain {
+    Receive,
+    Change,
+}
+
+impl KeyChain {
+    fn index(self) -> usize {
+        match self {
+            KeyChain::Receive => 0,
+            KeyChain::Change => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WalletAddress {
+    // Outputs pay to the 32-byte public key itself, hex encoded here
+    pub address: String,
+    pub path: String,
+    pub chain: KeyChain,
+    pub index: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SyncSummary {
+    pub blocks_scanned: u64,
+    // Blocks pruned away before they could be scanned
+    pub blocks_missing: u64,
+    pub outputs_found: u64,
+    pub next_receive_index: u32,
+    pub next_change_index: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct WalletBalance {
+    pub confirmed: u64,
+    // Paid to and spent from the wallet by transactions in the mempool or taken out of the chain
+    // by a reorg
+    pub pending_received: u64,
+    pub pending_sent: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WalletCoin {
+    pub output: TxOutput,
+    pub height: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum KeyOrigin {
+    Derived(KeyChain, u32),
+    Imported,
+    // The multisig script of a multisig wallet
+    Multisig,
+}
+
+// A share of an m-of-n multisig: the wallet's own key is the first receive key of its account,
+// and `cosigners` are the other parties' keys
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MultisigAccount {
+    required: u8,
+    cosigners: Vec<[u8; 32]>,
+}
+
+// What the wallet file holds. The seed is stored unencrypted, so the file is created owner-only.
+#[derive(Serialize, Deserialize)]
+struct WalletState {
+    // Empty for watch-only wallets
+    seed: Vec<u8>,
+    coin_type: u32,
+    account: u32,
+    gap_limit: u32,
+    // First unused index on the receive and change chains
+    next_index: [u32; 2],
+    // Public keys watched without their private keys
+    watched: Vec<[u8; 32]>,
+    // Confirmed outputs paying the wallet that are still unspent
+    coins: HashMap<OutPoint, WalletCoin>,
+    // Last block scanned, None until the first sync
+    synced_height: Option<u64>,
+    multisig: Option<MultisigAccount>,
+}
+
+// Wallet files written before multisig accounts
+#[derive(Deserialize)]
+struct LegacyWalletState {
+    seed: Vec<u8>,
+    coin_type: u32,
+    account: u32,
+    gap_limit: u32,
+    next_index: [u32; 2],
+    watched: Vec<[u8; 32]>,
+    coins: HashMap<OutPoint, WalletCoin>,
+    synced_height: Option<u64>,
+}
+
+impl From<LegacyWalletState> for WalletState {
+    fn from(legacy: LegacyWalletState) -> Self {
+        WalletState {
+            seed: legacy.seed,
+            coin_type: legacy.coin_type,
+            account: legacy.account,
+            gap_limit: legacy.gap_limit,
+            next_index: legacy.next_index,
+            watched: legacy.watched,
+            coins: legacy.coins,
+            synced_height: legacy.synced_height,
+            multisig: None,
+        }
+    }
+}
+
+// An HD wallet following m/44'/coin_type'/account'/change'/index', or a watch-only wallet of
+// imported public keys. Every address handed out is a fresh index, and scans look `gap_limit`
+// indexes past the last used one on each chain. A multisig wallet instead has the one address of
+// its script, and signs for it with its own key alongside the cosigners.
+pub struct Wallet {
+    name: String,
+    path: PathBuf,
+    state: WalletState,
+    account_key: Option<ExtendedKey>,
+    multisig: Option<MultisigScript>,
+    history: WalletHistory,
+    // The mempool's view, rebuilt on every mempool sync rather than stored
+    pending: Vec<HistoryEntry>,
+    pending_spends: HashSet<OutPoint>,
+    // Transactions a reorg took out of the chain, as kept in the history database
+    unconfirmed: HashMap<TxHash, UnconfirmedTransaction>,
+    // Those of them that were double-spent, and their descendants
+    conflicted: HashSet<TxHash>,
+}
+
+impl Wallet {
+    pub fn create(dir: &Path, name: &str, mnemonic: &Mnemonic, passphrase: &str, coin_type: u32, account: u32, gap_limit: u32) -> Result<Self, WalletError> {
+        let seed = mnemonic.to_seed(passphrase).to_vec();
+        Self::create_with_state(dir, name, WalletState::new(seed, coin_type, account, gap_limit, Vec::new()))
+    }
+
+    // Holds no private keys; spending goes through `create_psbt` and a signer elsewhere
+    pub fn create_watch_only(dir: &Path, name: &str, keys: Vec<[u8; 32]>) -> Result<Self, WalletError> {
+        Self::create_with_state(dir, name, WalletState::new(Vec::new(), 0, 0, 0, keys))
+    }
+
+    // `required` of the wallet's own key and `cosigners` must sign to spend. Every party creates one
+    // with the same threshold and the others' keys, getting the same address.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_multisig(dir: &Path, name: &str, mnemonic: &Mnemonic, passphrase: &str, coin_type: u32, account: u32, required: usize, cosigners: Vec<[u8; 32]>) -> Result<Self, WalletError> {
+        let seed = mnemonic.to_seed(passphrase).to_vec();
+        let mut state = WalletState::new(seed, coin_type, account, 0, Vec::new());
+        let required = u8::try_from(required).map_err(|_| MultisigError::InvalidThreshold { required, keys: cosigners.len() + 1 })?;
+        state.multisig = Some(MultisigAccount { required, cosigners });
+        Self::create_with_state(dir, name, state)
+    }
+
+    // The key a multisig wallet from these words would contribute, to share with the cosigners
+    pub fn multisig_key(mnemonic: &Mnemonic, passphrase: &str, coin_type: u32, account: u32) -> Result<[u8; 32], WalletError> {
+        let account_key = derive_account_key(&mnemonic.to_seed(passphrase), coin_type, account)?;
+        Ok(own_multisig_key(&account_key)?)
+    }
+
+    fn create_with_state(dir: &Path, name: &str, state: WalletState) -> Result<Self, WalletError> {
+        check_name(name)?;
+        let path = wallet_path(dir, name);
+        if path.exists() {
+            return Err(WalletError::AlreadyExists(name.to_string()));
+        }
+        let wallet = Self::from_state(name.to_string(), path, state)?;
+        wallet.save()?;
+        Ok(wallet)
+    }
+
+    pub fn open(path: &Path) -> Result<Self, WalletError> {
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let bytes = fs::read(path)?;
+        let state = match codec::decode(&bytes) {
+            Ok(state) => state,
+            Err(e) => codec::decode::<LegacyWalletState>(&bytes).map_err(|_| e)?.into(),
+        };
+        Self::from_state(name, path.to_path_buf(), state)
+    }
+
+    fn from_state(name: String, path: PathBuf, state: WalletState) -> Result<Self, WalletError> {
+        let account_key = if state.seed.is_empty() {
+            None
+        } else {
+            Some(derive_account_key(&state.seed, state.coin_type, state.account)?)
+        };
+        let multisig = match (&state.multisig, &account_key) {
+            (Some(account), Some(account_key)) => {
+                let mut keys = account.cosigners.clone();
+                keys.push(own_multisig_key(account_key)?);
+                Some(MultisigScript::new(account.required as usize, keys)?)
+            }
+            (Some(_), None) => return Err(WalletError::WatchOnly(name)),
+            (None, _) => None,
+        };
+        let history = WalletHistory::open(&path.with_extension(HISTORY_EXTENSION))?;
+        let unconfirmed = history.unconfirmed()?.into_iter().map(|unconfirmed| (unconfirmed.entry.txid, unconfirmed)).collect();
+        let mut wallet = Wallet {
+            name,
+            path,
+            state,
+            account_key,
+            multisig,
+            history,
+            pending: Vec::new(),
+            pending_spends: HashSet::new(),
+            unconfirmed,
+            conflicted: HashSet::new(),
+        };
+        wallet.refresh_conflicts();
+        Ok(wallet)
+    }
+
+    // Written to a temporary file first so a crash never leaves a truncated wallet
+    fn save(&self) -> Result<(), WalletError> {
+        let bytes = codec::encode(&self.state)?;
+        let tmp = self.path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+
+    // Writes the wallet file and a checkpoint of its history into `dir`, creating it. The result is a
+    // wallets directory of its own: copy both back into the node's to restore.
+    pub fn backup(&self, dir: &Path) -> Result<PathBuf, WalletError> {
+        fs::create_dir_all(dir)?;
+        let path = wallet_path(dir, &self.name);
+        if path.exists() {
+            return Err(WalletError::AlreadyExists(self.name.clone()));
+        }
+        self.history.create_checkpoint(&path.with_extension(HISTORY_EXTENSION))?;
+        fs::copy(&self.path, &path)?;
+        Ok(path)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn gap_limit(&self) -> u32 {
+        self.state.gap_limit
+    }
+
+    pub fn is_watch_only(&self) -> bool {
+        self.account_key.is_none()
+    }
+
+    pub fn multisig_script(&self) -> Option<&MultisigScript> {
+        self.multisig.as_ref()
+    }
+
+    pub fn key_path(&self, chain: KeyChain, index: u32) -> Result<DerivationPath, WalletError> {
+        Ok(DerivationPath::bip44(self.state.coin_type, self.state.account, chain == KeyChain::Change, index)?)
+    }
+
+    pub fn derive_key(&self, chain: KeyChain, index: u32) -> Result<ExtendedKey, WalletError> {
+        let account_key = self.account_key.as_ref().ok_or_else(|| WalletError::WatchOnly(self.name.clone()))?;
+        Ok(account_key.derive_child(chain.index() as u32)?.derive_child(index)?)
+    }
+
+    // Multisig wallets have the one address, whatever the chain and index; the path is that of
+    // their own key
+    pub fn address(&self, chain: KeyChain, index: u32) -> Result<WalletAddress, WalletError> {
+        if let Some(script) = &self.multisig {
+            return Ok(WalletAddress { address: script.address(), path: self.key_path(KeyChain::Receive, 0)?.to_string(), chain, index: 0 });
+        }
+        Ok(WalletAddress {
+            address: hex::encode(self.derive_key(chain, index)?.public_key()),
+            path: self.key_path(chain, index)?.to_string(),
+            chain,
+            index,
+        })
+    }
+
+    // Hands out the next unused address on `chain`, so no address is given out twice
+    pub fn new_address(&mut self, chain: KeyChain) -> Result<WalletAddress, WalletError> {
+        if self.multisig.is_some() {
+            return self.address(chain, 0);
+        }
+        let index = self.state.next_index[chain.index()];
+        let address = self.address(chain, index)?;
+        self.state.next_index[chain.index()] = index + 1;
+        self.save()?;
+        Ok(address)
+    }
+
+    // Watches `key` from now on. The next sync rescans from genesis so earlier payments to it show up.
+    pub fn import_key(&mut self, key: [u8; 32]) -> Result<bool, WalletError> {
+        if self.state.watched.contains(&key) {
+            return Ok(false);
+        }
+        self.state.watched.push(key);
+        self.state.coins.clear();
+        self.history.clear()?;
+        self.unconfirmed.clear();
+        self.conflicted.clear();
+        self.state.synced_height = None;
+        self.save()?;
+        Ok(true)
+    }
+
+    // Scripts of every imported key, and of every derived key up to `gap_limit` past the first
+    // unused index on each chain
+    fn scripts(&self) -> Result<HashMap<Vec<u8>, KeyOrigin>, WalletError> {
+        let mut scripts = HashMap::new();
+        if let Some(script) = &self.multisig {
+            scripts.insert(script.to_script(), KeyOrigin::Multisig);
+        } else if self.account_key.is_some() {
+            for chain in [KeyChain::Receive, KeyChain::Change] {
+                let next = self.state.next_index[chain.index()];
+                for index in next..next.saturating_add(self.state.gap_limit) {
+                    scripts.insert(self.derive_key(chain, index)?.public_key().to_vec(), KeyOrigin::Derived(chain, index));
+                }
+            }
+        }
+        for key in &self.state.watched {
+            scripts.insert(key.to_vec(), KeyOrigin::Imported);
+        }
+        Ok(scripts)
+    }
+
+    // Scripts of every key the wallet has used or is watching for, to recognise its own coins
+    fn owned_scripts(&self) -> Result<HashSet<Vec<u8>>, WalletError> {
+        let mut owned = self.scripts()?.into_keys().collect::<HashSet<_>>();
+        if self.account_key.is_some() && self.multisig.is_none() {
+            for chain in [KeyChain::Receive, KeyChain::Change] {
+                for index in 0..self.state.next_index[chain.index()] {
+                    owned.insert(self.derive_key(chain, index)?.public_key().to_vec());
+                }
+            }
+        }
+        Ok(owned)
+    }
+
+    // Scans the blocks connected since the last sync, moving each chain's next index past the last
+    // one used. The lookahead extends as keys are found, so any run of fewer than `gap_limit`
+    // unused addresses is crossed. Blocks a reorg has replaced since are disconnected first.
+    pub async fn sync(&mut self, blockchain: &Blockchain) -> Result<SyncSummary, Box<dyn std::error::Error>> {
+        self.disconnect_stale_blocks(blockchain).await?;
+        self.scan(blockchain, None, &JobProgress::new()).await
+    }
+
+    // Follows the chain as blocks are connected and disconnected, so a reorg returns the wallet's
+    // transactions in the blocks it takes away to pending straight away. Events that don't follow on
+    // from the last block scanned are left to the next sync.
+    pub async fn chain_event(&mut self, blockchain: &Blockchain, event: &ChainEvent) -> Result<(), Box<dyn std::error::Error>> {
+        match event {
+            ChainEvent::BlockConnected { block, height } => {
+                let extends = match self.state.synced_height {
+                    Some(synced) => synced + 1 == *height && self.history.block_hash(synced)?.is_none_or(|hash| hash == block.header.previous_hash),
+                    None => *height == 0,
+                };
+                if !extends {
+                    return Ok(());
+                }
+                let scripts = self.scripts()?;
+                self.connect_block(*height, block, &scripts)?;
+                self.state.synced_height = Some(*height);
+            }
+            ChainEvent::BlockDisconnected { block, height } => {
+                let hash = block.hash();
+                if self.state.synced_height != Some(*height) || self.history.block_hash(*height)?.is_some_and(|scanned| scanned != hash) {
+                    return Ok(());
+                }
+                let undo = blockchain.get_undo(&hash).await?
+                    .ok_or_else(|| format!("undo data for height {} has been pruned", height))?;
+                let owned = self.owned_scripts()?;
+                self.disconnect_block(*height, block, &undo, &owned)?;
+            }
+        }
+        self.save()?;
+        Ok(())
+    }
+
+    // Walks back from the last block scanned to one still on the active chain, disconnecting those
+    // a reorg replaced while the wallet wasn't following, e.g. before a restart. They're read by
+    // hash, as block storage keeps stale blocks and their undo data.
+    async fn disconnect_stale_blocks(&mut self, blockchain: &Blockchain) -> Result<(), Box<dyn std::error::Error>> {
+        let owned = self.owned_scripts()?;
+        let mut disconnected = false;
+        while let Some(height) = self.state.synced_height {
+            // Blocks scanned before their hashes were recorded are taken to be on the chain
+            let Some(hash) = self.history.block_hash(height)? else { break };
+            if blockchain.get_block_hashes(height..height + 1).await?.first() == Some(&hash) {
+                break;
+            }
+            let block = blockchain.get_block(&hash).await?
+                .ok_or_else(|| format!("stale block at height {} is missing from block storage", height))?;
+            let undo = blockchain.get_undo(&hash).await?
+                .ok_or_else(|| format!("undo data for the stale block at height {} has been pruned", height))?;
+            self.disconnect_block(height, &block, &undo, &owned)?;
+            disconnected = true;
+        }
+        if disconnected {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Undoes `block` at `height`: coins it created are dropped and those it spent put back from its
+    // undo data. Its wallet transactions return to unconfirmed, apart from a coinbase, which can't
+    // exist outside its block and is forgotten with the reward it paid. Double-spends the block
+    // confirmed no longer conflict.
+    fn disconnect_block(&mut self, height: u64, block: &Block, undo: &BlockUndo, owned: &HashSet<Vec<u8>>) -> Result<(), WalletError> {
+        self.state.coins.retain(|_, coin| coin.height < height);
+        for (outpoint, coin) in &undo.spent_coins {
+            if coin.height < height && owned.contains(&coin.output.script_pubkey) {
+                self.state.coins.insert(*outpoint, WalletCoin { output: coin.output.clone(), height: coin.height });
+            }
+        }
+        for tx in block.transactions.iter().rev() {
+            let txid = tx.hash();
+            let Some(mut entry) = self.history.get(&txid)?.filter(|entry| entry.height == Some(height)) else { continue };
+            self.history.delete(&txid)?;
+            if tx.is_coinbase() {
+                continue;
+            }
+            entry.height = None;
+            entry.block_hash = None;
+            entry.block_time = None;
+            let unconfirmed = UnconfirmedTransaction { entry, tx: tx.clone(), conflict: None };
+            self.history.put_unconfirmed(&unconfirmed)?;
+            self.unconfirmed.insert(txid, unconfirmed);
+        }
+        for unconfirmed in self.unconfirmed.values_mut().filter(|unconfirmed| unconfirmed.conflict.is_some_and(|(_, at)| at >= height)) {
+            unconfirmed.conflict = None;
+            self.history.put_unconfirmed(unconfirmed)?;
+        }
+        self.refresh_conflicts();
+        self.state.synced_height = height.checked_sub(1);
+        // The block's hash is how a restart finds it to disconnect again, so it goes only once the
+        // wallet file has stopped counting the block
+        self.save()?;
+        self.history.delete_block_hash(height)?;
+        Ok(())
+    }
+
+    // Conflicted transactions are those double-spent in the chain and, as they spend outputs that
+    // will never exist, their unconfirmed descendants
+    fn refresh_conflicts(&mut self) {
+        let mut conflicted = self.unconfirmed.values()
+            .filter(|unconfirmed| unconfirmed.conflict.is_some())
+            .map(|unconfirmed| unconfirmed.entry.txid)
+            .collect::<HashSet<_>>();
+        loop {
+            let descendants = self.unconfirmed.values()
+                .filter(|unconfirmed| !conflicted.contains(&unconfirmed.entry.txid))
+                .filter(|unconfirmed| unconfirmed.tx.inputs.iter().any(|input| conflicted.contains(&input.previous_output.txid)))
+                .map(|unconfirmed| unconfirmed.entry.txid)
+                .collect::<Vec<_>>();
+            if descendants.is_empty() {
+                break;
+            }
+            conflicted.extend(descendants);
+        }
+        self.conflicted = conflicted;
+    }
+
+    // Scans from the block after the last one synced through `stop`, saving every
+    // SCAN_CHECKPOINT_INTERVAL blocks and stopping early if `progress` is cancelled
+    async fn scan(&mut self, blockchain: &Blockchain, stop: Option<u64>, progress: &JobProgress) -> Result<SyncSummary, Box<dyn std::error::Error>> {
+        let mut summary = SyncSummary::default();
+        let mut scripts = self.scripts()?;
+        let start = self.state.synced_height.map_or(0, |height| height + 1);
+        let tip = match (blockchain.get_chain_height(), stop) {
+            (Some(tip), Some(stop)) => Some(tip.min(stop)),
+            (tip, _) => tip,
+        };
+        if let Some(tip) = tip {
+            progress.set_total((tip + 1).saturating_sub(start));
+            for height in start..=tip {
+                if progress.is_cancelled() {
+                    break;
+                }
+                let block = blockchain.get_block_by_height(height).await?;
+                self.scan_block(height, block.as_deref(), &mut scripts, &mut summary)?;
+                progress.set_done(height + 1 - start);
+                if (height + 1 - start) % SCAN_CHECKPOINT_INTERVAL == 0 {
+                    self.save()?;
+                }
+            }
+        }
+        Ok(self.finish_scan(summary)?)
+    }
+
+    // Takes the block at `height`, or notes it missing, as the next one scanned
+    fn scan_block(&mut self, height: u64, block: Option<&Block>, scripts: &mut HashMap<Vec<u8>, KeyOrigin>, summary: &mut SyncSummary) -> Result<(), WalletError> {
+        match block {
+            Some(block) => {
+                summary.blocks_scanned += 1;
+                let (found, extended) = self.connect_block(height, block, scripts)?;
+                summary.outputs_found += found;
+                if extended {
+                    scripts.extend(self.scripts()?);
+                }
+            }
+            None => summary.blocks_missing += 1,
+        }
+        self.state.synced_height = Some(height);
+        Ok(())
+    }
+
+    fn finish_scan(&mut self, mut summary: SyncSummary) -> Result<SyncSummary, WalletError> {
+        if summary.blocks_scanned + summary.blocks_missing > 0 {
+            self.save()?;
+        }
+        summary.next_receive_index = self.state.next_index[KeyChain::Receive.index()];
+        summary.next_change_index = self.state.next_index[KeyChain::Change.index()];
+        Ok(summary)
+    }
+
+    // Returns how many outputs paid the wallet and whether a derived key past the lookahead's
+    // start was used
+    fn connect_block(&mut self, height: u64, block: &Block, scripts: &HashMap<Vec<u8>, KeyOrigin>) -> Result<(u64, bool), WalletError> {
+        let mut found = 0;
+        let mut extended = false;
+        let block_hash = block.hash();
+        self.history.put_block_hash(height, &block_hash)?;
+        // Outputs spent by transactions a reorg returned to unconfirmed, to notice them being double-spent
+        let unconfirmed_spends = self.unconfirmed.values()
+            .flat_map(|unconfirmed| unconfirmed.tx.inputs.iter().map(|input| (input.previous_output, unconfirmed.entry.txid)))
+            .collect::<HashMap<_, _>>();
+        for tx in &block.transactions {
+            let txid = tx.hash();
+            for input in &tx.inputs {
+                let spender = unconfirmed_spends.get(&input.previous_output).filter(|spender| **spender != txid);
+                let Some(unconfirmed) = spender.and_then(|spender| self.unconfirmed.get_mut(spender)) else { continue };
+                if unconfirmed.conflict.is_none() {
+                    unconfirmed.conflict = Some((txid, height));
+                    self.history.put_unconfirmed(unconfirmed)?;
+                }
+            }
+            let first_seen = self.pending.iter().find(|pending| pending.txid == txid).map(|pending| pending.first_seen)
+                .or_else(|| self.unconfirmed.get(&txid).map(|unconfirmed| unconfirmed.entry.first_seen))
+                .unwrap_or_else(unix_now);
+            let mut entry = HistoryEntry {
+                txid,
+                height: Some(height),
+                block_hash: Some(block_hash),
+                block_time: Some(block.header.timestamp),
+                first_seen,
+                received: 0,
+                sent: 0,
+                fee: None,
+            };
+            let mut relevant = self.unconfirmed.contains_key(&txid);
+            let mut own_inputs = 0;
+            for input in &tx.inputs {
+                if let Some(coin) = self.state.coins.remove(&input.previous_output) {
+                    entry.sent += coin.output.value;
+                    own_inputs += 1;
+                    relevant = true;
+                }
+            }
+            if own_inputs > 0 && own_inputs == tx.inputs.len() {
+                entry.fee = entry.sent.checked_sub(tx.outputs.iter().map(|output| output.value).sum());
+            }
+            for (index, output) in tx.outputs.iter().enumerate() {
+                let Some(&origin) = scripts.get(&output.script_pubkey) else { continue };
+                found += 1;
+                entry.received += output.value;
+                relevant = true;
+                self.state.coins.insert(OutPoint { txid, index: index as u32 }, WalletCoin { output: output.clone(), height });
+                if let KeyOrigin::Derived(chain, key_index) = origin {
+                    let next = &mut self.state.next_index[chain.index()];
+                    if key_index >= *next {
+                        *next = key_index + 1;
+                        extended = true;
+                    }
+                }
+            }
+            if relevant {
+                // A rescan replays transactions already recorded; they keep when they were first seen
+                if let Some(known) = self.history.get(&txid)? {
+                    entry.first_seen = known.first_seen;
+                }
+                self.pending.retain(|pending| pending.txid != txid);
+                self.history.put(&entry)?;
+                if self.unconfirmed.remove(&txid).is_some() {
+                    self.history.remove_unconfirmed(&txid)?;
+                }
+            }
+        }
+        if !unconfirmed_spends.is_empty() {
+            self.refresh_conflicts();
+        }
+        Ok((found, extended))
+    }
+
+    // Rebuilds the unconfirmed view from `transactions`, which should be the whole mempool
+    pub fn sync_mempool<'a>(&mut self, transactions: impl IntoIterator<Item = &'a Transaction>) -> Result<(), WalletError> {
+        let scripts = self.scripts()?;
+        let previous = std::mem::take(&mut self.pending);
+        self.pending_spends.clear();
+        for tx in transactions {
+            let txid = tx.hash();
+            let first_seen = previous.iter().find(|pending| pending.txid == txid).map_or_else(unix_now, |pending| pending.first_seen);
+            let mut entry = HistoryEntry { txid, height: None, block_hash: None, block_time: None, first_seen, received: 0, sent: 0, fee: None };
+            let mut relevant = false;
+            let mut own_inputs = 0;
+            for input in &tx.inputs {
+                if let Some(coin) = self.state.coins.get(&input.previous_output) {
+                    entry.sent += coin.output.value;
+                    self.pending_spends.insert(input.previous_output);
+                    own_inputs += 1;
+                    relevant = true;
+                }
+            }
+            if own_inputs > 0 && own_inputs == tx.inputs.len() {
+                entry.fee = entry.sent.checked_sub(tx.outputs.iter().map(|output| output.value).sum());
+            }
+            for output in tx.outputs.iter().filter(|output| scripts.contains_key(&output.script_pubkey)) {
+                entry.received += output.value;
+                relevant = true;
+            }
+            if relevant {
+                self.pending.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    // Transactions a reorg returned to unconfirmed that the mempool view doesn't already show,
+    // oldest first
+    fn reorged(&self) -> Vec<&UnconfirmedTransaction> {
+        let mut reorged = self.unconfirmed.values()
+            .filter(|unconfirmed| self.pending.iter().all(|pending| pending.txid != unconfirmed.entry.txid))
+            .collect::<Vec<_>>();
+        reorged.sort_by_key(|unconfirmed| unconfirmed.entry.first_seen);
+        reorged
+    }
+
+    // Conflicted transactions count for neither side of the pending balance
+    pub fn balance(&self) -> WalletBalance {
+        let reorged = self.reorged().into_iter()
+            .filter(|unconfirmed| !self.conflicted.contains(&unconfirmed.entry.txid))
+            .map(|unconfirmed| &unconfirmed.entry)
+            .collect::<Vec<_>>();
+        WalletBalance {
+            confirmed: self.state.coins.values().map(|coin| coin.output.value).sum(),
+            pending_received: self.pending.iter().chain(reorged.iter().copied()).map(|tx| tx.received).sum(),
+            pending_sent: self.pending.iter().chain(reorged.iter().copied()).map(|tx| tx.sent).sum(),
+        }
+    }
+
+    // Confirmed transactions oldest first, then those a reorg returned to unconfirmed, then those
+    // in the mempool
+    pub fn history(&self) -> Result<Vec<HistoryEntry>, WalletError> {
+        let mut entries = self.history.entries()?;
+        entries.extend(self.reorged().into_iter().map(|unconfirmed| unconfirmed.entry.clone()));
+        entries.extend(self.pending.iter().cloned());
+        Ok(entries)
+    }
+
+    pub fn transaction(&self, txid: &TxHash) -> Result<Option<HistoryEntry>, WalletError> {
+        if let Some(pending) = self.pending.iter().find(|pending| &pending.txid == txid) {
+            return Ok(Some(pending.clone()));
+        }
+        match self.unconfirmed.get(txid) {
+            Some(unconfirmed) => Ok(Some(unconfirmed.entry.clone())),
+            None => Ok(self.history.get(txid)?),
+        }
+    }
+
+    pub fn status(&self, entry: &HistoryEntry) -> TxStatus {
+        match entry.height {
+            Some(_) => TxStatus::Confirmed,
+            None if self.conflicted.contains(&entry.txid) => TxStatus::Conflicted,
+            None => TxStatus::Pending,
+        }
+    }
+
+    // Spent by a transaction in the mempool, or by one a reorg returned to unconfirmed that may
+    // still confirm
+    fn is_pending_spend(&self, outpoint: &OutPoint) -> bool {
+        self.pending_spends.contains(outpoint) || self.unconfirmed.values().any(|unconfirmed| {
+            !self.conflicted.contains(&unconfirmed.entry.txid) && unconfirmed.tx.inputs.iter().any(|input| input.previous_output == *outpoint)
+        })
+    }
+
+    pub fn set_label(&self, target: LabelTarget, label: &str) -> Result<(), WalletError> {
+        Ok(self.history.set_label(target, label)?)
+    }
+
+    pub fn label(&self, target: LabelTarget) -> Result<Option<String>, WalletError> {
+        Ok(self.history.label(target)?)
+    }
+
+    pub fn labels(&self) -> Result<Vec<(LabelTarget, String)>, WalletError> {
+        Ok(self.history.labels()?)
+    }
+
+    // Funds `outputs` from confirmed coins no pending transaction is already spending, largest first, and
+    // sends the change back to the wallet. The result is unsigned for whoever holds the keys.
+    pub fn create_psbt(&mut self, outputs: Vec<TxOutput>, fee_rate: f64, dust_limit: u64) -> Result<PartiallySignedTransaction, WalletError> {
+        let amount = outputs.iter().fold(0u64, |sum, output| sum.saturating_add(output.value));
+        let mut candidates = self.state.coins.iter()
+            .filter(|(outpoint, coin)| !self.is_pending_spend(outpoint) && multisig::spending_key(&coin.output.script_pubkey).is_some())
+            .map(|(outpoint, coin)| (*outpoint, coin.clone()))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.1.output.value));
+        let available = candidates.iter().fold(0u64, |sum, (_, coin)| sum.saturating_add(coin.output.value));
+
+        // Sized with a change output and full-length signatures, so the fee covers the signed result
+        let change_script = self.multisig.as_ref().map_or_else(|| vec![0; 32], MultisigScript::to_script);
+        let mut tx = Transaction { inputs: Vec::new(), outputs, lock_time: 0 };
+        tx.outputs.push(TxOutput { value: 0, script_pubkey: change_script });
+        let mut selected = Vec::new();
+        let mut input_value = 0u64;
+        let mut needed = amount;
+        for (outpoint, coin) in candidates {
+            let script_pubkey = &coin.output.script_pubkey;
+            let public_key = multisig::spending_key(script_pubkey).expect("filtered to spendable scripts");
+            let signature = match MultisigScript::from_script(script_pubkey) {
+                Some(script) => MultisigWitness::placeholder(script).encode(),
+                None => vec![0; SIGNATURE_LENGTH],
+            };
+            tx.inputs.push(TxInput { previous_output: outpoint, public_key, signature, sequence: SEQUENCE_FINAL });
+            input_value += coin.output.value;
+            selected.push(coin);
+            let size = bincode::serialized_size(&tx).map_err(CodecError::from)?;
+            needed = amount.saturating_add((size as f64 * fee_rate).ceil() as u64);
+            if input_value >= needed {
+                break;
+            }
+        }
+        if input_value < needed {
+            return Err(WalletError::InsufficientFunds { available, needed });
+        }
+
+        // Change too small to relay is left to the fee instead
+        let change = input_value - needed;
+        if change < dust_limit.max(1) {
+            tx.outputs.pop();
+        } else {
+            let script_pubkey = match (&self.multisig, &self.account_key) {
+                (Some(script), _) => script.to_script(),
+                (None, Some(_)) => hex::decode(self.new_address(KeyChain::Change)?.address).expect("addresses are hex"),
+                // Watch-only change goes back to the key of the largest input
+                (None, None) => selected[0].output.script_pubkey.clone(),
+            };
+            *tx.outputs.last_mut().expect("change output was pushed") = TxOutput { value: change, script_pubkey };
+        }
+        for input in &mut tx.inputs {
+            input.signature.clear();
+        }
+
+        let scripts = self.scripts()?;
+        let inputs = selected.into_iter()
+            .map(|coin| {
+                let key_path = match scripts.get(&coin.output.script_pubkey) {
+                    Some(&KeyOrigin::Derived(chain, index)) => Some(self.key_path(chain, index)?.to_string()),
+                    Some(&KeyOrigin::Multisig) => Some(self.key_path(KeyChain::Receive, 0)?.to_string()),
+                    _ => None,
+                };
+                Ok(PsbtInput { spent_output: coin.output, key_path, signature: None })
+            })
+            .collect::<Result<Vec<_>, WalletError>>()?;
+        Ok(PartiallySignedTransaction::new(tx, inputs)?)
+    }
+
+    // Signs every input spending one of this wallet's derived keys, returning how many were signed.
+    // Multisig wallets add their own key's signature to inputs of their script, and watch-only
+    // wallets have nothing to sign with.
+    pub fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<usize, WalletError> {
+        if self.account_key.is_none() {
+            return Ok(0);
+        }
+        if self.multisig.is_some() {
+            return Ok(psbt.sign(&self.derive_key(KeyChain::Receive, 0)?.signing_key())?);
+        }
+        let wanted = psbt.tx.inputs.iter().map(|input| input.public_key).collect::<HashSet<_>>();
+        let mut signed = 0;
+        for chain in [KeyChain::Receive, KeyChain::Change] {
+            let end = self.state.next_index[chain.index()].saturating_add(self.state.gap_limit);
+            for index in 0..end {
+                let key = self.derive_key(chain, index)?;
+                if wanted.contains(&key.public_key()) {
+                    signed += psbt.sign(&key.signing_key())?;
+                }
+            }
+        }
+        Ok(signed)
+    }
+}
+
+impl WalletState {
+    fn new(seed: Vec<u8>, coin_type: u32, account: u32, gap_limit: u32, watched: Vec<[u8; 32]>) -> Self {
+        WalletState {
+            seed,
+            coin_type,
+            account,
+            gap_limit,
+            next_index: [0, 0],
+            watched,
+            coins: HashMap::new(),
+            synced_height: None,
+            multisig: None,
+        }
+    }
+}
+
+fn derive_account_key(seed: &[u8], coin_type: u32, account: u32) -> Result<ExtendedKey, HdKeyError> {
+    let account_path = DerivationPath::new(vec![PURPOSE, coin_type, account])?;
+    ExtendedKey::master(seed).derive_path(&account_path)
+}
+
+fn own_multisig_key(account_key: &ExtendedKey) -> Result<[u8; 32], HdKeyError> {
+    Ok(account_key.derive_child(KeyChain::Receive.index() as u32)?.derive_child(0)?.public_key())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn check_name(name: &str) -> Result<(), WalletError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(WalletError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn wallet_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name).with_extension(WALLET_FILE_EXTENSION)
+}
+
+// The wallets found in the wallets directory, by name
+#[derive(Default)]
+pub struct Wallets {
+    wallets: BTreeMap<String, Wallet>,
+}
+
+impl Wallets {
+    pub fn load(dir: &Path) -> Result<Self, WalletError> {
+        let mut wallets = BTreeMap::new();
+        if dir.exists() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == WALLET_FILE_EXTENSION) {
+                    let wallet = Wallet::open(&path)?;
+                    wallets.insert(wallet.name().to_string(), wallet);
+                }
+            }
+        }
+        Ok(Wallets { wallets })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.wallets.keys().map(String::as_str).collect()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Wallet> {
+        self.wallets.values_mut()
+    }
+
+    // `None` picks the only loaded wallet
+    pub fn get(&self, name: Option<&str>) -> Result<&Wallet, WalletError> {
+        match name {
+            Some(name) => self.wallets.get(name).ok_or_else(|| WalletError::NotFound(name.to_string())),
+            None if self.wallets.len() == 1 => Ok(self.wallets.values().next().expect("one wallet is loaded")),
+            None => Err(WalletError::NoDefaultWallet(self.wallets.len())),
+        }
+    }
+
+    pub fn get_mut(&mut self, name: Option<&str>) -> Result<&mut Wallet, WalletError> {
+        match name {
+            Some(name) => self.wallets.get_mut(name).ok_or_else(|| WalletError::NotFound(name.to_string())),
+            None if self.wallets.len() == 1 => Ok(self.wallets.values_mut().next().expect("one wallet is loaded")),
+            None => Err(WalletError::NoDefaultWallet(self.wallets.len())),
+        }
+    }
+}
+
+// Replays the blocks from `start` through `stop` (default the tip) for the wallet `name`, e.g.
+// after importing keys that were paid before, reading them from the block files. What the wallet
+// learned from those blocks is rolled back first, so replaying them doesn't count anything twice;
+// every one of them must still have its undo data, which is checked before anything changes.
+// `wallets` is locked only while each block is applied, so a rescan of the whole chain doesn't
+// hold up other wallet calls or the chain follower. Syncs carry on past `stop` as usual, and from
+// the last checkpoint if the rescan was interrupted.
+pub async fn rescan(wallets: &tokio::sync::Mutex<Wallets>, name: Option<&str>, blockchain: &Blockchain, start: u64, stop: Option<u64>, progress: &JobProgress) -> Result<SyncSummary, Box<dyn std::error::Error>> {
+    let (synced, owned) = {
+        let mut wallets = wallets.lock().await;
+        let wallet = wallets.get_mut(name)?;
+        wallet.disconnect_stale_blocks(blockchain).await?;
+        (wallet.state.synced_height, wallet.owned_scripts()?)
+    };
+    if let Some(synced) = synced.filter(|synced| *synced >= start) {
+        let hashes = blockchain.get_block_hashes(start..synced + 1).await?;
+        for (offset, hash) in hashes.iter().enumerate() {
+            if !blockchain.has_undo(hash).await? {
+                return Err(format!("undo data for height {} has been pruned; rescan from a higher height", start + offset as u64).into());
+            }
+        }
+    }
+
+    // Back to just below `start`, a block at a time. The follower may connect a block between two
+    // of them, which is then rolled back too.
+    loop {
+        let height = match wallets.lock().await.get(name)?.state.synced_height.filter(|synced| *synced >= start) {
+            Some(height) => height,
+            None => break,
+        };
+        let hash = blockchain.get_block_hashes(height..height + 1).await?.first().copied()
+            .ok_or_else(|| format!("block at height {} is not on the active chain", height))?;
+        let block = blockchain.get_block(&hash).await?
+            .ok_or_else(|| format!("block at height {} is missing from block storage", height))?;
+        let undo = blockchain.get_undo(&hash).await?
+            .ok_or_else(|| format!("undo data for height {} has been pruned; rescan from a higher height", height))?;
+        let mut wallets = wallets.lock().await;
+        let wallet = wallets.get_mut(name)?;
+        if wallet.state.synced_height == Some(height) {
+            wallet.disconnect_block(height, &block, &undo, &owned)?;
+        }
+        drop(wallets);
+        // Blocks served from the cache never make this wait, so others waiting on the wallets would
+        // otherwise only get them once the whole rescan was done
+        tokio::task::yield_now().await;
+    }
+    wallets.lock().await.get(name)?.save()?;
+
+    let mut summary = SyncSummary::default();
+    let (first, mut scripts) = {
+        let wallets = wallets.lock().await;
+        let wallet = wallets.get(name)?;
+        (wallet.state.synced_height.map_or(0, |synced| synced + 1), wallet.scripts()?)
+    };
+    let tip = match (blockchain.get_chain_height(), stop) {
+        (Some(tip), Some(stop)) => Some(tip.min(stop)),
+        (tip, _) => tip,
+    };
+    if let Some(tip) = tip {
+        progress.set_total((tip + 1).saturating_sub(first));
+        let mut since_checkpoint = 0;
+        while !progress.is_cancelled() {
+            let height = wallets.lock().await.get(name)?.state.synced_height.map_or(0, |synced| synced + 1);
+            if height > tip {
+                break;
+            }
+            let block = blockchain.get_block_by_height(height).await?;
+            let mut wallets = wallets.lock().await;
+            let wallet = wallets.get_mut(name)?;
+            // The follower got there first; it may also have extended the lookahead
+            if wallet.state.synced_height.map_or(0, |synced| synced + 1) != height {
+                scripts = wallet.scripts()?;
+                continue;
+            }
+            wallet.scan_block(height, block.as_deref(), &mut scripts, &mut summary)?;
+            progress.set_done((height + 1).saturating_sub(first));
+            since_checkpoint += 1;
+            if since_checkpoint == SCAN_CHECKPOINT_INTERVAL {
+                wallet.save()?;
+                since_checkpoint = 0;
+            }
+            drop(wallets);
+            tokio::task::yield_now().await;
+        }
+    }
+    Ok(wallets.lock().await.get_mut(name)?.finish_scan(summary)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{BlockHeader, BlockType};
+    use crate::hd_keys;
+    use crate::transaction::Coin;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_addresses_rotate_and_survive_reopen() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let mnemonic = hd_keys::generate_mnemonic(12)?;
+        let mut wallet = Wallet::create(temp_dir.path(), "main", &mnemonic, "", 1, 0, 20)?;
+        let first = wallet.new_address(KeyChain::Receive)?;
+        let second = wallet.new_address(KeyChain::Receive)?;
+        assert_ne!(first.address, second.address);
+        assert_eq!(second.path, "m/44'/1'/0'/0'/1'");
+        assert!(matches!(Wallet::create(temp_dir.path(), "main", &mnemonic, "", 1, 0, 20), Err(WalletError::AlreadyExists(_))));
+
+        // The same words and passphrase give back the same keys
+        let restored = Wallet::create(temp_dir.path(), "restored", &mnemonic, "", 1, 0, 20)?;
+        assert_eq!(restored.address(KeyChain::Receive, 1)?.address, second.address);
+        let other = Wallet::create(temp_dir.path(), "other", &mnemonic, "passphrase", 1, 0, 20)?;
+        assert_ne!(other.address(KeyChain::Receive, 1)?.address, second.address);
+
+        let mut wallets = Wallets::load(temp_dir.path())?;
+        assert_eq!(wallets.names(), vec!["main", "other", "restored"]);
+        assert!(matches!(wallets.get_mut(None), Err(WalletError::NoDefaultWallet(3))));
+        let reopened = wallets.get_mut(Some("main"))?;
+        assert_eq!(reopened.new_address(KeyChain::Receive)?.index, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_opens_as_a_wallets_directory() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let mnemonic = hd_keys::generate_mnemonic(12)?;
+        let mut wallet = Wallet::create(temp_dir.path(), "main", &mnemonic, "", 1, 0, 20)?;
+        wallet.new_address(KeyChain::Receive)?;
+        wallet.set_label(LabelTarget::Transaction([7; 32]), "rent")?;
+
+        let backup_dir = temp_dir.path().join("backup");
+        wallet.backup(&backup_dir)?;
+        assert!(matches!(wallet.backup(&backup_dir), Err(WalletError::AlreadyExists(_))));
+
+        let mut restored = Wallets::load(&backup_dir)?;
+        let restored = restored.get_mut(Some("main"))?;
+        assert_eq!(restored.label(LabelTarget::Transaction([7; 32]))?.as_deref(), Some("rent"));
+        assert_eq!(restored.new_address(KeyChain::Receive)?.index, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_only_tracks_coins_and_funds_psbt() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let key = [9u8; 32];
+        let mut wallet = Wallet::create_watch_only(temp_dir.path(), "watch", vec![key])?;
+        assert!(wallet.new_address(KeyChain::Receive).is_err());
+
+        let funding = Transaction {
+            inputs: Vec::new(),
+            outputs: vec![
+                TxOutput { value: 5_000, script_pubkey: key.to_vec() },
+                TxOutput { value: 3_000, script_pubkey: key.to_vec() },
+                TxOutput { value: 7_000, script_pubkey: vec![1; 32] },
+            ],
+            lock_time: 0,
+        };
+        let block = Block {
+            header: BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [0; 32], fruits_root: [0; 32], timestamp: 0, bits: 0, nonce: 0 },
+            block_type: BlockType::Block,
+            fruit_header: None,
+            fruits: Vec::new(),
+            transactions: vec![funding.clone()],
+        };
+        let scripts = wallet.scripts()?;
+        assert_eq!(wallet.connect_block(1, &block, &scripts)?, (2, false));
+        assert_eq!(wallet.balance().confirmed, 8_000);
+
+        // A mempool spend of the larger coin takes it out of coin selection
+        let input = TxInput { previous_output: OutPoint { txid: funding.hash(), index: 0 }, public_key: key, signature: Vec::new(), sequence: SEQUENCE_FINAL };
+        let spend = Transaction { inputs: vec![input], outputs: vec![TxOutput { value: 4_900, script_pubkey: vec![2; 32] }], lock_time: 0 };
+        wallet.sync_mempool([&spend])?;
+        assert_eq!((wallet.balance().pending_sent, wallet.history()?.len()), (5_000, 2));
+
+        let psbt = wallet.create_psbt(vec![TxOutput { value: 2_000, script_pubkey: vec![3; 32] }], 1.0, 0)?;
+        assert_eq!(psbt.tx.inputs.len(), 1);
+        assert_eq!(psbt.tx.inputs[0].previous_output.index, 1);
+        assert_eq!(psbt.tx.outputs[1].script_pubkey, key.to_vec());
+        assert!(psbt.fee()? > 0);
+        assert_eq!(PartiallySignedTransaction::from_bytes(&psbt.to_bytes()?)?, psbt);
+        assert!(matches!(
+            wallet.create_psbt(vec![TxOutput { value: 3_000, script_pubkey: vec![3; 32] }], 1.0, 0),
+            Err(WalletError::InsufficientFunds { available: 3_000, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewind_restores_spent_coins_and_drops_newer_ones() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let key = [9u8; 32];
+        let mut wallet = Wallet::create_watch_only(temp_dir.path(), "watch", vec![key])?;
+        let block = |transactions| Block {
+            header: BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [0; 32], fruits_root: [0; 32], timestamp: 0, bits: 0, nonce: 0 },
+            block_type: BlockType::Block,
+            fruit_header: None,
+            fruits: Vec::new(),
+            transactions,
+        };
+        let funding = Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value: 5_000, script_pubkey: key.to_vec() }], lock_time: 0 };
+        let outpoint = OutPoint { txid: funding.hash(), index: 0 };
+        let input = TxInput { previous_output: outpoint, public_key: key, signature: Vec::new(), sequence: SEQUENCE_FINAL };
+        let spend = Transaction { inputs: vec![input], outputs: vec![TxOutput { value: 4_000, script_pubkey: key.to_vec() }], lock_time: 0 };
+
+        let scripts = wallet.scripts()?;
+        wallet.connect_block(1, &block(vec![funding.clone()]), &scripts)?;
+        wallet.connect_block(2, &block(vec![spend.clone()]), &scripts)?;
+        wallet.state.synced_height = Some(2);
+        assert_eq!(wallet.balance().confirmed, 4_000);
+
+        let owned = wallet.owned_scripts()?;
+        let coin = Coin { output: funding.outputs[0].clone(), height: 1, median_time_past: 0, is_coinbase: false };
+        wallet.disconnect_block(2, &block(vec![spend.clone()]), &BlockUndo { spent_coins: vec![(outpoint, coin)] }, &owned)?;
+        assert_eq!((wallet.balance().confirmed, wallet.state.synced_height), (5_000, Some(1)));
+        // The wallet file no longer counts the block by the time its hash is forgotten
+        let saved: WalletState = codec::decode(&fs::read(&wallet.path)?)?;
+        assert_eq!((saved.synced_height, wallet.history.block_hash(2)?), (Some(1), None));
+
+        // Replaying the block gives the same result, and the history entry is kept rather than doubled
+        wallet.connect_block(2, &block(vec![spend]), &scripts)?;
+        assert_eq!((wallet.balance().confirmed, wallet.history()?.len()), (4_000, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorg_returns_spends_to_pending_and_flags_double_spends() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let key = [9u8; 32];
+        let mut wallet = Wallet::create_watch_only(temp_dir.path(), "watch", vec![key])?;
+        let block = |timestamp, transactions| Block {
+            header: BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [0; 32], fruits_root: [0; 32], timestamp, bits: 0, nonce: 0 },
+            block_type: BlockType::Block,
+            fruit_header: None,
+            fruits: Vec::new(),
+            transactions,
+        };
+        let funding = Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value: 5_000, script_pubkey: key.to_vec() }], lock_time: 0 };
+        let outpoint = OutPoint { txid: funding.hash(), index: 0 };
+        let spend_to = |script: u8| Transaction {
+            inputs: vec![TxInput { previous_output: outpoint, public_key: key, signature: Vec::new(), sequence: SEQUENCE_FINAL }],
+            outputs: vec![TxOutput { value: 4_000, script_pubkey: vec![script; 32] }],
+            lock_time: 0,
+        };
+        let (spend, double_spend) = (spend_to(2), spend_to(3));
+        let reward = Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value: 1_000, script_pubkey: key.to_vec() }], lock_time: 2 };
+
+        let scripts = wallet.scripts()?;
+        let owned = wallet.owned_scripts()?;
+        let undo = BlockUndo { spent_coins: vec![(outpoint, Coin { output: funding.outputs[0].clone(), height: 1, median_time_past: 0, is_coinbase: true })] };
+        wallet.connect_block(1, &block(1, vec![funding]), &scripts)?;
+        let stale = block(2, vec![reward, spend.clone()]);
+        wallet.connect_block(2, &stale, &scripts)?;
+        assert_eq!(wallet.balance().confirmed, 1_000);
+
+        // The reorg takes away the coinbase reward with its block, and the spend goes back to pending
+        wallet.disconnect_block(2, &stale, &undo, &owned)?;
+        let entry = wallet.transaction(&spend.hash())?.expect("spend is still known");
+        assert_eq!((entry.height, wallet.status(&entry)), (None, TxStatus::Pending));
+        assert_eq!(wallet.history()?.len(), 2);
+        let balance = wallet.balance();
+        assert_eq!((balance.confirmed, balance.pending_sent), (5_000, 5_000));
+        assert!(matches!(wallet.create_psbt(vec![TxOutput { value: 1_000, script_pubkey: vec![3; 32] }], 1.0, 0), Err(WalletError::InsufficientFunds { .. })));
+
+        // The new chain spends the same coin elsewhere
+        let replacement = block(3, vec![double_spend.clone()]);
+        wallet.connect_block(2, &replacement, &scripts)?;
+        let entry = wallet.transaction(&spend.hash())?.expect("spend is still known");
+        assert_eq!(wallet.status(&entry), TxStatus::Conflicted);
+        assert_eq!((wallet.balance().confirmed, wallet.balance().pending_sent), (0, 0));
+
+        // And once that's undone too the spend may yet confirm
+        wallet.disconnect_block(2, &replacement, &undo, &owned)?;
+        assert_eq!(wallet.status(&entry), TxStatus::Pending);
+        drop(wallet);
+        let reopened = Wallet::open(&wallet_path(temp_dir.path(), "watch"))?;
+        assert_eq!(reopened.status(&entry), TxStatus::Pending);
+        assert_eq!(reopened.history()?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_two_of_two_multisig_wallets_spend_together()-> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = TempDir::new()?;
+        let mnemonics = [hd_keys::generate_mnemonic(12)?, hd_keys::generate_mnemonic(12)?];
+        let keys = [Wallet::multisig_key(&mnemonics[0], "", 1, 0)?, Wallet::multisig_key(&mnemonics[1], "", 1, 0)?];
+        let mut alice = Wallet::create_multisig(temp_dir.path(), "alice", &mnemonics[0], "", 1, 0, 2, vec![keys[1]])?;
+        let bob = Wallet::create_multisig(temp_dir.path(), "bob", &mnemonics[1], "", 1, 0, 2, vec![keys[0]])?;
+        let address = alice.new_address(KeyChain::Receive)?.address;
+        assert_eq!(address, bob.address(KeyChain::Change, 5)?.address);
+        assert!(matches!(
+            Wallet::create_multisig(temp_dir.path(), "carol", &mnemonics[0], "", 1, 0, 3, vec![keys[1]]),
+            Err(WalletError::Multisig(MultisigError::InvalidThreshold { .. }))
+        ));
+
+        let funding = Transaction { inputs: Vec::new(), outputs: vec![TxOutput { value: 10_000, script_pubkey: hex::decode(&address)? }], lock_time: 0 };
+        let block = Block {
+            header: BlockHeader { version: 1, previous_hash: [0; 32], merkle_root: [0; 32], fruits_root: [0; 32], timestamp: 0, bits: 0, nonce: 0 },
+            block_type: BlockType::Block,
+            fruit_header: None,
+            fruits: Vec::new(),
+            transactions: vec![funding],
+        };
+        let scripts = alice.scripts()?;
+        assert_eq!(alice.connect_block(1, &block, &scripts)?, (1, false));
+
+        // Change goes back to the shared address, and neither signature is enough on its own
+        let mut psbt = alice.create_psbt(vec![TxOutput { value: 4_000, script_pubkey: vec![3; 32] }], 1.0, 0)?;
+        assert_eq!(psbt.tx.outputs[1].script_pubkey, hex::decode(&address)?);
+        let mut cosigned = psbt.clone();
+        assert_eq!(alice.sign_psbt(&mut psbt)?, 1);
+        assert_eq!(bob.sign_psbt(&mut cosigned)?, 1);
+        assert!(!psbt.is_complete());
+        psbt.combine(&cosigned)?;
+        psbt.finalize()?.verify_signatures()?;
+
+        let script = alice.multisig_script().cloned();
+        drop(alice);
+        assert_eq!(Wallet::open(&wallet_path(temp_dir.path(), "alice"))?.multisig_script().cloned(), script);
+        Ok(())
+    }
+}
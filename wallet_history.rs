@@ -0,0 +1,261 @@
+use crate::blockchain::BlockHash;
+use crate::codec::{self, CodecError};
+use crate::transaction::{Transaction, TxHash};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{ColumnFamilyDescriptor, IteratorMode, Options, DB};
+use serde::{Serialize, Deserialize};
+use std::path::Path;
+use thiserror::Error;
+
+// Each wallet keeps its transaction history and labels in a small database next to its wallet file.
+// History can always be rebuilt by rescanning the chain; labels can't, so a rescan leaves them alone.
+// Alongside are the hashes of the blocks the wallet scanned, so it can tell when a reorg has taken
+// them off the chain, and the transactions such a reorg returned to unconfirmed.
+const CF_TRANSACTIONS: &str = "transactions";
+const CF_LABELS: &str = "labels";
+const CF_BLOCKS: &str = "blocks";
+const CF_UNCONFIRMED: &str = "unconfirmed";
+// Label keys are a kind byte followed by the txid or address
+const LABEL_TRANSACTION: u8 = b't';
+const LABEL_ADDRESS: u8 = b'a';
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("Database error: {0}")]
+    Database(#[from] rocksdb::Error),
+    #[error("Codec error: {0}")]
+    Codec(#[from] CodecError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Receive,
+    Send,
+    // Everything spent came back to the wallet apart from the fee
+    #[serde(rename = "self")]
+    SelfTransfer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxStatus {
+    Confirmed,
+    // In the mempool, or taken out of the chain by a reorg and not yet back in it
+    Pending,
+    // An input was spent by another confirmed transaction, so this one can never confirm
+    Conflicted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub txid: TxHash,
+    // None while the transaction is unconfirmed
+    pub height: Option<u64>,
+    pub block_hash: Option<BlockHash>,
+    pub block_time: Option<u64>,
+    // Unix time this node first saw the transaction
+    pub first_seen: u64,
+    pub received: u64,
+    pub sent: u64,
+    // Known when every input spent one of the wallet's coins
+    pub fee: Option<u64>,
+}
+
+impl HistoryEntry {
+    pub fn direction(&self) -> Direction {
+        if self.sent == 0 {
+            Direction::Receive
+        } else if self.fee.is_some_and(|fee| self.received.saturating_add(fee) == self.sent) {
+            Direction::SelfTransfer
+        } else {
+            Direction::Send
+        }
+    }
+}
+
+// A wallet transaction a reorg disconnected, kept until it confirms again. `conflict` is the
+// transaction that spent one of its inputs instead and the height it confirmed at.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UnconfirmedTransaction {
+    pub entry: HistoryEntry,
+    pub tx: Transaction,
+    pub conflict: Option<(TxHash, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelTarget {
+    Transaction(TxHash),
+    Address([u8; 32]),
+}
+
+impl LabelTarget {
+    fn key(&self) -> [u8; 33] {
+        let (kind, id) = match self {
+            LabelTarget::Transaction(txid) => (LABEL_TRANSACTION, txid),
+            LabelTarget::Address(address) => (LABEL_ADDRESS, address),
+        };
+        let mut key = [0u8; 33];
+        key[0] = kind;
+        key[1..].copy_from_slice(id);
+        key
+    }
+
+    fn from_key(key: &[u8]) -> Option<Self> {
+        let id = key.get(1..)?.try_into().ok()?;
+        match key[0] {
+            LABEL_TRANSACTION => Some(LabelTarget::Transaction(id)),
+            LABEL_ADDRESS => Some(LabelTarget::Address(id)),
+            _ => None,
+        }
+    }
+}
+
+pub struct WalletHistory {
+    db: DB,
+}
+
+impl WalletHistory {
+    pub fn open(path: &Path) -> Result<Self, HistoryError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let cfs = [CF_TRANSACTIONS, CF_LABELS, CF_BLOCKS, CF_UNCONFIRMED].map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+        Ok(WalletHistory { db: DB::open_cf_descriptors(&opts, path, cfs)? })
+    }
+
+    // Consistent copy of the history at `path`, which must not exist yet
+    pub fn create_checkpoint(&self, path: &Path) -> Result<(), HistoryError> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    fn cf(&self, name: &str) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(name).expect("column family is created on open")
+    }
+
+    pub fn put(&self, entry: &HistoryEntry) -> Result<(), HistoryError> {
+        self.db.put_cf(self.cf(CF_TRANSACTIONS), entry.txid, codec::encode(entry)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, txid: &TxHash) -> Result<Option<HistoryEntry>, HistoryError> {
+        match self.db.get_cf(self.cf(CF_TRANSACTIONS), txid)? {
+            Some(bytes) => Ok(Some(codec::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete(&self, txid: &TxHash) -> Result<(), HistoryError> {
+        self.db.delete_cf(self.cf(CF_TRANSACTIONS), txid)?;
+        Ok(())
+    }
+
+    // Oldest first by height, then by when they were first seen
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>, HistoryError> {
+        let mut entries = self.db.iterator_cf(self.cf(CF_TRANSACTIONS), IteratorMode::Start)
+            .map(|item| Ok(codec::decode::<HistoryEntry>(&item?.1)?))
+            .collect::<Result<Vec<_>, HistoryError>>()?;
+        entries.sort_by_key(|entry| (entry.height.unwrap_or(u64::MAX), entry.first_seen));
+        Ok(entries)
+    }
+
+    // Forgets every transaction and scanned block ahead of a rescan, keeping the labels
+    pub fn clear(&self) -> Result<(), HistoryError> {
+        for name in [CF_TRANSACTIONS, CF_BLOCKS, CF_UNCONFIRMED] {
+            let cf = self.cf(name);
+            for item in self.db.iterator_cf(cf, IteratorMode::Start) {
+                self.db.delete_cf(cf, item?.0)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn put_block_hash(&self, height: u64, hash: &BlockHash) -> Result<(), HistoryError> {
+        self.db.put_cf(self.cf(CF_BLOCKS), height.to_be_bytes(), hash)?;
+        Ok(())
+    }
+
+    // None for heights not scanned, or scanned before block hashes were recorded
+    pub fn block_hash(&self, height: u64) -> Result<Option<BlockHash>, HistoryError> {
+        Ok(self.db.get_cf(self.cf(CF_BLOCKS), height.to_be_bytes())?.and_then(|bytes| bytes.as_slice().try_into().ok()))
+    }
+
+    pub fn delete_block_hash(&self, height: u64) -> Result<(), HistoryError> {
+        self.db.delete_cf(self.cf(CF_BLOCKS), height.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn put_unconfirmed(&self, unconfirmed: &UnconfirmedTransaction) -> Result<(), HistoryError> {
+        self.db.put_cf(self.cf(CF_UNCONFIRMED), unconfirmed.entry.txid, codec::encode(unconfirmed)?)?;
+        Ok(())
+    }
+
+    pub fn remove_unconfirmed(&self, txid: &TxHash) -> Result<(), HistoryError> {
+        self.db.delete_cf(self.cf(CF_UNCONFIRMED), txid)?;
+        Ok(())
+    }
+
+    pub fn unconfirmed(&self) -> Result<Vec<UnconfirmedTransaction>, HistoryError> {
+        self.db.iterator_cf(self.cf(CF_UNCONFIRMED), IteratorMode::Start)
+            .map(|item| Ok(codec::decode(&item?.1)?))
+            .collect()
+    }
+
+    // An empty label removes it
+    pub fn set_label(&self, target: LabelTarget, label: &str) -> Result<(), HistoryError> {
+        if label.is_empty() {
+            self.db.delete_cf(self.cf(CF_LABELS), target.key())?;
+        } else {
+            self.db.put_cf(self.cf(CF_LABELS), target.key(), label.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn label(&self, target: LabelTarget) -> Result<Option<String>, HistoryError> {
+        Ok(self.db.get_cf(self.cf(CF_LABELS), target.key())?.map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    pub fn labels(&self) -> Result<Vec<(LabelTarget, String)>, HistoryError> {
+        let mut labels = Vec::new();
+        for item in self.db.iterator_cf(self.cf(CF_LABELS), IteratorMode::Start) {
+            let (key, value) = item?;
+            if let Some(target) = LabelTarget::from_key(&key) {
+                labels.push((target, String::from_utf8_lossy(&value).into_owned()));
+            }
+        }
+        Ok(labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_history_and_labels() -> Result<(), HistoryError> {
+        let temp_dir = TempDir::new().unwrap();
+        let history = WalletHistory::open(temp_dir.path())?;
+        let entry = |txid: u8, height: Option<u64>, received: u64, sent: u64, fee: Option<u64>| HistoryEntry {
+            txid: [txid; 32], height, block_hash: None, block_time: None, first_seen: 0, received, sent, fee,
+        };
+        history.put(&entry(1, None, 40, 100, Some(10)))?;
+        history.put(&entry(2, Some(7), 100, 0, None))?;
+        history.put(&entry(3, Some(9), 90, 100, Some(10)))?;
+        let entries = history.entries()?;
+        assert_eq!(entries.iter().map(|entry| entry.txid[0]).collect::<Vec<_>>(), vec![2, 3, 1]);
+        assert_eq!(entries.iter().map(HistoryEntry::direction).collect::<Vec<_>>(), vec![Direction::Receive, Direction::SelfTransfer, Direction::Send]);
+
+        history.set_label(LabelTarget::Transaction([2; 32]), "salary")?;
+        history.set_label(LabelTarget::Address([2; 32]), "savings")?;
+        history.clear()?;
+        assert!(history.get(&[2; 32])?.is_none());
+        // Labels outlive a rescan, and the same bytes as a txid and an address don't collide
+        assert_eq!(history.label(LabelTarget::Transaction([2; 32]))?.as_deref(), Some("salary"));
+        assert_eq!(history.labels()?.len(), 2);
+        history.set_label(LabelTarget::Transaction([2; 32]), "")?;
+        assert_eq!(history.labels()?, vec![(LabelTarget::Address([2; 32]), "savings".to_string())]);
+        Ok(())
+    }
+}
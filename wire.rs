@@ -0,0 +1,196 @@
+use crate::chain_params::ChainParams;
+use crate::codec::{self, CodecError};
+use crate::protocol::Message;
+use crate::rate_limit;
+use bytes::{Buf, BufMut, BytesMut};
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+// Every P2P message on the wire is a fixed header followed by its payload:
+//   magic (4) | command, NUL padded (12) | payload length, little-endian u32 (4) | checksum (4) | payload
+// The magic identifies the network so a peer from another chain is dropped after four bytes. The
+// payload is `codec::encode` of the message, whose leading format version byte lets it evolve.
+pub const HEADER_LEN: usize = 24;
+const COMMAND_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum WireError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Message magic {} is not this network's {}", hex::encode(.actual), hex::encode(.expected))]
+    WrongNetwork { expected: [u8; 4], actual: [u8; 4] },
+    #[error("Malformed command name")]
+    BadCommand,
+    #[error("{command} message of {size} bytes exceeds the {max} byte limit")]
+    MessageTooLarge { command: String, size: usize, max: usize },
+    #[error("{0} message payload does not match its checksum")]
+    BadChecksum(String),
+    #[error("Header says {header} but the payload is a {payload} message")]
+    CommandMismatch { header: String, payload: &'static str },
+    #[error("Codec error: {0}")]
+    Codec(#[from] CodecError),
+}
+
+// First four bytes of the payload's BLAKE3 hash
+pub fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = blake3::hash(payload);
+    hash.as_bytes()[..4].try_into().expect("hash is 32 bytes")
+}
+
+// Frames `Message`s for one network, for use with `tokio_util::codec::Framed`
+#[derive(Debug, Clone)]
+pub struct MessageCodec {
+    magic: [u8; 4],
+    max_block_size: usize,
+    // Header of a message whose payload hasn't fully arrived yet
+    pending: Option<FrameHeader>,
+}
+
+#[derive(Debug, Clone)]
+struct FrameHeader {
+    command: String,
+    length: usize,
+    checksum: [u8; 4],
+}
+
+impl MessageCodec {
+    pub fn new(params: &ChainParams) -> Self {
+        MessageCodec { magic: params.magic, max_block_size: params.max_block_size, pending: None }
+    }
+
+    // Largest payload accepted for `command`
+    pub fn max_payload(&self, command: &str) -> usize {
+        rate_limit::max_message_size(command, self.max_block_size)
+    }
+
+    // Largest frame of any message, header included
+    pub fn max_frame_len(&self) -> usize {
+        HEADER_LEN + self.max_payload("tx")
+    }
+
+    fn decode_header(&self, header: &[u8]) -> Result<FrameHeader, WireError> {
+        let name = &header[4..4 + COMMAND_LEN];
+        let end = name.iter().position(|&b| b == 0).unwrap_or(COMMAND_LEN);
+        if end == 0 || name[end..].iter().any(|&b| b != 0) || !name[..end].iter().all(u8::is_ascii_lowercase) {
+            return Err(WireError::BadCommand);
+        }
+        let command = String::from_utf8_lossy(&name[..end]).into_owned();
+        let length = u32::from_le_bytes(header[16..20].try_into().expect("four bytes")) as usize;
+        let max = self.max_payload(&command);
+        if length > max {
+            return Err(WireError::MessageTooLarge { command, size: length, max });
+        }
+        Ok(FrameHeader { command, length, checksum: header[20..24].try_into().expect("four bytes") })
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = WireError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, WireError> {
+        let header = match self.pending.take() {
+            Some(header) => header,
+            None => {
+                // Checked before the rest of the header arrives, so traffic for another network fails fast
+                if src.len() >= 4 && src[..4] != self.magic {
+                    return Err(WireError::WrongNetwork { expected: self.magic, actual: src[..4].try_into().expect("four bytes") });
+                }
+                if src.len() < HEADER_LEN {
+                    src.reserve(HEADER_LEN - src.len());
+                    return Ok(None);
+                }
+                let header = self.decode_header(&src[..HEADER_LEN])?;
+                src.advance(HEADER_LEN);
+                header
+            }
+        };
+        if src.len() < header.length {
+            src.reserve(header.length - src.len());
+            self.pending = Some(header);
+            return Ok(None);
+        }
+
+        let payload = src.split_to(header.length);
+        if checksum(&payload) != header.checksum {
+            return Err(WireError::BadChecksum(header.command));
+        }
+        // Peers only send messages the negotiated protocol version has, so an unknown one is an error
+        let message = codec::decode::<Message>(&payload)?;
+        if message.command() != header.command {
+            return Err(WireError::CommandMismatch { header: header.command, payload: message.command() });
+        }
+        Ok(Some(message))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = WireError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), WireError> {
+        let command = message.command();
+        let payload = codec::encode(&message)?;
+        let max = self.max_payload(command);
+        if payload.len() > max {
+            return Err(WireError::MessageTooLarge { command: command.to_string(), size: payload.len(), max });
+        }
+        let mut name = [0u8; COMMAND_LEN];
+        name[..command.len()].copy_from_slice(command.as_bytes());
+
+        dst.reserve(HEADER_LEN + payload.len());
+        dst.put_slice(&self.magic);
+        dst.put_slice(&name);
+        dst.put_u32_le(payload.len() as u32);
+        dst.put_slice(&checksum(&payload));
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_params::Network;
+    use crate::protocol::Inventory;
+
+    #[test]
+    fn test_frames_round_trip_and_bad_frames_are_rejected() {
+        let params = ChainParams::for_network(Network::Regtest);
+        let mut codec = MessageCodec::new(&params);
+        let mut buffer = BytesMut::new();
+        codec.encode(Message::Ping(7), &mut buffer).unwrap();
+        codec.encode(Message::Inv(vec![Inventory::Block([3; 32])]), &mut buffer).unwrap();
+        assert_eq!(&buffer[..4], &params.magic);
+        assert_eq!(&buffer[4..9], b"ping\0");
+
+        // Split mid-payload, then let the rest arrive
+        let rest = buffer.split_off(HEADER_LEN + 3);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        buffer.unsplit(rest);
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(Message::Ping(7))));
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(Message::Inv(items)) if items.len() == 1));
+        assert!(buffer.is_empty());
+
+        // Wrong network, from the magic alone
+        let mut foreign = BytesMut::from(&b"xcMN"[..]);
+        assert!(matches!(MessageCodec::new(&params).decode(&mut foreign), Err(WireError::WrongNetwork { .. })));
+
+        // One limit per command, shared with the rate limits
+        assert_eq!(codec.max_payload("inv"), rate_limit::MAX_MESSAGE_SIZE);
+        assert!(codec.max_payload("tx") >= codec.max_payload("block"));
+
+        // Oversized before any payload is read
+        let mut oversized = BytesMut::new();
+        oversized.put_slice(&params.magic);
+        oversized.put_slice(b"inv\0\0\0\0\0\0\0\0\0");
+        oversized.put_u32_le(rate_limit::MAX_MESSAGE_SIZE as u32 + 1);
+        oversized.put_slice(&[0; 4]);
+        assert!(matches!(MessageCodec::new(&params).decode(&mut oversized), Err(WireError::MessageTooLarge { .. })));
+
+        let mut corrupted = BytesMut::new();
+        codec.encode(Message::Pong(1), &mut corrupted).unwrap();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        assert!(matches!(codec.decode(&mut corrupted), Err(WireError::BadChecksum(command)) if command == "pong"));
+    }
+}